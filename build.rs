@@ -50,6 +50,9 @@ fn build_common(builder: &mut Build) {
         let win_path = _platform_path.join("win");
         builder.include(&win_path);
         builder.file(win_path.join("win.cpp"));
+        // the hwcodec_debug_* calls of testutil
+        #[cfg(feature = "testutil")]
+        builder.define("HWCODEC_TESTUTIL", None);
     }
     #[cfg(target_os = "linux")]
     {
@@ -80,8 +83,22 @@ fn build_common(builder: &mut Build) {
 struct CommonCallbacks;
 impl bindgen::callbacks::ParseCallbacks for CommonCallbacks {
     fn add_derives(&self, name: &str) -> Vec<String> {
-        let names = vec!["DataFormat", "SurfaceFormat", "API"];
-        if names.contains(&name) {
+        let names = vec![
+            "DataFormat",
+            "SurfaceFormat",
+            "API",
+            "AqMode",
+            "ChromaLocation",
+            "ConfigParam",
+            "EntropyCoding",
+            "ColorConvert",
+        ];
+        if name == "EncodeCaps" || name == "DecodeCaps" || name == "TextureDesc" {
+            vec!["Default", "PartialEq", "Eq", "Serialize", "Deserialize"]
+                .drain(..)
+                .map(|s| s.to_string())
+                .collect()
+        } else if names.contains(&name) {
             vec!["Serialize", "Deserialize"]
                 .drain(..)
                 .map(|s| s.to_string())
@@ -103,7 +120,7 @@ mod ffmpeg {
         link_vcpkg(builder, std::env::var("VCPKG_ROOT").unwrap().into());
         link_os();
         build_ffmpeg_ram(builder);
-        #[cfg(feature = "vram")]
+        #[cfg(all(feature = "vram", feature = "vram-ffmpeg"))]
         build_ffmpeg_vram(builder);
         build_mux(builder);
         let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap();
@@ -156,7 +173,8 @@ mod ffmpeg {
         {
             let mut static_libs = vec!["avcodec", "avutil", "avformat"];
             if target_os == "windows" {
-                static_libs.push("libmfx");
+                // the oneVPL dispatcher, it loads Media SDK runtimes too
+                static_libs.push("vpl");
             }
             static_libs
                 .iter()
@@ -167,6 +185,10 @@ mod ffmpeg {
         let include = path.join("include");
         println!("{}", format!("cargo:include={}", include.to_str().unwrap()));
         builder.include(&include);
+        if target_os == "windows" {
+            // ahead of the Media SDK headers the mfx samples are built with
+            builder.include(include.join("vpl"));
+        }
         include
     }
 
@@ -240,7 +262,7 @@ mod ffmpeg {
         );
     }
 
-    #[cfg(feature = "vram")]
+    #[cfg(all(feature = "vram", feature = "vram-ffmpeg"))]
     fn build_ffmpeg_vram(builder: &mut Build) {
         let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         let ffmpeg_ram_dir = manifest_dir.join("cpp").join("ffmpeg_vram");
@@ -251,13 +273,26 @@ mod ffmpeg {
         bindgen::builder()
             .header(ffi_header)
             .rustified_enum("*")
+            .blocklist_type("EncodeOptions")
+            .blocklist_type("MemoryInfo")
+            .blocklist_type("EncodeCaps")
+            .blocklist_type("DecodeCaps")
+            .blocklist_type("RuntimeInfo")
+            .blocklist_type("GpuTiming")
+            .blocklist_type("ConfigCheck")
+            .blocklist_type("DriverProbe")
             .generate()
             .unwrap()
             .write_to_file(Path::new(&env::var_os("OUT_DIR").unwrap()).join("ffmpeg_vram_ffi.rs"))
             .unwrap();
 
         builder.files(
-            ["ffmpeg_vram_decode.cpp", "ffmpeg_vram_encode.cpp"].map(|f| ffmpeg_ram_dir.join(f)),
+            [
+                "ffmpeg_vram_decode.cpp",
+                "ffmpeg_vram_encode.cpp",
+                "ffmpeg_vram_jpeg.cpp",
+            ]
+            .map(|f| ffmpeg_ram_dir.join(f)),
         );
     }
 
@@ -281,12 +316,16 @@ mod ffmpeg {
 mod sdk {
     use super::*;
 
-    pub(crate) fn build_sdk(builder: &mut Build) {
-        build_amf(builder);
-        build_nv(builder);
-        build_mfx(builder);
+    pub(crate) fn build_sdk(_builder: &mut Build) {
+        #[cfg(feature = "amf")]
+        build_amf(_builder);
+        #[cfg(feature = "nv")]
+        build_nv(_builder);
+        #[cfg(feature = "mfx")]
+        build_mfx(_builder);
     }
 
+    #[cfg(feature = "nv")]
     fn build_nv(builder: &mut Build) {
         let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         let externals_dir = manifest_dir.join("externals");
@@ -298,6 +337,14 @@ mod sdk {
         bindgen::builder()
             .header(&nv_dir.join("nv_ffi.h").to_string_lossy().to_string())
             .rustified_enum("*")
+            .blocklist_type("EncodeOptions")
+            .blocklist_type("MemoryInfo")
+            .blocklist_type("EncodeCaps")
+            .blocklist_type("DecodeCaps")
+            .blocklist_type("RuntimeInfo")
+            .blocklist_type("GpuTiming")
+            .blocklist_type("ConfigCheck")
+            .blocklist_type("DriverProbe")
             .generate()
             .unwrap()
             .write_to_file(Path::new(&env::var_os("OUT_DIR").unwrap()).join("nv_ffi.rs"))
@@ -351,9 +398,10 @@ mod sdk {
         }
 
         // crate
-        builder.files(["nv_encode.cpp", "nv_decode.cpp"].map(|f| nv_dir.join(f)));
+        builder.files(["nv_encode.cpp", "nv_decode.cpp", "nv_jpeg.cpp"].map(|f| nv_dir.join(f)));
     }
 
+    #[cfg(feature = "amf")]
     fn build_amf(builder: &mut Build) {
         let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         let externals_dir = manifest_dir.join("externals");
@@ -363,6 +411,14 @@ mod sdk {
         bindgen::builder()
             .header(amf_dir.join("amf_ffi.h").to_string_lossy().to_string())
             .rustified_enum("*")
+            .blocklist_type("EncodeOptions")
+            .blocklist_type("MemoryInfo")
+            .blocklist_type("EncodeCaps")
+            .blocklist_type("DecodeCaps")
+            .blocklist_type("RuntimeInfo")
+            .blocklist_type("GpuTiming")
+            .blocklist_type("ConfigCheck")
+            .blocklist_type("DriverProbe")
             .generate()
             .unwrap()
             .write_to_file(Path::new(&env::var_os("OUT_DIR").unwrap()).join("amf_ffi.rs"))
@@ -396,6 +452,7 @@ mod sdk {
         builder.files(["amf_encode.cpp", "amf_decode.cpp"].map(|f| amf_dir.join(f)));
     }
 
+    #[cfg(feature = "mfx")]
     fn build_mfx(builder: &mut Build) {
         let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         let externals_dir = manifest_dir.join("externals");
@@ -405,19 +462,22 @@ mod sdk {
         bindgen::builder()
             .header(&mfx_dir.join("mfx_ffi.h").to_string_lossy().to_string())
             .rustified_enum("*")
+            .blocklist_type("EncodeOptions")
+            .blocklist_type("MemoryInfo")
+            .blocklist_type("EncodeCaps")
+            .blocklist_type("DecodeCaps")
+            .blocklist_type("RuntimeInfo")
+            .blocklist_type("GpuTiming")
+            .blocklist_type("ConfigCheck")
+            .blocklist_type("DriverProbe")
             .generate()
             .unwrap()
             .write_to_file(Path::new(&env::var_os("OUT_DIR").unwrap()).join("mfx_ffi.rs"))
             .unwrap();
 
-        // MediaSDK
+        // MediaSDK samples, its api headers only serve what the VPL ones dropped
         let sdk_path = externals_dir.join("MediaSDK_22.5.4");
 
-        // mfx_dispatch
-        let mfx_path = sdk_path.join("api").join("mfx_dispatch");
-        // include headers and reuse static lib
-        builder.include(mfx_path.join("windows").join("include"));
-
         let sample_path = sdk_path.join("samples").join("sample_common");
         builder
             .includes([
@@ -453,7 +513,15 @@ mod sdk {
         .map(|lib| println!("cargo:rustc-link-lib={}", lib));
 
         builder
-            .files(["mfx_encode.cpp", "mfx_decode.cpp"].map(|f| mfx_dir.join(f)))
+            .files(
+                [
+                    "mfx_session.cpp",
+                    "mfx_encode.cpp",
+                    "mfx_decode.cpp",
+                    "mfx_jpeg.cpp",
+                ]
+                .map(|f| mfx_dir.join(f)),
+            )
             .define("NOMINMAX", None)
             .define("MFX_DEPRECATED_OFF", None)
             .define("MFX_D3D11_SUPPORT", None);
@@ -62,6 +62,54 @@ public:
 
   ~AMFDecoder() {}
 
+  bool IsDeviceLost() {
+    return nativeDevice_ && nativeDevice_->IsDeviceLost();
+  }
+
+  bool MemoryUsage(MemoryInfo *info) {
+    if (!nativeDevice_) {
+      info->estimated = 0;
+      info->allocated = 0;
+      return false;
+    }
+    estimate_decoder_memory(nativeDevice_.get(), info);
+    return true;
+  }
+
+  // AMF reports the size range of the component, the profiles are those of the
+  // h264 and hevc components, main10 has its own, mjpeg has none. The level stays
+  // unknown.
+  void caps(DecodeCaps *caps) {
+    *caps = {};
+    amf::AMFCapsPtr decoderCaps;
+    if (!AMFDecoder_ || AMFDecoder_->GetCaps(&decoderCaps) != AMF_OK)
+      return;
+    amf::AMFIOCapsPtr inputCaps;
+    if (decoderCaps->GetInputCaps(&inputCaps) == AMF_OK) {
+      amf_int32 minValue = 0, maxValue = 0;
+      inputCaps->GetWidthRange(&minValue, &maxValue);
+      caps->maxWidth = maxValue;
+      inputCaps->GetHeightRange(&minValue, &maxValue);
+      caps->maxHeight = maxValue;
+    }
+    caps->bitDepths = 1u << 8;
+    if (codec_ == amf_wstring(AMFVideoDecoderUVD_MJPEG))
+      return;
+    if (codec_ == amf_wstring(AMFVideoDecoderUVD_H264_AVC)) {
+      caps->profiles = DECODE_PROFILE_H264_BASELINE | DECODE_PROFILE_H264_MAIN |
+                       DECODE_PROFILE_H264_HIGH;
+      return;
+    }
+    caps->profiles = DECODE_PROFILE_HEVC_MAIN;
+    amf::AMFComponentPtr main10;
+    if (AMFFactory_.GetFactory()->CreateComponent(
+            AMFContext_, AMFVideoDecoderHW_H265_MAIN10, &main10) == AMF_OK) {
+      caps->profiles |= DECODE_PROFILE_HEVC_MAIN10;
+      caps->bitDepths |= 1u << 10;
+      main10->Terminate();
+    }
+  }
+
   AMF_RESULT decode(uint8_t *iData, uint32_t iDataSize, DecodeCallback callback,
                     void *obj) {
     AMF_RESULT res = AMF_FAIL;
@@ -120,12 +168,20 @@ public:
       case amf::AMF_MEMORY_DX11: {
         {
           ID3D11Texture2D *src = (ID3D11Texture2D *)native;
-          D3D11_TEXTURE2D_DESC desc;
-          src->GetDesc(&desc);
-          nativeDevice_->EnsureTexture(desc.Width, desc.Height);
+          // the texture may be of the coded size, e.g. 1088 rows for 1080,
+          // only the plane's region is the picture
+          amf::AMFPlanePtr plane = convertSurface->GetPlaneAt(0);
+          int x = plane->GetOffsetX(), y = plane->GetOffsetY();
+          int width = plane->GetWidth(), height = plane->GetHeight();
+          if (width <= 0 || height <= 0)
+            return AMF_FAIL;
+          nativeDevice_->EnsureTexture(width, height);
           nativeDevice_->next();
           ID3D11Texture2D *dst = nativeDevice_->GetCurrentTexture();
-          nativeDevice_->context_->CopyResource(dst, src);
+          D3D11_BOX box = {(UINT)x, (UINT)y, 0, (UINT)(x + width),
+                           (UINT)(y + height), 1};
+          nativeDevice_->context_->CopySubresourceRegion(dst, 0, 0, 0, 0, src,
+                                                         0, &box);
           nativeDevice_->context_->Flush();
           if (callback)
             callback(dst, obj);
@@ -175,6 +231,8 @@ public:
   AMF_RESULT initialize() {
     AMF_RESULT res;
 
+    if (module_hidden(AMF_DLL_NAMEA))
+      return AMF_NOT_FOUND;
     res = AMFFactory_.Init();
     AMF_CHECK_RETURN(res, "AMFFactory Init failed");
     amf::AMFSetCustomTracer(AMFFactory_.GetTrace());
@@ -337,6 +395,9 @@ bool convert_codec(DataFormat lhs, amf_wstring &rhs) {
   case H265:
     rhs = AMFVideoDecoderHW_H265_HEVC;
     break;
+  case MJPEG:
+    rhs = AMFVideoDecoderUVD_MJPEG;
+    break;
   default:
     LOG_ERROR(std::string("unsupported codec: ") + std::to_string(lhs));
     return false;
@@ -395,16 +456,40 @@ void *amf_new_decoder(void *device, int64_t luid,
   return NULL;
 }
 
+int amf_decoder_memory(void *decoder, MemoryInfo *info) {
+  AMFDecoder *dec = (AMFDecoder *)decoder;
+  return dec->MemoryUsage(info) ? 0 : -1;
+}
+
+// the decoder always outputs concealed frames, not concealing is up to the caller
+int amf_decoder_set_conceal(void *decoder, int32_t conceal) {
+  return HWCODEC_SUCCESS;
+}
+
+int amf_decoder_caps(void *decoder, DecodeCaps *caps) {
+  try {
+    ((AMFDecoder *)decoder)->caps(caps);
+    return HWCODEC_SUCCESS;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("caps failed: ") + e.what());
+  }
+  return HWCODEC_ERR_COMMON;
+}
+
 int amf_decode(void *decoder, uint8_t *data, int32_t length,
                DecodeCallback callback, void *obj) {
+  AMFDecoder *dec = (AMFDecoder *)decoder;
   try {
-    AMFDecoder *dec = (AMFDecoder *)decoder;
+    if (dec->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
     if (dec->decode(data, length, callback, obj) == AMF_OK) {
       return HWCODEC_SUCCESS;
     }
   } catch (const std::exception &e) {
           LOG_ERROR(std::string("decode failed: ") + e.what());
   }
+  if (dec->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
   return HWCODEC_ERR_COMMON;
 }
 
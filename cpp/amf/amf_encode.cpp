@@ -8,6 +8,7 @@
 #include <public/include/core/Platform.h>
 #include <stdio.h>
 
+#include <algorithm>
 #include <cstring>
 #include <iostream>
 #include <math.h>
@@ -31,6 +32,28 @@ namespace {
     return res;                                                                \
   }
 
+// negated AMF_RESULTs would overlap with HwcodecErrno
+int to_errno(AMF_RESULT res) {
+  switch (res) {
+  case AMF_OK:
+    return HWCODEC_SUCCESS;
+  case AMF_ACCESS_DENIED:
+    return HWCODEC_ERR_INPUT_ACCESS_DENIED;
+  case AMF_NOT_INITIALIZED:
+  case AMF_NO_DEVICE:
+  case AMF_DIRECTX_FAILED:
+    return HWCODEC_ERR_SESSION_LOST;
+  default:
+    return HWCODEC_ERR_COMMON;
+  }
+}
+
+#define USER_DATA_PROPERTY L"HwcodecUserData"
+
+// the submitted surface is duplicated and converted to nv12 by the component
+#define AMF_INPUT_BGRA_SURFACES 1
+#define AMF_INPUT_NV12_SURFACES 1
+
 /** Encoder output packet */
 struct encoder_packet {
   uint8_t *data; /**< Packet data */
@@ -60,6 +83,7 @@ public:
   DataFormat dataFormat_;
   amf::AMFComponentPtr AMFEncoder_ = NULL;
   amf::AMFContextPtr AMFContext_ = NULL;
+  bool force_idr_ = false;
 
 private:
   // system
@@ -77,8 +101,12 @@ private:
   int32_t frameRate_;
   int32_t gop_;
   bool enable4K_ = false;
+  EncodeOptions options_ = {};
+  int64_t allocated_ = 0;
   bool full_range_ = false;
   bool bt709_ = false;
+  GpuTimer timer_;
+  int64_t frame_ = 0;
 
   // Buffers
   std::vector<uint8_t> packetDataBuffer_;
@@ -86,7 +114,8 @@ private:
 public:
   AMFEncoder(void *handle, amf::AMF_MEMORY_TYPE memoryType, amf_wstring codec,
              DataFormat dataFormat, int32_t width, int32_t height,
-             int32_t bitrate, int32_t framerate, int32_t gop) {
+             int32_t bitrate, int32_t framerate, int32_t gop,
+             const EncodeOptions *options) {
     handle_ = handle;
     dataFormat_ = dataFormat;
     AMFMemoryType_ = memoryType;
@@ -96,24 +125,32 @@ public:
     frameRate_ = framerate;
     gop_ = (gop > 0 && gop < MAX_GOP) ? gop : MAX_GOP;
     enable4K_ = width > 1920 && height > 1080;
+    if (options)
+      options_ = *options;
   }
 
+  bool IsDeviceLost() { return is_device_lost((ID3D11Device *)handle_); }
+
   ~AMFEncoder() {}
 
-  AMF_RESULT encode(void *tex, EncodeCallback callback, void *obj, int64_t ms) {
+  AMF_RESULT encode(void *tex, EncodeCallback callback, void *obj, int64_t ms,
+                    uint64_t user_data) {
     amf::AMFSurfacePtr surface = NULL;
     amf::AMFComputeSyncPointPtr pSyncPoint = NULL;
     AMF_RESULT res;
     bool encoded = false;
 
+    timer_.Begin(frame_++, user_data);
     switch (AMFMemoryType_) {
     case amf::AMF_MEMORY_DX11:
       // https://github.com/GPUOpen-LibrariesAndSDKs/AMF/issues/280
       // AMF will not copy the surface during the CreateSurfaceFromDX11Native
       // call
-      res = AMFContext_->CreateSurfaceFromDX11Native(tex, &surface, NULL);
-      AMF_CHECK_RETURN(res, "CreateSurfaceFromDX11Native failed");
       {
+        // the copy runs on the immediate context other codecs may share
+        DeviceLock lock((ID3D11Device *)handle_);
+        res = AMFContext_->CreateSurfaceFromDX11Native(tex, &surface, NULL);
+        AMF_CHECK_RETURN(res, "CreateSurfaceFromDX11Native failed");
         amf::AMFDataPtr data1;
         surface->Duplicate(surface->GetMemoryType(), &data1);
         surface = amf::AMFSurfacePtr(data1);
@@ -124,12 +161,29 @@ public:
       return AMF_NOT_IMPLEMENTED;
       break;
     }
+    timer_.Mark();
     surface->SetPts(ms * AMF_MILLISECOND);
+    // the component copies the properties of a surface to its output
+    surface->SetProperty(USER_DATA_PROPERTY, (amf_int64)user_data);
+    if (force_idr_) {
+      if (dataFormat_ == H265) {
+        surface->SetProperty(AMF_VIDEO_ENCODER_HEVC_FORCE_PICTURE_TYPE,
+                             AMF_VIDEO_ENCODER_HEVC_PICTURE_TYPE_IDR);
+        surface->SetProperty(AMF_VIDEO_ENCODER_HEVC_INSERT_HEADER, true);
+      } else {
+        surface->SetProperty(AMF_VIDEO_ENCODER_FORCE_PICTURE_TYPE,
+                             AMF_VIDEO_ENCODER_PICTURE_TYPE_IDR);
+        surface->SetProperty(AMF_VIDEO_ENCODER_INSERT_SPS, true);
+        surface->SetProperty(AMF_VIDEO_ENCODER_INSERT_PPS, true);
+      }
+    }
     res = AMFEncoder_->SubmitInput(surface);
     AMF_CHECK_RETURN(res, "SubmitInput failed");
+    force_idr_ = false;
 
     amf::AMFDataPtr data = NULL;
     res = AMFEncoder_->QueryOutput(&data);
+    timer_.End();
     if (res == AMF_OK && data != NULL) {
       struct encoder_packet packet;
       PacketKeyframe(data, &packet);
@@ -142,8 +196,11 @@ public:
         }
         packet.data = packetDataBuffer_.data();
         std::memcpy(packet.data, pBuffer->GetNative(), packet.size);
+        amf_int64 packetUserData = (amf_int64)user_data;
+        data->GetProperty(USER_DATA_PROPERTY, &packetUserData);
         if (callback)
-          callback(packet.data, packet.size, packet.keyframe, obj, ms);
+          callback(packet.data, packet.size, PacketFlags(data, packet), obj, ms,
+                   (uint64_t)packetUserData);
         encoded = true;
       }
       pBuffer = NULL;
@@ -167,7 +224,106 @@ public:
     return AMF_OK;
   }
 
-  AMF_RESULT test() {
+  void info(RuntimeInfo *info) {
+    *info = {};
+    snprintf(info->runtime, sizeof(info->runtime), "AMF");
+    amf_uint64 version = AMFFactory_.AMFQueryVersion();
+    info->apiMajor = AMF_GET_MAJOR_VERSION(version);
+    info->apiMinor = AMF_GET_MINOR_VERSION(version);
+    // the pattern is h264 only, hevc has no b-frames
+    amf_int64 pattern = 0;
+    if (AMFEncoder_ && codec_ == amf_wstring(AMFVideoEncoderVCE_AVC) &&
+        AMFEncoder_->GetProperty(AMF_VIDEO_ENCODER_B_PIC_PATTERN, &pattern) ==
+            AMF_OK)
+      info->bframes = (int32_t)pattern;
+  }
+
+  bool gpu_timing(GpuTiming *timing) { return timer_.Collect(timing); }
+
+  void caps(EncodeCaps *caps) {
+    *caps = {};
+    amf::AMFCapsPtr encoderCaps;
+    if (!AMFEncoder_ || AMFEncoder_->GetCaps(&encoderCaps) != AMF_OK)
+      return;
+    amf_int64 level = 0;
+    bool value = false;
+    switch (dataFormat_) {
+    case H264:
+      // AMF_H264_LEVEL values are level_idc
+      encoderCaps->GetProperty(AMF_VIDEO_ENCODER_CAP_MAX_LEVEL, &level);
+      if (encoderCaps->GetProperty(AMF_VIDEO_ENCODER_CAP_BFRAMES, &value) ==
+              AMF_OK &&
+          value)
+        caps->flags |= ENCODE_CAP_BFRAMES;
+      break;
+    case H265:
+      // AMF_HEVC_LEVEL values are general_level_idc
+      encoderCaps->GetProperty(AMF_VIDEO_ENCODER_HEVC_CAP_MAX_LEVEL, &level);
+      if (encoderCaps->GetProperty(AMF_VIDEO_ENCODER_HEVC_CAP_SUPPORT_10BIT_DEPTH,
+                                   &value) == AMF_OK &&
+          value)
+        caps->flags |= ENCODE_CAP_10BIT;
+      break;
+    default:
+      break;
+    }
+    caps->maxLevel = (int32_t)level;
+    amf::AMFIOCapsPtr inputCaps;
+    if (encoderCaps->GetInputCaps(&inputCaps) == AMF_OK) {
+      amf_int32 minValue = 0, maxValue = 0;
+      inputCaps->GetWidthRange(&minValue, &maxValue);
+      caps->maxWidth = maxValue;
+      inputCaps->GetHeightRange(&minValue, &maxValue);
+      caps->maxHeight = maxValue;
+    }
+  }
+
+  // the configuration against the caps of the opened component, false with
+  // the first parameter out of them in check
+  bool check_config(ConfigCheck *check) {
+    auto reject = [&](ConfigParam param, bool hasCorrected, int32_t corrected) {
+      check->param = param;
+      check->hasCorrected = hasCorrected ? 1 : 0;
+      check->corrected = corrected;
+      return false;
+    };
+    amf::AMFCapsPtr encoderCaps;
+    if (AMFEncoder_->GetCaps(&encoderCaps) != AMF_OK)
+      return reject(CONFIG_PARAM_NONE, false, 0);
+    amf::AMFIOCapsPtr inputCaps;
+    if (encoderCaps->GetInputCaps(&inputCaps) == AMF_OK) {
+      amf_int32 minWidth = 0, maxWidth = 0, minHeight = 0, maxHeight = 0;
+      inputCaps->GetWidthRange(&minWidth, &maxWidth);
+      inputCaps->GetHeightRange(&minHeight, &maxHeight);
+      if (maxWidth > 0 && resolution_.first > maxWidth)
+        return reject(CONFIG_PARAM_WIDTH, true, maxWidth);
+      if (maxHeight > 0 && resolution_.second > maxHeight)
+        return reject(CONFIG_PARAM_HEIGHT, true, maxHeight);
+      if (resolution_.first < minWidth)
+        return reject(CONFIG_PARAM_WIDTH, true, minWidth);
+      if (resolution_.second < minHeight)
+        return reject(CONFIG_PARAM_HEIGHT, true, minHeight);
+    }
+    // bits per second
+    amf_int64 maxBitrate = 0;
+    encoderCaps->GetProperty(dataFormat_ == H265
+                                 ? AMF_VIDEO_ENCODER_HEVC_CAP_MAX_BITRATE
+                                 : AMF_VIDEO_ENCODER_CAP_MAX_BITRATE,
+                             &maxBitrate);
+    if (maxBitrate > 0 && bitRateIn_ > maxBitrate)
+      return reject(CONFIG_PARAM_BITRATE, true, (int32_t)(maxBitrate / 1000));
+    // SetParams ignores these
+    if (options_.aqMode == AQ_TEMPORAL)
+      return reject(CONFIG_PARAM_AQ_MODE, true, AQ_DEFAULT);
+    if (options_.aqStrength > 0)
+      return reject(CONFIG_PARAM_AQ_STRENGTH, true, 0);
+    if (options_.chromaLocation != CHROMA_LOC_LEFT)
+      return reject(CONFIG_PARAM_CHROMA_LOCATION, true, CHROMA_LOC_LEFT);
+    return true;
+  }
+
+  // the packet goes to callback with luid as its user_data, see TestOutput
+  AMF_RESULT test(EncodeCallback callback, void *obj, int64_t luid) {
     AMF_RESULT res = AMF_OK;
     amf::AMFSurfacePtr surface = nullptr;
     res = AMFContext_->AllocSurface(AMFMemoryType_, AMFSurfaceFormat_,
@@ -179,19 +335,22 @@ public:
     void *native = surface->GetPlaneAt(0)->GetNative();
     if (!native)
       return AMF_FAIL;
-    int32_t key_obj = 0;
+    util_encode::TestOutput out = {0, callback, obj, luid};
     auto start = util::now();
-    res = encode(native, util_encode::vram_encode_test_callback, &key_obj, 0);
+    res = encode(native, util_encode::vram_encode_test_callback, &out, 0, 0);
     int64_t elapsed = util::elapsed_ms(start);
-    if (res == AMF_OK && key_obj == 1 && elapsed < TEST_TIMEOUT_MS) {
+    if (res == AMF_OK && out.key == 1 && elapsed < TEST_TIMEOUT_MS) {
       return AMF_OK;
     }
     return AMF_FAIL;
   }
 
-  AMF_RESULT initialize() {
+  // up to the encoder component, its caps can be queried before SetParams
+  AMF_RESULT open() {
     AMF_RESULT res;
 
+    if (module_hidden(AMF_DLL_NAMEA))
+      return AMF_NOT_FOUND;
     res = AMFFactory_.Init();
     if (res != AMF_OK) {
       std::cerr << "AMF init failed, error code = " << res << "\n";
@@ -207,6 +366,8 @@ public:
 
     switch (AMFMemoryType_) {
     case amf::AMF_MEMORY_DX11:
+      if (!set_multithread_protected((ID3D11Device *)handle_))
+        return AMF_FAIL;
       res = AMFContext_->InitDX11(handle_); // can be DX11 device
       AMF_CHECK_RETURN(res, "InitDX11 failed");
       break;
@@ -219,6 +380,13 @@ public:
     res = AMFFactory_.GetFactory()->CreateComponent(AMFContext_, codec_.c_str(),
                                                     &AMFEncoder_);
     AMF_CHECK_RETURN(res, "CreateComponent failed");
+    return AMF_OK;
+  }
+
+  AMF_RESULT initialize() {
+    AMF_RESULT res = open();
+    if (res != AMF_OK)
+      return res;
 
     res = SetParams(codec_);
     AMF_CHECK_RETURN(res, "Could not set params in encoder.");
@@ -227,17 +395,42 @@ public:
                             resolution_.second);
     AMF_CHECK_RETURN(res, "encoder->Init() failed");
 
+    if (options_.gpuTiming && !timer_.Init((ID3D11Device *)handle_))
+      LOG_WARN("gpu timing unavailable");
+
     return AMF_OK;
   }
 
+  // after set_bitrate or set_framerate, 0 for unchanged. Tune::CloudGaming
+  // keeps the vbv at one frame.
+  AMF_RESULT RateChanged(int32_t bitrate, int32_t framerate) {
+    if (bitrate > 0)
+      bitRateIn_ = bitrate;
+    if (framerate > 0)
+      frameRate_ = framerate;
+    return options_.cloudGaming ? SetOneFrameVBV() : AMF_OK;
+  }
+
 private:
+  // without aspect_ratio_info decoders take square samples
+  bool HasSar() const {
+    return options_.sarWidth > 0 && options_.sarHeight > 0 &&
+           options_.sarWidth != options_.sarHeight;
+  }
+
   AMF_RESULT SetParams(const amf_wstring &codecStr) {
     AMF_RESULT res;
+    // amf writes no chroma_loc_info, decoders then assume left
+    if (options_.chromaLocation != CHROMA_LOC_LEFT) {
+      LOG_WARN("chroma location not supported, left is signaled");
+    }
     if (codecStr == amf_wstring(AMFVideoEncoderVCE_AVC)) {
       // ------------- Encoder params usage---------------
       res = AMFEncoder_->SetProperty(
           AMF_VIDEO_ENCODER_USAGE,
-          AMF_VIDEO_ENCODER_USAGE_LOW_LATENCY_HIGH_QUALITY);
+          options_.cloudGaming
+              ? AMF_VIDEO_ENCODER_USAGE_ULTRA_LOW_LATENCY
+              : AMF_VIDEO_ENCODER_USAGE_LOW_LATENCY_HIGH_QUALITY);
       AMF_CHECK_RETURN(res, "SetProperty AMF_VIDEO_ENCODER_USAGE failed");
 
       // ------------- Encoder params static---------------
@@ -251,18 +444,30 @@ private:
       res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_LOWLATENCY_MODE, true);
       AMF_CHECK_RETURN(res,
                        "SetProperty AMF_VIDEO_ENCODER_LOWLATENCY_MODE failed");
-      res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_QUALITY_PRESET,
-                                     AMF_VIDEO_ENCODER_QUALITY_PRESET_QUALITY);
+      res = AMFEncoder_->SetProperty(
+          AMF_VIDEO_ENCODER_QUALITY_PRESET,
+          options_.cloudGaming ? AMF_VIDEO_ENCODER_QUALITY_PRESET_SPEED
+                               : AMF_VIDEO_ENCODER_QUALITY_PRESET_QUALITY);
       AMF_CHECK_RETURN(res,
                        "SetProperty AMF_VIDEO_ENCODER_QUALITY_PRESET failed");
       res =
           AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_COLOR_BIT_DEPTH, eDepth_);
       AMF_CHECK_RETURN(res,
                        "SetProperty(AMF_VIDEO_ENCODER_COLOR_BIT_DEPTH  failed");
-      res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_RATE_CONTROL_METHOD,
-                                     AMF_VIDEO_ENCODER_RATE_CONTROL_METHOD_CBR);
+      res = AMFEncoder_->SetProperty(
+          AMF_VIDEO_ENCODER_RATE_CONTROL_METHOD,
+          options_.quality > 0
+              ? AMF_VIDEO_ENCODER_RATE_CONTROL_METHOD_QUALITY_VBR
+              : AMF_VIDEO_ENCODER_RATE_CONTROL_METHOD_CBR);
       AMF_CHECK_RETURN(res,
                        "SetProperty AMF_VIDEO_ENCODER_RATE_CONTROL_METHOD");
+      res = AMFEncoder_->SetProperty(
+          AMF_VIDEO_ENCODER_CABAC_ENABLE,
+          options_.entropyCoding == ENTROPY_CODING_CAVLC
+              ? AMF_VIDEO_ENCODER_CALV
+              : AMF_VIDEO_ENCODER_CABAC);
+      AMF_CHECK_RETURN(res,
+                       "SetProperty AMF_VIDEO_ENCODER_CABAC_ENABLE failed");
       if (enable4K_) {
         res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_PROFILE,
                                        AMF_VIDEO_ENCODER_PROFILE_HIGH);
@@ -297,6 +502,12 @@ private:
           bt709_ ? AMF_COLOR_PRIMARIES_BT709 : AMF_COLOR_PRIMARIES_SMPTE170M);
       AMF_CHECK_RETURN(res,
                        "SetProperty AMF_VIDEO_ENCODER_OUTPUT_COLOR_PRIMARIES");
+      if (HasSar()) {
+        res = AMFEncoder_->SetProperty(
+            AMF_VIDEO_ENCODER_ASPECT_RATIO,
+            ::AMFConstructRatio(options_.sarWidth, options_.sarHeight));
+        AMF_CHECK_RETURN(res, "SetProperty AMF_VIDEO_ENCODER_ASPECT_RATIO");
+      }
 
       // ------------- Encoder params dynamic ---------------
       AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_B_PIC_PATTERN, 0);
@@ -307,22 +518,42 @@ private:
                                      query_timeout_); // ms
       AMF_CHECK_RETURN(res,
                        "SetProperty AMF_VIDEO_ENCODER_QUERY_TIMEOUT failed");
-      res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_TARGET_BITRATE,
-                                     bitRateIn_);
-      AMF_CHECK_RETURN(res,
-                       "SetProperty AMF_VIDEO_ENCODER_TARGET_BITRATE failed");
+      if (options_.quality > 0) {
+        res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_QVBR_QUALITY_LEVEL,
+                                       options_.quality);
+        AMF_CHECK_RETURN(
+            res, "SetProperty AMF_VIDEO_ENCODER_QVBR_QUALITY_LEVEL failed");
+      } else {
+        res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_TARGET_BITRATE,
+                                       bitRateIn_);
+        AMF_CHECK_RETURN(res,
+                         "SetProperty AMF_VIDEO_ENCODER_TARGET_BITRATE failed");
+      }
       res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_FRAMERATE,
                                      ::AMFConstructRate(frameRate_, 1));
       AMF_CHECK_RETURN(res, "SetProperty AMF_VIDEO_ENCODER_FRAMERATE failed");
 
-      res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_IDR_PERIOD, gop_);
+      // 0 is the first frame only
+      res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_IDR_PERIOD,
+                                     options_.cloudGaming ? 0 : gop_);
       AMF_CHECK_RETURN(res, "SetProperty AMF_VIDEO_ENCODER_IDR_PERIOD failed");
 
+      SetAQ(AMF_VIDEO_ENCODER_ENABLE_VBAQ);
+      SetSceneCut(AMF_VIDEO_ENCODER_PRE_ANALYSIS_ENABLE);
+      if (options_.cloudGaming) {
+        res = SetCloudGaming(AMF_VIDEO_ENCODER_INTRA_REFRESH_NUM_MBS_PER_SLOT,
+                             (resolution_.first + 15) / 16,
+                             AMF_VIDEO_ENCODER_SLICES_PER_FRAME);
+        AMF_CHECK_RETURN(res, "SetCloudGaming failed");
+      }
+
     } else if (codecStr == amf_wstring(AMFVideoEncoder_HEVC)) {
       // ------------- Encoder params usage---------------
       res = AMFEncoder_->SetProperty(
           AMF_VIDEO_ENCODER_HEVC_USAGE,
-          AMF_VIDEO_ENCODER_HEVC_USAGE_LOW_LATENCY_HIGH_QUALITY);
+          options_.cloudGaming
+              ? AMF_VIDEO_ENCODER_HEVC_USAGE_ULTRA_LOW_LATENCY
+              : AMF_VIDEO_ENCODER_HEVC_USAGE_LOW_LATENCY_HIGH_QUALITY);
       AMF_CHECK_RETURN(res, "SetProperty AMF_VIDEO_ENCODER_HEVC_USAGE failed");
 
       // ------------- Encoder params static---------------
@@ -339,7 +570,8 @@ private:
 
       res = AMFEncoder_->SetProperty(
           AMF_VIDEO_ENCODER_HEVC_QUALITY_PRESET,
-          AMF_VIDEO_ENCODER_HEVC_QUALITY_PRESET_QUALITY);
+          options_.cloudGaming ? AMF_VIDEO_ENCODER_HEVC_QUALITY_PRESET_SPEED
+                               : AMF_VIDEO_ENCODER_HEVC_QUALITY_PRESET_QUALITY);
       AMF_CHECK_RETURN(
           res, "SetProperty AMF_VIDEO_ENCODER_HEVC_QUALITY_PRESET failed");
 
@@ -350,7 +582,9 @@ private:
 
       res = AMFEncoder_->SetProperty(
           AMF_VIDEO_ENCODER_HEVC_RATE_CONTROL_METHOD,
-          AMF_VIDEO_ENCODER_HEVC_RATE_CONTROL_METHOD_CBR);
+          options_.quality > 0
+              ? AMF_VIDEO_ENCODER_HEVC_RATE_CONTROL_METHOD_QUALITY_VBR
+              : AMF_VIDEO_ENCODER_HEVC_RATE_CONTROL_METHOD_CBR);
       AMF_CHECK_RETURN(
           res, "SetProperty AMF_VIDEO_ENCODER_HEVC_RATE_CONTROL_METHOD failed");
 
@@ -393,6 +627,13 @@ private:
       AMF_CHECK_RETURN(
           res,
           "SetProperty AMF_VIDEO_ENCODER_HEVC_OUTPUT_COLOR_PRIMARIES failed");
+      if (HasSar()) {
+        res = AMFEncoder_->SetProperty(
+            AMF_VIDEO_ENCODER_HEVC_ASPECT_RATIO,
+            ::AMFConstructRatio(options_.sarWidth, options_.sarHeight));
+        AMF_CHECK_RETURN(res,
+                         "SetProperty AMF_VIDEO_ENCODER_HEVC_ASPECT_RATIO");
+      }
 
       // ------------- Encoder params dynamic ---------------
       res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_HEVC_QUERY_TIMEOUT,
@@ -400,10 +641,18 @@ private:
       AMF_CHECK_RETURN(
           res, "SetProperty(AMF_VIDEO_ENCODER_HEVC_QUERY_TIMEOUT failed");
 
-      res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_HEVC_TARGET_BITRATE,
-                                     bitRateIn_);
-      AMF_CHECK_RETURN(
-          res, "SetProperty AMF_VIDEO_ENCODER_HEVC_TARGET_BITRATE failed");
+      if (options_.quality > 0) {
+        res = AMFEncoder_->SetProperty(
+            AMF_VIDEO_ENCODER_HEVC_QVBR_QUALITY_LEVEL, options_.quality);
+        AMF_CHECK_RETURN(
+            res,
+            "SetProperty AMF_VIDEO_ENCODER_HEVC_QVBR_QUALITY_LEVEL failed");
+      } else {
+        res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_HEVC_TARGET_BITRATE,
+                                       bitRateIn_);
+        AMF_CHECK_RETURN(
+            res, "SetProperty AMF_VIDEO_ENCODER_HEVC_TARGET_BITRATE failed");
+      }
 
       res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_HEVC_FRAMERATE,
                                      ::AMFConstructRate(frameRate_, 1));
@@ -411,15 +660,114 @@ private:
                        "SetProperty AMF_VIDEO_ENCODER_HEVC_FRAMERATE failed");
 
       res = AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_HEVC_GOP_SIZE,
-                                     gop_); // todo
+                                     options_.cloudGaming ? 0
+                                                          : gop_); // todo
       AMF_CHECK_RETURN(res,
                        "SetProperty AMF_VIDEO_ENCODER_HEVC_GOP_SIZE failed");
+
+      SetAQ(AMF_VIDEO_ENCODER_HEVC_ENABLE_VBAQ);
+      SetSceneCut(AMF_VIDEO_ENCODER_HEVC_PRE_ANALYSIS_ENABLE);
+      if (options_.cloudGaming) {
+        res = SetCloudGaming(
+            AMF_VIDEO_ENCODER_HEVC_INTRA_REFRESH_NUM_CTBS_PER_SLOT,
+            (resolution_.first + 63) / 64,
+            AMF_VIDEO_ENCODER_HEVC_SLICES_PER_FRAME);
+        AMF_CHECK_RETURN(res, "SetCloudGaming failed");
+      }
     } else {
       return AMF_FAIL;
     }
     return AMF_OK;
   }
 
+  // vbaq is the only aq amf exposes without pre-analysis, failures are not fatal
+  void SetAQ(const wchar_t *vbaqProperty) {
+    AMF_RESULT res = AMF_OK;
+    switch (options_.aqMode) {
+    case AQ_OFF:
+      res = AMFEncoder_->SetProperty(vbaqProperty, false);
+      break;
+    case AQ_SPATIAL:
+      res = AMFEncoder_->SetProperty(vbaqProperty, true);
+      break;
+    case AQ_TEMPORAL:
+      LOG_WARN("temporal aq not supported, ignored");
+      break;
+    default:
+      break;
+    }
+    if (res != AMF_OK) {
+      LOG_WARN(std::string("SetProperty vbaq failed, result code: ") +
+               std::to_string(int(res)));
+    }
+    if (options_.aqStrength > 0) {
+      LOG_WARN("aq strength not supported, ignored");
+    }
+  }
+
+  // Tune::CloudGaming on top of the ultra low latency usage, a row of blocks
+  // is refreshed per frame. SetSceneCut already turned pre-analysis off.
+  AMF_RESULT SetCloudGaming(const wchar_t *refreshProperty,
+                            int32_t blocksPerRow,
+                            const wchar_t *slicesProperty) {
+    AMF_RESULT res = SetOneFrameVBV();
+    if (res != AMF_OK)
+      return res;
+    res = AMFEncoder_->SetProperty(refreshProperty, blocksPerRow);
+    if (res != AMF_OK)
+      return res;
+    return AMFEncoder_->SetProperty(slicesProperty, 4);
+  }
+
+  // a frame can't take more than its share of the bitrate
+  AMF_RESULT SetOneFrameVBV() {
+    // qvbr has no bitrate to split
+    if (options_.quality > 0)
+      return AMF_OK;
+    int32_t bits = bitRateIn_ / std::max(frameRate_, 1);
+    if (dataFormat_ == H265) {
+      AMF_RESULT res = AMFEncoder_->SetProperty(
+          AMF_VIDEO_ENCODER_HEVC_VBV_BUFFER_SIZE, bits);
+      if (res != AMF_OK)
+        return res;
+      return AMFEncoder_->SetProperty(
+          AMF_VIDEO_ENCODER_HEVC_INITIAL_VBV_BUFFER_FULLNESS, 64);
+    }
+    AMF_RESULT res =
+        AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_VBV_BUFFER_SIZE, bits);
+    if (res != AMF_OK)
+      return res;
+    return AMFEncoder_->SetProperty(
+        AMF_VIDEO_ENCODER_INITIAL_VBV_BUFFER_FULLNESS, 64);
+  }
+
+  // amf detects scene cuts in pre-analysis, off unless a driver default turns
+  // it on. Without it there are no inserted keyframes to keep apart either.
+  void SetSceneCut(const wchar_t *preAnalysisProperty) {
+    if (!options_.noSceneCutKeyframes)
+      return;
+    AMF_RESULT res = AMFEncoder_->SetProperty(preAnalysisProperty, false);
+    if (res != AMF_OK) {
+      LOG_WARN(std::string("SetProperty pre analysis failed, result code: ") +
+               std::to_string(int(res)));
+    }
+  }
+
+  // the FrameFlag bits of an output, long term references only with ltr enabled
+  int32_t PacketFlags(amf::AMFDataPtr &pData,
+                      const struct encoder_packet &packet) {
+    int32_t flags = packet.keyframe ? FRAME_FLAG_KEYFRAME : 0;
+    amf_int64 ltr = -1;
+    pData->GetProperty(AMFVideoEncoder_HEVC == codec_
+                           ? AMF_VIDEO_ENCODER_HEVC_OUTPUT_MARKED_LTR_INDEX
+                           : AMF_VIDEO_ENCODER_OUTPUT_MARKED_LTR_INDEX,
+                       &ltr);
+    if (ltr >= 0)
+      flags |= FRAME_FLAG_LTR_MARKED |
+               (int32_t)(ltr & 0xff) << FRAME_FLAG_LTR_SLOT_SHIFT;
+    return flags;
+  }
+
   void PacketKeyframe(amf::AMFDataPtr &pData, struct encoder_packet *packet) {
     if (AMFVideoEncoderVCE_AVC == codec_) {
       uint64_t pktType;
@@ -471,7 +819,8 @@ int amf_destroy_encoder(void *encoder) {
 
 void *amf_new_encoder(void *handle, int64_t luid,
                       DataFormat dataFormat, int32_t width, int32_t height,
-                      int32_t kbs, int32_t framerate, int32_t gop) {
+                      int32_t kbs, int32_t framerate, int32_t gop,
+                      const EncodeOptions *options) {
   AMFEncoder *enc = NULL;
   try {
     amf_wstring codecStr;
@@ -482,10 +831,13 @@ void *amf_new_encoder(void *handle, int64_t luid,
     if (!convert_api(memoryType)) {
       return NULL;
     }
+    int64_t usage = video_memory_usage((ID3D11Device *)handle);
     enc = new AMFEncoder(handle, memoryType, codecStr, dataFormat, width,
-                         height, kbs * 1000, framerate, gop);
+                         height, kbs * 1000, framerate, gop, options);
     if (enc) {
       if (AMF_OK == enc->initialize()) {
+        usage = video_memory_usage((ID3D11Device *)handle) - usage;
+        enc->allocated_ = usage > 0 ? usage : 0;
         return enc;
       }
     }
@@ -501,33 +853,62 @@ void *amf_new_encoder(void *handle, int64_t luid,
 }
 
 int amf_encode(void *encoder, void *tex, EncodeCallback callback, void *obj,
-               int64_t ms) {
+               int64_t ms, uint64_t user_data) {
+  AMFEncoder *enc = (AMFEncoder *)encoder;
   try {
-    AMFEncoder *enc = (AMFEncoder *)encoder;
-    return -enc->encode(tex, callback, obj, ms);
+    if (enc->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    int ret = check_input_texture((ID3D11Device *)enc->handle_,
+                                  (ID3D11Texture2D *)tex);
+    if (ret != 0)
+      return ret;
+    ret = to_errno(enc->encode(tex, callback, obj, ms, user_data));
+    if (ret != 0 && enc->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    return ret;
   } catch (const std::exception &e) {
           LOG_ERROR(std::string("encode failed: ") + e.what());
   }
+  if (enc->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
   return -1;
 }
 
-int amf_driver_support() {
+// The runtime is loaded by AMFFactoryHelper, not linked. It asks for the
+// version of the sdk headers, runtimes older than that may still take it.
+int amf_driver_support(DriverProbe *probe) {
+  *probe = {};
+  snprintf(probe->name, sizeof(probe->name), "AMF runtime");
+  snprintf(probe->required, sizeof(probe->required), "%d.%d.%d",
+           AMF_VERSION_MAJOR, AMF_VERSION_MINOR, AMF_VERSION_RELEASE);
+  if (module_hidden(AMF_DLL_NAMEA))
+    return -1;
   try {
     AMFFactoryHelper factory;
     AMF_RESULT res = factory.Init();
+    // known once the dll is loaded, whether the init passed or not
+    amf_uint64 version = factory.AMFQueryVersion();
+    if (version != 0)
+      snprintf(probe->installed, sizeof(probe->installed), "%d.%d.%d",
+               (int)AMF_GET_MAJOR_VERSION(version),
+               (int)AMF_GET_MINOR_VERSION(version),
+               (int)AMF_GET_SUBMINOR_VERSION(version));
     if (res == AMF_OK) {
       factory.Terminate();
       return 0;
     }
+    probe->tooOld = version != 0 && version < AMF_FULL_VERSION;
   } catch (const std::exception &e) {
   }
   return -1;
 }
 
-int amf_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
+int amf_test_encode(int64_t *outLuids, int32_t *outVendors,
+                    EncodeCaps *outCaps, int32_t maxDescNum, int32_t *outDescNum,
                     DataFormat dataFormat, int32_t width,
                     int32_t height, int32_t kbs, int32_t framerate,
-                    int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount) {
+                    int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount,
+                    EncodeCallback callback, void *obj) {
   try {
     Adapters adapters;
     if (!adapters.Init(ADAPTER_VENDOR_AMD))
@@ -541,12 +922,13 @@ int amf_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum,
       
       AMFEncoder *e = (AMFEncoder *)amf_new_encoder(
           (void *)adapter.get()->device_.Get(), currentLuid,
-          dataFormat, width, height, kbs, framerate, gop);
+          dataFormat, width, height, kbs, framerate, gop, nullptr);
       if (!e)
         continue;
-      if (e->test() == AMF_OK) {
+      if (e->test(callback, obj, currentLuid) == AMF_OK) {
         outLuids[count] = currentLuid;
         outVendors[count] = VENDOR_AMD;
+        e->caps(&outCaps[count]);
         count += 1;
       }
       e->destroy();
@@ -564,6 +946,129 @@ int amf_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum,
   return -1;
 }
 
+int amf_encoder_memory(void *encoder, MemoryInfo *info) {
+  AMFEncoder *enc = (AMFEncoder *)encoder;
+  estimate_encoder_memory(enc->dataFormat_, enc->resolution_.first,
+                          enc->resolution_.second, AMF_INPUT_BGRA_SURFACES,
+                          AMF_INPUT_NV12_SURFACES, info);
+  info->allocated = enc->allocated_;
+  return 0;
+}
+
+int amf_encoder_info(void *encoder, RuntimeInfo *info) {
+  AMFEncoder *enc = (AMFEncoder *)encoder;
+  enc->info(info);
+  return 0;
+}
+
+int amf_encoder_gpu_timings(void *encoder, GpuTiming *timings, int32_t max,
+                            int32_t *count) {
+  AMFEncoder *enc = (AMFEncoder *)encoder;
+  *count = 0;
+  while (*count < max && enc->gpu_timing(&timings[*count]))
+    (*count)++;
+  return 0;
+}
+
+// the output of a frame is queried until it arrives, nothing stays buffered
+int amf_encoder_flush(void *encoder, EncodeCallback callback, void *obj) {
+  return 0;
+}
+
+// no emphasis maps, the caps never report ENCODE_CAP_EMPHASIS_MAP
+int amf_encoder_set_emphasis_map(void *encoder, const uint8_t *map,
+                                 int32_t len) {
+  return HWCODEC_ERR_COMMON;
+}
+
+// no per frame qp offset under cbr
+int amf_encoder_set_qp_delta(void *encoder, int32_t delta) {
+  return HWCODEC_ERR_COMMON;
+}
+
+// the long term references would have to be marked while encoding, the caps
+// never report ENCODE_CAP_REF_INVALIDATION
+int amf_encoder_invalidate_reference(void *encoder, int64_t ms) {
+  return HWCODEC_ERR_COMMON;
+}
+
+int amf_estimate_encoder_memory(int32_t dataFormat, int32_t width,
+                                int32_t height, MemoryInfo *info) {
+  estimate_encoder_memory((DataFormat)dataFormat, width, height,
+                          AMF_INPUT_BGRA_SURFACES, AMF_INPUT_NV12_SURFACES,
+                          info);
+  info->allocated = 0;
+  return 0;
+}
+
+// Creates the encoder component for its caps without initializing it. 0 when
+// the configuration is within them.
+int amf_check_encoder_config(void *handle, int64_t luid, DataFormat dataFormat,
+                             int32_t width, int32_t height, int32_t kbs,
+                             int32_t framerate, int32_t gop,
+                             const EncodeOptions *options, ConfigCheck *check) {
+  *check = {};
+  AMFEncoder *enc = NULL;
+  int ret = HWCODEC_ERR_COMMON;
+  try {
+    amf_wstring codecStr;
+    amf::AMF_MEMORY_TYPE memoryType;
+    if (!convert_codec(dataFormat, codecStr)) {
+      check->param = CONFIG_PARAM_FORMAT;
+      return ret;
+    }
+    if (!convert_api(memoryType))
+      return ret;
+    enc = new AMFEncoder(handle, memoryType, codecStr, dataFormat, width,
+                         height, kbs * 1000, framerate, gop, options);
+    AMF_RESULT res = enc->open();
+    if (res == AMF_OK) {
+      ret = enc->check_config(check) ? HWCODEC_SUCCESS : HWCODEC_ERR_COMMON;
+    } else if (res == AMF_CODEC_NOT_SUPPORTED) {
+      check->param = CONFIG_PARAM_FORMAT;
+    }
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("check config failed: ") + e.what());
+  }
+  if (enc) {
+    enc->destroy();
+    delete enc;
+  }
+  return ret;
+}
+
+int amf_check_encoder(void *encoder) {
+  AMFEncoder *enc = (AMFEncoder *)encoder;
+  try {
+    if (enc->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    if (!enc->AMFContext_ || !enc->AMFEncoder_ ||
+        !enc->AMFContext_->GetDX11Device())
+      return HWCODEC_ERR_SESSION_LOST;
+    AMFRate rate;
+    AMF_RESULT res = enc->AMFEncoder_->GetProperty(
+        enc->dataFormat_ == H265 ? AMF_VIDEO_ENCODER_HEVC_FRAMERATE
+                                 : AMF_VIDEO_ENCODER_FRAMERATE,
+        &rate);
+    if (res != AMF_OK)
+      LOG_ERROR(std::string("check encoder failed, result code: ") +
+                std::to_string(int(res)));
+    return to_errno(res);
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("check encoder failed: ") + e.what());
+  }
+  if (enc->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
+  return HWCODEC_ERR_COMMON;
+}
+
+// the next encoded frame is an IDR with the parameter sets
+int amf_request_keyframe(void *encoder) {
+  AMFEncoder *enc = (AMFEncoder *)encoder;
+  enc->force_idr_ = true;
+  return 0;
+}
+
 int amf_set_bitrate(void *encoder, int32_t kbs) {
   try {
     AMFEncoder *enc = (AMFEncoder *)encoder;
@@ -578,6 +1083,8 @@ int amf_set_bitrate(void *encoder, int32_t kbs) {
                                           kbs * 1000);
       break;
     }
+    if (res == AMF_OK)
+      res = enc->RateChanged(kbs * 1000, 0);
     return res == AMF_OK ? 0 : -1;
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("set bitrate to ") + std::to_string(kbs) +
@@ -600,6 +1107,8 @@ int amf_set_framerate(void *encoder, int32_t framerate) {
           enc->AMFEncoder_->SetProperty(AMF_VIDEO_ENCODER_HEVC_FRAMERATE, rate);
       break;
     }
+    if (res == AMF_OK)
+      res = enc->RateChanged(0, framerate);
     return res == AMF_OK ? 0 : -1;
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("set framerate to ") + std::to_string(framerate) +
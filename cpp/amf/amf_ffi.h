@@ -4,14 +4,24 @@
 #include "../common/callback.h"
 #include <stdbool.h>
 
-int amf_driver_support();
+struct EncodeOptions;
+struct MemoryInfo;
+struct EncodeCaps;
+struct DecodeCaps;
+struct RuntimeInfo;
+struct GpuTiming;
+struct ConfigCheck;
+struct DriverProbe;
+
+int amf_driver_support(struct DriverProbe *probe);
 
 void *amf_new_encoder(void *handle, int64_t luid,
                       int32_t data_format, int32_t width, int32_t height,
-                      int32_t bitrate, int32_t framerate, int32_t gop);
+                      int32_t bitrate, int32_t framerate, int32_t gop,
+                      const struct EncodeOptions *options);
 
 int amf_encode(void *encoder, void *texture, EncodeCallback callback, void *obj,
-               int64_t ms);
+               int64_t ms, uint64_t user_data);
 
 int amf_destroy_encoder(void *encoder);
 
@@ -23,15 +33,52 @@ int amf_decode(void *decoder, uint8_t *data, int32_t length,
 
 int amf_destroy_decoder(void *decoder);
 
-int amf_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
+int amf_decoder_memory(void *decoder, struct MemoryInfo *info);
+
+int amf_decoder_set_conceal(void *decoder, int32_t conceal);
+
+int amf_decoder_caps(void *decoder, struct DecodeCaps *caps);
+
+int amf_test_encode(int64_t *outLuids, int32_t *outVendors,
+                    struct EncodeCaps *outCaps, int32_t maxDescNum, int32_t *outDescNum,
                     int32_t dataFormat, int32_t width,
                     int32_t height, int32_t kbs, int32_t framerate,
-                    int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount);
+                    int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount,
+                    EncodeCallback callback, void *obj);
 
 int amf_test_decode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
                     int32_t dataFormat, uint8_t *data,
                     int32_t length, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount);
 
+int amf_check_encoder(void *encoder);
+
+int amf_encoder_memory(void *encoder, struct MemoryInfo *info);
+
+int amf_encoder_info(void *encoder, struct RuntimeInfo *info);
+
+int amf_encoder_gpu_timings(void *encoder, struct GpuTiming *timings,
+                            int32_t max, int32_t *count);
+
+int amf_encoder_flush(void *encoder, EncodeCallback callback, void *obj);
+
+int amf_encoder_set_emphasis_map(void *encoder, const uint8_t *map,
+                                int32_t len);
+
+int amf_encoder_set_qp_delta(void *encoder, int32_t delta);
+
+int amf_encoder_invalidate_reference(void *encoder, int64_t ms);
+
+int amf_estimate_encoder_memory(int32_t dataFormat, int32_t width,
+                                int32_t height, struct MemoryInfo *info);
+
+int amf_check_encoder_config(void *handle, int64_t luid, int32_t dataFormat,
+                             int32_t width, int32_t height, int32_t kbs,
+                             int32_t framerate, int32_t gop,
+                             const struct EncodeOptions *options,
+                             struct ConfigCheck *check);
+
+int amf_request_keyframe(void *encoder);
+
 int amf_set_bitrate(void *encoder, int32_t kbs);
 
 int amf_set_framerate(void *encoder, int32_t framerate);
@@ -3,8 +3,11 @@
 
 #include <stdint.h>
 
-typedef void (*EncodeCallback)(const uint8_t *data, int32_t len, int32_t key,
-                               const void *obj, int64_t pts);
+// user_data is the one given to the encode call of the packet's frame, flags
+// are FrameFlag bits, 1 alone is a keyframe as the key of older versions
+typedef void (*EncodeCallback)(const uint8_t *data, int32_t len, int32_t flags,
+                               const void *obj, int64_t pts,
+                               uint64_t user_data);
 
 typedef void (*DecodeCallback)(void *opaque, const void *obj);
 
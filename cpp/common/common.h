@@ -28,6 +28,8 @@ enum DataFormat {
   VP8,
   VP9,
   AV1,
+  // decode only, every packet is a baseline JPEG image
+  MJPEG,
 };
 
 // same as Driver
@@ -47,10 +49,303 @@ enum RateControl {
   RC_CQ,
 };
 
+enum AqMode {
+  AQ_DEFAULT,
+  AQ_OFF,
+  AQ_SPATIAL,
+  AQ_TEMPORAL,
+};
+
+// chroma_sample_loc_type of the vui, left is what most capture and players assume
+enum ChromaLocation {
+  CHROMA_LOC_LEFT,
+  CHROMA_LOC_CENTER,
+  CHROMA_LOC_TOP_LEFT,
+  CHROMA_LOC_TOP,
+  CHROMA_LOC_BOTTOM_LEFT,
+  CHROMA_LOC_BOTTOM,
+};
+
+// entropy_coding_mode_flag of the h264 pps, DEFAULT is CABAC as the sessions
+// encode Main or High. CAVLC is for decoders of Baseline streams.
+enum EntropyCoding {
+  ENTROPY_CODING_DEFAULT,
+  ENTROPY_CODING_CABAC,
+  ENTROPY_CODING_CAVLC,
+};
+
+// how the backends that convert bgra input to nv12 before the encoder do it:
+// HARDWARE on the d3d11 video processor, SOFTWARE on the cpu through staging
+// textures. AUTO is HARDWARE until the video processor fails, SOFTWARE after.
+enum ColorConvert {
+  COLOR_CONVERT_AUTO,
+  COLOR_CONVERT_HARDWARE,
+  COLOR_CONVERT_SOFTWARE,
+};
+
+// zero initialized means backend defaults
+struct EncodeOptions {
+  enum AqMode aqMode;
+  int32_t aqStrength; // 1 - 15, 0 lets the encoder choose
+  enum ChromaLocation chromaLocation;
+  // sample aspect ratio written into the vui, reduced, 1 - 65535 each. 0 or
+  // 1:1 writes none, decoders take square samples then.
+  int32_t sarWidth;
+  int32_t sarHeight;
+  int32_t gpuTiming; // 1 issues timestamp queries around each frame, GpuTiming
+  // 1 disables the keyframes encoders insert on scene cuts
+  int32_t noSceneCutKeyframes;
+  // frames between keyframes the encoder inserts on its own, 0 no minimum
+  int32_t minKeyframeInterval;
+  // 1 takes an emphasis level per macroblock with the frames, needs
+  // ENCODE_CAP_EMPHASIS_MAP and turns aq off
+  int32_t emphasisMap;
+  // 1 lets frames take a qp offset with set_qp_delta, excludes emphasisMap
+  int32_t qpDelta;
+  // 1 keeps earlier frames as references for invalidate_reference, needs
+  // ENCODE_CAP_REF_INVALIDATION
+  int32_t refInvalidation;
+  // h264 only, the others ignore it
+  enum EntropyCoding entropyCoding;
+  // 1 applies the backend's game streaming settings, see Tune::CloudGaming.
+  // Comes with noSceneCutKeyframes.
+  int32_t cloudGaming;
+  // added to the qp of both chroma planes, -12 - 12, nvenc only
+  int32_t chromaQpOffset;
+  // 1 - 51, lower is better. Not 0 encodes at this constant quality in place
+  // of the bitrate, which is 0 then.
+  int32_t quality;
+  // 1 encodes mathematically lossless, quality and the bitrate are ignored.
+  // Needs ENCODE_CAP_LOSSLESS, nvenc only.
+  int32_t lossless;
+  // mfx and ffmpeg, nvenc and amf take bgra and convert it in the encoder,
+  // which is HARDWARE
+  enum ColorConvert colorConvert;
+  // ffmpeg only, nul terminated "key=value" lines set with av_opt_set on the
+  // codec context and its encoder after the derived options, NULL for none
+  const char *ffmpegOptions;
+};
+
+// video memory of a codec session in bytes, 0 when unknown
+struct MemoryInfo {
+  // surfaces the session needs including the driver internal ones
+  int64_t estimated;
+  // measured on creation or tracked by the shim afterwards
+  int64_t allocated;
+};
+
+// gpu time of the stages of one encoded frame, from d3d11 timestamp queries on
+// the immediate context. encodeMs ends at a timestamp issued once the shim got
+// the bitstream back, it covers the video engine and the waits on it.
+struct GpuTiming {
+  // index of the encode call in the session, from 0
+  int64_t frame;
+  // what the encode call of the frame was given
+  uint64_t userData;
+  // copy or color conversion of the input, -1 for both when the gpu clock
+  // changed in between
+  float convertMs;
+  float encodeMs;
+};
+
+// the runtime behind an encoder session, nul terminated strings
+struct RuntimeInfo {
+  // e.g. "oneVPL" or "Media SDK" for mfx
+  char runtime[32];
+  // the implementation the runtime loaded, empty when unknown
+  char implementation[64];
+  // the api version the runtime implements, 0 when unknown
+  int32_t apiMajor;
+  int32_t apiMinor;
+  // the B-frames between references the session encodes with, 0 when the
+  // frames come out in display order
+  int32_t bframes;
+};
+
+// bits of EncodeCaps.flags
+enum EncodeCapability {
+  // B-frames can be enabled, they are off in all sessions of this library
+  ENCODE_CAP_BFRAMES = 1 << 0,
+  // 10-bit input and the Main10 / High10 profile
+  ENCODE_CAP_10BIT = 1 << 1,
+  // mathematically lossless encoding
+  ENCODE_CAP_LOSSLESS = 1 << 2,
+  // temporal adaptive quantization, AQ_TEMPORAL is ignored without it
+  ENCODE_CAP_TEMPORAL_AQ = 1 << 3,
+  // gradual intra refresh as an alternative to IDR frames
+  ENCODE_CAP_INTRA_REFRESH = 1 << 4,
+  // the resolution can change without creating a new session
+  ENCODE_CAP_DYNAMIC_RESOLUTION = 1 << 5,
+  // per macroblock emphasis levels, EncodeOptions.emphasisMap
+  ENCODE_CAP_EMPHASIS_MAP = 1 << 6,
+  // frames can be dropped from the references, EncodeOptions.refInvalidation
+  ENCODE_CAP_REF_INVALIDATION = 1 << 7,
+};
+
+// bits of the flags of an EncodeCallback, what the backend reports of the frame
+enum FrameFlag {
+  // an IDR or I frame
+  FRAME_FLAG_KEYFRAME = 1 << 0,
+  // the first frame of an intra refresh, with its recovery point SEI. Set
+  // from the SEI on the Rust side, the backends don't report it
+  FRAME_FLAG_RECOVERY_POINT_START = 1 << 1,
+  // no other frame references it
+  FRAME_FLAG_NON_REFERENCE = 1 << 2,
+  // stored as a long term reference, the slot in the bits from
+  // FRAME_FLAG_LTR_SLOT_SHIFT
+  FRAME_FLAG_LTR_MARKED = 1 << 3,
+  // the last frame of the stream, from a flush
+  FRAME_FLAG_END_OF_SEQUENCE = 1 << 4,
+  // a part of a frame split by OversizePolicy::Split, set on the Rust side
+  FRAME_FLAG_FRAGMENT = 1 << 5,
+  // the part of a split frame that ends it, with FRAME_FLAG_FRAGMENT
+  FRAME_FLAG_LAST_FRAGMENT = 1 << 6,
+};
+
+#define FRAME_FLAG_LTR_SLOT_SHIFT 8
+
+// what the encoder of an adapter supports beyond the tested configuration, filled by
+// the native test. Unset flags and 0 fields mean unsupported or unknown.
+struct EncodeCaps {
+  uint32_t flags;
+  // level_idc of the highest level, 51 is 5.1 for h264, 153 is 5.1 for h265
+  int32_t maxLevel;
+  int32_t maxWidth;
+  int32_t maxHeight;
+};
+
+// filled by the *_driver_support calls, versions as the vendor writes them
+struct DriverProbe {
+  // e.g. "NVIDIA driver" or "AMF runtime"
+  char name[32];
+  // e.g. "531.61", empty when not found
+  char installed[32];
+  // the oldest version the shim works with, empty for no minimum
+  char required[32];
+  // 1 when installed is older than required and that's why the check failed
+  int32_t tooOld;
+};
+
+// an adapter as DXGI reports it, filled by hwcodec_adapter_info
+struct AdapterDesc {
+  // utf-8, nul terminated
+  char description[256];
+  uint32_t vendorId;
+  uint32_t deviceId;
+  // bytes
+  int64_t dedicatedVideoMemory;
+  int64_t sharedSystemMemory;
+  // 1 for a gpu working in system memory, an integrated one
+  int32_t integrated;
+  // 1 for a software adapter, e.g. the Microsoft Basic Render Driver
+  int32_t software;
+};
+
+// the parameters of an encoder configuration, ConfigCheck.param
+enum ConfigParam {
+  CONFIG_PARAM_NONE,
+  CONFIG_PARAM_FORMAT,
+  CONFIG_PARAM_WIDTH,
+  CONFIG_PARAM_HEIGHT,
+  CONFIG_PARAM_BITRATE,
+  CONFIG_PARAM_FRAMERATE,
+  CONFIG_PARAM_GOP,
+  CONFIG_PARAM_AQ_MODE,
+  CONFIG_PARAM_AQ_STRENGTH,
+  CONFIG_PARAM_CHROMA_LOCATION,
+  CONFIG_PARAM_MIN_KEYFRAME_INTERVAL,
+  CONFIG_PARAM_EMPHASIS_MAP,
+  CONFIG_PARAM_ENTROPY_CODING,
+  CONFIG_PARAM_CHROMA_QP_OFFSET,
+  CONFIG_PARAM_QUALITY,
+  CONFIG_PARAM_COLOR_CONVERT,
+  CONFIG_PARAM_REF_INVALIDATION,
+  CONFIG_PARAM_SAR,
+  CONFIG_PARAM_LOSSLESS,
+};
+
+// filled by the check_encoder_config calls, param is the first parameter the
+// session wouldn't take as given: creating it fails or it runs with corrected
+// instead. NONE when the adapter couldn't be queried.
+struct ConfigCheck {
+  enum ConfigParam param;
+  // 1 when corrected is the nearest value the backend takes
+  int32_t hasCorrected;
+  int32_t corrected;
+};
+
+// bits of DecodeCaps.profiles
+enum DecodeProfile {
+  // constrained baseline, the streams of the encoders without B-frames
+  DECODE_PROFILE_H264_BASELINE = 1 << 0,
+  DECODE_PROFILE_H264_MAIN = 1 << 1,
+  DECODE_PROFILE_H264_HIGH = 1 << 2,
+  DECODE_PROFILE_H264_HIGH10 = 1 << 3,
+  DECODE_PROFILE_HEVC_MAIN = 1 << 4,
+  DECODE_PROFILE_HEVC_MAIN10 = 1 << 5,
+};
+
+// what the decoder of an adapter supports for one format, filled by the native
+// caps query of a session. Unset bits and 0 fields mean unsupported or unknown.
+struct DecodeCaps {
+  // coded size
+  int32_t maxWidth;
+  int32_t maxHeight;
+  // level_idc of the highest level, as EncodeCaps.maxLevel
+  int32_t maxLevel;
+  uint32_t profiles;
+  // bit n set for n bit luma, 1 << 8 | 1 << 10 for 8 and 10 bit
+  uint32_t bitDepths;
+};
+
+// the D3D11_TEXTURE2D_DESC of a decoded frame, see DecodeFrame::desc
+struct TextureDesc {
+  int32_t width;
+  int32_t height;
+  // a DXGI_FORMAT, e.g. 87 for DXGI_FORMAT_B8G8R8A8_UNORM
+  uint32_t format;
+  uint32_t mipLevels;
+  uint32_t arraySize;
+  // the slice of the frame if arraySize > 1, the native decoders output whole
+  // textures
+  uint32_t arraySlice;
+  uint32_t sampleCount;
+  uint32_t sampleQuality;
+  // D3D11_USAGE
+  uint32_t usage;
+  // D3D11_BIND_FLAG, D3D11_CPU_ACCESS_FLAG and D3D11_RESOURCE_MISC_FLAG bits
+  uint32_t bindFlags;
+  uint32_t cpuAccessFlags;
+  uint32_t miscFlags;
+};
+
+// DEVICE_LOST and SESSION_LOST: the codec can't be used anymore, pause, probe
+// the adapters again and recreate it.
+// INPUT_ACCESS_DENIED: the codec is fine but the input texture can't be read,
+// e.g. the duplication was stopped by the secure desktop or a session switch.
+// Skip the frame and wait for a texture from a recreated capture.
+// INVALID_DATA: the decoder rejected the packet before it reached the driver,
+// request a keyframe.
+// RESET_REQUIRED: the input changed in a way the session can't follow, e.g. its
+// size. Nothing was encoded, reset the encoder to the new size.
+// NEED_KEYFRAME: a reference picture of the packet is missing and the decoder
+// doesn't conceal errors, it discards packets until the next IDR. Request one.
+// EXCEEDS_CAPS: the stream's SPS is beyond the DecodeCaps of the decoder, e.g.
+// its size or level. Nothing was decoded, negotiate a smaller stream.
+// NOT_ONE_FRAME: an encoder held to one frame out per frame in returned none or
+// several. They are discarded and the next frame is an IDR.
 enum HwcodecErrno {
   HWCODEC_SUCCESS = 0,
   HWCODEC_ERR_COMMON = -1,
   HWCODEC_ERR_HEVC_COULD_NOT_FIND_POC = -2,
+  HWCODEC_ERR_DEVICE_LOST = -3,
+  HWCODEC_ERR_SESSION_LOST = -4,
+  HWCODEC_ERR_INPUT_ACCESS_DENIED = -5,
+  HWCODEC_ERR_INVALID_DATA = -6,
+  HWCODEC_ERR_RESET_REQUIRED = -7,
+  HWCODEC_ERR_NEED_KEYFRAME = -8,
+  HWCODEC_ERR_EXCEEDS_CAPS = -9,
+  HWCODEC_ERR_NOT_ONE_FRAME = -10,
 };
 
 #endif // COMMON_H
\ No newline at end of file
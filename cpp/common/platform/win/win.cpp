@@ -3,6 +3,7 @@
 #include <chrono>
 #include <cstdio>
 #include <list>
+#include <map>
 #include <mutex>
 #include <string>
 #include <thread>
@@ -10,6 +11,7 @@
 
 #include <d3d11.h>
 #include <dxgi.h>
+#include <dxgi1_4.h>
 
 #include "win.h"
 
@@ -102,15 +104,7 @@ bool NativeDevice::InitFromDevice(ID3D11Device *device) {
 }
 
 bool NativeDevice::SetMultithreadProtected() {
-  ComPtr<ID3D10Multithread> hmt = nullptr;
-  HRB(context_.As(&hmt));
-  if (!hmt->SetMultithreadProtected(TRUE)) {
-    if (!hmt->GetMultithreadProtected()) {
-      LOG_ERROR(std::string("Failed to SetMultithreadProtected"));
-      return false;
-    }
-  }
-  return true;
+  return set_multithread_protected(device_.Get());
 }
 
 bool NativeDevice::InitQuery() {
@@ -122,6 +116,99 @@ bool NativeDevice::InitQuery() {
   return true;
 }
 
+bool GpuTimer::Init(ID3D11Device *device) {
+  device_ = device;
+  device_->GetImmediateContext(context_.ReleaseAndGetAddressOf());
+  D3D11_QUERY_DESC disjoint = {D3D11_QUERY_TIMESTAMP_DISJOINT, 0};
+  D3D11_QUERY_DESC timestamp = {D3D11_QUERY_TIMESTAMP, 0};
+  for (Slot &slot : slots_) {
+    HRB(device_->CreateQuery(&disjoint, slot.disjoint.ReleaseAndGetAddressOf()));
+    HRB(device_->CreateQuery(&timestamp, slot.begin.ReleaseAndGetAddressOf()));
+    HRB(device_->CreateQuery(&timestamp, slot.mark.ReleaseAndGetAddressOf()));
+    HRB(device_->CreateQuery(&timestamp, slot.end.ReleaseAndGetAddressOf()));
+  }
+  return true;
+}
+
+void GpuTimer::Begin(int64_t frame, uint64_t userData) {
+  if (!context_)
+    return;
+  // the previous encode failed before End
+  if (current_) {
+    Slot *aborted = current_;
+    End();
+    aborted->pending = false;
+  }
+  current_ = &slots_[next_];
+  next_ = (next_ + 1) % kSlots;
+  if (current_->pending)
+    LOG_DEBUG("gpu timing of frame " + std::to_string(current_->frame) +
+              " dropped");
+  current_->frame = frame;
+  current_->userData = userData;
+  current_->pending = true;
+  DeviceLock lock(device_.Get());
+  context_->Begin(current_->disjoint.Get());
+  context_->End(current_->begin.Get());
+}
+
+void GpuTimer::Mark() {
+  if (!current_)
+    return;
+  DeviceLock lock(device_.Get());
+  context_->End(current_->mark.Get());
+}
+
+void GpuTimer::End() {
+  if (!current_)
+    return;
+  DeviceLock lock(device_.Get());
+  context_->End(current_->end.Get());
+  context_->End(current_->disjoint.Get());
+  current_ = nullptr;
+}
+
+bool GpuTimer::Collect(GpuTiming *timing) {
+  if (!context_)
+    return false;
+  // the oldest pending slot, next_ once the ring is full
+  Slot *oldest = nullptr;
+  for (int i = 0; i < kSlots; i++) {
+    Slot &slot = slots_[(next_ + i) % kSlots];
+    if (slot.pending && &slot != current_) {
+      oldest = &slot;
+      break;
+    }
+  }
+  if (!oldest)
+    return false;
+  D3D11_QUERY_DATA_TIMESTAMP_DISJOINT disjoint;
+  UINT64 begin, mark, end;
+  UINT flags = D3D11_ASYNC_GETDATA_DONOTFLUSH;
+  DeviceLock lock(device_.Get());
+  if (context_->GetData(oldest->disjoint.Get(), &disjoint, sizeof(disjoint),
+                        flags) != S_OK ||
+      context_->GetData(oldest->begin.Get(), &begin, sizeof(begin), flags) !=
+          S_OK ||
+      context_->GetData(oldest->mark.Get(), &mark, sizeof(mark), flags) !=
+          S_OK ||
+      context_->GetData(oldest->end.Get(), &end, sizeof(end), flags) != S_OK)
+    return false;
+  oldest->pending = false;
+  timing->frame = oldest->frame;
+  timing->userData = oldest->userData;
+  // a clock change in between makes the timestamps meaningless
+  if (disjoint.Disjoint || disjoint.Frequency == 0) {
+    timing->convertMs = -1;
+    timing->encodeMs = -1;
+  } else {
+    double ms = 1000.0 / disjoint.Frequency;
+    timing->convertMs = (float)((mark - begin) * ms);
+    timing->encodeMs = (float)((end - mark) * ms);
+  }
+  return true;
+}
+
 bool NativeDevice::InitVideoDevice() {
   HRB(device_.As(&video_device_));
   HRB(context_.As(&video_context_));
@@ -131,14 +218,13 @@ bool NativeDevice::InitVideoDevice() {
 
 bool NativeDevice::Nv12ToBgra(int width, int height,
                               ID3D11Texture2D *nv12Texture,
-                              ID3D11Texture2D *bgraTexture,
-                              int nv12ArrayIndex) {
+                              ID3D11Texture2D *bgraTexture, int nv12ArrayIndex,
+                              int x, int y) {
+  DeviceLock lock(device_.Get());
   if (width != last_nv12_to_bgra_width_ ||
       height != last_nv12_to_bgra_height_) {
     if (!nv12_to_bgra_set_srv(nv12Texture, width, height))
       return false;
-    if (!nv12_to_bgra_set_view_port(width, height))
-      return false;
     if (!nv12_to_bgra_set_sample())
       return false;
     if (!nv12_to_bgra_set_shader())
@@ -148,14 +234,15 @@ bool NativeDevice::Nv12ToBgra(int width, int height,
   }
   last_nv12_to_bgra_width_ = width;
   last_nv12_to_bgra_height_ = height;
+  nv12_to_bgra_bind(width, height);
   if (!nv12_to_bgra_set_rtv(bgraTexture, width, height))
     return false;
 
   D3D11_BOX srcBox;
-  srcBox.left = 0;
-  srcBox.top = 0;
-  srcBox.right = width;
-  srcBox.bottom = height;
+  srcBox.left = x;
+  srcBox.top = y;
+  srcBox.right = x + width;
+  srcBox.bottom = y + height;
   srcBox.front = 0;
   srcBox.back = 1;
   context_->CopySubresourceRegion(nv12SrvTexture_.Get(), 0, 0, 0, 0,
@@ -200,11 +287,6 @@ bool NativeDevice::nv12_to_bgra_set_srv(ID3D11Texture2D *nv12Texture, int width,
                                              DXGI_FORMAT_R8G8_UNORM);
   HRB(device_->CreateShaderResourceView(nv12SrvTexture_.Get(), &srvDesc,
                                         SRV_[1].ReleaseAndGetAddressOf()));
-
-  // set SRV
-  std::array<ID3D11ShaderResourceView *, 2> const textureViews = {
-      SRV_[0].Get(), SRV_[1].Get()};
-  context_->PSSetShaderResources(0, textureViews.size(), textureViews.data());
   return true;
 }
 
@@ -246,7 +328,6 @@ bool NativeDevice::nv12_to_bgra_set_sample() {
   D3D11_SAMPLER_DESC sampleDesc = CD3D11_SAMPLER_DESC(CD3D11_DEFAULT());
   HRB(device_->CreateSamplerState(&sampleDesc,
                                   samplerLinear_.ReleaseAndGetAddressOf()));
-  context_->PSSetSamplers(0, 1, samplerLinear_.GetAddressOf());
   return true;
 }
 
@@ -269,23 +350,13 @@ bool NativeDevice::nv12_to_bgra_set_shader() {
       {"TEXCOORD", 0, DXGI_FORMAT_R32G32_FLOAT, 0, 12,
        D3D11_INPUT_PER_VERTEX_DATA, 0},
   }};
-  ComPtr<ID3D11InputLayout> inputLayout = NULL;
   HRB(device_->CreateInputLayout(Layout.data(), Layout.size(), g_VS,
-                                 ARRAYSIZE(g_VS), inputLayout.GetAddressOf()));
-  context_->IASetInputLayout(inputLayout.Get());
-
-  context_->VSSetShader(vertexShader_.Get(), NULL, 0);
-  context_->PSSetShader(pixelShader_.Get(), NULL, 0);
-
+                                 ARRAYSIZE(g_VS),
+                                 inputLayout_.ReleaseAndGetAddressOf()));
   return true;
 }
 
 bool NativeDevice::nv12_to_bgra_set_vertex_buffer() {
-  UINT Stride = sizeof(VERTEX);
-  UINT Offset = 0;
-  FLOAT blendFactor[4] = {0.f, 0.f, 0.f, 0.f};
-  context_->OMSetBlendState(nullptr, blendFactor, 0xffffffff);
-  context_->IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
   // set VertexBuffers
   VERTEX Vertices[NUMVERTICES] = {
       {XMFLOAT3(-1.0f, -1.0f, 0), XMFLOAT2(0.0f, 1.0f)},
@@ -304,15 +375,32 @@ bool NativeDevice::nv12_to_bgra_set_vertex_buffer() {
   D3D11_SUBRESOURCE_DATA InitData;
   RtlZeroMemory(&InitData, sizeof(InitData));
   InitData.pSysMem = Vertices;
-  ComPtr<ID3D11Buffer> VertexBuffer = nullptr;
   // Create vertex buffer
-  HRB(device_->CreateBuffer(&BufferDesc, &InitData, &VertexBuffer));
-  context_->IASetVertexBuffers(0, 1, VertexBuffer.GetAddressOf(), &Stride,
-                               &Offset);
-
+  HRB(device_->CreateBuffer(&BufferDesc, &InitData,
+                            vertexBuffer_.ReleaseAndGetAddressOf()));
   return true;
 }
 
+// the objects are created once per size, the immediate context is shared with
+// the other codecs on the device so the pipeline is bound on every draw
+void NativeDevice::nv12_to_bgra_bind(int width, int height) {
+  std::array<ID3D11ShaderResourceView *, 2> const textureViews = {
+      SRV_[0].Get(), SRV_[1].Get()};
+  context_->PSSetShaderResources(0, textureViews.size(), textureViews.data());
+  nv12_to_bgra_set_view_port(width, height);
+  context_->PSSetSamplers(0, 1, samplerLinear_.GetAddressOf());
+  context_->IASetInputLayout(inputLayout_.Get());
+  context_->VSSetShader(vertexShader_.Get(), NULL, 0);
+  context_->PSSetShader(pixelShader_.Get(), NULL, 0);
+  FLOAT blendFactor[4] = {0.f, 0.f, 0.f, 0.f};
+  context_->OMSetBlendState(nullptr, blendFactor, 0xffffffff);
+  context_->IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
+  UINT Stride = sizeof(VERTEX);
+  UINT Offset = 0;
+  context_->IASetVertexBuffers(0, 1, vertexBuffer_.GetAddressOf(), &Stride,
+                               &Offset);
+}
+
 bool NativeDevice::nv12_to_bgra_draw() {
   context_->Draw(NUMVERTICES, 0);
   context_->Flush();
@@ -404,6 +492,8 @@ bool NativeDevice::Process(ID3D11Texture2D *in, ID3D11Texture2D *out, int width,
                            DXGI_COLOR_SPACE_TYPE colorSpace_in,
                            DXGI_COLOR_SPACE_TYPE colorSpace_out,
                            int arraySlice) {
+  DeviceLock lock(device_.Get());
+  input_access_denied_ = false;
   D3D11_TEXTURE2D_DESC inDesc = {0};
   D3D11_TEXTURE2D_DESC outDesc = {0};
   in->GetDesc(&inDesc);
@@ -454,9 +544,12 @@ bool NativeDevice::Process(ID3D11Texture2D *in, ID3D11Texture2D *out, int width,
   InputViewDesc.Texture2D.MipSlice = 0;
   InputViewDesc.Texture2D.ArraySlice = arraySlice;
   ComPtr<ID3D11VideoProcessorInputView> inputView = nullptr;
-  HRB(video_device_->CreateVideoProcessorInputView(
+  HRESULT hr = video_device_->CreateVideoProcessorInputView(
       in, video_processor_enumerator_.Get(), &InputViewDesc,
-      inputView.ReleaseAndGetAddressOf()));
+      inputView.ReleaseAndGetAddressOf());
+  if (hr == E_ACCESSDENIED)
+    input_access_denied_ = true;
+  HRB(hr);
 
   D3D11_VIDEO_PROCESSOR_OUTPUT_VIEW_DESC OutputViewDesc;
   ZeroMemory(&OutputViewDesc, sizeof(OutputViewDesc));
@@ -471,8 +564,11 @@ bool NativeDevice::Process(ID3D11Texture2D *in, ID3D11Texture2D *out, int width,
   ZeroMemory(&StreamData, sizeof(StreamData));
   StreamData.Enable = TRUE;
   StreamData.pInputSurface = inputView.Get();
-  HRB(video_context_->VideoProcessorBlt(video_processor_.Get(),
-                                        outputView.Get(), 0, 1, &StreamData));
+  hr = video_context_->VideoProcessorBlt(video_processor_.Get(),
+                                         outputView.Get(), 0, 1, &StreamData);
+  if (hr == E_ACCESSDENIED)
+    input_access_denied_ = true;
+  HRB(hr);
 
   return true;
 }
@@ -480,7 +576,8 @@ bool NativeDevice::Process(ID3D11Texture2D *in, ID3D11Texture2D *out, int width,
 bool NativeDevice::BgraToNv12(ID3D11Texture2D *bgraTexture,
                               ID3D11Texture2D *nv12Texture, int width,
                               int height, DXGI_COLOR_SPACE_TYPE colorSpace_in,
-                              DXGI_COLOR_SPACE_TYPE colorSpace_out) {
+                              DXGI_COLOR_SPACE_TYPE colorSpace_out,
+                              ColorConvert convert) {
   D3D11_TEXTURE2D_DESC bgraDesc = {0};
   D3D11_TEXTURE2D_DESC nv12Desc = {0};
   bgraTexture->GetDesc(&bgraDesc);
@@ -516,8 +613,127 @@ bool NativeDevice::BgraToNv12(ID3D11Texture2D *bgraTexture,
   contentDesc.OutputFrameRate.Numerator = 30;
   contentDesc.OutputFrameRate.Denominator = 1;
 
-  return Process(bgraTexture, nv12Texture, width, height, contentDesc,
-                 colorSpace_in, colorSpace_out, 0);
+  if (convert == COLOR_CONVERT_SOFTWARE ||
+      (convert == COLOR_CONVERT_AUTO && video_processor_failed_))
+    return bgra_to_nv12_cpu(bgraTexture, nv12Texture, width, height,
+                            colorSpace_out);
+  if (Process(bgraTexture, nv12Texture, width, height, contentDesc,
+              colorSpace_in, colorSpace_out, 0))
+    return true;
+  // the input or the device failing would fail on the cpu too
+  if (convert != COLOR_CONVERT_AUTO || input_access_denied_ || IsDeviceLost())
+    return false;
+  LOG_WARN("video processor failed, converting on the cpu from now on");
+  video_processor_failed_ = true;
+  return bgra_to_nv12_cpu(bgraTexture, nv12Texture, width, height,
+                          colorSpace_out);
+}
+
+// a staging texture of the size and format, kept while they stay the same
+static bool ensure_staging(ID3D11Device *device,
+                           ComPtr<ID3D11Texture2D> &texture, DXGI_FORMAT format,
+                           UINT width, UINT height, UINT cpuAccess) {
+  if (texture) {
+    D3D11_TEXTURE2D_DESC desc = {0};
+    texture->GetDesc(&desc);
+    if (desc.Format == format && desc.Width == width && desc.Height == height)
+      return true;
+  }
+  D3D11_TEXTURE2D_DESC desc = {0};
+  desc.Width = width;
+  desc.Height = height;
+  desc.MipLevels = 1;
+  desc.ArraySize = 1;
+  desc.Format = format;
+  desc.SampleDesc.Count = 1;
+  desc.Usage = D3D11_USAGE_STAGING;
+  desc.CPUAccessFlags = cpuAccess;
+  HRB(device->CreateTexture2D(&desc, nullptr,
+                              texture.ReleaseAndGetAddressOf()));
+  return true;
+}
+
+static uint8_t clip_byte(float v) {
+  return (uint8_t)(v < 0 ? 0 : v > 255 ? 255 : v + 0.5f);
+}
+
+// the matrix and range of colorSpace, chroma of each 2x2 block averaged
+static void bgra_to_nv12_rows(const uint8_t *bgra, int bgraPitch,
+                              uint8_t *nv12, int nv12Pitch, int nv12Height,
+                              int width, int height,
+                              DXGI_COLOR_SPACE_TYPE colorSpace) {
+  bool bt709 = colorSpace == DXGI_COLOR_SPACE_YCBCR_FULL_G22_LEFT_P709 ||
+               colorSpace == DXGI_COLOR_SPACE_YCBCR_STUDIO_G22_LEFT_P709;
+  bool full = colorSpace == DXGI_COLOR_SPACE_YCBCR_FULL_G22_LEFT_P709 ||
+              colorSpace == DXGI_COLOR_SPACE_YCBCR_FULL_G22_LEFT_P601;
+  float kr = bt709 ? 0.2126f : 0.299f;
+  float kb = bt709 ? 0.0722f : 0.114f;
+  float kg = 1 - kr - kb;
+  float yScale = full ? 1.0f : 219.0f / 255;
+  float yOffset = full ? 0.0f : 16.0f;
+  float cScale = full ? 1.0f : 224.0f / 255;
+  for (int y = 0; y < height; y++) {
+    const uint8_t *in = bgra + y * bgraPitch;
+    uint8_t *out = nv12 + y * nv12Pitch;
+    for (int x = 0; x < width; x++) {
+      const uint8_t *p = in + x * 4;
+      float luma = kr * p[2] + kg * p[1] + kb * p[0];
+      out[x] = clip_byte(yOffset + yScale * luma);
+    }
+  }
+  uint8_t *uv = nv12 + nv12Pitch * nv12Height;
+  for (int y = 0; y < (height + 1) / 2; y++) {
+    for (int x = 0; x < (width + 1) / 2; x++) {
+      float r = 0, g = 0, b = 0;
+      for (int i = 0; i < 4; i++) {
+        int sy = 2 * y + i / 2 < height ? 2 * y + i / 2 : height - 1;
+        int sx = 2 * x + i % 2 < width ? 2 * x + i % 2 : width - 1;
+        const uint8_t *p = bgra + sy * bgraPitch + sx * 4;
+        b += p[0] / 4.0f;
+        g += p[1] / 4.0f;
+        r += p[2] / 4.0f;
+      }
+      float luma = kr * r + kg * g + kb * b;
+      uint8_t *out = uv + y * nv12Pitch + 2 * x;
+      out[0] = clip_byte(128 + cScale * (b - luma) / (2 * (1 - kb)));
+      out[1] = clip_byte(128 + cScale * (r - luma) / (2 * (1 - kr)));
+    }
+  }
+}
+
+bool NativeDevice::bgra_to_nv12_cpu(ID3D11Texture2D *bgraTexture,
+                                    ID3D11Texture2D *nv12Texture, int width,
+                                    int height,
+                                    DXGI_COLOR_SPACE_TYPE colorSpace_out) {
+  DeviceLock lock(device_.Get());
+  D3D11_TEXTURE2D_DESC nv12Desc = {0};
+  nv12Texture->GetDesc(&nv12Desc);
+  if (!ensure_staging(device_.Get(), bgraStaging_,
+                      DXGI_FORMAT_B8G8R8A8_UNORM, width, height,
+                      D3D11_CPU_ACCESS_READ) ||
+      !ensure_staging(device_.Get(), nv12Staging_, DXGI_FORMAT_NV12,
+                      nv12Desc.Width, nv12Desc.Height,
+                      D3D11_CPU_ACCESS_WRITE))
+    return false;
+  D3D11_BOX box = {0, 0, 0, (UINT)width, (UINT)height, 1};
+  context_->CopySubresourceRegion(bgraStaging_.Get(), 0, 0, 0, 0, bgraTexture,
+                                  0, &box);
+  D3D11_MAPPED_SUBRESOURCE in = {0};
+  D3D11_MAPPED_SUBRESOURCE out = {0};
+  HRB(context_->Map(bgraStaging_.Get(), 0, D3D11_MAP_READ, 0, &in));
+  HRESULT hr = context_->Map(nv12Staging_.Get(), 0, D3D11_MAP_WRITE, 0, &out);
+  if (FAILED(hr)) {
+    context_->Unmap(bgraStaging_.Get(), 0);
+    HRB(hr);
+  }
+  bgra_to_nv12_rows((const uint8_t *)in.pData, in.RowPitch,
+                    (uint8_t *)out.pData, out.RowPitch, nv12Desc.Height, width,
+                    height, colorSpace_out);
+  context_->Unmap(nv12Staging_.Get(), 0);
+  context_->Unmap(bgraStaging_.Get(), 0);
+  context_->CopySubresourceRegion(nv12Texture, 0, 0, 0, 0, nv12Staging_.Get(),
+                                  0, nullptr);
+  return true;
 }
 
 AdapterVendor NativeDevice::GetVendor() {
@@ -534,6 +750,21 @@ AdapterVendor NativeDevice::GetVendor() {
   }
 }
 
+bool NativeDevice::IsDeviceLost() { return is_device_lost(device_.Get()); }
+
+int64_t NativeDevice::TextureBytes() {
+  int64_t bytes = 0;
+  for (auto &texture : texture_) {
+    bytes += texture_bytes(texture.Get());
+  }
+  bytes += texture_bytes(nv12SrvTexture_.Get());
+  return bytes;
+}
+
+// DXVA_ModeMJPEG_VLD_420, not in the SDK headers
+static const GUID DECODER_PROFILE_MJPEG_VLD_420 = {
+    0x725cb506, 0x0c29, 0x43c4, {0x94, 0x40, 0x8e, 0x93, 0x97, 0x90, 0x3a, 0x04}};
+
 bool NativeDevice::support_decode(DataFormat format) {
   const GUID *guid = nullptr;
   switch (format) {
@@ -543,6 +774,9 @@ bool NativeDevice::support_decode(DataFormat format) {
   case H265:
     guid = &D3D11_DECODER_PROFILE_HEVC_VLD_MAIN;
     break;
+  case MJPEG:
+    guid = &DECODER_PROFILE_MJPEG_VLD_420;
+    break;
   default:
     return false;
   }
@@ -563,6 +797,51 @@ bool NativeDevice::support_decode(DataFormat format) {
   return false;
 }
 
+void NativeDevice::decode_caps(DataFormat format, DecodeCaps *caps) {
+  *caps = {};
+  if (!support_decode(format))
+    return;
+  auto supported = [&](const GUID *guid, DXGI_FORMAT output) {
+    BOOL supported = FALSE;
+    return video_device_->CheckVideoDecoderFormat(guid, output, &supported) ==
+               S_OK &&
+           supported;
+  };
+  const GUID *guid = nullptr;
+  if (format == H264) {
+    guid = &D3D11_DECODER_PROFILE_H264_VLD_NOFGT;
+    caps->profiles = DECODE_PROFILE_H264_BASELINE | DECODE_PROFILE_H264_MAIN |
+                     DECODE_PROFILE_H264_HIGH;
+  } else if (format == MJPEG) {
+    guid = &DECODER_PROFILE_MJPEG_VLD_420;
+  } else {
+    guid = &D3D11_DECODER_PROFILE_HEVC_VLD_MAIN;
+    caps->profiles = DECODE_PROFILE_HEVC_MAIN;
+    if (supported(&D3D11_DECODER_PROFILE_HEVC_VLD_MAIN10, DXGI_FORMAT_P010)) {
+      caps->profiles |= DECODE_PROFILE_HEVC_MAIN10;
+      caps->bitDepths |= 1u << 10;
+    }
+  }
+  caps->bitDepths |= 1u << 8;
+  // a size without decoder configurations is beyond the hardware
+  const std::array<std::pair<UINT, UINT>, 5> sizes = {
+      {{8192, 8192}, {8192, 4352}, {4096, 4096}, {4096, 2304}, {1920, 1088}}};
+  for (auto size : sizes) {
+    D3D11_VIDEO_DECODER_DESC desc = {};
+    desc.Guid = *guid;
+    desc.SampleWidth = size.first;
+    desc.SampleHeight = size.second;
+    desc.OutputFormat = DXGI_FORMAT_NV12;
+    UINT count = 0;
+    if (SUCCEEDED(video_device_->GetVideoDecoderConfigCount(&desc, &count)) &&
+        count > 0) {
+      caps->maxWidth = size.first;
+      caps->maxHeight = size.second;
+      break;
+    }
+  }
+}
+
 // https://github.com/moonlight-stream/moonlight-qt/blob/9117f6565e4b2a6ba5417282de6bf9360b681f1a/app/streaming/video/ffmpeg-renderers/dxutil.h#L8
 bool NativeDevice::isFormatHybridDecodedByHardware(DataFormat format,
                                                    unsigned int vendorId,
@@ -746,6 +1025,380 @@ void hwcodec_get_d3d11_texture_width_height(ID3D11Texture2D *texture, int *w,
   *h = desc.Height;
 }
 
+int hwcodec_get_d3d11_texture_desc(ID3D11Texture2D *texture,
+                                   TextureDesc *desc) {
+  if (!texture || !desc)
+    return -1;
+  D3D11_TEXTURE2D_DESC d;
+  texture->GetDesc(&d);
+  desc->width = d.Width;
+  desc->height = d.Height;
+  desc->format = d.Format;
+  desc->mipLevels = d.MipLevels;
+  desc->arraySize = d.ArraySize;
+  desc->arraySlice = 0;
+  desc->sampleCount = d.SampleDesc.Count;
+  desc->sampleQuality = d.SampleDesc.Quality;
+  desc->usage = d.Usage;
+  desc->bindFlags = d.BindFlags;
+  desc->cpuAccessFlags = d.CPUAccessFlags;
+  desc->miscFlags = d.MiscFlags;
+  return 0;
+}
+
+void *hwcodec_new_d3d11_device(int64_t luid) {
+  NativeDevice native;
+  if (!native.Init(luid, nullptr))
+    return nullptr;
+  return native.device_.Detach();
+}
+
+int hwcodec_adapter_path(int64_t luid, char *description,
+                         int32_t descriptionLen, char *hardwareId,
+                         int32_t hardwareIdLen) {
+  if (!description || descriptionLen <= 0 || !hardwareId || hardwareIdLen <= 0)
+    return -1;
+  ComPtr<IDXGIFactory1> factory1 = nullptr;
+  if (FAILED(CreateDXGIFactory1(IID_IDXGIFactory1,
+                                (void **)factory1.ReleaseAndGetAddressOf())))
+    return -1;
+  ComPtr<IDXGIAdapter1> adapter = nullptr;
+  for (UINT i = 0;
+       SUCCEEDED(factory1->EnumAdapters1(i, adapter.ReleaseAndGetAddressOf()));
+       i++) {
+    DXGI_ADAPTER_DESC1 desc = DXGI_ADAPTER_DESC1();
+    if (FAILED(adapter->GetDesc1(&desc)) || LUID(desc) != luid)
+      continue;
+    int written = WideCharToMultiByte(CP_UTF8, 0, desc.Description, -1,
+                                      description, descriptionLen, NULL, NULL);
+    if (written <= 0)
+      return -1;
+    // the format of the hardware ids device manager shows
+    written = snprintf(hardwareId, hardwareIdLen,
+                       "PCI\\VEN_%04X&DEV_%04X&SUBSYS_%08X&REV_%02X",
+                       desc.VendorId, desc.DeviceId, desc.SubSysId,
+                       desc.Revision);
+    return written > 0 && written < hardwareIdLen ? 0 : -1;
+  }
+  return -1;
+}
+
+// D3D11_FEATURE_D3D11_OPTIONS2 needs windows 10, before it integrated gpus are
+// told by the little dedicated memory they carve out of system memory
+static bool unified_memory(IDXGIAdapter1 *adapter,
+                           const DXGI_ADAPTER_DESC1 &desc) {
+  ComPtr<ID3D11Device> device = nullptr;
+  D3D11_FEATURE_DATA_D3D11_OPTIONS2 options = {};
+  if (SUCCEEDED(D3D11CreateDevice(adapter, D3D_DRIVER_TYPE_UNKNOWN, NULL, 0,
+                                  NULL, 0, D3D11_SDK_VERSION,
+                                  device.ReleaseAndGetAddressOf(), NULL,
+                                  NULL)) &&
+      SUCCEEDED(device->CheckFeatureSupport(D3D11_FEATURE_D3D11_OPTIONS2,
+                                            &options, sizeof(options))))
+    return options.UnifiedMemoryArchitecture == TRUE;
+  return desc.DedicatedVideoMemory <= 512 * 1024 * 1024;
+}
+
+int hwcodec_adapter_info(int64_t luid, AdapterDesc *info) {
+  if (!info)
+    return -1;
+  ComPtr<IDXGIFactory1> factory1 = nullptr;
+  if (FAILED(CreateDXGIFactory1(IID_IDXGIFactory1,
+                                (void **)factory1.ReleaseAndGetAddressOf())))
+    return -1;
+  ComPtr<IDXGIAdapter1> adapter = nullptr;
+  for (UINT i = 0;
+       SUCCEEDED(factory1->EnumAdapters1(i, adapter.ReleaseAndGetAddressOf()));
+       i++) {
+    DXGI_ADAPTER_DESC1 desc = DXGI_ADAPTER_DESC1();
+    if (FAILED(adapter->GetDesc1(&desc)) || LUID(desc) != luid)
+      continue;
+    *info = AdapterDesc();
+    if (WideCharToMultiByte(CP_UTF8, 0, desc.Description, -1,
+                            info->description, sizeof(info->description),
+                            NULL, NULL) <= 0)
+      return -1;
+    info->vendorId = desc.VendorId;
+    info->deviceId = desc.DeviceId;
+    info->dedicatedVideoMemory = (int64_t)desc.DedicatedVideoMemory;
+    info->sharedSystemMemory = (int64_t)desc.SharedSystemMemory;
+    info->software = desc.Flags & DXGI_ADAPTER_FLAG_SOFTWARE ? 1 : 0;
+    info->integrated = unified_memory(adapter.Get(), desc) ? 1 : 0;
+    return 0;
+  }
+  return -1;
+}
+
+int hwcodec_device_luid(void *device, int64_t *luid) {
+  if (!device || !luid)
+    return -1;
+  ComPtr<ID3D11Device> d3d11 = nullptr;
+  ComPtr<IDXGIDevice> dxgiDevice = nullptr;
+  ComPtr<IDXGIAdapter> adapter = nullptr;
+  DXGI_ADAPTER_DESC desc = DXGI_ADAPTER_DESC();
+  if (FAILED(((IUnknown *)device)->QueryInterface(IID_PPV_ARGS(&d3d11))) ||
+      FAILED(d3d11.As(&dxgiDevice)) ||
+      FAILED(dxgiDevice->GetAdapter(adapter.ReleaseAndGetAddressOf())) ||
+      FAILED(adapter->GetDesc(&desc)))
+    return -1;
+  *luid = LUID(desc);
+  return 0;
+}
+
+int hwcodec_module_loaded(const char *name) {
+  return name && GetModuleHandleA(name) ? 1 : 0;
+}
+
+void hwcodec_release_d3d11(void *p) {
+  if (p)
+    ((IUnknown *)p)->Release();
+}
+
+void *hwcodec_new_d3d11_bgra_texture(void *device, int32_t width,
+                                     int32_t height, const uint8_t *data,
+                                     int32_t stride) {
+  D3D11_TEXTURE2D_DESC desc;
+  ZeroMemory(&desc, sizeof(desc));
+  desc.Width = width;
+  desc.Height = height;
+  desc.MipLevels = 1;
+  desc.ArraySize = 1;
+  desc.Format = DXGI_FORMAT_B8G8R8A8_UNORM;
+  desc.SampleDesc.Count = 1;
+  desc.MiscFlags = D3D11_RESOURCE_MISC_SHARED;
+  desc.Usage = D3D11_USAGE_DEFAULT;
+  desc.BindFlags = D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET;
+  D3D11_SUBRESOURCE_DATA init = {0};
+  init.pSysMem = data;
+  init.SysMemPitch = stride;
+  ID3D11Texture2D *texture = nullptr;
+  HRP(((ID3D11Device *)device)
+          ->CreateTexture2D(&desc, data ? &init : nullptr, &texture));
+  return texture;
+}
+
+int hwcodec_read_d3d11_bgra_texture(void *texture, uint8_t *data,
+                                    int32_t stride, int32_t height) {
+  ID3D11Texture2D *src = (ID3D11Texture2D *)texture;
+  D3D11_TEXTURE2D_DESC desc;
+  src->GetDesc(&desc);
+  if (desc.Format != DXGI_FORMAT_B8G8R8A8_UNORM || desc.ArraySize != 1) {
+    LOG_ERROR(std::string("read texture: unsupported format ") +
+              std::to_string(desc.Format));
+    return -1;
+  }
+  ComPtr<ID3D11Device> device = nullptr;
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  src->GetDevice(device.ReleaseAndGetAddressOf());
+  device->GetImmediateContext(context.ReleaseAndGetAddressOf());
+  ComPtr<ID3D11Texture2D> staging = src;
+  if (desc.Usage != D3D11_USAGE_STAGING ||
+      !(desc.CPUAccessFlags & D3D11_CPU_ACCESS_READ)) {
+    desc.Usage = D3D11_USAGE_STAGING;
+    desc.BindFlags = 0;
+    desc.MiscFlags = 0;
+    desc.CPUAccessFlags = D3D11_CPU_ACCESS_READ;
+    HRI(device->CreateTexture2D(&desc, nullptr,
+                                staging.ReleaseAndGetAddressOf()));
+    context->CopyResource(staging.Get(), src);
+  }
+  D3D11_MAPPED_SUBRESOURCE mapped;
+  HRI(context->Map(staging.Get(), 0, D3D11_MAP_READ, 0, &mapped));
+  int rows = height < (int)desc.Height ? height : desc.Height;
+  int rowBytes = stride < (int)mapped.RowPitch ? stride : mapped.RowPitch;
+  for (int y = 0; y < rows; y++) {
+    memcpy(data + (size_t)y * stride,
+           (uint8_t *)mapped.pData + (size_t)y * mapped.RowPitch, rowBytes);
+  }
+  context->Unmap(staging.Get(), 0);
+  return 0;
+}
+
+// The UNORM format of the group of an sRGB typed one. Sampling the sRGB one
+// would linearize the gamma encoded values the encoders expect, a copy between
+// the two keeps the bytes.
+static DXGI_FORMAT unorm_format(DXGI_FORMAT format) {
+  switch (format) {
+  case DXGI_FORMAT_B8G8R8A8_UNORM_SRGB:
+    return DXGI_FORMAT_B8G8R8A8_UNORM;
+  case DXGI_FORMAT_R8G8B8A8_UNORM_SRGB:
+    return DXGI_FORMAT_R8G8B8A8_UNORM;
+  default:
+    return format;
+  }
+}
+
+void *hwcodec_new_d3d11_texture_like(void *src, int32_t width,
+                                      int32_t height) {
+  if (!src)
+    return nullptr;
+  ComPtr<ID3D11Device> device = nullptr;
+  ((ID3D11Texture2D *)src)->GetDevice(device.ReleaseAndGetAddressOf());
+  D3D11_TEXTURE2D_DESC desc;
+  ((ID3D11Texture2D *)src)->GetDesc(&desc);
+  desc.Width = width;
+  desc.Height = height;
+  desc.MipLevels = 1;
+  desc.ArraySize = 1;
+  desc.Format = unorm_format(desc.Format);
+  // a keyed mutex or nt handle of the source isn't needed on the copy
+  desc.MiscFlags &= ~(D3D11_RESOURCE_MISC_SHARED_KEYEDMUTEX |
+                      D3D11_RESOURCE_MISC_SHARED_NTHANDLE);
+  ID3D11Texture2D *texture = nullptr;
+  HRP(device->CreateTexture2D(&desc, nullptr, &texture));
+  return texture;
+}
+
+void *hwcodec_new_d3d11_staging_texture_like(void *src, int32_t width,
+                                              int32_t height) {
+  if (!src || width <= 0 || height <= 0)
+    return nullptr;
+  ComPtr<ID3D11Device> device = nullptr;
+  ((ID3D11Texture2D *)src)->GetDevice(device.ReleaseAndGetAddressOf());
+  D3D11_TEXTURE2D_DESC desc = {};
+  desc.Width = width;
+  desc.Height = height;
+  desc.MipLevels = 1;
+  desc.ArraySize = 1;
+  desc.Format = DXGI_FORMAT_B8G8R8A8_UNORM;
+  desc.SampleDesc.Count = 1;
+  desc.Usage = D3D11_USAGE_STAGING;
+  desc.CPUAccessFlags = D3D11_CPU_ACCESS_READ;
+  ID3D11Texture2D *texture = nullptr;
+  HRP(device->CreateTexture2D(&desc, nullptr, &texture));
+  return texture;
+}
+
+int hwcodec_pad_d3d11_texture(void *src, void *dst, int32_t width,
+                              int32_t height) {
+  if (!src || !dst || width <= 0 || height <= 0)
+    return HWCODEC_ERR_COMMON;
+  ID3D11Texture2D *to = (ID3D11Texture2D *)dst;
+  D3D11_TEXTURE2D_DESC desc;
+  to->GetDesc(&desc);
+  if ((int)desc.Width < width || (int)desc.Height < height)
+    return HWCODEC_ERR_COMMON;
+  ComPtr<ID3D11Device> device = nullptr;
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  to->GetDevice(device.ReleaseAndGetAddressOf());
+  device->GetImmediateContext(context.ReleaseAndGetAddressOf());
+  DeviceLock lock(device.Get());
+  D3D11_BOX box = {0, 0, 0, (UINT)width, (UINT)height, 1};
+  context->CopySubresourceRegion(to, 0, 0, 0, 0, (ID3D11Texture2D *)src, 0,
+                                 &box);
+  D3D11_BOX column = {(UINT)width - 1, 0, 0, (UINT)width, (UINT)height, 1};
+  for (UINT x = width; x < desc.Width; x++)
+    context->CopySubresourceRegion(to, 0, x, 0, 0, to, 0, &column);
+  D3D11_BOX row = {0, (UINT)height - 1, 0, desc.Width, (UINT)height, 1};
+  for (UINT y = height; y < desc.Height; y++)
+    context->CopySubresourceRegion(to, 0, 0, y, 0, to, 0, &row);
+  return HWCODEC_SUCCESS;
+}
+
+int hwcodec_copy_d3d11_texture_rect(void *src, void *dst, int32_t x,
+                                    int32_t y, int32_t width,
+                                    int32_t height) {
+  if (!src || !dst || x < 0 || y < 0 || width <= 0 || height <= 0)
+    return HWCODEC_ERR_COMMON;
+  ID3D11Texture2D *from = (ID3D11Texture2D *)src;
+  ID3D11Texture2D *to = (ID3D11Texture2D *)dst;
+  D3D11_TEXTURE2D_DESC src_desc, dst_desc;
+  from->GetDesc(&src_desc);
+  to->GetDesc(&dst_desc);
+  if ((int)src_desc.Width < x + width || (int)src_desc.Height < y + height ||
+      (int)dst_desc.Width < width || (int)dst_desc.Height < height)
+    return HWCODEC_ERR_COMMON;
+  ComPtr<ID3D11Device> device = nullptr;
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  to->GetDevice(device.ReleaseAndGetAddressOf());
+  device->GetImmediateContext(context.ReleaseAndGetAddressOf());
+  DeviceLock lock(device.Get());
+  D3D11_BOX box = {(UINT)x, (UINT)y, 0, (UINT)(x + width), (UINT)(y + height),
+                   1};
+  context->CopySubresourceRegion(to, 0, 0, 0, 0, from, 0, &box);
+  return HWCODEC_SUCCESS;
+}
+
+int hwcodec_check_d3d11_staging_texture(void *texture, int32_t *width,
+                                        int32_t *height) {
+  if (!texture)
+    return HWCODEC_ERR_COMMON;
+  D3D11_TEXTURE2D_DESC desc;
+  ((ID3D11Texture2D *)texture)->GetDesc(&desc);
+  *width = desc.Width;
+  *height = desc.Height;
+  if (desc.Usage != D3D11_USAGE_STAGING ||
+      !(desc.CPUAccessFlags & D3D11_CPU_ACCESS_READ) || desc.ArraySize != 1 ||
+      desc.Format != DXGI_FORMAT_B8G8R8A8_UNORM) {
+    LOG_ERROR(std::string("not a cpu readable bgra staging texture, usage ") +
+              std::to_string(desc.Usage) + ", format " +
+              std::to_string(desc.Format));
+    return HWCODEC_ERR_COMMON;
+  }
+  return HWCODEC_SUCCESS;
+}
+
+int hwcodec_copy_to_d3d11_staging(void *src, void *staging, int32_t width,
+                                  int32_t height, int32_t *row_pitch) {
+  if (!src || !staging || width <= 0 || height <= 0)
+    return HWCODEC_ERR_COMMON;
+  ComPtr<ID3D11Device> device = nullptr;
+  ComPtr<ID3D11Device> staging_device = nullptr;
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  ((ID3D11Texture2D *)src)->GetDevice(device.ReleaseAndGetAddressOf());
+  ((ID3D11Texture2D *)staging)
+      ->GetDevice(staging_device.ReleaseAndGetAddressOf());
+  if (device != staging_device) {
+    LOG_ERROR(std::string("staging texture of another device"));
+    return HWCODEC_ERR_COMMON;
+  }
+  D3D11_TEXTURE2D_DESC src_desc, staging_desc;
+  ((ID3D11Texture2D *)src)->GetDesc(&src_desc);
+  ((ID3D11Texture2D *)staging)->GetDesc(&staging_desc);
+  if (src_desc.Format != staging_desc.Format ||
+      (int)src_desc.Width < width || (int)src_desc.Height < height ||
+      (int)staging_desc.Width != width || (int)staging_desc.Height != height)
+    return HWCODEC_ERR_COMMON;
+  device->GetImmediateContext(context.ReleaseAndGetAddressOf());
+  DeviceLock lock(device.Get());
+  D3D11_BOX box = {0, 0, 0, (UINT)width, (UINT)height, 1};
+  context->CopySubresourceRegion((ID3D11Texture2D *)staging, 0, 0, 0, 0,
+                                 (ID3D11Texture2D *)src, 0, &box);
+  D3D11_MAPPED_SUBRESOURCE mapped;
+  HRI(context->Map((ID3D11Texture2D *)staging, 0, D3D11_MAP_READ, 0, &mapped));
+  *row_pitch = mapped.RowPitch;
+  context->Unmap((ID3D11Texture2D *)staging, 0);
+  return HWCODEC_SUCCESS;
+}
+
+void *hwcodec_open_d3d11_shared_fence(void *texture, void *handle) {
+  if (!texture || !handle)
+    return nullptr;
+  ComPtr<ID3D11Device> device = nullptr;
+  ((ID3D11Texture2D *)texture)->GetDevice(device.ReleaseAndGetAddressOf());
+  ComPtr<ID3D11Device5> device5 = nullptr;
+  if (FAILED(device.As(&device5))) {
+    LOG_ERROR(std::string("shared fences need ID3D11Device5"));
+    return nullptr;
+  }
+  ID3D11Fence *fence = nullptr;
+  HRP(device5->OpenSharedFence((HANDLE)handle, IID_PPV_ARGS(&fence)));
+  return fence;
+}
+
+int hwcodec_wait_d3d11_fence(void *texture, void *fence, uint64_t value) {
+  if (!texture || !fence)
+    return HWCODEC_ERR_COMMON;
+  ComPtr<ID3D11Device> device = nullptr;
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  ((ID3D11Texture2D *)texture)->GetDevice(device.ReleaseAndGetAddressOf());
+  device->GetImmediateContext(context.ReleaseAndGetAddressOf());
+  ComPtr<ID3D11DeviceContext4> context4 = nullptr;
+  HRI(context.As(&context4));
+  HRI(context4->Wait((ID3D11Fence *)fence, value));
+  return HWCODEC_SUCCESS;
+}
+
 int32_t add_process_to_new_job(DWORD process_id) {
   HANDLE job_handle = CreateJobObjectW(nullptr, nullptr);
   if (job_handle == nullptr) {
@@ -790,4 +1443,398 @@ int32_t add_process_to_new_job(DWORD process_id) {
   CloseHandle(process_handle);
 
   return 0;
-}
\ No newline at end of file
+}
+
+static std::atomic<bool> g_debug_device_lost{false};
+
+#ifdef HWCODEC_TESTUTIL
+// simulates a removed device, a real TDR can't be triggered on demand
+extern "C" void hwcodec_debug_set_device_lost(int32_t lost) {
+  g_debug_device_lost = lost != 0;
+}
+#endif
+
+static std::mutex g_hidden_modules_mutex;
+static std::vector<std::string> g_hidden_modules;
+
+#ifdef HWCODEC_TESTUTIL
+extern "C" void hwcodec_debug_hide_module(const char *name, int32_t hidden) {
+  if (!name)
+    return;
+  std::lock_guard<std::mutex> lock(g_hidden_modules_mutex);
+  auto it = g_hidden_modules.begin();
+  while (it != g_hidden_modules.end() && _stricmp(it->c_str(), name) != 0)
+    it++;
+  if (hidden && it == g_hidden_modules.end())
+    g_hidden_modules.push_back(name);
+  else if (!hidden && it != g_hidden_modules.end())
+    g_hidden_modules.erase(it);
+}
+#endif
+
+bool module_hidden(const char *name) {
+  std::lock_guard<std::mutex> lock(g_hidden_modules_mutex);
+  for (auto &hidden : g_hidden_modules) {
+    if (_stricmp(hidden.c_str(), name) == 0)
+      return true;
+  }
+  return false;
+}
+
+// The references the shims hold on vendor dlls, by handle. Taken and dropped
+// with the sessions and probes, hwcodec_free_vendor_modules drops what is left.
+static std::mutex g_vendor_modules_mutex;
+static std::map<HMODULE, int> g_vendor_modules;
+
+void *vendor_module_load(const char *name) {
+  HMODULE module = LoadLibraryA(name);
+  if (module) {
+    std::lock_guard<std::mutex> lock(g_vendor_modules_mutex);
+    g_vendor_modules[module]++;
+  }
+  return module;
+}
+
+void vendor_module_free(void *module) {
+  if (!module)
+    return;
+  {
+    std::lock_guard<std::mutex> lock(g_vendor_modules_mutex);
+    auto it = g_vendor_modules.find((HMODULE)module);
+    // freed by shutdown already
+    if (it == g_vendor_modules.end())
+      return;
+    if (--it->second == 0)
+      g_vendor_modules.erase(it);
+  }
+  FreeLibrary((HMODULE)module);
+}
+
+int hwcodec_vendor_module_references() {
+  std::lock_guard<std::mutex> lock(g_vendor_modules_mutex);
+  int references = 0;
+  for (auto &module : g_vendor_modules)
+    references += module.second;
+  return references;
+}
+
+int hwcodec_free_vendor_modules() {
+  std::lock_guard<std::mutex> lock(g_vendor_modules_mutex);
+  int freed = 0;
+  for (auto &module : g_vendor_modules) {
+    for (int i = 0; i < module.second; i++)
+      FreeLibrary(module.first);
+    freed += module.second;
+  }
+  g_vendor_modules.clear();
+  return freed;
+}
+
+// the test side of the shim, see testutil
+#ifdef HWCODEC_TESTUTIL
+extern "C" void *hwcodec_debug_new_shared_fence(void *device, void **handle) {
+  ComPtr<ID3D11Device5> device5 = nullptr;
+  if (FAILED(((ID3D11Device *)device)->QueryInterface(IID_PPV_ARGS(&device5))))
+    return nullptr;
+  ID3D11Fence *fence = nullptr;
+  HRP(device5->CreateFence(0, D3D11_FENCE_FLAG_SHARED, IID_PPV_ARGS(&fence)));
+  if (FAILED(fence->CreateSharedHandle(nullptr, GENERIC_ALL, nullptr,
+                                       (HANDLE *)handle))) {
+    fence->Release();
+    return nullptr;
+  }
+  return fence;
+}
+
+extern "C" void hwcodec_debug_close_handle(void *handle) {
+  if (handle)
+    CloseHandle((HANDLE)handle);
+}
+
+extern "C" int hwcodec_debug_write_and_signal(void *texture, const uint8_t *data,
+                                              int32_t stride, void *fence,
+                                              uint64_t value, int32_t flush) {
+  ComPtr<ID3D11Device> device = nullptr;
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  ((ID3D11Texture2D *)texture)->GetDevice(device.ReleaseAndGetAddressOf());
+  device->GetImmediateContext(context.ReleaseAndGetAddressOf());
+  ComPtr<ID3D11DeviceContext4> context4 = nullptr;
+  HRI(context.As(&context4));
+  DeviceLock lock(device.Get());
+  context4->UpdateSubresource((ID3D11Texture2D *)texture, 0, nullptr, data,
+                              stride, 0);
+  HRI(context4->Signal((ID3D11Fence *)fence, value));
+  if (flush)
+    context4->Flush();
+  return 0;
+}
+
+extern "C" void hwcodec_debug_flush(void *device) {
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  ((ID3D11Device *)device)->GetImmediateContext(context.ReleaseAndGetAddressOf());
+  DeviceLock lock((ID3D11Device *)device);
+  context->Flush();
+}
+
+extern "C" void *hwcodec_debug_new_staging_texture(void *device, int32_t width,
+                                                   int32_t height) {
+  D3D11_TEXTURE2D_DESC desc = {};
+  desc.Width = width;
+  desc.Height = height;
+  desc.MipLevels = 1;
+  desc.ArraySize = 1;
+  desc.Format = DXGI_FORMAT_B8G8R8A8_UNORM;
+  desc.SampleDesc.Count = 1;
+  desc.Usage = D3D11_USAGE_STAGING;
+  desc.CPUAccessFlags = D3D11_CPU_ACCESS_READ;
+  ID3D11Texture2D *texture = nullptr;
+  HRP(((ID3D11Device *)device)->CreateTexture2D(&desc, nullptr, &texture));
+  return texture;
+}
+
+extern "C" void *hwcodec_debug_new_srgb_texture(void *device, int32_t width,
+                                                int32_t height,
+                                                const uint8_t *data,
+                                                int32_t stride) {
+  D3D11_TEXTURE2D_DESC desc = {};
+  desc.Width = width;
+  desc.Height = height;
+  desc.MipLevels = 1;
+  desc.ArraySize = 1;
+  desc.Format = DXGI_FORMAT_B8G8R8A8_UNORM_SRGB;
+  desc.SampleDesc.Count = 1;
+  desc.MiscFlags = D3D11_RESOURCE_MISC_SHARED;
+  desc.Usage = D3D11_USAGE_DEFAULT;
+  desc.BindFlags = D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET;
+  D3D11_SUBRESOURCE_DATA init = {0};
+  init.pSysMem = data;
+  init.SysMemPitch = stride;
+  ID3D11Texture2D *texture = nullptr;
+  HRP(((ID3D11Device *)device)->CreateTexture2D(&desc, &init, &texture));
+  return texture;
+}
+
+extern "C" void *hwcodec_debug_open_shared_texture(void *device, void *texture) {
+  ComPtr<IDXGIResource> resource = nullptr;
+  HRP(((ID3D11Texture2D *)texture)->QueryInterface(IID_PPV_ARGS(&resource)));
+  HANDLE handle = nullptr;
+  HRP(resource->GetSharedHandle(&handle));
+  ID3D11Texture2D *opened = nullptr;
+  HRP(((ID3D11Device *)device)
+          ->OpenSharedResource(handle, IID_PPV_ARGS(&opened)));
+  return opened;
+}
+
+extern "C" int hwcodec_debug_copy_to_texture_of_desc(void *texture,
+                                                     const TextureDesc *desc) {
+  if (!texture || !desc)
+    return -1;
+  ID3D11Texture2D *src = (ID3D11Texture2D *)texture;
+  D3D11_TEXTURE2D_DESC d = {};
+  d.Width = desc->width;
+  d.Height = desc->height;
+  d.Format = (DXGI_FORMAT)desc->format;
+  d.MipLevels = desc->mipLevels;
+  d.ArraySize = desc->arraySize;
+  d.SampleDesc.Count = desc->sampleCount;
+  d.SampleDesc.Quality = desc->sampleQuality;
+  d.Usage = (D3D11_USAGE)desc->usage;
+  d.BindFlags = desc->bindFlags;
+  d.CPUAccessFlags = desc->cpuAccessFlags;
+  d.MiscFlags = desc->miscFlags;
+  ComPtr<ID3D11Device> device = nullptr;
+  src->GetDevice(&device);
+  ComPtr<ID3D11Texture2D> dst = nullptr;
+  HRI(device->CreateTexture2D(&d, nullptr, &dst));
+  D3D11_TEXTURE2D_DESC src_desc, dst_desc;
+  src->GetDesc(&src_desc);
+  dst->GetDesc(&dst_desc);
+  if (memcmp(&src_desc, &dst_desc, sizeof(src_desc)) != 0)
+    return -1;
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  device->GetImmediateContext(&context);
+  DeviceLock lock(device.Get());
+  context->CopyResource(dst.Get(), src);
+  context->Flush();
+  return 0;
+}
+#endif
+
+static ComPtr<ID3D10Multithread> device_multithread(ID3D11Device *device) {
+  ComPtr<ID3D10Multithread> multithread = nullptr;
+  if (!device)
+    return multithread;
+  ComPtr<ID3D11DeviceContext> context = nullptr;
+  device->GetImmediateContext(context.ReleaseAndGetAddressOf());
+  if (context)
+    context.As(&multithread);
+  return multithread;
+}
+
+void lock_device(ID3D11Device *device) {
+  auto multithread = device_multithread(device);
+  if (multithread)
+    multithread->Enter();
+}
+
+void unlock_device(ID3D11Device *device) {
+  auto multithread = device_multithread(device);
+  if (multithread)
+    multithread->Leave();
+}
+
+bool set_multithread_protected(ID3D11Device *device) {
+  auto multithread = device_multithread(device);
+  if (!multithread)
+    return false;
+  if (!multithread->SetMultithreadProtected(TRUE)) {
+    if (!multithread->GetMultithreadProtected()) {
+      LOG_ERROR(std::string("Failed to SetMultithreadProtected"));
+      return false;
+    }
+  }
+  return true;
+}
+
+bool is_device_lost(ID3D11Device *device) {
+  if (g_debug_device_lost)
+    return true;
+  if (!device)
+    return false;
+  HRESULT hr = device->GetDeviceRemovedReason();
+  switch (hr) {
+  case DXGI_ERROR_DEVICE_REMOVED:
+  case DXGI_ERROR_DEVICE_RESET:
+  case DXGI_ERROR_DEVICE_HUNG:
+  case DXGI_ERROR_DRIVER_INTERNAL_ERROR:
+    LOG_ERROR(std::string("device lost, reason: ") + std::to_string(hr));
+    return true;
+  default:
+    return false;
+  }
+}
+
+int check_input_texture(ID3D11Device *device, ID3D11Texture2D *texture) {
+  if (!texture)
+    return HWCODEC_ERR_COMMON;
+  // a capture recreated after a session switch hands out textures of another
+  // device, copying them would silently produce black frames
+  ComPtr<ID3D11Device> textureDevice = nullptr;
+  texture->GetDevice(textureDevice.ReleaseAndGetAddressOf());
+  if (!textureDevice || (device && textureDevice.Get() != device)) {
+    LOG_ERROR(std::string("input texture doesn't belong to the codec device"));
+    return HWCODEC_ERR_INPUT_ACCESS_DENIED;
+  }
+  if (is_device_lost(textureDevice.Get()))
+    return HWCODEC_ERR_DEVICE_LOST;
+  return HWCODEC_SUCCESS;
+}
+
+int64_t frame_bytes(DXGI_FORMAT format, int width, int height) {
+  int64_t pixels = (int64_t)width * height;
+  switch (format) {
+  case DXGI_FORMAT_NV12:
+    return pixels * 3 / 2;
+  case DXGI_FORMAT_P010:
+    return pixels * 3;
+  case DXGI_FORMAT_B8G8R8A8_UNORM:
+  case DXGI_FORMAT_B8G8R8A8_UNORM_SRGB:
+  case DXGI_FORMAT_R8G8B8A8_UNORM:
+  case DXGI_FORMAT_R8G8B8A8_UNORM_SRGB:
+  case DXGI_FORMAT_R10G10B10A2_UNORM:
+    return pixels * 4;
+  default:
+    return 0;
+  }
+}
+
+int64_t texture_bytes(ID3D11Texture2D *texture) {
+  if (!texture)
+    return 0;
+  D3D11_TEXTURE2D_DESC desc = {0};
+  texture->GetDesc(&desc);
+  return frame_bytes(desc.Format, desc.Width, desc.Height) * desc.ArraySize;
+}
+
+int64_t video_memory_usage(ID3D11Device *device) {
+  if (!device)
+    return 0;
+  ComPtr<IDXGIDevice> dxgiDevice = nullptr;
+  ComPtr<IDXGIAdapter> adapter = nullptr;
+  ComPtr<IDXGIAdapter3> adapter3 = nullptr;
+  if (FAILED(device->QueryInterface(IID_PPV_ARGS(&dxgiDevice))) ||
+      FAILED(dxgiDevice->GetAdapter(adapter.ReleaseAndGetAddressOf())) ||
+      FAILED(adapter.As(&adapter3)))
+    return 0;
+  DXGI_QUERY_VIDEO_MEMORY_INFO info = {0};
+  if (FAILED(adapter3->QueryVideoMemoryInfo(
+          0, DXGI_MEMORY_SEGMENT_GROUP_LOCAL, &info)))
+    return 0;
+  return (int64_t)info.CurrentUsage;
+}
+
+bool adapter_driver_version(AdapterVendor vendor, char *version, int size) {
+  ComPtr<IDXGIFactory1> factory1 = nullptr;
+  if (FAILED(CreateDXGIFactory1(IID_IDXGIFactory1,
+                                (void **)factory1.ReleaseAndGetAddressOf())))
+    return false;
+  ComPtr<IDXGIAdapter1> adapter = nullptr;
+  for (UINT i = 0;
+       SUCCEEDED(factory1->EnumAdapters1(i, adapter.ReleaseAndGetAddressOf()));
+       i++) {
+    DXGI_ADAPTER_DESC1 desc = DXGI_ADAPTER_DESC1();
+    if (FAILED(adapter->GetDesc1(&desc)) || desc.VendorId != vendor)
+      continue;
+    // the only interface CheckInterfaceSupport answers, with the umd version
+    LARGE_INTEGER umd = {};
+    if (FAILED(adapter->CheckInterfaceSupport(__uuidof(IDXGIDevice), &umd)))
+      return false;
+    int parts[4] = {HIWORD(umd.HighPart), LOWORD(umd.HighPart),
+                    HIWORD(umd.LowPart), LOWORD(umd.LowPart)};
+    int written;
+    if (vendor == ADAPTER_VENDOR_NVIDIA) {
+      // the last five digits, 31.0.15.3161 is 531.61
+      int digits = parts[2] % 10 * 10000 + parts[3];
+      written = snprintf(version, size, "%d.%02d", digits / 100, digits % 100);
+    } else {
+      written = snprintf(version, size, "%d.%d.%d.%d", parts[0], parts[1],
+                         parts[2], parts[3]);
+    }
+    return written > 0 && written < size;
+  }
+  return false;
+}
+
+// low latency streams have no b frames, one reference and one reconstructed
+// picture are kept, plus a compressed frame buffer per input
+#define ENCODE_DPB_SURFACES 2
+
+void estimate_encoder_memory(DataFormat format, int width, int height,
+                             int bgraSurfaces, int nv12Surfaces,
+                             MemoryInfo *info) {
+  int align = format == H264 ? 16 : 64;
+  int alignedWidth = (width + align - 1) / align * align;
+  int alignedHeight = (height + align - 1) / align * align;
+  int64_t bgra = frame_bytes(DXGI_FORMAT_B8G8R8A8_UNORM, width, height);
+  int64_t nv12 = frame_bytes(DXGI_FORMAT_NV12, alignedWidth, alignedHeight);
+  int inputs = bgraSurfaces + nv12Surfaces;
+  info->estimated = bgraSurfaces * bgra + nv12Surfaces * nv12 +
+                    ENCODE_DPB_SURFACES * nv12 + inputs * nv12 / 2;
+}
+
+// h264 and hevc keep at most 16 references plus the current picture
+#define DECODE_DPB_SURFACES 17
+
+void estimate_decoder_memory(NativeDevice *native, MemoryInfo *info) {
+  info->allocated = native->TextureBytes();
+  info->estimated = 0;
+  ID3D11Texture2D *texture = native->GetCurrentTexture();
+  if (texture) {
+    D3D11_TEXTURE2D_DESC desc = {0};
+    texture->GetDesc(&desc);
+    info->estimated =
+        info->allocated +
+        DECODE_DPB_SURFACES *
+            frame_bytes(DXGI_FORMAT_NV12, desc.Width, desc.Height);
+  }
+}
@@ -4,6 +4,7 @@
 #include <DirectXMath.h>
 #include <d3d11.h>
 #include <d3d11_1.h>
+#include <d3d11_4.h>
 #include <directxcolors.h>
 #include <iostream>
 #include <vector>
@@ -25,6 +26,60 @@ using namespace DirectX;
 #define LUID(desc)                                                             \
   (((int64_t)desc.AdapterLuid.HighPart << 32) | desc.AdapterLuid.LowPart)
 
+// Codecs may share one device and its immediate context across threads. Every
+// device is made multithread protected, which only makes single calls atomic;
+// a sequence of calls that relies on context state holds this lock, the
+// device's ID3D10Multithread one. It is recursive, don't hold it while waiting
+// on a runtime that uses the device from its own threads.
+void lock_device(ID3D11Device *device);
+void unlock_device(ID3D11Device *device);
+// for devices that don't go through NativeDevice::Init
+bool set_multithread_protected(ID3D11Device *device);
+
+class DeviceLock {
+public:
+  explicit DeviceLock(ID3D11Device *device) : device_(device) {
+    lock_device(device_);
+  }
+  ~DeviceLock() { unlock_device(device_); }
+  DeviceLock(const DeviceLock &) = delete;
+  DeviceLock &operator=(const DeviceLock &) = delete;
+
+private:
+  ID3D11Device *device_;
+};
+
+// Timestamp queries around the stages of encoding a frame. The results arrive a
+// few frames late, Collect returns them in frame order and drops the frames
+// whose queries had to be reused before they were read.
+class GpuTimer {
+public:
+  bool Init(ID3D11Device *device);
+  // before the conversion, the following calls are no-ops until Init
+  void Begin(int64_t frame, uint64_t userData);
+  // conversion queued
+  void Mark();
+  // encode submitted
+  void End();
+  // false when the oldest frame isn't ready
+  bool Collect(GpuTiming *timing);
+
+private:
+  static const int kSlots = 8;
+  struct Slot {
+    ComPtr<ID3D11Query> disjoint, begin, mark, end;
+    int64_t frame = -1;
+    uint64_t userData = 0;
+    bool pending = false;
+  };
+  ComPtr<ID3D11Device> device_ = nullptr;
+  ComPtr<ID3D11DeviceContext> context_ = nullptr;
+  Slot slots_[kSlots];
+  // slot of the next Begin, the oldest pending one follows it
+  int next_ = 0;
+  Slot *current_ = nullptr;
+};
+
 class NativeDevice {
 public:
   bool Init(int64_t luid, ID3D11Device *device, int pool_size = 1);
@@ -40,13 +95,26 @@ public:
                D3D11_VIDEO_PROCESSOR_CONTENT_DESC content_desc,
                DXGI_COLOR_SPACE_TYPE colorSpace_in,
                DXGI_COLOR_SPACE_TYPE colorSpace_out, int arraySlice);
+  // on the video processor or the cpu by convert, see ColorConvert
   bool BgraToNv12(ID3D11Texture2D *bgraTexture, ID3D11Texture2D *nv12Texture,
                   int width, int height, DXGI_COLOR_SPACE_TYPE colorSpace_in,
-                  DXGI_COLOR_SPACE_TYPE colorSpace_outt);
+                  DXGI_COLOR_SPACE_TYPE colorSpace_outt,
+                  ColorConvert convert = COLOR_CONVERT_HARDWARE);
+  // the width x height rect of nv12Texture at x, y, the cropping of the
+  // stream, into the top left of bgraTexture. x and y are even.
   bool Nv12ToBgra(int width, int height, ID3D11Texture2D *nv12Texture,
-                  ID3D11Texture2D *bgraTexture, int nv12ArrayIndex);
+                  ID3D11Texture2D *bgraTexture, int nv12ArrayIndex, int x = 0,
+                  int y = 0);
   AdapterVendor GetVendor();
   bool support_decode(DataFormat format);
+  // the profiles and sizes of the d3d11 video decoder, the level stays unknown
+  void decode_caps(DataFormat format, DecodeCaps *caps);
+  bool IsDeviceLost();
+  // textures created or held by this device wrapper
+  int64_t TextureBytes();
+
+  // set when the last Process failed with E_ACCESSDENIED on its input
+  bool input_access_denied_ = false;
 
 private:
   bool InitFromLuid(int64_t luid);
@@ -56,6 +124,9 @@ private:
   bool InitVideoDevice();
   bool isFormatHybridDecodedByHardware(DataFormat format, unsigned int vendorId,
                                        unsigned int deviceId);
+  bool bgra_to_nv12_cpu(ID3D11Texture2D *bgraTexture,
+                        ID3D11Texture2D *nv12Texture, int width, int height,
+                        DXGI_COLOR_SPACE_TYPE colorSpace_out);
 
   // nv12 to bgra
   bool nv12_to_bgra_set_srv(ID3D11Texture2D *nv12Texture, int width,
@@ -66,6 +137,7 @@ private:
   bool nv12_to_bgra_set_sample();
   bool nv12_to_bgra_set_shader();
   bool nv12_to_bgra_set_vertex_buffer();
+  void nv12_to_bgra_bind(int width, int height);
   bool nv12_to_bgra_draw();
 
 public:
@@ -88,8 +160,16 @@ public:
   ComPtr<ID3D11VertexShader> vertexShader_ = NULL;
   ComPtr<ID3D11PixelShader> pixelShader_ = NULL;
   ComPtr<ID3D11SamplerState> samplerLinear_ = NULL;
+  ComPtr<ID3D11InputLayout> inputLayout_ = NULL;
+  ComPtr<ID3D11Buffer> vertexBuffer_ = NULL;
   ComPtr<ID3D11Texture2D> nv12SrvTexture_ = nullptr;
 
+  // bgra to nv12 on the cpu
+  ComPtr<ID3D11Texture2D> bgraStaging_ = nullptr;
+  ComPtr<ID3D11Texture2D> nv12Staging_ = nullptr;
+  // COLOR_CONVERT_AUTO converts on the cpu once set
+  bool video_processor_failed_ = false;
+
   int count_;
   int index_ = 0;
 
@@ -125,11 +205,174 @@ public:
   std::vector<std::unique_ptr<Adapter>> adapters_;
 };
 
+bool is_device_lost(ID3D11Device *device);
+
+// returns a HwcodecErrno, device is the one the codec was created on
+int check_input_texture(ID3D11Device *device, ID3D11Texture2D *texture);
+
+int64_t frame_bytes(DXGI_FORMAT format, int width, int height);
+int64_t texture_bytes(ID3D11Texture2D *texture);
+// local video memory used by this process on the adapter of device
+int64_t video_memory_usage(ID3D11Device *device);
+// the driver version of the first adapter of vendor as the vendor writes it,
+// e.g. 531.61 for nvidia, false without such an adapter
+bool adapter_driver_version(AdapterVendor vendor, char *version, int size);
+// fills estimated only, allocated is up to the caller
+void estimate_encoder_memory(DataFormat format, int width, int height,
+                             int bgraSurfaces, int nv12Surfaces,
+                             MemoryInfo *info);
+// the output textures of native are tracked, the decode surfaces are estimated
+// from their size
+void estimate_decoder_memory(NativeDevice *native, MemoryInfo *info);
+
 extern "C" uint64_t GetHwcodecGpuSignature();
 
 extern "C" void hwcodec_get_d3d11_texture_width_height(ID3D11Texture2D *texture, int *w,
                                              int *h);
 
+// Returns -1 for a null texture.
+extern "C" int hwcodec_get_d3d11_texture_desc(ID3D11Texture2D *texture,
+                                              TextureDesc *desc);
+
+extern "C" void *hwcodec_new_d3d11_device(int64_t luid);
+
+// The description and PCI hardware id of the adapter of luid as DXGI reports
+// them, utf-8 and nul terminated. The hardware id starts the adapter's device
+// instance path, e.g. PCI\VEN_10DE&DEV_2684&SUBSYS_16F310DE&REV_A1. Returns -1
+// if no adapter has luid or a buffer is too small.
+extern "C" int hwcodec_adapter_path(int64_t luid, char *description,
+                                    int32_t descriptionLen, char *hardwareId,
+                                    int32_t hardwareIdLen);
+
+// Fills desc with the adapter of luid. Creates a device on the adapter to ask
+// whether it's integrated. Returns -1 if no adapter has luid.
+extern "C" int hwcodec_adapter_info(int64_t luid, AdapterDesc *desc);
+
+// the luid of the adapter device was created on, -1 if it's not a d3d11 device
+extern "C" int hwcodec_device_luid(void *device, int64_t *luid);
+
+// 1 when the dll of name is loaded in the process, from whoever loaded it
+extern "C" int hwcodec_module_loaded(const char *name);
+
+extern "C" void hwcodec_release_d3d11(void *p);
+
+extern "C" void *hwcodec_new_d3d11_bgra_texture(void *device, int32_t width,
+                                                int32_t height,
+                                                const uint8_t *data,
+                                                int32_t stride);
+
+// copies the top left of texture into data, used to verify decoded frames. A
+// cpu readable staging texture is mapped as it is.
+extern "C" int hwcodec_read_d3d11_bgra_texture(void *texture, uint8_t *data,
+                                               int32_t stride, int32_t height);
+
+// a texture like src, of another size, on src's device. UNORM for an sRGB
+// typed src, the bytes copied into it are encoded as they are.
+extern "C" void *hwcodec_new_d3d11_texture_like(void *src, int32_t width,
+                                                int32_t height);
+
+// a cpu readable bgra staging texture on src's device
+extern "C" void *hwcodec_new_d3d11_staging_texture_like(void *src,
+                                                        int32_t width,
+                                                        int32_t height);
+
+// Copies the width x height top left of src into dst and fills the rest of dst
+// by repeating the last column and row, edges instead of black keep the encoder
+// from ringing at the border.
+extern "C" int hwcodec_pad_d3d11_texture(void *src, void *dst, int32_t width,
+                                         int32_t height);
+
+// Copies the width x height rect of src at x, y into the top left of dst, a
+// tile of a frame too wide for one session.
+extern "C" int hwcodec_copy_d3d11_texture_rect(void *src, void *dst, int32_t x,
+                                               int32_t y, int32_t width,
+                                               int32_t height);
+
+// 0 for a cpu readable bgra staging texture, the size is filled in either way
+extern "C" int hwcodec_check_d3d11_staging_texture(void *texture,
+                                                   int32_t *width,
+                                                   int32_t *height);
+
+// Copies the width x height top left of src into staging, a texture of that size
+// and src's format on src's device. Mapping it to read the row pitch waits for
+// the copy, the caller's own Map then doesn't stall.
+extern "C" int hwcodec_copy_to_d3d11_staging(void *src, void *staging,
+                                             int32_t width, int32_t height,
+                                             int32_t *row_pitch);
+
+// Opens the NT handle of a shared fence on the device of texture: a D3D12 fence
+// created with D3D12_FENCE_FLAG_SHARED, a shared ID3D11Fence, or a Vulkan timeline
+// semaphore exported as VK_EXTERNAL_SEMAPHORE_HANDLE_TYPE_D3D12_FENCE_BIT. The
+// handle stays owned by the caller. Needs ID3D11Device5, windows 10 1703.
+extern "C" void *hwcodec_open_d3d11_shared_fence(void *texture, void *handle);
+
+// Queues a gpu wait for fence to reach value on the immediate context of
+// texture's device, the work queued afterwards, e.g. an encoder reading texture,
+// runs once the producer signaled. The cpu doesn't block.
+extern "C" int hwcodec_wait_d3d11_fence(void *texture, void *fence,
+                                        uint64_t value);
+
 extern "C" int32_t add_process_to_new_job(DWORD process_id);
 
+#ifdef HWCODEC_TESTUTIL
+extern "C" void hwcodec_debug_set_device_lost(int32_t lost);
+
+// The shims treat the runtime dll name as missing until it is unhidden, their
+// loaders fail for it like for an absent or incomplete dll. A driver's dlls are
+// found in System32 before PATH, tests can't remove them otherwise.
+extern "C" void hwcodec_debug_hide_module(const char *name, int32_t hidden);
+#endif
+
+// checked by the shims before loading a runtime dll
+bool module_hidden(const char *name);
+
+// LoadLibraryA and FreeLibrary of the vendor dlls the shims load themselves,
+// counting the references for shutdown
+void *vendor_module_load(const char *name);
+void vendor_module_free(void *module);
+
+// the references counted by vendor_module_load
+extern "C" int hwcodec_vendor_module_references();
+
+// Frees the counted references still held, e.g. of a session that failed half
+// way, and resets the count. Only while no session is alive. Returns how many.
+extern "C" int hwcodec_free_vendor_modules();
+
+#ifdef HWCODEC_TESTUTIL
+// a shared fence of device and its NT handle, closed with CloseHandle, for tests
+// playing an external renderer
+extern "C" void *hwcodec_debug_new_shared_fence(void *device, void **handle);
+
+extern "C" void hwcodec_debug_close_handle(void *handle);
+
+// Queues an update of texture with data then a signal of fence to value on the
+// immediate context of texture's device, submitted to the gpu only if flush.
+extern "C" int hwcodec_debug_write_and_signal(void *texture, const uint8_t *data,
+                                              int32_t stride, void *fence,
+                                              uint64_t value, int32_t flush);
+
+extern "C" void hwcodec_debug_flush(void *device);
+
+// a cpu readable bgra staging texture, as a caller of
+// Decoder::register_staging would create it
+extern "C" void *hwcodec_debug_new_staging_texture(void *device, int32_t width,
+                                                   int32_t height);
+
+// a shared bgra texture of DXGI_FORMAT_B8G8R8A8_UNORM_SRGB, as an sRGB render
+// target of a game would be
+extern "C" void *hwcodec_debug_new_srgb_texture(void *device, int32_t width,
+                                                int32_t height,
+                                                const uint8_t *data,
+                                                int32_t stride);
+
+// texture must be a shared one of another device
+extern "C" void *hwcodec_debug_open_shared_texture(void *device, void *texture);
+
+// Creates a texture of desc on the device of texture and copies texture into
+// it, as a consumer of DecodeFrame::desc would. Returns -1 if the created
+// texture's D3D11_TEXTURE2D_DESC differs from the one of texture.
+extern "C" int hwcodec_debug_copy_to_texture_of_desc(void *texture,
+                                                     const TextureDesc *desc);
+#endif
+
 #endif
\ No newline at end of file
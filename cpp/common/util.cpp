@@ -3,6 +3,7 @@ extern "C" {
 }
 
 #include "util.h"
+#include <algorithm>
 #include <limits>
 #include <map>
 #include <string.h>
@@ -294,6 +295,198 @@ bool set_others(void *priv_data, const std::string &name) {
   return true;
 }
 
+// unsupported aq settings are ignored with a warning
+bool set_aq(void *priv_data, const std::string &name, int aq_mode,
+            int aq_strength) {
+  std::vector<std::pair<std::string, int64_t>> opts;
+  if (name.find("nvenc") != std::string::npos) {
+    switch (aq_mode) {
+    case AQ_OFF:
+      opts = {{"spatial-aq", 0}, {"temporal-aq", 0}};
+      break;
+    case AQ_SPATIAL:
+      opts = {{"spatial-aq", 1}, {"temporal-aq", 0}};
+      if (aq_strength > 0)
+        opts.push_back({"aq-strength", std::min(aq_strength, 15)});
+      break;
+    case AQ_TEMPORAL:
+      opts = {{"spatial-aq", 0}, {"temporal-aq", 1}};
+      break;
+    }
+    if (aq_mode != AQ_SPATIAL && aq_strength > 0) {
+      LOG_WARN(name + " aq strength only applies to spatial aq, ignored");
+    }
+  } else if (name.find("amf") != std::string::npos ||
+             name.find("qsv") != std::string::npos) {
+    // amf vbaq and qsv mbbrc are spatial only
+    const char *opt = name.find("amf") != std::string::npos ? "vbaq" : "mbbrc";
+    switch (aq_mode) {
+    case AQ_OFF:
+      opts = {{opt, 0}};
+      break;
+    case AQ_SPATIAL:
+      opts = {{opt, 1}};
+      break;
+    case AQ_TEMPORAL:
+      LOG_WARN(name + " temporal aq not supported, ignored");
+      break;
+    }
+    if (aq_strength > 0) {
+      LOG_WARN(name + " aq strength not supported, ignored");
+    }
+  } else if (aq_mode != AQ_DEFAULT || aq_strength > 0) {
+    LOG_WARN(name + " aq not supported, ignored");
+  }
+  for (const auto &opt : opts) {
+    int ret = av_opt_set_int(priv_data, opt.first.c_str(), opt.second, 0);
+    if (ret < 0) {
+      LOG_WARN(name + " set opt " + opt.first + " failed, ret = " +
+               av_err2str(ret));
+    }
+  }
+  return true;
+}
+
+// keyint_min is already the maximum, a min keyframe interval holds for the
+// encoders that honor it
+bool set_scene_cut(void *priv_data, const std::string &name, bool enabled) {
+  if (enabled)
+    return true;
+  std::pair<const char *, int64_t> opt;
+  if (name.find("nvenc") != std::string::npos) {
+    opt = {"no-scenecut", 1};
+  } else if (name.find("amf") != std::string::npos) {
+    opt = {"pa_scene_change_detection_enable", 0};
+  } else if (name.find("qsv") != std::string::npos) {
+    opt = {"adaptive_i", 0};
+  } else {
+    return true;
+  }
+  int ret = av_opt_set_int(priv_data, opt.first, opt.second, 0);
+  if (ret < 0) {
+    LOG_WARN(name + " set opt " + opt.first + " failed, ret = " +
+             av_err2str(ret));
+  }
+  return true;
+}
+
+bool set_entropy_coding(void *priv_data, const std::string &name,
+                        int entropy_coding) {
+  if (name.find("h264") == std::string::npos)
+    return true;
+  bool cavlc = entropy_coding == ENTROPY_CODING_CAVLC;
+  int ret;
+  if (name.find("qsv") != std::string::npos) {
+    ret = av_opt_set_int(priv_data, "cavlc", cavlc, 0);
+  } else if (name.find("nvenc") != std::string::npos ||
+             name.find("amf") != std::string::npos) {
+    ret = av_opt_set(priv_data, "coder", cavlc ? "cavlc" : "cabac", 0);
+  } else {
+    return true;
+  }
+  if (ret < 0) {
+    LOG_ERROR(name + " set entropy coding failed, ret = " + av_err2str(ret));
+    return false;
+  }
+  return true;
+}
+
+// Tune::CloudGaming after set_av_codec_ctx and set_rate_control, options an
+// encoder lacks are ignored with a warning. gop is the refresh period.
+bool set_cloud_gaming(AVCodecContext *c, const std::string &name, int gop,
+                      int fps) {
+  int period = gop > 0 && gop < std::numeric_limits<int16_t>::max() ? gop : fps;
+  // a frame can't take more than its share of the bitrate
+  if (c->bit_rate > 0 && fps > 0)
+    c->rc_buffer_size = (int)(c->bit_rate / fps);
+  c->slices = 4;
+  std::vector<std::pair<std::string, std::string>> opts;
+  if (name.find("nvenc") != std::string::npos) {
+    // nvenc refreshes over gop_size frames and then runs without a gop
+    c->gop_size = period;
+    opts = {{"preset", "p1"},
+            {"tune", "ull"},
+            {"zerolatency", "1"},
+            {"intra-refresh", "1"},
+            {"rc-lookahead", "0"}};
+  } else if (name.find("amf") != std::string::npos) {
+    // a row of macroblocks per frame
+    opts = {{"usage", "ultralowlatency"},
+            {"quality", "speed"},
+            {"intra_refresh_mb", std::to_string((c->width + 15) / 16)}};
+  } else if (name.find("qsv") != std::string::npos) {
+    opts = {{"preset", "veryfast"},
+            {"int_ref_type", "vertical"},
+            {"int_ref_cycle_size", std::to_string(std::max(fps / 2, 2))},
+            {"low_delay_brc", "1"},
+            {"recovery_point_sei", "1"},
+            {"look_ahead_depth", "0"}};
+  }
+  for (const auto &opt : opts) {
+    int ret =
+        av_opt_set(c->priv_data, opt.first.c_str(), opt.second.c_str(), 0);
+    if (ret < 0) {
+      LOG_WARN(name + " set opt " + opt.first + " failed, ret = " +
+               av_err2str(ret));
+    }
+  }
+  return true;
+}
+
+// In place of set_rate_control with a kbs of 0: crf like encoding at quality,
+// 1 - 51, lower is better. qsv runs icq for a global_quality without bitrate.
+bool set_constant_quality(AVCodecContext *c, const std::string &name,
+                          int quality) {
+  std::vector<std::pair<std::string, std::string>> opts;
+  if (name.find("nvenc") != std::string::npos) {
+    opts = {{"rc", "vbr"}, {"cq", std::to_string(quality)}};
+  } else if (name.find("amf") != std::string::npos) {
+    opts = {{"rc", "qvbr"}, {"qvbr_quality_level", std::to_string(quality)}};
+  } else if (name.find("qsv") != std::string::npos) {
+    c->global_quality = quality;
+  }
+  for (const auto &opt : opts) {
+    int ret =
+        av_opt_set(c->priv_data, opt.first.c_str(), opt.second.c_str(), 0);
+    if (ret < 0) {
+      LOG_ERROR(name + " set opt " + opt.first + " failed, ret = " +
+                av_err2str(ret));
+      return false;
+    }
+  }
+  return true;
+}
+
+// In place of the rate control and after set_cloud_gaming, whose tune it
+// replaces: nvenc's lossless tuning, constant qp 0 with the transform
+// bypassed. h264 needs the High 4:4:4 Predictive profile for that. The other
+// encoders have no lossless mode and fail.
+bool set_lossless(AVCodecContext *c, const std::string &name) {
+  if (name.find("nvenc") == std::string::npos) {
+    LOG_ERROR(name + " has no lossless encoding");
+    return false;
+  }
+  c->bit_rate = 0;
+  c->rc_max_rate = 0;
+  c->rc_buffer_size = 0;
+  std::vector<std::pair<std::string, std::string>> opts = {
+      {"tune", "lossless"}, {"rc", "constqp"}, {"qp", "0"}};
+  if (name.find("h264") != std::string::npos) {
+    c->profile = FF_PROFILE_H264_HIGH_444_PREDICTIVE;
+    opts.push_back({"profile", "high444p"});
+  }
+  for (const auto &opt : opts) {
+    int ret =
+        av_opt_set(c->priv_data, opt.first.c_str(), opt.second.c_str(), 0);
+    if (ret < 0) {
+      LOG_ERROR(name + " set opt " + opt.first + " failed, ret = " +
+                av_err2str(ret));
+      return false;
+    }
+  }
+  return true;
+}
+
 bool change_bit_rate(AVCodecContext *c, const std::string &name, int kbs) {
   if (kbs > 0) {
     c->bit_rate = kbs * 1000;
@@ -304,13 +497,14 @@ bool change_bit_rate(AVCodecContext *c, const std::string &name, int kbs) {
   return true;
 }
 
-void vram_encode_test_callback(const uint8_t *data, int32_t len, int32_t key, const void *obj, int64_t pts) {
-  (void)data;
-  (void)len;
-  (void)pts;
+// obj is a TestOutput
+void vram_encode_test_callback(const uint8_t *data, int32_t len, int32_t flags, const void *obj, int64_t pts, uint64_t user_data) {
+  (void)user_data;
   if (obj) {
-    int32_t *pkey = (int32_t *)obj;
-    *pkey = key;
+    TestOutput *out = (TestOutput *)obj;
+    out->key = (flags & FRAME_FLAG_KEYFRAME) ? 1 : 0;
+    if (out->callback)
+      out->callback(data, len, flags, out->obj, pts, (uint64_t)out->luid);
   }
 }
 
@@ -3,6 +3,7 @@
 
 #include <string>
 #include <chrono>
+#include "callback.h"
 extern "C" {
 #include <libavcodec/avcodec.h>
 }
@@ -18,9 +19,28 @@ bool set_rate_control(AVCodecContext *c, const std::string &name, int rc,
 bool set_gpu(void *priv_data, const std::string &name, int gpu);
 bool force_hw(void *priv_data, const std::string &name);
 bool set_others(void *priv_data, const std::string &name);
+bool set_aq(void *priv_data, const std::string &name, int aq_mode,
+            int aq_strength);
+bool set_scene_cut(void *priv_data, const std::string &name, bool enabled);
+bool set_entropy_coding(void *priv_data, const std::string &name,
+                        int entropy_coding);
+bool set_cloud_gaming(AVCodecContext *c, const std::string &name, int gop,
+                      int fps);
+bool set_constant_quality(AVCodecContext *c, const std::string &name,
+                          int quality);
+bool set_lossless(AVCodecContext *c, const std::string &name);
 
 bool change_bit_rate(AVCodecContext *c, const std::string &name, int kbs);
-void vram_encode_test_callback(const uint8_t *data, int32_t len, int32_t key, const void *obj, int64_t pts);
+
+// The obj of vram_encode_test_callback, key is set to 1 for a keyframe. The
+// packet goes on to callback when set, with obj and luid as its user_data.
+struct TestOutput {
+  int32_t key;
+  EncodeCallback callback;
+  void *obj;
+  int64_t luid;
+};
+void vram_encode_test_callback(const uint8_t *data, int32_t len, int32_t flags, const void *obj, int64_t pts, uint64_t user_data);
 
 } // namespace util
 
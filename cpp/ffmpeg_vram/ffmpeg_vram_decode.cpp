@@ -47,6 +47,8 @@ public:
 
   bool bt709_ = false;
   bool full_range_ = false;
+  // output the frames ffmpeg reports as corrupt
+  bool conceal_ = true;
 
   FFmpegVRamDecoder(void *device, int64_t luid, DataFormat dataFormat) {
     device_ = device;
@@ -59,6 +61,11 @@ public:
     case H265:
       name_ = "hevc";
       break;
+    case MJPEG:
+      // opens only with an ffmpeg whose mjpeg decoder has a d3d11va hwaccel,
+      // others output software frames and fail the probe
+      name_ = "mjpeg";
+      break;
     default:
       LOG_ERROR(std::string("unsupported data format"));
       break;
@@ -160,6 +167,20 @@ public:
     return 0;
   }
 
+  // d3d11va decodes what the d3d11 video decoder of the adapter does
+  void caps(DecodeCaps *caps) { native_->decode_caps(dataFormat_, caps); }
+
+  // Without concealment the frames of missing references, which ffmpeg fills
+  // in with generated ones, aren't output.
+  void set_conceal(bool conceal) {
+    conceal_ = conceal;
+    if (conceal) {
+      c_->flags |= AV_CODEC_FLAG_OUTPUT_CORRUPT;
+    } else {
+      c_->flags &= ~AV_CODEC_FLAG_OUTPUT_CORRUPT;
+    }
+  }
+
   int decode(const uint8_t *data, int length, DecodeCallback callback,
              const void *obj) {
     int ret = -1;
@@ -178,7 +199,6 @@ private:
   int do_decode(DecodeCallback callback, const void *obj) {
     int ret;
     bool decoded = false;
-    bool locked = false;
 
     ret = avcodec_send_packet(c_, pkt_);
     if (ret < 0) {
@@ -198,20 +218,26 @@ private:
         LOG_ERROR(std::string("only AV_PIX_FMT_D3D11 is supported"));
         goto _exit;
       }
+      if (!conceal_ && ((frame_->flags & AV_FRAME_FLAG_CORRUPT) ||
+                        frame_->decode_error_flags)) {
+        LOG_TRACE(std::string("dropped a corrupt frame, decode_error_flags=") +
+                  std::to_string(frame_->decode_error_flags));
+        av_frame_unref(frame_);
+        continue;
+      }
+      // once per frame, the lock is recursive
       lockContext(this);
-      locked = true;
-      if (!convert(frame_, callback, obj)) {
+      bool converted = convert(frame_, callback, obj);
+      if (converted && callback)
+        callback(native_->GetCurrentTexture(), obj);
+      unlockContext(this);
+      if (!converted) {
         LOG_ERROR(std::string("Failed to convert"));
         goto _exit;
       }
-      if (callback)
-        callback(native_->GetCurrentTexture(), obj);
       decoded = true;
     }
   _exit:
-    if (locked) {
-      unlockContext(this);
-    }
     av_packet_unref(pkt_);
     return decoded ? 0 : -1;
   }
@@ -229,16 +255,26 @@ private:
       LOG_ERROR(std::string("only DXGI_FORMAT_NV12 is supported"));
       return false;
     }
-    if (!native_->EnsureTexture(frame->width, frame->height)) {
+    // hardware frames are only cropped at the right and bottom by shrinking
+    // width and height, the left and top are left to us
+    int x = (int)frame->crop_left;
+    int y = (int)frame->crop_top;
+    int width = frame->width - x;
+    int height = frame->height - y;
+    if (width <= 0 || height <= 0) {
+      LOG_ERROR(std::string("invalid cropping"));
+      return false;
+    }
+    if (!native_->EnsureTexture(width, height)) {
       LOG_ERROR(std::string("Failed to EnsureTexture"));
       return false;
     }
     native_->next(); // comment out to remove picture shaking
 #ifdef USE_SHADER
     native_->BeginQuery();
-    if (!native_->Nv12ToBgra(frame->width, frame->height, texture,
+    if (!native_->Nv12ToBgra(width, height, texture,
                              native_->GetCurrentTexture(),
-                             (int)frame->data[1])) {
+                             (int)frame->data[1], x, y)) {
       LOG_ERROR(std::string("Failed to Nv12ToBgra"));
       native_->EndQuery();
       return false;
@@ -295,9 +331,14 @@ private:
   }
 };
 
-void lockContext(void *lock_ctx) { (void)lock_ctx; }
+// the context may be shared with other codecs, see DeviceLock
+void lockContext(void *lock_ctx) {
+  lock_device(((FFmpegVRamDecoder *)lock_ctx)->native_->device_.Get());
+}
 
-void unlockContext(void *lock_ctx) { (void)lock_ctx; }
+void unlockContext(void *lock_ctx) {
+  unlock_device(((FFmpegVRamDecoder *)lock_ctx)->native_->device_.Get());
+}
 
 } // namespace
 
@@ -337,11 +378,42 @@ extern "C" FFmpegVRamDecoder *ffmpeg_vram_new_decoder(void *device,
   return NULL;
 }
 
+extern "C" int ffmpeg_vram_decoder_set_conceal(FFmpegVRamDecoder *decoder,
+                                               int32_t conceal) {
+  if (!decoder->c_)
+    return HWCODEC_ERR_COMMON;
+  decoder->set_conceal(conceal != 0);
+  return HWCODEC_SUCCESS;
+}
+
+extern "C" int ffmpeg_vram_decoder_caps(FFmpegVRamDecoder *decoder,
+                                        DecodeCaps *caps) {
+  if (!decoder->native_)
+    return HWCODEC_ERR_COMMON;
+  decoder->caps(caps);
+  return HWCODEC_SUCCESS;
+}
+
+extern "C" int ffmpeg_vram_decoder_memory(FFmpegVRamDecoder *decoder,
+                                          MemoryInfo *info) {
+  if (!decoder->native_) {
+    info->estimated = 0;
+    info->allocated = 0;
+    return 0;
+  }
+  estimate_decoder_memory(decoder->native_.get(), info);
+  return 0;
+}
+
 extern "C" int ffmpeg_vram_decode(FFmpegVRamDecoder *decoder,
                                   const uint8_t *data, int length,
                                   DecodeCallback callback, const void *obj) {
   try {
+    if (decoder->native_ && decoder->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
     int ret = decoder->decode(data, length, callback, obj);
+    if (ret != 0 && decoder->native_ && decoder->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
     if (DataFormat::H265 == decoder->dataFormat_ && util_decode::has_flag_could_not_find_ref_with_poc()) {
       return HWCODEC_ERR_HEVC_COULD_NOT_FIND_POC;
     } else {
@@ -10,6 +10,7 @@ extern "C" {
 #include <libavutil/hwcontext_d3d11va.h>
 #endif
 
+#include <deque>
 #include <memory>
 #include <stdbool.h>
 #include <stdio.h>
@@ -55,6 +56,11 @@ public:
   AVPixelFormat sw_pixfmt_;
 };
 
+// initial_pool_size of the d3d11 hw frames
+#define FFMPEG_VRAM_INPUT_NV12_SURFACES 1
+// frames the encoder dropped would keep their user data forever
+#define FFMPEG_VRAM_MAX_PENDING_FRAMES 64
+
 class FFmpegVRamEncoder {
 public:
   AVCodecContext *c_ = NULL;
@@ -80,9 +86,17 @@ public:
   const int align_ = 0;
   const bool full_range_ = false;
   const bool bt709_ = false;
+  EncodeOptions options_ = {};
+  // options_.ffmpegOptions, which the caller frees after new
+  std::string overrides_;
+  int64_t allocated_ = 0;
+  bool force_idr_ = false;
+  // (pts, user data) of the frames sent and not received yet, in sending order
+  std::deque<std::pair<int64_t, uint64_t>> pending_;
   FFmpegVRamEncoder(void *handle, int64_t luid, DataFormat dataFormat,
                     int32_t width, int32_t height, int32_t kbs,
-                    int32_t framerate, int32_t gop) {
+                    int32_t framerate, int32_t gop,
+                    const EncodeOptions *options) {
     handle_ = handle;
     luid_ = luid;
     dataFormat_ = dataFormat;
@@ -91,6 +105,12 @@ public:
     kbs_ = kbs;
     framerate_ = framerate;
     gop_ = gop;
+    if (options) {
+      options_ = *options;
+      if (options->ffmpegOptions)
+        overrides_ = options->ffmpegOptions;
+    }
+    options_.ffmpegOptions = NULL;
   }
 
   ~FFmpegVRamEncoder() {}
@@ -129,13 +149,9 @@ public:
     c_->height = height_;
     c_->pix_fmt = encoder_->hw_pixfmt_;
     c_->sw_pix_fmt = encoder_->sw_pixfmt_;
-    util_encode::set_av_codec_ctx(c_, encoder_->name_, kbs_, gop_, framerate_);
-    if (!util_encode::set_lantency_free(c_->priv_data, encoder_->name_)) {
+    if (!set_options()) {
       return false;
     }
-    // util_encode::set_quality(c_->priv_data, encoder_->name_, Quality_Default);
-    util_encode::set_rate_control(c_, encoder_->name_, RC_CBR, -1);
-    util_encode::set_others(c_->priv_data, encoder_->name_);
 
     hw_device_ctx_ = av_hwdevice_ctx_alloc(encoder_->device_type_);
     if (!hw_device_ctx_) {
@@ -223,12 +239,84 @@ public:
     return true;
   }
 
-  int encode(void *texture, EncodeCallback callback, void *obj, int64_t ms) {
+  // the options of init on the context of the vendor's encoder, in place of a
+  // session: those differing from the defaults as "key=value" lines
+  bool derive_options(AdapterVendor vendor, std::string &out) {
+    const AVCodec *codec = NULL;
+    if (!choose_encoder(vendor) ||
+        !(codec = avcodec_find_encoder_by_name(encoder_->name_.c_str())))
+      return false;
+    if (!(c_ = avcodec_alloc_context3(codec)))
+      return false;
+    c_->width = width_;
+    c_->height = height_;
+    c_->pix_fmt = encoder_->hw_pixfmt_;
+    c_->sw_pix_fmt = encoder_->sw_pixfmt_;
+    if (!set_options())
+      return false;
+    out.clear();
+    void *objs[] = {c_, c_->priv_data};
+    for (void *obj : objs) {
+      char *buf = NULL;
+      if (!obj)
+        continue;
+      if (av_opt_serialize(obj, AV_OPT_FLAG_ENCODING_PARAM,
+                           AV_OPT_SERIALIZE_SKIP_DEFAULTS, &buf, '=',
+                           '\n') < 0)
+        return false;
+      if (buf && *buf) {
+        if (!out.empty())
+          out += '\n';
+        out += buf;
+      }
+      av_free(buf);
+    }
+    return true;
+  }
+
+  int encode(void *texture, EncodeCallback callback, void *obj, int64_t ms,
+             uint64_t user_data) {
 
     if (!convert(texture))
       return -1;
 
-    return do_encode(callback, obj, ms);
+    return do_encode(callback, obj, ms, user_data);
+  }
+
+  // ffmpeg has no caps query, only the encoder of the adapter's vendor and the
+  // options init leaves to util_encode are checked. False with the first
+  // parameter init wouldn't take as given in check.
+  bool check_config(ConfigCheck *check) {
+    auto reject = [&](ConfigParam param, bool hasCorrected, int32_t corrected) {
+      check->param = param;
+      check->hasCorrected = hasCorrected ? 1 : 0;
+      check->corrected = corrected;
+      return false;
+    };
+    native_ = std::make_unique<NativeDevice>();
+    if (!native_->Init(luid_, (ID3D11Device *)handle_)) {
+      LOG_ERROR(std::string("NativeDevice init failed"));
+      return reject(CONFIG_PARAM_NONE, false, 0);
+    }
+    if (!choose_encoder(native_->GetVendor()) ||
+        !avcodec_find_encoder_by_name(encoder_->name_.c_str()))
+      return reject(CONFIG_PARAM_FORMAT, false, 0);
+    // see util_encode::set_aq
+    const std::string &name = encoder_->name_;
+    bool nvenc = name.find("nvenc") != std::string::npos;
+    if (!nvenc && options_.aqMode == AQ_TEMPORAL)
+      return reject(CONFIG_PARAM_AQ_MODE, true, AQ_DEFAULT);
+    if (options_.aqStrength > 0 && (!nvenc || options_.aqMode != AQ_SPATIAL))
+      return reject(CONFIG_PARAM_AQ_STRENGTH, true, 0);
+    if (options_.aqStrength > 15)
+      return reject(CONFIG_PARAM_AQ_STRENGTH, true, 15);
+    if (name.find("amf") != std::string::npos &&
+        options_.chromaLocation != CHROMA_LOC_LEFT)
+      return reject(CONFIG_PARAM_CHROMA_LOCATION, true, CHROMA_LOC_LEFT);
+    // see util_encode::set_lossless
+    if (!nvenc && options_.lossless)
+      return reject(CONFIG_PARAM_LOSSLESS, true, 0);
+    return true;
   }
 
   void destroy() {
@@ -255,13 +343,106 @@ public:
     return util_encode::change_bit_rate(c_, encoder_->name_, kbs) ? 0 : -1;
   }
 
+  // the time base stays 1/1000, the pts are ms
   int set_framerate(int framerate) {
-    c_->time_base = av_make_q(1, framerate);
-    c_->framerate = av_inv_q(c_->time_base);
+    c_->framerate = av_make_q(framerate, 1);
+    return 0;
+  }
+
+  // drains the packets still in the encoder, it takes no frames afterwards
+  int flush(EncodeCallback callback, const void *obj) {
+    int ret = avcodec_send_frame(c_, NULL);
+    if (ret < 0 && ret != AVERROR_EOF) {
+      LOG_ERROR(std::string("avcodec_send_frame flush failed, ret = ") + av_err2str(ret));
+      return ret;
+    }
+    while ((ret = avcodec_receive_packet(c_, pkt_)) >= 0) {
+      if (callback && pkt_->data && pkt_->size)
+        callback(pkt_->data, pkt_->size, packet_flags(), obj, pkt_->pts,
+                 take_user_data(pkt_->pts));
+      av_packet_unref(pkt_);
+    }
+    if (ret != AVERROR_EOF) {
+      LOG_ERROR(std::string("avcodec_receive_packet flush failed, ret = ") + av_err2str(ret));
+      return ret;
+    }
     return 0;
   }
 
 private:
+  // what init sets on c_ before the session is opened, the derived options
+  // and then the caller's overrides, which fail init if ffmpeg rejects them
+  bool set_options() {
+    // Tune::CloudGaming has no gop, set_cloud_gaming takes it as the refresh
+    util_encode::set_av_codec_ctx(c_, encoder_->name_, kbs_,
+                                  options_.cloudGaming ? 0 : gop_, framerate_);
+    if (!util_encode::set_lantency_free(c_->priv_data, encoder_->name_)) {
+      return false;
+    }
+    // util_encode::set_quality(c_->priv_data, encoder_->name_, Quality_Default);
+    if (options_.quality > 0) {
+      if (!util_encode::set_constant_quality(c_, encoder_->name_,
+                                             options_.quality))
+        return false;
+    } else {
+      util_encode::set_rate_control(c_, encoder_->name_, RC_CBR, -1);
+    }
+    if (options_.cloudGaming)
+      util_encode::set_cloud_gaming(c_, encoder_->name_, gop_, framerate_);
+    if (options_.lossless &&
+        !util_encode::set_lossless(c_, encoder_->name_))
+      return false;
+    util_encode::set_others(c_->priv_data, encoder_->name_);
+    util_encode::set_aq(c_->priv_data, encoder_->name_, options_.aqMode,
+                        options_.aqStrength);
+    util_encode::set_scene_cut(c_->priv_data, encoder_->name_,
+                               !options_.noSceneCutKeyframes);
+    if (!util_encode::set_entropy_coding(c_->priv_data, encoder_->name_,
+                                         options_.entropyCoding)) {
+      return false;
+    }
+    // AVChromaLocation is chroma_sample_loc_type + 1, amf ignores it
+    c_->chroma_sample_location =
+        (AVChromaLocation)(options_.chromaLocation + 1);
+    if (options_.sarWidth > 0 && options_.sarHeight > 0 &&
+        options_.sarWidth != options_.sarHeight)
+      c_->sample_aspect_ratio =
+          av_make_q(options_.sarWidth, options_.sarHeight);
+    // makes an I pict_type an IDR, nvenc names it forced-idr
+    if (av_opt_set_int(c_->priv_data, "forced-idr", 1, 0) < 0 &&
+        av_opt_set_int(c_->priv_data, "forced_idr", 1, 0) < 0) {
+      LOG_WARN(std::string("forced idr not supported by ") + encoder_->name_);
+    }
+
+    return set_overrides();
+  }
+
+  bool set_overrides() {
+    size_t start = 0;
+    while (start < overrides_.size()) {
+      size_t end = overrides_.find('\n', start);
+      if (end == std::string::npos)
+        end = overrides_.size();
+      std::string line = overrides_.substr(start, end - start);
+      start = end + 1;
+      size_t eq = line.find('=');
+      if (eq == std::string::npos || eq == 0) {
+        LOG_ERROR(std::string("invalid ffmpeg option: ") + line);
+        return false;
+      }
+      std::string key = line.substr(0, eq);
+      std::string value = line.substr(eq + 1);
+      int ret = av_opt_set(c_, key.c_str(), value.c_str(),
+                           AV_OPT_SEARCH_CHILDREN);
+      if (ret < 0) {
+        LOG_ERROR(encoder_->name_ + " set opt " + key + " " + value +
+                  " failed, ret = " + av_err2str(ret));
+        return false;
+      }
+    }
+    return true;
+  }
+
   bool choose_encoder(AdapterVendor vendor) {
     if (ADAPTER_VENDOR_NVIDIA == vendor) {
       const char *name = nullptr;
@@ -311,14 +492,20 @@ private:
     }
     return false;
   }
-  int do_encode(EncodeCallback callback, const void *obj, int64_t ms) {
+  int do_encode(EncodeCallback callback, const void *obj, int64_t ms,
+                uint64_t user_data) {
     int ret;
     bool encoded = false;
     frame_->pts = ms;
+    frame_->pict_type = force_idr_ ? AV_PICTURE_TYPE_I : AV_PICTURE_TYPE_NONE;
     if ((ret = avcodec_send_frame(c_, frame_)) < 0) {
       LOG_ERROR(std::string("avcodec_send_frame failed, ret = ") + av_err2str(ret));
       return ret;
     }
+    force_idr_ = false;
+    pending_.emplace_back(ms, user_data);
+    if (pending_.size() > FFMPEG_VRAM_MAX_PENDING_FRAMES)
+      pending_.pop_front();
 
     auto start = util::now();
     while (ret >= 0 && util::elapsed_ms(start) < ENCODE_TIMEOUT_MS) {
@@ -333,15 +520,40 @@ private:
         goto _exit;
       }
       encoded = true;
+      // packets without a pts are of the oldest pending frame, never made up
+      // from the framerate
+      if (pkt_->pts == AV_NOPTS_VALUE && !pending_.empty())
+        pkt_->pts = pending_.front().first;
       if (callback)
-        callback(pkt_->data, pkt_->size, pkt_->flags & AV_PKT_FLAG_KEY, obj,
-                 pkt_->pts);
+        callback(pkt_->data, pkt_->size, packet_flags(), obj, pkt_->pts,
+                 take_user_data(pkt_->pts));
     }
   _exit:
     av_packet_unref(pkt_);
     return encoded ? 0 : -1;
   }
 
+  int32_t packet_flags() {
+    int32_t flags = 0;
+    if (pkt_->flags & AV_PKT_FLAG_KEY)
+      flags |= FRAME_FLAG_KEYFRAME;
+    if (pkt_->flags & AV_PKT_FLAG_DISPOSABLE)
+      flags |= FRAME_FLAG_NON_REFERENCE;
+    return flags;
+  }
+
+  // the oldest pending frame with pts, the packets may come later or reordered
+  uint64_t take_user_data(int64_t pts) {
+    for (auto it = pending_.begin(); it != pending_.end(); it++) {
+      if (it->first == pts) {
+        uint64_t user_data = it->second;
+        pending_.erase(it);
+        return user_data;
+      }
+    }
+    return 0;
+  }
+
   bool convert(void *texture) {
     if (frame_->format == AV_PIX_FMT_D3D11 ||
         frame_->format == AV_PIX_FMT_QSV) {
@@ -371,7 +583,8 @@ private:
         }
       }
       if (!native_->BgraToNv12((ID3D11Texture2D *)texture, texture2D, width_,
-                               height_, colorSpace_in, colorSpace_out)) {
+                               height_, colorSpace_in, colorSpace_out,
+                               options_.colorConvert)) {
         LOG_ERROR(std::string("convert: BgraToNv12 failed"));
         return false;
       }
@@ -422,9 +635,14 @@ private:
   }
 };
 
-void lockContext(void *lock_ctx) { (void)lock_ctx; }
+// the context may be shared with other codecs, see DeviceLock
+void lockContext(void *lock_ctx) {
+  lock_device(((FFmpegVRamEncoder *)lock_ctx)->native_->device_.Get());
+}
 
-void unlockContext(void *lock_ctx) { (void)lock_ctx; }
+void unlockContext(void *lock_ctx) {
+  unlock_device(((FFmpegVRamEncoder *)lock_ctx)->native_->device_.Get());
+}
 
 } // namespace
 
@@ -432,13 +650,17 @@ extern "C" {
 FFmpegVRamEncoder *ffmpeg_vram_new_encoder(void *handle, int64_t luid,
                                            DataFormat dataFormat, int32_t width,
                                            int32_t height, int32_t kbs,
-                                           int32_t framerate, int32_t gop) {
+                                           int32_t framerate, int32_t gop,
+                                           const EncodeOptions *options) {
   FFmpegVRamEncoder *encoder = NULL;
   try {
+    int64_t usage = video_memory_usage((ID3D11Device *)handle);
     encoder = new FFmpegVRamEncoder(handle, luid, dataFormat, width,
-                                    height, kbs, framerate, gop);
+                                    height, kbs, framerate, gop, options);
     if (encoder) {
       if (encoder->init()) {
+        usage = video_memory_usage((ID3D11Device *)handle) - usage;
+        encoder->allocated_ = usage > 0 ? usage : 0;
         return encoder;
       }
     }
@@ -454,12 +676,28 @@ FFmpegVRamEncoder *ffmpeg_vram_new_encoder(void *handle, int64_t luid,
 }
 
 int ffmpeg_vram_encode(FFmpegVRamEncoder *encoder, void *texture,
-                       EncodeCallback callback, void *obj, int64_t ms) {
+                       EncodeCallback callback, void *obj, int64_t ms,
+                       uint64_t user_data) {
   try {
-    return encoder->encode(texture, callback, obj, ms);
+    if (encoder->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    int ret = check_input_texture(encoder->native_->device_.Get(),
+                                  (ID3D11Texture2D *)texture);
+    if (ret != 0)
+      return ret;
+    ret = encoder->encode(texture, callback, obj, ms, user_data);
+    if (ret != 0) {
+      if (encoder->native_->IsDeviceLost())
+        return HWCODEC_ERR_DEVICE_LOST;
+      if (encoder->native_->input_access_denied_)
+        return HWCODEC_ERR_INPUT_ACCESS_DENIED;
+    }
+    return ret;
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("ffmpeg_vram_encode failed, ") + std::string(e.what()));
   }
+  if (encoder->native_->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
   return -1;
 }
 
@@ -475,6 +713,150 @@ void ffmpeg_vram_destroy_encoder(FFmpegVRamEncoder *encoder) {
   }
 }
 
+int ffmpeg_vram_encoder_memory(FFmpegVRamEncoder *encoder, MemoryInfo *info) {
+  estimate_encoder_memory(encoder->dataFormat_, encoder->width_,
+                          encoder->height_, 0, FFMPEG_VRAM_INPUT_NV12_SURFACES,
+                          info);
+  info->allocated = encoder->allocated_;
+  return 0;
+}
+
+// the conversion and encode run inside ffmpeg, there is nothing to time around
+int ffmpeg_vram_encoder_gpu_timings(FFmpegVRamEncoder *encoder,
+                                    GpuTiming *timings, int32_t max,
+                                    int32_t *count) {
+  *count = 0;
+  return 0;
+}
+
+int ffmpeg_vram_encoder_flush(FFmpegVRamEncoder *encoder,
+                              EncodeCallback callback, void *obj) {
+  try {
+    return encoder->flush(callback, obj);
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("ffmpeg_vram_encoder_flush failed, ") + std::string(e.what()));
+  }
+  return -1;
+}
+
+// no emphasis maps, the caps never report ENCODE_CAP_EMPHASIS_MAP
+int ffmpeg_vram_encoder_set_emphasis_map(FFmpegVRamEncoder *encoder,
+                                         const uint8_t *map, int32_t len) {
+  return HWCODEC_ERR_COMMON;
+}
+
+// no per frame qp offset through the ffmpeg encoders
+int ffmpeg_vram_encoder_set_qp_delta(FFmpegVRamEncoder *encoder,
+                                     int32_t delta) {
+  return HWCODEC_ERR_COMMON;
+}
+
+// the ffmpeg encoders don't pass reference invalidation on
+int ffmpeg_vram_encoder_invalidate_reference(FFmpegVRamEncoder *encoder,
+                                             int64_t ms) {
+  return HWCODEC_ERR_COMMON;
+}
+
+// the implementation is the ffmpeg encoder, the version libavcodec's
+int ffmpeg_vram_encoder_info(FFmpegVRamEncoder *encoder, RuntimeInfo *info) {
+  *info = {};
+  snprintf(info->runtime, sizeof(info->runtime), "FFmpeg");
+  if (encoder->encoder_)
+    snprintf(info->implementation, sizeof(info->implementation), "%s",
+             encoder->encoder_->name_.c_str());
+  unsigned version = avcodec_version();
+  info->apiMajor = AV_VERSION_MAJOR(version);
+  info->apiMinor = AV_VERSION_MINOR(version);
+  if (encoder->c_)
+    info->bframes = encoder->c_->max_b_frames;
+  return 0;
+}
+
+int ffmpeg_vram_estimate_encoder_memory(int32_t dataFormat, int32_t width,
+                                        int32_t height, MemoryInfo *info) {
+  estimate_encoder_memory((DataFormat)dataFormat, width, height, 0,
+                          FFMPEG_VRAM_INPUT_NV12_SURFACES, info);
+  info->allocated = 0;
+  return 0;
+}
+
+// 0 when the configuration passes FFmpegVRamEncoder::check_config, no codec
+// context is opened
+int ffmpeg_vram_check_encoder_config(void *handle, int64_t luid,
+                                     DataFormat dataFormat, int32_t width,
+                                     int32_t height, int32_t kbs,
+                                     int32_t framerate, int32_t gop,
+                                     const EncodeOptions *options,
+                                     ConfigCheck *check) {
+  *check = {};
+  FFmpegVRamEncoder *encoder = NULL;
+  int ret = HWCODEC_ERR_COMMON;
+  try {
+    encoder = new FFmpegVRamEncoder(handle, luid, dataFormat, width, height,
+                                    kbs, framerate, gop, options);
+    ret = encoder->check_config(check) ? HWCODEC_SUCCESS : HWCODEC_ERR_COMMON;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("ffmpeg_vram_check_encoder_config failed, ") +
+              std::string(e.what()));
+  }
+  if (encoder) {
+    encoder->destroy();
+    delete encoder;
+  }
+  return ret;
+}
+
+// the options ffmpeg_vram_new_encoder would set for an adapter of vendor as
+// "key=value" lines into buf of len bytes, nul terminated. Returns the length
+// without the nul, larger than len - 1 if buf is too small, or an error.
+int ffmpeg_vram_encoder_options(int32_t vendor, DataFormat dataFormat,
+                                int32_t width, int32_t height, int32_t kbs,
+                                int32_t framerate, int32_t gop,
+                                const EncodeOptions *options, char *buf,
+                                int32_t len) {
+  FFmpegVRamEncoder *encoder = NULL;
+  int ret = HWCODEC_ERR_COMMON;
+  try {
+    encoder = new FFmpegVRamEncoder(NULL, 0, dataFormat, width, height, kbs,
+                                    framerate, gop, options);
+    std::string out;
+    if (encoder->derive_options((AdapterVendor)vendor, out)) {
+      if (buf && len > 0)
+        snprintf(buf, len, "%s", out.c_str());
+      ret = (int)out.size();
+    }
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("ffmpeg_vram_encoder_options failed, ") +
+              std::string(e.what()));
+  }
+  if (encoder) {
+    encoder->destroy();
+    delete encoder;
+  }
+  return ret;
+}
+
+// ffmpeg doesn't expose the vendor session, an open context on a live device
+// is all that can be checked
+int ffmpeg_vram_check_encoder(FFmpegVRamEncoder *encoder) {
+  try {
+    if (encoder->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    if (!encoder->c_ || !avcodec_is_open(encoder->c_))
+      return HWCODEC_ERR_SESSION_LOST;
+    return HWCODEC_SUCCESS;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("ffmpeg_vram_check_encoder failed, ") + std::string(e.what()));
+  }
+  return HWCODEC_ERR_COMMON;
+}
+
+// the next encoded frame is an IDR with the parameter sets
+int ffmpeg_vram_request_keyframe(FFmpegVRamEncoder *encoder) {
+  encoder->force_idr_ = true;
+  return 0;
+}
+
 int ffmpeg_vram_set_bitrate(FFmpegVRamEncoder *encoder, int kbs) {
   try {
     return encoder->set_bitrate(kbs);
@@ -486,18 +868,20 @@ int ffmpeg_vram_set_bitrate(FFmpegVRamEncoder *encoder, int kbs) {
 
 int ffmpeg_vram_set_framerate(FFmpegVRamEncoder *encoder, int32_t framerate) {
   try {
-    return encoder->set_bitrate(framerate);
+    return encoder->set_framerate(framerate);
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("ffmpeg_vram_set_framerate failed, ") + std::string(e.what()));
   }
   return -1;
 }
 
-int ffmpeg_vram_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum,
+int ffmpeg_vram_test_encode(int64_t *outLuids, int32_t *outVendors,
+                            EncodeCaps *outCaps, int32_t maxDescNum,
                             int32_t *outDescNum, DataFormat dataFormat,
                             int32_t width, int32_t height, int32_t kbs,
                             int32_t framerate, int32_t gop,
-                            const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount) {
+                            const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount,
+                            EncodeCallback callback, void *obj) {
   try {
     int count = 0;
     struct VendorMapping {
@@ -522,19 +906,21 @@ int ffmpeg_vram_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxD
         
         FFmpegVRamEncoder *e = (FFmpegVRamEncoder *)ffmpeg_vram_new_encoder(
             (void *)adapter.get()->device_.Get(), currentLuid,
-            dataFormat, width, height, kbs, framerate, gop);
+            dataFormat, width, height, kbs, framerate, gop, nullptr);
         if (!e)
           continue;
         if (e->native_->EnsureTexture(e->width_, e->height_)) {
           e->native_->next();
-          int32_t key_obj = 0;
+          util_encode::TestOutput out = {0, callback, obj, currentLuid};
           auto start = util::now();
           bool succ = ffmpeg_vram_encode(e, e->native_->GetCurrentTexture(), util_encode::vram_encode_test_callback,
-                                 &key_obj, 0) == 0 && key_obj == 1;
+                                 &out, 0, 0) == 0 && out.key == 1;
           int64_t elapsed = util::elapsed_ms(start);
           if (succ && elapsed < TEST_TIMEOUT_MS) {
             outLuids[count] = currentLuid;
             outVendors[count] = (int32_t)vendorMap.driver_vendor;  // Map adapter vendor to driver vendor
+            // ffmpeg doesn't expose the driver's caps
+            outCaps[count] = {};
             count += 1;
           }
         }
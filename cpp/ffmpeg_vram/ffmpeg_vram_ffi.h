@@ -4,28 +4,71 @@
 #include "../common/callback.h"
 #include <stdbool.h>
 
+struct EncodeOptions;
+struct MemoryInfo;
+struct EncodeCaps;
+struct DecodeCaps;
+struct RuntimeInfo;
+struct GpuTiming;
+struct ConfigCheck;
+
 void *ffmpeg_vram_new_decoder(void *device, int64_t luid,
                               int32_t codecID);
 int ffmpeg_vram_decode(void *decoder, uint8_t *data, int len,
                        DecodeCallback callback, void *obj);
 int ffmpeg_vram_destroy_decoder(void *decoder);
+int ffmpeg_vram_decoder_memory(void *decoder, struct MemoryInfo *info);
+int ffmpeg_vram_decoder_set_conceal(void *decoder, int32_t conceal);
+int ffmpeg_vram_decoder_caps(void *decoder, struct DecodeCaps *caps);
 int ffmpeg_vram_test_decode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum,
                             int32_t *outDescNum,
                             int32_t dataFormat, uint8_t *data, int32_t length,
                             const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount);
 void *ffmpeg_vram_new_encoder(void *handle, int64_t luid,
                               int32_t dataFormat, int32_t width, int32_t height,
-                              int32_t kbs, int32_t framerate, int32_t gop);
+                              int32_t kbs, int32_t framerate, int32_t gop,
+                              const struct EncodeOptions *options);
 
 int ffmpeg_vram_encode(void *encoder, void *tex, EncodeCallback callback,
-                       void *obj, int64_t ms);
+                       void *obj, int64_t ms, uint64_t user_data);
 int ffmpeg_vram_destroy_encoder(void *encoder);
 
-int ffmpeg_vram_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum,
+int ffmpeg_vram_test_encode(int64_t *outLuids, int32_t *outVendors,
+                            struct EncodeCaps *outCaps, int32_t maxDescNum,
                             int32_t *outDescNum,
                             int32_t dataFormat, int32_t width, int32_t height,
                             int32_t kbs, int32_t framerate, int32_t gop,
-                            const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount);
+                            const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount,
+                            EncodeCallback callback, void *obj);
+void *ffmpeg_vram_new_jpeg_encoder(void *device, int64_t luid, int32_t quality);
+int ffmpeg_vram_jpeg_encode(void *encoder, void *tex, int32_t width,
+                            int32_t height, EncodeCallback callback, void *obj);
+int ffmpeg_vram_destroy_jpeg_encoder(void *encoder);
+int ffmpeg_vram_check_encoder(void *encoder);
+int ffmpeg_vram_encoder_memory(void *encoder, struct MemoryInfo *info);
+int ffmpeg_vram_encoder_info(void *encoder, struct RuntimeInfo *info);
+int ffmpeg_vram_encoder_gpu_timings(void *encoder, struct GpuTiming *timings,
+                                    int32_t max, int32_t *count);
+int ffmpeg_vram_encoder_flush(void *encoder, EncodeCallback callback,
+                              void *obj);
+int ffmpeg_vram_encoder_set_emphasis_map(void *encoder, const uint8_t *map,
+                                         int32_t len);
+int ffmpeg_vram_encoder_set_qp_delta(void *encoder, int32_t delta);
+int ffmpeg_vram_encoder_invalidate_reference(void *encoder, int64_t ms);
+int ffmpeg_vram_estimate_encoder_memory(int32_t dataFormat, int32_t width,
+                                        int32_t height,
+                                        struct MemoryInfo *info);
+int ffmpeg_vram_check_encoder_config(void *handle, int64_t luid, int32_t dataFormat,
+                                     int32_t width, int32_t height, int32_t kbs,
+                                     int32_t framerate, int32_t gop,
+                                     const struct EncodeOptions *options,
+                                     struct ConfigCheck *check);
+int ffmpeg_vram_encoder_options(int32_t vendor, int32_t dataFormat,
+                                int32_t width, int32_t height, int32_t kbs,
+                                int32_t framerate, int32_t gop,
+                                const struct EncodeOptions *options, char *buf,
+                                int32_t len);
+int ffmpeg_vram_request_keyframe(void *encoder);
 int ffmpeg_vram_set_bitrate(void *encoder, int32_t kbs);
 int ffmpeg_vram_set_framerate(void *encoder, int32_t framerate);
 
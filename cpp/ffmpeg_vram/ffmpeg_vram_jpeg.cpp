@@ -0,0 +1,233 @@
+extern "C" {
+#include <libavcodec/avcodec.h>
+#include <libavutil/imgutils.h>
+#include <libavutil/log.h>
+}
+
+#include <memory>
+#include <vector>
+
+#include "callback.h"
+#include "common.h"
+#include "system.h"
+
+#define LOG_MODULE "FFMPEG_VRAM_JPEG"
+#include <log.h>
+#include <util.h>
+
+namespace {
+
+// The snapshot fallback, the bgra texture is read back and coded by the
+// software mjpeg encoder as full range bt.601 yuvj420p. There is no swscale
+// here, the conversion is done by hand.
+class FFmpegVRamJpegEncoder {
+public:
+  std::unique_ptr<NativeDevice> native_ = nullptr;
+  AVCodecContext *c_ = NULL;
+  AVFrame *frame_ = NULL;
+  AVPacket *pkt_ = NULL;
+  std::vector<uint8_t> bgra_;
+
+  void *device_;
+  int64_t luid_;
+  int32_t quality_;
+
+  FFmpegVRamJpegEncoder(void *device, int64_t luid, int32_t quality) {
+    device_ = device;
+    luid_ = luid;
+    quality_ = quality;
+  }
+
+  bool init() {
+    native_ = std::make_unique<NativeDevice>();
+    if (!native_->Init(luid_, (ID3D11Device *)device_)) {
+      LOG_ERROR(std::string("NativeDevice init failed"));
+      return false;
+    }
+    if (!avcodec_find_encoder_by_name("mjpeg")) {
+      LOG_ERROR(std::string("Codec mjpeg not found"));
+      return false;
+    }
+    if (!(frame_ = av_frame_alloc()) || !(pkt_ = av_packet_alloc())) {
+      LOG_ERROR(std::string("Could not allocate frame or packet"));
+      return false;
+    }
+    return true;
+  }
+
+  int encode(ID3D11Texture2D *tex, int width, int height,
+             EncodeCallback callback, void *obj) {
+    if (!c_ || c_->width != width || c_->height != height) {
+      if (!open(width, height))
+        return -1;
+    }
+    bgra_.resize((size_t)width * height * 4);
+    if (hwcodec_read_d3d11_bgra_texture(tex, bgra_.data(), width * 4,
+                                        height) != 0) {
+      LOG_ERROR(std::string("Failed to read the texture"));
+      return -1;
+    }
+    int ret = av_frame_make_writable(frame_);
+    if (ret < 0) {
+      LOG_ERROR(std::string("av_frame_make_writable failed, ret = ") +
+                av_err2str(ret));
+      return -1;
+    }
+    bgra_to_yuvj420p(width, height);
+
+    if ((ret = avcodec_send_frame(c_, frame_)) < 0) {
+      LOG_ERROR(std::string("avcodec_send_frame failed, ret = ") +
+                av_err2str(ret));
+      return -1;
+    }
+    if ((ret = avcodec_receive_packet(c_, pkt_)) < 0) {
+      LOG_ERROR(std::string("avcodec_receive_packet failed, ret = ") +
+                av_err2str(ret));
+      return -1;
+    }
+    if (callback)
+      callback(pkt_->data, pkt_->size, 1, obj, 0, 0);
+    av_packet_unref(pkt_);
+    return 0;
+  }
+
+  void destroy() {
+    if (c_)
+      avcodec_free_context(&c_);
+    if (frame_)
+      av_frame_free(&frame_);
+    if (pkt_)
+      av_packet_free(&pkt_);
+  }
+
+private:
+  bool open(int width, int height) {
+    if (c_)
+      avcodec_free_context(&c_);
+    av_frame_unref(frame_);
+    const AVCodec *codec = avcodec_find_encoder_by_name("mjpeg");
+    if (!(c_ = avcodec_alloc_context3(codec))) {
+      LOG_ERROR(std::string("Could not allocate video codec context"));
+      return false;
+    }
+    c_->width = width;
+    c_->height = height;
+    c_->pix_fmt = AV_PIX_FMT_YUVJ420P;
+    c_->color_range = AVCOL_RANGE_JPEG;
+    c_->colorspace = AVCOL_SPC_BT470BG;
+    c_->time_base = av_make_q(1, 30);
+    // quality 1 - 100 onto qscale 31 - 2
+    int qscale = 31 - (quality_ - 1) * 29 / 99;
+    c_->flags |= AV_CODEC_FLAG_QSCALE;
+    c_->global_quality = FF_QP2LAMBDA * qscale;
+    int ret = avcodec_open2(c_, codec, NULL);
+    if (ret < 0) {
+      LOG_ERROR(std::string("avcodec_open2 failed, ret = ") + av_err2str(ret));
+      avcodec_free_context(&c_);
+      return false;
+    }
+    frame_->format = c_->pix_fmt;
+    frame_->width = width;
+    frame_->height = height;
+    frame_->quality = c_->global_quality;
+    if ((ret = av_frame_get_buffer(frame_, 0)) < 0) {
+      LOG_ERROR(std::string("av_frame_get_buffer failed, ret = ") +
+                av_err2str(ret));
+      avcodec_free_context(&c_);
+      return false;
+    }
+    return true;
+  }
+
+  // chroma of each 2x2 block from its average, odd edges repeat the last
+  // column or row
+  void bgra_to_yuvj420p(int width, int height) {
+    const uint8_t *src = bgra_.data();
+    for (int y = 0; y < height; y++) {
+      uint8_t *dy = frame_->data[0] + (size_t)y * frame_->linesize[0];
+      const uint8_t *s = src + (size_t)y * width * 4;
+      for (int x = 0; x < width; x++, s += 4) {
+        dy[x] = (uint8_t)((19595 * s[2] + 38470 * s[1] + 7471 * s[0] +
+                           32768) >> 16);
+      }
+    }
+    for (int y = 0; y < (height + 1) / 2; y++) {
+      uint8_t *du = frame_->data[1] + (size_t)y * frame_->linesize[1];
+      uint8_t *dv = frame_->data[2] + (size_t)y * frame_->linesize[2];
+      int y0 = y * 2, y1 = y0 + 1 < height ? y0 + 1 : y0;
+      for (int x = 0; x < (width + 1) / 2; x++) {
+        int x0 = x * 2, x1 = x0 + 1 < width ? x0 + 1 : x0;
+        int b = 0, g = 0, r = 0;
+        for (int yy : {y0, y1}) {
+          for (int xx : {x0, x1}) {
+            const uint8_t *p = src + ((size_t)yy * width + xx) * 4;
+            b += p[0];
+            g += p[1];
+            r += p[2];
+          }
+        }
+        // (-0.168736, -0.331264, 0.5) and (0.5, -0.418688, -0.081312) << 16,
+        // over the 4 samples
+        du[x] = (uint8_t)((-11059 * r - 21709 * g + 32768 * b +
+                           (128 << 18) + (1 << 17)) >> 18);
+        dv[x] = (uint8_t)((32768 * r - 27439 * g - 5329 * b + (128 << 18) +
+                           (1 << 17)) >> 18);
+      }
+    }
+  }
+};
+
+} // namespace
+
+extern "C" {
+
+void *ffmpeg_vram_new_jpeg_encoder(void *device, int64_t luid,
+                                   int32_t quality) {
+  FFmpegVRamJpegEncoder *encoder = NULL;
+  try {
+    encoder = new FFmpegVRamJpegEncoder(device, luid, quality);
+    if (encoder->init())
+      return encoder;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("new FFmpegVRamJpegEncoder failed, ") +
+              std::string(e.what()));
+  }
+  if (encoder) {
+    encoder->destroy();
+    delete encoder;
+  }
+  return NULL;
+}
+
+int ffmpeg_vram_jpeg_encode(void *encoder, void *tex, int32_t width,
+                            int32_t height, EncodeCallback callback,
+                            void *obj) {
+  FFmpegVRamJpegEncoder *p = (FFmpegVRamJpegEncoder *)encoder;
+  try {
+    if (p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    int ret = check_input_texture(p->native_->device_.Get(),
+                                  (ID3D11Texture2D *)tex);
+    if (ret != 0)
+      return ret;
+    ret = p->encode((ID3D11Texture2D *)tex, width, height, callback, obj);
+    if (ret != 0 && p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    return ret;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("ffmpeg_vram_jpeg_encode failed, ") +
+              std::string(e.what()));
+  }
+  return HWCODEC_ERR_COMMON;
+}
+
+int ffmpeg_vram_destroy_jpeg_encoder(void *encoder) {
+  FFmpegVRamJpegEncoder *p = (FFmpegVRamJpegEncoder *)encoder;
+  if (p) {
+    p->destroy();
+    delete p;
+  }
+  return 0;
+}
+
+} // extern "C"
@@ -1,4 +1,5 @@
 #include <cstring>
+#include <functional>
 
 #include <d3d11_allocator.h>
 #include <libavutil/pixfmt.h>
@@ -7,6 +8,7 @@
 
 #include "callback.h"
 #include "common.h"
+#include "mfx_session.h"
 #include "system.h"
 #include "util.h"
 
@@ -30,7 +32,7 @@ namespace {
 class VplDecoder {
 public:
   std::unique_ptr<NativeDevice> native_ = nullptr;
-  MFXVideoSession session_;
+  MfxSession session_;
   MFXVideoDECODE *mfxDEC_ = NULL;
   std::vector<mfxFrameSurface1> pmfxSurfaces_;
   mfxVideoParam mfxVideoParams_;
@@ -44,6 +46,8 @@ public:
 
   bool bt709_ = false;
   bool full_range_ = false;
+  // output the surfaces the runtime reports as corrupted
+  bool conceal_ = true;
 
   VplDecoder(void *device, int64_t luid, DataFormat codecID) {
     device_ = device;
@@ -95,7 +99,7 @@ public:
     // DecodedOrder: For AVC and HEVC, used to instruct the decoder
     // to return output frames in the decoded order. Must be zero for all other
     // decoders.
-    mfxVideoParams_.mfx.DecodedOrder = true; // Not important.
+    mfxVideoParams_.mfx.DecodedOrder = codecID_ != MJPEG;
 
     mfxVideoParams_.mfx.FrameInfo.FrameRateExtN = 30;
     mfxVideoParams_.mfx.FrameInfo.FrameRateExtD = 1;
@@ -111,6 +115,86 @@ public:
     return MFX_ERR_NONE;
   }
 
+  // Media SDK has no caps query either, see the encoder's. Each profile, level
+  // and size is checked with Query, from the highest down.
+  void caps(DecodeCaps *caps) {
+    *caps = {};
+    auto supported = [&](const std::function<void(mfxVideoParam &)> &change) {
+      mfxVideoParam in = mfxVideoParams_;
+      in.ExtParam = nullptr;
+      in.NumExtParam = 0;
+      change(in);
+      mfxVideoParam out = in;
+      return mfxDEC_->Query(&in, &out) == MFX_ERR_NONE &&
+             out.mfx.CodecProfile == in.mfx.CodecProfile &&
+             out.mfx.CodecLevel == in.mfx.CodecLevel &&
+             out.mfx.FrameInfo.Width == in.mfx.FrameInfo.Width &&
+             out.mfx.FrameInfo.Height == in.mfx.FrameInfo.Height;
+    };
+    auto profile = [&](mfxU16 profile, int bitDepth) {
+      return supported([&](mfxVideoParam &p) {
+        p.mfx.CodecProfile = profile;
+        if (bitDepth == 10) {
+          p.mfx.FrameInfo.FourCC = MFX_FOURCC_P010;
+          p.mfx.FrameInfo.BitDepthLuma = 10;
+          p.mfx.FrameInfo.BitDepthChroma = 10;
+          p.mfx.FrameInfo.Shift = 1;
+        }
+      });
+    };
+    std::vector<mfxU16> levels;
+    if (codecID_ == MJPEG) {
+      // baseline only, no profiles or levels
+      caps->bitDepths |= 1u << 8;
+    } else if (codecID_ == H264) {
+      if (profile(MFX_PROFILE_AVC_CONSTRAINED_BASELINE, 8))
+        caps->profiles |= DECODE_PROFILE_H264_BASELINE;
+      if (profile(MFX_PROFILE_AVC_MAIN, 8))
+        caps->profiles |= DECODE_PROFILE_H264_MAIN;
+      if (profile(MFX_PROFILE_AVC_HIGH, 8))
+        caps->profiles |= DECODE_PROFILE_H264_HIGH;
+      if (profile(MFX_PROFILE_AVC_HIGH10, 10))
+        caps->profiles |= DECODE_PROFILE_H264_HIGH10;
+      // MFX_LEVEL_AVC values are level_idc
+      levels = {MFX_LEVEL_AVC_52, MFX_LEVEL_AVC_51, MFX_LEVEL_AVC_5,
+                MFX_LEVEL_AVC_42, MFX_LEVEL_AVC_41};
+    } else {
+      if (profile(MFX_PROFILE_HEVC_MAIN, 8))
+        caps->profiles |= DECODE_PROFILE_HEVC_MAIN;
+      if (profile(MFX_PROFILE_HEVC_MAIN10, 10))
+        caps->profiles |= DECODE_PROFILE_HEVC_MAIN10;
+      // MFX_LEVEL_HEVC values are general_level_idc
+      levels = {MFX_LEVEL_HEVC_62, MFX_LEVEL_HEVC_61, MFX_LEVEL_HEVC_6,
+                MFX_LEVEL_HEVC_52, MFX_LEVEL_HEVC_51, MFX_LEVEL_HEVC_5,
+                MFX_LEVEL_HEVC_41};
+    }
+    if (caps->profiles & (DECODE_PROFILE_H264_BASELINE |
+                          DECODE_PROFILE_H264_MAIN | DECODE_PROFILE_H264_HIGH |
+                          DECODE_PROFILE_HEVC_MAIN))
+      caps->bitDepths |= 1u << 8;
+    if (caps->profiles &
+        (DECODE_PROFILE_H264_HIGH10 | DECODE_PROFILE_HEVC_MAIN10))
+      caps->bitDepths |= 1u << 10;
+    for (mfxU16 level : levels) {
+      if (supported([&](mfxVideoParam &p) { p.mfx.CodecLevel = level; })) {
+        caps->maxLevel = level;
+        break;
+      }
+    }
+    const std::pair<mfxU16, mfxU16> sizes[] = {
+        {8192, 8192}, {8192, 4352}, {4096, 4096}, {4096, 2304}, {1920, 1088}};
+    for (auto size : sizes) {
+      if (supported([&](mfxVideoParam &p) {
+            p.mfx.FrameInfo.Width = size.first;
+            p.mfx.FrameInfo.Height = size.second;
+          })) {
+        caps->maxWidth = size.first;
+        caps->maxHeight = size.second;
+        break;
+      }
+    }
+  }
+
   int decode(uint8_t *data, int len, DecodeCallback callback, void *obj) {
     mfxStatus sts = MFX_ERR_NONE;
     mfxSyncPoint syncp;
@@ -150,7 +234,7 @@ public:
           LOG_ERROR(std::string("should not happen, syncp is NULL while error is none"));
           break;
         }
-        sts = session_.SyncOperation(syncp, 1000);
+        sts = MFXVideoCORE_SyncOperation(session_, syncp, 1000);
         if (MFX_ERR_NONE != sts) {
           LOG_ERROR(std::string("SyncOperation failed, sts=") + std::to_string((int)sts));
           break;
@@ -159,6 +243,11 @@ public:
           LOG_ERROR(std::string("pmfxOutSurface is null"));
           break;
         }
+        if (!conceal_ && pmfxOutSurface->Data.Corrupted) {
+          LOG_TRACE(std::string("dropped a corrupted frame, corrupted=") +
+                    std::to_string(pmfxOutSurface->Data.Corrupted));
+          break;
+        }
         if (!convert(pmfxOutSurface)) {
           LOG_ERROR(std::string("Failed to convert"));
           break;
@@ -213,14 +302,13 @@ public:
 private:
   mfxStatus InitializeMFX() {
     mfxStatus sts = MFX_ERR_NONE;
-    mfxIMPL impl = MFX_IMPL_HARDWARE_ANY | MFX_IMPL_VIA_D3D11;
-    mfxVersion ver = {{0, 1}};
     D3D11AllocatorParams allocParams;
 
-    sts = session_.Init(impl, &ver);
-    CHECK_STATUS(sts, "session Init");
+    sts = session_.Create(luid_);
+    CHECK_STATUS(sts, "Create session");
 
-    sts = session_.SetHandle(MFX_HANDLE_D3D11_DEVICE, native_->device_.Get());
+    sts = MFXVideoCORE_SetHandle(session_, MFX_HANDLE_D3D11_DEVICE,
+                                 native_->device_.Get());
     CHECK_STATUS(sts, "SetHandle");
 
     allocParams.bUseSingleTexture = false; // important
@@ -229,7 +317,7 @@ private:
     sts = d3d11FrameAllocator_.Init(&allocParams);
     CHECK_STATUS(sts, "init D3D11FrameAllocator");
 
-    sts = session_.SetFrameAllocator(&d3d11FrameAllocator_);
+    sts = MFXVideoCORE_SetFrameAllocator(session_, &d3d11FrameAllocator_);
     CHECK_STATUS(sts, "SetFrameAllocator");
 
     return MFX_ERR_NONE;
@@ -243,6 +331,9 @@ private:
     case H265:
       CodecId = MFX_CODEC_HEVC;
       return true;
+    case MJPEG:
+      CodecId = MFX_CODEC_JPEG;
+      return true;
     }
     return false;
   }
@@ -264,6 +355,17 @@ private:
     sts = mfxDEC_->DecodeHeader(mfxBS, &mfxVideoParams_);
     MSDK_IGNORE_MFX_STS(sts, MFX_WRN_PARTIAL_ACCELERATION);
     MSDK_CHECK_RESULT(sts, MFX_ERR_NONE, sts);
+    if (codecID_ == MJPEG) {
+      // the header picks the fourcc of the image's sampling, only 4:2:0
+      // decodes to the NV12 the shaders sample
+      if (mfxVideoParams_.mfx.FrameInfo.ChromaFormat !=
+          MFX_CHROMAFORMAT_YUV420) {
+        LOG_ERROR(std::string("unsupported JPEG chroma format: ") +
+                  std::to_string(mfxVideoParams_.mfx.FrameInfo.ChromaFormat));
+        return MFX_ERR_UNSUPPORTED;
+      }
+      mfxVideoParams_.mfx.FrameInfo.FourCC = MFX_FOURCC_NV12;
+    }
 
     sts = mfxDEC_->QueryIOSurf(&mfxVideoParams_, &Request);
     MSDK_IGNORE_MFX_STS(sts, MFX_WRN_PARTIAL_ACCELERATION);
@@ -332,9 +434,11 @@ private:
     native_->next(); // comment out to remove picture shaking
 #ifdef USE_SHADER
     native_->BeginQuery();
-    if (!native_->Nv12ToBgra(pmfxOutSurface->Info.CropW,
-                             pmfxOutSurface->Info.CropH, texture,
-                             native_->GetCurrentTexture(), 0)) {
+    // the surface is of the coded size, e.g. 1088 rows for 1080
+    if (!native_->Nv12ToBgra(
+            pmfxOutSurface->Info.CropW, pmfxOutSurface->Info.CropH, texture,
+            native_->GetCurrentTexture(), 0, pmfxOutSurface->Info.CropX,
+            pmfxOutSurface->Info.CropY)) {
       LOG_ERROR(std::string("Failed to Nv12ToBgra"));
       native_->EndQuery();
       return false;
@@ -426,16 +530,42 @@ void *mfx_new_decoder(void *device, int64_t luid, DataFormat codecID) {
   return NULL;
 }
 
+int mfx_decoder_memory(void *decoder, MemoryInfo *info) {
+  VplDecoder *p = (VplDecoder *)decoder;
+  estimate_decoder_memory(p->native_.get(), info);
+  return 0;
+}
+
 int mfx_decode(void *decoder, uint8_t *data, int len, DecodeCallback callback,
                void *obj) {
+  VplDecoder *p = (VplDecoder *)decoder;
   try {
-    VplDecoder *p = (VplDecoder *)decoder;
+    if (p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
     if (p->decode(data, len, callback, obj) == 0) {
       return HWCODEC_SUCCESS;
     }
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("decode failed: ") + e.what());
   }
+  if (p->native_->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
+  return HWCODEC_ERR_COMMON;
+}
+
+int mfx_decoder_set_conceal(void *decoder, int32_t conceal) {
+  VplDecoder *p = (VplDecoder *)decoder;
+  p->conceal_ = conceal != 0;
+  return HWCODEC_SUCCESS;
+}
+
+int mfx_decoder_caps(void *decoder, DecodeCaps *caps) {
+  try {
+    ((VplDecoder *)decoder)->caps(caps);
+    return HWCODEC_SUCCESS;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("caps failed: ") + e.what());
+  }
   return HWCODEC_ERR_COMMON;
 }
 
@@ -1,3 +1,4 @@
+#include <algorithm>
 #include <cstring>
 #include <iostream>
 #include <libavutil/pixfmt.h>
@@ -7,6 +8,7 @@
 
 #include "callback.h"
 #include "common.h"
+#include "mfx_session.h"
 #include "system.h"
 #include "util.h"
 
@@ -37,32 +39,39 @@ mfxStatus MFX_CDECL simple_getHDL(mfxHDL pthis, mfxMemId mid, mfxHDL *handle) {
 mfxFrameAllocator frameAllocator{{},   NULL,          NULL, NULL,
                                  NULL, simple_getHDL, NULL};
 
-mfxStatus InitSession(MFXVideoSession &session) {
-  mfxInitParam mfxparams{};
-  mfxIMPL impl = MFX_IMPL_HARDWARE_ANY | MFX_IMPL_VIA_D3D11;
-  mfxparams.Implementation = impl;
-  mfxparams.Version.Major = 1;
-  mfxparams.Version.Minor = 0;
-  mfxparams.GPUCopy = MFX_GPUCOPY_OFF;
-
-  return session.InitEx(mfxparams);
+// the bgra input is converted into one nv12 texture the encode surfaces share
+#define MFX_INPUT_NV12_SURFACES 1
+
+// the session has to be recreated after these, e.g. after a session switch
+bool is_session_lost(mfxStatus sts) {
+  switch (sts) {
+  case MFX_ERR_NOT_INITIALIZED:
+  case MFX_ERR_DEVICE_LOST:
+  case MFX_ERR_DEVICE_FAILED:
+  case MFX_ERR_GPU_HANG:
+    return true;
+  default:
+    return false;
+  }
 }
 
-
 class VplEncoder {
 public:
   std::unique_ptr<NativeDevice> native_ = nullptr;
-  MFXVideoSession session_;
+  MfxSession session_;
+  // owned by the caller, used instead of session_ and never closed here
+  mfxSession external_session_ = nullptr;
   MFXVideoENCODE *mfxENC_ = nullptr;
   std::vector<mfxFrameSurface1> encSurfaces_;
   std::vector<mfxU8> bstData_;
   mfxBitstream mfxBS_;
   mfxVideoParam mfxEncParams_;
-  mfxExtBuffer *extbuffers_[4] = {NULL, NULL, NULL, NULL};
+  mfxExtBuffer *extbuffers_[5] = {NULL, NULL, NULL, NULL, NULL};
   mfxExtCodingOption coding_option_;
   mfxExtCodingOption2 coding_option2_;
   mfxExtCodingOption3 coding_option3_;
   mfxExtVideoSignalInfo signal_info_;
+  mfxExtChromaLocInfo chroma_loc_info_;
   ComPtr<ID3D11Texture2D> nv12Texture_ = nullptr;
 
 // vpp
@@ -86,10 +95,16 @@ public:
 
   bool full_range_ = false;
   bool bt709_ = false;
+  EncodeOptions options_ = {};
+  mfxStatus last_sts_ = MFX_ERR_NONE;
+  int64_t allocated_ = 0;
+  bool force_idr_ = false;
+  GpuTimer timer_;
+  int64_t frame_ = 0;
 
   VplEncoder(void *handle, int64_t luid, DataFormat dataFormat,
              int32_t width, int32_t height, int32_t kbs, int32_t framerate,
-             int32_t gop) {
+             int32_t gop, const EncodeOptions *options) {
     handle_ = handle;
     luid_ = luid;
     dataFormat_ = dataFormat;
@@ -98,11 +113,18 @@ public:
     kbs_ = kbs;
     framerate_ = framerate;
     gop_ = gop;
+    if (options)
+      options_ = *options;
   }
 
   ~VplEncoder() {}
 
-  mfxStatus Reset() {
+  mfxSession session() {
+    return external_session_ ? external_session_ : (mfxSession)session_;
+  }
+
+  // up to the session, enough to Query parameters
+  mfxStatus open() {
     mfxStatus sts = MFX_ERR_NONE;
 
     if (!native_) {
@@ -114,6 +136,13 @@ public:
     }
     sts = resetMFX();
     CHECK_STATUS(sts, "resetMFX");
+    return MFX_ERR_NONE;
+  }
+
+  mfxStatus Reset() {
+    mfxStatus sts = open();
+    if (sts != MFX_ERR_NONE)
+      return sts;
 #ifdef CONFIG_USE_VPP
     sts = resetVpp();
     CHECK_STATUS(sts, "resetVpp");
@@ -124,7 +153,7 @@ public:
   }
 
   int encode(ID3D11Texture2D *tex, EncodeCallback callback, void *obj,
-             int64_t ms) {
+             int64_t ms, uint64_t user_data) {
     mfxStatus sts = MFX_ERR_NONE;
 
     int nEncSurfIdx =
@@ -134,6 +163,7 @@ public:
       return -1;
     }
     mfxFrameSurface1 *encSurf = &encSurfaces_[nEncSurfIdx];
+    timer_.Begin(frame_++, user_data);
 #ifdef CONFIG_USE_VPP
     mfxSyncPoint syncp;
     sts = vppOneFrame(tex, encSurf, syncp);
@@ -169,7 +199,8 @@ public:
           &desc, NULL, nv12Texture_.ReleaseAndGetAddressOf()));
     }
     if (!native_->BgraToNv12(tex, nv12Texture_.Get(), width_, height_,
-                             colorSpace_in, colorSpace_out)) {
+                             colorSpace_in, colorSpace_out,
+                             options_.colorConvert)) {
       LOG_ERROR(std::string("failed to convert to NV12"));
       return -1;
     }
@@ -177,7 +208,119 @@ public:
 #else
     encSurf->Data.MemId = tex;
 #endif
-    return encodeOneFrame(encSurf, callback, obj, ms);
+    timer_.Mark();
+    int ret = encodeOneFrame(encSurf, callback, obj, ms, user_data);
+    timer_.End();
+    return ret;
+  }
+
+  // Media SDK has no caps query, so the variations of the session's parameters
+  // are checked with Query. The level and size limits stay unknown.
+  void caps(EncodeCaps *caps) {
+    *caps = {};
+    auto supported = [&](void (*change)(mfxVideoParam &)) {
+      mfxVideoParam in = mfxEncParams_;
+      in.ExtParam = nullptr;
+      in.NumExtParam = 0;
+      change(in);
+      mfxVideoParam out = in;
+      return MFXVideoENCODE_Query(session(), &in, &out) == MFX_ERR_NONE;
+    };
+    if (supported([](mfxVideoParam &p) { p.mfx.GopRefDist = 2; }))
+      caps->flags |= ENCODE_CAP_BFRAMES;
+    if (dataFormat_ == H265 && supported([](mfxVideoParam &p) {
+          p.mfx.CodecProfile = MFX_PROFILE_HEVC_MAIN10;
+          p.mfx.FrameInfo.FourCC = MFX_FOURCC_P010;
+          p.mfx.FrameInfo.ChromaFormat = MFX_CHROMAFORMAT_YUV420;
+          p.mfx.FrameInfo.BitDepthLuma = 10;
+          p.mfx.FrameInfo.BitDepthChroma = 10;
+          p.mfx.FrameInfo.Shift = 1;
+        }))
+      caps->flags |= ENCODE_CAP_10BIT;
+  }
+
+  // Query of the session's parameters for the configuration, false with the
+  // first parameter it rejected or changed in check. Query zeroes what it
+  // doesn't support and corrects what it takes differently.
+  bool check_config(ConfigCheck *check) {
+    auto reject = [&](ConfigParam param, bool hasCorrected, int32_t corrected) {
+      check->param = param;
+      check->hasCorrected = hasCorrected ? 1 : 0;
+      check->corrected = corrected;
+      return false;
+    };
+    auto corrected = [&](ConfigParam param, int32_t value) {
+      return reject(param, value != 0, value);
+    };
+    if (setEncParams() != MFX_ERR_NONE)
+      return reject(CONFIG_PARAM_FORMAT, false, 0);
+    mfxInfoMFX in = mfxEncParams_.mfx;
+    mfxU16 inMBBRC = coding_option2_.MBBRC;
+    mfxU16 inCAVLC = coding_option_.CAVLC;
+    mfxStatus sts =
+        MFXVideoENCODE_Query(session(), &mfxEncParams_, &mfxEncParams_);
+    if (sts != MFX_ERR_NONE && sts != MFX_WRN_INCOMPATIBLE_VIDEO_PARAM &&
+        sts != MFX_ERR_UNSUPPORTED) {
+      LOG_ERROR(std::string("Query failed, sts=") + std::to_string((int)sts));
+      return reject(CONFIG_PARAM_NONE, false, 0);
+    }
+    const mfxInfoMFX &out = mfxEncParams_.mfx;
+    if (out.CodecId != in.CodecId)
+      return reject(CONFIG_PARAM_FORMAT, false, 0);
+    if (out.FrameInfo.Width != in.FrameInfo.Width ||
+        out.FrameInfo.CropW != in.FrameInfo.CropW)
+      return corrected(CONFIG_PARAM_WIDTH, out.FrameInfo.CropW);
+    if (out.FrameInfo.Height != in.FrameInfo.Height ||
+        out.FrameInfo.CropH != in.FrameInfo.CropH)
+      return corrected(CONFIG_PARAM_HEIGHT, out.FrameInfo.CropH);
+    if (out.RateControlMethod != in.RateControlMethod)
+      return reject(in.RateControlMethod == MFX_RATECONTROL_ICQ
+                        ? CONFIG_PARAM_QUALITY
+                        : CONFIG_PARAM_BITRATE,
+                    false, 0);
+    if (in.RateControlMethod == MFX_RATECONTROL_ICQ &&
+        out.ICQQuality != in.ICQQuality)
+      return corrected(CONFIG_PARAM_QUALITY, out.ICQQuality);
+    if (in.RateControlMethod != MFX_RATECONTROL_ICQ &&
+        (out.TargetKbps != in.TargetKbps ||
+         out.BRCParamMultiplier != in.BRCParamMultiplier))
+      return corrected(CONFIG_PARAM_BITRATE,
+                       out.TargetKbps * (out.BRCParamMultiplier
+                                             ? out.BRCParamMultiplier
+                                             : 1));
+    if (out.FrameInfo.FrameRateExtN != in.FrameInfo.FrameRateExtN ||
+        out.FrameInfo.FrameRateExtD != in.FrameInfo.FrameRateExtD)
+      return corrected(CONFIG_PARAM_FRAMERATE,
+                       out.FrameInfo.FrameRateExtD
+                           ? out.FrameInfo.FrameRateExtN /
+                                 out.FrameInfo.FrameRateExtD
+                           : 0);
+    if (out.GopPicSize != in.GopPicSize)
+      return corrected(CONFIG_PARAM_GOP, out.GopPicSize);
+    if (coding_option2_.MBBRC != inMBBRC)
+      return reject(CONFIG_PARAM_AQ_MODE, true,
+                    coding_option2_.MBBRC == MFX_CODINGOPTION_ON ? AQ_SPATIAL
+                    : coding_option2_.MBBRC == MFX_CODINGOPTION_OFF
+                        ? AQ_OFF
+                        : AQ_DEFAULT);
+    if (coding_option_.CAVLC != inCAVLC)
+      return reject(CONFIG_PARAM_ENTROPY_CODING, true,
+                    coding_option_.CAVLC == MFX_CODINGOPTION_ON
+                        ? ENTROPY_CODING_CAVLC
+                    : coding_option_.CAVLC == MFX_CODINGOPTION_OFF
+                        ? ENTROPY_CODING_CABAC
+                        : ENTROPY_CODING_DEFAULT);
+    // unsupported but none of the parameters above
+    if (sts == MFX_ERR_UNSUPPORTED)
+      return reject(CONFIG_PARAM_NONE, false, 0);
+    // resetEncExtParams ignores these
+    if (options_.aqMode == AQ_TEMPORAL)
+      return reject(CONFIG_PARAM_AQ_MODE, true, AQ_DEFAULT);
+    if (options_.aqStrength > 0)
+      return reject(CONFIG_PARAM_AQ_STRENGTH, true, 0);
+    if (!options_.noSceneCutKeyframes && options_.minKeyframeInterval > 0)
+      return reject(CONFIG_PARAM_MIN_KEYFRAME_INTERVAL, true, 0);
+    return true;
   }
 
   void destroy() {
@@ -196,18 +339,36 @@ public:
       mfxVPP_ = NULL;
     }
 #endif
-    // session closed automatically on destruction
+    // session_ closed automatically on destruction, external_session_ left open
+  }
+
+  // Tune::CloudGaming, a frame can't take more than its share of the bitrate.
+  // Follows kbs_ and framerate_ after a change, icq has no bitrate to split.
+  void setOneFrameBuffer() {
+    if (options_.quality > 0)
+      return;
+    mfxU32 bytes = (mfxU32)kbs_ * 1000 / 8 / std::max(framerate_, 1);
+    mfxEncParams_.mfx.BufferSizeInKB = (mfxU16)std::max(bytes / 1000, 1u);
+    mfxEncParams_.mfx.InitialDelayInKB = mfxEncParams_.mfx.BufferSizeInKB;
+    coding_option2_.MaxFrameSize = bytes;
   }
 
 private:
   mfxStatus resetMFX() {
     mfxStatus sts = MFX_ERR_NONE;
 
-    sts = InitSession(session_);
-    CHECK_STATUS(sts, "InitSession");
-    sts = session_.SetHandle(MFX_HANDLE_D3D11_DEVICE, native_->device_.Get());
+    if (external_session_) {
+      // the device handle is already set, the input textures need our allocator
+      sts = MFXVideoCORE_SetFrameAllocator(external_session_, &frameAllocator);
+      CHECK_STATUS(sts, "SetFrameAllocator");
+      return MFX_ERR_NONE;
+    }
+    sts = session_.Create(luid_);
+    CHECK_STATUS(sts, "Create session");
+    sts = MFXVideoCORE_SetHandle(session_, MFX_HANDLE_D3D11_DEVICE,
+                                 native_->device_.Get());
     CHECK_STATUS(sts, "SetHandle");
-    sts = session_.SetFrameAllocator(&frameAllocator);
+    sts = MFXVideoCORE_SetFrameAllocator(session_, &frameAllocator);
     CHECK_STATUS(sts, "SetFrameAllocator");
 
     return MFX_ERR_NONE;
@@ -256,7 +417,7 @@ private:
       delete mfxVPP_;
       mfxVPP_ = NULL;
     }
-    mfxVPP_ = new MFXVideoVPP(session_);
+    mfxVPP_ = new MFXVideoVPP(session());
     if (!mfxVPP_) {
       LOG_ERROR(std::string("Failed to create MFXVideoVPP"));
       return MFX_ERR_MEMORY_ALLOC;
@@ -284,8 +445,8 @@ private:
   }
 #endif
 
-  mfxStatus resetEnc() {
-    mfxStatus sts = MFX_ERR_NONE;
+  // mfxEncParams_ and its ext buffers from the configuration
+  mfxStatus setEncParams() {
     memset(&mfxEncParams_, 0, sizeof(mfxEncParams_));
 
     // Basic
@@ -312,6 +473,12 @@ private:
     mfxEncParams_.mfx.FrameInfo.BitDepthLuma = 8;
     mfxEncParams_.mfx.FrameInfo.BitDepthChroma = 8;
     mfxEncParams_.mfx.FrameInfo.Shift = 0;
+    // the sar of the vui, without it decoders take square samples
+    if (options_.sarWidth > 0 && options_.sarHeight > 0 &&
+        options_.sarWidth != options_.sarHeight) {
+      mfxEncParams_.mfx.FrameInfo.AspectRatioW = (mfxU16)options_.sarWidth;
+      mfxEncParams_.mfx.FrameInfo.AspectRatioH = (mfxU16)options_.sarHeight;
+    }
     mfxEncParams_.mfx.FrameInfo.PicStruct = MFX_PICSTRUCT_PROGRESSIVE;
     mfxEncParams_.mfx.FrameInfo.CropX = 0;
     mfxEncParams_.mfx.FrameInfo.CropY = 0;
@@ -336,16 +503,32 @@ private:
     mfxEncParams_.mfx.GopRefDist =
         1; // 1 is best for low latency, I and P frames only
     mfxEncParams_.mfx.GopPicSize = (gop_ > 0 && gop_ < 0xFFFF) ? gop_ : 0xFFFF;
+    // no idr or i frames other than the gop's and the requested ones
+    if (options_.noSceneCutKeyframes)
+      mfxEncParams_.mfx.GopOptFlag = MFX_GOP_STRICT;
     // quality
     // https://www.intel.com/content/www/us/en/developer/articles/technical/common-bitrate-control-methods-in-intel-media-sdk.html
     mfxEncParams_.mfx.TargetUsage = MFX_TARGETUSAGE_BEST_SPEED;
-    mfxEncParams_.mfx.RateControlMethod = MFX_RATECONTROL_VBR;
-    mfxEncParams_.mfx.InitialDelayInKB = 0;
-    mfxEncParams_.mfx.BufferSizeInKB = 512;
-    mfxEncParams_.mfx.TargetKbps = kbs_;
-    mfxEncParams_.mfx.MaxKbps = kbs_;
+    if (options_.quality > 0) {
+      // ICQQuality shares its field with TargetKbps
+      mfxEncParams_.mfx.RateControlMethod = MFX_RATECONTROL_ICQ;
+      mfxEncParams_.mfx.InitialDelayInKB = 0;
+      mfxEncParams_.mfx.ICQQuality = (mfxU16)options_.quality;
+      mfxEncParams_.mfx.MaxKbps = 0;
+    } else {
+      mfxEncParams_.mfx.RateControlMethod = MFX_RATECONTROL_VBR;
+      mfxEncParams_.mfx.InitialDelayInKB = 0;
+      mfxEncParams_.mfx.BufferSizeInKB = 512;
+      mfxEncParams_.mfx.TargetKbps = kbs_;
+      mfxEncParams_.mfx.MaxKbps = kbs_;
+    }
     mfxEncParams_.mfx.NumSlice = 1;
     mfxEncParams_.mfx.NumRefFrame = 0;
+    // no gop, the intra refresh of resetEncExtParams restores the picture
+    if (options_.cloudGaming) {
+      mfxEncParams_.mfx.GopPicSize = 0xFFFF;
+      mfxEncParams_.mfx.NumSlice = 4;
+    }
 
     if (H264 == dataFormat_) {
       mfxEncParams_.mfx.CodecLevel = MFX_LEVEL_AVC_51;
@@ -356,6 +539,13 @@ private:
     }
 
     resetEncExtParams();
+    return MFX_ERR_NONE;
+  }
+
+  mfxStatus resetEnc() {
+    mfxStatus sts = setEncParams();
+    if (sts != MFX_ERR_NONE)
+      return sts;
 
     // Create Media SDK encoder
     if (mfxENC_) {
@@ -363,7 +553,7 @@ private:
       delete mfxENC_;
       mfxENC_ = NULL;
     }
-    mfxENC_ = new MFXVideoENCODE(session_);
+    mfxENC_ = new MFXVideoENCODE(session());
     if (!mfxENC_) {
       LOG_ERROR(std::string("failed to create MFXVideoENCODE"));
       return MFX_ERR_NOT_INITIALIZED;
@@ -404,9 +594,14 @@ private:
     // Prepare Media SDK bit stream buffer
     memset(&mfxBS_, 0, sizeof(mfxBS_));
     mfxBS_.MaxLength = mfxEncParams_.mfx.BufferSizeInKB * 1024;
+    // the one frame vbv of Tune::CloudGaming, keyframes still take more
+    if (options_.cloudGaming)
+      mfxBS_.MaxLength = std::max(mfxBS_.MaxLength, (mfxU32)512 * 1024);
     bstData_.resize(mfxBS_.MaxLength);
     mfxBS_.Data = bstData_.data();
 
+    if (options_.gpuTiming && !timer_.Init(native_->device_.Get()))
+      LOG_WARN("gpu timing unavailable");
     return MFX_ERR_NONE;
   }
 
@@ -442,8 +637,8 @@ private:
     }
 
     if (MFX_ERR_NONE == sts) {
-      sts = session_.SyncOperation(
-          syncp, 1000); // Synchronize. Wait until encoded frame is ready
+      sts = MFXVideoCORE_SyncOperation(
+          session(), syncp, 1000); // Synchronize. Wait until encoded frame is ready
       CHECK_STATUS(sts, "SyncOperation");
     }
 
@@ -451,11 +646,17 @@ private:
   }
 #endif
 
+  // synced before returning and no b-frames, the bitstream is of this frame
   int encodeOneFrame(mfxFrameSurface1 *in, EncodeCallback callback, void *obj,
-                     int64_t ms) {
+                     int64_t ms, uint64_t user_data) {
     mfxStatus sts = MFX_ERR_NONE;
     mfxSyncPoint syncp;
     bool encoded = false;
+    mfxEncodeCtrl ctrl;
+    memset(&ctrl, 0, sizeof(ctrl));
+    if (force_idr_) {
+      ctrl.FrameType = MFX_FRAMETYPE_I | MFX_FRAMETYPE_REF | MFX_FRAMETYPE_IDR;
+    }
 
     auto start = util::now();
     do {
@@ -465,16 +666,17 @@ private:
       }
       mfxBS_.DataLength = 0;
       mfxBS_.DataOffset = 0;
-      mfxBS_.TimeStamp = ms * 90; // ms to 90KHZ
-      mfxBS_.DecodeTimeStamp = mfxBS_.TimeStamp;
-      sts = mfxENC_->EncodeFrameAsync(NULL, in, &mfxBS_, &syncp);
+      // ms to 90khz, without it the runtime makes them up from the framerate
+      in->Data.TimeStamp = ms * 90;
+      sts = mfxENC_->EncodeFrameAsync(force_idr_ ? &ctrl : NULL, in, &mfxBS_,
+                                      &syncp);
       if (MFX_ERR_NONE == sts) {
         if (!syncp) {
           LOG_ERROR(std::string("should not happen, error is none while syncp is null"));
           break;
         }
-        sts = session_.SyncOperation(
-            syncp, 1000); // Synchronize. Wait until encoded frame is ready
+        sts = MFXVideoCORE_SyncOperation(
+            session(), syncp, 1000); // Synchronize. Wait until encoded frame is ready
         if (MFX_ERR_NONE != sts) {
           LOG_ERROR(std::string("SyncOperation failed, sts=") + std::to_string(sts));
           break;
@@ -483,12 +685,16 @@ private:
           LOG_ERROR(std::string("mfxBS_.DataLength <= 0"));
           break;
         }
-        int key = (mfxBS_.FrameType & MFX_FRAMETYPE_I) ||
-                  (mfxBS_.FrameType & MFX_FRAMETYPE_IDR);
+        int32_t flags = 0;
+        if (mfxBS_.FrameType & (MFX_FRAMETYPE_I | MFX_FRAMETYPE_IDR))
+          flags |= FRAME_FLAG_KEYFRAME;
+        if (!(mfxBS_.FrameType & MFX_FRAMETYPE_REF))
+          flags |= FRAME_FLAG_NON_REFERENCE;
         if (callback)
-          callback(mfxBS_.Data + mfxBS_.DataOffset, mfxBS_.DataLength, key, obj,
-                   ms);
+          callback(mfxBS_.Data + mfxBS_.DataOffset, mfxBS_.DataLength, flags,
+                   obj, ms, user_data);
         encoded = true;
+        force_idr_ = false;
         break;
       } else if (MFX_WRN_DEVICE_BUSY == sts) {
         LOG_INFO(std::string("device busy"));
@@ -516,6 +722,7 @@ private:
     if (!encoded) {
       LOG_ERROR(std::string("encode failed, sts=") + std::to_string(sts));
     }
+    last_sts_ = sts;
     return encoded ? 0 : -1;
   }
 
@@ -525,6 +732,10 @@ private:
     coding_option_.Header.BufferId = MFX_EXTBUFF_CODING_OPTION;
     coding_option_.Header.BufferSz = sizeof(mfxExtCodingOption);
     coding_option_.NalHrdConformance = MFX_CODINGOPTION_OFF;
+    if (dataFormat_ == H264)
+      coding_option_.CAVLC = options_.entropyCoding == ENTROPY_CODING_CAVLC
+                                 ? MFX_CODINGOPTION_ON
+                                 : MFX_CODINGOPTION_OFF;
     extbuffers_[0] = (mfxExtBuffer *)&coding_option_;
 
     // coding option2
@@ -532,6 +743,28 @@ private:
     coding_option2_.Header.BufferId = MFX_EXTBUFF_CODING_OPTION2;
     coding_option2_.Header.BufferSz = sizeof(mfxExtCodingOption2);
     coding_option2_.RepeatPPS = MFX_CODINGOPTION_OFF;
+    // mb level brc is the spatial aq of media sdk
+    switch (options_.aqMode) {
+    case AQ_OFF:
+      coding_option2_.MBBRC = MFX_CODINGOPTION_OFF;
+      break;
+    case AQ_SPATIAL:
+      coding_option2_.MBBRC = MFX_CODINGOPTION_ON;
+      break;
+    case AQ_TEMPORAL:
+      LOG_WARN("temporal aq not supported, ignored");
+      break;
+    default:
+      break;
+    }
+    if (options_.aqStrength > 0) {
+      LOG_WARN("aq strength not supported, ignored");
+    }
+    if (options_.noSceneCutKeyframes) {
+      coding_option2_.AdaptiveI = MFX_CODINGOPTION_OFF;
+    } else if (options_.minKeyframeInterval > 0) {
+      LOG_WARN("min keyframe interval not supported, ignored");
+    }
     extbuffers_[1] = (mfxExtBuffer *)&coding_option2_;
 
     // coding option3
@@ -539,6 +772,18 @@ private:
     coding_option3_.Header.BufferId = MFX_EXTBUFF_CODING_OPTION3;
     coding_option3_.Header.BufferSz = sizeof(mfxExtCodingOption3);
     extbuffers_[2] = (mfxExtBuffer *)&coding_option3_;
+
+    if (options_.cloudGaming) {
+      // a column of intra blocks crosses the picture in half a second
+      coding_option2_.IntRefType = MFX_REFRESH_VERTICAL;
+      coding_option2_.IntRefCycleSize = (mfxU16)std::max(framerate_ / 2, 2);
+      coding_option_.RecoveryPointSEI = MFX_CODINGOPTION_ON;
+      coding_option2_.LookAheadDepth = 0;
+      // vbr only, icq takes no brc options
+      if (options_.quality == 0)
+        coding_option3_.LowDelayBRC = MFX_CODINGOPTION_ON;
+      setOneFrameBuffer();
+    }
     
     // signal info
     memset(&signal_info_, 0, sizeof(mfxExtVideoSignalInfo));
@@ -556,8 +801,17 @@ private:
     // https://github.com/GStreamer/gstreamer/blob/651dcb49123ec516e7c582e4a49a5f3f15c10f93/subprojects/gst-plugins-bad/sys/qsv/gstqsvh264enc.cpp#L1647
     extbuffers_[3] = (mfxExtBuffer *)&signal_info_;
 
+    // chroma location
+    memset(&chroma_loc_info_, 0, sizeof(mfxExtChromaLocInfo));
+    chroma_loc_info_.Header.BufferId = MFX_EXTBUFF_CHROMA_LOC_INFO;
+    chroma_loc_info_.Header.BufferSz = sizeof(mfxExtChromaLocInfo);
+    chroma_loc_info_.ChromaLocInfoPresentFlag = 1;
+    chroma_loc_info_.ChromaSampleLocTypeTopField = options_.chromaLocation;
+    chroma_loc_info_.ChromaSampleLocTypeBottomField = options_.chromaLocation;
+    extbuffers_[4] = (mfxExtBuffer *)&chroma_loc_info_;
+
     mfxEncParams_.ExtParam = extbuffers_;
-    mfxEncParams_.NumExtParam = 4;
+    mfxEncParams_.NumExtParam = 5;
   }
 
   bool convert_codec(DataFormat dataFormat, mfxU32 &CodecId) {
@@ -577,9 +831,21 @@ private:
 
 extern "C" {
 
-int mfx_driver_support() {
-  MFXVideoSession session;
-  return InitSession(session) == MFX_ERR_NONE ? 0 : -1;
+// A VPL or Media SDK runtime is installed for some adapter. The fallback
+// session asks for api 1.0, every runtime has that.
+int mfx_driver_support(DriverProbe *probe) {
+  *probe = {};
+  snprintf(probe->name, sizeof(probe->name), "Intel media runtime");
+  snprintf(probe->required, sizeof(probe->required), "1.0");
+  MfxSession session;
+  if (session.Create(0) != MFX_ERR_NONE)
+    return -1;
+  RuntimeInfo info;
+  session.Info(&info);
+  // the api level, e.g. 2.9 for a oneVPL runtime
+  snprintf(probe->installed, sizeof(probe->installed), "%d.%d",
+           info.apiMajor, info.apiMinor);
+  return 0;
 }
 
 int mfx_destroy_encoder(void *encoder) {
@@ -594,16 +860,20 @@ int mfx_destroy_encoder(void *encoder) {
 
 void *mfx_new_encoder(void *handle, int64_t luid,
                       DataFormat dataFormat, int32_t w, int32_t h, int32_t kbs,
-                      int32_t framerate, int32_t gop) {
+                      int32_t framerate, int32_t gop,
+                      const EncodeOptions *options) {
   VplEncoder *p = NULL;
   try {
     p = new VplEncoder(handle, luid, dataFormat, w, h, kbs, framerate,
-                       gop);
+                       gop, options);
     if (!p) {
       return NULL;
     }
+    int64_t usage = video_memory_usage((ID3D11Device *)handle);
     mfxStatus sts = p->Reset();
     if (sts == MFX_ERR_NONE) {
+      usage = video_memory_usage((ID3D11Device *)handle) - usage;
+      p->allocated_ = usage > 0 ? usage : 0;
       return p;
     } else {
       LOG_ERROR(std::string("Init failed, sts=") + std::to_string(sts));
@@ -620,20 +890,103 @@ void *mfx_new_encoder(void *handle, int64_t luid,
   return NULL;
 }
 
+// Wraps a session created by the caller with a d3d11 device handle set. The encoder
+// installs its frame allocator on it and doesn't close it on destroy.
+void *mfx_new_encoder_from_session(void *session, int64_t luid,
+                                   DataFormat dataFormat, int32_t w, int32_t h,
+                                   int32_t kbs, int32_t framerate, int32_t gop,
+                                   const EncodeOptions *options) {
+  VplEncoder *p = NULL;
+  try {
+    mfxHDL device = NULL;
+    mfxStatus sts = MFXVideoCORE_GetHandle((mfxSession)session,
+                                           MFX_HANDLE_D3D11_DEVICE, &device);
+    if (sts != MFX_ERR_NONE || !device) {
+      LOG_ERROR(std::string("session has no d3d11 device, sts=") +
+                std::to_string(sts));
+      return NULL;
+    }
+    p = new VplEncoder(device, luid, dataFormat, w, h, kbs, framerate, gop,
+                       options);
+    p->external_session_ = (mfxSession)session;
+    int64_t usage = video_memory_usage((ID3D11Device *)device);
+    sts = p->Reset();
+    if (sts == MFX_ERR_NONE) {
+      usage = video_memory_usage((ID3D11Device *)device) - usage;
+      p->allocated_ = usage > 0 ? usage : 0;
+      return p;
+    }
+    LOG_ERROR(std::string("Init failed, sts=") + std::to_string(sts));
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("Exception: ") + e.what());
+  }
+
+  if (p) {
+    p->destroy();
+    delete p;
+    p = NULL;
+  }
+  return NULL;
+}
+
+// for tests of mfx_new_encoder_from_session, a session as an application would create it
+void *mfx_debug_new_session(void *device) {
+  mfxInitParam params{};
+  params.Implementation = MFX_IMPL_HARDWARE_ANY | MFX_IMPL_VIA_D3D11;
+  params.Version.Major = 1;
+  params.Version.Minor = 0;
+  mfxSession session = NULL;
+  if (MFXInitEx(params, &session) != MFX_ERR_NONE)
+    return NULL;
+  if (MFXVideoCORE_SetHandle(session, MFX_HANDLE_D3D11_DEVICE, device) !=
+      MFX_ERR_NONE) {
+    MFXClose(session);
+    return NULL;
+  }
+  return session;
+}
+
+int mfx_debug_session_alive(void *session) {
+  mfxIMPL impl;
+  return MFXQueryIMPL((mfxSession)session, &impl) == MFX_ERR_NONE ? 1 : 0;
+}
+
+void mfx_debug_close_session(void *session) { MFXClose((mfxSession)session); }
+
 int mfx_encode(void *encoder, ID3D11Texture2D *tex, EncodeCallback callback,
-               void *obj, int64_t ms) {
+               void *obj, int64_t ms, uint64_t user_data) {
+  VplEncoder *p = (VplEncoder *)encoder;
   try {
-    return ((VplEncoder *)encoder)->encode(tex, callback, obj, ms);
+    if (p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    int ret = check_input_texture(p->native_->device_.Get(), tex);
+    if (ret != 0)
+      return ret;
+    p->last_sts_ = MFX_ERR_NONE;
+    ret = p->encode(tex, callback, obj, ms, user_data);
+    if (ret != 0) {
+      if (p->native_->IsDeviceLost())
+        return HWCODEC_ERR_DEVICE_LOST;
+      if (is_session_lost(p->last_sts_))
+        return HWCODEC_ERR_SESSION_LOST;
+      if (p->native_->input_access_denied_)
+        return HWCODEC_ERR_INPUT_ACCESS_DENIED;
+    }
+    return ret;
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("Exception: ") + e.what());
   }
+  if (p->native_->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
   return -1;
 }
 
-int mfx_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
+int mfx_test_encode(int64_t *outLuids, int32_t *outVendors,
+                    EncodeCaps *outCaps, int32_t maxDescNum, int32_t *outDescNum,
                     DataFormat dataFormat, int32_t width,
                     int32_t height, int32_t kbs, int32_t framerate,
-                    int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount) {
+                    int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount,
+                    EncodeCallback callback, void *obj) {
   try {
     Adapters adapters;
     if (!adapters.Init(ADAPTER_VENDOR_INTEL))
@@ -647,19 +1000,20 @@ int mfx_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum,
       
       VplEncoder *e = (VplEncoder *)mfx_new_encoder(
           (void *)adapter.get()->device_.Get(), currentLuid,
-          dataFormat, width, height, kbs, framerate, gop);
+          dataFormat, width, height, kbs, framerate, gop, nullptr);
       if (!e)
         continue;
       if (e->native_->EnsureTexture(e->width_, e->height_)) {
         e->native_->next();
-        int32_t key_obj = 0;
+        util_encode::TestOutput out = {0, callback, obj, currentLuid};
         auto start = util::now();
-        bool succ = mfx_encode(e, e->native_->GetCurrentTexture(), util_encode::vram_encode_test_callback, &key_obj,
-                       0) == 0 && key_obj == 1;
+        bool succ = mfx_encode(e, e->native_->GetCurrentTexture(), util_encode::vram_encode_test_callback, &out,
+                       0, 0) == 0 && out.key == 1;
         int64_t elapsed = util::elapsed_ms(start);
         if (succ && elapsed < TEST_TIMEOUT_MS) {
           outLuids[count] = currentLuid;
           outVendors[count] = VENDOR_INTEL;
+          e->caps(&outCaps[count]);
           count += 1;
         }
       }
@@ -678,6 +1032,121 @@ int mfx_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum,
   return -1;
 }
 
+int mfx_encoder_memory(void *encoder, MemoryInfo *info) {
+  VplEncoder *p = (VplEncoder *)encoder;
+  estimate_encoder_memory(p->dataFormat_, p->width_, p->height_, 0,
+                          MFX_INPUT_NV12_SURFACES, info);
+  info->allocated = p->allocated_;
+  return 0;
+}
+
+int mfx_estimate_encoder_memory(int32_t dataFormat, int32_t width,
+                                int32_t height, MemoryInfo *info) {
+  estimate_encoder_memory((DataFormat)dataFormat, width, height, 0,
+                          MFX_INPUT_NV12_SURFACES, info);
+  info->allocated = 0;
+  return 0;
+}
+
+// Queries the parameters on a session of the adapter without creating an
+// encoder. 0 when the runtime takes them as given.
+int mfx_check_encoder_config(void *handle, int64_t luid, DataFormat dataFormat,
+                             int32_t width, int32_t height, int32_t kbs,
+                             int32_t framerate, int32_t gop,
+                             const EncodeOptions *options, ConfigCheck *check) {
+  *check = {};
+  VplEncoder *p = NULL;
+  int ret = HWCODEC_ERR_COMMON;
+  try {
+    p = new VplEncoder(handle, luid, dataFormat, width, height, kbs, framerate,
+                       gop, options);
+    mfxStatus sts = p->open();
+    if (sts == MFX_ERR_NONE) {
+      ret = p->check_config(check) ? HWCODEC_SUCCESS : HWCODEC_ERR_COMMON;
+    } else {
+      LOG_ERROR(std::string("open failed, sts=") + std::to_string(sts));
+    }
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("Exception: ") + e.what());
+  }
+  if (p) {
+    p->destroy();
+    delete p;
+  }
+  return ret;
+}
+
+int mfx_encoder_info(void *encoder, RuntimeInfo *info) {
+  VplEncoder *p = (VplEncoder *)encoder;
+  if (p->external_session_)
+    mfx_runtime_info(p->external_session_, info);
+  else
+    p->session_.Info(info);
+  // what Init negotiated, GetVideoParam wrote it back
+  if (p->mfxEncParams_.mfx.GopRefDist > 1)
+    info->bframes = p->mfxEncParams_.mfx.GopRefDist - 1;
+  return 0;
+}
+
+int mfx_encoder_gpu_timings(void *encoder, GpuTiming *timings, int32_t max,
+                            int32_t *count) {
+  VplEncoder *p = (VplEncoder *)encoder;
+  *count = 0;
+  while (*count < max && p->timer_.Collect(&timings[*count]))
+    (*count)++;
+  return 0;
+}
+
+// each frame is synced before encode returns, nothing stays buffered
+int mfx_encoder_flush(void *encoder, EncodeCallback callback, void *obj) {
+  return 0;
+}
+
+// no emphasis maps, the caps never report ENCODE_CAP_EMPHASIS_MAP
+int mfx_encoder_set_emphasis_map(void *encoder, const uint8_t *map,
+                                 int32_t len) {
+  return HWCODEC_ERR_COMMON;
+}
+
+// the qp of a frame can only be set in constant qp mode
+int mfx_encoder_set_qp_delta(void *encoder, int32_t delta) {
+  return HWCODEC_ERR_COMMON;
+}
+
+// no reference invalidation, the caps never report ENCODE_CAP_REF_INVALIDATION
+int mfx_encoder_invalidate_reference(void *encoder, int64_t ms) {
+  return HWCODEC_ERR_COMMON;
+}
+
+int mfx_check_encoder(void *encoder) {
+  VplEncoder *p = (VplEncoder *)encoder;
+  try {
+    if (p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    if (!p->mfxENC_)
+      return HWCODEC_ERR_SESSION_LOST;
+    mfxVideoParam param;
+    memset(&param, 0, sizeof(param));
+    mfxStatus sts = p->mfxENC_->GetVideoParam(&param);
+    if (sts == MFX_ERR_NONE)
+      return HWCODEC_SUCCESS;
+    LOG_ERROR(std::string("check encoder failed, sts=") + std::to_string(sts));
+    return is_session_lost(sts) ? HWCODEC_ERR_SESSION_LOST : HWCODEC_ERR_COMMON;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("Exception: ") + e.what());
+  }
+  if (p->native_->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
+  return HWCODEC_ERR_COMMON;
+}
+
+// the next encoded frame is an IDR with the parameter sets
+int mfx_request_keyframe(void *encoder) {
+  VplEncoder *p = (VplEncoder *)encoder;
+  p->force_idr_ = true;
+  return 0;
+}
+
 // https://github.com/Intel-Media-SDK/MediaSDK/blob/master/doc/mediasdk-man.md#dynamic-bitrate-change
 // https://github.com/Intel-Media-SDK/MediaSDK/blob/master/doc/mediasdk-man.md#mfxinfomfx
 // https://spec.oneapi.io/onevpl/2.4.0/programming_guide/VPL_prg_encoding.html#configuration-change
@@ -690,6 +1159,8 @@ int mfx_set_bitrate(void *encoder, int32_t kbs) {
     p->mfxENC_->GetVideoParam(&p->mfxEncParams_);
     p->mfxEncParams_.mfx.TargetKbps = kbs;
     p->mfxEncParams_.mfx.MaxKbps = kbs;
+    if (p->options_.cloudGaming)
+      p->setOneFrameBuffer();
     sts = p->mfxENC_->Reset(&p->mfxEncParams_);
     if (sts != MFX_ERR_NONE) {
       LOG_ERROR(std::string("reset failed, sts=") + std::to_string(sts));
@@ -4,14 +4,36 @@
 #include "../common/callback.h"
 #include <stdbool.h>
 
-int mfx_driver_support();
+struct EncodeOptions;
+struct MemoryInfo;
+struct EncodeCaps;
+struct DecodeCaps;
+struct RuntimeInfo;
+struct GpuTiming;
+struct ConfigCheck;
+struct DriverProbe;
+
+int mfx_driver_support(struct DriverProbe *probe);
 
 void *mfx_new_encoder(void *handle, int64_t luid,
                       int32_t dataFormat, int32_t width, int32_t height,
-                      int32_t kbs, int32_t framerate, int32_t gop);
+                      int32_t kbs, int32_t framerate, int32_t gop,
+                      const struct EncodeOptions *options);
+
+void *mfx_new_encoder_from_session(void *session, int64_t luid,
+                                   int32_t dataFormat, int32_t width,
+                                   int32_t height, int32_t kbs,
+                                   int32_t framerate, int32_t gop,
+                                   const struct EncodeOptions *options);
+
+void *mfx_debug_new_session(void *device);
+
+int mfx_debug_session_alive(void *session);
+
+void mfx_debug_close_session(void *session);
 
 int mfx_encode(void *encoder, void *tex, EncodeCallback callback, void *obj,
-               int64_t ms);
+               int64_t ms, uint64_t user_data);
 
 int mfx_destroy_encoder(void *encoder);
 
@@ -23,15 +45,59 @@ int mfx_decode(void *decoder, uint8_t *data, int len, DecodeCallback callback,
 
 int mfx_destroy_decoder(void *decoder);
 
-int mfx_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
+int mfx_decoder_memory(void *decoder, struct MemoryInfo *info);
+
+int mfx_decoder_set_conceal(void *decoder, int32_t conceal);
+
+int mfx_decoder_caps(void *decoder, struct DecodeCaps *caps);
+
+int mfx_test_encode(int64_t *outLuids, int32_t *outVendors,
+                    struct EncodeCaps *outCaps, int32_t maxDescNum, int32_t *outDescNum,
                     int32_t dataFormat, int32_t width,
                     int32_t height, int32_t kbs, int32_t framerate,
-                    int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount);
+                    int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount,
+                    EncodeCallback callback, void *obj);
 
 int mfx_test_decode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
                     int32_t dataFormat, uint8_t *data,
                     int32_t length, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount);
 
+int mfx_check_encoder(void *encoder);
+
+int mfx_encoder_memory(void *encoder, struct MemoryInfo *info);
+
+int mfx_encoder_info(void *encoder, struct RuntimeInfo *info);
+
+int mfx_encoder_gpu_timings(void *encoder, struct GpuTiming *timings,
+                            int32_t max, int32_t *count);
+
+int mfx_encoder_flush(void *encoder, EncodeCallback callback, void *obj);
+
+int mfx_encoder_set_emphasis_map(void *encoder, const uint8_t *map,
+                                int32_t len);
+
+int mfx_encoder_set_qp_delta(void *encoder, int32_t delta);
+
+int mfx_encoder_invalidate_reference(void *encoder, int64_t ms);
+
+int mfx_estimate_encoder_memory(int32_t dataFormat, int32_t width,
+                                int32_t height, struct MemoryInfo *info);
+
+int mfx_check_encoder_config(void *handle, int64_t luid, int32_t dataFormat,
+                             int32_t width, int32_t height, int32_t kbs,
+                             int32_t framerate, int32_t gop,
+                             const struct EncodeOptions *options,
+                             struct ConfigCheck *check);
+
+void *mfx_new_jpeg_encoder(void *device, int64_t luid, int32_t quality);
+
+int mfx_jpeg_encode(void *encoder, void *tex, int32_t width, int32_t height,
+                    EncodeCallback callback, void *obj);
+
+int mfx_destroy_jpeg_encoder(void *encoder);
+
+int mfx_request_keyframe(void *encoder);
+
 int mfx_set_bitrate(void *encoder, int32_t kbs);
 
 int mfx_set_framerate(void *encoder, int32_t framerate);
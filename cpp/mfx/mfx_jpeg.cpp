@@ -0,0 +1,266 @@
+#include <cstring>
+#include <sample_defs.h>
+#include <sample_utils.h>
+
+#include "callback.h"
+#include "common.h"
+#include "mfx_session.h"
+#include "system.h"
+#include "util.h"
+
+#define LOG_MODULE "MFXJPEG"
+#include "log.h"
+
+#define CHECK_STATUS(X, MSG)                                                   \
+  {                                                                            \
+    mfxStatus __sts = (X);                                                     \
+    if (__sts != MFX_ERR_NONE) {                                               \
+      LOG_ERROR(std::string(MSG) + " failed, sts=" +                           \
+                std::to_string((int)__sts));                                   \
+      return __sts;                                                            \
+    }                                                                          \
+  }
+
+namespace {
+
+mfxStatus MFX_CDECL simple_getHDL(mfxHDL pthis, mfxMemId mid, mfxHDL *handle) {
+  mfxHDLPair *pair = (mfxHDLPair *)handle;
+  pair->first = mid;
+  pair->second = (mfxHDL)(UINT)0;
+  return MFX_ERR_NONE;
+}
+
+mfxFrameAllocator frameAllocator{{},   NULL,          NULL, NULL,
+                                 NULL, simple_getHDL, NULL};
+
+// One image per call on the JPEG engine, the bgra input is converted into a
+// full range bt.601 nv12 texture. The encoder is initialized for the size of
+// the first image and again whenever it changes.
+class VplJpegEncoder {
+public:
+  std::unique_ptr<NativeDevice> native_ = nullptr;
+  MfxSession session_;
+  MFXVideoENCODE *mfxENC_ = nullptr;
+  mfxVideoParam mfxEncParams_;
+  mfxFrameSurface1 surface_;
+  mfxBitstream mfxBS_;
+  std::vector<mfxU8> bstData_;
+  ComPtr<ID3D11Texture2D> nv12Texture_ = nullptr;
+
+  void *device_;
+  int64_t luid_;
+  int32_t quality_;
+  int width_ = 0;
+  int height_ = 0;
+  bool initialized_ = false;
+
+  VplJpegEncoder(void *device, int64_t luid, int32_t quality) {
+    device_ = device;
+    luid_ = luid;
+    quality_ = quality;
+  }
+
+  mfxStatus Init() {
+    native_ = std::make_unique<NativeDevice>();
+    if (!native_->Init(luid_, (ID3D11Device *)device_)) {
+      LOG_ERROR(std::string("failed to init native device"));
+      return MFX_ERR_DEVICE_FAILED;
+    }
+    mfxStatus sts = session_.Create(luid_);
+    CHECK_STATUS(sts, "Create session");
+    sts = MFXVideoCORE_SetHandle(session_, MFX_HANDLE_D3D11_DEVICE,
+                                 native_->device_.Get());
+    CHECK_STATUS(sts, "SetHandle");
+    sts = MFXVideoCORE_SetFrameAllocator(session_, &frameAllocator);
+    CHECK_STATUS(sts, "SetFrameAllocator");
+    mfxENC_ = new MFXVideoENCODE(session_);
+    // adapters without a JPEG engine fail here rather than on the first image
+    memset(&mfxEncParams_, 0, sizeof(mfxEncParams_));
+    setParams(64, 64);
+    mfxVideoParam out = mfxEncParams_;
+    sts = mfxENC_->Query(&mfxEncParams_, &out);
+    CHECK_STATUS(sts, "Query");
+    return MFX_ERR_NONE;
+  }
+
+  // tex is at least the next even size, width and height go into the header
+  int encode(ID3D11Texture2D *tex, int width, int height,
+             EncodeCallback callback, void *obj) {
+    if (width != width_ || height != height_ || !initialized_) {
+      if (resize(tex, width, height) != MFX_ERR_NONE)
+        return -1;
+    }
+    if (!native_->BgraToNv12(tex, nv12Texture_.Get(), (width + 1) & ~1,
+                             (height + 1) & ~1,
+                             DXGI_COLOR_SPACE_RGB_FULL_G22_NONE_P709,
+                             DXGI_COLOR_SPACE_YCBCR_FULL_G22_LEFT_P601)) {
+      LOG_ERROR(std::string("failed to convert to NV12"));
+      return -1;
+    }
+    surface_.Data.MemId = nv12Texture_.Get();
+
+    mfxSyncPoint syncp = NULL;
+    mfxStatus sts = MFX_ERR_NONE;
+    auto start = util::now();
+    do {
+      if (util::elapsed_ms(start) > ENCODE_TIMEOUT_MS) {
+        LOG_ERROR(std::string("encode timeout"));
+        break;
+      }
+      mfxBS_.DataLength = 0;
+      mfxBS_.DataOffset = 0;
+      sts = mfxENC_->EncodeFrameAsync(NULL, &surface_, &mfxBS_, &syncp);
+      if (sts == MFX_WRN_DEVICE_BUSY) {
+        Sleep(1);
+      } else if (sts == MFX_ERR_NOT_ENOUGH_BUFFER &&
+                 mfxBS_.MaxLength < 64 * 1024 * 1024) {
+        mfxBS_.MaxLength *= 2;
+        bstData_.resize(mfxBS_.MaxLength);
+        mfxBS_.Data = bstData_.data();
+      }
+    } while (sts == MFX_WRN_DEVICE_BUSY || sts == MFX_ERR_NOT_ENOUGH_BUFFER);
+    if (sts != MFX_ERR_NONE || !syncp) {
+      LOG_ERROR(std::string("EncodeFrameAsync failed, sts=") +
+                std::to_string(sts));
+      return -1;
+    }
+    sts = MFXVideoCORE_SyncOperation(session_, syncp, 1000);
+    if (sts != MFX_ERR_NONE || mfxBS_.DataLength == 0) {
+      LOG_ERROR(std::string("SyncOperation failed, sts=") +
+                std::to_string(sts));
+      return -1;
+    }
+    if (callback)
+      callback(mfxBS_.Data + mfxBS_.DataOffset, mfxBS_.DataLength, 1, obj, 0,
+               0);
+    return 0;
+  }
+
+  void destroy() {
+    if (mfxENC_) {
+      mfxENC_->Close();
+      delete mfxENC_;
+      mfxENC_ = NULL;
+    }
+  }
+
+private:
+  void setParams(int width, int height) {
+    mfxEncParams_.mfx.CodecId = MFX_CODEC_JPEG;
+    mfxEncParams_.mfx.Quality = quality_;
+    mfxEncParams_.mfx.Interleaved = MFX_SCANTYPE_INTERLEAVED;
+    mfxEncParams_.mfx.RestartInterval = 0;
+    mfxEncParams_.mfx.FrameInfo.FrameRateExtN = 30;
+    mfxEncParams_.mfx.FrameInfo.FrameRateExtD = 1;
+    mfxEncParams_.mfx.FrameInfo.FourCC = MFX_FOURCC_NV12;
+    mfxEncParams_.mfx.FrameInfo.ChromaFormat = MFX_CHROMAFORMAT_YUV420;
+    mfxEncParams_.mfx.FrameInfo.PicStruct = MFX_PICSTRUCT_PROGRESSIVE;
+    mfxEncParams_.mfx.FrameInfo.CropX = 0;
+    mfxEncParams_.mfx.FrameInfo.CropY = 0;
+    mfxEncParams_.mfx.FrameInfo.CropW = width;
+    mfxEncParams_.mfx.FrameInfo.CropH = height;
+    mfxEncParams_.mfx.FrameInfo.Width = MSDK_ALIGN16(width);
+    mfxEncParams_.mfx.FrameInfo.Height = MSDK_ALIGN16(height);
+    mfxEncParams_.IOPattern = MFX_IOPATTERN_IN_VIDEO_MEMORY;
+    mfxEncParams_.AsyncDepth = 1;
+  }
+
+  mfxStatus resize(ID3D11Texture2D *tex, int width, int height) {
+    if (initialized_) {
+      mfxENC_->Close();
+      initialized_ = false;
+    }
+    D3D11_TEXTURE2D_DESC desc;
+    ZeroMemory(&desc, sizeof(desc));
+    tex->GetDesc(&desc);
+    desc.Width = (width + 1) & ~1;
+    desc.Height = (height + 1) & ~1;
+    desc.MipLevels = 1;
+    desc.ArraySize = 1;
+    desc.Format = DXGI_FORMAT_NV12;
+    desc.Usage = D3D11_USAGE_DEFAULT;
+    desc.BindFlags = D3D11_BIND_RENDER_TARGET;
+    desc.CPUAccessFlags = 0;
+    desc.MiscFlags = 0;
+    if (FAILED(native_->device_->CreateTexture2D(
+            &desc, NULL, nv12Texture_.ReleaseAndGetAddressOf()))) {
+      LOG_ERROR(std::string("failed to create the nv12 texture"));
+      return MFX_ERR_MEMORY_ALLOC;
+    }
+
+    memset(&mfxEncParams_, 0, sizeof(mfxEncParams_));
+    setParams(width, height);
+    mfxStatus sts = mfxENC_->Init(&mfxEncParams_);
+    MSDK_IGNORE_MFX_STS(sts, MFX_WRN_PARTIAL_ACCELERATION);
+    CHECK_STATUS(sts, "Init");
+    initialized_ = true;
+    width_ = width;
+    height_ = height;
+
+    memset(&surface_, 0, sizeof(surface_));
+    surface_.Info = mfxEncParams_.mfx.FrameInfo;
+    // raw 4:2:0 is above what any quality codes to
+    memset(&mfxBS_, 0, sizeof(mfxBS_));
+    mfxBS_.MaxLength = desc.Width * desc.Height * 2 + 4096;
+    bstData_.resize(mfxBS_.MaxLength);
+    mfxBS_.Data = bstData_.data();
+    return MFX_ERR_NONE;
+  }
+};
+
+} // namespace
+
+extern "C" {
+
+void *mfx_new_jpeg_encoder(void *device, int64_t luid, int32_t quality) {
+  VplJpegEncoder *p = NULL;
+  try {
+    p = new VplJpegEncoder(device, luid, quality);
+    mfxStatus sts = p->Init();
+    if (sts == MFX_ERR_NONE)
+      return p;
+    LOG_ERROR(std::string("Init failed, sts=") + std::to_string(sts));
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("Exception: ") + e.what());
+  }
+  if (p) {
+    p->destroy();
+    delete p;
+  }
+  return NULL;
+}
+
+int mfx_jpeg_encode(void *encoder, void *tex, int32_t width, int32_t height,
+                    EncodeCallback callback, void *obj) {
+  VplJpegEncoder *p = (VplJpegEncoder *)encoder;
+  try {
+    if (p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    int ret = check_input_texture(p->native_->device_.Get(),
+                                  (ID3D11Texture2D *)tex);
+    if (ret != 0)
+      return ret;
+    ret = p->encode((ID3D11Texture2D *)tex, width, height, callback, obj);
+    if (ret != 0) {
+      if (p->native_->IsDeviceLost())
+        return HWCODEC_ERR_DEVICE_LOST;
+      if (p->native_->input_access_denied_)
+        return HWCODEC_ERR_INPUT_ACCESS_DENIED;
+    }
+    return ret;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("Exception: ") + e.what());
+  }
+  return HWCODEC_ERR_COMMON;
+}
+
+int mfx_destroy_jpeg_encoder(void *encoder) {
+  VplJpegEncoder *p = (VplJpegEncoder *)encoder;
+  if (p) {
+    p->destroy();
+    delete p;
+  }
+  return 0;
+}
+
+} // extern "C"
@@ -0,0 +1,140 @@
+#include <cstdio>
+#include <cstring>
+
+#include "common.h"
+#include "mfx_session.h"
+#include "system.h"
+
+#define LOG_MODULE "MFX"
+#include "log.h"
+
+namespace {
+
+// the dxgi enumeration order, VendorImplID of the adapter's implementations
+int adapter_index(int64_t luid) {
+  ComPtr<IDXGIFactory1> factory;
+  if (FAILED(CreateDXGIFactory1(IID_PPV_ARGS(factory.GetAddressOf()))))
+    return -1;
+  ComPtr<IDXGIAdapter1> adapter;
+  for (UINT i = 0; factory->EnumAdapters1(i, adapter.ReleaseAndGetAddressOf()) !=
+                   DXGI_ERROR_NOT_FOUND;
+       i++) {
+    DXGI_ADAPTER_DESC1 desc;
+    if (SUCCEEDED(adapter->GetDesc1(&desc)) && LUID(desc) == luid)
+      return i;
+  }
+  return -1;
+}
+
+bool implementation_on(mfxLoader loader, mfxU32 i, int64_t luid, int index) {
+  if (luid == 0)
+    return true;
+  // api 2.6 runtimes report the luid
+  mfxExtendedDeviceId *id = nullptr;
+  if (MFXEnumImplementations(loader, i, MFX_IMPLCAPS_DEVICE_ID_EXTENDED,
+                             (mfxHDL *)&id) == MFX_ERR_NONE &&
+      id) {
+    bool match = id->LUIDValid &&
+                 memcmp(id->DeviceLUID, &luid, sizeof(luid)) == 0;
+    MFXDispReleaseImplDescription(loader, id);
+    return match;
+  }
+  // older VPL runtimes and the Media SDK one
+  mfxImplDescription *desc = nullptr;
+  if (MFXEnumImplementations(loader, i, MFX_IMPLCAPS_IMPLDESCSTRUCTURE,
+                             (mfxHDL *)&desc) != MFX_ERR_NONE ||
+      !desc)
+    return false;
+  bool match = index >= 0 && desc->VendorImplID == (mfxU32)index;
+  MFXDispReleaseImplDescription(loader, desc);
+  return match;
+}
+
+void filter_u32(mfxLoader loader, const char *name, mfxU32 value) {
+  mfxConfig cfg = MFXCreateConfig(loader);
+  mfxVariant v{};
+  v.Type = MFX_VARIANT_TYPE_U32;
+  v.Data.U32 = value;
+  MFXSetConfigFilterProperty(cfg, (const mfxU8 *)name, v);
+}
+
+} // namespace
+
+mfxStatus MfxSession::Create(int64_t luid) {
+  Close();
+  // the dispatcher loads the runtimes, libmfx64-gen.dll of VPL and
+  // libmfxhw64.dll of the Media SDK, neither is linked
+  if (!module_hidden("libmfx64-gen.dll"))
+    loader_ = MFXLoad();
+  if (loader_) {
+    filter_u32(loader_, "mfxImplDescription.Impl", MFX_IMPL_TYPE_HARDWARE);
+    filter_u32(loader_, "mfxImplDescription.AccelerationMode",
+               MFX_ACCEL_MODE_VIA_D3D11);
+    int index = luid ? adapter_index(luid) : -1;
+    mfxImplDescription *desc = nullptr;
+    for (mfxU32 i = 0;
+         MFXEnumImplementations(loader_, i, MFX_IMPLCAPS_IMPLDESCSTRUCTURE,
+                                (mfxHDL *)&desc) == MFX_ERR_NONE;
+         i++) {
+      MFXDispReleaseImplDescription(loader_, desc);
+      if (!implementation_on(loader_, i, luid, index))
+        continue;
+      mfxStatus sts = MFXCreateSession(loader_, i, &session_);
+      if (sts == MFX_ERR_NONE) {
+        impl_ = i;
+        return MFX_ERR_NONE;
+      }
+      LOG_WARN(std::string("MFXCreateSession failed, sts=") +
+               std::to_string(sts));
+    }
+    MFXUnload(loader_);
+    loader_ = nullptr;
+  }
+
+  LOG_DEBUG("no oneVPL implementation, trying MFXInitEx");
+  if (module_hidden("libmfxhw64.dll"))
+    return MFX_ERR_UNSUPPORTED;
+  mfxInitParam params{};
+  params.Implementation = MFX_IMPL_HARDWARE_ANY | MFX_IMPL_VIA_D3D11;
+  params.Version.Major = 1;
+  params.Version.Minor = 0;
+  params.GPUCopy = MFX_GPUCOPY_OFF;
+  return MFXInitEx(params, &session_);
+}
+
+void MfxSession::Close() {
+  if (session_) {
+    MFXClose(session_);
+    session_ = nullptr;
+  }
+  // the loader goes after the sessions it created
+  if (loader_) {
+    MFXUnload(loader_);
+    loader_ = nullptr;
+  }
+  impl_ = -1;
+}
+
+void MfxSession::Info(RuntimeInfo *info) const {
+  mfx_runtime_info(session_, info);
+  mfxImplDescription *desc = nullptr;
+  if (loader_ && impl_ >= 0 &&
+      MFXEnumImplementations(loader_, impl_, MFX_IMPLCAPS_IMPLDESCSTRUCTURE,
+                             (mfxHDL *)&desc) == MFX_ERR_NONE &&
+      desc) {
+    snprintf(info->implementation, sizeof(info->implementation), "%s",
+             desc->ImplName);
+    MFXDispReleaseImplDescription(loader_, desc);
+  }
+}
+
+void mfx_runtime_info(mfxSession session, RuntimeInfo *info) {
+  memset(info, 0, sizeof(*info));
+  mfxVersion ver{};
+  if (!session || MFXQueryVersion(session, &ver) != MFX_ERR_NONE)
+    return;
+  info->apiMajor = ver.Major;
+  info->apiMinor = ver.Minor;
+  snprintf(info->runtime, sizeof(info->runtime), "%s",
+           ver.Major >= 2 ? "oneVPL" : "Media SDK");
+}
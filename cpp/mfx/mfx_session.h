@@ -0,0 +1,34 @@
+#ifndef MFX_SESSION_H
+#define MFX_SESSION_H
+
+#include <cstdint>
+#include <mfxdispatcher.h>
+#include <mfxvideo.h>
+
+struct RuntimeInfo;
+
+// A hardware d3d11 session created through the oneVPL dispatcher, which loads
+// the VPL runtime on Xe and newer and the Media SDK runtime on older adapters.
+// MFXInitEx is the fallback when the dispatcher enumerates no implementation.
+class MfxSession {
+public:
+  ~MfxSession() { Close(); }
+
+  // luid 0 takes the first hardware implementation
+  mfxStatus Create(int64_t luid);
+  void Close();
+  void Info(RuntimeInfo *info) const;
+
+  operator mfxSession() const { return session_; }
+
+private:
+  mfxLoader loader_ = nullptr;
+  mfxSession session_ = nullptr;
+  // of the implementation in loader_, -1 after the fallback
+  int impl_ = -1;
+};
+
+// the runtime of a session created by anyone, the implementation is unknown
+void mfx_runtime_info(mfxSession session, RuntimeInfo *info);
+
+#endif // MFX_SESSION_H
@@ -93,7 +93,8 @@ public:
     return true;
   }
 
-  int write_video_frame(const uint8_t *data, int len, int64_t pts_ms, int key) {
+  int write_video_frame(const uint8_t *data, int len, int64_t pts_ms,
+                        int64_t duration_ms, int key) {
     OutputStream *ost = &video_st;
     AVPacket *pkt = ost->tmp_pkt;
     AVFormatContext *fmt_ctx = oc;
@@ -116,7 +117,9 @@ public:
     pkt->size = len;
     pkt->pts = pts;
     pkt->dts = pkt->pts; // no B-frame
-    int64_t duration = pkt->pts - last_pts;
+    int64_t duration = duration_ms;
+    if (duration <= 0)
+      duration = pkt->pts - last_pts;
     last_pts = pkt->pts;
     pkt->duration = duration > 0 ? duration : 1000 / framerate; // predict
     AVRational rational;
@@ -162,9 +165,10 @@ extern "C" Muxer *hwcodec_new_muxer(const char *filename, int width, int height,
 }
 
 extern "C" int hwcodec_write_video_frame(Muxer *muxer, const uint8_t *data,
-                                         int len, int64_t pts_ms, int key) {
+                                         int len, int64_t pts_ms,
+                                         int64_t duration_ms, int key) {
   try {
-    return muxer->write_video_frame(data, len, pts_ms, key);
+    return muxer->write_video_frame(data, len, pts_ms, duration_ms, key);
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("write_video_frame exception: ") + std::string(e.what()));
   }
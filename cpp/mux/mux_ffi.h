@@ -6,8 +6,9 @@
 void *hwcodec_new_muxer(const char *filename, int width, int height, int is265,
                         int framerate);
 
+// duration_ms <= 0 takes the interval since the previous frame
 int hwcodec_write_video_frame(void *muxer, const uint8_t *data, int len,
-                              int64_t pts_ms, int key);
+                              int64_t pts_ms, int64_t duration_ms, int key);
 int hwcodec_write_tail(void *muxer);
 
 void hwcodec_free_muxer(void *muxer);
@@ -1,5 +1,6 @@
 #define FFNV_LOG_FUNC
 #define FFNV_DEBUG_LOG_FUNC
+#include "nv_dynlink.h"
 
 #include <DirectXMath.h>
 #include <Samples/NvCodec/NvDecoder/NvDecoder.h>
@@ -67,17 +68,6 @@ public:
   }
 };
 
-void load_driver(CudaFunctions **pp_cudl, CuvidFunctions **pp_cvdl) {
-  if (cuda_load_functions(pp_cudl, NULL) < 0) {
-    LOG_TRACE(std::string("cuda_load_functions failed"));
-    NVDEC_THROW_ERROR("cuda_load_functions failed", CUDA_ERROR_UNKNOWN);
-  }
-  if (cuvid_load_functions(pp_cvdl, NULL) < 0) {
-    LOG_TRACE(std::string("cuvid_load_functions failed"));
-    NVDEC_THROW_ERROR("cuvid_load_functions failed", CUDA_ERROR_UNKNOWN);
-  }
-}
-
 void free_driver(CudaFunctions **pp_cudl, CuvidFunctions **pp_cvdl) {
   if (*pp_cvdl) {
     cuvid_free_functions(pp_cvdl);
@@ -89,6 +79,19 @@ void free_driver(CudaFunctions **pp_cudl, CuvidFunctions **pp_cvdl) {
   }
 }
 
+// see load_driver of nv_encode.cpp
+void load_driver(CudaFunctions **pp_cudl, CuvidFunctions **pp_cvdl) {
+  if (module_hidden("nvcuda.dll") || cuda_load_functions(pp_cudl, NULL) < 0) {
+    LOG_TRACE(std::string("cuda_load_functions failed"));
+    NVDEC_THROW_ERROR("cuda_load_functions failed", CUDA_ERROR_UNKNOWN);
+  }
+  if (module_hidden("nvcuvid.dll") || cuvid_load_functions(pp_cvdl, NULL) < 0) {
+    LOG_TRACE(std::string("cuvid_load_functions failed"));
+    free_driver(pp_cudl, pp_cvdl);
+    NVDEC_THROW_ERROR("cuvid_load_functions failed", CUDA_ERROR_UNKNOWN);
+  }
+}
+
 typedef struct _VERTEX {
   DirectX::XMFLOAT3 Pos;
   DirectX::XMFLOAT2 TexCoord;
@@ -158,6 +161,36 @@ public:
     return true;
   }
 
+  // cuvid reports sizes and bit depths, the profiles are those NVDEC decodes at
+  // each depth. The level stays unknown, JPEG has neither.
+  void caps(DecodeCaps *caps) {
+    *caps = {};
+    cudaVideoCodec codec;
+    if (!dataFormat_to_cuCodecID(dataFormat_, codec))
+      return;
+    CUVIDAutoCtxPopper popper(cudl_, cuContext_);
+    for (int bitDepth : {8, 10}) {
+      CUVIDDECODECAPS decodeCaps = {};
+      decodeCaps.eCodecType = codec;
+      decodeCaps.eChromaFormat = cudaVideoChromaFormat_420;
+      decodeCaps.nBitDepthMinus8 = bitDepth - 8;
+      if (!succ(cvdl_->cuvidGetDecoderCaps(&decodeCaps)) ||
+          !decodeCaps.bIsSupported)
+        continue;
+      caps->bitDepths |= 1u << bitDepth;
+      caps->maxWidth =
+          (std::max)(caps->maxWidth, (int32_t)decodeCaps.nMaxWidth);
+      caps->maxHeight =
+          (std::max)(caps->maxHeight, (int32_t)decodeCaps.nMaxHeight);
+      if (codec == cudaVideoCodec_H264 && bitDepth == 8)
+        caps->profiles |= DECODE_PROFILE_H264_BASELINE |
+                          DECODE_PROFILE_H264_MAIN | DECODE_PROFILE_H264_HIGH;
+      else if (codec == cudaVideoCodec_HEVC)
+        caps->profiles |= bitDepth == 8 ? DECODE_PROFILE_HEVC_MAIN
+                                        : DECODE_PROFILE_HEVC_MAIN10;
+    }
+  }
+
   // ref: HandlePictureDisplay
   int decode(uint8_t *data, int len, DecodeCallback callback, void *obj) {
     int nFrameReturned = decode_and_recreate(data, len);
@@ -169,6 +202,12 @@ public:
     }
     last_video_format_ = dec_->GetLatestVideoFormat();
     cudaVideoSurfaceFormat format = dec_->GetOutputFormat();
+    if (format != cudaVideoSurfaceFormat_NV12) {
+      // 4:4:4 JPEG images, the shaders only sample NV12
+      LOG_ERROR(std::string("unsupported output format: ") +
+                std::to_string(format));
+      return -1;
+    }
     int width = dec_->GetWidth();
     int height = dec_->GetHeight();
     if (prepare_tried_ && (width != width_ || height != height_)) {
@@ -558,6 +597,9 @@ private:
     case H265:
       cuda = cudaVideoCodec_HEVC;
       break;
+    case MJPEG:
+      cuda = cudaVideoCodec_JPEG;
+      break;
     default:
       return false;
     }
@@ -577,6 +619,8 @@ private:
       delete dec_;
       dec_ = nullptr;
     }
+    // without a crop rect the frames are of the sps display area, the
+    // cropping of the coded size, e.g. 1088 rows, is applied by nvdec
     dec_ = new NvDecoder(cudl_, cvdl_, cuContext_, bUseDeviceFrame, cudaCodecID,
                          bLowLatency, bDeviceFramePitched);
     return true;
@@ -587,7 +631,12 @@ private:
 
 extern "C" {
 
-int nv_decode_driver_support() {
+// nvdec has no api version to check, any driver with the dlls decodes
+int nv_decode_driver_support(DriverProbe *probe) {
+  *probe = {};
+  snprintf(probe->name, sizeof(probe->name), "NVIDIA driver");
+  adapter_driver_version(ADAPTER_VENDOR_NVIDIA, probe->installed,
+                         sizeof(probe->installed));
   try {
     CudaFunctions *cudl = NULL;
     CuvidFunctions *cvdl = NULL;
@@ -638,16 +687,41 @@ _exit:
   return NULL;
 }
 
+int nv_decoder_memory(void *decoder, MemoryInfo *info) {
+  CuvidDecoder *p = (CuvidDecoder *)decoder;
+  estimate_decoder_memory(p->native_.get(), info);
+  return 0;
+}
+
+// cuvid always outputs concealed frames, not concealing is up to the caller
+int nv_decoder_set_conceal(void *decoder, int32_t conceal) {
+  return HWCODEC_SUCCESS;
+}
+
+int nv_decoder_caps(void *decoder, DecodeCaps *caps) {
+  try {
+    ((CuvidDecoder *)decoder)->caps(caps);
+    return HWCODEC_SUCCESS;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("caps failed: ") + e.what());
+  }
+  return HWCODEC_ERR_COMMON;
+}
+
 int nv_decode(void *decoder, uint8_t *data, int len, DecodeCallback callback,
               void *obj) {
+  CuvidDecoder *p = (CuvidDecoder *)decoder;
   try {
-    CuvidDecoder *p = (CuvidDecoder *)decoder;
+    if (p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
     if (p->decode(data, len, callback, obj) == 0 ) {
       return HWCODEC_SUCCESS;
     }
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("decode failed: ") + e.what());
   }
+  if (p->native_->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
   return HWCODEC_ERR_COMMON;
 }
 
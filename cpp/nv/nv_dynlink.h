@@ -0,0 +1,14 @@
+#ifndef NV_DYNLINK_H
+#define NV_DYNLINK_H
+
+// Before dynlink_loader.h. Its loads of nvcuda, nvcuvid and nvEncodeAPI go
+// through vendor_module_load of win.cpp, which shutdown frees what is left of.
+#define FFNV_LIB_HANDLE void *
+#define FFNV_LOAD_FUNC(path) vendor_module_load(path)
+#define FFNV_SYM_FUNC(lib, sym) GetProcAddress((HMODULE)(lib), (sym))
+#define FFNV_FREE_FUNC(lib) vendor_module_free(lib)
+
+void *vendor_module_load(const char *name);
+void vendor_module_free(void *module);
+
+#endif // NV_DYNLINK_H
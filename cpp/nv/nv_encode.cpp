@@ -1,10 +1,13 @@
 #define FFNV_LOG_FUNC
 #define FFNV_DEBUG_LOG_FUNC
+#include "nv_dynlink.h"
 
 #include <Samples/NvCodec/NvEncoder/NvEncoderD3D11.h>
 #include <Samples/Utils/Logger.h>
 #include <Samples/Utils/NvCodecUtils.h>
 #include <Samples/Utils/NvEncoderCLIOptions.h>
+#include <algorithm>
+#include <deque>
 #include <dynlink_cuda.h>
 #include <dynlink_loader.h>
 #include <fstream>
@@ -35,32 +38,94 @@ namespace {
 
 #define succ(call) ((call) == 0)
 
+void free_driver(CudaFunctions **pp_cuda_dl, NvencFunctions **pp_nvenc_dl) {
+  if (*pp_nvenc_dl) {
+    nvenc_free_functions(pp_nvenc_dl);
+    *pp_nvenc_dl = NULL;
+  }
+  if (*pp_cuda_dl) {
+    cuda_free_functions(pp_cuda_dl);
+    *pp_cuda_dl = NULL;
+  }
+}
+
+// The dlls come with the driver and are loaded here, not linked. A missing dll
+// or entry point throws with nothing left loaded.
 void load_driver(CudaFunctions **pp_cuda_dl, NvencFunctions **pp_nvenc_dl) {
-  if (cuda_load_functions(pp_cuda_dl, NULL) < 0) {
+  if (module_hidden("nvcuda.dll") ||
+      cuda_load_functions(pp_cuda_dl, NULL) < 0) {
     LOG_TRACE(std::string("cuda_load_functions failed"));
     NVENC_THROW_ERROR("cuda_load_functions failed", NV_ENC_ERR_GENERIC);
   }
-  if (nvenc_load_functions(pp_nvenc_dl, NULL) < 0) {
+  if (module_hidden("nvEncodeAPI64.dll") ||
+      nvenc_load_functions(pp_nvenc_dl, NULL) < 0) {
     LOG_TRACE(std::string("nvenc_load_functions failed"));
+    free_driver(pp_cuda_dl, pp_nvenc_dl);
     NVENC_THROW_ERROR("nvenc_load_functions failed", NV_ENC_ERR_GENERIC);
   }
 }
 
-void free_driver(CudaFunctions **pp_cuda_dl, NvencFunctions **pp_nvenc_dl) {
-  if (*pp_nvenc_dl) {
-    nvenc_free_functions(pp_nvenc_dl);
-    *pp_nvenc_dl = NULL;
+// The windows driver each nvenc api version came with, from the sdk readmes.
+// Sessions of an api the driver doesn't have fail to open.
+struct NvencDriver {
+  uint32_t major;
+  uint32_t minor;
+  const char *driver;
+};
+const NvencDriver NVENC_DRIVERS[] = {
+    {12, 2, "551.76"}, {12, 1, "531.61"}, {12, 0, "522.25"},
+    {11, 1, "471.41"}, {11, 0, "456.71"},
+};
+
+const char *required_driver() {
+  for (const NvencDriver &d : NVENC_DRIVERS) {
+    if (d.major == NVENCAPI_MAJOR_VERSION && d.minor == NVENCAPI_MINOR_VERSION)
+      return d.driver;
   }
-  if (*pp_cuda_dl) {
-    cuda_free_functions(pp_cuda_dl);
-    *pp_cuda_dl = NULL;
+  return "";
+}
+
+// the session can't be used anymore after these, e.g. after a session switch
+bool is_session_lost(NVENCSTATUS status) {
+  switch (status) {
+  case NV_ENC_ERR_NO_ENCODE_DEVICE:
+  case NV_ENC_ERR_UNSUPPORTED_DEVICE:
+  case NV_ENC_ERR_INVALID_DEVICE:
+  case NV_ENC_ERR_DEVICE_NOT_EXIST:
+  case NV_ENC_ERR_ENCODER_NOT_INITIALIZED:
+    return true;
+  default:
+    return false;
   }
 }
 
+// frameIntervalP + lookaheadDepth + nExtraOutputDelay
+#define NV_INPUT_BGRA_SURFACES 1
+
+// the frames kept as references with refInvalidation, the one before a loss
+// stays among them for the losses a receiver reports within this many frames
+#define NV_INVALIDATION_REFS 4
+
+// the sdk keeps the session handle and the api to its subclasses
+class NvEncoderSession : public NvEncoderD3D11 {
+public:
+  using NvEncoderD3D11::NvEncoderD3D11;
+
+  NVENCSTATUS InvalidateRefFrame(uint64_t timestamp) {
+    return m_nvenc.nvEncInvalidateRefFrames(m_hEncoder, timestamp);
+  }
+};
+
+// a frame of the references, by the inputTimeStamp it was encoded with
+struct NvReference {
+  int64_t ms;
+  bool valid;
+};
+
 class NvencEncoder {
 public:
   std::unique_ptr<NativeDevice> native_ = nullptr;
-  NvEncoderD3D11 *pEnc_ = nullptr;
+  NvEncoderSession *pEnc_ = nullptr;
   CudaFunctions *cuda_dl_ = nullptr;
   NvencFunctions *nvenc_dl_ = nullptr;
 
@@ -74,11 +139,22 @@ public:
   int32_t gop_;
   bool full_range_ = false;
   bool bt709_ = false;
+  EncodeOptions options_ = {};
+  int64_t allocated_ = 0;
+  bool force_idr_ = false;
   NV_ENC_CONFIG encodeConfig_ = {0};
+  GpuTimer timer_;
+  int64_t frame_ = 0;
+  // the emphasis levels for the next frame, empty for none
+  std::vector<int8_t> emphasis_;
+  // added to the qp of every block of the next frame, 0 for none
+  int32_t qp_delta_ = 0;
+  // with refInvalidation, the frames since the latest idr, oldest first
+  std::deque<NvReference> references_;
 
   NvencEncoder(void *handle, int64_t luid, DataFormat dataFormat,
                int32_t width, int32_t height, int32_t kbs, int32_t framerate,
-               int32_t gop) {
+               int32_t gop, const EncodeOptions *options) {
     handle_ = handle;
     luid_ = luid;
     dataFormat_ = dataFormat;
@@ -87,20 +163,22 @@ public:
     kbs_ = kbs;
     framerate_ = framerate;
     gop_ = gop;
+    if (options)
+      options_ = *options;
 
     load_driver(&cuda_dl_, &nvenc_dl_);
   }
 
   ~NvencEncoder() {}
 
-  bool init() {
-    GUID guidCodec;
+  // up to an encode session that isn't initialized, enough to query its caps
+  bool open(GUID *guidCodec) {
     switch (dataFormat_) {
     case H264:
-      guidCodec = NV_ENC_CODEC_H264_GUID;
+      *guidCodec = NV_ENC_CODEC_H264_GUID;
       break;
     case H265:
-      guidCodec = NV_ENC_CODEC_HEVC_GUID;
+      *guidCodec = NV_ENC_CODEC_HEVC_GUID;
       break;
     default:
       LOG_ERROR(std::string("dataFormat not support, dataFormat: ") +
@@ -130,17 +208,34 @@ public:
     }
 
     int nExtraOutputDelay = 0;
-    pEnc_ = new NvEncoderD3D11(cuda_dl_, nvenc_dl_, native_->device_.Get(),
-                               width_, height_, NV_ENC_BUFFER_FORMAT_ARGB,
-                               nExtraOutputDelay, false, false); // no delay
+    pEnc_ = new NvEncoderSession(cuda_dl_, nvenc_dl_, native_->device_.Get(),
+                                 width_, height_, NV_ENC_BUFFER_FORMAT_ARGB,
+                                 nExtraOutputDelay, false, false); // no delay
+    return true;
+  }
+
+  bool init() {
+    GUID guidCodec;
+    if (!open(&guidCodec))
+      return false;
     NV_ENC_INITIALIZE_PARAMS initializeParams = {0};
     ZeroMemory(&initializeParams, sizeof(initializeParams));
     ZeroMemory(&encodeConfig_, sizeof(encodeConfig_));
     initializeParams.encodeConfig = &encodeConfig_;
-    pEnc_->CreateDefaultEncoderParams(
-        &initializeParams, guidCodec,
-        NV_ENC_PRESET_P3_GUID /*NV_ENC_PRESET_LOW_LATENCY_HP_GUID*/,
-        NV_ENC_TUNING_INFO_LOW_LATENCY);
+    if (options_.lossless) {
+      pEnc_->CreateDefaultEncoderParams(&initializeParams, guidCodec,
+                                        NV_ENC_PRESET_P3_GUID,
+                                        NV_ENC_TUNING_INFO_LOSSLESS);
+    } else if (options_.cloudGaming) {
+      pEnc_->CreateDefaultEncoderParams(&initializeParams, guidCodec,
+                                        NV_ENC_PRESET_P1_GUID,
+                                        NV_ENC_TUNING_INFO_ULTRA_LOW_LATENCY);
+    } else {
+      pEnc_->CreateDefaultEncoderParams(
+          &initializeParams, guidCodec,
+          NV_ENC_PRESET_P3_GUID /*NV_ENC_PRESET_LOW_LATENCY_HP_GUID*/,
+          NV_ENC_TUNING_INFO_LOW_LATENCY);
+    }
 
     // no delay
     initializeParams.encodeConfig->frameIntervalP = 1;
@@ -154,24 +249,83 @@ public:
     // gop
     initializeParams.encodeConfig->gopLength =
         (gop_ > 0 && gop_ < MAX_GOP) ? gop_ : NVENC_INFINITE_GOPLENGTH;
+    // nvenc inserts keyframes at scene cuts only with lookahead, which is off,
+    // so there is no interval to keep either
+    if (options_.noSceneCutKeyframes)
+      initializeParams.encodeConfig->rcParams.disableIadapt = 1;
     // rc method
-    initializeParams.encodeConfig->rcParams.rateControlMode =
-        NV_ENC_PARAMS_RC_CBR;
+    if (options_.quality > 0) {
+      // vbr without a bitrate holds targetQuality, whatever bitrate it takes
+      NV_ENC_RC_PARAMS *rcParams = &initializeParams.encodeConfig->rcParams;
+      rcParams->rateControlMode = NV_ENC_PARAMS_RC_VBR;
+      rcParams->averageBitRate = 0;
+      rcParams->maxBitRate = 0;
+      rcParams->targetQuality = (uint8_t)options_.quality;
+      rcParams->targetQualityLSB = 0;
+    } else {
+      initializeParams.encodeConfig->rcParams.rateControlMode =
+          NV_ENC_PARAMS_RC_CBR;
+    }
+    // aq
+    setup_aq(initializeParams.encodeConfig, guidCodec);
+    // chroma_qp_index_offset of the h264 pps, pps_cb_qp_offset and
+    // pps_cr_qp_offset of the hevc one
+    initializeParams.encodeConfig->rcParams.cbQPIndexOffset =
+        (int8_t)std::max(std::min(options_.chromaQpOffset, 12), -12);
+    initializeParams.encodeConfig->rcParams.crQPIndexOffset =
+        initializeParams.encodeConfig->rcParams.cbQPIndexOffset;
+    if (options_.emphasisMap &&
+        !setup_emphasis(initializeParams.encodeConfig, guidCodec))
+      return false;
+    if (options_.qpDelta) {
+      if (options_.emphasisMap) {
+        LOG_ERROR(std::string("qp delta and emphasis map exclude each other"));
+        return false;
+      }
+      initializeParams.encodeConfig->rcParams.qpMapMode = NV_ENC_QP_MAP_DELTA;
+    }
+    if (options_.refInvalidation &&
+        !pEnc_->GetCapabilityValue(guidCodec,
+                                   NV_ENC_CAPS_SUPPORT_REF_PIC_INVALIDATION)) {
+      LOG_ERROR(std::string("reference invalidation not supported"));
+      return false;
+    }
     // color
     if (dataFormat_ == H264) {
       setup_h264(initializeParams.encodeConfig);
     } else {
       setup_hevc(initializeParams.encodeConfig);
     }
+    // after the rate control and profile it replaces
+    if (options_.lossless &&
+        !setup_lossless(initializeParams.encodeConfig, guidCodec))
+      return false;
+    // nvenc derives the vui sar from the display aspect ratio of the frame
+    if (options_.sarWidth > 0 && options_.sarHeight > 0 &&
+        options_.sarWidth != options_.sarHeight) {
+      initializeParams.darWidth = (uint32_t)(width_ * options_.sarWidth);
+      initializeParams.darHeight = (uint32_t)(height_ * options_.sarHeight);
+    }
+    if (options_.cloudGaming)
+      setup_cloud_gaming(&initializeParams, guidCodec);
+    if (options_.refInvalidation)
+      setup_references(initializeParams.encodeConfig);
 
+    // no session can be pinned to one of several encode engines, nvenc has no
+    // such parameter and balances the sessions across them itself
     pEnc_->CreateEncoder(&initializeParams);
+    if (options_.gpuTiming && !timer_.Init(native_->device_.Get()))
+      LOG_WARN("gpu timing unavailable");
     return true;
   }
 
-  int encode(void *texture, EncodeCallback callback, void *obj, int64_t ms) {
+  // no b-frames, lookahead or output delay, the packets are of this frame
+  int encode(void *texture, EncodeCallback callback, void *obj, int64_t ms,
+             uint64_t user_data) {
     bool encoded = false;
     std::vector<NvPacket> vPacket;
     const NvEncInputFrame *pEncInput = pEnc_->GetNextInputFrame();
+    timer_.Begin(frame_++, user_data);
 
     // TODO: sdk can ensure the inputPtr's width, height same as width_,
     // height_, does capture's frame can ensure width height same with width_,
@@ -185,23 +339,146 @@ public:
         pBgraTextyure, reinterpret_cast<ID3D11Texture2D *>(texture));
 #endif
 
+    timer_.Mark();
+
     NV_ENC_PIC_PARAMS picParams = {0};
     picParams.inputTimeStamp = ms;
-    pEnc_->EncodeFrame(vPacket);
+    if (!emphasis_.empty()) {
+      picParams.qpDeltaMap = emphasis_.data();
+      picParams.qpDeltaMapSize = (uint32_t)emphasis_.size();
+    }
+    std::vector<int8_t> qpDeltas;
+    if (qp_delta_ != 0) {
+      // a value per 16x16 block covers the h264 macroblocks and the hevc ctbs
+      qpDeltas.assign(((width_ + 15) / 16) * ((height_ + 15) / 16),
+                      (int8_t)qp_delta_);
+      picParams.qpDeltaMap = qpDeltas.data();
+      picParams.qpDeltaMapSize = (uint32_t)qpDeltas.size();
+    }
+    if (force_idr_) {
+      picParams.encodePicFlags =
+          NV_ENC_PIC_FLAG_FORCEIDR | NV_ENC_PIC_FLAG_OUTPUT_SPSPPS;
+      pEnc_->EncodeFrame(vPacket, &picParams);
+      force_idr_ = false;
+    } else if (picParams.qpDeltaMap) {
+      pEnc_->EncodeFrame(vPacket, &picParams);
+    } else {
+      pEnc_->EncodeFrame(vPacket);
+    }
+    emphasis_.clear();
+    qp_delta_ = 0;
+    timer_.End();
     for (NvPacket &packet : vPacket) {
-      int32_t key = (packet.pictureType == NV_ENC_PIC_TYPE_IDR ||
-                     packet.pictureType == NV_ENC_PIC_TYPE_I)
-                        ? 1
-                        : 0;
+      int32_t flags = 0;
+      if (packet.pictureType == NV_ENC_PIC_TYPE_IDR ||
+          packet.pictureType == NV_ENC_PIC_TYPE_I)
+        flags |= FRAME_FLAG_KEYFRAME;
+      if (packet.pictureType == NV_ENC_PIC_TYPE_NONREF_P)
+        flags |= FRAME_FLAG_NON_REFERENCE;
+      if (options_.refInvalidation)
+        add_reference(packet.pictureType, ms);
       if (packet.data.size() > 0) {
         if (callback)
-          callback(packet.data.data(), packet.data.size(), key, obj, ms);
+          callback(packet.data.data(), packet.data.size(), flags, obj, ms,
+                   user_data);
         encoded = true;
       }
     }
     return encoded ? 0 : -1;
   }
 
+  void caps(EncodeCaps *caps) {
+    *caps = {};
+    if (!pEnc_)
+      return;
+    GUID guidCodec = dataFormat_ == H265 ? NV_ENC_CODEC_HEVC_GUID
+                                         : NV_ENC_CODEC_H264_GUID;
+    auto value = [&](NV_ENC_CAPS cap) {
+      return pEnc_->GetCapabilityValue(guidCodec, cap);
+    };
+    if (value(NV_ENC_CAPS_NUM_MAX_BFRAMES) > 0)
+      caps->flags |= ENCODE_CAP_BFRAMES;
+    if (value(NV_ENC_CAPS_SUPPORT_10BIT_ENCODE))
+      caps->flags |= ENCODE_CAP_10BIT;
+    if (value(NV_ENC_CAPS_SUPPORT_LOSSLESS_ENCODE))
+      caps->flags |= ENCODE_CAP_LOSSLESS;
+    if (value(NV_ENC_CAPS_SUPPORT_TEMPORAL_AQ))
+      caps->flags |= ENCODE_CAP_TEMPORAL_AQ;
+    if (value(NV_ENC_CAPS_SUPPORT_INTRA_REFRESH))
+      caps->flags |= ENCODE_CAP_INTRA_REFRESH;
+    if (value(NV_ENC_CAPS_SUPPORT_DYN_RES_CHANGE))
+      caps->flags |= ENCODE_CAP_DYNAMIC_RESOLUTION;
+    // the driver applies emphasis maps to h264 only
+    if (dataFormat_ == H264 && value(NV_ENC_CAPS_SUPPORT_EMPHASIS_LEVEL_MAP))
+      caps->flags |= ENCODE_CAP_EMPHASIS_MAP;
+    if (value(NV_ENC_CAPS_SUPPORT_REF_PIC_INVALIDATION))
+      caps->flags |= ENCODE_CAP_REF_INVALIDATION;
+    // NV_ENC_LEVEL values are level_idc
+    caps->maxLevel = value(NV_ENC_CAPS_LEVEL_MAX);
+    caps->maxWidth = value(NV_ENC_CAPS_WIDTH_MAX);
+    caps->maxHeight = value(NV_ENC_CAPS_HEIGHT_MAX);
+  }
+
+  // the configuration against the caps of the opened session, false with the
+  // first parameter out of them in check
+  bool check_config(GUID guidCodec, ConfigCheck *check) {
+    auto value = [&](NV_ENC_CAPS cap) {
+      return pEnc_->GetCapabilityValue(guidCodec, cap);
+    };
+    auto reject = [&](ConfigParam param, bool hasCorrected, int32_t corrected) {
+      check->param = param;
+      check->hasCorrected = hasCorrected ? 1 : 0;
+      check->corrected = corrected;
+      return false;
+    };
+    // the caps query of a codec the gpu doesn't encode leaves them 0
+    int maxWidth = value(NV_ENC_CAPS_WIDTH_MAX);
+    int maxHeight = value(NV_ENC_CAPS_HEIGHT_MAX);
+    if (maxWidth <= 0 || maxHeight <= 0)
+      return reject(CONFIG_PARAM_FORMAT, false, 0);
+    if (width_ > maxWidth)
+      return reject(CONFIG_PARAM_WIDTH, true, maxWidth);
+    if (height_ > maxHeight)
+      return reject(CONFIG_PARAM_HEIGHT, true, maxHeight);
+    int minWidth = value(NV_ENC_CAPS_WIDTH_MIN);
+    int minHeight = value(NV_ENC_CAPS_HEIGHT_MIN);
+    if (width_ < minWidth)
+      return reject(CONFIG_PARAM_WIDTH, true, minWidth);
+    if (height_ < minHeight)
+      return reject(CONFIG_PARAM_HEIGHT, true, minHeight);
+    int64_t mbs = (int64_t)((width_ + 15) / 16) * ((height_ + 15) / 16);
+    int maxMbs = value(NV_ENC_CAPS_MB_NUM_MAX);
+    if (maxMbs > 0 && mbs > maxMbs)
+      return reject(CONFIG_PARAM_HEIGHT, true,
+                    (int32_t)(maxMbs / ((width_ + 15) / 16) * 16));
+    int maxMbsPerSec = value(NV_ENC_CAPS_MB_PER_SEC_MAX);
+    if (maxMbsPerSec > 0 && mbs * framerate_ > maxMbsPerSec)
+      return reject(CONFIG_PARAM_FRAMERATE, true,
+                    (int32_t)(maxMbsPerSec / mbs));
+    // setup_aq ignores these
+    if (options_.aqMode == AQ_TEMPORAL &&
+        !value(NV_ENC_CAPS_SUPPORT_TEMPORAL_AQ))
+      return reject(CONFIG_PARAM_AQ_MODE, true, AQ_DEFAULT);
+    if (options_.aqStrength > 15)
+      return reject(CONFIG_PARAM_AQ_STRENGTH, true, 15);
+    if (options_.aqStrength > 0 &&
+        (options_.aqMode == AQ_OFF || options_.aqMode == AQ_TEMPORAL))
+      return reject(CONFIG_PARAM_AQ_STRENGTH, true, 0);
+    if (options_.emphasisMap &&
+        (dataFormat_ != H264 ||
+         !value(NV_ENC_CAPS_SUPPORT_EMPHASIS_LEVEL_MAP)))
+      return reject(CONFIG_PARAM_EMPHASIS_MAP, true, 0);
+    if (options_.refInvalidation &&
+        !value(NV_ENC_CAPS_SUPPORT_REF_PIC_INVALIDATION))
+      return reject(CONFIG_PARAM_REF_INVALIDATION, true, 0);
+    if (options_.lossless && !value(NV_ENC_CAPS_SUPPORT_LOSSLESS_ENCODE))
+      return reject(CONFIG_PARAM_LOSSLESS, true, 0);
+    if (options_.chromaQpOffset < -12 || options_.chromaQpOffset > 12)
+      return reject(CONFIG_PARAM_CHROMA_QP_OFFSET, true,
+                    std::max(std::min(options_.chromaQpOffset, 12), -12));
+    return true;
+  }
+
   void destroy() {
     if (pEnc_) {
       pEnc_->DestroyEncoder();
@@ -211,6 +488,128 @@ public:
     free_driver(&cuda_dl_, &nvenc_dl_);
   }
 
+  void setup_aq(NV_ENC_CONFIG *encodeConfig, GUID guidCodec) {
+    NV_ENC_RC_PARAMS *rcParams = &encodeConfig->rcParams;
+    switch (options_.aqMode) {
+    case AQ_OFF:
+      rcParams->enableAQ = 0;
+      rcParams->enableTemporalAQ = 0;
+      break;
+    case AQ_SPATIAL:
+      rcParams->enableAQ = 1;
+      rcParams->enableTemporalAQ = 0;
+      break;
+    case AQ_TEMPORAL:
+      if (pEnc_->GetCapabilityValue(guidCodec,
+                                    NV_ENC_CAPS_SUPPORT_TEMPORAL_AQ)) {
+        rcParams->enableAQ = 0;
+        rcParams->enableTemporalAQ = 1;
+      } else {
+        LOG_WARN("temporal aq not supported, ignored");
+      }
+      break;
+    default:
+      break;
+    }
+    if (options_.aqStrength > 0) {
+      if (rcParams->enableAQ) {
+        rcParams->aqStrength = std::min(options_.aqStrength, 15);
+      } else {
+        LOG_WARN("aq strength only applies to spatial aq, ignored");
+      }
+    }
+  }
+
+  // the emphasis levels become delta qps on top of the rate control, which
+  // nvenc doesn't combine with spatial or temporal aq
+  bool setup_emphasis(NV_ENC_CONFIG *encodeConfig, GUID guidCodec) {
+    if (dataFormat_ != H264 ||
+        !pEnc_->GetCapabilityValue(guidCodec,
+                                   NV_ENC_CAPS_SUPPORT_EMPHASIS_LEVEL_MAP)) {
+      LOG_ERROR(std::string("emphasis map not supported"));
+      return false;
+    }
+    NV_ENC_RC_PARAMS *rcParams = &encodeConfig->rcParams;
+    if (rcParams->enableAQ || rcParams->enableTemporalAQ)
+      LOG_WARN("aq is turned off for the emphasis map");
+    rcParams->enableAQ = 0;
+    rcParams->enableTemporalAQ = 0;
+    rcParams->qpMapMode = NV_ENC_QP_MAP_EMPHASIS;
+    return true;
+  }
+
+  // Tune::CloudGaming, the refresh replaces the gop's keyframes
+  // constant qp 0 with the transform bypassed, which h264 has in the High
+  // 4:4:4 Predictive profile only. The lossless tuning bypasses it for hevc.
+  bool setup_lossless(NV_ENC_CONFIG *encodeConfig, GUID guidCodec) {
+    if (!pEnc_->GetCapabilityValue(guidCodec,
+                                   NV_ENC_CAPS_SUPPORT_LOSSLESS_ENCODE)) {
+      LOG_ERROR(std::string("lossless encoding not supported"));
+      return false;
+    }
+    NV_ENC_RC_PARAMS *rcParams = &encodeConfig->rcParams;
+    rcParams->rateControlMode = NV_ENC_PARAMS_RC_CONSTQP;
+    rcParams->constQP.qpInterP = 0;
+    rcParams->constQP.qpInterB = 0;
+    rcParams->constQP.qpIntra = 0;
+    rcParams->averageBitRate = 0;
+    rcParams->maxBitRate = 0;
+    rcParams->enableAQ = 0;
+    rcParams->enableTemporalAQ = 0;
+    if (dataFormat_ == H264) {
+      NV_ENC_CONFIG_H264 *h264 = &encodeConfig->encodeCodecConfig.h264Config;
+      h264->qpPrimeYZeroTransformBypassFlag = 1;
+      encodeConfig->profileGUID = NV_ENC_H264_PROFILE_HIGH_444_GUID;
+    }
+    return true;
+  }
+
+  void setup_cloud_gaming(NV_ENC_INITIALIZE_PARAMS *params, GUID guidCodec) {
+    NV_ENC_CONFIG *encodeConfig = params->encodeConfig;
+    int32_t period = (gop_ > 0 && gop_ < MAX_GOP) ? gop_ : framerate_;
+    encodeConfig->gopLength = NVENC_INFINITE_GOPLENGTH;
+    encodeConfig->frameIntervalP = 1;
+    encodeConfig->rcParams.lookaheadDepth = 0;
+    set_one_frame_vbv(params);
+    bool refresh = pEnc_->GetCapabilityValue(
+        guidCodec, NV_ENC_CAPS_SUPPORT_INTRA_REFRESH);
+    if (!refresh)
+      LOG_WARN("intra refresh not supported, running without it");
+    // the refresh takes half a second and has to end before the next one
+    uint32_t refreshCnt =
+        std::max(std::min(framerate_ / 2, period - 1), (int32_t)1);
+    uint32_t refreshPeriod = std::max(period, (int32_t)refreshCnt + 1);
+    NV_ENC_CODEC_CONFIG *codec = &encodeConfig->encodeCodecConfig;
+    if (dataFormat_ == H264) {
+      NV_ENC_CONFIG_H264 *h264 = &codec->h264Config;
+      h264->idrPeriod = NVENC_INFINITE_GOPLENGTH;
+      h264->sliceModeData = 4;
+      h264->enableIntraRefresh = refresh;
+      h264->intraRefreshPeriod = refresh ? refreshPeriod : 0;
+      h264->intraRefreshCnt = refresh ? refreshCnt : 0;
+      h264->outputRecoveryPointSEI = refresh;
+    } else {
+      NV_ENC_CONFIG_HEVC *hevc = &codec->hevcConfig;
+      hevc->idrPeriod = NVENC_INFINITE_GOPLENGTH;
+      hevc->sliceModeData = 4;
+      hevc->enableIntraRefresh = refresh;
+      hevc->intraRefreshPeriod = refresh ? refreshPeriod : 0;
+      hevc->intraRefreshCnt = refresh ? refreshCnt : 0;
+      hevc->outputRecoveryPointSEI = refresh;
+    }
+  }
+
+  // a frame can't take more than its share of the bitrate, no frame waits
+  // behind a large one
+  static void set_one_frame_vbv(NV_ENC_INITIALIZE_PARAMS *params) {
+    NV_ENC_RC_PARAMS *rcParams = &params->encodeConfig->rcParams;
+    uint32_t num = std::max(params->frameRateNum, (uint32_t)1);
+    uint32_t den = std::max(params->frameRateDen, (uint32_t)1);
+    rcParams->vbvBufferSize =
+        (uint32_t)((uint64_t)rcParams->averageBitRate * den / num);
+    rcParams->vbvInitialDelay = rcParams->vbvBufferSize;
+  }
+
   void setup_h264(NV_ENC_CONFIG *encodeConfig) {
     NV_ENC_CODEC_CONFIG *encodeCodecConfig = &encodeConfig->encodeCodecConfig;
     NV_ENC_CONFIG_H264 *h264 = &encodeCodecConfig->h264Config;
@@ -222,6 +621,9 @@ public:
         bt709_ ? NV_ENC_VUI_TRANSFER_CHARACTERISTIC_BT709 : NV_ENC_VUI_TRANSFER_CHARACTERISTIC_SMPTE170M;
     vui->colourDescriptionPresentFlag = 1;
     vui->videoSignalTypePresentFlag = 1;
+    vui->chromaSampleLocationFlag = 1;
+    vui->chromaSampleLocationTop = options_.chromaLocation;
+    vui->chromaSampleLocationBot = options_.chromaLocation;
 
     h264->sliceMode = 3;
     h264->sliceModeData = 1;
@@ -230,6 +632,9 @@ public:
     // yuv444 input
     h264->chromaFormatIDC = 1;
     h264->level = NV_ENC_LEVEL_AUTOSELECT;
+    h264->entropyCodingMode = options_.entropyCoding == ENTROPY_CODING_CAVLC
+                                  ? NV_ENC_H264_ENTROPY_CODING_MODE_CAVLC
+                                  : NV_ENC_H264_ENTROPY_CODING_MODE_CABAC;
 
     encodeConfig->profileGUID = NV_ENC_H264_PROFILE_MAIN_GUID;
   }
@@ -245,6 +650,9 @@ public:
         bt709_ ? NV_ENC_VUI_TRANSFER_CHARACTERISTIC_BT709 : NV_ENC_VUI_TRANSFER_CHARACTERISTIC_SMPTE170M;
     vui->colourDescriptionPresentFlag = 1;
     vui->videoSignalTypePresentFlag = 1;
+    vui->chromaSampleLocationFlag = 1;
+    vui->chromaSampleLocationTop = options_.chromaLocation;
+    vui->chromaSampleLocationBot = options_.chromaLocation;
 
     hevc->sliceMode = 3;
     hevc->sliceModeData = 1;
@@ -259,6 +667,56 @@ public:
     encodeConfig->profileGUID = NV_ENC_HEVC_PROFILE_MAIN_GUID;
   }
 
+  // the frames still reference the one before them only, the others are kept
+  // for when it is invalidated
+  void setup_references(NV_ENC_CONFIG *encodeConfig) {
+    NV_ENC_CODEC_CONFIG *codec = &encodeConfig->encodeCodecConfig;
+    if (dataFormat_ == H264)
+      codec->h264Config.maxNumRefFrames = NV_INVALIDATION_REFS;
+    else
+      codec->hevcConfig.maxNumRefFramesInDPB = NV_INVALIDATION_REFS;
+  }
+
+  void add_reference(NV_ENC_PIC_TYPE pictureType, int64_t ms) {
+    if (pictureType == NV_ENC_PIC_TYPE_NONREF_P)
+      return;
+    if (pictureType == NV_ENC_PIC_TYPE_IDR)
+      references_.clear();
+    references_.push_back({ms, true});
+    while (references_.size() > NV_INVALIDATION_REFS)
+      references_.pop_front();
+  }
+
+  // Drops the latest frame of ms and those after it from the references, the
+  // next frame references the one before it. Fails when the frame isn't among
+  // them or none before it is left.
+  int invalidate(int64_t ms) {
+    auto it = std::find_if(references_.rbegin(), references_.rend(),
+                           [&](const NvReference &r) { return r.ms == ms; });
+    if (it == references_.rend())
+      return HWCODEC_ERR_COMMON;
+    // reported again, e.g. by a nack and a pli
+    if (!it->valid)
+      return HWCODEC_SUCCESS;
+    auto first = it.base() - 1;
+    if (std::none_of(references_.begin(), first,
+                     [](const NvReference &r) { return r.valid; }))
+      return HWCODEC_ERR_COMMON;
+    for (auto r = first; r != references_.end(); r++) {
+      if (!r->valid)
+        continue;
+      NVENCSTATUS status = pEnc_->InvalidateRefFrame((uint64_t)r->ms);
+      if (status != NV_ENC_SUCCESS) {
+        LOG_ERROR(std::string("invalidate reference failed: ") +
+                  std::to_string(status));
+        return is_session_lost(status) ? HWCODEC_ERR_SESSION_LOST
+                                       : HWCODEC_ERR_COMMON;
+      }
+      r->valid = false;
+    }
+    return HWCODEC_SUCCESS;
+  }
+
 private:
 #ifdef CONFIG_NV_OPTIMUS_FOR_DEV
   int copy_texture(void *src, void *dst) {
@@ -308,14 +766,31 @@ private:
 
 extern "C" {
 
-int nv_encode_driver_support() {
+int nv_encode_driver_support(DriverProbe *probe) {
+  *probe = {};
+  snprintf(probe->name, sizeof(probe->name), "NVIDIA driver");
+  snprintf(probe->required, sizeof(probe->required), "%s", required_driver());
+  adapter_driver_version(ADAPTER_VENDOR_NVIDIA, probe->installed,
+                         sizeof(probe->installed));
+  CudaFunctions *cuda_dl = NULL;
+  NvencFunctions *nvenc_dl = NULL;
   try {
-    CudaFunctions *cuda_dl = NULL;
-    NvencFunctions *nvenc_dl = NULL;
     load_driver(&cuda_dl, &nvenc_dl);
+    // (major << 4) | minor
+    uint32_t version = 0;
+    NVENCSTATUS status = nvenc_dl->NvEncodeAPIGetMaxSupportedVersion(&version);
     free_driver(&cuda_dl, &nvenc_dl);
+    if (status != NV_ENC_SUCCESS)
+      return -1;
+    if (version < ((NVENCAPI_MAJOR_VERSION << 4) | NVENCAPI_MINOR_VERSION)) {
+      LOG_TRACE(std::string("nvenc api ") + std::to_string(version >> 4) +
+                "." + std::to_string(version & 0xf) + " too old");
+      probe->tooOld = 1;
+      return -1;
+    }
     return 0;
   } catch (const std::exception &e) {
+    free_driver(&cuda_dl, &nvenc_dl);
     LOG_TRACE(std::string("driver not support, ") + e.what());
   }
   return -1;
@@ -338,14 +813,18 @@ int nv_destroy_encoder(void *encoder) {
 
 void *nv_new_encoder(void *handle, int64_t luid, DataFormat dataFormat,
                      int32_t width, int32_t height, int32_t kbs,
-                     int32_t framerate, int32_t gop) {
+                     int32_t framerate, int32_t gop,
+                     const EncodeOptions *options) {
   NvencEncoder *e = NULL;
+  int64_t usage = video_memory_usage((ID3D11Device *)handle);
   try {
     e = new NvencEncoder(handle, luid, dataFormat, width, height, kbs,
-                         framerate, gop);
+                         framerate, gop, options);
     if (!e->init()) {
       goto _exit;
     }
+    usage = video_memory_usage((ID3D11Device *)handle) - usage;
+    e->allocated_ = usage > 0 ? usage : 0;
     return e;
   } catch (const std::exception &ex) {
     LOG_ERROR(std::string("new failed: ") + ex.what());
@@ -362,13 +841,30 @@ _exit:
 }
 
 int nv_encode(void *encoder, void *texture, EncodeCallback callback, void *obj,
-              int64_t ms) {
+              int64_t ms, uint64_t user_data) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
   try {
-    NvencEncoder *e = (NvencEncoder *)encoder;
-    return e->encode(texture, callback, obj, ms);
-  } catch (const std::exception &e) {
-    LOG_ERROR(std::string("encode failed: ") + e.what());
+    if (e->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+#ifndef CONFIG_NV_OPTIMUS_FOR_DEV
+    int input = check_input_texture(e->native_->device_.Get(),
+                                    (ID3D11Texture2D *)texture);
+    if (input != 0)
+      return input;
+#endif
+    int ret = e->encode(texture, callback, obj, ms, user_data);
+    if (ret != 0 && e->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    return ret;
+  } catch (const NVENCException &ex) {
+    LOG_ERROR(std::string("encode failed: ") + ex.what());
+    if (is_session_lost(ex.getErrorCode()))
+      return HWCODEC_ERR_SESSION_LOST;
+  } catch (const std::exception &ex) {
+    LOG_ERROR(std::string("encode failed: ") + ex.what());
   }
+  if (e->native_->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
   return -1;
 }
 
@@ -389,10 +885,12 @@ int nv_encode(void *encoder, void *texture, EncodeCallback callback, void *obj,
     return 0;                                                                  \
   }
 
-int nv_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
+int nv_test_encode(int64_t *outLuids, int32_t *outVendors,
+                   EncodeCaps *outCaps, int32_t maxDescNum, int32_t *outDescNum,
                    DataFormat dataFormat, int32_t width,
                    int32_t height, int32_t kbs, int32_t framerate,
-                   int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount) {
+                   int32_t gop, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount,
+                   EncodeCallback callback, void *obj) {
   try {
     Adapters adapters;
     if (!adapters.Init(ADAPTER_VENDOR_NVIDIA))
@@ -406,19 +904,20 @@ int nv_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, i
 
       NvencEncoder *e = (NvencEncoder *)nv_new_encoder(
           (void *)adapter.get()->device_.Get(), currentLuid,
-          dataFormat, width, height, kbs, framerate, gop);
+          dataFormat, width, height, kbs, framerate, gop, nullptr);
       if (!e)
         continue;
       if (e->native_->EnsureTexture(e->width_, e->height_)) {
         e->native_->next();
-        int32_t key_obj = 0;
+        util_encode::TestOutput out = {0, callback, obj, currentLuid};
         auto start = util::now();
-        bool succ = nv_encode(e, e->native_->GetCurrentTexture(), util_encode::vram_encode_test_callback, &key_obj,
-                      0) == 0 && key_obj == 1;
+        bool succ = nv_encode(e, e->native_->GetCurrentTexture(), util_encode::vram_encode_test_callback, &out,
+                      0, 0) == 0 && out.key == 1;
         int64_t elapsed = util::elapsed_ms(start);
         if (succ && elapsed < TEST_TIMEOUT_MS) {
           outLuids[count] = currentLuid;
           outVendors[count] = VENDOR_NV;
+          e->caps(&outCaps[count]);
           count += 1;
         }
       }
@@ -437,11 +936,159 @@ int nv_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, i
   return -1;
 }
 
+int nv_encoder_memory(void *encoder, MemoryInfo *info) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
+  estimate_encoder_memory(e->dataFormat_, e->width_, e->height_,
+                          NV_INPUT_BGRA_SURFACES, 0, info);
+  info->allocated = e->allocated_;
+  return 0;
+}
+
+int nv_encoder_info(void *encoder, RuntimeInfo *info) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
+  *info = {};
+  snprintf(info->runtime, sizeof(info->runtime), "NVENC");
+  uint32_t version = 0;
+  if (e->nvenc_dl_ &&
+      e->nvenc_dl_->NvEncodeAPIGetMaxSupportedVersion(&version) ==
+          NV_ENC_SUCCESS) {
+    info->apiMajor = version >> 4;
+    info->apiMinor = version & 0xf;
+  }
+  if (e->encodeConfig_.frameIntervalP > 1)
+    info->bframes = e->encodeConfig_.frameIntervalP - 1;
+  return 0;
+}
+
+int nv_encoder_gpu_timings(void *encoder, GpuTiming *timings, int32_t max,
+                           int32_t *count) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
+  *count = 0;
+  while (*count < max && e->timer_.Collect(&timings[*count]))
+    (*count)++;
+  return 0;
+}
+
+// no b-frames or lookahead, every frame came back with its encode call
+int nv_encoder_flush(void *encoder, EncodeCallback callback, void *obj) {
+  return 0;
+}
+
+int nv_estimate_encoder_memory(int32_t dataFormat, int32_t width,
+                               int32_t height, MemoryInfo *info) {
+  estimate_encoder_memory((DataFormat)dataFormat, width, height,
+                          NV_INPUT_BGRA_SURFACES, 0, info);
+  info->allocated = 0;
+  return 0;
+}
+
+// Opens an encode session for its caps without initializing it, nothing is
+// allocated on the gpu. 0 when the configuration is within them.
+int nv_check_encoder_config(void *handle, int64_t luid, DataFormat dataFormat,
+                            int32_t width, int32_t height, int32_t kbs,
+                            int32_t framerate, int32_t gop,
+                            const EncodeOptions *options, ConfigCheck *check) {
+  *check = {};
+  NvencEncoder *e = NULL;
+  int ret = HWCODEC_ERR_COMMON;
+  try {
+    e = new NvencEncoder(handle, luid, dataFormat, width, height, kbs,
+                         framerate, gop, options);
+    GUID guidCodec;
+    if (e->open(&guidCodec)) {
+      ret = e->check_config(guidCodec, check) ? HWCODEC_SUCCESS
+                                              : HWCODEC_ERR_COMMON;
+    } else if (dataFormat != H264 && dataFormat != H265) {
+      check->param = CONFIG_PARAM_FORMAT;
+    }
+  } catch (const std::exception &ex) {
+    LOG_ERROR(std::string("check config failed: ") + ex.what());
+  }
+  if (e) {
+    e->destroy();
+    delete e;
+  }
+  return ret;
+}
+
+// queries the sequence parameters, a cheap round trip through the driver
+int nv_check_encoder(void *encoder) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
+  try {
+    if (e->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    if (!e->pEnc_)
+      return HWCODEC_ERR_SESSION_LOST;
+    std::vector<uint8_t> seqParams;
+    e->pEnc_->GetSequenceParams(seqParams);
+    return seqParams.empty() ? HWCODEC_ERR_SESSION_LOST : HWCODEC_SUCCESS;
+  } catch (const NVENCException &ex) {
+    LOG_ERROR(std::string("check encoder failed: ") + ex.what());
+    if (is_session_lost(ex.getErrorCode()))
+      return HWCODEC_ERR_SESSION_LOST;
+  } catch (const std::exception &ex) {
+    LOG_ERROR(std::string("check encoder failed: ") + ex.what());
+  }
+  if (e->native_->IsDeviceLost())
+    return HWCODEC_ERR_DEVICE_LOST;
+  return HWCODEC_ERR_COMMON;
+}
+
+// a level of NV_ENC_EMPHASIS_MAP_LEVEL per macroblock in raster order, for the
+// next encoded frame only
+int nv_encoder_set_emphasis_map(void *encoder, const uint8_t *map,
+                                int32_t len) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
+  if (!e->options_.emphasisMap)
+    return HWCODEC_ERR_COMMON;
+  int32_t mbs = ((e->width_ + 15) / 16) * ((e->height_ + 15) / 16);
+  if (!map || len != mbs) {
+    LOG_ERROR(std::string("emphasis map of ") + std::to_string(len) +
+              " macroblocks, expected " + std::to_string(mbs));
+    return HWCODEC_ERR_INVALID_DATA;
+  }
+  for (int32_t i = 0; i < len; i++) {
+    if (map[i] > NV_ENC_EMPHASIS_MAP_LEVEL_5)
+      return HWCODEC_ERR_INVALID_DATA;
+  }
+  e->emphasis_.assign((const int8_t *)map, (const int8_t *)map + len);
+  return 0;
+}
+
+// added to the qp the rate control chose for every block of the next encoded
+// frame, for sessions created with qpDelta
+int nv_encoder_set_qp_delta(void *encoder, int32_t delta) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
+  if (!e->options_.qpDelta || delta < -51 || delta > 51)
+    return HWCODEC_ERR_COMMON;
+  e->qp_delta_ = delta;
+  return 0;
+}
+
+// the next encoded frame is an IDR with the parameter sets
+int nv_request_keyframe(void *encoder) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
+  e->force_idr_ = true;
+  return 0;
+}
+
+// For sessions created with refInvalidation: the latest frame encoded with ms
+// and those after it are no references anymore, the next frame references an
+// earlier one instead of being an IDR.
+int nv_encoder_invalidate_reference(void *encoder, int64_t ms) {
+  NvencEncoder *e = (NvencEncoder *)encoder;
+  if (!e->options_.refInvalidation)
+    return HWCODEC_ERR_COMMON;
+  return e->invalidate(ms);
+}
+
 int nv_set_bitrate(void *e, int32_t kbs) {
   try {
     RECONFIGURE_HEAD
     params.reInitEncodeParams.encodeConfig->rcParams.averageBitRate =
         kbs * 1000;
+    if (enc->options_.cloudGaming)
+      NvencEncoder::set_one_frame_vbv(&params.reInitEncodeParams);
     RECONFIGURE_TAIL
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("set bitrate to ") + std::to_string(kbs) +
@@ -455,6 +1102,8 @@ int nv_set_framerate(void *e, int32_t framerate) {
     RECONFIGURE_HEAD
     params.reInitEncodeParams.frameRateNum = framerate;
     params.reInitEncodeParams.frameRateDen = 1;
+    if (enc->options_.cloudGaming)
+      NvencEncoder::set_one_frame_vbv(&params.reInitEncodeParams);
     RECONFIGURE_TAIL
   } catch (const std::exception &e) {
     LOG_ERROR(std::string("set framerate failed: ") + e.what());
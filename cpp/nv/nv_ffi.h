@@ -4,19 +4,36 @@
 #include "../common/callback.h"
 #include <stdbool.h>
 
-int nv_encode_driver_support();
+struct EncodeOptions;
+struct MemoryInfo;
+struct EncodeCaps;
+struct DecodeCaps;
+struct RuntimeInfo;
+struct GpuTiming;
+struct ConfigCheck;
+struct DriverProbe;
 
-int nv_decode_driver_support();
+int nv_encode_driver_support(struct DriverProbe *probe);
+
+int nv_decode_driver_support(struct DriverProbe *probe);
 
 void *nv_new_encoder(void *handle, int64_t luid,
                      int32_t dataFormat, int32_t width, int32_t height,
-                     int32_t bitrate, int32_t framerate, int32_t gop);
+                     int32_t bitrate, int32_t framerate, int32_t gop,
+                     const struct EncodeOptions *options);
 
 int nv_encode(void *encoder, void *tex, EncodeCallback callback, void *obj,
-              int64_t ms);
+              int64_t ms, uint64_t user_data);
 
 int nv_destroy_encoder(void *encoder);
 
+void *nv_new_jpeg_encoder(void *device, int64_t luid, int32_t quality);
+
+int nv_jpeg_encode(void *encoder, void *tex, int32_t width, int32_t height,
+                   EncodeCallback callback, void *obj);
+
+int nv_destroy_jpeg_encoder(void *encoder);
+
 void *nv_new_decoder(void *device, int64_t luid, int32_t codecID);
 
 int nv_decode(void *decoder, uint8_t *data, int len, DecodeCallback callback,
@@ -24,15 +41,52 @@ int nv_decode(void *decoder, uint8_t *data, int len, DecodeCallback callback,
 
 int nv_destroy_decoder(void *decoder);
 
-int nv_test_encode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
+int nv_decoder_memory(void *decoder, struct MemoryInfo *info);
+
+int nv_decoder_set_conceal(void *decoder, int32_t conceal);
+
+int nv_decoder_caps(void *decoder, struct DecodeCaps *caps);
+
+int nv_test_encode(int64_t *outLuids, int32_t *outVendors,
+                   struct EncodeCaps *outCaps, int32_t maxDescNum, int32_t *outDescNum,
                    int32_t dataFormat, int32_t width,
                    int32_t height, int32_t kbs, int32_t framerate, int32_t gop,
-                   const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount);
+                   const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount,
+                   EncodeCallback callback, void *obj);
 
 int nv_test_decode(int64_t *outLuids, int32_t *outVendors, int32_t maxDescNum, int32_t *outDescNum,
                    int32_t dataFormat, uint8_t *data,
                    int32_t length, const int64_t *excludedLuids, const int32_t *excludeFormats, int32_t excludeCount);
 
+int nv_check_encoder(void *encoder);
+
+int nv_encoder_memory(void *encoder, struct MemoryInfo *info);
+
+int nv_encoder_info(void *encoder, struct RuntimeInfo *info);
+
+int nv_encoder_gpu_timings(void *encoder, struct GpuTiming *timings,
+                           int32_t max, int32_t *count);
+
+int nv_encoder_flush(void *encoder, EncodeCallback callback, void *obj);
+
+int nv_encoder_set_emphasis_map(void *encoder, const uint8_t *map,
+                               int32_t len);
+
+int nv_encoder_set_qp_delta(void *encoder, int32_t delta);
+
+int nv_encoder_invalidate_reference(void *encoder, int64_t ms);
+
+int nv_estimate_encoder_memory(int32_t dataFormat, int32_t width,
+                               int32_t height, struct MemoryInfo *info);
+
+int nv_check_encoder_config(void *handle, int64_t luid, int32_t dataFormat,
+                            int32_t width, int32_t height, int32_t kbs,
+                            int32_t framerate, int32_t gop,
+                            const struct EncodeOptions *options,
+                            struct ConfigCheck *check);
+
+int nv_request_keyframe(void *encoder);
+
 int nv_set_bitrate(void *encoder, int32_t kbs);
 
 int nv_set_framerate(void *encoder, int32_t framerate);
@@ -0,0 +1,320 @@
+#define FFNV_LOG_FUNC
+#define FFNV_DEBUG_LOG_FUNC
+#include "nv_dynlink.h"
+
+#include <dynlink_cuda.h>
+#include <dynlink_loader.h>
+#include <memory>
+#include <vector>
+
+#include <d3d11.h>
+#include <wrl/client.h>
+
+using Microsoft::WRL::ComPtr;
+
+#include "callback.h"
+#include "common.h"
+#include "system.h"
+#include "util.h"
+
+#define LOG_MODULE "NVJPEG"
+#include "log.h"
+
+namespace {
+
+#define succ(call) ((call) == 0)
+
+// nvjpeg.h of the CUDA toolkit, the dll is loaded at runtime so the toolkit
+// isn't needed to build
+typedef struct nvjpegHandle *nvjpegHandle_t;
+typedef struct nvjpegEncoderState *nvjpegEncoderState_t;
+typedef struct nvjpegEncoderParams *nvjpegEncoderParams_t;
+typedef int nvjpegStatus_t;
+typedef struct {
+  unsigned char *channel[4];
+  size_t pitch[4];
+} nvjpegImage_t;
+#define NVJPEG_CSS_420 2
+#define NVJPEG_INPUT_BGRI 6
+
+struct NvjpegFunctions {
+  HMODULE lib = NULL;
+  nvjpegStatus_t (*create_simple)(nvjpegHandle_t *);
+  nvjpegStatus_t (*destroy)(nvjpegHandle_t);
+  nvjpegStatus_t (*state_create)(nvjpegHandle_t, nvjpegEncoderState_t *,
+                                 CUstream);
+  nvjpegStatus_t (*state_destroy)(nvjpegEncoderState_t);
+  nvjpegStatus_t (*params_create)(nvjpegHandle_t, nvjpegEncoderParams_t *,
+                                  CUstream);
+  nvjpegStatus_t (*params_destroy)(nvjpegEncoderParams_t);
+  nvjpegStatus_t (*set_quality)(nvjpegEncoderParams_t, int, CUstream);
+  nvjpegStatus_t (*set_sampling)(nvjpegEncoderParams_t, int, CUstream);
+  nvjpegStatus_t (*encode_image)(nvjpegHandle_t, nvjpegEncoderState_t,
+                                 nvjpegEncoderParams_t, const nvjpegImage_t *,
+                                 int, int, int, CUstream);
+  nvjpegStatus_t (*retrieve)(nvjpegHandle_t, nvjpegEncoderState_t,
+                             unsigned char *, size_t *, CUstream);
+
+  // the dll ships with the CUDA runtime, not the driver
+  bool load() {
+    for (auto name : {"nvjpeg64_12.dll", "nvjpeg64_11.dll"}) {
+      if (module_hidden(name))
+        continue;
+      lib = (HMODULE)vendor_module_load(name);
+      if (lib)
+        break;
+    }
+    if (!lib) {
+      LOG_TRACE(std::string("no nvjpeg dll"));
+      return false;
+    }
+#define LOAD(field, name)                                                      \
+  field = (decltype(field))GetProcAddress(lib, name);                          \
+  if (!field) {                                                                \
+    LOG_ERROR(std::string("nvjpeg has no ") + name);                           \
+    return false;                                                              \
+  }
+    LOAD(create_simple, "nvjpegCreateSimple");
+    LOAD(destroy, "nvjpegDestroy");
+    LOAD(state_create, "nvjpegEncoderStateCreate");
+    LOAD(state_destroy, "nvjpegEncoderStateDestroy");
+    LOAD(params_create, "nvjpegEncoderParamsCreate");
+    LOAD(params_destroy, "nvjpegEncoderParamsDestroy");
+    LOAD(set_quality, "nvjpegEncoderParamsSetQuality");
+    LOAD(set_sampling, "nvjpegEncoderParamsSetSamplingFactors");
+    LOAD(encode_image, "nvjpegEncodeImage");
+    LOAD(retrieve, "nvjpegEncodeRetrieveBitstream");
+#undef LOAD
+    return true;
+  }
+
+  ~NvjpegFunctions() {
+    if (lib)
+      vendor_module_free(lib);
+  }
+};
+
+// The bgra texture is read back and uploaded as interleaved bgr, nvJPEG has
+// no D3D11 interop. Only the encoding runs on the gpu.
+class NvJpegEncoder {
+public:
+  CudaFunctions *cudl_ = NULL;
+  NvjpegFunctions jpeg_;
+  CUcontext cuContext_ = NULL;
+  nvjpegHandle_t handle_ = NULL;
+  nvjpegEncoderState_t state_ = NULL;
+  nvjpegEncoderParams_t params_ = NULL;
+  std::unique_ptr<NativeDevice> native_ = nullptr;
+  CUdeviceptr image_ = 0;
+  size_t imagePitch_ = 0;
+  std::vector<uint8_t> bgra_;
+  std::vector<uint8_t> bgr_;
+  std::vector<uint8_t> bitstream_;
+
+  void *device_;
+  int64_t luid_;
+  int32_t quality_;
+  int width_ = 0;
+  int height_ = 0;
+
+  NvJpegEncoder(void *device, int64_t luid, int32_t quality) {
+    device_ = device;
+    luid_ = luid;
+    quality_ = quality;
+  }
+
+  bool init() {
+    if (cuda_load_functions(&cudl_, NULL) < 0) {
+      LOG_TRACE(std::string("cuda_load_functions failed"));
+      return false;
+    }
+    if (!jpeg_.load())
+      return false;
+    if (!succ(cudl_->cuInit(0))) {
+      LOG_ERROR(std::string("cuInit failed"));
+      return false;
+    }
+    native_ = std::make_unique<NativeDevice>();
+    if (!native_->Init(luid_, (ID3D11Device *)device_)) {
+      LOG_ERROR(std::string("Failed to init native device"));
+      return false;
+    }
+    CUdevice cuDevice = 0;
+    if (!succ(cudl_->cuD3D11GetDevice(&cuDevice, native_->adapter_.Get()))) {
+      LOG_ERROR(std::string("Failed to get cuDevice"));
+      return false;
+    }
+    if (!succ(cudl_->cuCtxCreate(&cuContext_, 0, cuDevice))) {
+      LOG_ERROR(std::string("Failed to create cuContext"));
+      return false;
+    }
+    // cuCtxCreate leaves it current, the runtime nvJPEG is on uses that one
+    bool ok = succ(jpeg_.create_simple(&handle_)) &&
+              succ(jpeg_.state_create(handle_, &state_, NULL)) &&
+              succ(jpeg_.params_create(handle_, &params_, NULL)) &&
+              succ(jpeg_.set_quality(params_, quality_, NULL)) &&
+              succ(jpeg_.set_sampling(params_, NVJPEG_CSS_420, NULL));
+    cudl_->cuCtxPopCurrent(NULL);
+    if (!ok)
+      LOG_ERROR(std::string("Failed to create the nvjpeg encoder"));
+    return ok;
+  }
+
+  int encode(ID3D11Texture2D *tex, int width, int height,
+             EncodeCallback callback, void *obj) {
+    bgra_.resize((size_t)width * height * 4);
+    if (hwcodec_read_d3d11_bgra_texture(tex, bgra_.data(), width * 4,
+                                        height) != 0) {
+      LOG_ERROR(std::string("Failed to read the texture"));
+      return -1;
+    }
+    bgr_.resize((size_t)width * height * 3);
+    for (size_t i = 0; i < (size_t)width * height; i++) {
+      bgr_[i * 3] = bgra_[i * 4];
+      bgr_[i * 3 + 1] = bgra_[i * 4 + 1];
+      bgr_[i * 3 + 2] = bgra_[i * 4 + 2];
+    }
+
+    if (!succ(cudl_->cuCtxPushCurrent(cuContext_))) {
+      LOG_ERROR(std::string("cuCtxPushCurrent failed"));
+      return -1;
+    }
+    int ret = encode_bgr(width, height);
+    cudl_->cuCtxPopCurrent(NULL);
+    if (ret != 0)
+      return ret;
+    if (callback)
+      callback(bitstream_.data(), (int)bitstream_.size(), 1, obj, 0, 0);
+    return 0;
+  }
+
+  void destroy() {
+    if (cuContext_)
+      cudl_->cuCtxPushCurrent(cuContext_);
+    if (params_)
+      jpeg_.params_destroy(params_);
+    if (state_)
+      jpeg_.state_destroy(state_);
+    if (handle_)
+      jpeg_.destroy(handle_);
+    if (image_)
+      cudl_->cuMemFree(image_);
+    params_ = NULL;
+    state_ = NULL;
+    handle_ = NULL;
+    image_ = 0;
+    if (cuContext_) {
+      cudl_->cuCtxPopCurrent(NULL);
+      cudl_->cuCtxDestroy(cuContext_);
+      cuContext_ = NULL;
+    }
+    if (cudl_) {
+      cuda_free_functions(&cudl_);
+      cudl_ = NULL;
+    }
+  }
+
+private:
+  // bgr_ into bitstream_, the context is current
+  int encode_bgr(int width, int height) {
+    if (width != width_ || height != height_) {
+      if (image_)
+        cudl_->cuMemFree(image_);
+      image_ = 0;
+      width_ = height_ = 0;
+      if (!succ(cudl_->cuMemAllocPitch(&image_, &imagePitch_, width * 3,
+                                       height, 4))) {
+        LOG_ERROR(std::string("cuMemAllocPitch failed"));
+        return -1;
+      }
+      width_ = width;
+      height_ = height;
+    }
+    CUDA_MEMCPY2D copy = {};
+    copy.srcMemoryType = CU_MEMORYTYPE_HOST;
+    copy.srcHost = bgr_.data();
+    copy.srcPitch = width * 3;
+    copy.dstMemoryType = CU_MEMORYTYPE_DEVICE;
+    copy.dstDevice = image_;
+    copy.dstPitch = imagePitch_;
+    copy.WidthInBytes = width * 3;
+    copy.Height = height;
+    if (!succ(cudl_->cuMemcpy2D(&copy))) {
+      LOG_ERROR(std::string("cuMemcpy2D failed"));
+      return -1;
+    }
+    nvjpegImage_t image = {};
+    image.channel[0] = (unsigned char *)image_;
+    image.pitch[0] = imagePitch_;
+    if (!succ(jpeg_.encode_image(handle_, state_, params_, &image,
+                                 NVJPEG_INPUT_BGRI, width, height, NULL))) {
+      LOG_ERROR(std::string("nvjpegEncodeImage failed"));
+      return -1;
+    }
+    size_t length = 0;
+    if (!succ(jpeg_.retrieve(handle_, state_, NULL, &length, NULL))) {
+      LOG_ERROR(std::string("nvjpegEncodeRetrieveBitstream failed"));
+      return -1;
+    }
+    bitstream_.resize(length);
+    if (!succ(jpeg_.retrieve(handle_, state_, bitstream_.data(), &length,
+                             NULL))) {
+      LOG_ERROR(std::string("nvjpegEncodeRetrieveBitstream failed"));
+      return -1;
+    }
+    bitstream_.resize(length);
+    return 0;
+  }
+};
+
+} // namespace
+
+extern "C" {
+
+// NULL without a CUDA runtime's nvjpeg dll
+void *nv_new_jpeg_encoder(void *device, int64_t luid, int32_t quality) {
+  NvJpegEncoder *p = NULL;
+  try {
+    p = new NvJpegEncoder(device, luid, quality);
+    if (p->init())
+      return p;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("new failed: ") + e.what());
+  }
+  if (p) {
+    p->destroy();
+    delete p;
+  }
+  return NULL;
+}
+
+int nv_jpeg_encode(void *encoder, void *tex, int32_t width, int32_t height,
+                   EncodeCallback callback, void *obj) {
+  NvJpegEncoder *p = (NvJpegEncoder *)encoder;
+  try {
+    if (p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    int ret = check_input_texture(p->native_->device_.Get(),
+                                  (ID3D11Texture2D *)tex);
+    if (ret != 0)
+      return ret;
+    ret = p->encode((ID3D11Texture2D *)tex, width, height, callback, obj);
+    if (ret != 0 && p->native_->IsDeviceLost())
+      return HWCODEC_ERR_DEVICE_LOST;
+    return ret;
+  } catch (const std::exception &e) {
+    LOG_ERROR(std::string("encode failed: ") + e.what());
+  }
+  return HWCODEC_ERR_COMMON;
+}
+
+int nv_destroy_jpeg_encoder(void *encoder) {
+  NvJpegEncoder *p = (NvJpegEncoder *)encoder;
+  if (p) {
+    p->destroy();
+    delete p;
+  }
+  return 0;
+}
+
+} // extern "C"
@@ -0,0 +1,62 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::{
+    common::{DataFormat, MAX_GOP},
+    vram::{
+        decode,
+        encode::best_encoder,
+        worker::{AsyncDecoder, AsyncEncoder},
+        DynamicContext, EncodeContext,
+    },
+};
+use std::time::Instant;
+use tool::Tool;
+
+const FRAMES: usize = 60;
+
+#[tokio::main]
+async fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let mut d = DynamicContext {
+        device: None,
+        width: 1920,
+        height: 1080,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    let f = best_encoder(d, &[DataFormat::H264, DataFormat::H265]).unwrap();
+    let mut dec_ctx = decode::available()
+        .drain(..)
+        .find(|c| c.luid == f.luid && c.data_format == f.data_format)
+        .unwrap();
+    let mut tool = Tool::new(f.luid).unwrap();
+    d.device = Some(tool.device());
+    dec_ctx.device = Some(tool.device());
+    let texture = tool.get_texture(d.width, d.height);
+
+    let encoder = AsyncEncoder::new(EncodeContext { f, d }, 2).unwrap();
+    let mut decoder = AsyncDecoder::new(dec_ctx).unwrap();
+    let start = Instant::now();
+    let mut decoded = 0;
+    for i in 0..FRAMES {
+        if i == FRAMES / 2 {
+            encoder.set_bitrate(d.kbitrate / 2).await.unwrap();
+            encoder.request_keyframe().await.unwrap();
+        }
+        // two frames at once, the second waits in the worker's queue
+        let (a, b) = tokio::join!(
+            encoder.encode(texture, (2 * i) as _),
+            encoder.encode(texture, (2 * i + 1) as _)
+        );
+        for frame in a.unwrap().into_iter().chain(b.unwrap()) {
+            decoded += decoder.decode(frame.data).await.unwrap().len();
+        }
+    }
+    log::info!(
+        "encoded {} frames, decoded {}, elapsed: {:?}",
+        2 * FRAMES,
+        decoded,
+        start.elapsed()
+    );
+}
@@ -0,0 +1,68 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::{
+    common::MAX_GOP,
+    vram::{
+        encode::{available, available_async},
+        DynamicContext,
+    },
+};
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake},
+    thread::Thread,
+    time::Instant,
+};
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// the future must not depend on a particular runtime
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let d = DynamicContext {
+        device: None,
+        width: 1920,
+        height: 1080,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    let sync = available(d);
+    log::info!("available: {:?}, elapsed: {:?}", sync, start.elapsed());
+
+    let start = Instant::now();
+    let future = available_async(d);
+    log::info!("available_async returned after {:?}", start.elapsed());
+    let result = block_on(future);
+    log::info!(
+        "available_async: {:?}, elapsed: {:?}",
+        result,
+        start.elapsed()
+    );
+    log::info!("same as available: {}", sync == result);
+
+    // dropped before completion, the remaining tests are skipped
+    drop(available_async(d));
+}
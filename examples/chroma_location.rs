@@ -0,0 +1,52 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::{
+    bitstream::{h264, hevc},
+    common::{ChromaLocation, DataFormat, MAX_GOP},
+    vram::{
+        encode::{available, Encoder},
+        DynamicContext, EncodeContext,
+    },
+};
+use tool::Tool;
+
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let d = DynamicContext {
+        device: None,
+        width: 1280,
+        height: 720,
+        kbitrate: 2000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    for f in available(d) {
+        let mut tool = Tool::new(f.luid).unwrap();
+        for location in [
+            ChromaLocation::CHROMA_LOC_LEFT,
+            ChromaLocation::CHROMA_LOC_CENTER,
+            ChromaLocation::CHROMA_LOC_TOP_LEFT,
+        ] {
+            let mut d = d;
+            d.device = Some(tool.device());
+            d.chroma_location = location;
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            let texture = tool.get_texture(d.width, d.height);
+            let data = encoder.encode(texture, 0).unwrap()[0].data.clone();
+            let vui = match f.data_format {
+                DataFormat::H264 => h264::find_sps(&data).and_then(|sps| sps.vui),
+                DataFormat::H265 => hevc::parameter_sets(&data).sps.and_then(|sps| sps.vui),
+                _ => continue,
+            };
+            // absent chroma_loc_info means left, amf can't signal the others
+            let signaled = vui.and_then(|vui| vui.chroma_loc).unwrap_or((0, 0));
+            log::info!(
+                "{:?} {:?} {:?}: {:?}",
+                f.driver,
+                f.data_format,
+                location,
+                signaled
+            );
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::{
+    common::{DataFormat, Driver, MAX_GOP},
+    vram::{
+        backend::{register_encode_driver, EncodeBackend, EncodeDriver},
+        encode::{self, EncodeFrame, Encoder},
+        DynamicContext, EncodeContext,
+    },
+};
+use std::{ffi::c_void, sync::Arc};
+
+const NAME: &str = "null";
+
+// Emits an empty access unit per frame, a stand-in for an encoder living in another crate.
+struct NullEncoder {
+    frames: Vec<EncodeFrame>,
+    gop: i32,
+    count: i32,
+    keyframe: bool,
+}
+
+impl EncodeBackend for NullEncoder {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        let key = self.keyframe || self.count % self.gop == 0;
+        self.keyframe = false;
+        self.count += 1;
+        self.frames.clear();
+        // the Encoder sets the rest of the frame
+        self.frames.push(EncodeFrame::new(
+            vec![0, 0, 0, 1, if key { 0x65 } else { 0x41 }],
+            ms,
+            key,
+        ));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.keyframe = true;
+        Ok(())
+    }
+}
+
+struct NullDriver;
+
+impl EncodeDriver for NullDriver {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    // no adapter has luid 0, so the built-in drivers never cover it
+    fn test(&self, format: DataFormat, _d: &DynamicContext) -> Vec<(i64, Driver)> {
+        match format {
+            DataFormat::H264 => vec![(0, Driver::CUSTOM(NAME.to_owned()))],
+            _ => vec![],
+        }
+    }
+
+    fn create(&self, ctx: &EncodeContext) -> Result<Box<dyn EncodeBackend>, ()> {
+        Ok(Box::new(NullEncoder {
+            frames: vec![],
+            gop: ctx.d.gop.max(1),
+            count: 0,
+            keyframe: false,
+        }))
+    }
+}
+
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    register_encode_driver(Arc::new(NullDriver));
+    let d = DynamicContext {
+        device: None,
+        width: 1920,
+        height: 1080,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    let encoders = encode::available(d);
+    encoders.iter().for_each(|f| println!("{:?}", f));
+    let f = encoders
+        .into_iter()
+        .find(|f| f.driver == Driver::CUSTOM(NAME.to_owned()))
+        .unwrap();
+    let mut encoder = Encoder::new(EncodeContext { f, d }).unwrap();
+    for i in 0..3 {
+        if i == 2 {
+            encoder.request_keyframe().unwrap();
+        }
+        for frame in encoder.encode(std::ptr::null_mut(), i).unwrap().iter() {
+            println!("{}", frame);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::common::{DataFormat, HwcodecErrno, MAX_GOP};
+use hwcodec::vram::{
+    debug_set_device_lost,
+    encode::{best_encoder, Encoder},
+    DynamicContext, EncodeContext,
+};
+use tool::Tool;
+
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let mut d = DynamicContext {
+        device: None,
+        width: 1280,
+        height: 720,
+        kbitrate: 3000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    let f = best_encoder(d, &[DataFormat::H264, DataFormat::H265]).unwrap();
+    let mut tool = Tool::new(f.luid).unwrap();
+    d.device = Some(tool.device());
+    let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+    let texture = tool.get_texture(d.width, d.height);
+    for i in 0..3 {
+        encoder.encode(texture, i).unwrap();
+    }
+
+    debug_set_device_lost(true);
+    if let Err(err) = encoder.encode(texture, 3) {
+        log::info!(
+            "encode failed with {}, codec lost: {}, healthy: {}",
+            err,
+            HwcodecErrno::is_codec_lost(err),
+            encoder.is_healthy()
+        );
+    }
+    debug_set_device_lost(false);
+
+    drop(tool);
+    let mut tool = Tool::new(f.luid).unwrap();
+    encoder.recreate_after_device_lost(tool.device()).unwrap();
+    let texture = tool.get_texture(d.width, d.height);
+    let frames = encoder.encode(texture, 4).unwrap();
+    log::info!(
+        "encoder recovered after device lost, keyframe: {}",
+        frames.iter().any(|f| f.key == 1)
+    );
+
+    // a texture of another device, like a capture recreated after a session switch
+    let mut other = Tool::new(f.luid).unwrap();
+    let stale = other.get_texture(d.width, d.height);
+    if let Err(err) = encoder.encode(stale, 5) {
+        log::info!(
+            "foreign input rejected with {}, input lost: {}, healthy: {}",
+            err,
+            HwcodecErrno::is_input_lost(err),
+            encoder.is_healthy()
+        );
+    }
+    encoder.encode(texture, 6).unwrap();
+}
@@ -0,0 +1,69 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::common::{DataFormat, MAX_GOP};
+use hwcodec::vram::{
+    decode::Decoder,
+    encode::{best_encoder, estimate_memory, Encoder},
+    DecodeContext, DynamicContext, EncodeContext, OutputOrder,
+};
+use tool::Tool;
+
+const MB: i64 = 1024 * 1024;
+
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let mut d = DynamicContext {
+        device: None,
+        width: 3840,
+        height: 2160,
+        kbitrate: 20000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    let f = best_encoder(d, &[DataFormat::H265, DataFormat::H264]).unwrap();
+    for (width, height) in [(3840, 2160), (1920, 1080)] {
+        let mut d = d;
+        d.width = width;
+        d.height = height;
+        let info = estimate_memory(&EncodeContext { f: f.clone(), d });
+        log::info!(
+            "{:?} {}x{} estimated: {}MB",
+            f.driver,
+            width,
+            height,
+            info.estimated / MB
+        );
+    }
+
+    let mut tool = Tool::new(f.luid).unwrap();
+    d.device = Some(tool.device());
+    let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+    let info = encoder.memory_usage();
+    log::info!(
+        "encoder estimated: {}MB, allocated: {}MB",
+        info.estimated / MB,
+        info.allocated / MB
+    );
+
+    let mut decoder = Decoder::new(DecodeContext {
+        device: Some(tool.device()),
+        driver: f.driver.clone(),
+        vendor: f.vendor.clone(),
+        luid: f.luid,
+        data_format: f.data_format,
+        output_order: OutputOrder::Decode,
+        conceal_errors: false,
+    })
+    .unwrap();
+    let texture = tool.get_texture(d.width, d.height);
+    let frames = encoder.encode(texture, 0).unwrap();
+    for frame in frames.iter() {
+        decoder.decode(&frame.data).unwrap();
+    }
+    let info = decoder.memory_usage();
+    log::info!(
+        "decoder estimated: {}MB, allocated: {}MB",
+        info.estimated / MB,
+        info.allocated / MB
+    );
+}
@@ -0,0 +1,30 @@
+use hwcodec::bitstream::hevc;
+
+// usage: parameter_sets [file.h265], parses the bundled 720p sample by default
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or(String::from("src/res/720p.h265"));
+    let data = std::fs::read(&path).unwrap();
+    let sets = hevc::parameter_sets(&data);
+    if let Some(vps) = sets.vps {
+        println!("vps: {:?}", vps);
+    }
+    if let Some(sps) = sets.sps {
+        println!(
+            "profile_idc:{}, tier:{}, level:{}, chroma_format_idc:{}, bit_depth:{}/{}, size:{:?}",
+            sps.ptl.general.profile_idc,
+            if sps.ptl.general.tier_flag {
+                "high"
+            } else {
+                "main"
+            },
+            sps.ptl.level(),
+            sps.chroma_format_idc,
+            sps.bit_depth_luma,
+            sps.bit_depth_chroma,
+            sps.cropped_size()
+        );
+        println!("vui: {:?}", sps.vui);
+    }
+}
@@ -3,6 +3,7 @@ use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
 use hwcodec::common::{DataFormat, Driver, MAX_GOP};
 use hwcodec::vram::{
     decode::Decoder, encode::Encoder, DecodeContext, DynamicContext, EncodeContext, FeatureContext,
+    OutputOrder,
 };
 use render::Render;
 use std::{
@@ -26,6 +27,7 @@ fn main() {
                 vendor: Driver::NV,
                 data_format,
                 luid,
+                caps: Default::default(),
             },
             d: DynamicContext {
                 device: Some(capturer.device()),
@@ -34,6 +36,7 @@ fn main() {
                 kbitrate: 5000,
                 framerate: 30,
                 gop: MAX_GOP as _,
+                ..Default::default()
             },
         };
         let de_ctx = DecodeContext {
@@ -42,6 +45,8 @@ fn main() {
             vendor: Driver::NV,
             data_format,
             luid,
+            output_order: OutputOrder::Decode,
+            conceal_errors: false,
         };
 
         let mut dec = Decoder::new(de_ctx).unwrap();
@@ -0,0 +1,30 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::common::{DataFormat, MAX_GOP};
+use hwcodec::vram::{encode::best_encoder, record::Recorder, DynamicContext, EncodeContext};
+use std::path::PathBuf;
+use tool::Tool;
+
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let mut d = DynamicContext {
+        device: None,
+        width: 1280,
+        height: 720,
+        kbitrate: 3000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    let f = best_encoder(d, &[DataFormat::H265, DataFormat::H264]).unwrap();
+    let mut tool = Tool::new(f.luid).unwrap();
+    d.device = Some(tool.device());
+    let filename = PathBuf::from(".\\record.mp4");
+    let mut recorder = Recorder::with_context(&filename, EncodeContext { f, d }).unwrap();
+    let frame_ms = 1000 / d.framerate as i64;
+    for i in 0..300 {
+        let texture = tool.get_texture(d.width, d.height);
+        recorder.write(texture, i * frame_ms).unwrap();
+    }
+    recorder.finish().unwrap();
+    log::info!("recorded to {:?}", filename);
+}
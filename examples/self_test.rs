@@ -0,0 +1,19 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::common::MAX_GOP;
+use hwcodec::vram::{self_test::self_test_all, DynamicContext};
+
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let d = DynamicContext {
+        device: None,
+        width: 1280,
+        height: 720,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    for report in self_test_all(d) {
+        println!("{}", report.serialize().unwrap());
+    }
+}
@@ -0,0 +1,41 @@
+use hwcodec::{bitstream::validate::Validator, common::DataFormat};
+
+// usage: validate [file] [h264|h265], checks the bundled samples and corrupted copies by default
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(path) = args.get(1) {
+        let format = match args.get(2).map(|s| s.as_str()) {
+            Some("h264") => DataFormat::H264,
+            _ => DataFormat::H265,
+        };
+        let data = std::fs::read(path).unwrap();
+        let mut validator = Validator::new(format);
+        println!(
+            "{:?}, size: {:?}",
+            validator.validate(&data),
+            validator.size()
+        );
+        return;
+    }
+    for (path, format) in [
+        ("src/res/720p.h264", DataFormat::H264),
+        ("src/res/720p.h265", DataFormat::H265),
+    ] {
+        let data = std::fs::read(path).unwrap();
+        let mut validator = Validator::new(format);
+        println!(
+            "{:?}: {:?}, size: {:?}",
+            format,
+            validator.validate(&data),
+            validator.size()
+        );
+        // forbidden_zero_bit
+        let mut corrupted = data.clone();
+        corrupted[4] |= 0x80;
+        println!(
+            "{:?} corrupted: {:?}",
+            format,
+            Validator::new(format).validate(&corrupted)
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV};
+use hwcodec::{
+    common::{DataFormat, MAX_GOP},
+    vram::{
+        encode::{best_encoder, Encoder},
+        Available, DynamicContext, EncodeContext, FeatureContext,
+    },
+};
+use std::{process::Command, time::Instant};
+use tool::Tool;
+
+// Shader and kernel caches live in the process, so the cold and warm runs get one each.
+// The encoder is probed here and handed over serialized, available() would warm them too.
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let d = DynamicContext {
+        device: None,
+        width: 1920,
+        height: 1080,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    let args: Vec<String> = std::env::args().collect();
+    if let (Some(mode), Some(available)) = (args.get(1), args.get(2)) {
+        let f = Available::deserialize(available).unwrap().e.remove(0);
+        println!("{}", first_frame_ms(f, d, mode == "warm"));
+        return;
+    }
+    let f = best_encoder(d, &[DataFormat::H264, DataFormat::H265]).unwrap();
+    let available = Available {
+        e: vec![f],
+        d: vec![],
+    }
+    .serialize()
+    .unwrap();
+    let cold = run("cold", &available);
+    let warm = run("warm", &available);
+    log::info!(
+        "first frame cold: {:.2}ms, after warmup: {:.2}ms",
+        cold,
+        warm
+    );
+}
+
+fn run(mode: &str, available: &str) -> f64 {
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args([mode, available])
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+fn first_frame_ms(f: FeatureContext, mut d: DynamicContext, warmup: bool) -> f64 {
+    let mut tool = Tool::new(f.luid).unwrap();
+    d.device = Some(tool.device());
+    let mut encoder = Encoder::new(EncodeContext { f, d }).unwrap();
+    if warmup {
+        encoder.warmup().unwrap();
+    }
+    let texture = tool.get_texture(d.width, d.height);
+    let start = Instant::now();
+    encoder.encode(texture, 0).unwrap();
+    start.elapsed().as_secs_f64() * 1000.0
+}
@@ -0,0 +1,16 @@
+#![no_main]
+
+use hwcodec::bitstream::{annexb_nal_units, h264, hevc, nal_units, rbsp};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for nal in nal_units(data) {
+        let _ = (nal.h264_type(), nal.hevc_type());
+        assert!(rbsp(nal.data).len() <= nal.data.len());
+    }
+    for nal in annexb_nal_units(data) {
+        assert!(!nal.is_empty());
+    }
+    let _ = h264::find_sps(data);
+    let _ = hevc::parameter_sets(data);
+});
@@ -0,0 +1,26 @@
+#![no_main]
+
+use hwcodec::bitstream::{h264, hevc};
+use libfuzzer_sys::fuzz_target;
+
+// single NAL units including their header, the first byte picks the parser
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, nal)) = data.split_first() else {
+        return;
+    };
+    match selector % 3 {
+        0 => {
+            if let Ok(sps) = h264::Sps::parse(nal) {
+                let _ = sps.cropped_size();
+            }
+        }
+        1 => {
+            if let Ok(sps) = hevc::Sps::parse(nal) {
+                let _ = sps.cropped_size();
+            }
+        }
+        _ => {
+            let _ = hevc::Vps::parse(nal);
+        }
+    }
+});
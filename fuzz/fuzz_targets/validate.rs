@@ -0,0 +1,28 @@
+#![no_main]
+
+use hwcodec::{
+    bitstream::validate::{validate_bitstream, Validator},
+    common::DataFormat,
+};
+use libfuzzer_sys::fuzz_target;
+
+// split into packets at 0xff bytes so the state kept between packets is covered too
+fuzz_target!(|data: &[u8]| {
+    for format in [DataFormat::H264, DataFormat::H265] {
+        let mut validator = Validator::new(format);
+        for packet in data.split(|&b| b == 0xff).filter(|p| !p.is_empty()) {
+            if validator.validate(packet).is_ok() {
+                if let Some((width, height)) = validator.size() {
+                    assert!(width >= 16 && height >= 16);
+                }
+            }
+        }
+        let _ = validate_bitstream(data, format);
+    }
+    // JPEG markers start with 0xff, the whole input is one image
+    let mut validator = Validator::new(DataFormat::MJPEG);
+    if validator.validate(data).is_ok() {
+        let (width, height) = validator.size().unwrap();
+        assert!(width >= 16 && height >= 16);
+    }
+});
@@ -0,0 +1,221 @@
+/*
+ * hwcodec C API, Windows only, built with the capi feature.
+ *
+ * ABI: functions and structs are stable within one HWCODEC_CAPI_VERSION, new functions
+ * may be added. Compare hwcodec_capi_version() with HWCODEC_CAPI_VERSION at startup.
+ * Contexts are passed as json so new options don't change the ABI, unknown fields
+ * are ignored and missing optional ones take their defaults.
+ *
+ * Ownership:
+ * - strings returned by hwcodec_available are owned by the caller, free them with
+ *   hwcodec_free_string
+ * - hwcodec_last_error and hwcodec_error_string return library owned strings
+ * - encoders and decoders are freed with hwcodec_encoder_free and hwcodec_decoder_free
+ * - buffers and textures passed to callbacks are owned by the library and only valid
+ *   as documented at the callback types
+ *
+ * An encoder or decoder may be used from any thread, but not from two at once.
+ * Functions returning int32_t return 0 or a negative error code, described by
+ * hwcodec_error_string.
+ */
+
+#ifndef HWCODEC_H
+#define HWCODEC_H
+
+// Generated by cbindgen from src/capi.rs, do not edit.
+
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ * Bumped on every incompatible change of the functions or structs in this header.
+ * Functions are only added within one version.
+ */
+#define HWCODEC_CAPI_VERSION 1
+
+/*
+ * Opaque decoder handle.
+ */
+typedef struct HwcodecDecoder HwcodecDecoder;
+
+/*
+ * Opaque encoder handle.
+ */
+typedef struct HwcodecEncoder HwcodecEncoder;
+
+/*
+ * `data` is only valid during the call, copy it to keep it.
+ */
+typedef void (*HwcodecPacketCallback)(const uint8_t *data,
+                                      size_t len,
+                                      int64_t pts,
+                                      int32_t key,
+                                      void *user_data);
+
+/*
+ * `texture` is an ID3D11Texture2D owned by the decoder, valid until the next
+ * hwcodec_decode or hwcodec_decoder_free on the same decoder.
+ */
+typedef void (*HwcodecFrameCallback)(void *texture, int32_t width, int32_t height, void *user_data);
+
+/*
+ * `data` holds `height` rows of `stride` bytes of bgra, valid only during the call.
+ */
+typedef void (*HwcodecBgraCallback)(const uint8_t *data,
+                                    int32_t stride,
+                                    int32_t width,
+                                    int32_t height,
+                                    void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ * The HWCODEC_CAPI_VERSION the library was built with, compare it to the header's.
+ */
+uint32_t hwcodec_capi_version(void);
+
+/*
+ * Message of the last failed call on the calling thread, or null. The string is owned
+ * by the library and valid until the next failing call on the same thread.
+ */
+const char *hwcodec_last_error(void);
+
+/*
+ * Static description of an HwcodecErrno value, never null and never freed.
+ */
+const char *hwcodec_error_string(int32_t code);
+
+/*
+ * Frees a string returned by this library, null is ignored.
+ *
+ * # Safety
+ * `s` must come from this library and must not be used afterwards.
+ */
+void hwcodec_free_string(char *s);
+
+/*
+ * Probes all encoders and decoders, returns the json of Available, `{"e": [..], "d": [..]}`.
+ * An element of "e" combined with the dynamic fields is the context of
+ * hwcodec_encoder_new, an element of "d" the context of hwcodec_decoder_new.
+ * Free the result with hwcodec_free_string. Null on failure.
+ */
+char *hwcodec_available(int32_t width,
+                        int32_t height,
+                        int32_t kbitrate,
+                        int32_t framerate,
+                        int32_t gop);
+
+/*
+ * `ctx` is the json of EncodeContext, `{"f": <element of "e">, "d": {"width": ..}}`.
+ * `device` is an ID3D11Device the input textures are created on, or null to create one
+ * on the adapter of the context. Free the encoder with hwcodec_encoder_free.
+ *
+ * # Safety
+ * `ctx` must be a nul terminated string, `device` a valid ID3D11Device or null.
+ */
+HwcodecEncoder *hwcodec_encoder_new(const char *ctx, void *device);
+
+/*
+ * # Safety
+ * `encoder` must come from hwcodec_encoder_new and must not be used afterwards.
+ */
+void hwcodec_encoder_free(HwcodecEncoder *encoder);
+
+/*
+ * Encodes an ID3D11Texture2D of the encoder's device, `callback` runs once per packet
+ * before this returns. Returns 0 or an HwcodecErrno value.
+ *
+ * # Safety
+ * `encoder` must come from hwcodec_encoder_new, `texture` must be a valid texture.
+ */
+int32_t hwcodec_encode(HwcodecEncoder *encoder,
+                       void *texture,
+                       int64_t ms,
+                       HwcodecPacketCallback callback,
+                       void *user_data);
+
+/*
+ * # Safety
+ * `encoder` must come from hwcodec_encoder_new.
+ */
+int32_t hwcodec_encoder_set_bitrate(HwcodecEncoder *encoder, int32_t kbitrate);
+
+/*
+ * # Safety
+ * `encoder` must come from hwcodec_encoder_new.
+ */
+int32_t hwcodec_encoder_set_framerate(HwcodecEncoder *encoder, int32_t framerate);
+
+/*
+ * The next encoded frame is an IDR frame with parameter sets.
+ *
+ * # Safety
+ * `encoder` must come from hwcodec_encoder_new.
+ */
+int32_t hwcodec_encoder_request_keyframe(HwcodecEncoder *encoder);
+
+/*
+ * Resets the encoder to textures of width x height after hwcodec_encode returned
+ * HWCODEC_ERR_RESET_REQUIRED. `callback` runs once per packet still buffered in the old
+ * session before this returns. Returns 0 or an HwcodecErrno value, the encoder can only
+ * be freed after an error.
+ *
+ * # Safety
+ * `encoder` must come from hwcodec_encoder_new.
+ */
+int32_t hwcodec_encoder_reset(HwcodecEncoder *encoder,
+                              int32_t width,
+                              int32_t height,
+                              HwcodecPacketCallback callback,
+                              void *user_data);
+
+/*
+ * `ctx` is the json of an element of "d" of hwcodec_available. `device` is the
+ * ID3D11Device the decoded textures are created on, or null to create one on the
+ * adapter of the context. Free the decoder with hwcodec_decoder_free.
+ *
+ * # Safety
+ * `ctx` must be a nul terminated string, `device` a valid ID3D11Device or null.
+ */
+HwcodecDecoder *hwcodec_decoder_new(const char *ctx, void *device);
+
+/*
+ * # Safety
+ * `decoder` must come from hwcodec_decoder_new and must not be used afterwards.
+ */
+void hwcodec_decoder_free(HwcodecDecoder *decoder);
+
+/*
+ * Decodes one Annex-B packet, `callback` runs once per decoded frame before this
+ * returns. Returns 0 or an HwcodecErrno value.
+ *
+ * # Safety
+ * `decoder` must come from hwcodec_decoder_new, `data` must point to `len` bytes.
+ */
+int32_t hwcodec_decode(HwcodecDecoder *decoder,
+                       const uint8_t *data,
+                       size_t len,
+                       HwcodecFrameCallback callback,
+                       void *user_data);
+
+/*
+ * Like hwcodec_decode but reads each frame back into memory, slower and meant for
+ * tools rather than rendering. Streams signalling bt.709, bt.2020 or full range in
+ * their VUI are converted accordingly. Returns 0 or an HwcodecErrno value.
+ *
+ * # Safety
+ * `decoder` must come from hwcodec_decoder_new, `data` must point to `len` bytes.
+ */
+int32_t hwcodec_decode_bgra(HwcodecDecoder *decoder,
+                            const uint8_t *data,
+                            size_t len,
+                            HwcodecBgraCallback callback,
+                            void *user_data);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* HWCODEC_H */
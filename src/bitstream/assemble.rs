@@ -0,0 +1,127 @@
+use super::{find_start_code, h264, hevc, validate::Limits};
+use crate::common::{DataFormat, HwcodecErrno};
+
+// Collects Annex-B data arriving in arbitrary chunks, e.g. as the network delivers it, into
+// whole access units for Decoder::decode of either the vram or the ram decoders. An access
+// unit is complete once the first NAL unit of the next one arrived: an AUD, a parameter
+// set, a prefix SEI or the first slice of the next picture. Start codes and NAL headers
+// may be split across chunks.
+pub struct AccessUnitAssembler {
+    format: DataFormat,
+    max_size: usize,
+    buf: Vec<u8>,
+    // where the start code search resumes, nothing before it is left to classify
+    scan: usize,
+    // a slice of the buffered access unit was seen
+    vcl: bool,
+}
+
+impl AccessUnitAssembler {
+    pub fn new(format: DataFormat) -> Result<Self, ()> {
+        if format != DataFormat::H264 && format != DataFormat::H265 {
+            return Err(());
+        }
+        Ok(Self {
+            format,
+            max_size: Limits::default().max_packet_size,
+            buf: vec![],
+            scan: 0,
+            vcl: false,
+        })
+    }
+
+    // Appends chunk and calls decode with each access unit it completes, returns the
+    // bytes handed to decode. An error of decode is returned after dropping its access
+    // unit. An incomplete access unit growing beyond the validator's packet size limit is
+    // dropped with HWCODEC_ERR_INVALID_DATA.
+    pub fn push<F>(&mut self, chunk: &[u8], mut decode: F) -> Result<usize, i32>
+    where
+        F: FnMut(&[u8]) -> Result<(), i32>,
+    {
+        self.buf.extend_from_slice(chunk);
+        let mut consumed = 0;
+        while let Some((start_code, nal)) = find_start_code(&self.buf, self.scan) {
+            let Some((starts, vcl)) = classify(self.format, &self.buf[nal..]) else {
+                // the header is cut off, look at this start code again with more data
+                self.scan = start_code;
+                break;
+            };
+            self.scan = nal;
+            if starts && self.vcl {
+                let end = trim_zeros(&self.buf, start_code);
+                let au: Vec<u8> = self.buf.drain(..end).collect();
+                self.scan -= end;
+                self.vcl = vcl;
+                consumed += au.len();
+                decode(&au)?;
+            } else {
+                self.vcl |= vcl;
+            }
+        }
+        if find_start_code(&self.buf, self.scan).is_none() {
+            // a start code split across chunks begins within the last 3 bytes
+            self.scan = self.scan.max(self.buf.len().saturating_sub(3));
+        }
+        if self.buf.len() > self.max_size {
+            self.reset();
+            return Err(HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _);
+        }
+        Ok(consumed)
+    }
+
+    // Hands the buffered access unit to decode, for the end of the stream or a transport
+    // that marks the last packet of a frame. Returns the bytes handed to decode.
+    pub fn flush<F>(&mut self, mut decode: F) -> Result<usize, i32>
+    where
+        F: FnMut(&[u8]) -> Result<(), i32>,
+    {
+        let au = std::mem::take(&mut self.buf);
+        self.reset();
+        if au.is_empty() {
+            return Ok(0);
+        }
+        decode(&au)?;
+        Ok(au.len())
+    }
+
+    // drops the buffered data, e.g. after packet loss
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.scan = 0;
+        self.vcl = false;
+    }
+
+    // bytes waiting for the end of their access unit
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+// (begins an access unit after a slice, is a slice), None if nal is too short to tell
+fn classify(format: DataFormat, nal: &[u8]) -> Option<(bool, bool)> {
+    if format == DataFormat::H264 {
+        let nal_type = h264::nal_unit_type(nal)?;
+        match nal_type {
+            // first_mb_in_slice is 0 if its ue(v) is the single bit 1
+            1..=5 => Some((*nal.get(1)? & 0x80 != 0, true)),
+            6..=9 | 14..=18 => Some((true, false)),
+            _ => Some((false, false)),
+        }
+    } else {
+        let nal_type = hevc::nal_unit_type(nal.get(..2)?)?;
+        match nal_type {
+            // first_slice_segment_in_pic_flag
+            0..=31 => Some((*nal.get(2)? & 0x80 != 0, true)),
+            32..=35 | 39 | 41..=44 | 48..=55 => Some((true, false)),
+            _ => Some((false, false)),
+        }
+    }
+}
+
+// the zero byte of a 4 byte start code stays with the access unit it begins
+fn trim_zeros(data: &[u8], mut end: usize) -> usize {
+    while end > 0 && data[end - 1] == 0 {
+        end -= 1;
+    }
+    end
+}
@@ -0,0 +1,75 @@
+// A stream of encoded frames with their metadata, for golden files of decode tests and
+// repro streams attached to bug reports. "HWCF", the u32 version, then per frame the
+// u32 length of its data, the i64 pts, the i32 key flag and the data. Little endian.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+const MAGIC: &[u8; 4] = b"HWCF";
+const VERSION: u32 = 1;
+// a corrupt length fails instead of allocating gigabytes
+const MAX_FRAME_BYTES: u32 = 256 << 20;
+
+pub fn write_header<W: Write>(w: &mut W) -> Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())
+}
+
+pub fn read_header<R: Read>(r: &mut R) -> Result<()> {
+    let mut header = [0u8; 8];
+    r.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a frame dump"));
+    }
+    let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported frame dump version {}", version),
+        ));
+    }
+    Ok(())
+}
+
+pub fn write_frame<W: Write>(w: &mut W, data: &[u8], pts: i64, key: i32) -> Result<()> {
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_BYTES)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "frame too large"))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&pts.to_le_bytes())?;
+    w.write_all(&key.to_le_bytes())?;
+    w.write_all(data)
+}
+
+// (data, pts, key), None at the end of the stream, a frame cut short is an error
+pub fn read_frame<R: Read>(r: &mut R) -> Result<Option<(Vec<u8>, i64, i32)>> {
+    let mut len = [0u8; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_BYTES {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes", len),
+        ));
+    }
+    let mut pts = [0u8; 8];
+    r.read_exact(&mut pts)?;
+    let mut key = [0u8; 4];
+    r.read_exact(&mut key)?;
+    let mut data = vec![0u8; len as usize];
+    r.read_exact(&mut data)?;
+    Ok(Some((
+        data,
+        i64::from_le_bytes(pts),
+        i32::from_le_bytes(key),
+    )))
+}
@@ -0,0 +1,313 @@
+use super::{annexb_nal_units, rbsp, sei_messages, vui::Vui, BitReader, SEI_RECOVERY_POINT};
+use serde_derive::{Deserialize, Serialize};
+
+pub const NAL_SEI: u8 = 6;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+pub const NAL_FILLER: u8 = 12;
+// start code, header and rbsp trailing bits
+pub const FILLER_MIN_LEN: usize = 6;
+
+// profiles carrying chroma_format_idc, bit depths and scaling matrices
+const HIGH_PROFILES: [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+const MAX_REF_FRAMES_IN_POC_CYCLE: u32 = 255;
+const MAX_CPB_CNT: u32 = 32;
+
+pub fn nal_unit_type(nal: &[u8]) -> Option<u8> {
+    nal.first().map(|b| b & 0x1f)
+}
+
+// An Annex-B filler data NAL unit of len bytes including its start code, at least
+// FILLER_MIN_LEN. Decoders discard it, it belongs after the slices of an access unit.
+pub fn filler_nal(len: usize) -> Vec<u8> {
+    let len = len.max(FILLER_MIN_LEN);
+    let mut nal = Vec::with_capacity(len);
+    nal.extend_from_slice(&[0, 0, 0, 1, NAL_FILLER]);
+    nal.resize(len - 1, 0xff);
+    nal.push(0x80);
+    nal
+}
+
+// recovery_frame_cnt of the recovery point SEI in nal, the frames after it until the
+// output is correct again
+pub fn recovery_point(nal: &[u8]) -> Option<u32> {
+    if nal_unit_type(nal) != Some(NAL_SEI) {
+        return None;
+    }
+    let data = rbsp(nal);
+    let messages = sei_messages(data.get(1..)?);
+    let (_, payload) = messages.iter().find(|(t, _)| *t == SEI_RECOVERY_POINT)?;
+    BitReader::new(payload).read_ue()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub sps_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane_flag: bool,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    pub log2_max_frame_num: u32,
+    pub pic_order_cnt_type: u32,
+    pub max_num_ref_frames: u32,
+    pub gaps_in_frame_num_value_allowed_flag: bool,
+    pub pic_width_in_mbs: u32,
+    pub pic_height_in_map_units: u32,
+    pub frame_mbs_only_flag: bool,
+    // left, right, top, bottom in crop units
+    pub frame_cropping: Option<[u32; 4]>,
+    pub vui: Option<Vui>,
+    // of the vui's bitstream_restriction, None if absent
+    pub max_num_reorder_frames: Option<u32>,
+}
+
+impl Sps {
+    // nal includes the one byte nal header
+    pub fn parse(nal: &[u8]) -> Result<Self, ()> {
+        if nal_unit_type(nal) != Some(NAL_SPS) {
+            return Err(());
+        }
+        let data = rbsp(nal);
+        let mut r = BitReader::new(data.get(1..).ok_or(())?);
+        Self::parse_rbsp(&mut r).ok_or(())
+    }
+
+    fn parse_rbsp(r: &mut BitReader) -> Option<Self> {
+        let profile_idc = r.read_bits(8)? as u8;
+        let constraint_flags = r.read_bits(8)? as u8;
+        let level_idc = r.read_bits(8)? as u8;
+        let sps_id = r.read_ue()?;
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane_flag = false;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+        if HIGH_PROFILES.contains(&profile_idc) {
+            chroma_format_idc = r.read_ue()?;
+            if chroma_format_idc > 3 {
+                return None;
+            }
+            if chroma_format_idc == 3 {
+                separate_colour_plane_flag = r.read_bit()?;
+            }
+            bit_depth_luma = r.read_ue()?.checked_add(8)?;
+            bit_depth_chroma = r.read_ue()?.checked_add(8)?;
+            // qpprime_y_zero_transform_bypass_flag
+            r.skip_bits(1)?;
+            if r.read_bit()? {
+                let count = if chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0..count {
+                    if r.read_bit()? {
+                        skip_scaling_list(r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+        let log2_max_frame_num = r.read_ue()?.checked_add(4)?;
+        if log2_max_frame_num > 16 {
+            return None;
+        }
+        let pic_order_cnt_type = r.read_ue()?;
+        match pic_order_cnt_type {
+            0 => {
+                // log2_max_pic_order_cnt_lsb_minus4
+                r.read_ue()?;
+            }
+            1 => {
+                // delta_pic_order_always_zero_flag, offset_for_non_ref_pic,
+                // offset_for_top_to_bottom_field
+                r.skip_bits(1)?;
+                r.read_se()?;
+                r.read_se()?;
+                let n = r.read_ue()?;
+                if n > MAX_REF_FRAMES_IN_POC_CYCLE {
+                    return None;
+                }
+                for _ in 0..n {
+                    r.read_se()?;
+                }
+            }
+            2 => {}
+            _ => return None,
+        }
+        let max_num_ref_frames = r.read_ue()?;
+        let gaps_in_frame_num_value_allowed_flag = r.read_bit()?;
+        let pic_width_in_mbs = r.read_ue()? + 1;
+        let pic_height_in_map_units = r.read_ue()? + 1;
+        let frame_mbs_only_flag = r.read_bit()?;
+        if !frame_mbs_only_flag {
+            // mb_adaptive_frame_field_flag
+            r.skip_bits(1)?;
+        }
+        // direct_8x8_inference_flag
+        r.skip_bits(1)?;
+        let frame_cropping = if r.read_bit()? {
+            Some([r.read_ue()?, r.read_ue()?, r.read_ue()?, r.read_ue()?])
+        } else {
+            None
+        };
+        let vui = if r.read_bit()? { Vui::parse(r) } else { None };
+        let max_num_reorder_frames = vui.as_ref().and_then(|_| parse_max_num_reorder_frames(r));
+        Some(Self {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            sps_id,
+            chroma_format_idc,
+            separate_colour_plane_flag,
+            bit_depth_luma,
+            bit_depth_chroma,
+            log2_max_frame_num,
+            pic_order_cnt_type,
+            max_num_ref_frames,
+            gaps_in_frame_num_value_allowed_flag,
+            pic_width_in_mbs,
+            pic_height_in_map_units,
+            frame_mbs_only_flag,
+            frame_cropping,
+            vui,
+            max_num_reorder_frames,
+        })
+    }
+
+    pub fn coded_size(&self) -> (u32, u32) {
+        let field_factor: u32 = if self.frame_mbs_only_flag { 1 } else { 2 };
+        (
+            self.pic_width_in_mbs.saturating_mul(16),
+            self.pic_height_in_map_units
+                .saturating_mul(16 * field_factor),
+        )
+    }
+
+    // display size after applying frame cropping
+    pub fn cropped_size(&self) -> (u32, u32) {
+        let [left, right, top, bottom] = self.crop();
+        let (width, height) = self.coded_size();
+        (
+            width.saturating_sub(left.saturating_add(right)),
+            height.saturating_sub(top.saturating_add(bottom)),
+        )
+    }
+
+    // frame cropping in luma samples, left, right, top and bottom
+    pub fn crop(&self) -> [u32; 4] {
+        let (sub_width, sub_height): (u32, u32) = match self.chroma_format_idc {
+            1 if !self.separate_colour_plane_flag => (2, 2),
+            2 if !self.separate_colour_plane_flag => (2, 1),
+            _ => (1, 1),
+        };
+        let field_factor: u32 = if self.frame_mbs_only_flag { 1 } else { 2 };
+        let [left, right, top, bottom] = self.frame_cropping.unwrap_or_default();
+        [
+            sub_width.saturating_mul(left),
+            sub_width.saturating_mul(right),
+            (sub_height * field_factor).saturating_mul(top),
+            (sub_height * field_factor).saturating_mul(bottom),
+        ]
+    }
+}
+
+// the beginning of a PPS, up to its entropy coding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pps {
+    pub pps_id: u32,
+    pub sps_id: u32,
+    // CABAC, CAVLC if false
+    pub entropy_coding_mode_flag: bool,
+}
+
+impl Pps {
+    // nal includes the one byte nal header
+    pub fn parse(nal: &[u8]) -> Result<Self, ()> {
+        if nal_unit_type(nal) != Some(NAL_PPS) {
+            return Err(());
+        }
+        let data = rbsp(nal);
+        let mut r = BitReader::new(data.get(1..).ok_or(())?);
+        let pps_id = r.read_ue().ok_or(())?;
+        let sps_id = r.read_ue().ok_or(())?;
+        let entropy_coding_mode_flag = r.read_bit().ok_or(())?;
+        Ok(Self {
+            pps_id,
+            sps_id,
+            entropy_coding_mode_flag,
+        })
+    }
+}
+
+// the rest of vui_parameters() after chroma_loc_info
+fn parse_max_num_reorder_frames(r: &mut BitReader) -> Option<u32> {
+    if r.read_bit()? {
+        // num_units_in_tick, time_scale, fixed_frame_rate_flag
+        r.skip_bits(65)?;
+    }
+    let nal_hrd = r.read_bit()?;
+    if nal_hrd {
+        skip_hrd_parameters(r)?;
+    }
+    let vcl_hrd = r.read_bit()?;
+    if vcl_hrd {
+        skip_hrd_parameters(r)?;
+    }
+    if nal_hrd || vcl_hrd {
+        // low_delay_hrd_flag
+        r.skip_bits(1)?;
+    }
+    // pic_struct_present_flag
+    r.skip_bits(1)?;
+    if !r.read_bit()? {
+        return None;
+    }
+    // motion_vectors_over_pic_boundaries_flag, max_bytes_per_pic_denom,
+    // max_bits_per_mb_denom, log2_max_mv_length_horizontal and vertical
+    r.skip_bits(1)?;
+    for _ in 0..4 {
+        r.read_ue()?;
+    }
+    r.read_ue()
+}
+
+fn skip_hrd_parameters(r: &mut BitReader) -> Option<()> {
+    let cpb_cnt = r.read_ue()?.checked_add(1)?;
+    if cpb_cnt > MAX_CPB_CNT {
+        return None;
+    }
+    // bit_rate_scale, cpb_size_scale
+    r.skip_bits(8)?;
+    for _ in 0..cpb_cnt {
+        // bit_rate_value_minus1, cpb_size_value_minus1, cbr_flag
+        r.read_ue()?;
+        r.read_ue()?;
+        r.skip_bits(1)?;
+    }
+    // the lengths of initial_cpb_removal_delay, cpb_removal_delay, dpb_output_delay and
+    // time_offset
+    r.skip_bits(20)
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let mut last_scale = 8i32;
+    let mut next_scale = 8i32;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = r.read_se()?;
+            if !(-128..=127).contains(&delta_scale) {
+                return None;
+            }
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
+
+// first SPS found in an Annex-B stream
+pub fn find_sps(data: &[u8]) -> Option<Sps> {
+    annexb_nal_units(data)
+        .filter(|nal| nal_unit_type(nal) == Some(NAL_SPS))
+        .find_map(|nal| Sps::parse(nal).ok())
+}
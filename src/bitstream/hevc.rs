@@ -0,0 +1,549 @@
+use super::{annexb_nal_units, rbsp, sei_messages, vui::Vui, BitReader, SEI_RECOVERY_POINT};
+use serde_derive::{Deserialize, Serialize};
+
+pub const NAL_VPS: u8 = 32;
+pub const NAL_SPS: u8 = 33;
+pub const NAL_PPS: u8 = 34;
+pub const NAL_FD: u8 = 38;
+pub const NAL_PREFIX_SEI: u8 = 39;
+// start code, two bytes header and rbsp trailing bits
+pub const FILLER_MIN_LEN: usize = 7;
+
+const MAX_SUB_LAYERS: usize = 7;
+const MAX_SHORT_TERM_REF_PIC_SETS: u32 = 64;
+const MAX_LONG_TERM_REF_PICS: u32 = 32;
+
+pub fn nal_unit_type(nal: &[u8]) -> Option<u8> {
+    nal.first().map(|b| (b >> 1) & 0x3f)
+}
+
+// An Annex-B filler data NAL unit of len bytes including its start code, at least
+// FILLER_MIN_LEN. Decoders discard it, it belongs after the slices of an access unit.
+pub fn filler_nal(len: usize) -> Vec<u8> {
+    let len = len.max(FILLER_MIN_LEN);
+    let mut nal = Vec::with_capacity(len);
+    // layer 0, temporal id 0
+    nal.extend_from_slice(&[0, 0, 0, 1, NAL_FD << 1, 1]);
+    nal.resize(len - 1, 0xff);
+    nal.push(0x80);
+    nal
+}
+
+// recovery_poc_cnt of the recovery point SEI in nal, the pictures after it until the
+// output is correct again, negative for a point before it
+pub fn recovery_point(nal: &[u8]) -> Option<i32> {
+    if nal_unit_type(nal) != Some(NAL_PREFIX_SEI) {
+        return None;
+    }
+    let data = rbsp(nal);
+    let messages = sei_messages(data.get(2..)?);
+    let (_, payload) = messages.iter().find(|(t, _)| *t == SEI_RECOVERY_POINT)?;
+    BitReader::new(payload).read_se()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Profile {
+    pub profile_space: u8,
+    pub tier_flag: bool,
+    pub profile_idc: u8,
+    pub profile_compatibility_flags: u32,
+    pub progressive_source_flag: bool,
+    pub interlaced_source_flag: bool,
+    pub non_packed_constraint_flag: bool,
+    pub frame_only_constraint_flag: bool,
+    // the 48 bits starting with progressive_source_flag, as stored in hvcC
+    pub constraint_indicator_flags: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SubLayer {
+    pub profile: Option<Profile>,
+    pub level_idc: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProfileTierLevel {
+    pub general: Profile,
+    pub general_level_idc: u8,
+    pub sub_layers: Vec<SubLayer>,
+}
+
+impl ProfileTierLevel {
+    fn parse(r: &mut BitReader, max_sub_layers_minus1: usize) -> Option<Self> {
+        let general = Profile::parse(r)?;
+        let general_level_idc = r.read_bits(8)? as u8;
+        let mut present = Vec::with_capacity(max_sub_layers_minus1);
+        for _ in 0..max_sub_layers_minus1 {
+            present.push((r.read_bit()?, r.read_bit()?));
+        }
+        if max_sub_layers_minus1 > 0 {
+            // reserved_zero_2bits up to 8 sub layers
+            r.skip_bits(2 * (8 - max_sub_layers_minus1))?;
+        }
+        let mut sub_layers = Vec::with_capacity(max_sub_layers_minus1);
+        for (profile_present, level_present) in present {
+            let profile = if profile_present {
+                Some(Profile::parse(r)?)
+            } else {
+                None
+            };
+            let level_idc = if level_present {
+                Some(r.read_bits(8)? as u8)
+            } else {
+                None
+            };
+            sub_layers.push(SubLayer { profile, level_idc });
+        }
+        Some(Self {
+            general,
+            general_level_idc,
+            sub_layers,
+        })
+    }
+
+    // level_idc is 30 times the level number, e.g. 93 for 3.1
+    pub fn level(&self) -> f32 {
+        self.general_level_idc as f32 / 30.0
+    }
+}
+
+impl Profile {
+    fn parse(r: &mut BitReader) -> Option<Self> {
+        let profile_space = r.read_bits(2)? as u8;
+        let tier_flag = r.read_bit()?;
+        let profile_idc = r.read_bits(5)? as u8;
+        let profile_compatibility_flags = r.read_bits(32)? as u32;
+        let constraint_indicator_flags = r.read_bits(48)?;
+        Some(Self {
+            profile_space,
+            tier_flag,
+            profile_idc,
+            profile_compatibility_flags,
+            progressive_source_flag: constraint_indicator_flags >> 47 & 1 == 1,
+            interlaced_source_flag: constraint_indicator_flags >> 46 & 1 == 1,
+            non_packed_constraint_flag: constraint_indicator_flags >> 45 & 1 == 1,
+            frame_only_constraint_flag: constraint_indicator_flags >> 44 & 1 == 1,
+            constraint_indicator_flags,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Vps {
+    pub vps_id: u8,
+    pub max_layers_minus1: u8,
+    pub max_sub_layers_minus1: u8,
+    pub temporal_id_nesting_flag: bool,
+    pub ptl: ProfileTierLevel,
+}
+
+impl Vps {
+    // nal includes the two bytes nal header
+    pub fn parse(nal: &[u8]) -> Result<Self, ()> {
+        if nal_unit_type(nal) != Some(NAL_VPS) {
+            return Err(());
+        }
+        let data = rbsp(nal);
+        let mut r = BitReader::new(data.get(2..).ok_or(())?);
+        Self::parse_rbsp(&mut r).ok_or(())
+    }
+
+    fn parse_rbsp(r: &mut BitReader) -> Option<Self> {
+        let vps_id = r.read_bits(4)? as u8;
+        // vps_base_layer_internal_flag, vps_base_layer_available_flag
+        r.skip_bits(2)?;
+        let max_layers_minus1 = r.read_bits(6)? as u8;
+        let max_sub_layers_minus1 = r.read_bits(3)? as u8;
+        if max_sub_layers_minus1 as usize >= MAX_SUB_LAYERS {
+            return None;
+        }
+        let temporal_id_nesting_flag = r.read_bit()?;
+        // vps_reserved_0xffff_16bits
+        r.skip_bits(16)?;
+        let ptl = ProfileTierLevel::parse(r, max_sub_layers_minus1 as _)?;
+        Some(Self {
+            vps_id,
+            max_layers_minus1,
+            max_sub_layers_minus1,
+            temporal_id_nesting_flag,
+            ptl,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Sps {
+    pub vps_id: u8,
+    pub max_sub_layers_minus1: u8,
+    pub temporal_id_nesting_flag: bool,
+    pub ptl: ProfileTierLevel,
+    pub sps_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane_flag: bool,
+    pub pic_width_in_luma_samples: u32,
+    pub pic_height_in_luma_samples: u32,
+    // left, right, top, bottom in chroma units
+    pub conformance_window: Option<[u32; 4]>,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    // None if absent or the syntax before it could not be parsed
+    pub vui: Option<Vui>,
+    // of the highest sub-layer, None if the syntax before it could not be parsed
+    pub max_num_reorder_pics: Option<u32>,
+    // None if the syntax before it could not be parsed
+    pub ref_pic_sets: Option<RefPicSets>,
+}
+
+// what slice headers need to derive their POC and short-term RPS
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RefPicSets {
+    pub log2_max_poc_lsb: u32,
+    pub short_term: Vec<ShortTermRps>,
+    pub long_term_ref_pics_present_flag: bool,
+}
+
+// The POC deltas of a short-term reference picture set with used_by_curr_pic, closest
+// first. Predicted sets are resolved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ShortTermRps {
+    pub negative: Vec<(i32, bool)>,
+    pub positive: Vec<(i32, bool)>,
+}
+
+impl Sps {
+    // nal includes the two bytes nal header
+    pub fn parse(nal: &[u8]) -> Result<Self, ()> {
+        if nal_unit_type(nal) != Some(NAL_SPS) {
+            return Err(());
+        }
+        let data = rbsp(nal);
+        let mut r = BitReader::new(data.get(2..).ok_or(())?);
+        Self::parse_rbsp(&mut r).ok_or(())
+    }
+
+    fn parse_rbsp(r: &mut BitReader) -> Option<Self> {
+        let vps_id = r.read_bits(4)? as u8;
+        let max_sub_layers_minus1 = r.read_bits(3)? as u8;
+        if max_sub_layers_minus1 as usize >= MAX_SUB_LAYERS {
+            return None;
+        }
+        let temporal_id_nesting_flag = r.read_bit()?;
+        let ptl = ProfileTierLevel::parse(r, max_sub_layers_minus1 as _)?;
+        let sps_id = r.read_ue()?;
+        let chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc > 3 {
+            return None;
+        }
+        let separate_colour_plane_flag = if chroma_format_idc == 3 {
+            r.read_bit()?
+        } else {
+            false
+        };
+        let pic_width_in_luma_samples = r.read_ue()?;
+        let pic_height_in_luma_samples = r.read_ue()?;
+        let conformance_window = if r.read_bit()? {
+            Some([r.read_ue()?, r.read_ue()?, r.read_ue()?, r.read_ue()?])
+        } else {
+            None
+        };
+        let bit_depth_luma = r.read_ue()?.checked_add(8)?;
+        let bit_depth_chroma = r.read_ue()?.checked_add(8)?;
+        let mut max_num_reorder_pics = None;
+        let mut ref_pic_sets = None;
+        let vui = Self::parse_vui(
+            r,
+            max_sub_layers_minus1,
+            &mut max_num_reorder_pics,
+            &mut ref_pic_sets,
+        )
+        .flatten();
+        Some(Self {
+            vps_id,
+            max_sub_layers_minus1,
+            temporal_id_nesting_flag,
+            ptl,
+            sps_id,
+            chroma_format_idc,
+            separate_colour_plane_flag,
+            pic_width_in_luma_samples,
+            pic_height_in_luma_samples,
+            conformance_window,
+            bit_depth_luma,
+            bit_depth_chroma,
+            vui,
+            max_num_reorder_pics,
+            ref_pic_sets,
+        })
+    }
+
+    // skips everything between bit_depth_chroma_minus8 and vui_parameters() except
+    // max_num_reorder_pics and the reference picture sets
+    fn parse_vui(
+        r: &mut BitReader,
+        max_sub_layers_minus1: u8,
+        max_num_reorder_pics: &mut Option<u32>,
+        ref_pic_sets: &mut Option<RefPicSets>,
+    ) -> Option<Option<Vui>> {
+        let log2_max_poc_lsb = r.read_ue()?.checked_add(4)?;
+        if log2_max_poc_lsb > 16 {
+            return None;
+        }
+        let ordering_info_present = r.read_bit()?;
+        let first = if ordering_info_present {
+            0
+        } else {
+            max_sub_layers_minus1
+        };
+        for _ in first..=max_sub_layers_minus1 {
+            // max_dec_pic_buffering_minus1, max_latency_increase_plus1
+            r.read_ue()?;
+            *max_num_reorder_pics = Some(r.read_ue()?);
+            r.read_ue()?;
+        }
+        // coding and transform block sizes, max transform hierarchy depths
+        for _ in 0..6 {
+            r.read_ue()?;
+        }
+        // scaling_list_enabled_flag, sps_scaling_list_data_present_flag
+        if r.read_bit()? && r.read_bit()? {
+            skip_scaling_list_data(r)?;
+        }
+        // amp_enabled_flag, sample_adaptive_offset_enabled_flag
+        r.skip_bits(2)?;
+        if r.read_bit()? {
+            // pcm sample bit depths, pcm coding block sizes, pcm_loop_filter_disabled_flag
+            r.skip_bits(8)?;
+            r.read_ue()?;
+            r.read_ue()?;
+            r.skip_bits(1)?;
+        }
+        let num_short_term_ref_pic_sets = r.read_ue()?;
+        if num_short_term_ref_pic_sets > MAX_SHORT_TERM_REF_PIC_SETS {
+            return None;
+        }
+        let mut short_term = Vec::with_capacity(num_short_term_ref_pic_sets as _);
+        for _ in 0..num_short_term_ref_pic_sets {
+            let set = parse_st_ref_pic_set(r, &short_term, false)?;
+            short_term.push(set);
+        }
+        let long_term_ref_pics_present_flag = r.read_bit()?;
+        *ref_pic_sets = Some(RefPicSets {
+            log2_max_poc_lsb,
+            short_term,
+            long_term_ref_pics_present_flag,
+        });
+        if long_term_ref_pics_present_flag {
+            let num_long_term_ref_pics = r.read_ue()?;
+            if num_long_term_ref_pics > MAX_LONG_TERM_REF_PICS {
+                return None;
+            }
+            // lt_ref_pic_poc_lsb_sps, used_by_curr_pic_lt_sps_flag
+            r.skip_bits(num_long_term_ref_pics as usize * (log2_max_poc_lsb as usize + 1))?;
+        }
+        // sps_temporal_mvp_enabled_flag, strong_intra_smoothing_enabled_flag
+        r.skip_bits(2)?;
+        if !r.read_bit()? {
+            return Some(None);
+        }
+        Vui::parse(r).map(Some)
+    }
+
+    // display size after applying the conformance window
+    pub fn cropped_size(&self) -> (u32, u32) {
+        let [left, right, top, bottom] = self.crop();
+        (
+            self.pic_width_in_luma_samples
+                .saturating_sub(left.saturating_add(right)),
+            self.pic_height_in_luma_samples
+                .saturating_sub(top.saturating_add(bottom)),
+        )
+    }
+
+    // the conformance window in luma samples, left, right, top and bottom
+    pub fn crop(&self) -> [u32; 4] {
+        let (sub_width, sub_height): (u32, u32) = match self.chroma_format_idc {
+            1 if !self.separate_colour_plane_flag => (2, 2),
+            2 if !self.separate_colour_plane_flag => (2, 1),
+            _ => (1, 1),
+        };
+        let [left, right, top, bottom] = self.conformance_window.unwrap_or_default();
+        [
+            sub_width.saturating_mul(left),
+            sub_width.saturating_mul(right),
+            sub_height.saturating_mul(top),
+            sub_height.saturating_mul(bottom),
+        ]
+    }
+}
+
+fn skip_scaling_list_data(r: &mut BitReader) -> Option<()> {
+    for size_id in 0..4 {
+        let step = if size_id == 3 { 3 } else { 1 };
+        for _ in (0..6).step_by(step) {
+            if !r.read_bit()? {
+                // scaling_list_pred_matrix_id_delta
+                r.read_ue()?;
+                continue;
+            }
+            let coef_num = 64.min(1 << (4 + (size_id << 1)));
+            if size_id > 1 {
+                // scaling_list_dc_coef_minus8
+                r.read_se()?;
+            }
+            for _ in 0..coef_num {
+                r.read_se()?;
+            }
+        }
+    }
+    Some(())
+}
+
+// st_ref_pic_set() following the sets of the SPS, in_slice for the one of a slice header
+pub fn parse_st_ref_pic_set(
+    r: &mut BitReader,
+    sets: &[ShortTermRps],
+    in_slice: bool,
+) -> Option<ShortTermRps> {
+    if !sets.is_empty() && r.read_bit()? {
+        let delta_idx = if in_slice {
+            r.read_ue()?.checked_add(1)? as usize
+        } else {
+            1
+        };
+        let reference = sets.get(sets.len().checked_sub(delta_idx)?)?;
+        let sign = r.read_bit()?;
+        let abs_delta_rps = r.read_ue()?.checked_add(1)?;
+        if abs_delta_rps > 1 << 15 {
+            return None;
+        }
+        let delta_rps = if sign {
+            -(abs_delta_rps as i32)
+        } else {
+            abs_delta_rps as i32
+        };
+        let count = reference.negative.len() + reference.positive.len();
+        // (used_by_curr_pic_flag, use_delta_flag) of the reference's pictures, negative
+        // then positive, and of the reference picture itself
+        let mut flags = Vec::with_capacity(count + 1);
+        for _ in 0..=count {
+            let used = r.read_bit()?;
+            let use_delta = used || r.read_bit()?;
+            flags.push((used, use_delta));
+        }
+        Some(predict_st_ref_pic_set(reference, delta_rps, &flags))
+    } else {
+        let num_negative_pics = r.read_ue()?;
+        let num_positive_pics = r.read_ue()?;
+        if num_negative_pics > 16 || num_positive_pics > 16 {
+            return None;
+        }
+        let mut set = ShortTermRps::default();
+        let mut poc = 0i32;
+        for _ in 0..num_negative_pics {
+            poc -= r.read_ue()?.checked_add(1)?.min(1 << 15) as i32;
+            set.negative.push((poc, r.read_bit()?));
+        }
+        poc = 0;
+        for _ in 0..num_positive_pics {
+            poc += r.read_ue()?.checked_add(1)?.min(1 << 15) as i32;
+            set.positive.push((poc, r.read_bit()?));
+        }
+        Some(set)
+    }
+}
+
+// the derivation of DeltaPocS0 and DeltaPocS1 of an inter predicted set, 7.4.8
+fn predict_st_ref_pic_set(
+    reference: &ShortTermRps,
+    delta_rps: i32,
+    flags: &[(bool, bool)],
+) -> ShortTermRps {
+    let negative_count = reference.negative.len();
+    let own = flags[flags.len() - 1];
+    let mut set = ShortTermRps::default();
+    for (j, (delta, _)) in reference.positive.iter().enumerate().rev() {
+        let (used, use_delta) = flags[negative_count + j];
+        if delta + delta_rps < 0 && use_delta {
+            set.negative.push((delta + delta_rps, used));
+        }
+    }
+    if delta_rps < 0 && own.1 {
+        set.negative.push((delta_rps, own.0));
+    }
+    for (j, (delta, _)) in reference.negative.iter().enumerate() {
+        let (used, use_delta) = flags[j];
+        if delta + delta_rps < 0 && use_delta {
+            set.negative.push((delta + delta_rps, used));
+        }
+    }
+    for (j, (delta, _)) in reference.negative.iter().enumerate().rev() {
+        let (used, use_delta) = flags[j];
+        if delta + delta_rps > 0 && use_delta {
+            set.positive.push((delta + delta_rps, used));
+        }
+    }
+    if delta_rps > 0 && own.1 {
+        set.positive.push((delta_rps, own.0));
+    }
+    for (j, (delta, _)) in reference.positive.iter().enumerate() {
+        let (used, use_delta) = flags[negative_count + j];
+        if delta + delta_rps > 0 && use_delta {
+            set.positive.push((delta + delta_rps, used));
+        }
+    }
+    set
+}
+
+// the beginning of a PPS, what slice headers need up to their RPS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pps {
+    pub pps_id: u32,
+    pub sps_id: u32,
+    pub dependent_slice_segments_enabled_flag: bool,
+    pub output_flag_present_flag: bool,
+    pub num_extra_slice_header_bits: u32,
+}
+
+impl Pps {
+    // nal includes the two bytes nal header
+    pub fn parse(nal: &[u8]) -> Result<Self, ()> {
+        if nal_unit_type(nal) != Some(NAL_PPS) {
+            return Err(());
+        }
+        let data = rbsp(nal);
+        let mut r = BitReader::new(data.get(2..).ok_or(())?);
+        Self::parse_rbsp(&mut r).ok_or(())
+    }
+
+    fn parse_rbsp(r: &mut BitReader) -> Option<Self> {
+        Some(Self {
+            pps_id: r.read_ue()?,
+            sps_id: r.read_ue()?,
+            dependent_slice_segments_enabled_flag: r.read_bit()?,
+            output_flag_present_flag: r.read_bit()?,
+            num_extra_slice_header_bits: r.read_bits(3)? as u32,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ParameterSets {
+    pub vps: Option<Vps>,
+    pub sps: Option<Sps>,
+}
+
+// first VPS and SPS found in an Annex-B stream
+pub fn parameter_sets(data: &[u8]) -> ParameterSets {
+    let mut sets = ParameterSets::default();
+    for nal in annexb_nal_units(data) {
+        match nal_unit_type(nal) {
+            Some(NAL_VPS) if sets.vps.is_none() => sets.vps = Vps::parse(nal).ok(),
+            Some(NAL_SPS) if sets.sps.is_none() => sets.sps = Sps::parse(nal).ok(),
+            _ => {}
+        }
+        if sets.vps.is_some() && sets.sps.is_some() {
+            break;
+        }
+    }
+    sets
+}
@@ -0,0 +1,221 @@
+pub const SOF0: u8 = 0xc0;
+pub const SOF1: u8 = 0xc1;
+pub const DHT: u8 = 0xc4;
+pub const SOI: u8 = 0xd8;
+pub const EOI: u8 = 0xd9;
+pub const SOS: u8 = 0xda;
+
+// ITU-T T.81 K.3, the tables MJPEG frames without a DHT segment are coded with: class and
+// destination, 16 code counts by length, the values
+const DEFAULT_HUFFMAN_TABLES: [(u8, [u8; 16], &[u8]); 4] = [
+    (
+        0x00,
+        [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+        &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+    ),
+    (
+        0x10,
+        [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d],
+        &[
+            0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51,
+            0x61, 0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1,
+            0x15, 0x52, 0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18,
+            0x19, 0x1a, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39,
+            0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57,
+            0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75,
+            0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92,
+            0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+            0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+            0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8,
+            0xd9, 0xda, 0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2,
+            0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+        ],
+    ),
+    (
+        0x01,
+        [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+        &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+    ),
+    (
+        0x11,
+        [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+        &[
+            0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07,
+            0x61, 0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09,
+            0x23, 0x33, 0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25,
+            0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38,
+            0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56,
+            0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74,
+            0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+            0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+            0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba,
+            0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6,
+            0xd7, 0xd8, 0xd9, 0xda, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2,
+            0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+        ],
+    ),
+];
+
+// a marker segment before the entropy coded data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a> {
+    pub marker: u8,
+    // of the 0xff of the marker in the image
+    pub offset: usize,
+    // without marker and length, empty for markers without a segment
+    pub payload: &'a [u8],
+}
+
+// The marker segments of a JPEG image from the SOI up to and including the first SOS,
+// ends early where the image is cut off or malformed.
+pub fn segments(image: &[u8]) -> Segments<'_> {
+    Segments {
+        image,
+        pos: 0,
+        done: false,
+    }
+}
+
+pub struct Segments<'a> {
+    image: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let offset = self.pos;
+        if self.image.get(offset) != Some(&0xff) {
+            self.done = true;
+            return None;
+        }
+        // fill bytes may precede a marker
+        let mut pos = offset + 1;
+        while self.image.get(pos) == Some(&0xff) {
+            pos += 1;
+        }
+        let Some(&marker) = self.image.get(pos) else {
+            self.done = true;
+            return None;
+        };
+        pos += 1;
+        if marker == SOI || marker == EOI || (0xd0..=0xd7).contains(&marker) || marker == 0x01 {
+            self.done = marker == EOI;
+            self.pos = pos;
+            return Some(Segment {
+                marker,
+                offset,
+                payload: &[],
+            });
+        }
+        let len = match self.image.get(pos..pos + 2) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => 0,
+        };
+        let payload = if len >= 2 {
+            self.image.get(pos + 2..pos + len)
+        } else {
+            None
+        };
+        let Some(payload) = payload else {
+            self.done = true;
+            return None;
+        };
+        self.pos = pos + len;
+        self.done = marker == SOS;
+        Some(Segment {
+            marker,
+            offset,
+            payload,
+        })
+    }
+}
+
+// the SOFn markers, DHT, JPG and DAC share their range
+pub fn is_start_of_frame(marker: u8) -> bool {
+    (0xc0..=0xcf).contains(&marker) && marker != DHT && marker != 0xc8 && marker != 0xcc
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub marker: u8,
+    pub precision: u8,
+    pub width: u32,
+    pub height: u32,
+    // component id, horizontal and vertical sampling factor
+    pub components: Vec<(u8, u8, u8)>,
+}
+
+impl FrameHeader {
+    // payload of a SOFn segment
+    pub fn parse(marker: u8, payload: &[u8]) -> Result<Self, ()> {
+        if !is_start_of_frame(marker) || payload.len() < 6 {
+            return Err(());
+        }
+        let count = payload[5] as usize;
+        let Some(components) = payload.get(6..6 + count * 3) else {
+            return Err(());
+        };
+        let header = Self {
+            marker,
+            precision: payload[0],
+            height: u16::from_be_bytes([payload[1], payload[2]]) as _,
+            width: u16::from_be_bytes([payload[3], payload[4]]) as _,
+            components: components
+                .chunks_exact(3)
+                .map(|c| (c[0], c[1] >> 4, c[1] & 0xf))
+                .collect(),
+        };
+        let factors = |(_, h, v): &(u8, u8, u8)| (1..=4).contains(h) && (1..=4).contains(v);
+        if count == 0 || !header.components.iter().all(factors) {
+            return Err(());
+        }
+        Ok(header)
+    }
+
+    // the frame header of image, Err if there is none before the first scan
+    pub fn find(image: &[u8]) -> Result<Self, ()> {
+        segments(image)
+            .take_while(|s| s.marker != SOS)
+            .find(|s| is_start_of_frame(s.marker))
+            .map_or(Err(()), |s| Self::parse(s.marker, s.payload))
+    }
+
+    // 8 bit huffman coded sequential DCT, what the hardware decoders take
+    pub fn is_baseline(&self) -> bool {
+        (self.marker == SOF0 || self.marker == SOF1) && self.precision == 8
+    }
+}
+
+// Motion JPEG frames of capture cards and cameras often leave out the huffman tables and
+// expect those of K.3. Returns image with them inserted before the first scan, None if it
+// has its own or doesn't reach a scan.
+pub fn with_default_huffman_tables(image: &[u8]) -> Option<Vec<u8>> {
+    let mut sos = None;
+    for segment in segments(image) {
+        match segment.marker {
+            DHT => return None,
+            SOS => sos = Some(segment.offset),
+            _ => {}
+        }
+    }
+    let sos = sos?;
+    let mut payload = vec![];
+    for (class, counts, values) in DEFAULT_HUFFMAN_TABLES.iter() {
+        payload.push(*class);
+        payload.extend_from_slice(counts);
+        payload.extend_from_slice(values);
+    }
+    let mut out = Vec::with_capacity(image.len() + payload.len() + 4);
+    out.extend_from_slice(&image[..sos]);
+    out.extend_from_slice(&[0xff, DHT]);
+    out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(&image[sos..]);
+    Some(out)
+}
@@ -0,0 +1,235 @@
+pub mod assemble;
+pub mod dump;
+pub mod h264;
+pub mod hevc;
+pub mod jpeg;
+pub mod refs;
+pub mod validate;
+pub mod vui;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NalRef<'a> {
+    // the NAL unit including its header, without start code or length prefix
+    pub data: &'a [u8],
+}
+
+impl<'a> NalRef<'a> {
+    pub fn h264_type(&self) -> u8 {
+        h264::nal_unit_type(self.data).unwrap_or(0)
+    }
+
+    pub fn hevc_type(&self) -> u8 {
+        hevc::nal_unit_type(self.data).unwrap_or(0)
+    }
+}
+
+pub enum NalUnits<'a> {
+    AnnexB(AnnexBNalUnits<'a>),
+    Avcc(AvccNalUnits<'a>),
+}
+
+// Annex-B if the data begins with a start code, otherwise 4 bytes length prefixed AVCC
+pub fn nal_units(data: &[u8]) -> NalUnits<'_> {
+    if data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1]) {
+        NalUnits::AnnexB(annexb_nal_units(data))
+    } else {
+        NalUnits::Avcc(AvccNalUnits { data })
+    }
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = NalRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            NalUnits::AnnexB(it) => it.next(),
+            NalUnits::Avcc(it) => it.next(),
+        }
+        .map(|data| NalRef { data })
+    }
+}
+
+pub struct AvccNalUnits<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for AvccNalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 4 {
+            return None;
+        }
+        let len =
+            u32::from_be_bytes([self.data[0], self.data[1], self.data[2], self.data[3]]) as usize;
+        let Some(nal) = len.checked_add(4).and_then(|end| self.data.get(4..end)) else {
+            self.data = &[];
+            return None;
+        };
+        self.data = &self.data[4 + len..];
+        Some(nal)
+    }
+}
+
+// Annex-B NAL units without their start codes, trailing zero bytes belong to the next start code
+pub struct AnnexBNalUnits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+pub fn annexb_nal_units(data: &[u8]) -> AnnexBNalUnits<'_> {
+    AnnexBNalUnits {
+        data,
+        pos: find_start_code(data, 0)
+            .map(|(_, end)| end)
+            .unwrap_or(data.len()),
+    }
+}
+
+impl<'a> Iterator for AnnexBNalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.data.len() {
+            let start = self.pos;
+            let (mut end, next) =
+                find_start_code(self.data, start).unwrap_or((self.data.len(), self.data.len()));
+            self.pos = next;
+            while end > start && self.data[end - 1] == 0 {
+                end -= 1;
+            }
+            if end > start {
+                return Some(&self.data[start..end]);
+            }
+        }
+        None
+    }
+}
+
+// returns (position of the start code, position after it)
+fn find_start_code(data: &[u8], from: usize) -> Option<(usize, usize)> {
+    let mut i = from;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 {
+            if data[i + 2] == 1 {
+                return Some((i, i + 3));
+            }
+            if data[i + 2] == 0 {
+                i += 1;
+                continue;
+            }
+            i += 3;
+            continue;
+        }
+        i += 1;
+    }
+    None
+}
+
+// payloadType of the recovery point SEI in h264 and h265
+pub const SEI_RECOVERY_POINT: u32 = 6;
+
+// The (payloadType, payload) of the sei_message()s of an SEI rbsp after its NAL header,
+// up to the first one that doesn't fit.
+pub fn sei_messages(data: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut messages = vec![];
+    let mut rest = data;
+    // rbsp_trailing_bits
+    while rest.len() > 1 {
+        let mut read = || -> Option<u32> {
+            let mut v = 0;
+            loop {
+                let (&b, tail) = rest.split_first()?;
+                rest = tail;
+                v += b as u32;
+                if b != 0xff {
+                    return Some(v);
+                }
+            }
+        };
+        let (Some(payload_type), Some(size)) = (read(), read()) else {
+            break;
+        };
+        let Some(payload) = rest.get(..size as usize) else {
+            break;
+        };
+        messages.push((payload_type, payload));
+        rest = &rest[size as usize..];
+    }
+    messages
+}
+
+// removes emulation_prevention_three_byte from a NAL unit
+pub fn rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &b in nal {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    // data must already be rbsp
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn bits_left(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.pos)
+    }
+
+    pub fn read_bit(&mut self) -> Option<bool> {
+        let byte = *self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit == 1)
+    }
+
+    pub fn read_bits(&mut self, n: u32) -> Option<u64> {
+        if n > 64 || self.bits_left() < n as usize {
+            return None;
+        }
+        let mut v = 0u64;
+        for _ in 0..n {
+            v = (v << 1) | self.read_bit()? as u64;
+        }
+        Some(v)
+    }
+
+    pub fn skip_bits(&mut self, n: usize) -> Option<()> {
+        if self.bits_left() < n {
+            return None;
+        }
+        self.pos += n;
+        Some(())
+    }
+
+    pub fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        let v = (1u64 << leading_zeros) - 1 + self.read_bits(leading_zeros)?;
+        u32::try_from(v).ok()
+    }
+
+    pub fn read_se(&mut self) -> Option<i32> {
+        let k = self.read_ue()? as i64;
+        let v = if k % 2 == 1 { (k + 1) / 2 } else { -(k / 2) };
+        i32::try_from(v).ok()
+    }
+}
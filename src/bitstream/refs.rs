@@ -0,0 +1,257 @@
+use super::{annexb_nal_units, h264, hevc, rbsp, BitReader};
+use crate::common::DataFormat;
+use std::collections::HashMap;
+
+const NAL_IDR_SLICE: u8 = 5;
+const HEVC_BLA_W_LP: u8 = 16;
+const HEVC_IDR_W_RADL: u8 = 19;
+const HEVC_IDR_N_LP: u8 = 20;
+const HEVC_CRA: u8 = 21;
+
+// what the pictures of an access unit reference, see RefTracker::push
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum References {
+    // no slice, e.g. parameter sets alone
+    NoPicture,
+    // nothing before it is referenced by it or the pictures after it
+    Idr,
+    // every reference was seen, or the slice header couldn't be parsed to tell
+    Complete,
+    // a reference never arrived, e.g. its packet was lost, or no IDR or for H.265 no
+    // IRAP picture was seen yet
+    Missing,
+}
+
+// Follows the reference pictures of an Annex-B H.264 or H.265 stream through the first
+// slice header of each access unit, to tell the pictures whose references never arrived
+// before a decoder sees them. H.264 pictures are checked for gaps in frame_num, H.265 ones
+// for pictures their short-term RPS uses that weren't seen. Long-term references and
+// memory_management_control_operation 5 aren't followed.
+pub struct RefTracker {
+    format: DataFormat,
+    h264_sps: HashMap<u32, h264::Sps>,
+    h264_pps: HashMap<u32, h264::Pps>,
+    hevc_sps: HashMap<u32, hevc::Sps>,
+    hevc_pps: HashMap<u32, hevc::Pps>,
+    // an IDR or for H.265 an IRAP picture was seen
+    started: bool,
+    // H.264, of the previous reference picture
+    prev_ref_frame_num: u32,
+    // H.265, (slice_pic_order_cnt_lsb, PicOrderCntMsb) of prevTid0Pic
+    prev_tid0: (i32, i32),
+    // H.265, POCs of the pictures that may still be referenced
+    dpb: Vec<i32>,
+}
+
+impl RefTracker {
+    pub fn new(format: DataFormat) -> Self {
+        Self {
+            format,
+            h264_sps: HashMap::new(),
+            h264_pps: HashMap::new(),
+            hevc_sps: HashMap::new(),
+            hevc_pps: HashMap::new(),
+            started: false,
+            prev_ref_frame_num: 0,
+            prev_tid0: (0, 0),
+            dpb: vec![],
+        }
+    }
+
+    // Takes the parameter sets of au and checks its first slice. Every access unit of the
+    // stream has to be pushed in decode order, including those that are then dropped.
+    pub fn push(&mut self, au: &[u8]) -> References {
+        if self.format == DataFormat::MJPEG {
+            // every image stands alone
+            return References::Idr;
+        }
+        for nal in annexb_nal_units(au) {
+            let picture = match self.format {
+                DataFormat::H264 => self.push_h264(nal),
+                DataFormat::H265 => self.push_hevc(nal),
+                _ => return References::Complete,
+            };
+            if let Some(references) = picture {
+                return references;
+            }
+        }
+        References::NoPicture
+    }
+
+    // None for NAL units other than slices
+    fn push_h264(&mut self, nal: &[u8]) -> Option<References> {
+        match h264::nal_unit_type(nal)? {
+            h264::NAL_SPS => {
+                if let Ok(sps) = h264::Sps::parse(nal) {
+                    self.h264_sps.insert(sps.sps_id, sps);
+                }
+                None
+            }
+            h264::NAL_PPS => {
+                if let Ok(pps) = h264::Pps::parse(nal) {
+                    self.h264_pps.insert(pps.pps_id, pps);
+                }
+                None
+            }
+            nal_type @ (1 | 5) => Some(self.h264_slice(nal, nal_type)),
+            _ => None,
+        }
+    }
+
+    fn h264_slice(&mut self, nal: &[u8], nal_type: u8) -> References {
+        if nal_type == NAL_IDR_SLICE {
+            // frame_num is 0
+            self.started = true;
+            self.prev_ref_frame_num = 0;
+            return References::Idr;
+        }
+        if !self.started {
+            return References::Missing;
+        }
+        let Some((frame_num, max_frame_num, gaps_allowed)) = self.h264_frame_num(nal) else {
+            return References::Complete;
+        };
+        let reference = nal[0] >> 5 != 0;
+        let gap = !gaps_allowed
+            && frame_num != self.prev_ref_frame_num
+            && frame_num != (self.prev_ref_frame_num + 1) % max_frame_num;
+        if reference {
+            self.prev_ref_frame_num = frame_num;
+        }
+        if gap {
+            References::Missing
+        } else {
+            References::Complete
+        }
+    }
+
+    // (frame_num, MaxFrameNum, gaps_in_frame_num_value_allowed_flag) of a slice
+    fn h264_frame_num(&self, nal: &[u8]) -> Option<(u32, u32, bool)> {
+        let data = rbsp(nal);
+        let mut r = BitReader::new(data.get(1..)?);
+        // first_mb_in_slice, slice_type
+        r.read_ue()?;
+        r.read_ue()?;
+        let pps = self.h264_pps.get(&r.read_ue()?)?;
+        let sps = self.h264_sps.get(&pps.sps_id)?;
+        if sps.separate_colour_plane_flag {
+            // colour_plane_id
+            r.skip_bits(2)?;
+        }
+        let frame_num = r.read_bits(sps.log2_max_frame_num)? as u32;
+        Some((
+            frame_num,
+            1 << sps.log2_max_frame_num,
+            sps.gaps_in_frame_num_value_allowed_flag,
+        ))
+    }
+
+    // None for NAL units other than slices
+    fn push_hevc(&mut self, nal: &[u8]) -> Option<References> {
+        match hevc::nal_unit_type(nal)? {
+            hevc::NAL_SPS => {
+                if let Ok(sps) = hevc::Sps::parse(nal) {
+                    self.hevc_sps.insert(sps.sps_id, sps);
+                }
+                None
+            }
+            hevc::NAL_PPS => {
+                if let Ok(pps) = hevc::Pps::parse(nal) {
+                    self.hevc_pps.insert(pps.pps_id, pps);
+                }
+                None
+            }
+            nal_type @ 0..=31 => Some(self.hevc_slice(nal, nal_type)),
+            _ => None,
+        }
+    }
+
+    fn hevc_slice(&mut self, nal: &[u8], nal_type: u8) -> References {
+        let irap = (HEVC_BLA_W_LP..=HEVC_CRA).contains(&nal_type);
+        if !irap && !self.started {
+            return References::Missing;
+        }
+        if nal_type == HEVC_IDR_W_RADL || nal_type == HEVC_IDR_N_LP {
+            self.started = true;
+            self.prev_tid0 = (0, 0);
+            self.dpb = vec![0];
+            return References::Idr;
+        }
+        let Some((lsb, log2_max_poc_lsb, rps)) = self.hevc_rps(nal, nal_type) else {
+            return References::Complete;
+        };
+        // NoRaslOutputFlag, decoding starts over at a BLA or the first CRA
+        let msb = if irap && (nal_type < HEVC_IDR_W_RADL || !self.started) {
+            self.dpb.clear();
+            0
+        } else {
+            let (prev_lsb, prev_msb) = self.prev_tid0;
+            let max_lsb = 1 << log2_max_poc_lsb;
+            if lsb < prev_lsb && prev_lsb - lsb >= max_lsb / 2 {
+                prev_msb + max_lsb
+            } else if lsb > prev_lsb && lsb - prev_lsb > max_lsb / 2 {
+                prev_msb - max_lsb
+            } else {
+                prev_msb
+            }
+        };
+        self.started = true;
+        let poc = msb + lsb;
+        let temporal_id = nal.get(1).map_or(0, |b| (b & 7).saturating_sub(1));
+        let sub_layer_non_reference = nal_type < 16 && nal_type & 1 == 0;
+        // prevTid0Pic is neither RADL, RASL nor a sub-layer non-reference picture
+        if temporal_id == 0 && !(6..=9).contains(&nal_type) && !sub_layer_non_reference {
+            self.prev_tid0 = (lsb, msb);
+        }
+        let deltas = rps.negative.iter().chain(rps.positive.iter());
+        let missing = deltas
+            .clone()
+            .any(|(delta, used)| *used && !self.dpb.contains(&(poc + delta)));
+        self.dpb
+            .retain(|p| deltas.clone().any(|(delta, _)| poc + delta == *p));
+        self.dpb.push(poc);
+        if missing {
+            References::Missing
+        } else {
+            References::Complete
+        }
+    }
+
+    // (slice_pic_order_cnt_lsb, log2_max_poc_lsb, short-term RPS) of a slice other than
+    // an IDR's
+    fn hevc_rps(&self, nal: &[u8], nal_type: u8) -> Option<(i32, u32, hevc::ShortTermRps)> {
+        let data = rbsp(nal);
+        let mut r = BitReader::new(data.get(2..)?);
+        if !r.read_bit()? {
+            // not the first slice segment of the picture, the beginning was lost
+            return None;
+        }
+        if (HEVC_BLA_W_LP..=23).contains(&nal_type) {
+            // no_output_of_prior_pics_flag
+            r.skip_bits(1)?;
+        }
+        let pps = self.hevc_pps.get(&r.read_ue()?)?;
+        let sps = self.hevc_sps.get(&pps.sps_id)?;
+        let sets = sps.ref_pic_sets.as_ref()?;
+        r.skip_bits(pps.num_extra_slice_header_bits as _)?;
+        // slice_type
+        r.read_ue()?;
+        if pps.output_flag_present_flag {
+            // pic_output_flag
+            r.skip_bits(1)?;
+        }
+        if sps.separate_colour_plane_flag {
+            // colour_plane_id
+            r.skip_bits(2)?;
+        }
+        let lsb = r.read_bits(sets.log2_max_poc_lsb)? as i32;
+        let rps = if !r.read_bit()? {
+            hevc::parse_st_ref_pic_set(&mut r, &sets.short_term, true)?
+        } else {
+            let bits = usize::BITS - sets.short_term.len().saturating_sub(1).leading_zeros();
+            let idx = r.read_bits(bits)? as usize;
+            sets.short_term.get(idx)?.clone()
+        };
+        Some((lsb, sets.log2_max_poc_lsb, rps))
+    }
+}
@@ -0,0 +1,398 @@
+use super::{annexb_nal_units, h264, hevc, jpeg, rbsp, BitReader};
+use crate::common::DataFormat;
+use std::collections::HashSet;
+
+const MIN_SIZE: u32 = 16;
+const MAX_H264_PPS_ID: u32 = 255;
+const MAX_HEVC_PPS_ID: u32 = 63;
+const MAX_H264_SLICE_TYPE: u32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_packet_size: usize,
+    pub max_nal_units: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_packet_size: 16 * 1024 * 1024,
+            max_nal_units: 1024,
+            max_width: 8192,
+            max_height: 8192,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidPacket {
+    Empty,
+    TooLarge(usize),
+    NoStartCode,
+    TooManyNalUnits,
+    // forbidden_zero_bit set, header cut off or nuh_temporal_id_plus1 of 0
+    BadNalHeader(u8),
+    // a slice too short for its header or with out of range header fields
+    BadSlice(u8),
+    BadParameterSet(u8),
+    // parses, but isn't 8 bit 4:2:0 as the decode sessions are created for
+    UnsupportedParameterSet(u8),
+    BadDimensions(u32, u32),
+    // slice data before any SPS was accepted
+    MissingParameterSet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitstreamError {
+    Invalid(InvalidPacket),
+    // an IDR picture without the parameter sets in its access unit, with its NAL unit type
+    KeyframeWithoutParameterSets(u8),
+    // a slice refers to a PPS id no PPS before it had
+    UnknownPps(u32),
+}
+
+impl From<InvalidPacket> for BitstreamError {
+    fn from(e: InvalidPacket) -> Self {
+        BitstreamError::Invalid(e)
+    }
+}
+
+// Checks a whole Annex-B H.264 or H.265 stream, e.g. an encoder's output before it is sent
+// on: the structure as Validator does without its packet limits, every slice refers to a
+// PPS sent before it and every IDR carries the parameter sets, as the encoders repeat
+// them. Other formats are checked by Validator alone.
+pub fn validate_bitstream(data: &[u8], format: DataFormat) -> Result<(), BitstreamError> {
+    let limits = Limits {
+        max_packet_size: usize::MAX,
+        max_nal_units: usize::MAX,
+        ..Default::default()
+    };
+    Validator::with_limits(format, limits).validate(data)?;
+    let hevc = match format {
+        DataFormat::H264 => false,
+        DataFormat::H265 => true,
+        _ => return Ok(()),
+    };
+    // the parameter sets since the last slice, VPS, SPS and PPS
+    let mut sent = [false; 3];
+    let mut pps_ids = HashSet::new();
+    for nal in annexb_nal_units(data) {
+        let header = if hevc {
+            hevc_header(nal)
+        } else {
+            h264_header(nal)
+        };
+        match header {
+            NalHeader::ParameterSet(i) => sent[i] = true,
+            NalHeader::Pps(id) => {
+                sent[2] = true;
+                pps_ids.insert(id);
+            }
+            NalHeader::Slice {
+                nal_type,
+                key,
+                first,
+                pps_id,
+            } => {
+                if !pps_ids.contains(&pps_id) {
+                    return Err(BitstreamError::UnknownPps(pps_id));
+                }
+                let needed = if hevc { &sent[..] } else { &sent[1..] };
+                if key && first && !needed.iter().all(|s| *s) {
+                    return Err(BitstreamError::KeyframeWithoutParameterSets(nal_type));
+                }
+                sent = [false; 3];
+            }
+            NalHeader::Other => {}
+        }
+    }
+    Ok(())
+}
+
+// Whether data starts a stream the way an encoder's first frame does: it passes
+// validate_bitstream and has an SPS and an IDR slice NAL unit of at least min_idr_len
+// bytes. False for formats other than H.264 and H.265.
+pub fn starts_stream(data: &[u8], format: DataFormat, min_idr_len: usize) -> bool {
+    let hevc = match format {
+        DataFormat::H264 => false,
+        DataFormat::H265 => true,
+        _ => return false,
+    };
+    if validate_bitstream(data, format).is_err() {
+        return false;
+    }
+    let (mut sps, mut idr) = (false, false);
+    for nal in annexb_nal_units(data) {
+        let header = if hevc {
+            hevc_header(nal)
+        } else {
+            h264_header(nal)
+        };
+        match header {
+            NalHeader::ParameterSet(1) => sps = true,
+            NalHeader::Slice { key: true, .. } => idr |= nal.len() >= min_idr_len,
+            _ => {}
+        }
+    }
+    sps && idr
+}
+
+// of a NAL unit Validator accepted
+enum NalHeader {
+    // VPS 0 or SPS 1
+    ParameterSet(usize),
+    Pps(u32),
+    Slice {
+        nal_type: u8,
+        key: bool,
+        // the first slice of its picture
+        first: bool,
+        pps_id: u32,
+    },
+    Other,
+}
+
+fn h264_header(nal: &[u8]) -> NalHeader {
+    let nal_type = h264::nal_unit_type(nal).unwrap_or(0);
+    match nal_type {
+        h264::NAL_SPS => NalHeader::ParameterSet(1),
+        h264::NAL_PPS => match h264::Pps::parse(nal) {
+            Ok(pps) => NalHeader::Pps(pps.pps_id),
+            Err(_) => NalHeader::Other,
+        },
+        1..=5 => {
+            let data = rbsp(&nal[1..]);
+            let mut r = BitReader::new(&data);
+            let first_mb = r.read_ue().unwrap_or(0);
+            r.read_ue();
+            NalHeader::Slice {
+                nal_type,
+                key: nal_type == 5,
+                first: first_mb == 0,
+                pps_id: r.read_ue().unwrap_or(0),
+            }
+        }
+        _ => NalHeader::Other,
+    }
+}
+
+fn hevc_header(nal: &[u8]) -> NalHeader {
+    let nal_type = hevc::nal_unit_type(nal).unwrap_or(0);
+    match nal_type {
+        hevc::NAL_VPS => NalHeader::ParameterSet(0),
+        hevc::NAL_SPS => NalHeader::ParameterSet(1),
+        hevc::NAL_PPS => match hevc::Pps::parse(nal) {
+            Ok(pps) => NalHeader::Pps(pps.pps_id),
+            Err(_) => NalHeader::Other,
+        },
+        0..=31 => {
+            let data = rbsp(&nal[2..]);
+            let mut r = BitReader::new(&data);
+            let irap = (16..=23).contains(&nal_type);
+            let first = r.read_bit().unwrap_or(false);
+            if irap {
+                // no_output_of_prior_pics_flag
+                r.skip_bits(1);
+            }
+            NalHeader::Slice {
+                nal_type,
+                key: nal_type == 19 || nal_type == 20,
+                first,
+                pps_id: r.read_ue().unwrap_or(0),
+            }
+        }
+        _ => NalHeader::Other,
+    }
+}
+
+// Structural checks on Annex-B packets before they are handed to a driver. The decoders
+// run it unless validation is disabled, the fuzz targets run it on arbitrary input.
+// MJPEG packets are single images, their errors carry the marker in place of the NAL
+// unit type: NoStartCode without an SOI, MissingParameterSet for a scan before the frame
+// header and BadSlice(SOS) for an image cut off before its scan.
+pub struct Validator {
+    format: DataFormat,
+    limits: Limits,
+    size: Option<(u32, u32)>,
+}
+
+impl Validator {
+    pub fn new(format: DataFormat) -> Self {
+        Self::with_limits(format, Limits::default())
+    }
+
+    pub fn with_limits(format: DataFormat, limits: Limits) -> Self {
+        Self {
+            format,
+            limits,
+            size: None,
+        }
+    }
+
+    // cropped size of the last accepted SPS or MJPEG frame header
+    pub fn size(&self) -> Option<(u32, u32)> {
+        self.size
+    }
+
+    // forget the accepted SPS, the next packet must carry one again
+    pub fn reset(&mut self) {
+        self.size = None;
+    }
+
+    // Only the size is checked for formats other than h264, h265 and MJPEG.
+    pub fn validate(&mut self, packet: &[u8]) -> Result<(), InvalidPacket> {
+        if packet.is_empty() {
+            return Err(InvalidPacket::Empty);
+        }
+        if packet.len() > self.limits.max_packet_size {
+            return Err(InvalidPacket::TooLarge(packet.len()));
+        }
+        if self.format == DataFormat::MJPEG {
+            self.size = Some(self.check_jpeg(packet)?);
+            return Ok(());
+        }
+        if self.format != DataFormat::H264 && self.format != DataFormat::H265 {
+            return Ok(());
+        }
+        if !packet.starts_with(&[0, 0, 1]) && !packet.starts_with(&[0, 0, 0, 1]) {
+            return Err(InvalidPacket::NoStartCode);
+        }
+        // applied only if the whole packet is valid
+        let mut size = self.size;
+        let mut count = 0;
+        for nal in annexb_nal_units(packet) {
+            count += 1;
+            if count > self.limits.max_nal_units {
+                return Err(InvalidPacket::TooManyNalUnits);
+            }
+            if self.format == DataFormat::H264 {
+                self.check_h264_nal(nal, &mut size)?;
+            } else {
+                self.check_hevc_nal(nal, &mut size)?;
+            }
+        }
+        if count == 0 {
+            return Err(InvalidPacket::Empty);
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    fn check_h264_nal(
+        &self,
+        nal: &[u8],
+        size: &mut Option<(u32, u32)>,
+    ) -> Result<(), InvalidPacket> {
+        let nal_type = h264::nal_unit_type(nal).unwrap_or(0);
+        if nal[0] & 0x80 != 0 {
+            return Err(InvalidPacket::BadNalHeader(nal_type));
+        }
+        match nal_type {
+            h264::NAL_SPS => {
+                let sps =
+                    h264::Sps::parse(nal).map_err(|_| InvalidPacket::BadParameterSet(nal_type))?;
+                if sps.chroma_format_idc != 1
+                    || sps.bit_depth_luma != 8
+                    || sps.bit_depth_chroma != 8
+                {
+                    return Err(InvalidPacket::UnsupportedParameterSet(nal_type));
+                }
+                *size = Some(self.check_size(sps.cropped_size())?);
+            }
+            1..=5 => {
+                if size.is_none() {
+                    return Err(InvalidPacket::MissingParameterSet);
+                }
+                let data = rbsp(&nal[1..]);
+                let mut r = BitReader::new(&data);
+                // first_mb_in_slice, slice_type, pic_parameter_set_id
+                let ok = r.read_ue().is_some()
+                    && r.read_ue().is_some_and(|t| t <= MAX_H264_SLICE_TYPE)
+                    && r.read_ue().is_some_and(|id| id <= MAX_H264_PPS_ID);
+                if !ok {
+                    return Err(InvalidPacket::BadSlice(nal_type));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn check_hevc_nal(
+        &self,
+        nal: &[u8],
+        size: &mut Option<(u32, u32)>,
+    ) -> Result<(), InvalidPacket> {
+        let nal_type = hevc::nal_unit_type(nal).unwrap_or(0);
+        if nal.len() < 2 || nal[0] & 0x80 != 0 || nal[1] & 0x07 == 0 {
+            return Err(InvalidPacket::BadNalHeader(nal_type));
+        }
+        match nal_type {
+            hevc::NAL_VPS => {
+                hevc::Vps::parse(nal).map_err(|_| InvalidPacket::BadParameterSet(nal_type))?;
+            }
+            hevc::NAL_SPS => {
+                let sps =
+                    hevc::Sps::parse(nal).map_err(|_| InvalidPacket::BadParameterSet(nal_type))?;
+                if sps.chroma_format_idc != 1
+                    || sps.bit_depth_luma != 8
+                    || sps.bit_depth_chroma != 8
+                {
+                    return Err(InvalidPacket::UnsupportedParameterSet(nal_type));
+                }
+                *size = Some(self.check_size(sps.cropped_size())?);
+            }
+            0..=31 => {
+                if size.is_none() {
+                    return Err(InvalidPacket::MissingParameterSet);
+                }
+                let data = rbsp(&nal[2..]);
+                let mut r = BitReader::new(&data);
+                // first_slice_segment_in_pic_flag, no_output_of_prior_pics_flag of IRAP
+                let irap = (16..=23).contains(&nal_type);
+                let ok = r.skip_bits(if irap { 2 } else { 1 }).is_some()
+                    && r.read_ue().is_some_and(|id| id <= MAX_HEVC_PPS_ID);
+                if !ok {
+                    return Err(InvalidPacket::BadSlice(nal_type));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn check_jpeg(&self, image: &[u8]) -> Result<(u32, u32), InvalidPacket> {
+        if !image.starts_with(&[0xff, jpeg::SOI]) {
+            return Err(InvalidPacket::NoStartCode);
+        }
+        let mut size = None;
+        for segment in jpeg::segments(image) {
+            match segment.marker {
+                marker if jpeg::is_start_of_frame(marker) => {
+                    let header = jpeg::FrameHeader::parse(marker, segment.payload)
+                        .map_err(|_| InvalidPacket::BadParameterSet(marker))?;
+                    if !header.is_baseline() || ![1, 3].contains(&header.components.len()) {
+                        return Err(InvalidPacket::UnsupportedParameterSet(marker));
+                    }
+                    size = Some(self.check_size((header.width, header.height))?);
+                }
+                jpeg::SOS => return size.ok_or(InvalidPacket::MissingParameterSet),
+                _ => {}
+            }
+        }
+        Err(InvalidPacket::BadSlice(jpeg::SOS))
+    }
+
+    fn check_size(&self, (width, height): (u32, u32)) -> Result<(u32, u32), InvalidPacket> {
+        if width < MIN_SIZE
+            || height < MIN_SIZE
+            || width > self.limits.max_width
+            || height > self.limits.max_height
+        {
+            return Err(InvalidPacket::BadDimensions(width, height));
+        }
+        Ok((width, height))
+    }
+}
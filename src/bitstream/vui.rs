@@ -0,0 +1,91 @@
+use super::BitReader;
+use serde_derive::{Deserialize, Serialize};
+
+const EXTENDED_SAR: u8 = 255;
+// the sample aspect ratios of aspect_ratio_idc 1 - 16, Table E-1 of both specs
+const SAR_TABLE: [(u16, u16); 16] = [
+    (1, 1),
+    (12, 11),
+    (10, 11),
+    (16, 11),
+    (40, 33),
+    (24, 11),
+    (20, 11),
+    (32, 11),
+    (80, 33),
+    (18, 11),
+    (15, 11),
+    (64, 33),
+    (160, 99),
+    (4, 3),
+    (3, 2),
+    (2, 1),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VideoSignal {
+    pub video_format: u8,
+    pub full_range: bool,
+    // colour_primaries, transfer_characteristics, matrix_coeffs
+    pub colour_description: Option<[u8; 3]>,
+}
+
+// The part of vui_parameters() h264 and hevc share, parsing stops after chroma_loc_info.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Vui {
+    pub aspect_ratio_idc: Option<u8>,
+    // only for aspect_ratio_idc 255
+    pub sar: Option<(u16, u16)>,
+    pub overscan_appropriate: Option<bool>,
+    pub video_signal: Option<VideoSignal>,
+    // chroma_sample_loc_type of the top and bottom field
+    pub chroma_loc: Option<(u32, u32)>,
+}
+
+impl Vui {
+    // The sample aspect ratio signaled, from the table or sar. None without
+    // aspect_ratio_info, for the unspecified idc 0 and the reserved ones.
+    pub fn sample_aspect_ratio(&self) -> Option<(u16, u16)> {
+        match self.aspect_ratio_idc? {
+            EXTENDED_SAR => self.sar,
+            idc @ 1..=16 => Some(SAR_TABLE[idc as usize - 1]),
+            _ => None,
+        }
+    }
+
+    pub(super) fn parse(r: &mut BitReader) -> Option<Self> {
+        let mut vui = Vui::default();
+        if r.read_bit()? {
+            let idc = r.read_bits(8)? as u8;
+            vui.aspect_ratio_idc = Some(idc);
+            if idc == EXTENDED_SAR {
+                vui.sar = Some((r.read_bits(16)? as u16, r.read_bits(16)? as u16));
+            }
+        }
+        if r.read_bit()? {
+            vui.overscan_appropriate = Some(r.read_bit()?);
+        }
+        if r.read_bit()? {
+            let video_format = r.read_bits(3)? as u8;
+            let full_range = r.read_bit()?;
+            let colour_description = if r.read_bit()? {
+                Some([
+                    r.read_bits(8)? as u8,
+                    r.read_bits(8)? as u8,
+                    r.read_bits(8)? as u8,
+                ])
+            } else {
+                None
+            };
+            vui.video_signal = Some(VideoSignal {
+                video_format,
+                full_range,
+                colour_description,
+            });
+        }
+        if r.read_bit()? {
+            vui.chroma_loc = Some((r.read_ue()?, r.read_ue()?));
+        }
+        Some(vui)
+    }
+}
@@ -0,0 +1,432 @@
+//! C API over the vram encoder and decoder, see include/hwcodec.h.
+//!
+//! Regenerate the header after changing this file:
+//! `cbindgen --config cbindgen.toml --output include/hwcodec.h`
+//! Build the library with `cargo rustc --release --features capi --crate-type cdylib`.
+
+use crate::{
+    common::HwcodecErrno,
+    vram::{
+        decode::{self, Decoder},
+        encode::{self, Encoder},
+        Available, DecodeContext, DynamicContext, EncodeContext,
+    },
+};
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void, CStr, CString},
+    ptr::null_mut,
+};
+
+/// Bumped on every incompatible change of the functions or structs in this header.
+/// Functions are only added within one version.
+pub const HWCODEC_CAPI_VERSION: u32 = 1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    log::debug!("capi: {}", message);
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Option<&'a str> {
+    if s.is_null() {
+        set_last_error(format!("{} is null", name));
+        return None;
+    }
+    match CStr::from_ptr(s).to_str() {
+        Ok(s) => Some(s),
+        Err(_) => {
+            set_last_error(format!("{} is not utf-8", name));
+            None
+        }
+    }
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    match CString::new(s) {
+        Ok(s) => s.into_raw(),
+        Err(_) => null_mut(),
+    }
+}
+
+/// Opaque encoder handle.
+pub struct HwcodecEncoder(Encoder);
+
+/// Opaque decoder handle.
+pub struct HwcodecDecoder(Decoder);
+
+/// `data` is only valid during the call, copy it to keep it.
+pub type HwcodecPacketCallback =
+    extern "C" fn(data: *const u8, len: usize, pts: i64, key: i32, user_data: *mut c_void);
+
+/// `texture` is an ID3D11Texture2D owned by the decoder, valid until the next
+/// hwcodec_decode or hwcodec_decoder_free on the same decoder.
+pub type HwcodecFrameCallback =
+    extern "C" fn(texture: *mut c_void, width: i32, height: i32, user_data: *mut c_void);
+
+/// `data` holds `height` rows of `stride` bytes of bgra, valid only during the call.
+pub type HwcodecBgraCallback =
+    extern "C" fn(data: *const u8, stride: i32, width: i32, height: i32, user_data: *mut c_void);
+
+/// The HWCODEC_CAPI_VERSION the library was built with, compare it to the header's.
+#[no_mangle]
+pub extern "C" fn hwcodec_capi_version() -> u32 {
+    HWCODEC_CAPI_VERSION
+}
+
+/// Message of the last failed call on the calling thread, or null. The string is owned
+/// by the library and valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn hwcodec_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| {
+        e.borrow()
+            .as_ref()
+            .map(|s| s.as_ptr())
+            .unwrap_or(std::ptr::null())
+    })
+}
+
+/// Static description of an HwcodecErrno value, never null and never freed.
+#[no_mangle]
+pub extern "C" fn hwcodec_error_string(code: i32) -> *const c_char {
+    use HwcodecErrno::*;
+    let s: &'static [u8] = match code {
+        0 => b"success\0",
+        x if x == HWCODEC_ERR_HEVC_COULD_NOT_FIND_POC as i32 => b"hevc could not find poc\0",
+        x if x == HWCODEC_ERR_DEVICE_LOST as i32 => b"device lost\0",
+        x if x == HWCODEC_ERR_SESSION_LOST as i32 => b"session lost\0",
+        x if x == HWCODEC_ERR_INPUT_ACCESS_DENIED as i32 => b"input access denied\0",
+        x if x == HWCODEC_ERR_INVALID_DATA as i32 => b"invalid data\0",
+        x if x == HWCODEC_ERR_RESET_REQUIRED as i32 => b"reset required\0",
+        x if x == HWCODEC_ERR_NEED_KEYFRAME as i32 => b"need keyframe\0",
+        x if x == HWCODEC_ERR_EXCEEDS_CAPS as i32 => b"exceeds decoder caps\0",
+        x if x == HWCODEC_ERR_NOT_ONE_FRAME as i32 => b"not one frame\0",
+        _ => b"error\0",
+    };
+    s.as_ptr() as _
+}
+
+/// Frees a string returned by this library, null is ignored.
+///
+/// # Safety
+/// `s` must come from this library and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Probes all encoders and decoders, returns the json of Available, `{"e": [..], "d": [..]}`.
+/// An element of "e" combined with the dynamic fields is the context of
+/// hwcodec_encoder_new, an element of "d" the context of hwcodec_decoder_new.
+/// Free the result with hwcodec_free_string. Null on failure.
+#[no_mangle]
+pub extern "C" fn hwcodec_available(
+    width: i32,
+    height: i32,
+    kbitrate: i32,
+    framerate: i32,
+    gop: i32,
+) -> *mut c_char {
+    let d = DynamicContext {
+        device: None,
+        width,
+        height,
+        kbitrate,
+        framerate,
+        gop,
+        ..Default::default()
+    };
+    let available = Available {
+        e: encode::available(d),
+        d: decode::available(),
+    };
+    match available.serialize() {
+        Ok(s) => into_c_string(s),
+        Err(_) => {
+            set_last_error("failed to serialize".to_owned());
+            null_mut()
+        }
+    }
+}
+
+/// `ctx` is the json of EncodeContext, `{"f": <element of "e">, "d": {"width": ..}}`.
+/// `device` is an ID3D11Device the input textures are created on, or null to create one
+/// on the adapter of the context. Free the encoder with hwcodec_encoder_free.
+///
+/// # Safety
+/// `ctx` must be a nul terminated string, `device` a valid ID3D11Device or null.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_encoder_new(
+    ctx: *const c_char,
+    device: *mut c_void,
+) -> *mut HwcodecEncoder {
+    let Some(ctx) = str_arg(ctx, "ctx") else {
+        return null_mut();
+    };
+    let mut ctx: EncodeContext = match serde_json::from_str(ctx) {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            set_last_error(format!("invalid encode context: {}", e));
+            return null_mut();
+        }
+    };
+    ctx.d.device = (!device.is_null()).then_some(device);
+    match Encoder::new(ctx) {
+        Ok(encoder) => Box::into_raw(Box::new(HwcodecEncoder(encoder))),
+        Err(_) => {
+            set_last_error("failed to create encoder".to_owned());
+            null_mut()
+        }
+    }
+}
+
+/// # Safety
+/// `encoder` must come from hwcodec_encoder_new and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_encoder_free(encoder: *mut HwcodecEncoder) {
+    if !encoder.is_null() {
+        drop(Box::from_raw(encoder));
+    }
+}
+
+/// Encodes an ID3D11Texture2D of the encoder's device, `callback` runs once per packet
+/// before this returns. Returns 0 or an HwcodecErrno value.
+///
+/// # Safety
+/// `encoder` must come from hwcodec_encoder_new, `texture` must be a valid texture.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_encode(
+    encoder: *mut HwcodecEncoder,
+    texture: *mut c_void,
+    ms: i64,
+    callback: HwcodecPacketCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(encoder) = encoder.as_mut() else {
+        set_last_error("encoder is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_COMMON as _;
+    };
+    match encoder.0.encode(texture, ms) {
+        Ok(frames) => {
+            for frame in frames.iter() {
+                callback(
+                    frame.data.as_ptr(),
+                    frame.data.len(),
+                    frame.pts,
+                    frame.key,
+                    user_data,
+                );
+            }
+            0
+        }
+        Err(e) => {
+            set_last_error(format!("encode failed: {}", e));
+            e
+        }
+    }
+}
+
+/// # Safety
+/// `encoder` must come from hwcodec_encoder_new.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_encoder_set_bitrate(
+    encoder: *mut HwcodecEncoder,
+    kbitrate: i32,
+) -> i32 {
+    let Some(encoder) = encoder.as_mut() else {
+        set_last_error("encoder is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_COMMON as _;
+    };
+    encoder.0.set_bitrate(kbitrate).err().unwrap_or(0)
+}
+
+/// # Safety
+/// `encoder` must come from hwcodec_encoder_new.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_encoder_set_framerate(
+    encoder: *mut HwcodecEncoder,
+    framerate: i32,
+) -> i32 {
+    let Some(encoder) = encoder.as_mut() else {
+        set_last_error("encoder is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_COMMON as _;
+    };
+    encoder.0.set_framerate(framerate).err().unwrap_or(0)
+}
+
+/// The next encoded frame is an IDR frame with parameter sets.
+///
+/// # Safety
+/// `encoder` must come from hwcodec_encoder_new.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_encoder_request_keyframe(encoder: *mut HwcodecEncoder) -> i32 {
+    let Some(encoder) = encoder.as_mut() else {
+        set_last_error("encoder is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_COMMON as _;
+    };
+    encoder.0.request_keyframe().err().unwrap_or(0)
+}
+
+/// Resets the encoder to textures of width x height after hwcodec_encode returned
+/// HWCODEC_ERR_RESET_REQUIRED. `callback` runs once per packet still buffered in the old
+/// session before this returns. Returns 0 or an HwcodecErrno value, the encoder can only
+/// be freed after an error.
+///
+/// # Safety
+/// `encoder` must come from hwcodec_encoder_new.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_encoder_reset(
+    encoder: *mut HwcodecEncoder,
+    width: i32,
+    height: i32,
+    callback: HwcodecPacketCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(encoder) = encoder.as_mut() else {
+        set_last_error("encoder is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_COMMON as _;
+    };
+    match encoder.0.reset(width, height) {
+        Ok(frames) => {
+            for frame in frames.iter() {
+                callback(
+                    frame.data.as_ptr(),
+                    frame.data.len(),
+                    frame.pts,
+                    frame.key,
+                    user_data,
+                );
+            }
+            0
+        }
+        Err(e) => {
+            set_last_error(format!("reset failed: {}", e));
+            e
+        }
+    }
+}
+
+/// `ctx` is the json of an element of "d" of hwcodec_available. `device` is the
+/// ID3D11Device the decoded textures are created on, or null to create one on the
+/// adapter of the context. Free the decoder with hwcodec_decoder_free.
+///
+/// # Safety
+/// `ctx` must be a nul terminated string, `device` a valid ID3D11Device or null.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_decoder_new(
+    ctx: *const c_char,
+    device: *mut c_void,
+) -> *mut HwcodecDecoder {
+    let Some(ctx) = str_arg(ctx, "ctx") else {
+        return null_mut();
+    };
+    let mut ctx: DecodeContext = match serde_json::from_str(ctx) {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            set_last_error(format!("invalid decode context: {}", e));
+            return null_mut();
+        }
+    };
+    ctx.device = (!device.is_null()).then_some(device);
+    match Decoder::new(ctx) {
+        Ok(decoder) => Box::into_raw(Box::new(HwcodecDecoder(decoder))),
+        Err(_) => {
+            set_last_error("failed to create decoder".to_owned());
+            null_mut()
+        }
+    }
+}
+
+/// # Safety
+/// `decoder` must come from hwcodec_decoder_new and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_decoder_free(decoder: *mut HwcodecDecoder) {
+    if !decoder.is_null() {
+        drop(Box::from_raw(decoder));
+    }
+}
+
+/// Decodes one Annex-B packet, `callback` runs once per decoded frame before this
+/// returns. Returns 0 or an HwcodecErrno value.
+///
+/// # Safety
+/// `decoder` must come from hwcodec_decoder_new, `data` must point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_decode(
+    decoder: *mut HwcodecDecoder,
+    data: *const u8,
+    len: usize,
+    callback: HwcodecFrameCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(decoder) = decoder.as_mut() else {
+        set_last_error("decoder is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_COMMON as _;
+    };
+    if data.is_null() {
+        set_last_error("data is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _;
+    }
+    let packet = std::slice::from_raw_parts(data, len);
+    match decoder.0.decode(packet) {
+        Ok(frames) => {
+            for frame in frames.iter() {
+                callback(frame.texture, frame.width, frame.height, user_data);
+            }
+            0
+        }
+        Err(e) => {
+            set_last_error(format!("decode failed: {}", e));
+            e
+        }
+    }
+}
+
+/// Like hwcodec_decode but reads each frame back into memory, slower and meant for
+/// tools rather than rendering. Streams signalling bt.709, bt.2020 or full range in
+/// their VUI are converted accordingly. Returns 0 or an HwcodecErrno value.
+///
+/// # Safety
+/// `decoder` must come from hwcodec_decoder_new, `data` must point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hwcodec_decode_bgra(
+    decoder: *mut HwcodecDecoder,
+    data: *const u8,
+    len: usize,
+    callback: HwcodecBgraCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(decoder) = decoder.as_mut() else {
+        set_last_error("decoder is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_COMMON as _;
+    };
+    if data.is_null() {
+        set_last_error("data is null".to_owned());
+        return HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _;
+    }
+    let packet = std::slice::from_raw_parts(data, len);
+    match decoder.0.decode_bgra(packet) {
+        Ok(frames) => {
+            for frame in frames.iter() {
+                callback(
+                    frame.data.as_ptr(),
+                    frame.stride,
+                    frame.width,
+                    frame.height,
+                    user_data,
+                );
+            }
+            0
+        }
+        Err(e) => {
+            set_last_error(format!("decode failed: {}", e));
+            e
+        }
+    }
+}
@@ -3,17 +3,527 @@
 #![allow(non_snake_case)]
 
 use serde_derive::{Deserialize, Serialize};
+use std::{borrow::Borrow, ops::Deref, sync::Arc, time::Duration};
 include!(concat!(env!("OUT_DIR"), "/common_ffi.rs"));
 
 pub(crate) const DATA_H264_720P: &[u8] = include_bytes!("res/720p.h264");
 pub(crate) const DATA_H265_720P: &[u8] = include_bytes!("res/720p.h265");
+// 4:2:0 colour bars: white, yellow, cyan, green, magenta, red, blue, black
+pub(crate) const DATA_MJPEG_720P: &[u8] = include_bytes!("res/720p.jpg");
 
+// Every variant deserializes whatever features are enabled, Encoder::new and Decoder::new
+// fail for a driver whose feature (nv, amf, mfx, vram-ffmpeg) is disabled.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Driver {
     NV,
     AMF,
     MFX,
     FFMPEG,
+    // a driver registered with vram::backend, by name
+    CUSTOM(String),
+}
+
+impl Default for AqMode {
+    fn default() -> Self {
+        AqMode::AQ_DEFAULT
+    }
+}
+
+impl Default for ChromaLocation {
+    fn default() -> Self {
+        ChromaLocation::CHROMA_LOC_LEFT
+    }
+}
+
+impl Default for EntropyCoding {
+    fn default() -> Self {
+        EntropyCoding::ENTROPY_CODING_DEFAULT
+    }
+}
+
+impl Default for ColorConvert {
+    fn default() -> Self {
+        ColorConvert::COLOR_CONVERT_AUTO
+    }
+}
+
+impl EncodeCaps {
+    pub fn has(&self, cap: EncodeCapability) -> bool {
+        self.flags & cap as u32 != 0
+    }
+}
+
+// the FrameFlag bits of an encoded frame, the LTR slot is in EncodeFrame::ltr_slot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameFlags(pub u32);
+
+impl FrameFlags {
+    pub fn has(&self, flag: FrameFlag) -> bool {
+        self.0 & flag as u32 != 0
+    }
+
+    pub fn insert(&mut self, flag: FrameFlag) {
+        self.0 |= flag as u32;
+    }
+
+    pub fn remove(&mut self, flag: FrameFlag) {
+        self.0 &= !(flag as u32);
+    }
+}
+
+impl From<FrameFlag> for FrameFlags {
+    fn from(flag: FrameFlag) -> Self {
+        Self(flag as u32)
+    }
+}
+
+// The bytes of an EncodeFrame, shared by its clones, so a frame handed to several
+// consumers is cloned without copying them. It derefs to [u8] for reading, make_mut
+// copies the bytes first when another clone holds them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FrameData(Arc<[u8]>);
+
+impl FrameData {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn make_mut(&mut self) -> &mut [u8] {
+        if Arc::get_mut(&mut self.0).is_none() {
+            self.0 = Arc::from(&self.0[..]);
+        }
+        Arc::get_mut(&mut self.0).unwrap()
+    }
+}
+
+impl Default for FrameData {
+    fn default() -> Self {
+        Self(Arc::from(&[][..]))
+    }
+}
+
+impl Deref for FrameData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for FrameData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for FrameData {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for FrameData {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data.into())
+    }
+}
+
+impl From<&[u8]> for FrameData {
+    fn from(data: &[u8]) -> Self {
+        Self(data.into())
+    }
+}
+
+impl FromIterator<u8> for FrameData {
+    fn from_iter<I: IntoIterator<Item = u8>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl PartialEq<[u8]> for FrameData {
+    fn eq(&self, other: &[u8]) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl DecodeCaps {
+    pub fn has_profile(&self, profile: DecodeProfile) -> bool {
+        self.profiles & profile as u32 != 0
+    }
+
+    pub fn has_bit_depth(&self, bits: u32) -> bool {
+        bits < u32::BITS && self.bitDepths & (1 << bits) != 0
+    }
+
+    // Whether a decoder of the descriptor's format with these caps takes its streams.
+    // Unknown profiles are assumed supported and unknown bit depths to be 8 bit only.
+    pub fn supports(&self, descriptor: &CodecDescriptor) -> bool {
+        let bit_depth = if self.bitDepths == 0 {
+            descriptor.bit_depth == 8
+        } else {
+            self.has_bit_depth(descriptor.bit_depth)
+        };
+        let profile = match descriptor.decode_profile() {
+            Some(profile) if self.profiles != 0 => self.has_profile(profile),
+            _ => true,
+        };
+        descriptor.chroma == ChromaFormat::Yuv420 && bit_depth && profile
+    }
+}
+
+// The profiles of CodecDescriptor, serialized by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CodecProfile {
+    // formats without profiles, e.g. MJPEG
+    Unspecified,
+    H264ConstrainedBaseline,
+    H264Main,
+    H264High,
+    H264High10,
+    HevcMain,
+    HevcMain10,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ChromaFormat {
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+// A stream finer than its DataFormat, for negotiating with peers, e.g. H.264 High or
+// constrained baseline, H.265 Main or Main10. From<DataFormat> gives the streams the
+// encoders produce, 8 bit 4:2:0 High and Main. Serialized as a struct of these fields
+// with the enums by name, new profiles are only ever added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CodecDescriptor {
+    pub format: DataFormat,
+    pub profile: CodecProfile,
+    pub bit_depth: u32,
+    pub chroma: ChromaFormat,
+}
+
+impl CodecDescriptor {
+    // the profiles of format at their bit depths, 4:2:0 as the decoders take
+    pub fn known(format: DataFormat) -> Vec<Self> {
+        let profiles: &[(CodecProfile, u32)] = match format {
+            DataFormat::H264 => &[
+                (CodecProfile::H264ConstrainedBaseline, 8),
+                (CodecProfile::H264Main, 8),
+                (CodecProfile::H264High, 8),
+                (CodecProfile::H264High10, 10),
+            ],
+            DataFormat::H265 => &[(CodecProfile::HevcMain, 8), (CodecProfile::HevcMain10, 10)],
+            _ => &[(CodecProfile::Unspecified, 8)],
+        };
+        profiles
+            .iter()
+            .map(|(profile, bit_depth)| Self {
+                format,
+                profile: *profile,
+                bit_depth: *bit_depth,
+                chroma: ChromaFormat::Yuv420,
+            })
+            .collect()
+    }
+
+    // the bit of DecodeCaps.profiles, None for Unspecified
+    pub fn decode_profile(&self) -> Option<DecodeProfile> {
+        match self.profile {
+            CodecProfile::Unspecified => None,
+            CodecProfile::H264ConstrainedBaseline => {
+                Some(DecodeProfile::DECODE_PROFILE_H264_BASELINE)
+            }
+            CodecProfile::H264Main => Some(DecodeProfile::DECODE_PROFILE_H264_MAIN),
+            CodecProfile::H264High => Some(DecodeProfile::DECODE_PROFILE_H264_HIGH),
+            CodecProfile::H264High10 => Some(DecodeProfile::DECODE_PROFILE_H264_HIGH10),
+            CodecProfile::HevcMain => Some(DecodeProfile::DECODE_PROFILE_HEVC_MAIN),
+            CodecProfile::HevcMain10 => Some(DecodeProfile::DECODE_PROFILE_HEVC_MAIN10),
+        }
+    }
+
+    // Baseline has no CABAC, formats other than H.264 take only the default
+    pub fn supports_entropy_coding(&self, entropy_coding: EntropyCoding) -> bool {
+        match (self.format, entropy_coding) {
+            (_, EntropyCoding::ENTROPY_CODING_DEFAULT) => true,
+            (DataFormat::H264, EntropyCoding::ENTROPY_CODING_CABAC) => {
+                self.profile != CodecProfile::H264ConstrainedBaseline
+            }
+            (DataFormat::H264, EntropyCoding::ENTROPY_CODING_CAVLC) => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<DataFormat> for CodecDescriptor {
+    fn from(format: DataFormat) -> Self {
+        let profile = match format {
+            DataFormat::H264 => CodecProfile::H264High,
+            DataFormat::H265 => CodecProfile::HevcMain,
+            _ => CodecProfile::Unspecified,
+        };
+        Self {
+            format,
+            profile,
+            bit_depth: 8,
+            chroma: ChromaFormat::Yuv420,
+        }
+    }
+}
+
+impl From<CodecDescriptor> for DataFormat {
+    fn from(descriptor: CodecDescriptor) -> Self {
+        descriptor.format
+    }
+}
+
+impl Default for MemoryInfo {
+    fn default() -> Self {
+        MemoryInfo {
+            estimated: 0,
+            allocated: 0,
+        }
+    }
+}
+
+impl Default for RuntimeInfo {
+    fn default() -> Self {
+        RuntimeInfo {
+            runtime: [0; 32],
+            implementation: [0; 64],
+            apiMajor: 0,
+            apiMinor: 0,
+            bframes: 0,
+        }
+    }
+}
+
+impl Default for DriverProbe {
+    fn default() -> Self {
+        DriverProbe {
+            name: [0; 32],
+            installed: [0; 32],
+            required: [0; 32],
+            tooOld: 0,
+        }
+    }
+}
+
+impl Default for AdapterDesc {
+    fn default() -> Self {
+        AdapterDesc {
+            description: [0; 256],
+            vendorId: 0,
+            deviceId: 0,
+            dedicatedVideoMemory: 0,
+            sharedSystemMemory: 0,
+            integrated: 0,
+            software: 0,
+        }
+    }
+}
+
+impl Default for GpuTiming {
+    fn default() -> Self {
+        GpuTiming {
+            frame: 0,
+            userData: 0,
+            convertMs: 0.0,
+            encodeMs: 0.0,
+        }
+    }
+}
+
+impl HwcodecErrno {
+    // the codec is unusable, pause, run available() again and recreate it
+    pub fn is_codec_lost(err: i32) -> bool {
+        err == HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as i32
+            || err == HwcodecErrno::HWCODEC_ERR_SESSION_LOST as i32
+    }
+
+    // the codec is fine but the input is stale, skip the frame until the capture is recreated
+    pub fn is_input_lost(err: i32) -> bool {
+        err == HwcodecErrno::HWCODEC_ERR_INPUT_ACCESS_DENIED as i32
+    }
+
+    // nothing was encoded, the input no longer matches the session, see Encoder::reset
+    pub fn is_reset_required(err: i32) -> bool {
+        err == HwcodecErrno::HWCODEC_ERR_RESET_REQUIRED as i32
+    }
+
+    // the decoder discards packets until the next IDR, see DecodeContext::conceal_errors
+    pub fn is_keyframe_needed(err: i32) -> bool {
+        err == HwcodecErrno::HWCODEC_ERR_NEED_KEYFRAME as i32
+    }
+
+    // the stream is beyond the decoder, see Decoder::exceeded_caps
+    pub fn is_caps_exceeded(err: i32) -> bool {
+        err == HwcodecErrno::HWCODEC_ERR_EXCEEDS_CAPS as i32
+    }
+
+    // the session broke DynamicContext::one_in_one_out, see Encoder::encode_one
+    pub fn is_not_one_frame(err: i32) -> bool {
+        err == HwcodecErrno::HWCODEC_ERR_NOT_ONE_FRAME as i32
+    }
+}
+
+// h264 bits per pixel at QP_REFERENCE for mixed desktop content
+const BPP_AT_QP_REFERENCE: f32 = 0.1;
+const QP_REFERENCE: f32 = 26.0;
+// the bitrate halves every 6 qp
+const QP_PER_OCTAVE: f32 = 6.0;
+pub const MAX_QP: f32 = 51.0;
+
+fn bpp_at_reference(format: DataFormat) -> f32 {
+    match format {
+        DataFormat::H264 | DataFormat::VP8 => BPP_AT_QP_REFERENCE,
+        // ~30% smaller at the same quality
+        DataFormat::H265 | DataFormat::VP9 | DataFormat::AV1 => BPP_AT_QP_REFERENCE * 0.7,
+        // intra only, not encoded by this crate
+        DataFormat::MJPEG => BPP_AT_QP_REFERENCE * 8.0,
+    }
+}
+
+// Rough average QP a rate controlled encoder reaches at kbitrate, on the h264/h265 scale
+// 0 - 51 for every format. Only an estimate for display, real encodes vary with content
+// and driver. Non-increasing in kbitrate.
+pub fn estimate_qp(
+    format: DataFormat,
+    width: i32,
+    height: i32,
+    framerate: i32,
+    kbitrate: i32,
+) -> f32 {
+    let pixels_per_second = width.max(1) as f32 * height.max(1) as f32 * framerate.max(1) as f32;
+    let bpp = kbitrate.max(1) as f32 * 1000.0 / pixels_per_second;
+    let qp = QP_REFERENCE - QP_PER_OCTAVE * (bpp / bpp_at_reference(format)).log2();
+    qp.clamp(0.0, MAX_QP)
+}
+
+// inverse of estimate_qp, non-increasing in qp
+pub fn estimate_kbitrate(
+    format: DataFormat,
+    width: i32,
+    height: i32,
+    framerate: i32,
+    qp: f32,
+) -> i32 {
+    let pixels_per_second = width.max(1) as f32 * height.max(1) as f32 * framerate.max(1) as f32;
+    let qp = qp.clamp(0.0, MAX_QP);
+    let bpp = bpp_at_reference(format) * 2f32.powf((QP_REFERENCE - qp) / QP_PER_OCTAVE);
+    (bpp * pixels_per_second / 1000.0).round().max(1.0) as i32
+}
+
+// Exact below 128 us, above in 64 buckets per power of two so a bucket spans at most 1/64
+// of its durations. Those over 2^32 us, about 71 minutes, share the last one.
+const LATENCY_EXACT_US: u64 = 128;
+const LATENCY_SUB_BUCKETS: u64 = 64;
+const LATENCY_MAX_US: u64 = 1 << 32;
+const LATENCY_BUCKETS: usize = (LATENCY_EXACT_US + (32 - 7) * LATENCY_SUB_BUCKETS) as usize + 1;
+
+// Durations of encode calls, for the tail latency an average hides
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: vec![0; LATENCY_BUCKETS],
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn bucket(d: Duration) -> usize {
+        let us = d.as_micros() as u64;
+        if us < LATENCY_EXACT_US {
+            return us as usize;
+        }
+        if us >= LATENCY_MAX_US {
+            return LATENCY_BUCKETS - 1;
+        }
+        // 7 for [128, 256)
+        let octave = 63 - us.leading_zeros() as u64;
+        let sub = (us >> (octave - 6)) - LATENCY_SUB_BUCKETS;
+        (LATENCY_EXACT_US + (octave - 7) * LATENCY_SUB_BUCKETS + sub) as usize
+    }
+
+    // exclusive
+    fn upper_bound(bucket: usize) -> Duration {
+        let bucket = bucket as u64;
+        if bucket < LATENCY_EXACT_US {
+            return Duration::from_micros(bucket + 1);
+        }
+        let octave = (bucket - LATENCY_EXACT_US) / LATENCY_SUB_BUCKETS + 7;
+        if octave >= 32 {
+            return Duration::MAX;
+        }
+        let sub = (bucket - LATENCY_EXACT_US) % LATENCY_SUB_BUCKETS;
+        Duration::from_micros((LATENCY_SUB_BUCKETS + sub + 1) << (octave - 6))
+    }
+
+    pub fn record(&mut self, d: Duration) {
+        self.buckets[Self::bucket(d)] += 1;
+        self.count += 1;
+        self.total += d;
+        self.max = self.max.max(d);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64)
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    // The smallest duration at least p percent of the recorded ones don't exceed, rounded
+    // up to the end of its bucket and never above max. Zero when nothing was recorded.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((p.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Self::upper_bound(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn p50(&self) -> Duration {
+        self.percentile(50.0)
+    }
+
+    pub fn p95(&self) -> Duration {
+        self.percentile(95.0)
+    }
+
+    pub fn p99(&self) -> Duration {
+        self.percentile(99.0)
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
 }
 
 #[cfg(any(windows, target_os = "linux"))]
@@ -31,13 +541,24 @@ pub(crate) fn supported_gpu(_encode: bool) -> (bool, bool, bool) {
     unsafe {
         #[cfg(windows)]
         {
+            // a driver compiled out can't be checked, ffmpeg tries it as without vram
             #[cfg(feature = "vram")]
-            return (
-                _encode && crate::vram::nv::nv_encode_driver_support() == 0
-                    || !_encode && crate::vram::nv::nv_decode_driver_support() == 0,
-                crate::vram::amf::amf_driver_support() == 0,
-                crate::vram::mfx::mfx_driver_support() == 0,
-            );
+            {
+                #[cfg(feature = "nv")]
+                let nv = _encode && crate::vram::nv::encode_driver_support()
+                    || !_encode && crate::vram::nv::decode_driver_support();
+                #[cfg(not(feature = "nv"))]
+                let nv = true;
+                #[cfg(feature = "amf")]
+                let amf = crate::vram::amf::driver_support();
+                #[cfg(not(feature = "amf"))]
+                let amf = true;
+                #[cfg(feature = "mfx")]
+                let intel = crate::vram::mfx::driver_support();
+                #[cfg(not(feature = "mfx"))]
+                let intel = true;
+                return (nv, amf, intel);
+            }
             #[cfg(not(feature = "vram"))]
             return (true, true, true);
         }
@@ -53,6 +574,170 @@ pub(crate) fn supported_gpu(_encode: bool) -> (bool, bool, bool) {
     }
 }
 
+// The runtime behind a driver's presence check, with the versions as the vendor writes
+// them. The check fails for a runtime older than required, see too_old.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DriverInfo {
+    // e.g. "NVIDIA driver" or "AMF runtime"
+    pub name: String,
+    // e.g. "531.61", None when the runtime isn't found
+    pub installed: Option<String>,
+    // the oldest version hwcodec works with, None for no minimum
+    pub required: Option<String>,
+    // installed is older than required, which is why it's not supported
+    pub too_old: bool,
+    // the check passed, available() reports the driver's adapters
+    pub supported: bool,
+}
+
+impl DriverInfo {
+    pub(crate) fn new(probe: &DriverProbe, supported: bool) -> Self {
+        let string = |chars: &[std::os::raw::c_char]| {
+            let bytes: Vec<u8> = chars
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as u8)
+                .collect();
+            Some(String::from_utf8_lossy(&bytes).into_owned()).filter(|s| !s.is_empty())
+        };
+        Self {
+            name: string(&probe.name).unwrap_or_default(),
+            installed: string(&probe.installed),
+            required: string(&probe.required),
+            too_old: probe.tooOld != 0,
+            supported,
+        }
+    }
+}
+
+// e.g. "driver too old: have 456.71, need 522.25" or "AMF runtime not found"
+impl std::fmt::Display for DriverInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.too_old {
+            let unknown = "unknown";
+            return write!(
+                f,
+                "driver too old: have {}, need {}",
+                self.installed.as_deref().unwrap_or(unknown),
+                self.required.as_deref().unwrap_or(unknown)
+            );
+        }
+        match &self.installed {
+            None => write!(f, "{} not found", self.name),
+            Some(installed) if self.supported => write!(f, "{} {}", self.name, installed),
+            Some(installed) => write!(f, "{} {}, not usable", self.name, installed),
+        }
+    }
+}
+
+// The versions found by the driver's presence check, probed once per process like
+// available() does until vram::invalidate_driver_support. None for drivers without such a
+// check, FFMPEG and CUSTOM, and for those compiled out.
+pub fn driver_info(driver: Driver) -> Option<DriverInfo> {
+    match driver {
+        #[cfg(all(windows, feature = "vram", feature = "nv"))]
+        Driver::NV => Some(crate::vram::nv::encode_driver_info()),
+        #[cfg(all(windows, feature = "vram", feature = "amf"))]
+        Driver::AMF => Some(crate::vram::amf::driver_info()),
+        #[cfg(all(windows, feature = "vram", feature = "mfx"))]
+        Driver::MFX => Some(crate::vram::mfx::driver_info()),
+        _ => None,
+    }
+}
+
+// The adapter behind a luid of FeatureContext or DecodeContext, as DXGI reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterInfo {
+    pub luid: i64,
+    // e.g. "NVIDIA GeForce RTX 4090"
+    pub description: String,
+    // ADAPTER_VENDOR_UNKNOWN for vendors other than the three, see vendor_id
+    pub vendor: AdapterVendor,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    // bytes
+    pub dedicated_video_memory: u64,
+    pub shared_system_memory: u64,
+    // works in system memory, the integrated gpu of a laptop with a discrete one
+    pub integrated: bool,
+    // e.g. the Microsoft Basic Render Driver, which no driver encodes or decodes on
+    pub software: bool,
+}
+
+// None if no adapter has luid, e.g. it was removed or its driver restarted, which gives
+// it a new one. Creates a device on the adapter, keep the result rather than calling it
+// per frame. Always None on other platforms than Windows, which have no luids.
+pub fn adapter_for_luid(luid: i64) -> Option<AdapterInfo> {
+    #[cfg(windows)]
+    {
+        extern "C" {
+            fn hwcodec_adapter_info(luid: i64, desc: *mut AdapterDesc) -> i32;
+        }
+        let mut desc = AdapterDesc::default();
+        if unsafe { hwcodec_adapter_info(luid, &mut desc) } != 0 {
+            return None;
+        }
+        let description = unsafe { std::ffi::CStr::from_ptr(desc.description.as_ptr()) };
+        let vendor = match desc.vendorId {
+            id if id == AdapterVendor::ADAPTER_VENDOR_AMD as u32 => {
+                AdapterVendor::ADAPTER_VENDOR_AMD
+            }
+            id if id == AdapterVendor::ADAPTER_VENDOR_INTEL as u32 => {
+                AdapterVendor::ADAPTER_VENDOR_INTEL
+            }
+            id if id == AdapterVendor::ADAPTER_VENDOR_NVIDIA as u32 => {
+                AdapterVendor::ADAPTER_VENDOR_NVIDIA
+            }
+            _ => AdapterVendor::ADAPTER_VENDOR_UNKNOWN,
+        };
+        Some(AdapterInfo {
+            luid,
+            description: description.to_string_lossy().into_owned(),
+            vendor,
+            vendor_id: desc.vendorId,
+            device_id: desc.deviceId,
+            dedicated_video_memory: desc.dedicatedVideoMemory.max(0) as u64,
+            shared_system_memory: desc.sharedSystemMemory.max(0) as u64,
+            integrated: desc.integrated != 0,
+            software: desc.software != 0,
+        })
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = luid;
+        None
+    }
+}
+
+// The luid of the adapter an ID3D11Device was created on, e.g. the one of a desktop
+// duplication, to find the encoders on it. None for null or something else than a
+// device, and on other platforms than Windows.
+pub fn luid_of_device(d3d11_device: *mut std::ffi::c_void) -> Option<i64> {
+    #[cfg(windows)]
+    {
+        extern "C" {
+            fn hwcodec_device_luid(device: *mut std::ffi::c_void, luid: *mut i64) -> i32;
+        }
+        let mut luid = 0;
+        match unsafe { hwcodec_device_luid(d3d11_device, &mut luid) } {
+            0 => Some(luid),
+            _ => None,
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = d3d11_device;
+        None
+    }
+}
+
+// A luid names one adapter until reboot or until its driver restarts, so equal ones are
+// the same adapter and textures of one open on the other's devices without a copy
+// through the cpu. 0 is the luid of contexts not tested yet and matches nothing.
+pub fn same_adapter(a: i64, b: i64) -> bool {
+    a != 0 && a == b
+}
+
 #[cfg(target_os = "macos")]
 pub(crate) fn get_video_toolbox_codec_support() -> (bool, bool, bool, bool) {
     use std::ffi::c_void;
@@ -4,14 +4,15 @@ use crate::common::TEST_TIMEOUT_MS;
 use crate::ffmpeg::{init_av_log, AVHWDeviceType::*};
 
 use crate::{
-    common::DataFormat::*,
+    bitstream::validate::Validator,
+    common::{DataFormat::*, HwcodecErrno},
     ffmpeg::{AVHWDeviceType, AVPixelFormat},
     ffmpeg_ram::{
-        ffmpeg_ram_decode, ffmpeg_ram_free_decoder, ffmpeg_ram_new_decoder, CodecInfo,
-        AV_NUM_DATA_POINTERS,
+        encode::Encoder, ffmpeg_ram_decode, ffmpeg_ram_free_decoder, ffmpeg_ram_new_decoder,
+        CodecInfo, AV_NUM_DATA_POINTERS,
     },
 };
-use log::error;
+use log::{debug, error, warn};
 use std::{
     ffi::{c_void, CString},
     os::raw::c_int,
@@ -27,6 +28,19 @@ pub struct DecodeContext {
     pub thread_count: i32,
 }
 
+// What a hardware decoder does when it can't decode, e.g. a profile the gpu claims but fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SoftwareFallback {
+    // the error is returned
+    #[default]
+    Disabled,
+    // the first failed decode switches to ffmpeg's software decoder and decodes the
+    // packet again, the following frames may be broken until the next keyframe
+    OnError,
+    // switches right away
+    Force,
+}
+
 pub struct DecodeFrame {
     pub pixfmt: AVPixelFormat,
     pub width: i32,
@@ -36,6 +50,38 @@ pub struct DecodeFrame {
     pub key: bool,
 }
 
+impl DecodeFrame {
+    // The y and uv planes packed without padding, rows of width bytes. Software decoders
+    // output yuv420p, its u and v planes are interleaved. None for other formats.
+    pub fn to_nv12(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let chroma_width = width.div_ceil(2);
+        let chroma_height = height.div_ceil(2);
+        let rows = |plane: usize, len: usize, count: usize| {
+            let linesize = *self.linesize.get(plane)? as usize;
+            let data = self.data.get(plane)?;
+            if linesize < len || count == 0 || data.len() < linesize * (count - 1) + len {
+                return None;
+            }
+            Some((0..count).map(move |r| &data[r * linesize..r * linesize + len]))
+        };
+        let y: Vec<u8> = rows(0, width, height)?.flatten().copied().collect();
+        let uv = match self.pixfmt {
+            AVPixelFormat::AV_PIX_FMT_NV12 => rows(1, chroma_width * 2, chroma_height)?
+                .flatten()
+                .copied()
+                .collect(),
+            AVPixelFormat::AV_PIX_FMT_YUV420P => rows(1, chroma_width, chroma_height)?
+                .zip(rows(2, chroma_width, chroma_height)?)
+                .flat_map(|(u, v)| u.iter().zip(v).flat_map(|(u, v)| [*u, *v]))
+                .collect(),
+            _ => return None,
+        };
+        Some((y, uv))
+    }
+}
+
 impl std::fmt::Display for DecodeFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = String::from("data:");
@@ -58,6 +104,11 @@ impl std::fmt::Display for DecodeFrame {
 pub struct Decoder {
     codec: *mut c_void,
     frames: *mut Vec<DecodeFrame>,
+    validator: Option<Validator>,
+    fallback: SoftwareFallback,
+    #[cfg(feature = "testutil")]
+    fail_next_decode: bool,
+    // device_type is AV_HWDEVICE_TYPE_NONE after a fallback
     pub ctx: DecodeContext,
 }
 
@@ -67,41 +118,142 @@ unsafe impl Sync for Decoder {}
 impl Decoder {
     pub fn new(ctx: DecodeContext) -> Result<Self, ()> {
         init_av_log();
-        unsafe {
-            let codec = ffmpeg_ram_new_decoder(
+        let codec = Self::new_codec(&ctx)?;
+        Ok(Decoder {
+            codec,
+            frames: Box::into_raw(Box::new(Vec::<DecodeFrame>::new())),
+            validator: Self::new_validator(&ctx),
+            fallback: SoftwareFallback::Disabled,
+            #[cfg(feature = "testutil")]
+            fail_next_decode: false,
+            ctx,
+        })
+    }
+
+    fn new_codec(ctx: &DecodeContext) -> Result<*mut c_void, ()> {
+        let codec = unsafe {
+            ffmpeg_ram_new_decoder(
                 CString::new(ctx.name.as_str()).map_err(|_| ())?.as_ptr(),
                 ctx.device_type as _,
                 ctx.thread_count,
                 Some(Decoder::callback),
-            );
+            )
+        };
+        if codec.is_null() {
+            return Err(());
+        }
+        Ok(codec)
+    }
 
-            if codec.is_null() {
-                return Err(());
-            }
+    // Fails for a codec without a software decoder, then the setting is unchanged
+    pub fn set_software_fallback(&mut self, fallback: SoftwareFallback) -> Result<(), ()> {
+        if fallback != SoftwareFallback::Disabled && Self::software_context(&self.ctx).is_none() {
+            return Err(());
+        }
+        self.fallback = fallback;
+        if fallback == SoftwareFallback::Force && !self.is_software() {
+            self.switch_to_software()?;
+        }
+        Ok(())
+    }
+
+    // whether frames come from ffmpeg's software decoder, from the start or after a fallback
+    pub fn is_software(&self) -> bool {
+        self.ctx.device_type == AVHWDeviceType::AV_HWDEVICE_TYPE_NONE
+    }
 
-            Ok(Decoder {
-                codec,
-                frames: Box::into_raw(Box::new(Vec::<DecodeFrame>::new())),
-                ctx,
-            })
+    // the next hardware decode fails as if ffmpeg had, for testing the fallback
+    #[cfg(feature = "testutil")]
+    #[doc(hidden)]
+    pub fn debug_fail_next_decode(&mut self) {
+        self.fail_next_decode = true;
+    }
+
+    fn software_context(ctx: &DecodeContext) -> Option<DecodeContext> {
+        let soft = CodecInfo::soft();
+        let info = match Encoder::format_from_name(ctx.name.clone()).ok()? {
+            H264 => soft.h264,
+            H265 => soft.h265,
+            VP8 => soft.vp8,
+            VP9 => soft.vp9,
+            AV1 => soft.av1,
+            MJPEG => None,
+        }?;
+        Some(DecodeContext {
+            name: info.name,
+            device_type: info.hwdevice,
+            thread_count: ctx.thread_count,
+        })
+    }
+
+    fn switch_to_software(&mut self) -> Result<(), ()> {
+        let ctx = Self::software_context(&self.ctx).ok_or(())?;
+        let codec = Self::new_codec(&ctx)?;
+        warn!(
+            "decoder {} ({:?}) falls back to software",
+            self.ctx.name, self.ctx.device_type
+        );
+        unsafe {
+            ffmpeg_ram_free_decoder(self.codec);
         }
+        self.codec = codec;
+        self.ctx = ctx;
+        Ok(())
+    }
+
+    // Enabled by default, packets failing the checks return HWCODEC_ERR_INVALID_DATA
+    // without reaching ffmpeg. Disable only for trusted input.
+    pub fn set_validation(&mut self, enabled: bool) {
+        self.validator = if enabled {
+            Self::new_validator(&self.ctx)
+        } else {
+            None
+        };
+    }
+
+    // codecs other than h264 and hevc only get the size check, unknown ones none
+    fn new_validator(ctx: &DecodeContext) -> Option<Validator> {
+        Encoder::format_from_name(ctx.name.clone())
+            .ok()
+            .map(Validator::new)
     }
 
     pub fn decode(&mut self, packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
+        if let Some(validator) = self.validator.as_mut() {
+            if let Err(e) = validator.validate(packet) {
+                debug!("decoder rejected packet: {:?}", e);
+                return Err(HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _);
+            }
+        }
+        let mut ret = self.decode_packet(packet);
+        if ret < 0
+            && !self.is_software()
+            && self.fallback == SoftwareFallback::OnError
+            && self.switch_to_software().is_ok()
+        {
+            ret = self.decode_packet(packet);
+        }
+        if ret < 0 {
+            Err(ret)
+        } else {
+            Ok(unsafe { &mut *self.frames })
+        }
+    }
+
+    fn decode_packet(&mut self, packet: &[u8]) -> c_int {
         unsafe {
             (&mut *self.frames).clear();
-            let ret = ffmpeg_ram_decode(
+            #[cfg(feature = "testutil")]
+            if self.fail_next_decode && !self.is_software() {
+                self.fail_next_decode = false;
+                return HwcodecErrno::HWCODEC_ERR_COMMON as _;
+            }
+            ffmpeg_ram_decode(
                 self.codec,
                 packet.as_ptr(),
                 packet.len() as c_int,
                 self.frames as *const _ as *const c_void,
-            );
-
-            if ret < 0 {
-                Err(ret)
-            } else {
-                Ok(&mut *self.frames)
-            }
+            )
         }
     }
 
@@ -158,8 +310,6 @@ impl Decoder {
     }
 
     pub fn available_decoders() -> Vec<CodecInfo> {
-        use log::debug;
-
         #[allow(unused_mut)]
         let mut codecs: Vec<CodecInfo> = vec![];
         // windows disable nvdec to avoid gpu stuck
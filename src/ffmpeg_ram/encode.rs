@@ -1,7 +1,8 @@
 use crate::{
+    bitstream::{dump, nal_units, NalRef},
     common::{
         DataFormat::{self, *},
-        Quality, RateControl, TEST_TIMEOUT_MS,
+        HwcodecErrno, Quality, RateControl, TEST_TIMEOUT_MS,
     },
     ffmpeg::{init_av_log, AVPixelFormat},
     ffmpeg_ram::{
@@ -13,6 +14,7 @@ use log::trace;
 use std::{
     ffi::{c_void, CString},
     fmt::Display,
+    io::{self, Read, Write},
     os::raw::c_int,
     slice,
 };
@@ -44,6 +46,37 @@ pub struct EncodeFrame {
     pub key: i32,
 }
 
+impl EncodeFrame {
+    pub fn nal_units(&self) -> impl Iterator<Item = NalRef<'_>> {
+        nal_units(&self.data)
+    }
+
+    // one frame of a bitstream::dump stream
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        dump::write_frame(w, &self.data, self.pts, self.key)
+    }
+
+    // None at the end of the stream
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+        Ok(dump::read_frame(r)?.map(|(data, pts, key)| Self { data, pts, key }))
+    }
+}
+
+// a whole session with the header of the format, see bitstream::dump
+pub fn write_stream<W: Write>(w: &mut W, frames: &[EncodeFrame]) -> io::Result<()> {
+    dump::write_header(w)?;
+    frames.iter().try_for_each(|frame| frame.write_to(w))
+}
+
+pub fn read_stream<R: Read>(r: &mut R) -> io::Result<Vec<EncodeFrame>> {
+    dump::read_header(r)?;
+    let mut frames = vec![];
+    while let Some(frame) = EncodeFrame::read_from(r)? {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
 impl Display for EncodeFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "encode len:{}, pts:{}", self.data.len(), self.pts)
@@ -57,6 +90,8 @@ pub struct Encoder {
     pub linesize: Vec<i32>,
     pub offset: Vec<i32>,
     pub length: i32,
+    // the frame encode_nv12 copies the planes into
+    host: Vec<u8>,
 }
 
 impl Encoder {
@@ -109,6 +144,7 @@ impl Encoder {
                 linesize,
                 offset,
                 length: length[0],
+                host: vec![],
             })
         }
     }
@@ -130,6 +166,43 @@ impl Encoder {
         }
     }
 
+    // Encodes an NV12 frame from host memory, strides are the row pitches of the y and
+    // uv planes. The planes are copied into the layout of linesize and offset.
+    pub fn encode_nv12(
+        &mut self,
+        y: &[u8],
+        uv: &[u8],
+        strides: [usize; 2],
+        ms: i64,
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        let width = self.ctx.width as usize;
+        let height = self.ctx.height as usize;
+        let planes = [
+            (y, strides[0], height),
+            (uv, strides[1], height.div_ceil(2)),
+        ];
+        if self.ctx.pixfmt != AVPixelFormat::AV_PIX_FMT_NV12
+            || planes.iter().any(|&(data, stride, rows)| {
+                stride < width || rows == 0 || data.len() < stride * (rows - 1) + width
+            })
+        {
+            return Err(HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _);
+        }
+        let mut host = std::mem::take(&mut self.host);
+        host.resize(self.length as usize, 0);
+        let starts = [0, self.offset[0] as usize];
+        for (i, &(data, stride, rows)) in planes.iter().enumerate() {
+            let linesize = self.linesize[i] as usize;
+            for row in 0..rows {
+                let dst = starts[i] + row * linesize;
+                host[dst..dst + width].copy_from_slice(&data[row * stride..row * stride + width]);
+            }
+        }
+        let result = self.encode(&host, ms).map(|_| ());
+        self.host = host;
+        result.map(|_| unsafe { &mut *self.frames })
+    }
+
     extern "C" fn callback(data: *const u8, size: c_int, pts: i64, key: i32, obj: *const c_void) {
         unsafe {
             let frames = &mut *(obj as *mut Vec<EncodeFrame>);
@@ -95,6 +95,8 @@ impl CodecInfo {
                     }
                     None => av1 = Some(coder),
                 },
+                // not coded by ffmpeg_ram
+                DataFormat::MJPEG => {}
             }
         }
         CodecInfos {
@@ -1,9 +1,18 @@
+pub mod bitstream;
+#[cfg(all(windows, feature = "capi"))]
+pub mod capi;
 pub mod common;
 pub mod ffmpeg;
 pub mod ffmpeg_ram;
 pub mod mux;
+#[cfg(any(all(windows, feature = "vram"), feature = "testutil"))]
+mod pattern;
+#[cfg(feature = "testutil")]
+pub mod testutil;
 #[cfg(all(windows, feature = "vram"))]
 pub mod vram;
+#[cfg(all(windows, feature = "vram"))]
+pub use vram::shutdown;
 #[cfg(target_os = "android")]
 pub mod android;
 
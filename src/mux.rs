@@ -55,12 +55,31 @@ impl Muxer {
     }
 
     pub fn write_video(&mut self, data: &[u8], key: bool) -> Result<(), i32> {
+        self.write_video_at(data, key, self.start.elapsed().as_millis() as _)
+    }
+
+    // pts_ms is relative to an arbitrary origin, the first key frame becomes 0
+    pub fn write_video_at(&mut self, data: &[u8], key: bool, pts_ms: i64) -> Result<(), i32> {
+        self.write_video_with_duration(data, key, pts_ms, 0)
+    }
+
+    // duration_ms is how long the frame is shown, the sample duration in the file. Without
+    // it the interval since the previous frame is taken, which misses for variable
+    // framerate input.
+    pub fn write_video_with_duration(
+        &mut self,
+        data: &[u8],
+        key: bool,
+        pts_ms: i64,
+        duration_ms: i64,
+    ) -> Result<(), i32> {
         unsafe {
             let result = hwcodec_write_video_frame(
                 self.inner,
                 (*data).as_ptr(),
                 data.len() as _,
-                self.start.elapsed().as_millis() as _,
+                pts_ms,
+                duration_ms,
                 if key { 1 } else { 0 },
             );
             if result != 0 {
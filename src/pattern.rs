@@ -0,0 +1,88 @@
+// Deterministic content and quality metrics, for the probes, the warmup and the self
+// test. Re-exported by testutil.
+
+// gradients with a moving block, bgra
+pub fn bgra_pattern(width: usize, height: usize, index: usize) -> Vec<u8> {
+    let mut data = vec![0u8; width * height * 4];
+    let block = (width.min(height) / 4).max(1);
+    let (bx, by) = (
+        (index * 16) % width.saturating_sub(block).max(1),
+        (index * 8) % height.saturating_sub(block).max(1),
+    );
+    for y in 0..height {
+        for x in 0..width {
+            let p = &mut data[(y * width + x) * 4..][..4];
+            let inside = x >= bx && x < bx + block && y >= by && y < by + block;
+            p[0] = if inside { 32 } else { (x * 255 / width) as u8 };
+            p[1] = if inside {
+                224
+            } else {
+                (y * 255 / height) as u8
+            };
+            p[2] = if inside {
+                32
+            } else {
+                ((x + y) * 255 / (width + height)) as u8
+            };
+            p[3] = 255;
+        }
+    }
+    data
+}
+
+pub fn luma(bgra: &[u8], width: usize, height: usize) -> Vec<f64> {
+    bgra[..width * height * 4]
+        .chunks_exact(4)
+        .map(|p| 0.114 * p[0] as f64 + 0.587 * p[1] as f64 + 0.299 * p[2] as f64)
+        .collect()
+}
+
+pub fn psnr(a: &[f64], b: &[f64]) -> f64 {
+    let mse = a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f64>() / a.len() as f64;
+    if mse == 0.0 {
+        return 100.0;
+    }
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+// mean of 8x8 block ssim
+pub fn ssim(a: &[f64], b: &[f64], width: usize, height: usize) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    const N: usize = 8;
+    let mut sum = 0.0;
+    let mut count = 0;
+    for by in (0..height.saturating_sub(N - 1)).step_by(N) {
+        for bx in (0..width.saturating_sub(N - 1)).step_by(N) {
+            let (mut ma, mut mb) = (0.0, 0.0);
+            for y in by..by + N {
+                for x in bx..bx + N {
+                    ma += a[y * width + x];
+                    mb += b[y * width + x];
+                }
+            }
+            let n = (N * N) as f64;
+            ma /= n;
+            mb /= n;
+            let (mut va, mut vb, mut cov) = (0.0, 0.0, 0.0);
+            for y in by..by + N {
+                for x in bx..bx + N {
+                    let (da, db) = (a[y * width + x] - ma, b[y * width + x] - mb);
+                    va += da * da;
+                    vb += db * db;
+                    cov += da * db;
+                }
+            }
+            va /= n - 1.0;
+            vb /= n - 1.0;
+            cov /= n - 1.0;
+            sum += ((2.0 * ma * mb + C1) * (2.0 * cov + C2))
+                / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return 0.0;
+    }
+    sum / count as f64
+}
@@ -0,0 +1,216 @@
+// Deterministic test content and quality metrics, shared by the examples and the gpu
+// tests, and the d3d11 objects they draw into. Built with the testutil feature.
+
+pub use crate::pattern::*;
+#[cfg(all(windows, feature = "vram"))]
+pub use d3d11::*;
+
+// the same frames as bgra_pattern, width and height must be even
+pub fn nv12_pattern(width: usize, height: usize, index: usize) -> Vec<u8> {
+    bgra_to_nv12(&bgra_pattern(width, height, index), width, height)
+}
+
+// bt.601 limited range, chroma averaged over 2x2 blocks
+pub fn bgra_to_nv12(bgra: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut nv12 = vec![0u8; width * height * 3 / 2];
+    let (y_plane, uv_plane) = nv12.split_at_mut(width * height);
+    for (y, p) in y_plane.iter_mut().zip(bgra.chunks_exact(4)) {
+        let (b, g, r) = (p[0] as i32, p[1] as i32, p[2] as i32);
+        *y = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+    }
+    for cy in 0..height / 2 {
+        for cx in 0..width / 2 {
+            let (mut b, mut g, mut r) = (0, 0, 0);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let p = &bgra[((cy * 2 + dy) * width + cx * 2 + dx) * 4..][..3];
+                b += p[0] as i32;
+                g += p[1] as i32;
+                r += p[2] as i32;
+            }
+            let (b, g, r) = (b / 4, g / 4, r / 4);
+            let uv = &mut uv_plane[cy * width + cx * 2..][..2];
+            uv[0] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+            uv[1] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+        }
+    }
+    nv12
+}
+
+// cb of every pixel, then cr
+pub fn chroma(bgra: &[u8], width: usize, height: usize) -> Vec<f64> {
+    let pixels = || bgra[..width * height * 4].chunks_exact(4);
+    let cb = pixels().map(|p| 0.5 * p[0] as f64 - 0.331 * p[1] as f64 - 0.169 * p[2] as f64);
+    let cr = pixels().map(|p| -0.081 * p[0] as f64 - 0.419 * p[1] as f64 + 0.5 * p[2] as f64);
+    cb.chain(cr).collect()
+}
+
+#[cfg(all(windows, feature = "vram"))]
+mod d3d11 {
+    use crate::{common::TextureDesc, vram::inner::D3D11Ptr};
+    use std::ffi::c_void;
+
+    pub use crate::vram::d3d11::{read_bgra, Device, Texture};
+
+    // the test side of the shim, built with the testutil feature only
+    extern "C" {
+        fn hwcodec_debug_new_shared_fence(
+            device: *mut c_void,
+            handle: *mut *mut c_void,
+        ) -> *mut c_void;
+        fn hwcodec_debug_close_handle(handle: *mut c_void);
+        fn hwcodec_debug_write_and_signal(
+            texture: *mut c_void,
+            data: *const u8,
+            stride: i32,
+            fence: *mut c_void,
+            value: u64,
+            flush: i32,
+        ) -> i32;
+        fn hwcodec_debug_flush(device: *mut c_void);
+        fn hwcodec_debug_new_staging_texture(
+            device: *mut c_void,
+            width: i32,
+            height: i32,
+        ) -> *mut c_void;
+        fn hwcodec_debug_new_srgb_texture(
+            device: *mut c_void,
+            width: i32,
+            height: i32,
+            data: *const u8,
+            stride: i32,
+        ) -> *mut c_void;
+        fn hwcodec_debug_open_shared_texture(
+            device: *mut c_void,
+            texture: *mut c_void,
+        ) -> *mut c_void;
+        fn hwcodec_debug_copy_to_texture_of_desc(
+            texture: *mut c_void,
+            desc: *const TextureDesc,
+        ) -> i32;
+    }
+
+    impl Device {
+        // submits the queued work of the immediate context to the gpu
+        pub fn flush(&self) {
+            unsafe { hwcodec_debug_flush(self.0 .0) }
+        }
+    }
+
+    impl Texture {
+        // from_bgra typed DXGI_FORMAT_B8G8R8A8_UNORM_SRGB, the bytes are the same
+        pub fn from_bgra_srgb(
+            device: *mut c_void,
+            width: i32,
+            height: i32,
+            bgra: &[u8],
+        ) -> Result<Self, ()> {
+            if bgra.len() < width as usize * height as usize * 4 {
+                return Err(());
+            }
+            let texture = unsafe {
+                hwcodec_debug_new_srgb_texture(device, width, height, bgra.as_ptr(), width * 4)
+            };
+            if texture.is_null() {
+                return Err(());
+            }
+            Ok(Self(D3D11Ptr(texture)))
+        }
+
+        // the same texture on another device, e.g. a renderer's texture on the encoder's
+        pub fn open_on(&self, device: &Device) -> Result<Self, ()> {
+            let texture = unsafe { hwcodec_debug_open_shared_texture(device.as_ptr(), self.0 .0) };
+            if texture.is_null() {
+                return Err(());
+            }
+            Ok(Self(D3D11Ptr(texture)))
+        }
+    }
+
+    // cpu readable, for Decoder::register_staging
+    pub struct StagingTexture(D3D11Ptr);
+
+    impl StagingTexture {
+        pub fn new(device: &Device, width: i32, height: i32) -> Result<Self, ()> {
+            let texture =
+                unsafe { hwcodec_debug_new_staging_texture(device.as_ptr(), width, height) };
+            if texture.is_null() {
+                return Err(());
+            }
+            Ok(Self(D3D11Ptr(texture)))
+        }
+
+        pub fn as_ptr(&self) -> *mut c_void {
+            self.0 .0
+        }
+    }
+
+    // What an external renderer signals after drawing, see Encoder::encode_synced.
+    // Fails before windows 10 1703.
+    pub struct SharedFence {
+        fence: D3D11Ptr,
+        handle: *mut c_void,
+    }
+
+    impl SharedFence {
+        pub fn new(device: &Device) -> Result<Self, ()> {
+            let mut handle = std::ptr::null_mut();
+            let fence = unsafe { hwcodec_debug_new_shared_fence(device.as_ptr(), &mut handle) };
+            if fence.is_null() {
+                return Err(());
+            }
+            Ok(Self {
+                fence: D3D11Ptr(fence),
+                handle,
+            })
+        }
+
+        // the NT handle, for encode_synced
+        pub fn handle(&self) -> *mut c_void {
+            self.handle
+        }
+
+        // Queues writing bgra into texture and signaling value after it on the device of
+        // texture, the gpu only sees them after a flush.
+        pub fn write_and_signal(
+            &self,
+            texture: &Texture,
+            width: i32,
+            height: i32,
+            bgra: &[u8],
+            value: u64,
+            flush: bool,
+        ) -> Result<(), ()> {
+            if bgra.len() < width as usize * height as usize * 4 {
+                return Err(());
+            }
+            let ret = unsafe {
+                hwcodec_debug_write_and_signal(
+                    texture.as_ptr(),
+                    bgra.as_ptr(),
+                    width * 4,
+                    self.fence.0,
+                    value,
+                    flush as i32,
+                )
+            };
+            if ret != 0 {
+                return Err(());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for SharedFence {
+        fn drop(&mut self) {
+            unsafe { hwcodec_debug_close_handle(self.handle) }
+        }
+    }
+
+    // copies texture into a new one created from desc, fails if desc doesn't describe texture
+    pub fn copy_to_texture_of_desc(texture: *mut c_void, desc: &TextureDesc) -> Result<(), ()> {
+        if unsafe { hwcodec_debug_copy_to_texture_of_desc(texture, desc) } != 0 {
+            return Err(());
+        }
+        Ok(())
+    }
+}
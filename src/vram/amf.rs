@@ -5,10 +5,25 @@
 include!(concat!(env!("OUT_DIR"), "/amf_ffi.rs"));
 
 use crate::{
-    common::DataFormat::*,
-    vram::inner::{DecodeCalls, EncodeCalls, InnerDecodeContext, InnerEncodeContext},
+    common::{
+        ConfigCheck, DataFormat::*, DecodeCaps, DriverInfo, DriverProbe, EncodeCaps, EncodeOptions,
+        GpuTiming, MemoryInfo, RuntimeInfo,
+    },
+    vram::inner::{
+        DecodeCalls, DriverSupport, EncodeCalls, InnerDecodeContext, InnerEncodeContext,
+    },
 };
 
+static DRIVER_SUPPORT: DriverSupport = DriverSupport::new("AMF");
+
+pub(crate) fn driver_support() -> bool {
+    DRIVER_SUPPORT.get(|probe| unsafe { amf_driver_support(probe) })
+}
+
+pub(crate) fn driver_info() -> DriverInfo {
+    DRIVER_SUPPORT.info(|probe| unsafe { amf_driver_support(probe) })
+}
+
 pub fn encode_calls() -> EncodeCalls {
     EncodeCalls {
         new: amf_new_encoder,
@@ -17,6 +32,17 @@ pub fn encode_calls() -> EncodeCalls {
         test: amf_test_encode,
         set_bitrate: amf_set_bitrate,
         set_framerate: amf_set_framerate,
+        request_keyframe: amf_request_keyframe,
+        check: amf_check_encoder,
+        memory: amf_encoder_memory,
+        info: amf_encoder_info,
+        gpu_timings: amf_encoder_gpu_timings,
+        flush: amf_encoder_flush,
+        set_emphasis_map: amf_encoder_set_emphasis_map,
+        set_qp_delta: amf_encoder_set_qp_delta,
+        invalidate_reference: amf_encoder_invalidate_reference,
+        estimate_memory: amf_estimate_encoder_memory,
+        check_config: amf_check_encoder_config,
     }
 }
 
@@ -26,12 +52,15 @@ pub fn decode_calls() -> DecodeCalls {
         decode: amf_decode,
         destroy: amf_destroy_decoder,
         test: amf_test_decode,
+        memory: amf_decoder_memory,
+        set_conceal: amf_decoder_set_conceal,
+        caps: amf_decoder_caps,
     }
 }
 
 // to-do: hardware ability
 pub fn possible_support_encoders() -> Vec<InnerEncodeContext> {
-    if unsafe { amf_driver_support() } != 0 {
+    if !driver_support() {
         return vec![];
     }
     let codecs = vec![H264, H265];
@@ -46,11 +75,11 @@ pub fn possible_support_encoders() -> Vec<InnerEncodeContext> {
 }
 
 pub fn possible_support_decoders() -> Vec<InnerDecodeContext> {
-    if unsafe { amf_driver_support() } != 0 {
+    if !driver_support() {
         return vec![];
     }
     // https://github.com/GPUOpen-LibrariesAndSDKs/AMF/issues/432#issuecomment-1873141122
-    let codecs = vec![H264];
+    let codecs = vec![H264, MJPEG];
 
     let mut v = vec![];
     for codec in codecs.iter() {
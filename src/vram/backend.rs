@@ -0,0 +1,196 @@
+// Drivers implemented outside this crate. A registered driver is probed by
+// encode::available and decode::available after the built-in ones, the contexts it
+// reports carry Driver::CUSTOM(name) and Encoder::new/Decoder::new create its sessions.
+
+use crate::{
+    common::{DataFormat, DecodeCaps, Driver, GpuTiming, HwcodecErrno, MemoryInfo},
+    vram::{
+        decode::DecodeFrame,
+        encode::{ConfigError, EncodeFrame, EncoderInfo},
+        DecodeContext, DynamicContext, EncodeContext,
+    },
+};
+use log::debug;
+use std::{
+    ffi::c_void,
+    sync::{Arc, RwLock},
+};
+
+// One encoder session, destroyed on drop. Errors are HwcodecErrno values.
+pub trait EncodeBackend: Send {
+    // the returned frames are valid until the next call
+    fn encode(&mut self, tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32>;
+
+    // The returned frames carry the user_data of the call their frame was given to. The
+    // default is right for backends whose packets are all of the frame just encoded,
+    // those that buffer or reorder frames have to carry it themselves.
+    fn encode_with_user_data(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        user_data: u64,
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        let frames = self.encode(tex, ms)?;
+        frames.iter_mut().for_each(|f| f.user_data = user_data);
+        Ok(frames)
+    }
+
+    fn set_bitrate(&mut self, kbs: i32) -> Result<(), i32>;
+
+    fn set_framerate(&mut self, framerate: i32) -> Result<(), i32>;
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        Err(HwcodecErrno::HWCODEC_ERR_COMMON as _)
+    }
+
+    fn check(&self) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> MemoryInfo {
+        MemoryInfo::default()
+    }
+
+    fn info(&self) -> EncoderInfo {
+        EncoderInfo::default()
+    }
+
+    // the timings that became available since the last call, in frame order
+    fn gpu_timings(&mut self) -> Vec<GpuTiming> {
+        vec![]
+    }
+
+    // The frames still buffered in the session, it takes no input afterwards until it is
+    // recreated. Backends that return each frame's output from its encode call have none.
+    fn flush(&mut self) -> Result<Vec<EncodeFrame>, i32> {
+        Ok(vec![])
+    }
+
+    // the emphasis levels of the next encoded frame, see Encoder::encode_with_emphasis_map
+    fn set_emphasis_map(&mut self, _map: &[u8]) -> Result<(), i32> {
+        Err(HwcodecErrno::HWCODEC_ERR_COMMON as _)
+    }
+
+    // added to the qp the rate control chooses for the next encoded frame, see
+    // OversizePolicy::Reencode
+    fn set_qp_delta(&mut self, _delta: i32) -> Result<(), i32> {
+        Err(HwcodecErrno::HWCODEC_ERR_COMMON as _)
+    }
+
+    // The latest frame encoded with ms and those after it are no references anymore,
+    // the next frame references an earlier one. Fails when there is none, see
+    // Encoder::invalidate_reference.
+    fn invalidate_reference(&mut self, _ms: i64) -> Result<(), i32> {
+        Err(HwcodecErrno::HWCODEC_ERR_COMMON as _)
+    }
+
+    // see Encoder::input_alignment, backends aligning to more than 1 read the top left of
+    // larger textures
+    fn input_alignment(&self) -> (u32, u32) {
+        (1, 1)
+    }
+
+    // replaces the session after a device loss, ctx.d.device is the new device
+    fn recreate(&mut self, _ctx: &EncodeContext) -> Result<(), ()> {
+        Err(())
+    }
+
+    // Destroys the session now, see Encoder::close. Dropping the backend afterwards must
+    // destroy nothing more. Backends whose session ends in their Drop keep the default.
+    fn close(&mut self) -> Result<(), i32> {
+        Ok(())
+    }
+}
+
+pub trait EncodeDriver: Send + Sync {
+    // unique, the driver of its contexts is Driver::CUSTOM(name)
+    fn name(&self) -> &str;
+
+    // the (luid, vendor) of every adapter that encodes format with d
+    fn test(&self, format: DataFormat, d: &DynamicContext) -> Vec<(i64, Driver)>;
+
+    fn create(&self, ctx: &EncodeContext) -> Result<Box<dyn EncodeBackend>, ()>;
+
+    // see encode::check_config, drivers without a query of their own accept every
+    // context that passed its common checks
+    fn check_config(&self, _ctx: &EncodeContext) -> Result<(), ConfigError> {
+        Ok(())
+    }
+}
+
+// One decoder session, destroyed on drop. Errors are HwcodecErrno values.
+pub trait DecodeBackend: Send {
+    // the returned frames are valid until the next call
+    fn decode(&mut self, packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32>;
+
+    fn memory_usage(&self) -> MemoryInfo {
+        MemoryInfo::default()
+    }
+
+    // 0 fields are unknown and not checked
+    fn caps(&self) -> DecodeCaps {
+        DecodeCaps::default()
+    }
+}
+
+pub trait DecodeDriver: Send + Sync {
+    // unique, the driver of its contexts is Driver::CUSTOM(name)
+    fn name(&self) -> &str;
+
+    // the (luid, vendor) of every adapter that decodes format
+    fn test(&self, format: DataFormat) -> Vec<(i64, Driver)>;
+
+    fn create(&self, ctx: &DecodeContext) -> Result<Box<dyn DecodeBackend>, ()>;
+}
+
+static ENCODE_DRIVERS: RwLock<Vec<Arc<dyn EncodeDriver>>> = RwLock::new(Vec::new());
+static DECODE_DRIVERS: RwLock<Vec<Arc<dyn DecodeDriver>>> = RwLock::new(Vec::new());
+
+// replaces a driver registered under the same name
+pub fn register_encode_driver(driver: Arc<dyn EncodeDriver>) {
+    debug!("register encode driver {}", driver.name());
+    let mut drivers = ENCODE_DRIVERS.write().unwrap_or_else(|e| e.into_inner());
+    drivers.retain(|d| d.name() != driver.name());
+    drivers.push(driver);
+}
+
+pub fn unregister_encode_driver(name: &str) {
+    let mut drivers = ENCODE_DRIVERS.write().unwrap_or_else(|e| e.into_inner());
+    drivers.retain(|d| d.name() != name);
+}
+
+// replaces a driver registered under the same name
+pub fn register_decode_driver(driver: Arc<dyn DecodeDriver>) {
+    debug!("register decode driver {}", driver.name());
+    let mut drivers = DECODE_DRIVERS.write().unwrap_or_else(|e| e.into_inner());
+    drivers.retain(|d| d.name() != driver.name());
+    drivers.push(driver);
+}
+
+pub fn unregister_decode_driver(name: &str) {
+    let mut drivers = DECODE_DRIVERS.write().unwrap_or_else(|e| e.into_inner());
+    drivers.retain(|d| d.name() != name);
+}
+
+// snapshots, the lock isn't held while the drivers run
+pub(crate) fn encode_drivers() -> Vec<Arc<dyn EncodeDriver>> {
+    ENCODE_DRIVERS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+pub(crate) fn decode_drivers() -> Vec<Arc<dyn DecodeDriver>> {
+    DECODE_DRIVERS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+pub(crate) fn encode_driver(name: &str) -> Option<Arc<dyn EncodeDriver>> {
+    encode_drivers().into_iter().find(|d| d.name() == name)
+}
+
+pub(crate) fn decode_driver(name: &str) -> Option<Arc<dyn DecodeDriver>> {
+    decode_drivers().into_iter().find(|d| d.name() == name)
+}
@@ -0,0 +1,61 @@
+// The d3d11 objects the probes, the warmup and the self test create: a device of an
+// adapter, bgra textures on it and their readback.
+
+use crate::vram::inner::{
+    hwcodec_new_d3d11_bgra_texture, hwcodec_read_d3d11_bgra_texture, D3D11Ptr,
+};
+use std::ffi::c_void;
+
+pub struct Device(pub(crate) D3D11Ptr);
+
+impl Device {
+    pub fn new(luid: i64) -> Result<Self, ()> {
+        D3D11Ptr::device(luid).map(Self).map_err(|_| ())
+    }
+
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.0 .0
+    }
+}
+
+// d3d11 devices are free threaded, the context calls take the device lock
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
+
+// shared, usable as encoder input
+pub struct Texture(pub(crate) D3D11Ptr);
+
+impl Texture {
+    pub fn from_bgra(
+        device: *mut c_void,
+        width: i32,
+        height: i32,
+        bgra: &[u8],
+    ) -> Result<Self, ()> {
+        if bgra.len() < width as usize * height as usize * 4 {
+            return Err(());
+        }
+        let texture = unsafe {
+            hwcodec_new_d3d11_bgra_texture(device, width, height, bgra.as_ptr(), width * 4)
+        };
+        if texture.is_null() {
+            return Err(());
+        }
+        Ok(Self(D3D11Ptr(texture)))
+    }
+
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.0 .0
+    }
+}
+
+// copies the top left width x height of a bgra texture, e.g. a decoded frame
+pub fn read_bgra(texture: *mut c_void, width: i32, height: i32) -> Result<Vec<u8>, ()> {
+    let mut data = vec![0u8; width as usize * height as usize * 4];
+    let ret =
+        unsafe { hwcodec_read_d3d11_bgra_texture(texture, data.as_mut_ptr(), width * 4, height) };
+    if ret != 0 {
+        return Err(());
+    }
+    Ok(data)
+}
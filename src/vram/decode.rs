@@ -1,111 +1,1104 @@
+#[cfg(feature = "amf")]
+use crate::vram::amf;
+#[cfg(feature = "vram-ffmpeg")]
+use crate::vram::ffmpeg;
+#[cfg(feature = "mfx")]
+use crate::vram::mfx;
+#[cfg(feature = "nv")]
+use crate::vram::nv;
 use crate::{
-    common::{DataFormat::*, Driver::*},
+    bitstream::{
+        annexb_nal_units, h264, hevc, jpeg,
+        refs::{RefTracker, References},
+        validate::Validator,
+    },
+    common::{
+        CodecDescriptor, DataFormat, DataFormat::*, DecodeCaps, DecodeProfile, Driver, Driver::*,
+        HwcodecErrno, MemoryInfo, TextureDesc,
+    },
     ffmpeg::init_av_log,
-    vram::{amf, ffmpeg, inner::DecodeCalls, mfx, nv, DecodeContext},
+    vram::{
+        adapter_path,
+        backend::{self, DecodeBackend},
+        inner::{
+            hwcodec_check_d3d11_staging_texture, hwcodec_copy_to_d3d11_staging,
+            hwcodec_get_d3d11_texture_desc, hwcodec_new_d3d11_staging_texture_like,
+            hwcodec_new_d3d11_texture_like, hwcodec_pad_d3d11_texture,
+            hwcodec_read_d3d11_bgra_texture, CallbackFrames, D3D11Ptr, DecodeCalls,
+            InnerDecodeContext, SessionGuard,
+        },
+        report::ProbeFailure,
+        DecodeContext, OutputOrder,
+    },
 };
-use log::trace;
+use log::{debug, error, trace};
 use std::ffi::c_void;
 
 pub struct Decoder {
-    calls: DecodeCalls,
-    codec: *mut c_void,
-    frames: *mut Vec<DecodeFrame>,
+    backend: Box<dyn DecodeBackend>,
+    validator: Option<Validator>,
+    staging: Option<Staging>,
+    // with OutputOrder::Presentation
+    reorder: Option<Reorder>,
+    // for decode_bgra and decode_with_copy, its staging texture is created by the first
+    readback: Readback,
+    refs: RefTracker,
+    // a reference picture was missing or the session failed since the last IDR
+    damaged: bool,
+    caps: DecodeCaps,
+    // of the last packet failing with HWCODEC_ERR_EXCEEDS_CAPS
+    exceeded: Option<CapsLimit>,
+    concealment: Concealment,
+    // of the latest SPS
+    crop: Crop,
     pub ctx: DecodeContext,
 }
 
+// textures of the caller, filled round robin
+struct Staging {
+    textures: Vec<*mut c_void>,
+    width: i32,
+    height: i32,
+    next: usize,
+}
+
 unsafe impl Send for Decoder {}
 unsafe impl Sync for Decoder {}
 
-extern "C" {
-    fn hwcodec_get_d3d11_texture_width_height(
-        texture: *mut c_void,
-        width: *mut i32,
-        height: *mut i32,
-    );
-}
-
 impl Decoder {
+    // CUSTOM drivers are created by the DecodeDriver registered under their name.
     pub fn new(ctx: DecodeContext) -> Result<Self, ()> {
         init_av_log();
-        let calls = match ctx.driver {
-            NV => nv::decode_calls(),
-            AMF => amf::decode_calls(),
-            MFX => mfx::decode_calls(),
-            FFMPEG => ffmpeg::decode_calls(),
-        };
-        unsafe {
-            let codec = (calls.new)(
-                ctx.device.unwrap_or(std::ptr::null_mut()),
-                ctx.luid,
-                ctx.data_format as i32,
-            );
-            if codec.is_null() {
+        let backend: Box<dyn DecodeBackend> = match (&ctx.driver, native_calls(&ctx.driver)) {
+            (_, Some(calls)) => Box::new(NativeDecoder::new(calls, &ctx)?),
+            (CUSTOM(name), None) => {
+                let Some(driver) = backend::decode_driver(name) else {
+                    error!("decode driver {} is not registered", name);
+                    return Err(());
+                };
+                driver.create(&ctx)?
+            }
+            (driver, None) => {
+                error!("decode driver {:?} is not compiled in", driver);
                 return Err(());
             }
-            Ok(Self {
-                calls,
-                codec,
-                frames: Box::into_raw(Box::new(Vec::<DecodeFrame>::new())),
-                ctx,
-            })
+        };
+        Ok(Self::from_backend(backend, ctx))
+    }
+
+    // a backend created without going through a registered driver
+    pub fn from_backend(backend: Box<dyn DecodeBackend>, ctx: DecodeContext) -> Self {
+        init_av_log();
+        let caps = backend.caps();
+        Self {
+            backend,
+            validator: Some(Validator::new(ctx.data_format)),
+            staging: None,
+            reorder: (ctx.output_order == OutputOrder::Presentation).then(Reorder::default),
+            readback: Readback::default(),
+            refs: RefTracker::new(ctx.data_format),
+            damaged: false,
+            caps,
+            exceeded: None,
+            concealment: Concealment::default(),
+            crop: Crop::default(),
+            ctx,
         }
     }
 
+    // Enabled by default, packets failing the checks return HWCODEC_ERR_INVALID_DATA, or
+    // are concealed, see DecodeContext::conceal_errors, without reaching the driver.
+    // Disable only for trusted input. After enabling it again the next packet must carry
+    // the parameter sets.
+    pub fn set_validation(&mut self, enabled: bool) {
+        self.validator = enabled.then(|| Validator::new(self.ctx.data_format));
+    }
+
     pub fn decode(&mut self, packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
-        unsafe {
-            (&mut *self.frames).clear();
-            let ret = (self.calls.decode)(
-                self.codec,
-                packet.as_ptr() as _,
-                packet.len() as _,
-                Some(Self::callback),
-                self.frames as *mut _ as *mut c_void,
-            );
+        self.decode_with_pts(packet, 0)
+    }
 
-            if ret != 0 {
-                Err(ret)
-            } else {
-                Ok(&mut *self.frames)
+    // pts comes back on the frames of packet, with OutputOrder::Presentation they come
+    // out in its order
+    pub fn decode_with_pts(
+        &mut self,
+        packet: &[u8],
+        pts: i64,
+    ) -> Result<&mut Vec<DecodeFrame>, i32> {
+        self.decode_with_copy(packet, pts, false)
+    }
+
+    // Like decode_with_pts, with copy the frames of packet also carry cpu_copy, read back
+    // like those of decode_bgra through the same staging texture. Only those calls wait
+    // for the copy, e.g. to hash every nth frame of a stream that is displayed.
+    pub fn decode_with_copy(
+        &mut self,
+        packet: &[u8],
+        pts: i64,
+        copy: bool,
+    ) -> Result<&mut Vec<DecodeFrame>, i32> {
+        self.decode_packet(packet, pts, None, copy)
+    }
+
+    // Like decode_with_pts, seq comes back as DecodeFrame::seq on the frames of packet, the
+    // concealed ones included, e.g. the EncodeFrame::seq of the packet for counting
+    // losses.
+    pub fn decode_with_seq(
+        &mut self,
+        packet: &[u8],
+        pts: i64,
+        seq: u64,
+    ) -> Result<&mut Vec<DecodeFrame>, i32> {
+        self.decode_packet(packet, pts, Some(seq), false)
+    }
+
+    fn decode_packet(
+        &mut self,
+        packet: &[u8],
+        pts: i64,
+        seq: Option<u64>,
+        copy: bool,
+    ) -> Result<&mut Vec<DecodeFrame>, i32> {
+        let patched = (self.ctx.data_format == MJPEG)
+            .then(|| jpeg::with_default_huffman_tables(packet))
+            .flatten();
+        let packet = patched.as_deref().unwrap_or(packet);
+        self.readback.update_color(self.ctx.data_format, packet);
+        if let Some(crop) = stream_crop(self.ctx.data_format, packet) {
+            self.crop = crop;
+        }
+        // before validation, whose size limit is a sanity check and not the hardware's
+        if let Some(limit) = exceeded_caps(&self.caps, self.ctx.data_format, packet) {
+            error!("stream exceeds the decoder caps: {:?}", limit);
+            self.exceeded = Some(limit);
+            return Err(HwcodecErrno::HWCODEC_ERR_EXCEEDS_CAPS as _);
+        }
+        if let Some(validator) = self.validator.as_mut() {
+            if let Err(e) = validator.validate(packet) {
+                debug!("decoder rejected packet: {:?}", e);
+                let err = HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _;
+                if !self.ctx.conceal_errors {
+                    return Err(err);
+                }
+                // the packets referring to it
+                self.damaged = true;
+                let (readback, reorder) = (&mut self.readback, self.reorder.as_mut());
+                return self
+                    .concealment
+                    .repeat(err, pts, seq, copy, readback, reorder);
+            }
+        }
+        let references = self.refs.push(packet);
+        match references {
+            References::Idr => self.damaged = false,
+            References::Missing if !self.damaged => {
+                debug!("packet references a picture that wasn't decoded");
+                self.damaged = true;
+            }
+            _ => {}
+        }
+        // parameter sets alone still reach the session for the IDR after them
+        if self.damaged && !self.ctx.conceal_errors && references != References::NoPicture {
+            return Err(HwcodecErrno::HWCODEC_ERR_NEED_KEYFRAME as _);
+        }
+        // the textures may be of the aligned size, width and height are the SPS's cropped
+        // one. Without validation the whole texture is reported.
+        let cropped = self.validator.as_ref().and_then(|v| v.size());
+        if let Some(reorder) = self.reorder.as_mut() {
+            reorder.update_depth(self.ctx.data_format, packet);
+        }
+        let frames = match self.backend.decode(packet) {
+            Ok(frames) => frames,
+            Err(e) if HwcodecErrno::is_codec_lost(e) => return Err(e),
+            Err(e) => {
+                self.damaged = true;
+                if self.ctx.conceal_errors {
+                    let (readback, reorder) = (&mut self.readback, self.reorder.as_mut());
+                    return self
+                        .concealment
+                        .repeat(e, pts, seq, copy, readback, reorder);
+                }
+                debug!("decode failed with {}, waiting for an IDR", e);
+                return Err(HwcodecErrno::HWCODEC_ERR_NEED_KEYFRAME as _);
+            }
+        };
+        for frame in frames.iter_mut() {
+            frame.pts = pts;
+            frame.seq = seq;
+            frame.corrupted |= self.damaged;
+            frame.crop = self.crop;
+            // custom backends may leave it to us
+            if frame.desc == TextureDesc::default() {
+                frame.desc = texture_desc(frame.texture);
+            }
+            if let Some((width, height)) = cropped {
+                frame.width = frame.width.min(width as _);
+                frame.height = frame.height.min(height as _);
+            }
+            if copy {
+                // the texture is still good without it
+                frame.cpu_copy = self
+                    .readback
+                    .read(frame)
+                    .map_err(|e| error!("failed to copy a decoded frame: {}", e))
+                    .ok();
             }
         }
+        self.concealment.update(frames);
+        match self.reorder.as_mut() {
+            Some(reorder) => reorder.push(frames),
+            None => Ok(frames),
+        }
     }
 
-    unsafe extern "C" fn callback(texture: *mut c_void, obj: *const c_void) {
-        let frames = &mut *(obj as *mut Vec<DecodeFrame>);
-        let mut width = 0;
-        let mut height = 0;
-        hwcodec_get_d3d11_texture_width_height(texture, &mut width, &mut height);
+    // The frames held back for OutputOrder::Presentation in pts order, for the end of the
+    // stream. Empty in decode order.
+    pub fn flush(&mut self) -> Vec<DecodeFrame> {
+        let Some(reorder) = self.reorder.as_mut() else {
+            return vec![];
+        };
+        reorder.release(0);
+        std::mem::take(&mut reorder.frames)
+    }
 
-        let frame = DecodeFrame {
-            texture,
+    // The decoded frames waiting for later ones with OutputOrder::Presentation, what the
+    // reordering adds to the latency. At most reorder_depth after a decode call.
+    pub fn held_frames(&self) -> usize {
+        self.reorder.as_ref().map_or(0, |r| r.held.len())
+    }
+
+    // the frames the latest SPS allows to be reordered, 0 in decode order
+    pub fn reorder_depth(&self) -> usize {
+        self.reorder.as_ref().map_or(0, |r| r.depth)
+    }
+
+    // Registers cpu readable bgra staging textures of the decoder's device for
+    // decode_to_staging, replacing earlier ones. They must all be of the frame size, which
+    // is checked against the stream's once its parameter sets were seen. The caller keeps
+    // them alive until they are replaced or the decoder is dropped.
+    pub fn register_staging(&mut self, textures: &[*mut c_void]) -> Result<(), i32> {
+        let common = HwcodecErrno::HWCODEC_ERR_COMMON as i32;
+        let mut size = None;
+        for &texture in textures {
+            let (mut width, mut height) = (0, 0);
+            let ret =
+                unsafe { hwcodec_check_d3d11_staging_texture(texture, &mut width, &mut height) };
+            if ret != 0 {
+                return Err(common);
+            }
+            if *size.get_or_insert((width, height)) != (width, height) {
+                error!("staging textures of different sizes");
+                return Err(common);
+            }
+        }
+        let Some((width, height)) = size else {
+            return Err(common);
+        };
+        if let Some((w, h)) = self.validator.as_ref().and_then(|v| v.size()) {
+            if (w as i32, h as i32) != (width, height) {
+                error!(
+                    "staging textures of {}x{} for frames of {}x{}",
+                    width, height, w, h
+                );
+                return Err(common);
+            }
+        }
+        self.staging = Some(Staging {
+            textures: textures.to_vec(),
             width,
             height,
+            next: 0,
+        });
+        Ok(())
+    }
+
+    // Decodes and copies each frame into the next registered staging texture, a frame of
+    // another size fails with HWCODEC_ERR_COMMON. The copy is finished on return, mapping
+    // the texture for reading doesn't wait.
+    pub fn decode_to_staging(&mut self, packet: &[u8]) -> Result<Vec<StagedFrame>, i32> {
+        let common = HwcodecErrno::HWCODEC_ERR_COMMON as i32;
+        if self.staging.is_none() {
+            error!("no staging textures registered");
+            return Err(common);
+        }
+        let frames = std::mem::take(self.decode(packet)?);
+        let Some(staging) = self.staging.as_mut() else {
+            return Err(common);
         };
-        frames.push(frame);
+        let mut staged = Vec::with_capacity(frames.len());
+        for frame in frames {
+            if (frame.width, frame.height) != (staging.width, staging.height) {
+                error!(
+                    "decoded {}x{} into staging textures of {}x{}",
+                    frame.width, frame.height, staging.width, staging.height
+                );
+                return Err(common);
+            }
+            let index = staging.next;
+            let mut row_pitch = 0;
+            let ret = unsafe {
+                hwcodec_copy_to_d3d11_staging(
+                    frame.texture,
+                    staging.textures[index],
+                    frame.width,
+                    frame.height,
+                    &mut row_pitch,
+                )
+            };
+            if ret != 0 {
+                return Err(common);
+            }
+            staging.next = (index + 1) % staging.textures.len();
+            staged.push(StagedFrame {
+                index,
+                row_pitch,
+                width: frame.width,
+                height: frame.height,
+                corrupted: frame.corrupted,
+            });
+        }
+        Ok(staged)
+    }
+
+    // Decodes and reads the frames back into memory, for tools and tests rather than
+    // rendering: each frame waits for a copy through a staging texture, which is kept for
+    // the next call. The sessions convert as bt.601 studio range, frames of streams whose
+    // vui signals another matrix or full range, and MJPEG's which are full range, are
+    // corrected on the cpu. Levels the session clipped, e.g. below 16 of a full range
+    // stream, stay clipped.
+    pub fn decode_bgra(&mut self, packet: &[u8]) -> Result<Vec<BgraFrame>, i32> {
+        let frames = std::mem::take(self.decode(packet)?);
+        frames.iter().map(|frame| self.readback.read(frame)).collect()
+    }
+
+    // the decode surfaces are allocated with the first frame, before that both are 0
+    pub fn memory_usage(&self) -> MemoryInfo {
+        self.backend.memory_usage()
+    }
+
+    // what the session decodes, SPSs beyond it fail with HWCODEC_ERR_EXCEEDS_CAPS
+    pub fn caps(&self) -> DecodeCaps {
+        self.caps
+    }
+
+    // the limit the last packet failing with HWCODEC_ERR_EXCEEDS_CAPS was beyond
+    pub fn exceeded_caps(&self) -> Option<CapsLimit> {
+        self.exceeded
     }
 }
 
+// The caps of the decoder of ctx's adapter and format, e.g. to check a stream a peer
+// offers. Creates a session without decoding, cache the result per context.
+pub fn query_decode_caps(ctx: &DecodeContext) -> Result<DecodeCaps, ()> {
+    Decoder::new(ctx.clone()).map(|decoder| decoder.caps)
+}
+
+// the CodecDescriptor::known of ctx's format its decoder takes, see query_decode_caps
+pub fn query_descriptors(ctx: &DecodeContext) -> Result<Vec<CodecDescriptor>, ()> {
+    let caps = query_decode_caps(ctx)?;
+    Ok(CodecDescriptor::known(ctx.data_format)
+        .into_iter()
+        .filter(|d| caps.supports(d))
+        .collect())
+}
+
+// a value of an SPS beyond DecodeCaps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapsLimit {
+    // coded size
+    Size {
+        width: u32,
+        height: u32,
+        max_width: u32,
+        max_height: u32,
+    },
+    // level_idc
+    Level {
+        level: u32,
+        max_level: u32,
+    },
+    // profile_idc
+    Profile(u32),
+    BitDepth(u32),
+}
+
+// the values of an SPS DecodeCaps limit
+struct SpsCaps {
+    // coded size
+    width: u32,
+    height: u32,
+    level_idc: u32,
+    profile_idc: u8,
+    // None for profiles DecodeProfile doesn't list
+    profile: Option<DecodeProfile>,
+    bit_depth: u32,
+}
+
+impl SpsCaps {
+    fn find(format: DataFormat, packet: &[u8]) -> Option<Self> {
+        use DecodeProfile::*;
+        if format == MJPEG {
+            // no profiles or levels
+            return jpeg::FrameHeader::find(packet).ok().map(|header| Self {
+                width: header.width,
+                height: header.height,
+                level_idc: 0,
+                profile_idc: 0,
+                profile: None,
+                bit_depth: header.precision as _,
+            });
+        }
+        annexb_nal_units(packet).find_map(|nal| match format {
+            H264 => h264::Sps::parse(nal).ok().map(|sps| {
+                let (width, height) = sps.coded_size();
+                Self {
+                    width,
+                    height,
+                    level_idc: sps.level_idc as _,
+                    profile_idc: sps.profile_idc,
+                    profile: match sps.profile_idc {
+                        BASELINE_PROFILE => Some(DECODE_PROFILE_H264_BASELINE),
+                        77 => Some(DECODE_PROFILE_H264_MAIN),
+                        100 => Some(DECODE_PROFILE_H264_HIGH),
+                        110 => Some(DECODE_PROFILE_H264_HIGH10),
+                        _ => None,
+                    },
+                    bit_depth: sps.bit_depth_luma,
+                }
+            }),
+            H265 => hevc::Sps::parse(nal).ok().map(|sps| Self {
+                width: sps.pic_width_in_luma_samples,
+                height: sps.pic_height_in_luma_samples,
+                level_idc: sps.ptl.general_level_idc as _,
+                profile_idc: sps.ptl.general.profile_idc,
+                profile: match sps.ptl.general.profile_idc {
+                    // main still picture is a subset of main
+                    1 | 3 => Some(DECODE_PROFILE_HEVC_MAIN),
+                    2 => Some(DECODE_PROFILE_HEVC_MAIN10),
+                    _ => None,
+                },
+                bit_depth: sps.bit_depth_luma,
+            }),
+            _ => None,
+        })
+    }
+}
+
+// the first limit of caps an SPS of packet is beyond, unknown caps aren't checked
+fn exceeded_caps(caps: &DecodeCaps, format: DataFormat, packet: &[u8]) -> Option<CapsLimit> {
+    let sps = SpsCaps::find(format, packet)?;
+    let (max_width, max_height) = (caps.maxWidth as u32, caps.maxHeight as u32);
+    if caps.maxWidth > 0 && caps.maxHeight > 0 && (sps.width > max_width || sps.height > max_height)
+    {
+        return Some(CapsLimit::Size {
+            width: sps.width,
+            height: sps.height,
+            max_width,
+            max_height,
+        });
+    }
+    if caps.maxLevel > 0 && sps.level_idc > caps.maxLevel as u32 {
+        return Some(CapsLimit::Level {
+            level: sps.level_idc,
+            max_level: caps.maxLevel as _,
+        });
+    }
+    if caps.profiles != 0 && !sps.profile.is_some_and(|p| caps.has_profile(p)) {
+        return Some(CapsLimit::Profile(sps.profile_idc as _));
+    }
+    if caps.bitDepths != 0 && !caps.has_bit_depth(sps.bit_depth) {
+        return Some(CapsLimit::BitDepth(sps.bit_depth));
+    }
+    None
+}
+
 impl Drop for Decoder {
+    fn drop(&mut self) {
+        trace!("Decoder dropped");
+    }
+}
+
+// h264 allows no more frames in the dpb
+const MAX_REORDER_DEPTH: usize = 16;
+const BASELINE_PROFILE: u8 = 66;
+
+// Holds decoded frames until depth later ones were decoded and releases them by pts. The
+// session's output textures are reused for later frames, held ones are copied.
+#[derive(Default)]
+struct Reorder {
+    depth: usize,
+    held: Vec<HeldFrame>,
+    // decode order of the held frames, breaks pts ties
+    sequence: u64,
+    // of the frames returned by the previous call, free again with the next one
+    returned: Vec<(D3D11Ptr, i32, i32)>,
+    free: Vec<(D3D11Ptr, i32, i32)>,
+    frames: Vec<DecodeFrame>,
+}
+
+struct HeldFrame {
+    texture: D3D11Ptr,
+    width: i32,
+    height: i32,
+    // of the copy
+    desc: TextureDesc,
+    pts: i64,
+    seq: Option<u64>,
+    corrupted: bool,
+    crop: Crop,
+    cpu_copy: Option<BgraFrame>,
+    sequence: u64,
+}
+
+impl Reorder {
+    fn update_depth(&mut self, format: DataFormat, packet: &[u8]) {
+        let depth = annexb_nal_units(packet).find_map(|nal| match format {
+            H264 => h264::Sps::parse(nal)
+                .ok()
+                .map(|sps| match sps.max_num_reorder_frames {
+                    Some(n) => n,
+                    // baseline has no B-frames, otherwise every reference frame may be
+                    // reordered
+                    None if sps.profile_idc == BASELINE_PROFILE => 0,
+                    None => sps.max_num_ref_frames,
+                }),
+            H265 => hevc::Sps::parse(nal)
+                .ok()
+                .map(|sps| sps.max_num_reorder_pics.unwrap_or(MAX_REORDER_DEPTH as _)),
+            _ => None,
+        });
+        if let Some(depth) = depth {
+            let depth = (depth as usize).min(MAX_REORDER_DEPTH);
+            if depth != self.depth {
+                debug!("reorder depth {} -> {}", self.depth, depth);
+                self.depth = depth;
+            }
+        }
+    }
+
+    fn push(&mut self, frames: &mut Vec<DecodeFrame>) -> Result<&mut Vec<DecodeFrame>, i32> {
+        self.free.append(&mut self.returned);
+        self.frames.clear();
+        for frame in frames.drain(..) {
+            let texture = self.copy(&frame)?;
+            self.held.push(HeldFrame {
+                desc: texture_desc(texture.0),
+                texture,
+                width: frame.width,
+                height: frame.height,
+                pts: frame.pts,
+                seq: frame.seq,
+                corrupted: frame.corrupted,
+                crop: frame.crop,
+                cpu_copy: frame.cpu_copy,
+                sequence: self.sequence,
+            });
+            self.sequence += 1;
+        }
+        self.release(self.depth);
+        Ok(&mut self.frames)
+    }
+
+    fn copy(&mut self, frame: &DecodeFrame) -> Result<D3D11Ptr, i32> {
+        let size = (frame.width, frame.height);
+        let texture = match self.free.iter().position(|(_, w, h)| (*w, *h) == size) {
+            Some(i) => self.free.swap_remove(i).0,
+            None => {
+                let texture = unsafe {
+                    hwcodec_new_d3d11_texture_like(frame.texture, frame.width, frame.height)
+                };
+                if texture.is_null() {
+                    error!("failed to create a texture for a held frame");
+                    return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+                }
+                D3D11Ptr(texture)
+            }
+        };
+        match unsafe {
+            hwcodec_pad_d3d11_texture(frame.texture, texture.0, frame.width, frame.height)
+        } {
+            0 => Ok(texture),
+            err => Err(err),
+        }
+    }
+
+    // moves the held frames beyond keep with the lowest pts to frames
+    fn release(&mut self, keep: usize) {
+        while self.held.len() > keep {
+            let Some(i) = (0..self.held.len()).min_by_key(|i| {
+                let held = &self.held[*i];
+                (held.pts, held.sequence)
+            }) else {
+                break;
+            };
+            let held = self.held.swap_remove(i);
+            self.frames.push(DecodeFrame {
+                texture: held.texture.0,
+                width: held.width,
+                height: held.height,
+                desc: held.desc,
+                pts: held.pts,
+                seq: held.seq,
+                corrupted: held.corrupted,
+                crop: held.crop,
+                cpu_copy: held.cpu_copy,
+            });
+            self.returned.push((held.texture, held.width, held.height));
+        }
+    }
+}
+
+// The latest frame of the session, repeated for packets failing with
+// DecodeContext::conceal_errors. The session's output texture keeps it until the next
+// frame is decoded into it.
+#[derive(Default)]
+struct Concealment {
+    last: Option<DecodeFrame>,
+    frames: Vec<DecodeFrame>,
+}
+
+impl Concealment {
+    fn update(&mut self, frames: &[DecodeFrame]) {
+        if let Some(frame) = frames.last() {
+            self.last = Some(DecodeFrame {
+                cpu_copy: None,
+                ..*frame
+            });
+        }
+    }
+
+    // the latest frame again, marked corrupted, in place of the frame of a packet failing
+    // with err. Before the first frame that's the error.
+    fn repeat<'a>(
+        &'a mut self,
+        err: i32,
+        pts: i64,
+        seq: Option<u64>,
+        copy: bool,
+        readback: &mut Readback,
+        reorder: Option<&'a mut Reorder>,
+    ) -> Result<&'a mut Vec<DecodeFrame>, i32> {
+        let Some(last) = self.last.as_ref() else {
+            return Err(err);
+        };
+        debug!("concealing a packet failing with {} by the last frame", err);
+        let mut frame = DecodeFrame {
+            pts,
+            seq,
+            corrupted: true,
+            cpu_copy: None,
+            ..*last
+        };
+        if copy {
+            frame.cpu_copy = readback
+                .read(&frame)
+                .map_err(|e| error!("failed to copy a concealed frame: {}", e))
+                .ok();
+        }
+        self.frames.clear();
+        self.frames.push(frame);
+        match reorder {
+            Some(reorder) => reorder.push(&mut self.frames),
+            None => Ok(&mut self.frames),
+        }
+    }
+}
+
+// the staging texture and colour correction of decode_bgra and decode_with_copy
+#[derive(Default)]
+struct Readback {
+    staging: Option<(D3D11Ptr, i32, i32)>,
+    // rows of an affine map of r, g, b, None if the stream is bt.601 studio range
+    color: Option<[[f32; 4]; 3]>,
+}
+
+impl Readback {
+    fn update_color(&mut self, format: DataFormat, packet: &[u8]) {
+        if format == MJPEG {
+            // JFIF, bt.601 full range
+            self.color = color_correction(MATRIX_UNSPECIFIED, true);
+            return;
+        }
+        let vui = annexb_nal_units(packet).find_map(|nal| match format {
+            H264 => h264::Sps::parse(nal).ok().map(|sps| sps.vui),
+            H265 => hevc::Sps::parse(nal).ok().map(|sps| sps.vui),
+            _ => None,
+        });
+        let Some(vui) = vui else {
+            return;
+        };
+        let signal = vui.and_then(|vui| vui.video_signal);
+        let matrix = signal
+            .as_ref()
+            .and_then(|s| s.colour_description)
+            .map_or(MATRIX_UNSPECIFIED, |c| c[2]);
+        let full_range = signal.is_some_and(|s| s.full_range);
+        let color = color_correction(matrix, full_range);
+        if color.is_some() != self.color.is_some() {
+            debug!(
+                "readback colour: matrix {}, full range {}",
+                matrix, full_range
+            );
+        }
+        self.color = color;
+    }
+
+    fn read(&mut self, frame: &DecodeFrame) -> Result<BgraFrame, i32> {
+        let common = HwcodecErrno::HWCODEC_ERR_COMMON as i32;
+        let (width, height) = (frame.width, frame.height);
+        if !matches!(self.staging, Some((_, w, h)) if (w, h) == (width, height)) {
+            self.staging = None;
+            let texture =
+                unsafe { hwcodec_new_d3d11_staging_texture_like(frame.texture, width, height) };
+            if texture.is_null() {
+                error!("failed to create a {}x{} staging texture", width, height);
+                return Err(common);
+            }
+            self.staging = Some((D3D11Ptr(texture), width, height));
+        }
+        let Some((staging, _, _)) = self.staging.as_ref() else {
+            return Err(common);
+        };
+        let mut row_pitch = 0;
+        let ret = unsafe {
+            hwcodec_copy_to_d3d11_staging(frame.texture, staging.0, width, height, &mut row_pitch)
+        };
+        if ret != 0 {
+            return Err(common);
+        }
+        let stride = width * 4;
+        let mut data = vec![0; stride as usize * height as usize];
+        let ret = unsafe {
+            hwcodec_read_d3d11_bgra_texture(staging.0, data.as_mut_ptr(), stride, height)
+        };
+        if ret != 0 {
+            return Err(common);
+        }
+        if let Some(m) = self.color.as_ref() {
+            for pixel in data.chunks_exact_mut(4) {
+                let rgb = [pixel[2] as f32, pixel[1] as f32, pixel[0] as f32];
+                let apply = |row: &[f32; 4]| {
+                    (row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2] + row[3])
+                        .round()
+                        .clamp(0.0, 255.0) as u8
+                };
+                pixel[2] = apply(&m[0]);
+                pixel[1] = apply(&m[1]);
+                pixel[0] = apply(&m[2]);
+            }
+        }
+        Ok(BgraFrame {
+            data,
+            width,
+            height,
+            stride,
+            pts: frame.pts,
+            corrupted: frame.corrupted,
+        })
+    }
+}
+
+const MATRIX_UNSPECIFIED: u8 = 2;
+const BT601: (f32, f32) = (0.299, 0.114);
+
+// Kr and Kb of matrix_coeffs, None for bt.601 and those the sessions treat as it
+fn luma_coefficients(matrix: u8) -> Option<(f32, f32)> {
+    match matrix {
+        1 => Some((0.2126, 0.0722)),
+        9 | 10 => Some((0.2627, 0.0593)),
+        _ => None,
+    }
+}
+
+// 8 bit r, g, b to y, cb, cr
+fn to_ycbcr(rgb: [f32; 3], (kr, kb): (f32, f32), full_range: bool) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|v| v / 255.0);
+    let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+    let pb = (b - y) / (2.0 * (1.0 - kb));
+    let pr = (r - y) / (2.0 * (1.0 - kr));
+    if full_range {
+        [255.0 * y, 128.0 + 255.0 * pb, 128.0 + 255.0 * pr]
+    } else {
+        [16.0 + 219.0 * y, 128.0 + 224.0 * pb, 128.0 + 224.0 * pr]
+    }
+}
+
+fn to_rgb(ycbcr: [f32; 3], (kr, kb): (f32, f32), full_range: bool) -> [f32; 3] {
+    let [y, cb, cr] = ycbcr;
+    let (y, pb, pr) = if full_range {
+        (y / 255.0, (cb - 128.0) / 255.0, (cr - 128.0) / 255.0)
+    } else {
+        (
+            (y - 16.0) / 219.0,
+            (cb - 128.0) / 224.0,
+            (cr - 128.0) / 224.0,
+        )
+    };
+    let r = y + 2.0 * (1.0 - kr) * pr;
+    let b = y + 2.0 * (1.0 - kb) * pb;
+    let g = (y - kr * r - kb * b) / (1.0 - kr - kb);
+    [r, g, b].map(|v| v * 255.0)
+}
+
+// undoes the sessions' bt.601 studio range conversion and applies the stream's
+fn color_correction(matrix: u8, full_range: bool) -> Option<[[f32; 4]; 3]> {
+    let coefficients = luma_coefficients(matrix);
+    if coefficients.is_none() && !full_range {
+        return None;
+    }
+    let coefficients = coefficients.unwrap_or(BT601);
+    let convert = |rgb| to_rgb(to_ycbcr(rgb, BT601, false), coefficients, full_range);
+    // both conversions are affine, the map follows from the origin and unit vectors
+    let origin = convert([0.0; 3]);
+    let mut m = [[0.0; 4]; 3];
+    for column in 0..3 {
+        let mut unit = [0.0; 3];
+        unit[column] = 1.0;
+        for ((row, v), o) in m.iter_mut().zip(convert(unit)).zip(origin) {
+            row[column] = v - o;
+        }
+    }
+    for (row, o) in m.iter_mut().zip(origin) {
+        row[3] = o;
+    }
+    Some(m)
+}
+
+// None for CUSTOM and the drivers whose feature is disabled
+fn native_calls(driver: &Driver) -> Option<DecodeCalls> {
+    match driver {
+        #[cfg(feature = "nv")]
+        NV => Some(nv::decode_calls()),
+        #[cfg(feature = "amf")]
+        AMF => Some(amf::decode_calls()),
+        #[cfg(feature = "mfx")]
+        MFX => Some(mfx::decode_calls()),
+        #[cfg(feature = "vram-ffmpeg")]
+        FFMPEG => Some(ffmpeg::decode_calls()),
+        _ => None,
+    }
+}
+
+// the built-in drivers, a session of the C++ shims
+struct NativeDecoder {
+    calls: DecodeCalls,
+    codec: *mut c_void,
+    frames: CallbackFrames<DecodeFrame>,
+    conceal: bool,
+    _session: SessionGuard,
+}
+
+unsafe impl Send for NativeDecoder {}
+
+impl NativeDecoder {
+    fn new(calls: DecodeCalls, ctx: &DecodeContext) -> Result<Self, ()> {
+        let session = SessionGuard::new();
+        let codec = unsafe {
+            (calls.new)(
+                ctx.device.unwrap_or(std::ptr::null_mut()),
+                ctx.luid,
+                ctx.data_format as i32,
+            )
+        };
+        if codec.is_null() {
+            return Err(());
+        }
+        let decoder = Self {
+            calls,
+            codec,
+            frames: CallbackFrames::new(),
+            conceal: ctx.conceal_errors,
+            _session: session,
+        };
+        let ret = unsafe { (decoder.calls.set_conceal)(codec, ctx.conceal_errors as _) };
+        if ret != 0 {
+            error!("failed to set error concealment: {}", ret);
+            return Err(());
+        }
+        Ok(decoder)
+    }
+
+    unsafe extern "C" fn callback(texture: *mut c_void, obj: *const c_void) {
+        let desc = texture_desc(texture);
+        let frame = DecodeFrame {
+            texture,
+            width: desc.width,
+            height: desc.height,
+            desc,
+            pts: 0,
+            seq: None,
+            corrupted: false,
+            crop: Crop::default(),
+            cpu_copy: None,
+        };
+        CallbackFrames::push(obj, frame);
+    }
+}
+
+impl DecodeBackend for NativeDecoder {
+    fn decode(&mut self, packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
+        self.frames.get_mut().clear();
+        let ret = unsafe {
+            (self.calls.decode)(
+                self.codec,
+                packet.as_ptr() as _,
+                packet.len() as _,
+                Some(Self::callback),
+                self.frames.as_context(),
+            )
+        };
+        let stray = self.frames.take_stray();
+        if stray > 0 {
+            error!("{} frames delivered off the decode call", stray);
+            self.frames.get_mut().clear();
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        let frames = self.frames.get_mut();
+        // ffmpeg outputs the frames with the missing references generated
+        if ret == HwcodecErrno::HWCODEC_ERR_HEVC_COULD_NOT_FIND_POC as i32
+            && self.conceal
+            && !frames.is_empty()
+        {
+            frames.iter_mut().for_each(|f| f.corrupted = true);
+            return Ok(frames);
+        }
+        if ret != 0 {
+            Err(ret)
+        } else {
+            Ok(frames)
+        }
+    }
+
+    fn memory_usage(&self) -> MemoryInfo {
+        let mut info = MemoryInfo::default();
+        unsafe {
+            (self.calls.memory)(self.codec, &mut info);
+        }
+        info
+    }
+
+    fn caps(&self) -> DecodeCaps {
+        let mut caps = DecodeCaps::default();
+        if unsafe { (self.calls.caps)(self.codec, &mut caps) } != 0 {
+            return DecodeCaps::default();
+        }
+        caps
+    }
+}
+
+impl Drop for NativeDecoder {
     fn drop(&mut self) {
         unsafe {
             (self.calls.destroy)(self.codec);
-            self.codec = std::ptr::null_mut();
-            let _ = Box::from_raw(self.frames);
-            trace!("Decoder dropped");
         }
+        self.codec = std::ptr::null_mut();
     }
 }
 
 pub struct DecodeFrame {
     pub texture: *mut c_void,
+    // the displayed size, the texture may be larger
     pub width: i32,
     pub height: i32,
+    // of texture, to create a matching view or copy target. Its size is the allocated one,
+    // which may be aligned past width and height.
+    pub desc: TextureDesc,
+    // see Decoder::decode_with_pts, 0 for frames of decode
+    pub pts: i64,
+    // see Decoder::decode_with_seq, None for frames of other calls
+    pub seq: Option<u64>,
+    // concealed, see DecodeContext::conceal_errors
+    pub corrupted: bool,
+    // of the stream's coded size, already applied
+    pub crop: Crop,
+    // see Decoder::decode_with_copy, None for frames of other calls and failed copies
+    pub cpu_copy: Option<BgraFrame>,
+}
+
+unsafe impl Send for DecodeFrame {}
+
+// no texture, for custom backends filling in what they have
+impl Default for DecodeFrame {
+    fn default() -> Self {
+        Self {
+            texture: std::ptr::null_mut(),
+            width: 0,
+            height: 0,
+            desc: TextureDesc::default(),
+            pts: 0,
+            seq: None,
+            corrupted: false,
+            crop: Crop::default(),
+            cpu_copy: None,
+        }
+    }
+}
+
+// The cropping of an SPS from the coded size, e.g. 1920x1088, to the displayed one in
+// pixels. The decoders apply it, the displayed region starts at the top left of a frame's
+// texture and width and height are its size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Crop {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+// of the SPS in packet
+fn stream_crop(format: DataFormat, packet: &[u8]) -> Option<Crop> {
+    let crop = annexb_nal_units(packet).find_map(|nal| match format {
+        H264 => h264::Sps::parse(nal).ok().map(|sps| sps.crop()),
+        H265 => hevc::Sps::parse(nal).ok().map(|sps| sps.crop()),
+        _ => None,
+    })?;
+    let [left, right, top, bottom] = crop.map(|c| c.min(i32::MAX as u32) as i32);
+    Some(Crop {
+        left,
+        top,
+        right,
+        bottom,
+    })
+}
+
+// zeroed for a null texture
+fn texture_desc(texture: *mut c_void) -> TextureDesc {
+    let mut desc = TextureDesc::default();
+    if unsafe { hwcodec_get_d3d11_texture_desc(texture, &mut desc) } != 0 {
+        return TextureDesc::default();
+    }
+    desc
+}
+
+// a frame copied into the staging texture at index of the registered ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StagedFrame {
+    pub index: usize,
+    pub row_pitch: i32,
+    pub width: i32,
+    pub height: i32,
+    pub corrupted: bool,
+}
+
+// a frame of decode_bgra or a DecodeFrame::cpu_copy, rows of stride bytes without padding
+pub struct BgraFrame {
+    pub data: Vec<u8>,
+    pub width: i32,
+    pub height: i32,
+    pub stride: i32,
+    pub pts: i64,
+    pub corrupted: bool,
 }
 
 pub fn available() -> Vec<DecodeContext> {
+    available_with_failures(&mut vec![])
+}
+
+// available, with the native tests that failed, see report::capability_report
+pub(crate) fn available_with_failures(failures: &mut Vec<ProbeFailure>) -> Vec<DecodeContext> {
     use log::debug;
 
-    let mut codecs: Vec<_> = vec![];
+    let mut codecs: Vec<(Driver, InnerDecodeContext)> = vec![];
     // disable nv sdk decode
     // codecs.append(
     //     &mut nv::possible_support_decoders()
@@ -113,18 +1106,29 @@ pub fn available() -> Vec<DecodeContext> {
     //         .map(|n| (NV, n))
     //         .collect(),
     // );
+    // but it decodes MJPEG on NVIDIA adapters, which have no d3d11va MJPEG decoder
+    #[cfg(feature = "nv")]
+    codecs.extend(
+        nv::possible_support_decoders()
+            .into_iter()
+            .filter(|n| n.data_format == MJPEG)
+            .map(|n| (NV, n)),
+    );
+    #[cfg(feature = "vram-ffmpeg")]
     codecs.append(
         &mut ffmpeg::possible_support_decoders()
             .drain(..)
             .map(|n| (FFMPEG, n))
             .collect(),
     );
+    #[cfg(feature = "amf")]
     codecs.append(
         &mut amf::possible_support_decoders()
             .drain(..)
             .map(|n| (AMF, n))
             .collect(),
     );
+    #[cfg(feature = "mfx")]
     codecs.append(
         &mut mfx::possible_support_decoders()
             .drain(..)
@@ -140,6 +1144,8 @@ pub fn available() -> Vec<DecodeContext> {
             vendor: driver, // Initially set vendor same as driver, will be updated by test results
             data_format: n.data_format,
             luid: 0,
+            output_order: OutputOrder::Decode,
+            conceal_errors: false,
         })
         .collect();
 
@@ -147,6 +1153,7 @@ pub fn available() -> Vec<DecodeContext> {
     let mut exclude_luid_formats = Vec::<(i64, i32)>::new();
     let buf264 = &crate::common::DATA_H264_720P[..];
     let buf265 = &crate::common::DATA_H265_720P[..];
+    let mjpeg = &crate::common::DATA_MJPEG_720P[..];
 
     for input in inputs {
         debug!(
@@ -154,11 +1161,8 @@ pub fn available() -> Vec<DecodeContext> {
             input.driver, input.data_format
         );
 
-        let test = match input.driver {
-            NV => nv::decode_calls().test,
-            AMF => amf::decode_calls().test,
-            MFX => mfx::decode_calls().test,
-            FFMPEG => ffmpeg::decode_calls().test,
+        let Some(test) = native_calls(&input.driver).map(|calls| calls.test) else {
+            continue;
         };
 
         let mut luids: Vec<i64> = vec![0; crate::vram::MAX_ADATERS];
@@ -168,6 +1172,7 @@ pub fn available() -> Vec<DecodeContext> {
         let data = match input.data_format {
             H264 => buf264,
             H265 => buf265,
+            MJPEG => mjpeg,
             _ => {
                 debug!("Unsupported data format: {:?}, skipping", input.data_format);
                 continue;
@@ -215,6 +1220,12 @@ pub fn available() -> Vec<DecodeContext> {
                             continue;
                         },                    };
                     exclude_luid_formats.push((luids[i], input.data_format as i32));
+                    debug!(
+                        "{:?} decodes {:?} on {:?}",
+                        input.driver,
+                        input.data_format,
+                        adapter_path(input.luid)
+                    );
                     outputs.push(input);
                 }
             }
@@ -223,6 +1234,33 @@ pub fn available() -> Vec<DecodeContext> {
                 "vram decoder test failed: driver={:?}, error={}",
                 input.driver, result
             );
+            failures.push(ProbeFailure {
+                driver: input.driver.clone(),
+                data_format: input.data_format,
+                encode: false,
+                error: result,
+            });
+        }
+    }
+
+    // registered drivers come last and only add adapters and formats not covered yet
+    for driver in backend::decode_drivers() {
+        for format in [H264, H265, MJPEG] {
+            for (luid, vendor) in driver.test(format) {
+                if exclude_luid_formats.contains(&(luid, format as i32)) {
+                    continue;
+                }
+                exclude_luid_formats.push((luid, format as i32));
+                outputs.push(DecodeContext {
+                    device: None,
+                    driver: CUSTOM(driver.name().to_owned()),
+                    vendor,
+                    data_format: format,
+                    luid,
+                    output_order: OutputOrder::Decode,
+                    conceal_errors: false,
+                });
+            }
         }
     }
 
@@ -1,153 +1,2209 @@
+#[cfg(feature = "amf")]
+use crate::vram::amf;
+#[cfg(feature = "vram-ffmpeg")]
+use crate::vram::ffmpeg;
+#[cfg(feature = "mfx")]
+use crate::vram::mfx;
+#[cfg(feature = "nv")]
+use crate::vram::nv;
 use crate::{
-    common::Driver::*,
+    bitstream::{dump, h264, hevc, nal_units, validate::starts_stream, NalRef},
+    common::{
+        adapter_for_luid, same_adapter, AdapterInfo, CodecDescriptor, ColorConvert, ConfigCheck,
+        ConfigParam, DataFormat, Driver, Driver::*, EncodeCaps, EntropyCoding, FrameData,
+        FrameFlag::*, FrameFlags, GpuTiming, HwcodecErrno, LatencyHistogram, MemoryInfo,
+        RuntimeInfo, TextureDesc, FRAME_FLAG_LTR_SLOT_SHIFT,
+    },
     ffmpeg::init_av_log,
+    pattern::bgra_pattern,
     vram::{
-        amf, ffmpeg, inner::EncodeCalls, mfx, nv, DynamicContext, EncodeContext, FeatureContext,
+        adapter_path,
+        backend::{self, EncodeBackend},
+        d3d11::{read_bgra, Device, Texture},
+        inner::{
+            hwcodec_get_d3d11_texture_desc, hwcodec_get_d3d11_texture_width_height,
+            hwcodec_new_d3d11_texture_like, hwcodec_open_d3d11_shared_fence,
+            hwcodec_pad_d3d11_texture, hwcodec_wait_d3d11_fence, CallbackFrames, D3D11Ptr,
+            EncodeCalls, InnerEncodeContext, NewEncoderCall, SessionGuard,
+        },
+        report::ProbeFailure,
+        DynamicContext, EncodeContext, FeatureContext, OversizePolicy,
     },
 };
-use log::trace;
+use log::{debug, error, info, trace, warn};
+use std::{
+    collections::VecDeque, ffi::{CStr, CString}, fmt::Display, io::{self, Read, Write}, os::raw::{c_char, c_int, c_void}, slice::from_raw_parts, time::{Duration, Instant}
+};
+#[cfg(any(feature = "async", feature = "testutil"))]
+use std::sync::atomic::Ordering;
+#[cfg(feature = "testutil")]
+use std::sync::atomic::AtomicUsize;
+#[cfg(feature = "async")]
 use std::{
-    fmt::Display, os::raw::{c_int, c_void}, slice::from_raw_parts
+    future::Future,
+    pin::Pin,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    task::{Context, Poll, Waker},
 };
 
 pub struct Encoder {
+    backend: Box<dyn EncodeBackend>,
+    pub ctx: EncodeContext,
+    // of encode_synced
+    fence: Option<OpenedFence>,
+    latency: LatencyHistogram,
+    padding: Padding,
+    intervals: FrameIntervals,
+    // of sizes that aren't coded as they are
+    edge: Option<EdgePadding>,
+    headers: HeaderRepeat,
+    totals: Totals,
+    keyframes: KeyframeSchedule,
+    throttle: KeyframeThrottle,
+    durations: FrameDurations,
+    sequence: FrameSequence,
+    // see close_gop_at_next
+    close_gop: bool,
+    // see on_error
+    errors: ErrorCallback,
+    // see d.recovery
+    recovery: Recovery,
+    // the frames of the latest encode call
+    output: Vec<EncodeFrame>,
+}
+
+// a caller's shared fence opened on the encoder's device
+struct OpenedFence {
+    handle: *mut c_void,
+    device: Option<*mut c_void>,
+    fence: D3D11Ptr,
+}
+
+// NV12 has no odd sizes, such sessions encode the next even size. The input is copied
+// into a texture of that size with its last column and row repeated. 4:2:0 crop offsets
+// count pixel pairs, the SPS can't crop the margin away, so decoders output the coded
+// size and display_size tells what to show.
+struct EdgePadding {
+    device: Option<*mut c_void>,
+    texture: D3D11Ptr,
+}
+
+unsafe impl Send for Encoder {}
+unsafe impl Sync for Encoder {}
+
+impl Encoder {
+    // CUSTOM drivers are created by the EncodeDriver registered under their name.
+    pub fn new(ctx: EncodeContext) -> Result<Self, ()> {
+        Self::new_with_ffmpeg_options(ctx, &[])
+    }
+
+    // Like new, an FFMPEG session sets overrides, (key, value) pairs of options of the
+    // codec context or the vendor's encoder, after those ffmpeg_options shows. Fails if
+    // ffmpeg rejects one and for other drivers given overrides. Recreated sessions keep
+    // them.
+    pub fn new_with_ffmpeg_options(
+        ctx: EncodeContext,
+        overrides: &[(String, String)],
+    ) -> Result<Self, ()> {
+        init_av_log();
+        let overrides = ffmpeg_overrides(&ctx, overrides)?;
+        if ctx.d.emphasis_map && matches!(ctx.f.driver, AMF | MFX | FFMPEG) {
+            error!("{:?} has no emphasis maps", ctx.f.driver);
+            return Err(());
+        }
+        if ctx.d.reference_invalidation && matches!(ctx.f.driver, AMF | MFX | FFMPEG) {
+            error!("{:?} has no reference invalidation", ctx.f.driver);
+            return Err(());
+        }
+        if ctx.d.lossless && !has_lossless(&ctx.f) {
+            error!("{:?} has no lossless encoding", ctx.f.driver);
+            return Err(());
+        }
+        if ctx.d.reduced_sar().is_none() {
+            error!("invalid sample aspect ratio {:?}", ctx.d.sar);
+            return Err(());
+        }
+        if !(-MAX_CHROMA_QP_OFFSET..=MAX_CHROMA_QP_OFFSET).contains(&ctx.d.chroma_qp_offset) {
+            error!("chroma qp offset {} out of range", ctx.d.chroma_qp_offset);
+            return Err(());
+        }
+        if ctx.d.chroma_qp_offset != 0 && matches!(ctx.f.driver, AMF | MFX | FFMPEG) {
+            error!("{:?} has no chroma qp offset", ctx.f.driver);
+            return Err(());
+        }
+        if ctx.d.color_convert == ColorConvert::COLOR_CONVERT_SOFTWARE
+            && matches!(ctx.f.driver, NV | AMF)
+        {
+            error!("{:?} converts the input in the encoder", ctx.f.driver);
+            return Err(());
+        }
+        if ctx.d.kbitrate < 0 || !(0..=MAX_QUALITY).contains(&ctx.d.quality) {
+            error!(
+                "invalid bitrate {} or quality {}",
+                ctx.d.kbitrate, ctx.d.quality
+            );
+            return Err(());
+        }
+        let descriptor = ctx.f.descriptor();
+        if !descriptor.supports_entropy_coding(ctx.d.entropy_coding) {
+            error!(
+                "{:?} with {:?} is not supported",
+                descriptor.profile, ctx.d.entropy_coding
+            );
+            return Err(());
+        }
+        let coded = coded_context(&ctx);
+        let backend: Box<dyn EncodeBackend> = match (&ctx.f.driver, native_calls(&ctx.f.driver)) {
+            (_, Some(calls)) => {
+                let device = ctx.d.device.unwrap_or(std::ptr::null_mut());
+                let native = NativeEncoder::new(calls.new, calls, device, &coded, overrides)?;
+                Box::new(native)
+            }
+            (CUSTOM(name), None) => {
+                let Some(driver) = backend::encode_driver(name) else {
+                    error!("encode driver {} is not registered", name);
+                    return Err(());
+                };
+                driver.create(&coded)?
+            }
+            (driver, None) => {
+                error!("encode driver {:?} is not compiled in", driver);
+                return Err(());
+            }
+        };
+        check_one_in_one_out(&ctx, &*backend)?;
+        Ok(Self::assemble(backend, ctx))
+    }
+
+    // a backend created without going through a registered driver
+    pub fn from_backend(backend: Box<dyn EncodeBackend>, ctx: EncodeContext) -> Self {
+        init_av_log();
+        Self::assemble(backend, ctx)
+    }
+
+    // Wraps a session the caller created, only MFX sessions (mfxSession) are supported.
+    // The session must have a d3d11 device handle set, the input textures come from that
+    // device. The encoder installs its frame allocator on the session and never closes
+    // it, drop the encoder before closing the session.
+    // NVENC sessions fail: the NvEncoderD3D11 of the sdk opens the session it encodes
+    // with and destroys it with its buffers, and a session is initialized only once, so
+    // one the caller opened can neither be configured here nor outlive the encoder.
+    pub fn from_existing_session(
+        driver: Driver,
+        handle: *mut c_void,
+        ctx: EncodeContext,
+    ) -> Result<Self, ()> {
+        init_av_log();
+        if handle.is_null()
+            || ctx.f.driver != driver
+            || ctx.d.width % 2 == 1
+            || ctx.d.height % 2 == 1
+        {
+            return Err(());
+        }
+        #[cfg(feature = "mfx")]
+        if driver == MFX {
+            let new = mfx::mfx_new_encoder_from_session;
+            let native = NativeEncoder::new_external(new, mfx::encode_calls(), handle, &ctx)?;
+            check_one_in_one_out(&ctx, &native)?;
+            return Ok(Self::assemble(Box::new(native), ctx));
+        }
+        error!("only MFX sessions can be wrapped, not {:?}", driver);
+        Err(())
+    }
+
+    // the state of a new session around its backend
+    fn assemble(backend: Box<dyn EncodeBackend>, ctx: EncodeContext) -> Self {
+        Self {
+            backend,
+            ctx,
+            fence: None,
+            latency: LatencyHistogram::default(),
+            padding: Padding::default(),
+            intervals: FrameIntervals::default(),
+            edge: None,
+            headers: HeaderRepeat::default(),
+            totals: Totals::default(),
+            keyframes: KeyframeSchedule::default(),
+            throttle: KeyframeThrottle::default(),
+            durations: FrameDurations::default(),
+            close_gop: false,
+            errors: ErrorCallback::default(),
+            recovery: Recovery::default(),
+            sequence: FrameSequence::default(),
+            output: vec![],
+        }
+    }
+
+    // Call after encode returns an error HwcodecErrno::is_codec_lost accepts, the textures
+    // passed to encode must come from new_device afterwards. The first frame of the new
+    // session is an IDR. Fails for encoders of an existing session, its owner has to
+    // recreate the session.
+    pub fn recreate_after_device_lost(&mut self, new_device: *mut c_void) -> Result<(), ()> {
+        let mut ctx = self.ctx.clone();
+        ctx.d.device = Some(new_device);
+        self.backend.recreate(&coded_context(&ctx))?;
+        self.ctx = ctx;
+        self.errors.reported = false;
+        Ok(())
+    }
+
+    // Called once with the error when an encode call finds the device or session lost,
+    // HwcodecErrno::is_codec_lost, and again for a loss after recreate_after_device_lost.
+    // It runs on the thread of that encode call before the call returns, for an
+    // AsyncEncoder its worker thread, whether or not anyone awaits the frame, so apps
+    // that pipeline frames hear of it without polling every result. It must not block on
+    // the encoder, e.g. by awaiting an AsyncEncoder call.
+    pub fn on_error(&mut self, callback: impl Fn(i32) + Send + Sync + 'static) {
+        self.errors.callback = Some(Box::new(callback));
+    }
+
+    // ms becomes the pts of the frame as given, also when the calls are spaced unlike
+    // d.framerate.
+    pub fn encode(&mut self, tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.encode_frame(tex, ms, None, None)
+    }
+
+    // The frame of tex, for sessions held to d.one_in_one_out, others fail with
+    // HWCODEC_ERR_COMMON.
+    pub fn encode_one(&mut self, tex: *mut c_void, ms: i64) -> Result<EncodeFrame, i32> {
+        if !self.ctx.d.one_in_one_out() {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        let frame = self.encode(tex, ms)?.pop();
+        frame.ok_or(HwcodecErrno::HWCODEC_ERR_NOT_ONE_FRAME as _)
+    }
+
+    // user_data comes back on the EncodeFrames of this frame whenever they are emitted,
+    // e.g. a capture sequence number that doesn't rely on ms being unique
+    pub fn encode_with_user_data(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        user_data: u64,
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.encode_frame(tex, ms, Some(user_data), None)
+    }
+
+    // duration is how long the frame is shown in ms, e.g. the time until the next capture
+    // of a variable framerate source, which the pts can't tell for the latest frame. It
+    // comes back on the EncodeFrames of this frame for the muxer, see
+    // Muxer::write_video_with_duration. Frames given to encode get 1000 / d.framerate.
+    pub fn encode_with_duration(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        duration: i64,
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        if duration <= 0 {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        self.encode_frame(tex, ms, None, Some(duration))
+    }
+
+    fn encode_frame(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        user_data: Option<u64>,
+        duration: Option<i64>,
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        if let Err(e) = self.check_input(tex) {
+            self.errors.report(e);
+            return Err(e);
+        }
+        self.encode_checked(tex, ms, user_data, duration)
+    }
+
+    // encode_frame of a tex check_input accepted
+    fn encode_checked(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        user_data: Option<u64>,
+        duration: Option<i64>,
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        if let Err(e) = self.encode_output(tex, ms, user_data, duration) {
+            self.errors.report(e);
+            return Err(e);
+        }
+        Ok(&mut self.output)
+    }
+
+    // the frames of tex into output
+    fn encode_output(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        user_data: Option<u64>,
+        duration: Option<i64>,
+    ) -> Result<(), i32> {
+        let tex = self.pad_input(tex)?;
+        self.intervals.update(&mut *self.backend, &self.ctx, ms);
+        let closed = self.close_gop()?;
+        self.keyframes.schedule(&mut *self.backend, &self.ctx, ms)?;
+        self.throttle.schedule(&mut *self.backend, ms)?;
+        self.durations.input(ms, duration);
+        self.output.clear();
+        self.output.extend(closed);
+        let start = Instant::now();
+        self.encode_backend(tex, ms, user_data)?;
+        self.latency.record(start.elapsed());
+        if self.ctx.d.one_in_one_out() && self.output.len() != 1 {
+            warn!("{} frames out of one encode call", self.output.len());
+            self.output.clear();
+            self.backend.request_keyframe()?;
+            return Err(HwcodecErrno::HWCODEC_ERR_NOT_ONE_FRAME as _);
+        }
+        self.headers.repeat(&mut self.output, &self.ctx);
+        self.limit_size(tex, ms, user_data)?;
+        self.keyframes.seen(&self.output);
+        self.throttle.seen(&self.output);
+        self.padding.pad(&mut self.output, &self.ctx, ms);
+        self.totals.add(&self.output);
+        self.sequence.assign(&mut self.output);
+        self.split_oversized();
+        self.durations
+            .assign(&mut self.output, self.ctx.d.framerate);
+        Ok(())
+    }
+
+    // appends the frames the backend returns for tex, failures are recovered from as
+    // d.recovery says
+    fn encode_backend(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        user_data: Option<u64>,
+    ) -> Result<(), i32> {
+        let Some(policy) = self.ctx.d.recovery else {
+            return self.encode_native(tex, ms, user_data);
+        };
+        let mut result = self.encode_native(tex, ms, user_data);
+        let mut attempt = 0;
+        while let Err(e) = result {
+            if !Recovery::retries(e) || attempt >= policy.retries {
+                break;
+            }
+            attempt += 1;
+            self.recovery.stats.retries += 1;
+            debug!(
+                "encode failed with {}, retry {} of {}",
+                e, attempt, policy.retries
+            );
+            std::thread::sleep(Duration::from_millis(policy.backoff_ms as u64));
+            result = self.encode_native(tex, ms, user_data);
+        }
+        let Err(e) = result else {
+            self.recovery.failed = 0;
+            return Ok(());
+        };
+        let lost = e == HwcodecErrno::HWCODEC_ERR_SESSION_LOST as i32;
+        if !Recovery::retries(e) && !lost {
+            return Err(e);
+        }
+        self.recovery.stats.failed_frames += 1;
+        self.recovery.failed += 1;
+        if policy.reinit_after == 0 || (self.recovery.failed < policy.reinit_after && !lost) {
+            return Err(e);
+        }
+        warn!(
+            "{} frames failed in a row, last with {}, recreating the session",
+            self.recovery.failed, e
+        );
+        self.recovery.failed = 0;
+        self.recovery.stats.reinits += 1;
+        let fatal = HwcodecErrno::HWCODEC_ERR_SESSION_LOST as i32;
+        if self.backend.recreate(&coded_context(&self.ctx)).is_err() {
+            error!("recreating the session failed");
+            self.recovery.stats.fatal += 1;
+            return Err(fatal);
+        }
+        self.encode_native(tex, ms, user_data).map_err(|e| {
+            error!("the recreated session failed with {}", e);
+            self.recovery.stats.fatal += 1;
+            if HwcodecErrno::is_codec_lost(e) {
+                e
+            } else {
+                fatal
+            }
+        })
+    }
+
+    fn encode_native(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        user_data: Option<u64>,
+    ) -> Result<(), i32> {
+        let frames = match user_data {
+            Some(user_data) => self.backend.encode_with_user_data(tex, ms, user_data)?,
+            None => self.backend.encode(tex, ms)?,
+        };
+        let start = self.output.len();
+        self.output.append(frames);
+        tag_frames(&mut self.output[start..], self.ctx.f.data_format);
+        Ok(())
+    }
+
+    // Drop and Reencode of d.oversize, see OversizePolicy
+    fn limit_size(&mut self, tex: *mut c_void, ms: i64, user_data: Option<u64>) -> Result<(), i32> {
+        let max = self.ctx.d.max_frame_bytes;
+        if max <= 0 || self.ctx.d.oversize == OversizePolicy::Split {
+            return Ok(());
+        }
+        let reencode = self.ctx.d.oversize == OversizePolicy::Reencode && !self.bframes_active();
+        let mut delta = 0;
+        while let Some(i) = self.output.iter().position(|f| f.data.len() > max as usize) {
+            let size = self.output[i].data.len();
+            // the frames after it may reference it
+            self.output.truncate(i);
+            if reencode && delta < MAX_REENCODE_QP_DELTA {
+                delta += REENCODE_QP_STEP;
+                match self.backend.set_qp_delta(delta) {
+                    Ok(()) => {
+                        debug!(
+                            "re-encoding a frame of {} bytes beyond {} at qp +{}",
+                            size, max, delta
+                        );
+                        self.backend.request_keyframe()?;
+                        let start = self.output.len();
+                        self.encode_backend(tex, ms, user_data)?;
+                        self.headers.repeat(&mut self.output[start..], &self.ctx);
+                        continue;
+                    }
+                    Err(e) => debug!("no qp delta, dropping instead: {}", e),
+                }
+            }
+            debug!("dropped a frame of {} bytes beyond {}", size, max);
+            return self.close_gop_at_next();
+        }
+        Ok(())
+    }
+
+    // Split of d.oversize, after the totals counted the frames whole
+    fn split_oversized(&mut self) {
+        let max = self.ctx.d.max_frame_bytes;
+        if max <= 0
+            || self.ctx.d.oversize != OversizePolicy::Split
+            || self.output.iter().all(|f| f.data.len() <= max as usize)
+        {
+            return;
+        }
+        let max = max as usize;
+        for frame in std::mem::take(&mut self.output) {
+            if frame.data.len() <= max {
+                self.output.push(frame);
+                continue;
+            }
+            let fragments = fragments(&frame.data, max);
+            let last = fragments.len() - 1;
+            for (i, fragment) in fragments.into_iter().enumerate() {
+                let mut flags = frame.flags;
+                flags.insert(FRAME_FLAG_FRAGMENT);
+                if i > 0 {
+                    flags.remove(FRAME_FLAG_KEYFRAME);
+                }
+                if i == last {
+                    flags.insert(FRAME_FLAG_LAST_FRAGMENT);
+                }
+                self.output.push(EncodeFrame {
+                    data: fragment.into(),
+                    pts: frame.pts,
+                    key: if i == 0 { frame.key } else { 0 },
+                    flags,
+                    ltr_slot: frame.ltr_slot,
+                    user_data: frame.user_data,
+                    duration: frame.duration,
+                    seq: frame.seq,
+                });
+            }
+        }
+    }
+
+    // For sessions created with d.emphasis_map: a level from 0 to MAX_EMPHASIS_LEVEL per
+    // macroblock in raster order, see emphasis_map. Higher levels get a lower qp than the
+    // rate control chose, which may exceed the bitrate. The map applies to this frame only.
+    pub fn encode_with_emphasis_map(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        map: &[u8],
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        if !self.ctx.d.emphasis_map {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        let (columns, rows) = emphasis_map_size(self.ctx.d.width, self.ctx.d.height);
+        if map.len() != columns * rows || map.iter().any(|l| *l > MAX_EMPHASIS_LEVEL) {
+            return Err(HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _);
+        }
+        // before the map, which stays with the backend for the next frame
+        self.check_input(tex)?;
+        self.backend.set_emphasis_map(map)?;
+        self.encode_checked(tex, ms, None, None)
+    }
+
+    // For textures rendered on another queue or api: the gpu waits until fence reaches
+    // value before the encoder reads tex, the cpu doesn't block. fence is the NT handle
+    // of a shared fence, from ID3D12Device::CreateSharedHandle for a D3D12 fence created
+    // with D3D12_FENCE_FLAG_SHARED, ID3D11Fence::CreateSharedHandle for a d3d11 one, or
+    // vkGetSemaphoreWin32HandleKHR for a Vulkan timeline semaphore exported as
+    // VK_EXTERNAL_SEMAPHORE_HANDLE_TYPE_D3D12_FENCE_BIT. The producer signals value
+    // after its last write to tex. The handle stays owned by the caller and is opened
+    // once, pass the same one every frame. Needs windows 10 1703.
+    pub fn encode_synced(
+        &mut self,
+        tex: *mut c_void,
+        fence: *mut c_void,
+        value: u64,
+        ms: i64,
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.wait_fence(tex, fence, value)?;
+        self.encode(tex, ms)
+    }
+
+    // Resets the session to a new input size. The frames still buffered in the old session
+    // are returned first, the first frame of the new one is an IDR with new parameter sets.
+    // On error the encoder is unusable, like after a failed recreate_after_device_lost.
+    pub fn reset(&mut self, width: i32, height: i32) -> Result<Vec<EncodeFrame>, i32> {
+        if width <= 0 || height <= 0 {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        let mut frames = self.backend.flush()?;
+        tag_frames(&mut frames, self.ctx.f.data_format);
+        // the new session starts over with new parameter sets
+        if let Some(last) = frames.last_mut() {
+            last.flags.insert(FRAME_FLAG_END_OF_SEQUENCE);
+        }
+        self.headers.repeat(&mut frames, &self.ctx);
+        self.totals.add(&frames);
+        self.sequence.assign(&mut frames);
+        self.durations.assign(&mut frames, self.ctx.d.framerate);
+        let mut ctx = self.ctx.clone();
+        ctx.d.width = width;
+        ctx.d.height = height;
+        self.backend
+            .recreate(&coded_context(&ctx))
+            .map_err(|_| HwcodecErrno::HWCODEC_ERR_COMMON as i32)?;
+        debug!(
+            "encoder {:?} reset from {}x{} to {}x{}, {} frames flushed",
+            ctx.f.driver,
+            self.ctx.d.width,
+            self.ctx.d.height,
+            width,
+            height,
+            frames.len()
+        );
+        self.ctx = ctx;
+        self.edge = None;
+        self.headers = HeaderRepeat::default();
+        self.intervals.applied = 0;
+        self.keyframes = KeyframeSchedule::default();
+        self.throttle = KeyframeThrottle::default();
+        self.durations = FrameDurations::default();
+        self.close_gop = false;
+        Ok(frames)
+    }
+
+    // A session with B-frames may code frames that precede an IDR in display order after
+    // it, those could reference it. It is flushed and recreated instead.
+    fn close_gop(&mut self) -> Result<Vec<EncodeFrame>, i32> {
+        if !std::mem::take(&mut self.close_gop) {
+            return Ok(vec![]);
+        }
+        let mut frames = self.backend.flush()?;
+        tag_frames(&mut frames, self.ctx.f.data_format);
+        self.headers.repeat(&mut frames, &self.ctx);
+        self.backend
+            .recreate(&coded_context(&self.ctx))
+            .map_err(|_| HwcodecErrno::HWCODEC_ERR_COMMON as i32)?;
+        self.intervals.applied = 0;
+        debug!(
+            "encoder {:?} closed the gop, {} frames flushed",
+            self.ctx.f.driver,
+            frames.len()
+        );
+        Ok(frames)
+    }
+
+    // Sessions are created for one size, a texture of another size would be cropped,
+    // scaled or read out of bounds depending on the driver. Textures up to the display
+    // size rounded up to input_alignment are encoded from their top left.
+    fn check_input(&self, tex: *mut c_void) -> Result<(), i32> {
+        let Some((width, height)) = texture_size(tex) else {
+            return Ok(());
+        };
+        let (display_width, display_height) = self.display_size();
+        let (align_width, align_height) = self.input_alignment();
+        let fits = |size: i32, display: i32, align: u32| {
+            size >= display && size as u32 <= (display as u32).next_multiple_of(align)
+        };
+        if !fits(width, display_width, align_width) || !fits(height, display_height, align_height) {
+            debug!(
+                "input {}x{} doesn't match the {}x{} session aligned to {:?}",
+                width,
+                height,
+                display_width,
+                display_height,
+                self.input_alignment()
+            );
+            return Err(HwcodecErrno::HWCODEC_ERR_RESET_REQUIRED as _);
+        }
+        Ok(())
+    }
+
+    fn pad_input(&mut self, tex: *mut c_void) -> Result<*mut c_void, i32> {
+        let (width, height) = self.display_size();
+        let (coded_width, coded_height) = self.coded_size();
+        let size = texture_size(tex).unwrap_or((width, height));
+        // an sRGB typed input is copied into a UNORM texture, so that no backend samples
+        // it linearized
+        if !is_srgb(tex)
+            && (size == (coded_width, coded_height)
+                || ((width, height) == (coded_width, coded_height) && self.reads_top_left()))
+        {
+            return Ok(tex);
+        }
+        let device = self.ctx.d.device;
+        let padded = match &self.edge {
+            Some(e) if e.device == device => e.texture.0,
+            _ => {
+                self.edge = None;
+                let texture =
+                    unsafe { hwcodec_new_d3d11_texture_like(tex, coded_width, coded_height) };
+                if texture.is_null() {
+                    error!("failed to create the padded input texture");
+                    return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+                }
+                self.edge = Some(EdgePadding {
+                    device,
+                    texture: D3D11Ptr(texture),
+                });
+                texture
+            }
+        };
+        match unsafe { hwcodec_pad_d3d11_texture(tex, padded, width, height) } {
+            0 => Ok(padded),
+            err => Err(err),
+        }
+    }
+
+    fn wait_fence(&mut self, tex: *mut c_void, handle: *mut c_void, value: u64) -> Result<(), i32> {
+        let device = self.ctx.d.device;
+        let fence = match &self.fence {
+            Some(f) if f.handle == handle && f.device == device => f.fence.0,
+            _ => {
+                self.fence = None;
+                let fence = unsafe { hwcodec_open_d3d11_shared_fence(tex, handle) };
+                if fence.is_null() {
+                    error!("failed to open the shared fence");
+                    return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+                }
+                self.fence = Some(OpenedFence {
+                    handle,
+                    device,
+                    fence: D3D11Ptr(fence),
+                });
+                fence
+            }
+        };
+        match unsafe { hwcodec_wait_d3d11_fence(tex, fence, value) } {
+            0 => Ok(()),
+            err => Err(err),
+        }
+    }
+
+    // The first encode of a session compiles shaders and kernels. Call after new to pay
+    // for it with a blank frame whose packets are dropped. The next frame is an IDR.
+    pub fn warmup(&mut self) -> Result<(), i32> {
+        let Some(device) = self.ctx.d.device else {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        };
+        let (width, height) = (self.ctx.d.width, self.ctx.d.height);
+        let blank = vec![0u8; width as usize * height as usize * 4];
+        let texture = Texture::from_bgra(device, width, height, &blank)
+            .map_err(|_| HwcodecErrno::HWCODEC_ERR_COMMON as i32)?;
+        let start = Instant::now();
+        let tex = self.pad_input(texture.as_ptr())?;
+        // kept out of the latency histogram
+        self.backend.encode(tex, 0)?.clear();
+        // the blank frame would be the reference of the next one
+        self.request_keyframe()?;
+        debug!(
+            "encoder {:?} warmed up in {:?}",
+            self.ctx.f.driver,
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    // Destroys the native session now instead of when the encoder is dropped, for apps
+    // that release the device right after and want to know whether the teardown failed.
+    // The session is gone either way, frames still buffered in it are discarded.
+    pub fn close(mut self) -> Result<(), i32> {
+        let result = self.backend.close();
+        if let Err(e) = result {
+            error!("encoder {:?} failed to close: {}", self.ctx.f.driver, e);
+        }
+        result
+    }
+
+    // the next encoded frame is an IDR carrying the parameter sets
+    pub fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.backend.request_keyframe()
+    }
+
+    // For requests on every NACK or PLI of a receiver, which come in bursts. Like
+    // request_keyframe the next frame is an IDR, unless it's within min_interval of the
+    // latest keyframe, requested or not. Then the requests until then are coalesced into
+    // one for the first frame min_interval after that keyframe, or dropped if the session
+    // codes one of its own before. Intervals are measured in the ms given to encode.
+    pub fn request_keyframe_throttled(&mut self, min_interval: Duration) {
+        self.throttle.request(min_interval);
+    }
+
+    // The next frame starts a closed GOP for splicing and segmenting: it is an IDR, no
+    // frame after it references one before it and all frames before it come out first.
+    // request_keyframe only asks for an IDR, which suffices for sessions without B-frames.
+    // Sessions with them are flushed and recreated with the next encode call, its frames
+    // are those of the old session followed by the IDR. On error the encoder is unusable,
+    // like after a failed reset.
+    pub fn close_gop_at_next(&mut self) -> Result<(), i32> {
+        if self.bframes_active() {
+            self.close_gop = true;
+            return Ok(());
+        }
+        self.backend.request_keyframe()
+    }
+
+    // For feedback of a receiver that lost a frame, sequence is its pts, the ms it was
+    // given to encode. Sessions created with d.reference_invalidation drop it and the
+    // frames after it from their references, the next frame references an earlier one
+    // the receiver has and is no IDR. Others, frames no longer among the references and
+    // losses reported before the earliest one get request_keyframe instead.
+    pub fn invalidate_reference(&mut self, sequence: u64) -> Result<(), i32> {
+        if self.ctx.d.reference_invalidation && !self.bframes_active() {
+            let result = i64::try_from(sequence)
+                .map_err(|_| HwcodecErrno::HWCODEC_ERR_COMMON as i32)
+                .and_then(|ms| self.backend.invalidate_reference(ms));
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!(
+                    "frame {} not invalidated, requesting a keyframe: {}",
+                    sequence, e
+                ),
+            }
+        }
+        self.backend.request_keyframe()
+    }
+
+    // for an encoder taken out of an EncoderPool, the next frame starts the stream anew
+    pub(crate) fn restart(&mut self) -> Result<(), i32> {
+        self.backend.request_keyframe()?;
+        self.padding = Padding::default();
+        self.intervals.last_ms = None;
+        self.totals = Totals::default();
+        self.keyframes = KeyframeSchedule::default();
+        self.throttle = KeyframeThrottle::default();
+        self.durations = FrameDurations::default();
+        self.recovery = Recovery::default();
+        Ok(())
+    }
+
+    // Validates the native session without encoding, for when the input went stale
+    // (secure desktop, user switch, RDP) and the encoder may not have survived it.
+    pub fn is_healthy(&self) -> bool {
+        match self.backend.check() {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("encoder {:?} unhealthy: {}", self.ctx.f.driver, e);
+                false
+            }
+        }
+    }
+
+    // allocated is the video memory usage measured around the creation of the session
+    pub fn memory_usage(&self) -> MemoryInfo {
+        self.backend.memory_usage()
+    }
+
+    // The size in the bitstream, what decoders output. The width and height of ctx
+    // rounded up to even, the backends crop larger aligned sizes in the SPS.
+    pub fn coded_size(&self) -> (i32, i32) {
+        let d = coded_context(&self.ctx).d;
+        (d.width, d.height)
+    }
+
+    // the width and height of ctx, of the input textures and what players should show
+    pub fn display_size(&self) -> (i32, i32) {
+        (self.ctx.d.width, self.ctx.d.height)
+    }
+
+    // The width and height of the pictures in pixels, the display_size. With
+    // sample_aspect_ratio players show them width * sar.0 / sar.1 wide.
+    pub fn dimensions(&self) -> (i32, i32) {
+        self.display_size()
+    }
+
+    // d.sar in lowest terms, as written into the vui
+    pub fn sample_aspect_ratio(&self) -> (u32, u32) {
+        self.ctx.d.reduced_sar().unwrap_or((1, 1))
+    }
+
+    // The width and height alignment of the input textures: besides ones of the display
+    // size, textures of the display size rounded up to it are taken and only their top
+    // left display size is encoded, e.g. the aligned surfaces of a capture api or of
+    // Decoder. Larger ones fail with HWCODEC_ERR_RESET_REQUIRED like other sizes.
+    // NV: (2, 2), the input is copied whole into a buffer of the coded size.
+    // AMF: (2, 2), the texture is wrapped as the input surface of the coded size.
+    // MFX: (16, 16), the size its surfaces are aligned to, converted from the top left.
+    // FFmpeg: (2, 2), the hardware frames are the coded size, converted from the top left.
+    // Registered drivers report theirs with EncodeBackend::input_alignment. Where the
+    // backend can't take a texture as it is, it's copied into one of the coded size first.
+    pub fn input_alignment(&self) -> (u32, u32) {
+        match self.ctx.f.driver {
+            NV | AMF | FFMPEG => (2, 2),
+            MFX => (16, 16),
+            CUSTOM(_) => self.backend.input_alignment(),
+        }
+    }
+
+    // NV and AMF take the whole texture as the picture
+    fn reads_top_left(&self) -> bool {
+        !matches!(self.ctx.f.driver, NV | AMF)
+    }
+
+    // the runtime the session was created on, for MFX whether the VPL or the Media SDK one
+    pub fn info(&self) -> EncoderInfo {
+        self.backend.info()
+    }
+
+    // Whether the frames come out in decode order, muxers then need a reorder buffer and
+    // dts apart from pts. The sessions are configured without B-frames, backends that
+    // enable them anyway report it here.
+    pub fn bframes_active(&self) -> bool {
+        self.backend.info().bframes > 0
+    }
+
+    // Wall time of the successful encode calls since the session was created or
+    // reset_stats was called, warmup excluded.
+    pub fn latency_histogram(&self) -> &LatencyHistogram {
+        &self.latency
+    }
+
+    // The frames returned by the encode calls and reset since the session was created,
+    // for the metadata of a recording. Frames still buffered in the session aren't
+    // counted until reset returns them.
+    pub fn summary(&self) -> EncodeSummary {
+        self.totals.summary(self.ctx.d.framerate)
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.latency.reset();
+        self.recovery.stats = RecoveryStats::default();
+    }
+
+    // What d.recovery did since the session was created or reset_stats was called.
+    pub fn recovery_stats(&self) -> RecoveryStats {
+        self.recovery.stats
+    }
+
+    // With d.gpu_timing, the gpu time of the frames whose queries have completed since
+    // the last call, oldest first. They lag the encode calls by a few frames, frame is
+    // the index of the encode call counted from the session's creation and userData the
+    // one it was given. Empty for FFmpeg, whose conversion and encode run inside it.
+    pub fn gpu_timings(&mut self) -> Vec<GpuTiming> {
+        self.backend.gpu_timings()
+    }
+
+    // A session created in quality mode, d.kbitrate 0, or with d.lossless has no bitrate
+    // to change and a session can't switch to it either, both fail. Recreate the encoder
+    // for that.
+    pub fn set_bitrate(&mut self, kbs: i32) -> Result<(), i32> {
+        if self.ctx.d.kbitrate <= 0 || self.ctx.d.lossless || kbs <= 0 {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        self.backend.set_bitrate(kbs)?;
+        self.ctx.d.kbitrate = kbs;
+        Ok(())
+    }
+
+    // with d.variable_framerate the upper bound
+    pub fn set_framerate(&mut self, framerate: i32) -> Result<(), i32> {
+        self.backend.set_framerate(framerate)?;
+        self.ctx.d.framerate = framerate;
+        self.intervals.applied = 0;
+        Ok(())
+    }
+
+    // d.min_kbitrate at runtime, 0 turns the padding off
+    pub fn set_min_bitrate(&mut self, kbs: i32) -> Result<(), i32> {
+        if kbs < 0 || kbs > 0 && !Padding::supported(self.ctx.f.data_format) {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        self.ctx.d.min_kbitrate = kbs;
+        Ok(())
+    }
+}
+
+// a pause in the input doesn't turn into a burst of filler
+const MAX_PADDING_GAP_MS: i64 = 200;
+
+// every 6 qp halve the size
+const REENCODE_QP_STEP: i32 = 6;
+const MAX_REENCODE_QP_DELTA: i32 = 3 * REENCODE_QP_STEP;
+
+// Appends filler data NAL units to the last frame of an encode call so the frames carry
+// d.min_kbitrate over the time since the previous padded call. Native filler insertion
+// only pads up to the CBR target, so it is done here for every driver.
+#[derive(Default)]
+struct Padding {
+    last_ms: Option<i64>,
+}
+
+impl Padding {
+    fn supported(format: DataFormat) -> bool {
+        format == DataFormat::H264 || format == DataFormat::H265
+    }
+
+    fn pad(&mut self, frames: &mut [EncodeFrame], ctx: &EncodeContext, ms: i64) {
+        let kbs = ctx.d.min_kbitrate as i64;
+        if frames.is_empty() {
+            return;
+        }
+        if kbs <= 0 || !Padding::supported(ctx.f.data_format) {
+            self.last_ms = None;
+            return;
+        }
+        let Some(last_ms) = self.last_ms.replace(ms) else {
+            return;
+        };
+        let elapsed = (ms - last_ms).clamp(0, MAX_PADDING_GAP_MS);
+        let wanted = (kbs * elapsed / 8) as usize;
+        let encoded: usize = frames.iter().map(|f| f.data.len()).sum();
+        if wanted <= encoded {
+            return;
+        }
+        let Some(last) = frames.last_mut() else {
+            return;
+        };
+        let mut len = wanted - encoded;
+        let max = ctx.d.max_frame_bytes as usize;
+        if max > 0 {
+            len = len.min(max.saturating_sub(last.data.len()));
+        }
+        let filler = if ctx.f.data_format == DataFormat::H264 {
+            h264::filler_nal(len)
+        } else {
+            hevc::filler_nal(len)
+        };
+        // filler_nal doesn't go below its minimum length
+        if max > 0 && last.data.len() + filler.len() > max {
+            return;
+        }
+        let mut data = last.data.to_vec();
+        data.extend(filler);
+        last.data = data.into();
+    }
+}
+
+// Sets the framerate of the rate control to the rate of the latest frame interval when
+// d.variable_framerate is on, so that bits are budgeted by elapsed time. Small changes
+// aren't applied, every change reconfigures the session.
+#[derive(Default)]
+struct FrameIntervals {
+    last_ms: Option<i64>,
+    // the rate the backend runs at, 0 for d.framerate
+    applied: i32,
+    unsupported: bool,
+}
+
+impl FrameIntervals {
+    fn update(&mut self, backend: &mut dyn EncodeBackend, ctx: &EncodeContext, ms: i64) {
+        if !ctx.d.variable_framerate || self.unsupported {
+            return;
+        }
+        let Some(last_ms) = self.last_ms.replace(ms) else {
+            return;
+        };
+        let max = ctx.d.framerate.max(1);
+        let interval = (ms - last_ms).clamp(1, 1000);
+        let rate = ((1000 + interval / 2) / interval) as i32;
+        let rate = rate.clamp(1, max);
+        let current = if self.applied > 0 { self.applied } else { max };
+        if (rate - current).abs() * 8 <= current {
+            return;
+        }
+        match backend.set_framerate(rate) {
+            Ok(()) => self.applied = rate,
+            Err(_) => {
+                warn!(
+                    "{:?} can't change the framerate, variable framerate is off",
+                    ctx.f.driver
+                );
+                self.unsupported = true;
+            }
+        }
+    }
+}
+
+// Some backends put the parameter sets only on the first keyframe, or on the requested
+// ones. With d.repeat_headers the latest ones are inserted before the keyframes that
+// come without.
+#[derive(Default)]
+struct HeaderRepeat {
+    // the vps, sps and pps NAL units of the latest frame that had an sps
+    sets: Vec<Vec<u8>>,
+}
+
+impl HeaderRepeat {
+    fn repeat(&mut self, frames: &mut [EncodeFrame], ctx: &EncodeContext) {
+        let format = ctx.f.data_format;
+        if !ctx.d.repeat_headers || format != DataFormat::H264 && format != DataFormat::H265 {
+            return;
+        }
+        let (sps_type, set_types) = if format == DataFormat::H264 {
+            (h264::NAL_SPS, h264::NAL_SPS..=h264::NAL_PPS)
+        } else {
+            (hevc::NAL_SPS, hevc::NAL_VPS..=hevc::NAL_PPS)
+        };
+        for frame in frames.iter_mut() {
+            let mut sets = vec![];
+            let mut sps = false;
+            for nal in frame.nal_units() {
+                let t = if format == DataFormat::H264 {
+                    nal.h264_type()
+                } else {
+                    nal.hevc_type()
+                };
+                sps |= t == sps_type;
+                if set_types.contains(&t) {
+                    sets.push(nal.data.to_vec());
+                }
+            }
+            if sps {
+                self.sets = sets;
+            } else if frame.flags.has(FRAME_FLAG_KEYFRAME) && !self.sets.is_empty() {
+                let annexb =
+                    frame.data.starts_with(&[0, 0, 1]) || frame.data.starts_with(&[0, 0, 0, 1]);
+                let mut data = vec![];
+                for set in self.sets.iter() {
+                    if annexb {
+                        data.extend_from_slice(&[0, 0, 0, 1]);
+                    } else {
+                        data.extend_from_slice(&(set.len() as u32).to_be_bytes());
+                    }
+                    data.extend_from_slice(set);
+                }
+                data.extend_from_slice(&frame.data);
+                frame.data = data.into();
+            }
+        }
+    }
+}
+
+// Keeps key and FRAME_FLAG_KEYFRAME of the backend's frames the same, custom backends may
+// set only key. Recovery points are found from their SEI, no backend reports them.
+fn tag_frames(frames: &mut [EncodeFrame], format: DataFormat) {
+    for frame in frames.iter_mut() {
+        if frame.key == 1 {
+            frame.flags.insert(FRAME_FLAG_KEYFRAME);
+        }
+        frame.key = frame.flags.has(FRAME_FLAG_KEYFRAME) as i32;
+        if !frame.flags.has(FRAME_FLAG_KEYFRAME) && frame.recovery_point(format).is_some() {
+            frame.flags.insert(FRAME_FLAG_RECOVERY_POINT_START);
+        }
+    }
+}
+
+// Requests a keyframe when d.max_gop frames were given to the session since the latest
+// one. Keyframes are told apart by the pts of the output, so that frames a session
+// holds back, drops and scene cut keyframes it inserts are counted where they were input.
+#[derive(Default)]
+struct KeyframeSchedule {
+    // the ms of the latest inputs, at most d.max_gop
+    recent: VecDeque<i64>,
+    inputs: u64,
+    // the input number of the latest keyframe, requested or seen
+    last_key: u64,
+}
+
+impl KeyframeSchedule {
+    // before input ms is encoded
+    fn schedule(
+        &mut self,
+        backend: &mut dyn EncodeBackend,
+        ctx: &EncodeContext,
+        ms: i64,
+    ) -> Result<(), i32> {
+        let max = ctx.d.max_gop;
+        if max <= 0 {
+            return Ok(());
+        }
+        if self.inputs - self.last_key >= max as u64 {
+            backend.request_keyframe()?;
+            self.last_key = self.inputs;
+        }
+        if self.recent.len() >= max as usize {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(ms);
+        self.inputs += 1;
+        Ok(())
+    }
+
+    fn seen(&mut self, frames: &[EncodeFrame]) {
+        for frame in frames.iter().filter(|f| f.flags.has(FRAME_FLAG_KEYFRAME)) {
+            // the latest input of a repeated ms
+            if let Some(i) = self.recent.iter().rposition(|ms| *ms == frame.pts) {
+                let input = self.inputs - (self.recent.len() - i) as u64;
+                self.last_key = self.last_key.max(input);
+            }
+        }
+    }
+}
+
+// see Encoder::request_keyframe_throttled
+#[derive(Default)]
+struct KeyframeThrottle {
+    // a request waiting for min_interval to pass since the latest keyframe
+    pending: bool,
+    min_interval: Duration,
+    // the pts of the latest keyframe, requested or seen
+    last_key: Option<i64>,
+}
+
+impl KeyframeThrottle {
+    fn request(&mut self, min_interval: Duration) {
+        self.pending = true;
+        self.min_interval = min_interval;
+    }
+
+    // before input ms is encoded
+    fn schedule(&mut self, backend: &mut dyn EncodeBackend, ms: i64) -> Result<(), i32> {
+        if !self.pending {
+            return Ok(());
+        }
+        let min_interval = self.min_interval.as_millis().min(i64::MAX as u128) as i64;
+        if let Some(last) = self.last_key {
+            if ms.saturating_sub(last) < min_interval {
+                return Ok(());
+            }
+        }
+        backend.request_keyframe()?;
+        self.pending = false;
+        self.last_key = Some(ms);
+        Ok(())
+    }
+
+    fn seen(&mut self, frames: &[EncodeFrame]) {
+        for frame in frames.iter().filter(|f| f.flags.has(FRAME_FLAG_KEYFRAME)) {
+            self.pending = false;
+            self.last_key = Some(self.last_key.map_or(frame.pts, |last| last.max(frame.pts)));
+        }
+    }
+}
+
+// The durations given to encode_with_duration, matched to the output by pts like
+// KeyframeSchedule so that frames a session holds back or reorders get theirs.
+#[derive(Default)]
+struct FrameDurations {
+    // the ms and duration of the latest inputs, None for 1000 / d.framerate
+    recent: VecDeque<(i64, Option<i64>)>,
+}
+
+// more than any session holds back
+const MAX_PENDING_DURATIONS: usize = 64;
+
+impl FrameDurations {
+    fn input(&mut self, ms: i64, duration: Option<i64>) {
+        if self.recent.len() >= MAX_PENDING_DURATIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((ms, duration));
+    }
+
+    fn assign(&self, frames: &mut [EncodeFrame], framerate: i32) {
+        let default = 1000 / framerate.max(1) as i64;
+        for frame in frames.iter_mut() {
+            // the latest input of a repeated ms
+            frame.duration = self
+                .recent
+                .iter()
+                .rfind(|(ms, _)| *ms == frame.pts)
+                .and_then(|(_, duration)| *duration)
+                .unwrap_or(default);
+        }
+    }
+}
+
+// EncodeFrame::seq, numbering the frames of an Encoder in output order
+#[derive(Default)]
+struct FrameSequence {
+    next: u64,
+}
+
+impl FrameSequence {
+    fn assign(&mut self, frames: &mut [EncodeFrame]) {
+        for frame in frames.iter_mut() {
+            frame.seq = self.next;
+            self.next = self.next.wrapping_add(1);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    frames: u64,
+    bytes: u64,
+    keyframes: u64,
+    // the lowest and highest pts, frames may come out of display order
+    pts: Option<(i64, i64)>,
+}
+
+impl Totals {
+    fn add(&mut self, frames: &[EncodeFrame]) {
+        for frame in frames {
+            self.frames += 1;
+            self.bytes += frame.data.len() as u64;
+            self.keyframes += frame.flags.has(FRAME_FLAG_KEYFRAME) as u64;
+            let (first, last) = self.pts.get_or_insert((frame.pts, frame.pts));
+            *first = (*first).min(frame.pts);
+            *last = (*last).max(frame.pts);
+        }
+    }
+
+    fn summary(&self, framerate: i32) -> EncodeSummary {
+        let duration = match self.pts {
+            Some((first, last)) => {
+                Duration::from_millis((last - first) as u64)
+                    + Duration::from_secs(1) / framerate.max(1) as u32
+            }
+            None => Duration::ZERO,
+        };
+        EncodeSummary {
+            frames: self.frames,
+            bytes: self.bytes,
+            keyframes: self.keyframes,
+            duration,
+        }
+    }
+}
+
+// see RecoveryPolicy
+#[derive(Default)]
+struct Recovery {
+    // frames failing in a row after their retries
+    failed: u32,
+    stats: RecoveryStats,
+}
+
+impl Recovery {
+    // the errors of the session, the others need the caller
+    fn retries(err: i32) -> bool {
+        !HwcodecErrno::is_codec_lost(err)
+            && !HwcodecErrno::is_input_lost(err)
+            && !HwcodecErrno::is_reset_required(err)
+    }
+}
+
+#[derive(Default)]
+struct ErrorCallback {
+    callback: Option<Box<dyn Fn(i32) + Send + Sync>>,
+    // the loss was reported, the calls after it fail the same way
+    reported: bool,
+}
+
+impl ErrorCallback {
+    fn report(&mut self, err: i32) {
+        if !HwcodecErrno::is_codec_lost(err) || self.reported {
+            return;
+        }
+        self.reported = true;
+        if let Some(callback) = &self.callback {
+            callback(err);
+        }
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        trace!("Encoder dropped");
+    }
+}
+
+// see DynamicContext::one_in_one_out
+fn check_one_in_one_out(ctx: &EncodeContext, backend: &dyn EncodeBackend) -> Result<(), ()> {
+    if !ctx.d.one_in_one_out() {
+        return Ok(());
+    }
+    if ctx.d.max_frame_bytes > 0 {
+        error!("one frame out per frame in can't have a max_frame_bytes");
+        return Err(());
+    }
+    let bframes = backend.info().bframes;
+    if bframes > 0 {
+        error!(
+            "{:?} runs with {} B-frames, not one frame out per frame in",
+            ctx.f.driver, bframes
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+// None for the null texture of fake backends
+pub(crate) fn texture_size(tex: *mut c_void) -> Option<(i32, i32)> {
+    if tex.is_null() {
+        return None;
+    }
+    let (mut width, mut height) = (0, 0);
+    unsafe { hwcodec_get_d3d11_texture_width_height(tex, &mut width, &mut height) };
+    Some((width, height))
+}
+
+fn is_srgb(tex: *mut c_void) -> bool {
+    let mut desc = TextureDesc::default();
+    if unsafe { hwcodec_get_d3d11_texture_desc(tex, &mut desc) } != 0 {
+        return false;
+    }
+    SRGB_FORMATS.contains(&desc.format)
+}
+
+fn coded_context(ctx: &EncodeContext) -> EncodeContext {
+    let mut coded = ctx.clone();
+    coded.d.width += coded.d.width % 2;
+    coded.d.height += coded.d.height % 2;
+    if coded.d.max_gop > 0 {
+        // the keyframes between scene cuts come from KeyframeSchedule
+        coded.d.gop = 0;
+    }
+    coded
+}
+
+// None for CUSTOM and the drivers whose feature is disabled
+fn native_calls(driver: &Driver) -> Option<EncodeCalls> {
+    match driver {
+        #[cfg(feature = "nv")]
+        NV => Some(nv::encode_calls()),
+        #[cfg(feature = "amf")]
+        AMF => Some(amf::encode_calls()),
+        #[cfg(feature = "mfx")]
+        MFX => Some(mfx::encode_calls()),
+        #[cfg(feature = "vram-ffmpeg")]
+        FFMPEG => Some(ffmpeg::encode_calls()),
+        _ => None,
+    }
+}
+
+// the built-in drivers, a session of the C++ shims
+struct NativeEncoder {
     calls: EncodeCalls,
     codec: *mut c_void,
-    frames: *mut Vec<EncodeFrame>,
-    pub ctx: EncodeContext,
+    frames: CallbackFrames<EncodeFrame>,
+    // created by the caller, see Encoder::from_existing_session
+    external_session: bool,
+    // see Encoder::new_with_ffmpeg_options
+    overrides: Option<CString>,
+    _session: SessionGuard,
 }
 
-unsafe impl Send for Encoder {}
-unsafe impl Sync for Encoder {}
+unsafe impl Send for NativeEncoder {}
+
+impl NativeEncoder {
+    fn new(
+        new: NewEncoderCall,
+        calls: EncodeCalls,
+        handle: *mut c_void,
+        ctx: &EncodeContext,
+        overrides: Option<CString>,
+    ) -> Result<Self, ()> {
+        let session = SessionGuard::new();
+        Ok(Self {
+            codec: Self::new_codec(new, handle, ctx, overrides.as_deref())?,
+            calls,
+            frames: CallbackFrames::new(),
+            external_session: false,
+            overrides,
+            _session: session,
+        })
+    }
+
+    // of a session the caller created, see Encoder::from_existing_session
+    #[cfg(feature = "mfx")]
+    fn new_external(
+        new: NewEncoderCall,
+        calls: EncodeCalls,
+        handle: *mut c_void,
+        ctx: &EncodeContext,
+    ) -> Result<Self, ()> {
+        let mut native = Self::new(new, calls, handle, ctx, None)?;
+        native.external_session = true;
+        Ok(native)
+    }
+
+    fn new_codec(
+        new: NewEncoderCall,
+        handle: *mut c_void,
+        ctx: &EncodeContext,
+        overrides: Option<&CStr>,
+    ) -> Result<*mut c_void, ()> {
+        let mut options = ctx.d.encode_options();
+        options.ffmpegOptions = overrides.map_or(std::ptr::null(), |o| o.as_ptr());
+        let codec = unsafe {
+            new(
+                handle,
+                ctx.f.luid,
+                ctx.f.data_format as i32,
+                ctx.d.width,
+                ctx.d.height,
+                ctx.d.kbitrate,
+                ctx.d.framerate,
+                ctx.d.gop,
+                &options,
+            )
+        };
+        if codec.is_null() {
+            return Err(());
+        }
+        Ok(codec)
+    }
+
+    fn status(ret: i32) -> Result<(), i32> {
+        match ret {
+            0 => Ok(()),
+            err => Err(err),
+        }
+    }
+
+    extern "C" fn callback(
+        data: *const u8,
+        size: c_int,
+        flags: i32,
+        obj: *const c_void,
+        pts: i64,
+        user_data: u64,
+    ) {
+        let flags = flags as u32;
+        let slot_bits = u32::MAX << FRAME_FLAG_LTR_SLOT_SHIFT;
+        let flags_only = FrameFlags(flags & !slot_bits);
+        let frame = EncodeFrame {
+            data: unsafe { from_raw_parts(data, size as usize) }.into(),
+            pts,
+            key: flags_only.has(FRAME_FLAG_KEYFRAME) as i32,
+            flags: flags_only,
+            ltr_slot: (flags >> FRAME_FLAG_LTR_SLOT_SHIFT) as u8,
+            user_data,
+            duration: 0,
+            seq: 0,
+        };
+        unsafe { CallbackFrames::push(obj, frame) }
+    }
+
+    // fails calls whose frames came in breaking the contract of CallbackFrames
+    fn check_delivery(&mut self) -> Result<(), i32> {
+        match self.frames.take_stray() {
+            0 => Ok(()),
+            stray => {
+                error!("{} frames delivered off the encode call", stray);
+                self.frames.get_mut().clear();
+                Err(HwcodecErrno::HWCODEC_ERR_COMMON as _)
+            }
+        }
+    }
+}
+
+// Delivers frames of nals (< 255) NAL units each, an IDR slice header and the index of
+// the NAL plus 1, through the callback of the shims. On this thread for threads 0, else
+// that many threads at once deliver frames each. The frames the encoder keeps and how
+// many it dropped as stray.
+#[cfg(feature = "testutil")]
+#[doc(hidden)]
+pub fn debug_deliver_frames(
+    nals: usize,
+    frames: usize,
+    threads: usize,
+) -> (Vec<EncodeFrame>, usize) {
+    let mut slot = CallbackFrames::<EncodeFrame>::new();
+    let obj = slot.as_context() as usize;
+    let deliver = move |first: usize| {
+        for i in first..first + frames {
+            let data: Vec<u8> = (0..nals)
+                .flat_map(|n| [0, 0, 0, 1, 0x65, n as u8 + 1])
+                .collect();
+            NativeEncoder::callback(data.as_ptr(), data.len() as _, 1, obj as _, i as _, 0);
+        }
+    };
+    if threads == 0 {
+        deliver(0);
+    } else {
+        std::thread::scope(|s| {
+            for t in 0..threads {
+                s.spawn(move || deliver(t * frames));
+            }
+        });
+    }
+    let stray = slot.take_stray();
+    (std::mem::take(slot.get_mut()), stray)
+}
+
+// Delivers frames of one NAL unit from other threads while this one starts calls,
+// as_context, on the same encoder the whole time. The frames the encoder kept, none is
+// expected.
+#[cfg(feature = "testutil")]
+#[doc(hidden)]
+pub fn debug_deliver_frames_during_calls(frames: usize, threads: usize) -> usize {
+    let mut slot = CallbackFrames::<EncodeFrame>::new();
+    let obj = slot.as_context() as usize;
+    let delivering = AtomicUsize::new(threads);
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                let data = [0, 0, 0, 1, 0x65, 1];
+                for i in 0..frames {
+                    NativeEncoder::callback(data.as_ptr(), data.len() as _, 1, obj as _, i as _, 0);
+                }
+                delivering.fetch_sub(1, Ordering::Release);
+            });
+        }
+        while delivering.load(Ordering::Acquire) > 0 {
+            slot.as_context();
+            slot.take_stray();
+        }
+    });
+    slot.get_mut().len()
+}
+
+impl EncodeBackend for NativeEncoder {
+    fn encode(&mut self, tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.encode_with_user_data(tex, ms, 0)
+    }
+
+    // the shims carry it with their frames
+    fn encode_with_user_data(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        user_data: u64,
+    ) -> Result<&mut Vec<EncodeFrame>, i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
+        }
+        self.frames.get_mut().clear();
+        let result = unsafe {
+            (self.calls.encode)(
+                self.codec,
+                tex,
+                Some(Self::callback),
+                self.frames.as_context(),
+                ms,
+                user_data,
+            )
+        };
+        Self::status(result)?;
+        self.check_delivery()?;
+        Ok(self.frames.get_mut())
+    }
+
+    fn set_bitrate(&mut self, kbs: i32) -> Result<(), i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
+        }
+        Self::status(unsafe { (self.calls.set_bitrate)(self.codec, kbs) })
+    }
+
+    fn set_framerate(&mut self, framerate: i32) -> Result<(), i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
+        }
+        Self::status(unsafe { (self.calls.set_framerate)(self.codec, framerate) })
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
+        }
+        Self::status(unsafe { (self.calls.request_keyframe)(self.codec) })
+    }
+
+    fn check(&self) -> Result<(), i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
+        }
+        Self::status(unsafe { (self.calls.check)(self.codec) })
+    }
+
+    fn memory_usage(&self) -> MemoryInfo {
+        let mut info = MemoryInfo::default();
+        if !self.codec.is_null() {
+            unsafe {
+                (self.calls.memory)(self.codec, &mut info);
+            }
+        }
+        info
+    }
+
+    fn info(&self) -> EncoderInfo {
+        let mut info = RuntimeInfo::default();
+        if !self.codec.is_null() {
+            unsafe {
+                (self.calls.info)(self.codec, &mut info);
+            }
+        }
+        info.into()
+    }
+
+    fn gpu_timings(&mut self) -> Vec<GpuTiming> {
+        let mut timings = vec![];
+        if self.codec.is_null() {
+            return timings;
+        }
+        let mut buf = [GpuTiming::default(); 8];
+        loop {
+            let mut count = 0;
+            let ret = unsafe {
+                (self.calls.gpu_timings)(self.codec, buf.as_mut_ptr(), buf.len() as _, &mut count)
+            };
+            if ret != 0 || count <= 0 {
+                break;
+            }
+            timings.extend_from_slice(&buf[..count as usize]);
+        }
+        timings
+    }
 
-impl Encoder {
-    pub fn new(ctx: EncodeContext) -> Result<Self, ()> {
-        init_av_log();
-        if ctx.d.width % 2 == 1 || ctx.d.height % 2 == 1 {
-            return Err(());
+    fn flush(&mut self) -> Result<Vec<EncodeFrame>, i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
         }
-        let calls = match ctx.f.driver {
-            NV => nv::encode_calls(),
-            AMF => amf::encode_calls(),
-            MFX => mfx::encode_calls(),
-            FFMPEG => ffmpeg::encode_calls(),
+        self.frames.get_mut().clear();
+        let result = unsafe {
+            (self.calls.flush)(self.codec, Some(Self::callback), self.frames.as_context())
         };
-        unsafe {
-            let codec = (calls.new)(
-                ctx.d.device.unwrap_or(std::ptr::null_mut()),
-                ctx.f.luid,
-                ctx.f.data_format as i32,
-                ctx.d.width,
-                ctx.d.height,
-                ctx.d.kbitrate,
-                ctx.d.framerate,
-                ctx.d.gop,
-            );
-            if codec.is_null() {
-                return Err(());
-            }
-            Ok(Self {
-                calls,
-                codec,
-                frames: Box::into_raw(Box::new(Vec::<EncodeFrame>::new())),
-                ctx,
-            })
+        Self::status(result)?;
+        self.check_delivery()?;
+        Ok(std::mem::take(self.frames.get_mut()))
+    }
+
+    fn set_emphasis_map(&mut self, map: &[u8]) -> Result<(), i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
         }
+        Self::status(unsafe {
+            (self.calls.set_emphasis_map)(self.codec, map.as_ptr(), map.len() as _)
+        })
     }
 
-    pub fn encode(&mut self, tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
-        unsafe {
-            (&mut *self.frames).clear();
-            let result = (self.calls.encode)(
-                self.codec,
-                tex,
-                Some(Self::callback),
-                self.frames as *mut _ as *mut c_void,
-                ms,
-            );
-            if result != 0 {
-                Err(result)
-            } else {
-                Ok(&mut *self.frames)
-            }
+    fn set_qp_delta(&mut self, delta: i32) -> Result<(), i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
         }
+        Self::status(unsafe { (self.calls.set_qp_delta)(self.codec, delta) })
     }
 
-    extern "C" fn callback(data: *const u8, size: c_int, key: i32, obj: *const c_void, pts: i64) {
-        unsafe {
-            let frames = &mut *(obj as *mut Vec<EncodeFrame>);
-            frames.push(EncodeFrame {
-                data: from_raw_parts(data, size as usize).to_vec(),
-                pts,
-                key,
-            });
+    fn invalidate_reference(&mut self, ms: i64) -> Result<(), i32> {
+        if self.codec.is_null() {
+            return Err(HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as _);
         }
+        Self::status(unsafe { (self.calls.invalidate_reference)(self.codec, ms) })
     }
 
-    pub fn set_bitrate(&mut self, kbs: i32) -> Result<(), i32> {
+    fn recreate(&mut self, ctx: &EncodeContext) -> Result<(), ()> {
+        if self.external_session {
+            return Err(());
+        }
         unsafe {
-            match (self.calls.set_bitrate)(self.codec, kbs) {
-                0 => Ok(()),
-                err => Err(err),
-            }
+            (self.calls.destroy)(self.codec);
         }
+        self.codec = std::ptr::null_mut();
+        let device = ctx.d.device.unwrap_or(std::ptr::null_mut());
+        self.codec = Self::new_codec(self.calls.new, device, ctx, self.overrides.as_deref())?;
+        Ok(())
     }
 
-    pub fn set_framerate(&mut self, framerate: i32) -> Result<(), i32> {
-        unsafe {
-            match (self.calls.set_framerate)(self.codec, framerate) {
-                0 => Ok(()),
-                err => Err(err),
-            }
+    fn close(&mut self) -> Result<(), i32> {
+        if self.codec.is_null() {
+            return Ok(());
         }
+        let ret = unsafe { (self.calls.destroy)(self.codec) };
+        self.codec = std::ptr::null_mut();
+        Self::status(ret)
     }
 }
 
-impl Drop for Encoder {
+impl Drop for NativeEncoder {
     fn drop(&mut self) {
-        unsafe {
-            (self.calls.destroy)(self.codec);
-            self.codec = std::ptr::null_mut();
-            let _ = Box::from_raw(self.frames);
-            trace!("Encoder dropped");
+        if !self.codec.is_null() {
+            unsafe {
+                (self.calls.destroy)(self.codec);
+            }
         }
+        self.codec = std::ptr::null_mut();
     }
 }
 
+// Clones share data, see FrameData.
+#[derive(Clone, Default)]
 pub struct EncodeFrame {
-    pub data: Vec<u8>,
+    pub data: FrameData,
+    // the ms of the encode call of this frame
     pub pts: i64,
+    // 1 for a keyframe like FRAME_FLAG_KEYFRAME of flags, kept for older users for one
+    // release. Backends that set only key get the flag from the Encoder.
     pub key: i32,
+    pub flags: FrameFlags,
+    // the long term reference slot of FRAME_FLAG_LTR_MARKED, AMF only
+    pub ltr_slot: u8,
+    // see Encoder::encode_with_user_data, 0 for frames given to encode
+    pub user_data: u64,
+    // ms, see Encoder::encode_with_duration
+    pub duration: i64,
+    // Numbers the frames an Encoder outputs from 0, whatever their pts, so that a
+    // receiver counts lost frames by the gaps. It goes on across reset, recovery and
+    // recreated sessions and wraps at u64::MAX. The fragments of OversizePolicy::Split
+    // share the number of their frame. 0 from backends, the Encoder sets it.
+    pub seq: u64,
+}
+
+impl EncodeFrame {
+    // A frame of a custom backend, see backend::EncodeBackend, the Encoder sets the rest.
+    pub fn new(data: impl Into<FrameData>, pts: i64, key: bool) -> Self {
+        let mut flags = FrameFlags::default();
+        if key {
+            flags.insert(FRAME_FLAG_KEYFRAME);
+        }
+        Self {
+            data: data.into(),
+            pts,
+            key: key as i32,
+            flags,
+            ..Default::default()
+        }
+    }
+
+    pub fn nal_units(&self) -> impl Iterator<Item = NalRef<'_>> {
+        nal_units(&self.data)
+    }
+
+    // The frame starts an intra refresh and carries its recovery point SEI, the output is
+    // correct again this many frames (h264) or pictures (h265) on. Frames of the Encoder
+    // have FRAME_FLAG_RECOVERY_POINT_START when it's Some, this parses for the count.
+    // See Tune::CloudGaming for the backends that emit them.
+    pub fn recovery_point(&self, format: DataFormat) -> Option<i32> {
+        self.nal_units().find_map(|nal| match format {
+            DataFormat::H264 => h264::recovery_point(nal.data).map(|n| n as i32),
+            DataFormat::H265 => hevc::recovery_point(nal.data),
+            _ => None,
+        })
+    }
+
+    // one frame of a bitstream::dump stream
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        dump::write_frame(w, &self.data, self.pts, self.key)
+    }
+
+    // None at the end of the stream, user_data, duration and flags other than the keyframe
+    // aren't dumped
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+        Ok(dump::read_frame(r)?.map(|(data, pts, key)| {
+            let mut flags = FrameFlags::default();
+            if key == 1 {
+                flags.insert(FRAME_FLAG_KEYFRAME);
+            }
+            Self {
+                data: data.into(),
+                pts,
+                key,
+                flags,
+                ltr_slot: 0,
+                user_data: 0,
+                duration: 0,
+                seq: 0,
+            }
+        }))
+    }
+}
+
+// a whole session with the header of the format, see bitstream::dump
+pub fn write_stream<W: Write>(w: &mut W, frames: &[EncodeFrame]) -> io::Result<()> {
+    dump::write_header(w)?;
+    frames.iter().try_for_each(|frame| frame.write_to(w))
+}
+
+pub fn read_stream<R: Read>(r: &mut R) -> io::Result<Vec<EncodeFrame>> {
+    dump::read_header(r)?;
+    let mut frames = vec![];
+    while let Some(frame) = EncodeFrame::read_from(r)? {
+        frames.push(frame);
+    }
+    Ok(frames)
 }
 
 impl Display for EncodeFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "encode len:{}, key:{}", self.data.len(), self.key)
+        write!(
+            f,
+            "encode len:{}, key:{}, flags:{:#x}",
+            self.data.len(),
+            self.key,
+            self.flags.0
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncodeSummary {
+    pub frames: u64,
+    // filler and repeated parameter sets included
+    pub bytes: u64,
+    pub keyframes: u64,
+    // from the lowest pts to the end of the frame with the highest one, which is shown
+    // for 1 / d.framerate
+    pub duration: Duration,
+}
+
+// counts of d.recovery, see RecoveryPolicy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    // encode calls repeated for a frame
+    pub retries: u64,
+    // frames the encode calls returned an error for after their retries
+    pub failed_frames: u64,
+    // sessions recreated after failed frames
+    pub reinits: u64,
+    // recreated sessions that failed too, the calls returned HWCODEC_ERR_SESSION_LOST
+    pub fatal: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncoderInfo {
+    // "oneVPL" or "Media SDK" for MFX, "NVENC", "AMF", "FFmpeg", empty when unknown
+    pub runtime: String,
+    // e.g. the VPL implementation name or the ffmpeg encoder, often empty
+    pub implementation: String,
+    // (major, minor) of the runtime's api, (0, 0) when unknown
+    pub api_version: (i32, i32),
+    // B-frames between the reference frames, 0 when the output is in display order
+    pub bframes: i32,
+}
+
+impl From<RuntimeInfo> for EncoderInfo {
+    fn from(info: RuntimeInfo) -> Self {
+        let string = |chars: &[c_char]| {
+            let bytes: Vec<u8> = chars
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as u8)
+                .collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        };
+        Self {
+            runtime: string(&info.runtime),
+            implementation: string(&info.implementation),
+            api_version: (info.apiMajor, info.apiMinor),
+            bframes: info.bframes,
+        }
+    }
+}
+
+const FORMATS: [DataFormat; 2] = [DataFormat::H264, DataFormat::H265];
+
+// NV_ENC_EMPHASIS_MAP_LEVEL_5
+pub const MAX_EMPHASIS_LEVEL: u8 = 5;
+
+// of DynamicContext::chroma_qp_offset, the range of the h264 and h265 pps
+pub const MAX_CHROMA_QP_OFFSET: i32 = 12;
+
+// of DynamicContext::quality, the qp range the backends map it to
+pub const MAX_QUALITY: i32 = 51;
+pub const DEFAULT_QUALITY: i32 = 23;
+
+// The native tests always create a session at a bitrate, with this one for a context in
+// quality mode.
+const PROBE_KBITRATE: i32 = 5000;
+
+// the frames of probe_packets, of a sample or at a resolution the native tests didn't run at
+const PROBE_FRAMES: usize = 2;
+// of the IDR slice of a probe, even a blank frame takes more
+const MIN_PROBE_IDR_LEN: usize = 64;
+
+// DXGI_FORMAT_R8G8B8A8_UNORM_SRGB and DXGI_FORMAT_B8G8R8A8_UNORM_SRGB
+const SRGB_FORMATS: [u32; 2] = [29, 91];
+
+const MACROBLOCK_SIZE: usize = 16;
+
+// the (columns, rows) of the emphasis map of a width x height frame
+pub fn emphasis_map_size(width: i32, height: i32) -> (usize, usize) {
+    (
+        (width.max(0) as usize).div_ceil(MACROBLOCK_SIZE),
+        (height.max(0) as usize).div_ceil(MACROBLOCK_SIZE),
+    )
+}
+
+// Rasterizes rects, e.g. the dirty rects of a desktop duplication frame or the cursor, into
+// the emphasis map of a width x height frame. A rect is [left, top, right, bottom] with
+// right and bottom exclusive like a RECT, clipped to the frame. Every macroblock a rect
+// touches gets its level, the highest one where rects overlap.
+pub fn emphasis_map(width: i32, height: i32, rects: &[([i32; 4], u8)]) -> Vec<u8> {
+    let (columns, rows) = emphasis_map_size(width, height);
+    let mut map = vec![0; columns * rows];
+    for &([left, top, right, bottom], level) in rects {
+        let (left, top) = (left.max(0) as usize, top.max(0) as usize);
+        let (right, bottom) = (right.min(width), bottom.min(height));
+        if right <= left as i32 || bottom <= top as i32 {
+            continue;
+        }
+        let (right, bottom) = (right as usize, bottom as usize);
+        let level = level.min(MAX_EMPHASIS_LEVEL);
+        for row in top / MACROBLOCK_SIZE..=(bottom - 1) / MACROBLOCK_SIZE {
+            let line = &mut map[row * columns..(row + 1) * columns];
+            for mb in &mut line[left / MACROBLOCK_SIZE..=(right - 1) / MACROBLOCK_SIZE] {
+                *mb = (*mb).max(level);
+            }
+        }
+    }
+    map
+}
+
+// data in parts of at most max bytes, as many whole NAL units with their start codes or
+// length prefixes as fit in each, those larger than max cut into parts of their own
+fn fragments(data: &[u8], max: usize) -> Vec<&[u8]> {
+    // a NAL unit starts where the previous one ends
+    let mut starts: Vec<usize> = nal_units(data)
+        .map(|nal| nal.data.as_ptr() as usize - data.as_ptr() as usize + nal.data.len())
+        .collect();
+    starts.pop();
+    starts.insert(0, 0);
+    starts.push(data.len());
+    let mut fragments = vec![];
+    let mut start = 0;
+    for unit in starts.windows(2) {
+        if unit[1] - start <= max {
+            continue;
+        }
+        if unit[0] > start {
+            fragments.push(&data[start..unit[0]]);
+        }
+        start = unit[0];
+        if unit[1] - start > max {
+            fragments.extend(data[start..unit[1]].chunks(max));
+            start = unit[1];
+        }
+    }
+    if start < data.len() {
+        fragments.push(&data[start..]);
     }
+    fragments
 }
 
 pub fn available(d: DynamicContext) -> Vec<FeatureContext> {
-    use log::debug;
+    available_formats(d, &FORMATS)
+}
+
+// available() checking the output of the native tests with verify, the other probes always
+// do. An adapter a test passed on whose frame lacks an SPS or a plausible IDR is dropped,
+// some drivers pass the tests and then output nothing or garbage. Off trusts the tests.
+pub fn available_with_verification(d: DynamicContext, verify: bool) -> Vec<FeatureContext> {
+    available_until(d, &FORMATS, None, verify, || false, &mut vec![])
+}
+
+// available() with the output check encoding the top left d.width x d.height of sample, a
+// bgra texture of representative content, e.g. a dark or busy desktop some encoders only
+// fail on, in place of the frame of the native tests. The sample is optional. The native
+// tests run on devices of their own and keep their frame, the sample is read back once and
+// encoded in a session of its own on the device of each adapter they passed on. Err if it
+// can't be read, e.g. for a texture smaller than d or of another format.
+pub fn available_with_sample(
+    d: DynamicContext,
+    sample: *mut c_void,
+) -> Result<Vec<FeatureContext>, ()> {
+    let Ok(sample) = read_bgra(sample, d.width, d.height) else {
+        error!("failed to read the {}x{} probe sample", d.width, d.height);
+        return Err(());
+    };
+    Ok(available_until(
+        d,
+        &FORMATS,
+        Some(&sample),
+        true,
+        || false,
+        &mut vec![],
+    ))
+}
+
+// available() at the first of resolutions, each (width, height), with the largest of them
+// by area an encoder works at. Support isn't uniform, some integrated gpus pass at
+// 1920x1080 and fail at 3840x2160. Each other resolution costs one probe encode per
+// encoder, on a device per adapter unless d has one. A single resolution gives available()
+// at it.
+pub fn available_resolutions(
+    d: DynamicContext,
+    resolutions: &[(i32, i32)],
+) -> Vec<(FeatureContext, (i32, i32))> {
+    let Some(&(width, height)) = resolutions.first() else {
+        return vec![];
+    };
+    let d = DynamicContext { width, height, ..d };
+    let area = |(width, height): (i32, i32)| width as i64 * height as i64;
+    let mut devices: Vec<(i64, Device)> = vec![];
+    let mut result = vec![];
+    for f in available_formats(d, &FORMATS) {
+        let mut max = (width, height);
+        for &resolution in &resolutions[1..] {
+            if area(resolution) <= area(max) {
+                continue;
+            }
+            let mut d = DynamicContext {
+                width: resolution.0,
+                height: resolution.1,
+                ..d
+            };
+            if d.device.is_none() {
+                if !devices.iter().any(|(luid, _)| *luid == f.luid) {
+                    if let Ok(device) = Device::new(f.luid) {
+                        devices.push((f.luid, device));
+                    }
+                }
+                d.device = devices
+                    .iter()
+                    .find(|(luid, _)| *luid == f.luid)
+                    .map(|(_, device)| device.as_ptr());
+            }
+            let ctx = EncodeContext { f: f.clone(), d };
+            match verify_probe(&ctx, None) {
+                Ok(()) => max = resolution,
+                Err(e) => debug!("{:?} fails at {:?}: {}", f, resolution, e),
+            }
+        }
+        result.push((f, max));
+    }
+    result
+}
+
+// only the drivers' tests for formats are run
+pub fn available_formats(d: DynamicContext, formats: &[DataFormat]) -> Vec<FeatureContext> {
+    available_until(d, formats, None, true, || false, &mut vec![])
+}
+
+// available, with the native tests that failed, see report::capability_report
+pub(crate) fn available_with_failures(
+    d: DynamicContext,
+    failures: &mut Vec<ProbeFailure>,
+) -> Vec<FeatureContext> {
+    available_until(d, &FORMATS, None, true, || false, failures)
+}
 
-    let mut natives: Vec<_> = vec![];
+// the encoders of any of descriptors, see FeatureContext::descriptor
+pub fn available_descriptors(
+    d: DynamicContext,
+    descriptors: &[CodecDescriptor],
+) -> Vec<FeatureContext> {
+    let mut formats: Vec<DataFormat> = vec![];
+    for descriptor in descriptors {
+        if !formats.contains(&descriptor.format) {
+            formats.push(descriptor.format);
+        }
+    }
+    available_formats(d, &formats)
+        .into_iter()
+        .filter(|f| descriptors.contains(&f.descriptor()))
+        .collect()
+}
+
+// Which of several encoders select_best takes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelectionPolicy {
+    // One on the adapter of the captured texture. Encoding on another one copies every
+    // frame across adapters, which is slow and fails with some drivers.
+    #[default]
+    PreferCaptureAdapter,
+    // one on a discrete gpu, for quality and throughput
+    PreferDiscrete,
+    // one on an integrated gpu, for power on laptops
+    PreferIntegrated,
+    // one of the format
+    PreferFormat(DataFormat),
+}
+
+// The encoder of features the policy prefers, e.g. of available(). Ties go to the
+// capture adapter, then to the earlier feature. Adapters are looked up with
+// adapter_for_luid, None only for no features.
+pub fn select_best(
+    features: &[FeatureContext],
+    capture_luid: Option<i64>,
+    policy: SelectionPolicy,
+) -> Option<&FeatureContext> {
+    let mut adapters: Vec<AdapterInfo> = vec![];
+    for f in features {
+        if adapters.iter().all(|a| a.luid != f.luid) {
+            adapters.extend(adapter_for_luid(f.luid));
+        }
+    }
+    select_best_on(features, capture_luid, policy, &adapters)
+}
+
+// select_best with adapters already looked up, features on adapters not among them are
+// neither integrated nor discrete
+pub fn select_best_on<'a>(
+    features: &'a [FeatureContext],
+    capture_luid: Option<i64>,
+    policy: SelectionPolicy,
+    adapters: &[AdapterInfo],
+) -> Option<&'a FeatureContext> {
+    // 0 for integrated, 1 unknown, 2 discrete
+    let integrated = |f: &FeatureContext| match adapters.iter().find(|a| a.luid == f.luid) {
+        Some(a) if a.integrated => 0,
+        Some(_) => 2,
+        None => 1,
+    };
+    let elsewhere =
+        |f: &FeatureContext| !capture_luid.is_some_and(|luid| same_adapter(luid, f.luid));
+    // min_by_key returns the first of equal keys
+    features.iter().min_by_key(|f| {
+        let preference = match policy {
+            SelectionPolicy::PreferCaptureAdapter => 0,
+            SelectionPolicy::PreferDiscrete => 2 - integrated(f),
+            SelectionPolicy::PreferIntegrated => integrated(f),
+            SelectionPolicy::PreferFormat(format) => (f.data_format != format) as i32,
+        };
+        (preference, elsewhere(f))
+    })
+}
+
+// cancelled is checked between the native tests, a running test can't be interrupted. The
+// output check with verify is of the packets of the tests, or encodes sample, bgra of d's
+// size, in a session of its own.
+fn available_until(
+    d: DynamicContext,
+    formats: &[DataFormat],
+    sample: Option<&[u8]>,
+    verify: bool,
+    cancelled: impl Fn() -> bool,
+    failures: &mut Vec<ProbeFailure>,
+) -> Vec<FeatureContext> {
+    let mut natives: Vec<(Driver, InnerEncodeContext)> = vec![];
+    #[cfg(feature = "vram-ffmpeg")]
     natives.append(
         &mut ffmpeg::possible_support_encoders()
             .drain(..)
             .map(|n| (FFMPEG, n))
             .collect(),
     );
+    #[cfg(feature = "nv")]
     natives.append(
         &mut nv::possible_support_encoders()
             .drain(..)
             .map(|n| (NV, n))
             .collect(),
     );
+    #[cfg(feature = "amf")]
     natives.append(
         &mut amf::possible_support_encoders()
             .drain(..)
             .map(|n| (AMF, n))
             .collect(),
     );
+    #[cfg(feature = "mfx")]
     natives.append(
         &mut mfx::possible_support_encoders()
             .drain(..)
@@ -156,12 +2212,14 @@ pub fn available(d: DynamicContext) -> Vec<FeatureContext> {
     );
     let inputs: Vec<EncodeContext> = natives
         .drain(..)
+        .filter(|(_, n)| formats.contains(&n.format))
         .map(|(driver, n)| EncodeContext {
             f: FeatureContext {
                 driver: driver.clone(),
                 vendor: driver, // Initially set vendor same as driver, will be updated by test results
                 data_format: n.format,
                 luid: 0,
+                caps: EncodeCaps::default(),
             },
             d,
         })
@@ -171,20 +2229,22 @@ pub fn available(d: DynamicContext) -> Vec<FeatureContext> {
     let mut exclude_luid_formats = Vec::<(i64, i32)>::new();
 
     for input in inputs {
+        if cancelled() {
+            debug!("vram encoder test cancelled");
+            break;
+        }
         debug!(
             "Testing vram encoder: driver={:?}, format={:?}",
             input.f.driver, input.f.data_format
         );
 
-        let test = match input.f.driver {
-            NV => nv::encode_calls().test,
-            AMF => amf::encode_calls().test,
-            MFX => mfx::encode_calls().test,
-            FFMPEG => ffmpeg::encode_calls().test,
+        let Some(test) = native_calls(&input.f.driver).map(|calls| calls.test) else {
+            continue;
         };
 
         let mut luids: Vec<i64> = vec![0; crate::vram::MAX_ADATERS];
         let mut vendors: Vec<i32> = vec![0; crate::vram::MAX_ADATERS];
+        let mut caps: Vec<EncodeCaps> = vec![EncodeCaps::default(); crate::vram::MAX_ADATERS];
         let mut desc_count: i32 = 0;
 
         let (excluded_luids, exclude_formats): (Vec<i64>, Vec<i32>) = exclude_luid_formats
@@ -192,21 +2252,31 @@ pub fn available(d: DynamicContext) -> Vec<FeatureContext> {
             .map(|(luid, format)| (*luid, *format))
             .unzip();
 
+        // an odd size is tested as the session would be created
+        let coded = coded_context(&input).d;
+        let mut packets: Vec<(i64, Vec<u8>)> = vec![];
         let result = unsafe {
             test(
                 luids.as_mut_ptr(),
                 vendors.as_mut_ptr(),
+                caps.as_mut_ptr(),
                 luids.len() as _,
                 &mut desc_count,
                 input.f.data_format as i32,
-                input.d.width,
-                input.d.height,
-                input.d.kbitrate,
+                coded.width,
+                coded.height,
+                if input.d.kbitrate > 0 {
+                    input.d.kbitrate
+                } else {
+                    PROBE_KBITRATE
+                },
                 input.d.framerate,
                 input.d.gop,
                 excluded_luids.as_ptr(),
                 exclude_formats.as_ptr(),
                 exclude_luid_formats.len() as i32,
+                Some(test_callback),
+                &mut packets as *mut _ as *mut c_void,
             )
         };
 
@@ -219,6 +2289,7 @@ pub fn available(d: DynamicContext) -> Vec<FeatureContext> {
                 for i in 0..desc_count as usize {
                     let mut input = input.clone();
                     input.f.luid = luids[i];
+                    input.f.caps = caps[i];
                     input.f.vendor = match vendors[i] {
                         0 => NV,
                         1 => AMF,
@@ -231,7 +2302,39 @@ pub fn available(d: DynamicContext) -> Vec<FeatureContext> {
                             continue;
                         },
                     };
+                    if verify {
+                        let checked = match sample {
+                            Some(_) => verify_probe(&input, sample),
+                            None => {
+                                let data: Vec<u8> = packets
+                                    .iter()
+                                    .filter(|(luid, _)| *luid == input.f.luid)
+                                    .flat_map(|(_, packet)| packet.iter().copied())
+                                    .collect();
+                                check_probe(&data, input.f.data_format)
+                            }
+                        };
+                        if let Err(e) = checked {
+                            debug!(
+                                "{:?} passed the test on {} but its output failed: {}",
+                                input.f.driver, input.f.luid, e
+                            );
+                            failures.push(ProbeFailure {
+                                driver: input.f.driver.clone(),
+                                data_format: input.f.data_format,
+                                encode: true,
+                                error: e,
+                            });
+                            continue;
+                        }
+                    }
                     exclude_luid_formats.push((luids[i], input.f.data_format as i32));
+                    debug!(
+                        "{:?} encodes {:?} on {:?}",
+                        input.f.driver,
+                        input.f.data_format,
+                        adapter_path(input.f.luid)
+                    );
                     outputs.push(input);
                 }
             }
@@ -240,9 +2343,518 @@ pub fn available(d: DynamicContext) -> Vec<FeatureContext> {
                 "vram encoder test failed: driver={:?}, error={}",
                 input.f.driver, result
             );
+            failures.push(ProbeFailure {
+                driver: input.f.driver.clone(),
+                data_format: input.f.data_format,
+                encode: true,
+                error: result,
+            });
+        }
+    }
+
+    // registered drivers come last and only add adapters and formats not covered yet
+    for driver in backend::encode_drivers() {
+        for format in formats {
+            if cancelled() {
+                break;
+            }
+            for (luid, vendor) in driver.test(*format, &d) {
+                if exclude_luid_formats.contains(&(luid, *format as i32)) {
+                    continue;
+                }
+                exclude_luid_formats.push((luid, *format as i32));
+                outputs.push(EncodeContext {
+                    f: FeatureContext {
+                        driver: CUSTOM(driver.name().to_owned()),
+                        vendor,
+                        luid,
+                        data_format: *format,
+                        caps: EncodeCaps::default(),
+                    },
+                    d,
+                });
+            }
         }
     }
 
     let result: Vec<_> = outputs.drain(..).map(|e| e.f).collect();
     result
 }
+
+// obj is the Vec of the packets of a native test, each with the luid of its adapter
+extern "C" fn test_callback(
+    data: *const u8,
+    size: c_int,
+    _flags: i32,
+    obj: *const c_void,
+    _pts: i64,
+    user_data: u64,
+) {
+    let packets = unsafe { &mut *(obj as *mut Vec<(i64, Vec<u8>)>) };
+    let packet = unsafe { from_raw_parts(data, size as usize) };
+    packets.push((user_data as i64, packet.to_vec()));
+}
+
+// Err with HWCODEC_ERR_INVALID_DATA for output that doesn't start a stream
+fn check_probe(data: &[u8], format: DataFormat) -> Result<(), i32> {
+    if !starts_stream(data, format, MIN_PROBE_IDR_LEN) {
+        return Err(HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _);
+    }
+    Ok(())
+}
+
+// check_probe of probe_packets, Err with the error of probe_packets if it fails
+fn verify_probe(ctx: &EncodeContext, sample: Option<&[u8]>) -> Result<(), i32> {
+    check_probe(&probe_packets(ctx, sample)?.concat(), ctx.f.data_format)
+}
+
+// The packets of PROBE_FRAMES frames of a pattern or sample encoded with ctx, on a device of
+// its own unless ctx.d has one. Err with the error of the encoder, HWCODEC_ERR_COMMON if it
+// can't be created.
+pub(crate) fn probe_packets(
+    ctx: &EncodeContext,
+    sample: Option<&[u8]>,
+) -> Result<Vec<FrameData>, i32> {
+    let common = |_| HwcodecErrno::HWCODEC_ERR_COMMON as i32;
+    let mut ctx = ctx.clone();
+    let _device;
+    if ctx.d.device.is_none() {
+        _device = Device::new(ctx.f.luid).map_err(common)?;
+        ctx.d.device = Some(_device.as_ptr());
+    }
+    let device = ctx.d.device.unwrap();
+    let (width, height) = (ctx.d.width, ctx.d.height);
+    let mut encoder = Encoder::new(ctx).map_err(common)?;
+    let mut packets = vec![];
+    for i in 0..PROBE_FRAMES {
+        let pattern;
+        let source = match sample {
+            Some(sample) => sample,
+            None => {
+                pattern = bgra_pattern(width as _, height as _, i);
+                &pattern
+            }
+        };
+        let texture = Texture::from_bgra(device, width, height, source).map_err(common)?;
+        let frames = encoder.encode(texture.as_ptr(), i as _)?;
+        packets.extend(frames.drain(..).map(|f| f.data));
+    }
+    Ok(packets)
+}
+
+// The options an FFMPEG session of ctx sets on libavcodec, those of the codec context
+// and the vendor's encoder that differ from their defaults, as ffmpeg prints them, e.g.
+// ("b", "2000000") or ("delay", "0"). Fields without an option, like the time base of
+// ms, don't show. Derived on a context of the encoder without a device, empty for other
+// drivers and builds without the vendor's encoder.
+pub fn ffmpeg_options(ctx: &EncodeContext) -> Vec<(String, String)> {
+    ffmpeg_options_with(ctx, &[]).unwrap_or_default()
+}
+
+// ffmpeg_options with overrides set after the derived options, what
+// Encoder::new_with_ffmpeg_options sets. Err if ffmpeg rejects one, e.g. an unknown key.
+pub fn ffmpeg_options_with(
+    ctx: &EncodeContext,
+    overrides: &[(String, String)],
+) -> Result<Vec<(String, String)>, ()> {
+    if ctx.f.driver != FFMPEG {
+        return Err(());
+    }
+    let overrides = ffmpeg_overrides(ctx, overrides)?;
+    #[cfg(feature = "vram-ffmpeg")]
+    {
+        use crate::common::AdapterVendor::*;
+        let vendor = match ctx.f.vendor {
+            NV => ADAPTER_VENDOR_NVIDIA,
+            AMF => ADAPTER_VENDOR_AMD,
+            MFX => ADAPTER_VENDOR_INTEL,
+            _ => return Err(()),
+        };
+        let d = coded_context(ctx).d;
+        let mut options = d.encode_options();
+        options.ffmpegOptions = overrides.as_ref().map_or(std::ptr::null(), |o| o.as_ptr());
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let len = unsafe {
+                ffmpeg::ffmpeg_vram_encoder_options(
+                    vendor as _,
+                    ctx.f.data_format as _,
+                    d.width,
+                    d.height,
+                    d.kbitrate,
+                    d.framerate,
+                    d.gop,
+                    &options,
+                    buf.as_mut_ptr() as _,
+                    buf.len() as _,
+                )
+            };
+            if len < 0 {
+                return Err(());
+            }
+            if len as usize >= buf.len() {
+                buf.resize(len as usize + 1, 0);
+                continue;
+            }
+            buf.truncate(len as usize);
+            break;
+        }
+        Ok(String::from_utf8_lossy(&buf)
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once('=')?;
+                Some((key.to_owned(), unescape_option(value)))
+            })
+            .collect())
+    }
+    #[cfg(not(feature = "vram-ffmpeg"))]
+    {
+        let _ = overrides;
+        Err(())
+    }
+}
+
+// av_opt_serialize puts a backslash before separators in values
+fn unescape_option(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// the "key=value" lines of EncodeOptions.ffmpegOptions, None without overrides
+fn ffmpeg_overrides(
+    ctx: &EncodeContext,
+    overrides: &[(String, String)],
+) -> Result<Option<CString>, ()> {
+    if overrides.is_empty() {
+        return Ok(None);
+    }
+    if ctx.f.driver != FFMPEG {
+        error!("{:?} takes no ffmpeg options", ctx.f.driver);
+        return Err(());
+    }
+    let mut lines = vec![];
+    for (key, value) in overrides {
+        if key.is_empty() || key.contains(['=', '\n']) || value.contains('\n') {
+            error!("invalid ffmpeg option {:?} = {:?}", key, value);
+            return Err(());
+        }
+        lines.push(format!("{}={}", key, value));
+    }
+    CString::new(lines.join("\n")).map(Some).map_err(|_| {
+        error!("ffmpeg options with a nul");
+    })
+}
+
+// approximate video memory an encoder for ctx will need, check it before creating
+// one on adapters with little memory
+pub fn estimate_memory(ctx: &EncodeContext) -> MemoryInfo {
+    // no estimate for registered drivers
+    let Some(calls) = native_calls(&ctx.f.driver) else {
+        return MemoryInfo::default();
+    };
+    let mut info = MemoryInfo::default();
+    unsafe {
+        (calls.estimate_memory)(
+            ctx.f.data_format as i32,
+            ctx.d.width,
+            ctx.d.height,
+            &mut info,
+        );
+    }
+    info
+}
+
+// NVENC's lossless mode, natively or through FFmpeg's nvenc. Registered drivers decide
+// themselves.
+fn has_lossless(f: &FeatureContext) -> bool {
+    match f.driver {
+        AMF | MFX => false,
+        FFMPEG => f.vendor == NV,
+        _ => true,
+    }
+}
+
+// Why check_config rejected a context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    // the driver is neither compiled in nor registered
+    NoDriver,
+    // the adapter of ctx.f.luid couldn't be opened or queried
+    QueryFailed,
+    // The session wouldn't take param as given: creating it fails or it runs with
+    // corrected instead. None where the backend doesn't report a value it would take.
+    Unsupported {
+        param: ConfigParam,
+        corrected: Option<i32>,
+    },
+}
+
+// Checks ctx against the adapter without creating an encoder, for validating settings
+// as they are chosen. The backends query their caps: NVENC opens a session it doesn't
+// initialize, AMF creates the component, MFX runs Query on a session. FFMPEG can only
+// check that the adapter's encoder is there and which options it ignores. Parameters
+// beyond ctx.f.caps fail before any of that. Ok doesn't promise Encoder::new succeeds,
+// e.g. when the adapter runs out of sessions.
+pub fn check_config(ctx: &EncodeContext) -> Result<(), ConfigError> {
+    use ConfigParam::*;
+    let unsupported = |param, corrected| Err(ConfigError::Unsupported { param, corrected });
+    let coded = coded_context(ctx);
+    let d = &coded.d;
+    if d.width <= 0 {
+        return unsupported(CONFIG_PARAM_WIDTH, None);
+    }
+    if d.height <= 0 {
+        return unsupported(CONFIG_PARAM_HEIGHT, None);
+    }
+    if d.kbitrate < 0 {
+        return unsupported(CONFIG_PARAM_BITRATE, None);
+    }
+    if !(0..=MAX_QUALITY).contains(&d.quality) {
+        return unsupported(CONFIG_PARAM_QUALITY, Some(d.quality.clamp(0, MAX_QUALITY)));
+    }
+    if d.framerate <= 0 {
+        return unsupported(CONFIG_PARAM_FRAMERATE, None);
+    }
+    if !(0..=15).contains(&d.aq_strength) {
+        return unsupported(CONFIG_PARAM_AQ_STRENGTH, Some(d.aq_strength.clamp(0, 15)));
+    }
+    if d.emphasis_map && matches!(ctx.f.driver, AMF | MFX | FFMPEG) {
+        return unsupported(CONFIG_PARAM_EMPHASIS_MAP, Some(0));
+    }
+    if d.reference_invalidation && matches!(ctx.f.driver, AMF | MFX | FFMPEG) {
+        return unsupported(CONFIG_PARAM_REF_INVALIDATION, Some(0));
+    }
+    if d.lossless && !has_lossless(&ctx.f) {
+        return unsupported(CONFIG_PARAM_LOSSLESS, Some(0));
+    }
+    if d.reduced_sar().is_none() {
+        return unsupported(CONFIG_PARAM_SAR, None);
+    }
+    let max = MAX_CHROMA_QP_OFFSET;
+    if !(-max..=max).contains(&d.chroma_qp_offset) {
+        let corrected = d.chroma_qp_offset.clamp(-max, max);
+        return unsupported(CONFIG_PARAM_CHROMA_QP_OFFSET, Some(corrected));
+    }
+    if d.chroma_qp_offset != 0 && matches!(ctx.f.driver, AMF | MFX | FFMPEG) {
+        return unsupported(CONFIG_PARAM_CHROMA_QP_OFFSET, Some(0));
+    }
+    let software = d.color_convert == ColorConvert::COLOR_CONVERT_SOFTWARE;
+    if software && matches!(ctx.f.driver, NV | AMF) {
+        let auto = ColorConvert::COLOR_CONVERT_AUTO;
+        return unsupported(CONFIG_PARAM_COLOR_CONVERT, Some(auto as i32));
+    }
+    if !ctx.f.descriptor().supports_entropy_coding(d.entropy_coding) {
+        let default = EntropyCoding::ENTROPY_CODING_DEFAULT;
+        return unsupported(CONFIG_PARAM_ENTROPY_CODING, Some(default as i32));
+    }
+    let caps = &ctx.f.caps;
+    if caps.maxWidth > 0 && d.width > caps.maxWidth {
+        return unsupported(CONFIG_PARAM_WIDTH, Some(caps.maxWidth));
+    }
+    if caps.maxHeight > 0 && d.height > caps.maxHeight {
+        return unsupported(CONFIG_PARAM_HEIGHT, Some(caps.maxHeight));
+    }
+    let calls = match (&ctx.f.driver, native_calls(&ctx.f.driver)) {
+        (_, Some(calls)) => calls,
+        (CUSTOM(name), None) => {
+            let Some(driver) = backend::encode_driver(name) else {
+                return Err(ConfigError::NoDriver);
+            };
+            return driver.check_config(&coded);
+        }
+        (_, None) => return Err(ConfigError::NoDriver),
+    };
+    // AMF can't open a device of its own, the others are given one too
+    let owned;
+    let device = match d.device {
+        Some(device) => device,
+        None => {
+            owned = D3D11Ptr::device(ctx.f.luid).map_err(|e| {
+                debug!("{}", e);
+                ConfigError::QueryFailed
+            })?;
+            owned.0
+        }
+    };
+    let options = d.encode_options();
+    let mut check = ConfigCheck {
+        param: CONFIG_PARAM_NONE,
+        hasCorrected: 0,
+        corrected: 0,
+    };
+    let ret = unsafe {
+        (calls.check_config)(
+            device,
+            ctx.f.luid,
+            ctx.f.data_format as i32,
+            d.width,
+            d.height,
+            d.kbitrate,
+            d.framerate,
+            d.gop,
+            &options,
+            &mut check,
+        )
+    };
+    match (ret, check.param) {
+        (0, _) => Ok(()),
+        (_, CONFIG_PARAM_NONE) => Err(ConfigError::QueryFailed),
+        (_, param) => {
+            let corrected = (check.hasCorrected != 0).then_some(check.corrected);
+            debug!(
+                "{:?} rejects {:?}, corrected {:?}",
+                ctx.f.driver, param, corrected
+            );
+            unsupported(param, corrected)
+        }
+    }
+}
+
+// formats are in order of preference, adapters keep the order of available()
+pub fn best_encoder(d: DynamicContext, formats: &[DataFormat]) -> Option<FeatureContext> {
+    let features = available_formats(d, formats);
+    formats
+        .iter()
+        .find_map(|format| features.iter().find(|f| f.data_format == *format))
+        .cloned()
+}
+
+// Like best_encoder but probes one format at a time and stops at the first that works,
+// e.g. [H265, H264] for "prefer HEVC, accept H264". The data_format of the result is
+// the format it settled on.
+pub fn best_encoder_with_fallback(
+    d: DynamicContext,
+    preferred: &[DataFormat],
+) -> Option<FeatureContext> {
+    for (i, format) in preferred.iter().enumerate() {
+        if let Some(f) = available_formats(d, &[*format]).into_iter().next() {
+            if i > 0 {
+                info!(
+                    "no encoder for {:?}, fell back to {:?}",
+                    preferred[0], format
+                );
+            }
+            return Some(f);
+        }
+        debug!("no vram encoder for {:?}", format);
+    }
+    None
+}
+
+// Runs available() on its own thread, the future can be awaited on any executor.
+// Dropping it skips the tests that haven't started yet.
+#[cfg(feature = "async")]
+pub fn available_async(d: DynamicContext) -> AvailableFuture {
+    let shared = Arc::new(AvailableShared::default());
+    let worker = shared.clone();
+    let probe = AvailableProbe(d);
+    #[cfg(feature = "testutil")]
+    ASYNC_PROBES.fetch_add(1, Ordering::SeqCst);
+    let spawned = std::thread::Builder::new()
+        .name("hwcodec-available".to_owned())
+        .spawn(move || {
+            let probe = probe;
+            let result = available_until(
+                probe.0,
+                &FORMATS,
+                None,
+                true,
+                || {
+                    let cancelled = worker.cancelled.load(Ordering::Relaxed);
+                    #[cfg(feature = "testutil")]
+                    if !cancelled {
+                        ASYNC_PROBE_TESTS.fetch_add(1, Ordering::SeqCst);
+                    }
+                    cancelled
+                },
+                &mut vec![],
+            );
+            #[cfg(feature = "testutil")]
+            ASYNC_PROBES.fetch_sub(1, Ordering::SeqCst);
+            let mut state = worker.state.lock().unwrap();
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+    if spawned.is_err() {
+        log::error!("failed to spawn vram encoder test thread");
+        #[cfg(feature = "testutil")]
+        ASYNC_PROBES.fetch_sub(1, Ordering::SeqCst);
+        shared.state.lock().unwrap().result = Some(vec![]);
+    }
+    AvailableFuture { shared }
+}
+
+// d.device may be shared by codecs on different threads, see DynamicContext
+#[cfg(feature = "async")]
+struct AvailableProbe(DynamicContext);
+
+#[cfg(feature = "async")]
+unsafe impl Send for AvailableProbe {}
+
+// the probe threads of available_async still running and the native tests they started
+#[cfg(all(feature = "async", feature = "testutil"))]
+static ASYNC_PROBES: AtomicUsize = AtomicUsize::new(0);
+#[cfg(all(feature = "async", feature = "testutil"))]
+static ASYNC_PROBE_TESTS: AtomicUsize = AtomicUsize::new(0);
+
+// The probe threads of available_async still running and the native tests all of them
+// started so far, a dropped future's thread exits after the test it's in.
+#[cfg(all(feature = "async", feature = "testutil"))]
+#[doc(hidden)]
+pub fn debug_available_async_probes() -> (usize, usize) {
+    (
+        ASYNC_PROBES.load(Ordering::SeqCst),
+        ASYNC_PROBE_TESTS.load(Ordering::SeqCst),
+    )
+}
+
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct AvailableShared {
+    cancelled: AtomicBool,
+    state: Mutex<AvailableState>,
+}
+
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct AvailableState {
+    result: Option<Vec<FeatureContext>>,
+    waker: Option<Waker>,
+}
+
+#[cfg(feature = "async")]
+pub struct AvailableFuture {
+    shared: Arc<AvailableShared>,
+}
+
+#[cfg(feature = "async")]
+impl Future for AvailableFuture {
+    type Output = Vec<FeatureContext>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for AvailableFuture {
+    fn drop(&mut self) {
+        self.shared.cancelled.store(true, Ordering::Relaxed);
+    }
+}
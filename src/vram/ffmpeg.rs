@@ -5,8 +5,11 @@
 include!(concat!(env!("OUT_DIR"), "/ffmpeg_vram_ffi.rs"));
 
 use crate::{
-    common::DataFormat::*,
-    vram::inner::{DecodeCalls, EncodeCalls, InnerDecodeContext, InnerEncodeContext},
+    common::{
+        ConfigCheck, DataFormat::*, DecodeCaps, EncodeCaps, EncodeOptions, GpuTiming, MemoryInfo,
+        RuntimeInfo,
+    },
+    vram::inner::{DecodeCalls, EncodeCalls, InnerDecodeContext, InnerEncodeContext, JpegCalls},
 };
 
 pub fn encode_calls() -> EncodeCalls {
@@ -17,6 +20,17 @@ pub fn encode_calls() -> EncodeCalls {
         test: ffmpeg_vram_test_encode,
         set_bitrate: ffmpeg_vram_set_bitrate,
         set_framerate: ffmpeg_vram_set_framerate,
+        request_keyframe: ffmpeg_vram_request_keyframe,
+        check: ffmpeg_vram_check_encoder,
+        memory: ffmpeg_vram_encoder_memory,
+        info: ffmpeg_vram_encoder_info,
+        gpu_timings: ffmpeg_vram_encoder_gpu_timings,
+        flush: ffmpeg_vram_encoder_flush,
+        set_emphasis_map: ffmpeg_vram_encoder_set_emphasis_map,
+        set_qp_delta: ffmpeg_vram_encoder_set_qp_delta,
+        invalidate_reference: ffmpeg_vram_encoder_invalidate_reference,
+        estimate_memory: ffmpeg_vram_estimate_encoder_memory,
+        check_config: ffmpeg_vram_check_encoder_config,
     }
 }
 
@@ -26,6 +40,17 @@ pub fn decode_calls() -> DecodeCalls {
         decode: ffmpeg_vram_decode,
         destroy: ffmpeg_vram_destroy_decoder,
         test: ffmpeg_vram_test_decode,
+        memory: ffmpeg_vram_decoder_memory,
+        set_conceal: ffmpeg_vram_decoder_set_conceal,
+        caps: ffmpeg_vram_decoder_caps,
+    }
+}
+
+pub fn jpeg_calls() -> JpegCalls {
+    JpegCalls {
+        new: ffmpeg_vram_new_jpeg_encoder,
+        encode: ffmpeg_vram_jpeg_encode,
+        destroy: ffmpeg_vram_destroy_jpeg_encoder,
     }
 }
 
@@ -41,7 +66,7 @@ pub fn possible_support_encoders() -> Vec<InnerEncodeContext> {
 }
 
 pub fn possible_support_decoders() -> Vec<InnerDecodeContext> {
-    let codecs = vec![H264, H265];
+    let codecs = vec![H264, H265, MJPEG];
     let mut v = vec![];
     for codec in codecs.iter() {
         v.push(InnerDecodeContext {
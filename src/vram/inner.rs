@@ -1,5 +1,99 @@
-use crate::common::{DataFormat, DecodeCallback, EncodeCallback};
-use std::os::raw::{c_int, c_void};
+use crate::common::{
+    ConfigCheck, DataFormat, DecodeCallback, DecodeCaps, DriverInfo, DriverProbe, EncodeCallback,
+    EncodeCaps, EncodeOptions, GpuTiming, MemoryInfo, RuntimeInfo, TextureDesc,
+};
+use log::{debug, error, warn};
+use std::{
+    cell::UnsafeCell,
+    os::raw::{c_char, c_int, c_void},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+extern "C" {
+    pub(crate) fn hwcodec_new_d3d11_device(luid: i64) -> *mut c_void;
+    pub(crate) fn hwcodec_module_loaded(name: *const c_char) -> c_int;
+    pub(crate) fn hwcodec_vendor_module_references() -> c_int;
+    pub(crate) fn hwcodec_free_vendor_modules() -> c_int;
+    pub(crate) fn hwcodec_release_d3d11(p: *mut c_void);
+    pub(crate) fn hwcodec_adapter_path(
+        luid: i64,
+        description: *mut c_char,
+        description_len: i32,
+        hardware_id: *mut c_char,
+        hardware_id_len: i32,
+    ) -> c_int;
+    pub(crate) fn hwcodec_new_d3d11_bgra_texture(
+        device: *mut c_void,
+        width: i32,
+        height: i32,
+        data: *const u8,
+        stride: i32,
+    ) -> *mut c_void;
+    pub(crate) fn hwcodec_read_d3d11_bgra_texture(
+        texture: *mut c_void,
+        data: *mut u8,
+        stride: i32,
+        height: i32,
+    ) -> i32;
+    pub(crate) fn hwcodec_get_d3d11_texture_width_height(
+        texture: *mut c_void,
+        width: *mut i32,
+        height: *mut i32,
+    );
+    pub(crate) fn hwcodec_get_d3d11_texture_desc(
+        texture: *mut c_void,
+        desc: *mut TextureDesc,
+    ) -> i32;
+    pub(crate) fn hwcodec_new_d3d11_texture_like(
+        src: *mut c_void,
+        width: i32,
+        height: i32,
+    ) -> *mut c_void;
+    pub(crate) fn hwcodec_new_d3d11_staging_texture_like(
+        src: *mut c_void,
+        width: i32,
+        height: i32,
+    ) -> *mut c_void;
+    pub(crate) fn hwcodec_pad_d3d11_texture(
+        src: *mut c_void,
+        dst: *mut c_void,
+        width: i32,
+        height: i32,
+    ) -> i32;
+    pub(crate) fn hwcodec_copy_d3d11_texture_rect(
+        src: *mut c_void,
+        dst: *mut c_void,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    ) -> i32;
+    pub(crate) fn hwcodec_check_d3d11_staging_texture(
+        texture: *mut c_void,
+        width: *mut i32,
+        height: *mut i32,
+    ) -> i32;
+    pub(crate) fn hwcodec_copy_to_d3d11_staging(
+        src: *mut c_void,
+        staging: *mut c_void,
+        width: i32,
+        height: i32,
+        row_pitch: *mut i32,
+    ) -> i32;
+    pub(crate) fn hwcodec_open_d3d11_shared_fence(
+        texture: *mut c_void,
+        handle: *mut c_void,
+    ) -> *mut c_void;
+    pub(crate) fn hwcodec_wait_d3d11_fence(
+        texture: *mut c_void,
+        fence: *mut c_void,
+        value: u64,
+    ) -> i32;
+}
 
 pub type NewEncoderCall = unsafe extern "C" fn(
     hdl: *mut c_void,
@@ -10,6 +104,7 @@ pub type NewEncoderCall = unsafe extern "C" fn(
     bitrate: i32,
     framerate: i32,
     gop: i32,
+    options: *const EncodeOptions,
 ) -> *mut c_void;
 
 pub type EncodeCall = unsafe extern "C" fn(
@@ -18,6 +113,7 @@ pub type EncodeCall = unsafe extern "C" fn(
     callback: EncodeCallback,
     obj: *mut c_void,
     ms: i64,
+    user_data: u64,
 ) -> c_int;
 
 pub type NewDecoderCall =
@@ -34,6 +130,7 @@ pub type DecodeCall = unsafe extern "C" fn(
 pub type TestEncodeCall = unsafe extern "C" fn(
     outLuids: *mut i64,
     outVendors: *mut i32,
+    outCaps: *mut EncodeCaps,
     maxDescNum: i32,
     outDescNum: *mut i32,
     dataFormat: i32,
@@ -45,6 +142,8 @@ pub type TestEncodeCall = unsafe extern "C" fn(
     excludedLuids: *const i64,
     excludeFormats: *const i32,
     excludeCount: i32,
+    callback: EncodeCallback,
+    obj: *mut c_void,
 ) -> c_int;
 
 pub type TestDecodeCall = unsafe extern "C" fn(
@@ -60,10 +159,60 @@ pub type TestDecodeCall = unsafe extern "C" fn(
     excludeCount: i32,
 ) -> c_int;
 
+pub type MemoryCall = unsafe extern "C" fn(codec: *mut c_void, info: *mut MemoryInfo) -> c_int;
+
+pub type InfoCall = unsafe extern "C" fn(codec: *mut c_void, info: *mut RuntimeInfo) -> c_int;
+
+pub type DecodeCapsCall =
+    unsafe extern "C" fn(decoder: *mut c_void, caps: *mut DecodeCaps) -> c_int;
+
+pub type GpuTimingsCall = unsafe extern "C" fn(
+    codec: *mut c_void,
+    timings: *mut GpuTiming,
+    max: i32,
+    count: *mut i32,
+) -> c_int;
+
+pub type FlushCall =
+    unsafe extern "C" fn(encoder: *mut c_void, callback: EncodeCallback, obj: *mut c_void) -> c_int;
+
+pub type SetEmphasisMapCall =
+    unsafe extern "C" fn(encoder: *mut c_void, map: *const u8, len: i32) -> c_int;
+
+pub type EstimateMemoryCall =
+    unsafe extern "C" fn(dataFormat: i32, width: i32, height: i32, info: *mut MemoryInfo) -> c_int;
+
+pub type CheckEncoderConfigCall = unsafe extern "C" fn(
+    hdl: *mut c_void,
+    luid: i64,
+    codecID: i32,
+    width: i32,
+    height: i32,
+    bitrate: i32,
+    framerate: i32,
+    gop: i32,
+    options: *const EncodeOptions,
+    check: *mut ConfigCheck,
+) -> c_int;
+
+pub type NewJpegEncoderCall =
+    unsafe extern "C" fn(device: *mut c_void, luid: i64, quality: i32) -> *mut c_void;
+
+pub type JpegEncodeCall = unsafe extern "C" fn(
+    encoder: *mut c_void,
+    tex: *mut c_void,
+    width: i32,
+    height: i32,
+    callback: EncodeCallback,
+    obj: *mut c_void,
+) -> c_int;
+
 pub type IVCall = unsafe extern "C" fn(v: *mut c_void) -> c_int;
 
 pub type IVICall = unsafe extern "C" fn(v: *mut c_void, i: i32) -> c_int;
 
+pub type IVLCall = unsafe extern "C" fn(v: *mut c_void, l: i64) -> c_int;
+
 pub struct EncodeCalls {
     pub new: NewEncoderCall,
     pub encode: EncodeCall,
@@ -71,12 +220,32 @@ pub struct EncodeCalls {
     pub test: TestEncodeCall,
     pub set_bitrate: IVICall,
     pub set_framerate: IVICall,
+    pub request_keyframe: IVCall,
+    pub check: IVCall,
+    pub memory: MemoryCall,
+    pub info: InfoCall,
+    pub gpu_timings: GpuTimingsCall,
+    pub flush: FlushCall,
+    pub set_emphasis_map: SetEmphasisMapCall,
+    pub set_qp_delta: IVICall,
+    pub invalidate_reference: IVLCall,
+    pub estimate_memory: EstimateMemoryCall,
+    pub check_config: CheckEncoderConfigCall,
 }
 pub struct DecodeCalls {
     pub new: NewDecoderCall,
     pub decode: DecodeCall,
     pub destroy: IVCall,
     pub test: TestDecodeCall,
+    pub memory: MemoryCall,
+    pub set_conceal: IVICall,
+    pub caps: DecodeCapsCall,
+}
+
+pub struct JpegCalls {
+    pub new: NewJpegEncoderCall,
+    pub encode: JpegEncodeCall,
+    pub destroy: IVCall,
 }
 
 pub struct InnerEncodeContext {
@@ -86,3 +255,182 @@ pub struct InnerEncodeContext {
 pub struct InnerDecodeContext {
     pub data_format: DataFormat,
 }
+
+// Owns the Vec the C callbacks push into through their obj pointer. Kept as a raw
+// allocation rather than a Box so taking the context pointer doesn't conflict with
+// the unique ownership a Box asserts.
+// The shims call back on the thread of the call given the context and before it
+// returns, one frame or NAL batch after the other, and none runs them concurrently.
+// push holds them to that: a delivery from another thread or overlapping another is
+// dropped and counted, the caller fails the call with take_stray.
+pub(crate) struct CallbackFrames<T> {
+    ptr: NonNull<CallbackSlot<T>>,
+}
+
+struct CallbackSlot<T> {
+    frames: UnsafeCell<Vec<T>>,
+    // thread_key of the latest as_context, 0 before it. Atomic, a stray delivery may
+    // read it while the next call sets it.
+    thread: AtomicU64,
+    pushing: AtomicBool,
+    stray: AtomicUsize,
+}
+
+impl<T> CallbackFrames<T> {
+    pub fn new() -> Self {
+        let slot = CallbackSlot {
+            frames: UnsafeCell::new(Vec::new()),
+            thread: AtomicU64::new(0),
+            pushing: AtomicBool::new(false),
+            stray: AtomicUsize::new(0),
+        };
+        Self {
+            ptr: NonNull::from(Box::leak(Box::new(slot))),
+        }
+    }
+
+    // For a native call on this thread, only valid while self is alive.
+    // Only shared references to the slot are made, stray deliveries may hold one at any
+    // time.
+    pub fn as_context(&mut self) -> *mut c_void {
+        let slot = unsafe { self.ptr.as_ref() };
+        slot.thread.store(thread_key(), Ordering::Release);
+        slot.stray.store(0, Ordering::Relaxed);
+        self.ptr.as_ptr() as *mut c_void
+    }
+
+    // the frames are only pushed on the thread of the call, which is this one
+    pub fn get_mut(&mut self) -> &mut Vec<T> {
+        unsafe { &mut *self.ptr.as_ref().frames.get() }
+    }
+
+    // the deliveries push dropped since as_context
+    pub fn take_stray(&mut self) -> usize {
+        unsafe { self.ptr.as_ref().stray.swap(0, Ordering::Relaxed) }
+    }
+
+    // For the C callbacks, obj is the context they were given.
+    pub unsafe fn push(obj: *const c_void, frame: T) {
+        let slot = &*(obj as *const CallbackSlot<T>);
+        if slot.thread.load(Ordering::Acquire) != thread_key() {
+            error!("frame delivered on another thread than its call, dropped");
+            slot.stray.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if slot.pushing.swap(true, Ordering::Acquire) {
+            error!("frame delivered during another delivery, dropped");
+            slot.stray.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        (*slot.frames.get()).push(frame);
+        slot.pushing.store(false, Ordering::Release);
+    }
+}
+
+// A nonzero number per thread for CallbackSlot::thread, ThreadId has no stable integer.
+fn thread_key() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static KEY: u64 = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    KEY.with(|key| *key)
+}
+
+impl<T> Drop for CallbackFrames<T> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(self.ptr.as_ptr()));
+        }
+    }
+}
+
+// releases the d3d11 device or texture it holds
+pub(crate) struct D3D11Ptr(pub *mut c_void);
+
+impl D3D11Ptr {
+    pub fn device(luid: i64) -> Result<Self, String> {
+        let device = unsafe { hwcodec_new_d3d11_device(luid) };
+        if device.is_null() {
+            return Err(format!("failed to create device for luid {}", luid));
+        }
+        Ok(Self(device))
+    }
+}
+
+impl Drop for D3D11Ptr {
+    fn drop(&mut self) {
+        unsafe { hwcodec_release_d3d11(self.0) }
+    }
+}
+
+// bumped by vram::invalidate_driver_support, older cached results are probed again
+pub(crate) static DRIVER_SUPPORT_GENERATION: AtomicUsize = AtomicUsize::new(0);
+// calls of the probes, for debug_driver_support_probes
+#[cfg(feature = "testutil")]
+pub(crate) static DRIVER_SUPPORT_PROBES: AtomicUsize = AtomicUsize::new(0);
+
+// The result of one of the *_driver_support calls, which load the driver dlls and query
+// their version. Probed once per process until invalidated, the lock is held meanwhile
+// so that concurrent available() calls wait for the first probe. A missing dll or entry
+// point fails the probe, the driver's possible_support_* then return nothing.
+pub(crate) struct DriverSupport {
+    // for the log, e.g. "NV encode"
+    name: &'static str,
+    cached: Mutex<Option<(usize, DriverInfo)>>,
+}
+
+impl DriverSupport {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            cached: Mutex::new(None),
+        }
+    }
+
+    // probe fills in the versions and returns 0 when the driver is present
+    pub fn get(&self, probe: impl FnOnce(*mut DriverProbe) -> c_int) -> bool {
+        self.info(probe).supported
+    }
+
+    pub fn info(&self, probe: impl FnOnce(*mut DriverProbe) -> c_int) -> DriverInfo {
+        let generation = DRIVER_SUPPORT_GENERATION.load(Ordering::Acquire);
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((g, info)) = &*cached {
+            if *g == generation {
+                return info.clone();
+            }
+        }
+        #[cfg(feature = "testutil")]
+        DRIVER_SUPPORT_PROBES.fetch_add(1, Ordering::Relaxed);
+        let mut result = DriverProbe::default();
+        let supported = probe(&mut result) == 0;
+        let info = DriverInfo::new(&result, supported);
+        if info.too_old {
+            warn!("{}: {}", self.name, info);
+        } else if !supported {
+            debug!("{} runtime not found or not usable", self.name);
+        }
+        *cached = Some((generation, info.clone()));
+        info
+    }
+}
+
+// native sessions alive, see vram::shutdown
+pub(crate) static SESSIONS: Mutex<usize> = Mutex::new(0);
+
+// Counts a session of the shims, created before it and dropped after its destruction. A
+// field after the codec pointer of the owner, fields drop after the owner's Drop.
+pub(crate) struct SessionGuard;
+
+impl SessionGuard {
+    pub fn new() -> Self {
+        *SESSIONS.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        Self
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        *SESSIONS.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+    }
+}
@@ -5,10 +5,25 @@
 include!(concat!(env!("OUT_DIR"), "/mfx_ffi.rs"));
 
 use crate::{
-    common::DataFormat::*,
-    vram::inner::{DecodeCalls, EncodeCalls, InnerDecodeContext, InnerEncodeContext},
+    common::{
+        ConfigCheck, DataFormat::*, DecodeCaps, DriverInfo, DriverProbe, EncodeCaps, EncodeOptions,
+        GpuTiming, MemoryInfo, RuntimeInfo,
+    },
+    vram::inner::{
+        DecodeCalls, DriverSupport, EncodeCalls, InnerDecodeContext, InnerEncodeContext, JpegCalls,
+    },
 };
 
+static DRIVER_SUPPORT: DriverSupport = DriverSupport::new("MFX");
+
+pub(crate) fn driver_support() -> bool {
+    DRIVER_SUPPORT.get(|probe| unsafe { mfx_driver_support(probe) })
+}
+
+pub(crate) fn driver_info() -> DriverInfo {
+    DRIVER_SUPPORT.info(|probe| unsafe { mfx_driver_support(probe) })
+}
+
 pub fn encode_calls() -> EncodeCalls {
     EncodeCalls {
         new: mfx_new_encoder,
@@ -17,6 +32,17 @@ pub fn encode_calls() -> EncodeCalls {
         test: mfx_test_encode,
         set_bitrate: mfx_set_bitrate,
         set_framerate: mfx_set_framerate,
+        request_keyframe: mfx_request_keyframe,
+        check: mfx_check_encoder,
+        memory: mfx_encoder_memory,
+        info: mfx_encoder_info,
+        gpu_timings: mfx_encoder_gpu_timings,
+        flush: mfx_encoder_flush,
+        set_emphasis_map: mfx_encoder_set_emphasis_map,
+        set_qp_delta: mfx_encoder_set_qp_delta,
+        invalidate_reference: mfx_encoder_invalidate_reference,
+        estimate_memory: mfx_estimate_encoder_memory,
+        check_config: mfx_check_encoder_config,
     }
 }
 
@@ -26,11 +52,22 @@ pub fn decode_calls() -> DecodeCalls {
         decode: mfx_decode,
         destroy: mfx_destroy_decoder,
         test: mfx_test_decode,
+        memory: mfx_decoder_memory,
+        set_conceal: mfx_decoder_set_conceal,
+        caps: mfx_decoder_caps,
+    }
+}
+
+pub fn jpeg_calls() -> JpegCalls {
+    JpegCalls {
+        new: mfx_new_jpeg_encoder,
+        encode: mfx_jpeg_encode,
+        destroy: mfx_destroy_jpeg_encoder,
     }
 }
 
 pub fn possible_support_encoders() -> Vec<InnerEncodeContext> {
-    if unsafe { mfx_driver_support() } != 0 {
+    if !driver_support() {
         return vec![];
     }
     let dataFormats = vec![H264, H265];
@@ -44,10 +81,10 @@ pub fn possible_support_encoders() -> Vec<InnerEncodeContext> {
 }
 
 pub fn possible_support_decoders() -> Vec<InnerDecodeContext> {
-    if unsafe { mfx_driver_support() } != 0 {
+    if !driver_support() {
         return vec![];
     }
-    let dataFormats = vec![H264, H265];
+    let dataFormats = vec![H264, H265, MJPEG];
     let mut v = vec![];
     for dataFormat in dataFormats.iter() {
         v.push(InnerDecodeContext {
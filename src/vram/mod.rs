@@ -1,18 +1,39 @@
+#[cfg(feature = "amf")]
 pub(crate) mod amf;
+pub mod backend;
+pub(crate) mod d3d11;
 pub mod decode;
 pub mod encode;
+#[cfg(feature = "vram-ffmpeg")]
 pub(crate) mod ffmpeg;
-mod inner;
+pub(crate) mod inner;
+#[cfg(feature = "mfx")]
 pub(crate) mod mfx;
+#[cfg(feature = "nv")]
 pub(crate) mod nv;
+pub mod pool;
+pub mod record;
+pub mod report;
+pub mod self_test;
+pub mod snapshot;
+pub mod split;
+#[cfg(feature = "tokio")]
+pub mod worker;
 
 pub(crate) const MAX_ADATERS: usize = 16;
 
-use crate::common::{DataFormat, Driver};
+use crate::common::{
+    AqMode, ChromaLocation, CodecDescriptor, ColorConvert, DataFormat, Driver, EncodeCaps,
+    EncodeOptions, EntropyCoding,
+};
+use log::{error, warn};
 pub use serde;
 pub use serde_derive;
 use serde_derive::{Deserialize, Serialize};
-use std::ffi::c_void;
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    sync::atomic::Ordering,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct FeatureContext {
@@ -20,17 +41,300 @@ pub struct FeatureContext {
     pub vendor: Driver,
     pub luid: i64,
     pub data_format: DataFormat,
+    // reported by the native test, empty for contexts of older versions and custom drivers
+    #[serde(default)]
+    pub caps: EncodeCaps,
+}
+
+impl FeatureContext {
+    // what the sessions encode, 8 bit 4:2:0 H.264 High or H.265 Main
+    pub fn descriptor(&self) -> CodecDescriptor {
+        CodecDescriptor::from(self.data_format)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub struct DynamicContext {
+    // May be shared by codecs on different threads, the device is made multithread
+    // protected and the shims hold its ID3D10Multithread lock around their immediate
+    // context work. Others using the context concurrently have to take that lock too.
     #[serde(skip)]
     pub device: Option<*mut c_void>,
     pub width: i32,
     pub height: i32,
+    // 0 encodes at constant quality, see quality
     pub kbitrate: i32,
+    // What the rate control budgets kbitrate over, input faster than this gets cheaper
+    // frames. Timestamps don't come from it, the pts of the output are the ms given to
+    // encode whatever their spacing.
     pub framerate: i32,
     pub gop: i32,
+    // spatial suits screen text, temporal suits camera content
+    #[serde(default)]
+    pub aq_mode: AqMode,
+    // 1 - 15, 0 lets the encoder choose
+    #[serde(default)]
+    pub aq_strength: i32,
+    // written into the vui, must match how the input was subsampled
+    #[serde(default)]
+    pub chroma_location: ChromaLocation,
+    // The sample aspect ratio, width:height of a pixel, written into the vui for sources
+    // of non-square pixels, e.g. anamorphic video. Taken in lowest terms, which must be
+    // 1 - 65535 each. 1:1 writes none, decoders assume square pixels without one.
+    #[serde(default = "default_sar")]
+    pub sar: (u32, u32),
+    // queues d3d11 timestamp queries around each frame, read with Encoder::gpu_timings
+    #[serde(default)]
+    pub gpu_timing: bool,
+    // False turns off the keyframes encoders insert on scene cuts, with an infinite gop
+    // the only ones are then the first and the requested ones. Full window repaints of
+    // screen content look like scene cuts.
+    #[serde(default = "default_scene_cut_keyframes")]
+    pub scene_cut_keyframes: bool,
+    // Frames between keyframes the encoder inserts on its own, 0 for no minimum. MFX has
+    // no such setting and warns, the other backends insert them only in configurations
+    // this crate doesn't use.
+    #[serde(default)]
+    pub min_keyframe_interval: i32,
+    // Keyframes on scene cuts but at most this many frames apart, for recorders. The
+    // session runs with an infinite gop in place of gop and the encoder requests a
+    // keyframe when max_gop frames passed since the latest one, a scene cut keyframe
+    // restarts the count. Without scene_cut_keyframes it is a gop kept by the encoder.
+    // 0 is off.
+    #[serde(default)]
+    pub max_gop: i32,
+    // Floor of the output rate in kbps for transports that estimate bandwidth from it,
+    // static scenes are padded with filler data NAL units. 0 is off, h264 and h265 only.
+    #[serde(default)]
+    pub min_kbitrate: i32,
+    // For input that arrives only when the content changes: the rate control follows the
+    // interval between the ms of successive encode calls, framerate is the upper bound.
+    // Needs a backend that changes the framerate at runtime, MFX can't and warns.
+    #[serde(default)]
+    pub variable_framerate: bool,
+    // every keyframe carries the parameter sets inline, for receivers joining mid-stream.
+    // h264 and h265 only.
+    #[serde(default)]
+    pub repeat_headers: bool,
+    // Takes an emphasis level per macroblock with the frames given to
+    // Encoder::encode_with_emphasis_map and turns aq off. NV h264 only, see
+    // EncodeCapability::ENCODE_CAP_EMPHASIS_MAP, creating other encoders fails.
+    #[serde(default)]
+    pub emphasis_map: bool,
+    // Keeps the frames before the latest one as references, so that
+    // Encoder::invalidate_reference recovers from a loss without an IDR. NV only, see
+    // EncodeCapability::ENCODE_CAP_REF_INVALIDATION, creating other encoders fails.
+    #[serde(default)]
+    pub reference_invalidation: bool,
+    // For transports with a per frame budget: encoded frames beyond this many bytes are
+    // handled by oversize, 0 for no limit.
+    #[serde(default)]
+    pub max_frame_bytes: i32,
+    #[serde(default)]
+    pub oversize: OversizePolicy,
+    // H.264 only, CAVLC for receivers that decode Baseline streams. Encoder::new fails
+    // for a value the descriptor's profile doesn't allow, see
+    // CodecDescriptor::supports_entropy_coding.
+    #[serde(default)]
+    pub entropy_coding: EntropyCoding,
+    // backend settings for a use case, see Tune
+    #[serde(default)]
+    pub tune: Tune,
+    // Added to the qp of both chroma planes, -12 to 12. Negative spends more bits on
+    // chroma, e.g. for colored text on screen content. NV only, creating other encoders
+    // with an offset fails.
+    #[serde(default)]
+    pub chroma_qp_offset: i32,
+    // With kbitrate 0, the quality the encoder holds whatever bitrate that takes: 1 to
+    // encode::MAX_QUALITY, lower is better, 0 for encode::DEFAULT_QUALITY. NVENC targets
+    // it in VBR, AMF runs QVBR, MFX ICQ and FFmpeg their CRF like modes. Ignored with a
+    // kbitrate, Encoder::set_bitrate fails for such sessions.
+    #[serde(default)]
+    pub quality: i32,
+    // Mathematically lossless, for archival capture, kbitrate and quality are ignored and
+    // Encoder::set_bitrate fails. The color conversion of the bgra input to nv12 still
+    // loses the chroma detail. NV and FFMPEG on NVIDIA only, see
+    // EncodeCapability::ENCODE_CAP_LOSSLESS, creating other encoders fails. H.264 streams
+    // are High 4:4:4 Predictive, which the d3d11 decoders don't decode.
+    #[serde(default)]
+    pub lossless: bool,
+    // Whether every encode call must return exactly one EncodeFrame, of its own input,
+    // see one_in_one_out. None holds Tune::CloudGaming sessions to it.
+    #[serde(default)]
+    pub strict_one_in_one_out: Option<bool>,
+    // Where the bgra input becomes nv12 for MFX and FFMPEG, on the GPU's video processor
+    // or on the cpu for drivers whose video processor is broken. The cpu path reads each
+    // frame back and costs milliseconds per frame. NV and AMF convert inside the
+    // encoder, creating them with COLOR_CONVERT_SOFTWARE fails.
+    #[serde(default)]
+    pub color_convert: ColorConvert,
+    // What Encoder::encode does when the native encode call fails, None returns the
+    // error, see RecoveryPolicy.
+    #[serde(default)]
+    pub recovery: Option<RecoveryPolicy>,
+}
+
+// What Encoder::encode does with a frame beyond DynamicContext::max_frame_bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum OversizePolicy {
+    // The frame and those after it in the same call are dropped, the next encode call
+    // starts a closed gop so that nothing references them.
+    #[default]
+    Drop,
+    // The input is encoded again as an IDR at a higher qp, up to three times, then
+    // dropped. Needs a per frame qp offset, NV has one unless d.emphasis_map is on, the
+    // other backends and sessions with B-frames drop instead.
+    Reencode,
+    // The frame comes out as consecutive EncodeFrames of at most max_frame_bytes, cut
+    // between its NAL units where they fit and inside those that don't. They have its pts
+    // and user_data and FRAME_FLAG_FRAGMENT, the last one FRAME_FLAG_LAST_FRAGMENT, only
+    // the first is a key. The receiver concatenates them, e.g. with an
+    // AccessUnitAssembler. Encoder::summary counts it once.
+    Split,
+}
+
+// Recovery from native encode calls failing, e.g. for a frame or two when the driver
+// runs short of memory. A failed call is repeated for the same frame up to retries
+// times, backoff_ms apart, then the frame fails with the error. After reinit_after
+// frames failing in a row the session is recreated on the same device with the same
+// settings and encodes the frame again as an IDR, a lost session right away. When that
+// fails too the call returns HWCODEC_ERR_SESSION_LOST, see Encoder::on_error. Lost
+// devices, stale input and resets aren't retried, their errors need the caller. See
+// Encoder::recovery_stats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RecoveryPolicy {
+    pub retries: u32,
+    pub backoff_ms: u32,
+    // 0 never recreates the session
+    pub reinit_after: u32,
+}
+
+// Presets of the backend settings for a use case, on top of the rest of the context.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Tune {
+    // the settings the fields of DynamicContext describe, low latency at full quality
+    #[default]
+    Default,
+    // Game streaming: no B-frames, lookahead or output delay and a vbv of one frame's
+    // bits, so a frame is out before the next is captured and a link of kbitrate
+    // carries it within its frame interval. There are no keyframes but the first and the
+    // requested ones, scene cuts neither whatever scene_cut_keyframes says, intra
+    // refresh restores the picture after a loss instead. The first frame of each refresh
+    // carries a recovery point SEI, see EncodeFrame::recovery_point, but on AMF, which
+    // has no such option. Per backend:
+    // - NV: preset P1 with the ultra low latency tuning, infinite gop, a refresh over
+    //   half a second repeated every gop, framerate frames when gop is not finite, and
+    //   4 slices. Without ENCODE_CAP_INTRA_REFRESH it warns and runs without the
+    //   refresh.
+    // - AMF: the ultra low latency usage with the speed preset, no IDR period, a row of
+    //   macroblocks or CTBs refreshed per frame, 4 slices, pre-analysis off.
+    // - MFX: best speed, infinite gop, a vertical refresh over half a second, the low
+    //   delay brc with a frame size cap, 4 slices.
+    // - FFmpeg: the options of nvenc (p1, ull, zerolatency, intra-refresh over the
+    //   gop), amf (ultralowlatency, speed, intra_refresh_mb) and qsv (veryfast,
+    //   int_ref_type vertical, low_delay_brc, recovery_point_sei) with rc_buffer_size
+    //   of one frame and 4 slices. Options a build lacks are skipped with a warning.
+    //   nvenc has no option for the SEIs, whether it writes them depends on the build.
+    CloudGaming,
+}
+
+fn default_scene_cut_keyframes() -> bool {
+    true
+}
+
+fn default_sar() -> (u32, u32) {
+    (1, 1)
+}
+
+impl Default for DynamicContext {
+    fn default() -> Self {
+        DynamicContext {
+            device: None,
+            width: 0,
+            height: 0,
+            kbitrate: 0,
+            framerate: 0,
+            gop: 0,
+            aq_mode: AqMode::default(),
+            aq_strength: 0,
+            chroma_location: ChromaLocation::default(),
+            sar: default_sar(),
+            gpu_timing: false,
+            scene_cut_keyframes: default_scene_cut_keyframes(),
+            min_keyframe_interval: 0,
+            max_gop: 0,
+            min_kbitrate: 0,
+            variable_framerate: false,
+            repeat_headers: false,
+            emphasis_map: false,
+            reference_invalidation: false,
+            max_frame_bytes: 0,
+            oversize: OversizePolicy::default(),
+            entropy_coding: EntropyCoding::default(),
+            tune: Tune::default(),
+            chroma_qp_offset: 0,
+            quality: 0,
+            lossless: false,
+            strict_one_in_one_out: None,
+            color_convert: ColorConvert::default(),
+            recovery: None,
+        }
+    }
+}
+
+impl DynamicContext {
+    // Encoder::new fails for sessions that could return no frame or several for an
+    // input: with B-frames or a max_frame_bytes, which drops or splits frames. A call
+    // whose native encode returns another count fails with HWCODEC_ERR_NOT_ONE_FRAME
+    // anyway, the frames are discarded and the next one is an IDR.
+    pub fn one_in_one_out(&self) -> bool {
+        self.strict_one_in_one_out
+            .unwrap_or(self.tune == Tune::CloudGaming)
+    }
+
+    // sar in lowest terms, None when a term is 0 or beyond the 16 bits of the vui
+    pub(crate) fn reduced_sar(&self) -> Option<(u32, u32)> {
+        let (w, h) = self.sar;
+        let (mut a, mut b) = (w, h);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        if a == 0 {
+            return None;
+        }
+        let (w, h) = (w / a, h / a);
+        (w > 0 && h > 0 && w <= u16::MAX as u32 && h <= u16::MAX as u32).then_some((w, h))
+    }
+
+    pub(crate) fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            aqMode: self.aq_mode,
+            aqStrength: self.aq_strength,
+            chromaLocation: self.chroma_location,
+            sarWidth: self.reduced_sar().map_or(0, |sar| sar.0 as _),
+            sarHeight: self.reduced_sar().map_or(0, |sar| sar.1 as _),
+            gpuTiming: self.gpu_timing as _,
+            noSceneCutKeyframes: (!self.scene_cut_keyframes || self.tune == Tune::CloudGaming) as _,
+            minKeyframeInterval: self.min_keyframe_interval.max(0),
+            emphasisMap: self.emphasis_map as _,
+            qpDelta: (self.max_frame_bytes > 0
+                && self.oversize == OversizePolicy::Reencode
+                && !self.emphasis_map) as _,
+            refInvalidation: self.reference_invalidation as _,
+            entropyCoding: self.entropy_coding,
+            cloudGaming: (self.tune == Tune::CloudGaming) as _,
+            chromaQpOffset: self.chroma_qp_offset,
+            quality: match (self.kbitrate, self.quality) {
+                _ if self.lossless => 0,
+                (0, 0) => encode::DEFAULT_QUALITY,
+                (0, quality) => quality,
+                _ => 0,
+            },
+            lossless: self.lossless as _,
+            colorConvert: self.color_convert,
+            ffmpegOptions: std::ptr::null(),
+        }
+    }
 }
 
 unsafe impl Send for DynamicContext {}
@@ -50,6 +354,29 @@ pub struct DecodeContext {
     pub vendor: Driver,
     pub luid: i64,
     pub data_format: DataFormat,
+    #[serde(default)]
+    pub output_order: OutputOrder,
+    // After a packet whose reference pictures are missing, e.g. behind a lost packet,
+    // true decodes on with the driver concealing the damage and marks the frames
+    // corrupted until the next IDR. A packet failing validation or decoding, e.g. with
+    // corrupted slice data, returns the last decoded frame again marked corrupted instead
+    // of the error. False discards the packets until the next IDR with
+    // HWCODEC_ERR_NEED_KEYFRAME, a frozen picture instead of a smeared one, and returns
+    // the errors of the others. Off unless set.
+    #[serde(default)]
+    pub conceal_errors: bool,
+}
+
+// The sessions output each frame as soon as it is decoded, for streams with B-frames
+// that is decode order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum OutputOrder {
+    #[default]
+    Decode,
+    // Frames are held back until as many later ones as the SPS allows to be reordered
+    // were decoded and come out by the pts given to Decoder::decode_with_pts. That many
+    // frames of latency, see Decoder::held_frames, live streams keep Decode.
+    Presentation,
 }
 
 unsafe impl Send for DecodeContext {}
@@ -88,3 +415,156 @@ impl Available {
         }
     }
 }
+
+// how the OS names an adapter, to find the one of a luid in tools showing other ids
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AdapterPath {
+    // e.g. "NVIDIA GeForce RTX 4090"
+    pub description: String,
+    // the PCI hardware id that starts the adapter's device instance path, e.g.
+    // "PCI\VEN_10DE&DEV_2684&SUBSYS_16F310DE&REV_A1"
+    pub hardware_id: String,
+}
+
+// None if no adapter has luid, e.g. it was removed or the driver restarted
+pub fn adapter_path(luid: i64) -> Option<AdapterPath> {
+    let mut description = [0 as c_char; 256];
+    let mut hardware_id = [0 as c_char; 64];
+    let ret = unsafe {
+        inner::hwcodec_adapter_path(
+            luid,
+            description.as_mut_ptr(),
+            description.len() as _,
+            hardware_id.as_mut_ptr(),
+            hardware_id.len() as _,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    let string = |s: &[c_char]| {
+        unsafe { CStr::from_ptr(s.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Some(AdapterPath {
+        description: string(&description),
+        hardware_id: string(&hardware_id),
+    })
+}
+
+// The driver presence checks of NV, AMF and MFX run once per process and are reused by
+// every available() call. After a driver was installed or updated this makes the next
+// calls check again.
+pub fn invalidate_driver_support() {
+    inner::DRIVER_SUPPORT_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+// the dlls the shims load with their sessions and free with them
+const VENDOR_MODULES: &[&str] = &[
+    "nvEncodeAPI64.dll",
+    "nvcuvid.dll",
+    "nvcuda.dll",
+    "nvjpeg64_12.dll",
+    "nvjpeg64_11.dll",
+    "amfrt64.dll",
+    "libmfxhw64.dll",
+    "libmfx64-gen.dll",
+];
+
+// the vendor dlls loaded in the process, by the shims or anyone else
+pub fn loaded_vendor_modules() -> Vec<&'static str> {
+    VENDOR_MODULES
+        .iter()
+        .filter(|name| {
+            let name = CString::new(**name).unwrap_or_default();
+            unsafe { inner::hwcodec_module_loaded(name.as_ptr()) == 1 }
+        })
+        .copied()
+        .collect()
+}
+
+// For laptops keeping the dGPU powered and driver updates. The shims free the vendor dlls
+// and contexts with the last session using them, this drops what the process keeps
+// beyond that: the references on the NV dlls the shims still hold, e.g. of a session that
+// failed half way, and the cached driver checks, so that the next use loads and checks
+// the drivers again. AMF and MFX load their runtimes in the factory and the dispatcher,
+// which free them with their last session. Fails without effect while encoders,
+// decoders or snapshots are alive, sessions created meanwhile wait for it.
+pub fn shutdown() -> Result<(), ()> {
+    let sessions = inner::SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    if *sessions > 0 {
+        error!("can't shut down with {} sessions alive", *sessions);
+        return Err(());
+    }
+    let freed = unsafe { inner::hwcodec_free_vendor_modules() };
+    if freed > 0 {
+        warn!("freed {} vendor dll references left by the shims", freed);
+    }
+    invalidate_driver_support();
+    let loaded = loaded_vendor_modules();
+    if !loaded.is_empty() {
+        // loaded by the application or pinned by the driver
+        warn!("still loaded after shutdown: {:?}", loaded);
+    }
+    Ok(())
+}
+
+// the references the shims hold on the NV dlls, 0 after shutdown
+#[cfg(feature = "testutil")]
+#[doc(hidden)]
+pub fn debug_vendor_module_references() -> usize {
+    unsafe { inner::hwcodec_vendor_module_references() as usize }
+}
+
+// how often the driver presence checks ran in this process
+#[cfg(feature = "testutil")]
+#[doc(hidden)]
+pub fn debug_driver_support_probes() -> usize {
+    inner::DRIVER_SUPPORT_PROBES.load(Ordering::Relaxed)
+}
+
+// Makes the shims treat the runtime dll name as missing, e.g. "nvEncodeAPI64.dll", until
+// unhidden. The cached driver checks are dropped so that the next available() probes
+// again. Sessions already open keep their dlls.
+#[cfg(feature = "testutil")]
+#[doc(hidden)]
+pub fn debug_hide_runtime(name: &str, hidden: bool) {
+    extern "C" {
+        fn hwcodec_debug_hide_module(name: *const c_char, hidden: i32);
+    }
+    let Ok(name) = CString::new(name) else {
+        return;
+    };
+    unsafe { hwcodec_debug_hide_module(name.as_ptr(), hidden as i32) }
+    invalidate_driver_support();
+}
+
+// makes every vram encode/decode report HWCODEC_ERR_DEVICE_LOST until reset
+#[cfg(feature = "testutil")]
+#[doc(hidden)]
+pub fn debug_set_device_lost(lost: bool) {
+    extern "C" {
+        fn hwcodec_debug_set_device_lost(lost: i32);
+    }
+    unsafe { hwcodec_debug_set_device_lost(lost as i32) }
+}
+
+// an mfxSession on device as an application would create it, for Encoder::from_existing_session
+#[cfg(all(feature = "mfx", feature = "testutil"))]
+#[doc(hidden)]
+pub fn debug_new_mfx_session(device: *mut c_void) -> *mut c_void {
+    unsafe { mfx::mfx_debug_new_session(device) }
+}
+
+#[cfg(all(feature = "mfx", feature = "testutil"))]
+#[doc(hidden)]
+pub fn debug_mfx_session_alive(session: *mut c_void) -> bool {
+    unsafe { mfx::mfx_debug_session_alive(session) == 1 }
+}
+
+#[cfg(all(feature = "mfx", feature = "testutil"))]
+#[doc(hidden)]
+pub fn debug_close_mfx_session(session: *mut c_void) {
+    unsafe { mfx::mfx_debug_close_session(session) }
+}
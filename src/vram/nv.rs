@@ -5,10 +5,31 @@
 include!(concat!(env!("OUT_DIR"), "/nv_ffi.rs"));
 
 use crate::{
-    common::DataFormat::*,
-    vram::inner::{DecodeCalls, EncodeCalls, InnerDecodeContext, InnerEncodeContext},
+    common::{
+        ConfigCheck, DataFormat::*, DecodeCaps, DriverInfo, DriverProbe, EncodeCaps, EncodeOptions,
+        GpuTiming, MemoryInfo, RuntimeInfo,
+    },
+    vram::inner::{
+        DecodeCalls, DriverSupport, EncodeCalls, InnerDecodeContext, InnerEncodeContext, JpegCalls,
+    },
 };
 
+static ENCODE_DRIVER_SUPPORT: DriverSupport = DriverSupport::new("NV encode");
+static DECODE_DRIVER_SUPPORT: DriverSupport = DriverSupport::new("NV decode");
+
+pub(crate) fn encode_driver_support() -> bool {
+    ENCODE_DRIVER_SUPPORT.get(|probe| unsafe { nv_encode_driver_support(probe) })
+}
+
+pub(crate) fn decode_driver_support() -> bool {
+    DECODE_DRIVER_SUPPORT.get(|probe| unsafe { nv_decode_driver_support(probe) })
+}
+
+// the encode probe, it also checks the nvenc api version of the driver
+pub(crate) fn encode_driver_info() -> DriverInfo {
+    ENCODE_DRIVER_SUPPORT.info(|probe| unsafe { nv_encode_driver_support(probe) })
+}
+
 pub fn encode_calls() -> EncodeCalls {
     EncodeCalls {
         new: nv_new_encoder,
@@ -17,6 +38,17 @@ pub fn encode_calls() -> EncodeCalls {
         test: nv_test_encode,
         set_bitrate: nv_set_bitrate,
         set_framerate: nv_set_framerate,
+        request_keyframe: nv_request_keyframe,
+        check: nv_check_encoder,
+        memory: nv_encoder_memory,
+        info: nv_encoder_info,
+        gpu_timings: nv_encoder_gpu_timings,
+        flush: nv_encoder_flush,
+        set_emphasis_map: nv_encoder_set_emphasis_map,
+        set_qp_delta: nv_encoder_set_qp_delta,
+        invalidate_reference: nv_encoder_invalidate_reference,
+        estimate_memory: nv_estimate_encoder_memory,
+        check_config: nv_check_encoder_config,
     }
 }
 
@@ -26,11 +58,22 @@ pub fn decode_calls() -> DecodeCalls {
         decode: nv_decode,
         destroy: nv_destroy_decoder,
         test: nv_test_decode,
+        memory: nv_decoder_memory,
+        set_conceal: nv_decoder_set_conceal,
+        caps: nv_decoder_caps,
+    }
+}
+
+pub fn jpeg_calls() -> JpegCalls {
+    JpegCalls {
+        new: nv_new_jpeg_encoder,
+        encode: nv_jpeg_encode,
+        destroy: nv_destroy_jpeg_encoder,
     }
 }
 
 pub fn possible_support_encoders() -> Vec<InnerEncodeContext> {
-    if unsafe { nv_encode_driver_support() } != 0 {
+    if !encode_driver_support() {
         return vec![];
     }
     let dataFormats = vec![H264, H265];
@@ -44,10 +87,11 @@ pub fn possible_support_encoders() -> Vec<InnerEncodeContext> {
 }
 
 pub fn possible_support_decoders() -> Vec<InnerDecodeContext> {
-    if unsafe { nv_encode_driver_support() } != 0 {
+    // nvcuvid.dll, nvEncodeAPI64.dll may be present without it
+    if !decode_driver_support() {
         return vec![];
     }
-    let dataFormats = vec![H264, H265];
+    let dataFormats = vec![H264, H265, MJPEG];
     let mut v = vec![];
     for dataFormat in dataFormats.iter() {
         v.push(InnerDecodeContext {
@@ -0,0 +1,223 @@
+use crate::{
+    common::DataFormat,
+    vram::{
+        encode::{self, Encoder},
+        DynamicContext, EncodeContext, FeatureContext,
+    },
+};
+use log::{debug, warn};
+use std::{collections::VecDeque, ffi::c_void};
+
+// Keeps encoders that were given back so that a session of a size seen before doesn't have
+// to be created again, e.g. while a remote window is resized back and forth. An idle
+// encoder is handed out again for a context that differs at most in the framerate and
+// in the bitrate within a power of two, both are set on it. The least recently given
+// back ones are dropped beyond capacity encoders or budget bytes of video memory.
+pub struct EncoderPool {
+    capacity: usize,
+    budget: i64,
+    // least recently given back first
+    idle: VecDeque<Encoder>,
+}
+
+impl EncoderPool {
+    pub fn new(capacity: usize, budget: i64) -> Self {
+        Self {
+            capacity,
+            budget,
+            idle: VecDeque::new(),
+        }
+    }
+
+    // An idle encoder matching ctx or a new one. The first frame of an idle one is an IDR
+    // with the parameter sets, it encodes textures of ctx.d.device like a new one.
+    pub fn get(&mut self, ctx: EncodeContext) -> Result<Encoder, ()> {
+        while let Some(i) = self.idle.iter().rposition(|e| reusable(&e.ctx, &ctx)) {
+            let mut encoder = self.idle.remove(i).ok_or(())?;
+            match resume(&mut encoder, &ctx) {
+                Ok(()) => {
+                    debug!(
+                        "reusing {:?} encoder of {}x{}",
+                        ctx.f.driver, ctx.d.width, ctx.d.height
+                    );
+                    return Ok(encoder);
+                }
+                Err(e) => debug!("idle {:?} encoder not reusable: {}", ctx.f.driver, e),
+            }
+        }
+        Encoder::new(ctx)
+    }
+
+    // encoders that are no longer healthy are dropped
+    pub fn put(&mut self, encoder: Encoder) {
+        if self.capacity == 0 || !encoder.is_healthy() {
+            return;
+        }
+        self.idle.push_back(encoder);
+        while self.idle.len() > self.capacity || self.idle_memory() > self.budget {
+            let Some(evicted) = self.idle.pop_front() else {
+                break;
+            };
+            debug!(
+                "evicting {:?} encoder of {}x{}",
+                evicted.ctx.f.driver, evicted.ctx.d.width, evicted.ctx.d.height
+            );
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.idle.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.idle.is_empty()
+    }
+
+    // the video memory of the idle encoders, estimated where it wasn't measured
+    pub fn idle_memory(&self) -> i64 {
+        self.idle
+            .iter()
+            .map(|e| {
+                let memory = e.memory_usage();
+                if memory.allocated > 0 {
+                    memory.allocated
+                } else {
+                    memory.estimated
+                }
+            })
+            .sum()
+    }
+
+    // drops the idle encoders, e.g. after a device loss
+    pub fn clear(&mut self) {
+        self.idle.clear();
+    }
+}
+
+// Spreads the encoders of a server over the adapters encoding a format. Each one is
+// created on the adapter with the fewest of the pool's encoders, those handed out and not
+// given back with put, or on the next one when that fails, e.g. when a consumer NVENC runs
+// out of sessions. Ties go to the adapter whose encoder available() reports first.
+pub struct BalancedEncoderPool {
+    d: DynamicContext,
+    adapters: Vec<Adapter>,
+}
+
+struct Adapter {
+    // the first of available() on the luid
+    f: FeatureContext,
+    // see set_device
+    device: Option<*mut c_void>,
+    sessions: usize,
+}
+
+unsafe impl Send for BalancedEncoderPool {}
+
+impl BalancedEncoderPool {
+    // The encoders are created with d, but for d.device, see set_device.
+    pub fn new(format: DataFormat, d: DynamicContext) -> Self {
+        Self::from_features(encode::available_formats(d, &[format]), d)
+    }
+
+    // one adapter per luid of features, of their first one
+    pub fn from_features(features: Vec<FeatureContext>, d: DynamicContext) -> Self {
+        let mut adapters: Vec<Adapter> = vec![];
+        for f in features {
+            if adapters.iter().all(|a| a.f.luid != f.luid) {
+                adapters.push(Adapter {
+                    f,
+                    device: None,
+                    sessions: 0,
+                });
+            }
+        }
+        Self {
+            d: DynamicContext { device: None, ..d },
+            adapters,
+        }
+    }
+
+    // The device the encoders on luid take their textures from. Without one the shims
+    // create a device of their own, whose textures the caller can't reach.
+    pub fn set_device(&mut self, luid: i64, device: *mut c_void) {
+        if let Some(adapter) = self.adapters.iter_mut().find(|a| a.f.luid == luid) {
+            adapter.device = Some(device);
+        }
+    }
+
+    // Err when no adapter could create one
+    pub fn get(&mut self) -> Result<Encoder, ()> {
+        let mut order: Vec<usize> = (0..self.adapters.len()).collect();
+        // stable, ties keep the order of available()
+        order.sort_by_key(|i| self.adapters[*i].sessions);
+        for i in order {
+            let adapter = &mut self.adapters[i];
+            let ctx = EncodeContext {
+                f: adapter.f.clone(),
+                d: DynamicContext {
+                    device: adapter.device,
+                    ..self.d
+                },
+            };
+            match Encoder::new(ctx) {
+                Ok(encoder) => {
+                    adapter.sessions += 1;
+                    debug!(
+                        "{:?} encoder on {}, {} there",
+                        adapter.f.driver, adapter.f.luid, adapter.sessions
+                    );
+                    return Ok(encoder);
+                }
+                Err(()) => warn!(
+                    "no {:?} encoder on {}, {} there",
+                    adapter.f.driver, adapter.f.luid, adapter.sessions
+                ),
+            }
+        }
+        Err(())
+    }
+
+    // Drops an encoder of get, its adapter takes the next one again. Encoders dropped
+    // without it keep counting.
+    pub fn put(&mut self, encoder: Encoder) {
+        let luid = encoder.ctx.f.luid;
+        drop(encoder);
+        if let Some(adapter) = self.adapters.iter_mut().find(|a| a.f.luid == luid) {
+            adapter.sessions = adapter.sessions.saturating_sub(1);
+        }
+    }
+
+    // the encoders of the pool on each adapter, in the order of available()
+    pub fn sessions(&self) -> Vec<(i64, usize)> {
+        self.adapters
+            .iter()
+            .map(|a| (a.f.luid, a.sessions))
+            .collect()
+    }
+}
+
+// the session buffers are sized for the bitrate, a bucket spans a power of two
+// 0 for quality mode, which set_bitrate can't switch
+fn bitrate_bucket(kbs: i32) -> u32 {
+    32 - (kbs.max(0) as u32).leading_zeros()
+}
+
+fn reusable(idle: &EncodeContext, wanted: &EncodeContext) -> bool {
+    let key = |ctx: &EncodeContext| {
+        let mut d = ctx.d;
+        d.kbitrate = bitrate_bucket(d.kbitrate) as _;
+        d.framerate = 0;
+        d
+    };
+    idle.f == wanted.f && key(idle) == key(wanted)
+}
+
+fn resume(encoder: &mut Encoder, ctx: &EncodeContext) -> Result<(), i32> {
+    if encoder.ctx.d.kbitrate != ctx.d.kbitrate {
+        encoder.set_bitrate(ctx.d.kbitrate)?;
+    }
+    if encoder.ctx.d.framerate != ctx.d.framerate {
+        encoder.set_framerate(ctx.d.framerate)?;
+    }
+    encoder.restart()
+}
@@ -0,0 +1,119 @@
+use crate::{
+    common::{DataFormat, FrameFlag},
+    mux::{MuxContext, Muxer},
+    vram::{
+        encode::{best_encoder, EncodeFrame, Encoder},
+        DynamicContext, EncodeContext,
+    },
+};
+use log::{debug, warn};
+use std::{ffi::c_void, path::Path};
+
+// a key frame at least every few seconds keeps the file seekable
+const MAX_KEYFRAME_INTERVAL_SECS: i32 = 2;
+
+pub struct Recorder {
+    encoder: Encoder,
+    muxer: Muxer,
+    finished: bool,
+}
+
+impl Recorder {
+    // picks the encoder with best_encoder, the device in d must belong to its adapter
+    pub fn new<P: AsRef<Path>>(path: P, d: DynamicContext) -> Result<Self, ()> {
+        let Some(f) = best_encoder(d, &[DataFormat::H265, DataFormat::H264]) else {
+            warn!("no vram encoder available for recording");
+            return Err(());
+        };
+        Self::with_context(path, EncodeContext { f, d })
+    }
+
+    pub fn with_context<P: AsRef<Path>>(path: P, mut ctx: EncodeContext) -> Result<Self, ()> {
+        if ctx.d.framerate <= 0 {
+            return Err(());
+        }
+        // keyframes on scene cuts, the gop given becomes their bound
+        let max_gop = ctx.d.framerate.saturating_mul(MAX_KEYFRAME_INTERVAL_SECS);
+        if ctx.d.max_gop <= 0 || ctx.d.max_gop > max_gop {
+            ctx.d.max_gop = if ctx.d.gop > 0 {
+                ctx.d.gop.min(max_gop)
+            } else {
+                max_gop
+            };
+        }
+        debug!("recorder uses {:?}", ctx.f);
+        let muxer = Muxer::new(MuxContext {
+            filename: path.as_ref().to_str().ok_or(())?.to_owned(),
+            width: ctx.d.width as _,
+            height: ctx.d.height as _,
+            is265: ctx.f.data_format == DataFormat::H265,
+            framerate: ctx.d.framerate as _,
+        })?;
+        let encoder = Encoder::new(ctx)?;
+        Ok(Self {
+            encoder,
+            muxer,
+            finished: false,
+        })
+    }
+
+    pub fn data_format(&self) -> DataFormat {
+        self.encoder.ctx.f.data_format
+    }
+
+    // ms is the capture time of tex, it becomes the pts in the file
+    pub fn write(&mut self, tex: *mut c_void, ms: i64) -> Result<(), i32> {
+        if self.finished {
+            return Err(-1);
+        }
+        let frames = self.encoder.encode(tex, ms)?;
+        Self::mux(&mut self.muxer, frames)
+    }
+
+    // for variable framerate captures, duration is how long tex is shown in ms, see
+    // Encoder::encode_with_duration
+    pub fn write_with_duration(
+        &mut self,
+        tex: *mut c_void,
+        ms: i64,
+        duration: i64,
+    ) -> Result<(), i32> {
+        if self.finished {
+            return Err(-1);
+        }
+        let frames = self.encoder.encode_with_duration(tex, ms, duration)?;
+        Self::mux(&mut self.muxer, frames)
+    }
+
+    fn mux(muxer: &mut Muxer, frames: &[EncodeFrame]) -> Result<(), i32> {
+        for frame in frames.iter() {
+            muxer.write_video_with_duration(
+                &frame.data,
+                frame.flags.has(FrameFlag::FRAME_FLAG_KEYFRAME),
+                frame.pts,
+                frame.duration,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), i32> {
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> Result<(), i32> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.muxer.write_tail()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            warn!("recorder failed to write tail: {}", e);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    common::{driver_info, DataFormat, DecodeCaps, Driver, DriverInfo, FrameData, HwcodecErrno},
+    vram::{
+        adapter_path,
+        d3d11::Device,
+        decode::{self, query_decode_caps, Decoder},
+        encode::{self, probe_packets},
+        AdapterPath, DecodeContext, DynamicContext, EncodeContext, FeatureContext,
+    },
+};
+use serde_derive::{Deserialize, Serialize};
+
+// A test of available() that failed, the adapters it would have found are missing. For
+// encoders also the output check of an adapter the native test passed on, see
+// encode::available_with_verification.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProbeFailure {
+    pub driver: Driver,
+    pub data_format: DataFormat,
+    // the encode test, else the decode one
+    pub encode: bool,
+    // the HwcodecErrno or vendor status the test returned
+    pub error: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DecoderReport {
+    pub ctx: DecodeContext,
+    // None when no session could be created to query them
+    pub caps: Option<DecodeCaps>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AdapterReport {
+    pub luid: i64,
+    // None when the adapter was removed since the tests ran
+    pub path: Option<AdapterPath>,
+    // in the order of encode::available, with their caps
+    pub encoders: Vec<FeatureContext>,
+    pub decoders: Vec<DecoderReport>,
+}
+
+// the probe stream of an encoder fed to a decoder of its format
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RoundTrip {
+    pub encoder: FeatureContext,
+    pub decoder: DecodeContext,
+    // None if the packets decoded to frames of the stream's size, else the error of the
+    // encoder or the decoder, HWCODEC_ERR_INVALID_DATA for no such frame
+    pub error: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CapabilityReport {
+    // of hwcodec, e.g. "0.7.1"
+    pub version: String,
+    // in the order their first encoder or decoder was found
+    pub adapters: Vec<AdapterReport>,
+    // the presence checks of the drivers compiled in, the reason a driver has no adapters
+    pub drivers: Vec<DriverInfo>,
+    pub failures: Vec<ProbeFailure>,
+    // empty unless cross checked, see capability_report_with_round_trips
+    #[serde(default)]
+    pub round_trips: Vec<RoundTrip>,
+}
+
+impl CapabilityReport {
+    pub fn serialize(&self) -> Result<String, ()> {
+        serde_json::to_string_pretty(self).map_err(|_| ())
+    }
+
+    pub fn deserialize(s: &str) -> Result<Self, ()> {
+        serde_json::from_str(s).map_err(|_| ())
+    }
+}
+
+// What this machine encodes and decodes, for diagnostics and bug reports. Runs the tests of
+// encode::available(d) and decode::available(), which reuse the driver presence checks of
+// the process, and creates a decoder of every decode context for its caps.
+pub fn capability_report(d: DynamicContext) -> CapabilityReport {
+    let mut failures = vec![];
+    let encoders = encode::available_with_failures(d, &mut failures);
+    let decoders = decode::available_with_failures(&mut failures);
+    let mut adapters = vec![];
+    for f in encoders {
+        adapter(&mut adapters, f.luid).encoders.push(f);
+    }
+    for ctx in decoders {
+        let caps = query_decode_caps(&ctx).ok();
+        adapter(&mut adapters, ctx.luid)
+            .decoders
+            .push(DecoderReport { ctx, caps });
+    }
+    let drivers = [Driver::NV, Driver::AMF, Driver::MFX]
+        .into_iter()
+        .filter_map(driver_info)
+        .collect();
+    CapabilityReport {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        adapters,
+        drivers,
+        failures,
+        round_trips: vec![],
+    }
+}
+
+// capability_report with the stream of every encoder's probe fed to every decoder of its
+// format, on any adapter and of any driver, for the pairs a sender and a receiver can
+// negotiate. Costs an encoder session per encoder and a decoder session per pair on top,
+// available() doesn't do it.
+pub fn capability_report_with_round_trips(d: DynamicContext) -> CapabilityReport {
+    let mut report = capability_report(d);
+    let encoders: Vec<FeatureContext> = report
+        .adapters
+        .iter()
+        .flat_map(|a| a.encoders.iter().cloned())
+        .collect();
+    let decoders: Vec<DecodeContext> = report
+        .adapters
+        .iter()
+        .flat_map(|a| a.decoders.iter().map(|r| r.ctx.clone()))
+        .collect();
+    for f in encoders {
+        let packets = probe_packets(&EncodeContext { f: f.clone(), d }, None);
+        for ctx in decoders.iter().filter(|c| c.data_format == f.data_format) {
+            let error = match &packets {
+                Ok(packets) => round_trip(ctx, packets, &d).err(),
+                Err(e) => Some(*e),
+            };
+            report.round_trips.push(RoundTrip {
+                encoder: f.clone(),
+                decoder: ctx.clone(),
+                error,
+            });
+        }
+    }
+    report
+}
+
+// decodes packets on a device of the decoder's adapter
+fn round_trip(ctx: &DecodeContext, packets: &[FrameData], d: &DynamicContext) -> Result<(), i32> {
+    let common = |_| HwcodecErrno::HWCODEC_ERR_COMMON as i32;
+    let device = Device::new(ctx.luid).map_err(common)?;
+    let mut ctx = ctx.clone();
+    ctx.device = Some(device.as_ptr());
+    let mut decoder = Decoder::new(ctx).map_err(common)?;
+    let mut decoded = false;
+    for packet in packets {
+        for frame in decoder.decode(packet)?.iter() {
+            decoded |= frame.width >= d.width && frame.height >= d.height;
+        }
+    }
+    if !decoded {
+        return Err(HwcodecErrno::HWCODEC_ERR_INVALID_DATA as _);
+    }
+    Ok(())
+}
+
+fn adapter(adapters: &mut Vec<AdapterReport>, luid: i64) -> &mut AdapterReport {
+    if let Some(index) = adapters.iter().position(|a| a.luid == luid) {
+        return &mut adapters[index];
+    }
+    adapters.push(AdapterReport {
+        luid,
+        path: adapter_path(luid),
+        encoders: vec![],
+        decoders: vec![],
+    });
+    adapters.last_mut().unwrap()
+}
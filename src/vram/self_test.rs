@@ -0,0 +1,250 @@
+use crate::{
+    pattern::{bgra_pattern, luma, psnr as luma_psnr, ssim as luma_ssim},
+    vram::{
+        d3d11::{read_bgra, Device, Texture},
+        decode::{self, Decoder},
+        encode::{self, Encoder},
+        DecodeContext, DynamicContext, EncodeContext, FeatureContext, OutputOrder,
+    },
+};
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::time::Instant;
+
+const FRAMES: usize = 10;
+// black or garbled output stays far below this, real encodes are well above
+const MIN_PSNR: f64 = 20.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct Latency {
+    pub avg_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Latency {
+    fn from_samples(samples: &[f64]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        Self {
+            avg_ms: samples.iter().sum::<f64>() / samples.len() as f64,
+            max_ms: samples.iter().cloned().fold(0.0, f64::max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SelfTestReport {
+    pub encoder: FeatureContext,
+    pub decoder: DecodeContext,
+    pub width: i32,
+    pub height: i32,
+    pub kbitrate: i32,
+    pub frames: usize,
+    pub encoded: usize,
+    pub decoded: usize,
+    pub success: bool,
+    pub error: Option<String>,
+    // luma, averaged over the decoded frames
+    pub psnr: f64,
+    pub ssim: f64,
+    pub encode_latency: Latency,
+    pub decode_latency: Latency,
+}
+
+impl SelfTestReport {
+    pub fn serialize(&self) -> Result<String, ()> {
+        serde_json::to_string_pretty(self).map_err(|_| ())
+    }
+}
+
+// Encodes synthetic frames with f_enc and decodes them with f_dec through d3d11 textures.
+// Devices are created for both luids unless d.device or f_dec.device is set.
+pub fn self_test(
+    f_enc: &FeatureContext,
+    f_dec: &DecodeContext,
+    d: DynamicContext,
+) -> SelfTestReport {
+    let mut report = SelfTestReport {
+        encoder: f_enc.clone(),
+        decoder: f_dec.clone(),
+        width: d.width,
+        height: d.height,
+        kbitrate: d.kbitrate,
+        frames: FRAMES,
+        encoded: 0,
+        decoded: 0,
+        success: false,
+        error: None,
+        psnr: 0.0,
+        ssim: 0.0,
+        encode_latency: Latency::default(),
+        decode_latency: Latency::default(),
+    };
+    match run(f_enc, f_dec, d, &mut report) {
+        Ok(()) => {
+            report.success = true;
+        }
+        Err(e) => {
+            warn!(
+                "self test {:?} -> {:?} failed: {}",
+                f_enc.driver, f_dec.driver, e
+            );
+            report.error = Some(e);
+        }
+    }
+    report
+}
+
+// Runs self_test for every encoder of encode::available(d), one report each in that
+// order. Each is decoded by a decoder of its format, preferably of the same driver and
+// adapter. Encoders without one get a failed report without encoding anything.
+pub fn self_test_all(d: DynamicContext) -> Vec<SelfTestReport> {
+    let decoders = decode::available();
+    encode::available(d)
+        .iter()
+        .map(|f_enc| {
+            let same_format = |f: &&DecodeContext| f.data_format == f_enc.data_format;
+            let f_dec = decoders
+                .iter()
+                .filter(same_format)
+                .find(|f| f.driver == f_enc.driver && f.luid == f_enc.luid)
+                .or_else(|| {
+                    decoders
+                        .iter()
+                        .filter(same_format)
+                        .find(|f| f.luid == f_enc.luid)
+                })
+                .or_else(|| decoders.iter().find(same_format));
+            match f_dec {
+                Some(f_dec) => self_test(f_enc, f_dec, d),
+                None => no_decoder(f_enc, d),
+            }
+        })
+        .collect()
+}
+
+fn no_decoder(f_enc: &FeatureContext, d: DynamicContext) -> SelfTestReport {
+    let error = format!("no decoder for {:?}", f_enc.data_format);
+    warn!("self test {:?} failed: {}", f_enc.driver, error);
+    SelfTestReport {
+        encoder: f_enc.clone(),
+        decoder: DecodeContext {
+            device: None,
+            driver: f_enc.driver.clone(),
+            vendor: f_enc.vendor.clone(),
+            luid: f_enc.luid,
+            data_format: f_enc.data_format,
+            output_order: OutputOrder::default(),
+            conceal_errors: false,
+        },
+        width: d.width,
+        height: d.height,
+        kbitrate: d.kbitrate,
+        frames: 0,
+        encoded: 0,
+        decoded: 0,
+        success: false,
+        error: Some(error),
+        psnr: 0.0,
+        ssim: 0.0,
+        encode_latency: Latency::default(),
+        decode_latency: Latency::default(),
+    }
+}
+
+fn run(
+    f_enc: &FeatureContext,
+    f_dec: &DecodeContext,
+    mut d: DynamicContext,
+    report: &mut SelfTestReport,
+) -> Result<(), String> {
+    if d.width <= 0 || d.height <= 0 {
+        return Err(format!("invalid size {}x{}", d.width, d.height));
+    }
+    let _enc_device;
+    if d.device.is_none() {
+        _enc_device = Device::new(f_enc.luid)
+            .map_err(|_| format!("failed to create device for luid {}", f_enc.luid))?;
+        d.device = Some(_enc_device.as_ptr());
+    }
+    let mut dec_ctx = f_dec.clone();
+    let _dec_device;
+    if dec_ctx.device.is_none() {
+        _dec_device = Device::new(f_dec.luid)
+            .map_err(|_| format!("failed to create device for luid {}", f_dec.luid))?;
+        dec_ctx.device = Some(_dec_device.as_ptr());
+    }
+    let mut encoder = Encoder::new(EncodeContext {
+        f: f_enc.clone(),
+        d,
+    })
+    .map_err(|_| "failed to create encoder".to_owned())?;
+    let mut decoder = Decoder::new(dec_ctx).map_err(|_| "failed to create decoder".to_owned())?;
+
+    let (width, height) = (d.width as usize, d.height as usize);
+    let mut sources = Vec::with_capacity(FRAMES);
+    let mut encode_ms = vec![];
+    let mut decode_ms = vec![];
+    let mut psnr = vec![];
+    let mut ssim = vec![];
+    for i in 0..FRAMES {
+        let source = bgra_pattern(width, height, i);
+        let texture = Texture::from_bgra(d.device.unwrap(), d.width, d.height, &source)
+            .map_err(|_| "failed to create source texture".to_owned())?;
+        sources.push(source);
+
+        let start = Instant::now();
+        let packets: Vec<_> = encoder
+            .encode(texture.as_ptr(), i as _)
+            .map_err(|e| format!("encode frame {} failed: {}", i, e))?
+            .drain(..)
+            .map(|f| f.data)
+            .collect();
+        encode_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+        report.encoded += packets.len();
+
+        for packet in packets {
+            let start = Instant::now();
+            let frames = decoder
+                .decode(&packet)
+                .map_err(|e| format!("decode failed: {}", e))?;
+            decode_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+            for frame in frames.iter() {
+                if frame.width < d.width || frame.height < d.height {
+                    return Err(format!(
+                        "decoded {}x{}, expected {}x{}",
+                        frame.width, frame.height, d.width, d.height
+                    ));
+                }
+                let decoded = read_bgra(frame.texture, d.width, d.height)
+                    .map_err(|_| "failed to read decoded texture".to_owned())?;
+                // no b frames, decoded frames come out in source order
+                let source = sources
+                    .get(report.decoded)
+                    .ok_or_else(|| "more frames decoded than encoded".to_owned())?;
+                let (src_y, dec_y) = (luma(source, width, height), luma(&decoded, width, height));
+                psnr.push(luma_psnr(&src_y, &dec_y));
+                ssim.push(luma_ssim(&src_y, &dec_y, width, height));
+                report.decoded += 1;
+            }
+        }
+    }
+    report.encode_latency = Latency::from_samples(&encode_ms);
+    report.decode_latency = Latency::from_samples(&decode_ms);
+    if !psnr.is_empty() {
+        report.psnr = psnr.iter().sum::<f64>() / psnr.len() as f64;
+        report.ssim = ssim.iter().sum::<f64>() / ssim.len() as f64;
+    }
+    debug!(
+        "self test decoded {}/{}, psnr: {:.2}, ssim: {:.4}",
+        report.decoded, FRAMES, report.psnr, report.ssim
+    );
+    if report.decoded == 0 {
+        return Err("no frame decoded".to_owned());
+    }
+    if report.psnr < MIN_PSNR {
+        return Err(format!("psnr {:.2} below {}", report.psnr, MIN_PSNR));
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+#[cfg(feature = "vram-ffmpeg")]
+use crate::vram::ffmpeg;
+#[cfg(feature = "mfx")]
+use crate::vram::mfx;
+#[cfg(feature = "nv")]
+use crate::vram::nv;
+use crate::{
+    common::{Driver, Driver::*, HwcodecErrno},
+    ffmpeg::init_av_log,
+    vram::{
+        encode::texture_size,
+        inner::{
+            hwcodec_new_d3d11_texture_like, hwcodec_pad_d3d11_texture, CallbackFrames, D3D11Ptr,
+            JpegCalls, SessionGuard,
+        },
+        EncodeContext,
+    },
+};
+use log::{debug, error};
+use std::{ffi::c_void, os::raw::c_int, slice::from_raw_parts};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotContext {
+    // the device of the textures, None creates one on the adapter of luid
+    pub device: Option<*mut c_void>,
+    pub luid: i64,
+    // picks the hardware encoder, NV or MFX, others use the fallback
+    pub vendor: Driver,
+    // 1 - 100, as libjpeg's
+    pub quality: i32,
+}
+
+impl SnapshotContext {
+    // on the device and adapter of the encoder of ctx, e.g. one of Encoder::ctx
+    pub fn from_encode_context(ctx: &EncodeContext, quality: i32) -> Self {
+        Self {
+            device: ctx.d.device,
+            luid: ctx.f.luid,
+            vendor: ctx.f.vendor.clone(),
+            quality,
+        }
+    }
+}
+
+unsafe impl Send for SnapshotContext {}
+unsafe impl Sync for SnapshotContext {}
+
+// JPEG images of the bgra textures an Encoder takes, for thumbnails and the like rather
+// than streams. MFX encodes them on the adapter's JPEG engine and NV with nvJPEG, which
+// loads the nvjpeg dll of a CUDA runtime. The ffmpeg mjpeg encoder on the cpu is the
+// fallback for other adapters and where those fail to open. Images are 4:2:0 JFIF, bt.601
+// full range, any size.
+pub struct Snapshot {
+    pub ctx: SnapshotContext,
+    driver: Driver,
+    calls: JpegCalls,
+    codec: *mut c_void,
+    images: CallbackFrames<Vec<u8>>,
+    // NV12 has no odd sizes, the session's input is padded to the next even one
+    edge: Option<D3D11Ptr>,
+    _session: SessionGuard,
+}
+
+unsafe impl Send for Snapshot {}
+
+impl Snapshot {
+    pub fn new(ctx: SnapshotContext) -> Result<Self, ()> {
+        init_av_log();
+        if !(1..=100).contains(&ctx.quality) {
+            error!("jpeg quality {} is not 1 - 100", ctx.quality);
+            return Err(());
+        }
+        let device = ctx.device.unwrap_or(std::ptr::null_mut());
+        let session = SessionGuard::new();
+        for (driver, calls) in jpeg_calls(&ctx.vendor) {
+            let codec = unsafe { (calls.new)(device, ctx.luid, ctx.quality) };
+            if codec.is_null() {
+                debug!("{:?} jpeg encoder failed to open", driver);
+                continue;
+            }
+            debug!("snapshots of luid {} encoded by {:?}", ctx.luid, driver);
+            return Ok(Self {
+                ctx,
+                driver,
+                calls,
+                codec,
+                images: CallbackFrames::new(),
+                edge: None,
+                _session: session,
+            });
+        }
+        error!("no jpeg encoder for luid {}", ctx.luid);
+        Err(())
+    }
+
+    // the driver encoding the images, FFMPEG is the software fallback
+    pub fn driver(&self) -> Driver {
+        self.driver.clone()
+    }
+
+    // tex is a bgra texture of ctx.device, read when the call returns
+    pub fn encode(&mut self, tex: *mut c_void) -> Result<Vec<u8>, i32> {
+        let Some((width, height)) = texture_size(tex) else {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        };
+        let input = self.pad_input(tex, width, height)?;
+        self.images.get_mut().clear();
+        let ret = unsafe {
+            (self.calls.encode)(
+                self.codec,
+                input,
+                width,
+                height,
+                Some(Self::callback),
+                self.images.as_context(),
+            )
+        };
+        if ret != 0 {
+            error!("{:?} jpeg encode failed: {}", self.driver, ret);
+            return Err(ret);
+        }
+        self.images
+            .get_mut()
+            .pop()
+            .ok_or(HwcodecErrno::HWCODEC_ERR_COMMON as _)
+    }
+
+    fn pad_input(&mut self, tex: *mut c_void, width: i32, height: i32) -> Result<*mut c_void, i32> {
+        let (even_width, even_height) = (width + width % 2, height + height % 2);
+        if (width, height) == (even_width, even_height) {
+            return Ok(tex);
+        }
+        let size = self.edge.as_ref().and_then(|e| texture_size(e.0));
+        if size != Some((even_width, even_height)) {
+            self.edge = None;
+            let texture = unsafe { hwcodec_new_d3d11_texture_like(tex, even_width, even_height) };
+            if texture.is_null() {
+                error!("failed to create the padded snapshot texture");
+                return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+            }
+            self.edge = Some(D3D11Ptr(texture));
+        }
+        let padded = self.edge.as_ref().map_or(tex, |e| e.0);
+        match unsafe { hwcodec_pad_d3d11_texture(tex, padded, width, height) } {
+            0 => Ok(padded),
+            err => Err(err),
+        }
+    }
+
+    extern "C" fn callback(
+        data: *const u8,
+        size: c_int,
+        _key: i32,
+        obj: *const c_void,
+        _pts: i64,
+        _user_data: u64,
+    ) {
+        unsafe {
+            let images = &mut *(obj as *mut Vec<Vec<u8>>);
+            images.push(from_raw_parts(data, size as usize).to_vec());
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        unsafe { (self.calls.destroy)(self.codec) };
+    }
+}
+
+// the vendor's hardware encoder first, then the fallback
+fn jpeg_calls(vendor: &Driver) -> Vec<(Driver, JpegCalls)> {
+    let mut calls = vec![];
+    match vendor {
+        #[cfg(feature = "nv")]
+        NV => calls.push((NV, nv::jpeg_calls())),
+        #[cfg(feature = "mfx")]
+        MFX => calls.push((MFX, mfx::jpeg_calls())),
+        _ => {}
+    }
+    #[cfg(feature = "vram-ffmpeg")]
+    calls.push((FFMPEG, ffmpeg::jpeg_calls()));
+    calls
+}
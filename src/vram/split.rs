@@ -0,0 +1,244 @@
+use crate::{
+    common::HwcodecErrno,
+    vram::{
+        encode::{EncodeFrame, Encoder},
+        inner::{hwcodec_copy_d3d11_texture_rect, hwcodec_new_d3d11_texture_like, D3D11Ptr},
+        EncodeContext,
+    },
+};
+use log::{debug, error};
+use std::ffi::c_void;
+
+// the width of each tile but the last, macroblocks and chroma pairs don't straddle tiles
+const TILE_ALIGN: i32 = 16;
+
+// a part of the input in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+// a frame of the session of the tile at index tile, a stream of its own for the receiver
+// to decode and draw at rect
+pub struct TileFrame {
+    pub tile: usize,
+    pub rect: TileRect,
+    pub frame: EncodeFrame,
+}
+
+// Encodes frames wider than ctx.f.caps.maxWidth, e.g. of three monitors, with sessions of
+// side by side tiles on the same adapter. A frame a single session takes is encoded whole,
+// without a copy. Each tile is copied out of the input into a texture of its own and its
+// session gets the share of d.kbitrate of its area. Keyframe requests and bitrate changes
+// go to all tiles, which keep the same GOP.
+pub struct SplitEncoder {
+    tiles: Vec<Tile>,
+    pub ctx: EncodeContext,
+    // the frames of the latest encode call
+    output: Vec<TileFrame>,
+}
+
+struct Tile {
+    rect: TileRect,
+    encoder: Encoder,
+    // of the split input, created by the first encode call
+    texture: Option<D3D11Ptr>,
+}
+
+unsafe impl Send for SplitEncoder {}
+
+impl SplitEncoder {
+    pub fn new(ctx: EncodeContext) -> Result<Self, ()> {
+        let rects = tile_rects(&ctx)?;
+        if rects.len() > 1 {
+            debug!(
+                "splitting {}x{} into {} tiles for {:?} of at most {} wide",
+                ctx.d.width,
+                ctx.d.height,
+                rects.len(),
+                ctx.f.driver,
+                ctx.f.caps.maxWidth
+            );
+        }
+        let tiles = rects
+            .into_iter()
+            .map(|rect| {
+                let mut tile_ctx = ctx.clone();
+                tile_ctx.d.width = rect.width;
+                tile_ctx.d.kbitrate = tile_bitrate(ctx.d.kbitrate, rect, ctx.d.width);
+                Ok(Tile {
+                    rect,
+                    encoder: Encoder::new(tile_ctx)?,
+                    texture: None,
+                })
+            })
+            .collect::<Result<Vec<_>, ()>>()?;
+        Ok(Self {
+            tiles,
+            ctx,
+            output: vec![],
+        })
+    }
+
+    // The frames of all tiles in tile order, valid until the next call. When a tile fails
+    // the frames of the others are dropped too and every tile's next frame is an IDR, the
+    // receiver would miss references of theirs otherwise.
+    pub fn encode(&mut self, tex: *mut c_void, ms: i64) -> Result<&mut Vec<TileFrame>, i32> {
+        self.output.clear();
+        if let Err(e) = self.encode_tiles(tex, ms) {
+            self.output.clear();
+            for tile in self.tiles.iter_mut() {
+                if let Err(e) = tile.encoder.request_keyframe() {
+                    debug!(
+                        "tile at {} failed to request a keyframe: {}",
+                        tile.rect.x, e
+                    );
+                }
+            }
+            return Err(e);
+        }
+        Ok(&mut self.output)
+    }
+
+    fn encode_tiles(&mut self, tex: *mut c_void, ms: i64) -> Result<(), i32> {
+        let split = self.tiles.len() > 1;
+        for (index, tile) in self.tiles.iter_mut().enumerate() {
+            let input = if split { tile.copy(tex)? } else { tex };
+            let rect = tile.rect;
+            let frames = tile.encoder.encode(input, ms)?;
+            self.output.extend(frames.drain(..).map(|frame| TileFrame {
+                tile: index,
+                rect,
+                frame,
+            }));
+        }
+        Ok(())
+    }
+
+    pub fn tiles(&self) -> Vec<TileRect> {
+        self.tiles.iter().map(|t| t.rect).collect()
+    }
+
+    // more than one session
+    pub fn is_split(&self) -> bool {
+        self.tiles.len() > 1
+    }
+
+    // the next frame of every tile is an IDR
+    pub fn request_keyframe(&mut self) -> Result<(), i32> {
+        for tile in self.tiles.iter_mut() {
+            tile.encoder.request_keyframe()?;
+        }
+        Ok(())
+    }
+
+    // divided like d.kbitrate, see Encoder::set_bitrate
+    pub fn set_bitrate(&mut self, kbs: i32) -> Result<(), i32> {
+        if self.ctx.d.kbitrate <= 0 || kbs <= 0 {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        let width = self.ctx.d.width;
+        for tile in self.tiles.iter_mut() {
+            let share = tile_bitrate(kbs, tile.rect, width);
+            tile.encoder.set_bitrate(share)?;
+        }
+        self.ctx.d.kbitrate = kbs;
+        Ok(())
+    }
+
+    pub fn set_framerate(&mut self, framerate: i32) -> Result<(), i32> {
+        for tile in self.tiles.iter_mut() {
+            tile.encoder.set_framerate(framerate)?;
+        }
+        self.ctx.d.framerate = framerate;
+        Ok(())
+    }
+}
+
+impl Tile {
+    fn copy(&mut self, tex: *mut c_void) -> Result<*mut c_void, i32> {
+        let TileRect {
+            x,
+            y,
+            width,
+            height,
+        } = self.rect;
+        let texture = match &self.texture {
+            Some(texture) => texture.0,
+            None => {
+                let texture = unsafe { hwcodec_new_d3d11_texture_like(tex, width, height) };
+                if texture.is_null() {
+                    error!("failed to create a {}x{} tile texture", width, height);
+                    return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+                }
+                self.texture = Some(D3D11Ptr(texture));
+                texture
+            }
+        };
+        match unsafe { hwcodec_copy_d3d11_texture_rect(tex, texture, x, y, width, height) } {
+            0 => Ok(texture),
+            err => Err(err),
+        }
+    }
+}
+
+// The tiles SplitEncoder encodes ctx's frames as, left to right, a single one if the
+// session takes the whole width. Err for a height beyond ctx.f.caps.maxHeight, tiles
+// are only side by side.
+pub fn tile_rects(ctx: &EncodeContext) -> Result<Vec<TileRect>, ()> {
+    let (width, height) = (ctx.d.width, ctx.d.height);
+    if width <= 0 || height <= 0 {
+        return Err(());
+    }
+    let caps = &ctx.f.caps;
+    if caps.maxHeight > 0 && height > caps.maxHeight {
+        error!(
+            "{:?} sessions are at most {} high, not {}",
+            ctx.f.driver, caps.maxHeight, height
+        );
+        return Err(());
+    }
+    if caps.maxWidth <= 0 || width <= caps.maxWidth {
+        return Ok(vec![TileRect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }]);
+    }
+    let max = caps.maxWidth / TILE_ALIGN * TILE_ALIGN;
+    if max <= 0 {
+        return Err(());
+    }
+    let mut count = (width + max - 1) / max;
+    let tile = loop {
+        let tile = ((width + count - 1) / count + TILE_ALIGN - 1) / TILE_ALIGN * TILE_ALIGN;
+        if tile <= max {
+            break tile;
+        }
+        count += 1;
+    };
+    let last = width - tile * (count - 1);
+    if last <= 0 {
+        return Err(());
+    }
+    Ok((0..count)
+        .map(|i| TileRect {
+            x: i * tile,
+            y: 0,
+            width: if i == count - 1 { last } else { tile },
+            height,
+        })
+        .collect())
+}
+
+// the share of kbitrate of a tile's area, 0 stays quality mode
+fn tile_bitrate(kbitrate: i32, rect: TileRect, width: i32) -> i32 {
+    if kbitrate <= 0 {
+        return kbitrate;
+    }
+    ((kbitrate as i64 * rect.width as i64 / width as i64) as i32).max(1)
+}
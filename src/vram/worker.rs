@@ -0,0 +1,241 @@
+use crate::{
+    common::{FrameData, HwcodecErrno},
+    vram::{
+        decode::{DecodeFrame, Decoder},
+        encode::{EncodeFrame, Encoder},
+        DecodeContext, EncodeContext,
+    },
+};
+use log::{debug, error};
+use std::{
+    ffi::c_void,
+    future::Future,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedSender},
+    oneshot, OwnedSemaphorePermit, Semaphore,
+};
+
+const WORKER_GONE: i32 = HwcodecErrno::HWCODEC_ERR_COMMON as i32;
+
+struct SendPtr(*mut c_void);
+
+unsafe impl Send for SendPtr {}
+
+enum EncodeCommand {
+    Encode {
+        texture: SendPtr,
+        ms: i64,
+        user_data: u64,
+        done: oneshot::Sender<Result<Vec<EncodeFrame>, i32>>,
+        // set by the dropped future, held by the worker while it reads the texture
+        cancelled: Arc<Mutex<bool>>,
+        // released once the frame is encoded
+        _permit: OwnedSemaphorePermit,
+    },
+    SetBitrate(i32, oneshot::Sender<Result<(), i32>>),
+    RequestKeyframe(oneshot::Sender<Result<(), i32>>),
+}
+
+// Runs an Encoder on its own thread. Commands are executed in the order they are issued,
+// encode waits while max_in_flight frames are queued or encoding.
+pub struct AsyncEncoder {
+    tx: Option<UnboundedSender<EncodeCommand>>,
+    in_flight: Arc<Semaphore>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl AsyncEncoder {
+    pub fn new(ctx: EncodeContext, max_in_flight: usize) -> Result<Self, ()> {
+        Self::from_encoder(Encoder::new(ctx)?, max_in_flight)
+    }
+
+    // an encoder set up before it is moved to the worker, e.g. with Encoder::on_error
+    pub fn from_encoder(mut encoder: Encoder, max_in_flight: usize) -> Result<Self, ()> {
+        let (tx, mut rx) = unbounded_channel();
+        let worker = std::thread::Builder::new()
+            .name("hwcodec-encoder".to_owned())
+            .spawn(move || {
+                while let Some(command) = rx.blocking_recv() {
+                    match command {
+                        EncodeCommand::Encode {
+                            texture,
+                            ms,
+                            user_data,
+                            done,
+                            cancelled,
+                            ..
+                        } => {
+                            let cancelled = cancelled.lock().unwrap_or_else(|e| e.into_inner());
+                            if *cancelled {
+                                // the texture may be gone already
+                                debug!("skip the encode of a dropped future, ms: {}", ms);
+                                continue;
+                            }
+                            let result = encoder
+                                .encode_with_user_data(texture.0, ms, user_data)
+                                .map(|frames| frames.drain(..).collect());
+                            drop(cancelled);
+                            let _ = done.send(result);
+                        }
+                        EncodeCommand::SetBitrate(kbs, done) => {
+                            let _ = done.send(encoder.set_bitrate(kbs));
+                        }
+                        EncodeCommand::RequestKeyframe(done) => {
+                            let _ = done.send(encoder.request_keyframe());
+                        }
+                    }
+                }
+                debug!("encoder worker exit");
+            })
+            .map_err(|e| error!("failed to spawn encoder worker: {}", e))?;
+        Ok(Self {
+            tx: Some(tx),
+            in_flight: Arc::new(Semaphore::new(max_in_flight.max(1))),
+            worker: Some(worker),
+        })
+    }
+
+    // Texture must stay valid until the returned future completes or is dropped. A frame
+    // whose future is dropped before the worker gets to it isn't encoded, dropping the
+    // future while the frame is encoding blocks until the encoder is done with the texture.
+    pub fn encode(
+        &self,
+        texture: *mut c_void,
+        ms: i64,
+    ) -> impl Future<Output = Result<Vec<EncodeFrame>, i32>> + Send + '_ {
+        self.encode_with_user_data(texture, ms, 0)
+    }
+
+    // see Encoder::encode_with_user_data
+    pub fn encode_with_user_data(
+        &self,
+        texture: *mut c_void,
+        ms: i64,
+        user_data: u64,
+    ) -> impl Future<Output = Result<Vec<EncodeFrame>, i32>> + Send + '_ {
+        let texture = SendPtr(texture);
+        async move {
+            let permit = self
+                .in_flight
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|_| WORKER_GONE)?;
+            let (done, result) = oneshot::channel();
+            let cancel = CancelOnDrop(Arc::new(Mutex::new(false)));
+            self.send(EncodeCommand::Encode {
+                texture,
+                ms,
+                user_data,
+                done,
+                cancelled: cancel.0.clone(),
+                _permit: permit,
+            })?;
+            // once the result is in, the worker is past the check and the cancel is a no-op
+            result.await.map_err(|_| WORKER_GONE)?
+        }
+    }
+
+    pub async fn set_bitrate(&self, kbs: i32) -> Result<(), i32> {
+        let (done, result) = oneshot::channel();
+        self.send(EncodeCommand::SetBitrate(kbs, done))?;
+        result.await.map_err(|_| WORKER_GONE)?
+    }
+
+    pub async fn request_keyframe(&self) -> Result<(), i32> {
+        let (done, result) = oneshot::channel();
+        self.send(EncodeCommand::RequestKeyframe(done))?;
+        result.await.map_err(|_| WORKER_GONE)?
+    }
+
+    fn send(&self, command: EncodeCommand) -> Result<(), i32> {
+        self.tx
+            .as_ref()
+            .ok_or(WORKER_GONE)?
+            .send(command)
+            .map_err(|_| WORKER_GONE)
+    }
+}
+
+// Cancels the queued encode of a future dropped before its result.
+struct CancelOnDrop(Arc<Mutex<bool>>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = true;
+    }
+}
+
+impl Drop for AsyncEncoder {
+    // queued frames of live futures are still encoded, they complete before the join
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("encoder worker panicked");
+            }
+        }
+    }
+}
+
+struct DecodeCommand {
+    packet: FrameData,
+    done: oneshot::Sender<Result<Vec<DecodeFrame>, i32>>,
+}
+
+// Runs a Decoder on its own thread. The decoded textures belong to the decoder and are
+// reused by the next decode, so only one packet is decoded at a time.
+pub struct AsyncDecoder {
+    tx: Option<UnboundedSender<DecodeCommand>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl AsyncDecoder {
+    pub fn new(ctx: DecodeContext) -> Result<Self, ()> {
+        let mut decoder = Decoder::new(ctx)?;
+        let (tx, mut rx) = unbounded_channel::<DecodeCommand>();
+        let worker = std::thread::Builder::new()
+            .name("hwcodec-decoder".to_owned())
+            .spawn(move || {
+                while let Some(command) = rx.blocking_recv() {
+                    let result = decoder
+                        .decode(&command.packet)
+                        .map(|frames| frames.drain(..).collect());
+                    let _ = command.done.send(result);
+                }
+                debug!("decoder worker exit");
+            })
+            .map_err(|e| error!("failed to spawn decoder worker: {}", e))?;
+        Ok(Self {
+            tx: Some(tx),
+            worker: Some(worker),
+        })
+    }
+
+    // The textures are valid until the next decode or drop. Takes the data of an
+    // EncodeFrame without a copy.
+    pub async fn decode(&mut self, packet: impl Into<FrameData>) -> Result<Vec<DecodeFrame>, i32> {
+        let packet = packet.into();
+        let (done, result) = oneshot::channel();
+        self.tx
+            .as_ref()
+            .ok_or(WORKER_GONE)?
+            .send(DecodeCommand { packet, done })
+            .map_err(|_| WORKER_GONE)?;
+        result.await.map_err(|_| WORKER_GONE)?
+    }
+}
+
+impl Drop for AsyncDecoder {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("decoder worker panicked");
+            }
+        }
+    }
+}
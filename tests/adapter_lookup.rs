@@ -0,0 +1,15 @@
+use hwcodec::common::{adapter_for_luid, luid_of_device, same_adapter};
+
+#[test]
+fn unknown_adapters() {
+    assert_eq!(adapter_for_luid(0), None);
+    assert_eq!(luid_of_device(std::ptr::null_mut()), None);
+}
+
+#[test]
+fn same_luid_same_adapter() {
+    assert!(same_adapter(0x1_0000_5a3f, 0x1_0000_5a3f));
+    assert!(!same_adapter(0x1_0000_5a3f, 0x1_0000_5a40));
+    // contexts not tested yet aren't on any adapter
+    assert!(!same_adapter(0, 0));
+}
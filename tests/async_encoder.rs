@@ -0,0 +1,76 @@
+#![cfg(all(windows, feature = "tokio"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::vram::{
+    backend::EncodeBackend,
+    encode::{EncodeFrame, Encoder},
+    worker::AsyncEncoder,
+};
+use std::{
+    ffi::c_void,
+    ptr::null_mut,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+};
+
+// encodes a frame per release, records the pts of the frames it encoded
+struct Gated {
+    release: Receiver<()>,
+    encoded: Arc<Mutex<Vec<i64>>>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Gated {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.release.recv().map_err(|_| -1)?;
+        self.encoded.lock().unwrap().push(ms);
+        self.frames.clear();
+        self.frames.push(EncodeFrame::new(vec![0; 16], ms, true));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn dropped_future_not_encoded() {
+    let ctx = encode_context("async-encoder-test");
+    let (release, gate) = channel();
+    let encoded = Arc::new(Mutex::new(vec![]));
+    let backend = Gated {
+        release: gate,
+        encoded: encoded.clone(),
+        frames: vec![],
+    };
+    let encoder = Encoder::from_backend(Box::new(backend), ctx);
+    let encoder = AsyncEncoder::from_encoder(encoder, 2).unwrap();
+    // queued, the worker waits in the backend until the release
+    let first = encoder.encode(null_mut(), 0);
+    tokio::pin!(first);
+    tokio::select! {
+        biased;
+        _ = &mut first => unreachable!(),
+        _ = async {} => {}
+    }
+    // queued behind the first, then dropped
+    tokio::select! {
+        biased;
+        _ = encoder.encode(null_mut(), 1) => unreachable!(),
+        _ = async {} => {}
+    }
+    release.send(()).unwrap();
+    assert_eq!(first.await.unwrap().len(), 1);
+    release.send(()).unwrap();
+    assert_eq!(encoder.encode(null_mut(), 2).await.unwrap().len(), 1);
+    assert_eq!(encoded.lock().unwrap().as_slice(), &[0, 2]);
+}
@@ -0,0 +1,125 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    common::{DataFormat, Driver},
+    vram::{
+        backend::{self, EncodeBackend},
+        encode::EncodeFrame,
+        pool::BalancedEncoderPool,
+        DynamicContext, FeatureContext,
+    },
+};
+use std::{
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+const NAME: &str = "balanced-pool-test";
+
+// counts itself in on_2 while alive on adapter 2
+struct Session {
+    frames: Vec<EncodeFrame>,
+    on_2: Option<Arc<AtomicUsize>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(on_2) = &self.on_2 {
+            on_2.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl EncodeBackend for Session {
+    fn encode(&mut self, _tex: *mut c_void, _ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+}
+
+// adapters 1 and 2 encode H264, adapter 2 has room for limit sessions
+fn register(limit: usize) {
+    let on_2 = Arc::new(AtomicUsize::new(0));
+    let found = |format: DataFormat| match format {
+        DataFormat::H264 => vec![(1, Driver::NV), (2, Driver::AMF)],
+        _ => vec![],
+    };
+    common::register(NAME, found, move |ctx| {
+        let mut session = Session {
+            frames: vec![],
+            on_2: None,
+        };
+        if ctx.f.luid == 2 {
+            if on_2.load(Ordering::SeqCst) >= limit {
+                return Err(());
+            }
+            on_2.fetch_add(1, Ordering::SeqCst);
+            session.on_2 = Some(on_2.clone());
+        }
+        Ok(Box::new(session))
+    });
+}
+
+fn dynamic_context() -> DynamicContext {
+    DynamicContext {
+        width: 1280,
+        height: 720,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: 60,
+        ..Default::default()
+    }
+}
+
+// adapter 1 twice, the pool keeps its first
+fn features() -> Vec<FeatureContext> {
+    [(1, Driver::NV), (2, Driver::AMF), (1, Driver::NV)]
+        .into_iter()
+        .map(|(luid, vendor)| FeatureContext {
+            vendor,
+            luid,
+            ..encode_context(NAME).f
+        })
+        .collect()
+}
+
+// one test, the driver is registered under one name for the process
+#[test]
+fn spreads_over_adapters() {
+    register(2);
+    let mut pool = BalancedEncoderPool::from_features(features(), dynamic_context());
+    assert_eq!(pool.sessions(), [(1, 0), (2, 0)]);
+    let mut encoders: Vec<_> = (0..4).map(|_| pool.get().unwrap()).collect();
+    let luids: Vec<_> = encoders.iter().map(|e| e.ctx.f.luid).collect();
+    assert_eq!(luids, [1, 2, 1, 2]);
+    assert_eq!(pool.sessions(), [(1, 2), (2, 2)]);
+
+    // adapter 2 is out of sessions, the next ones go to 1
+    encoders.extend((0..2).map(|_| pool.get().unwrap()));
+    assert_eq!(pool.sessions(), [(1, 4), (2, 2)]);
+
+    // given back, adapter 2 takes the next one again
+    let on_2 = encoders.iter().position(|e| e.ctx.f.luid == 2).unwrap();
+    pool.put(encoders.remove(on_2));
+    assert_eq!(pool.sessions(), [(1, 4), (2, 1)]);
+    assert_eq!(pool.get().unwrap().ctx.f.luid, 2);
+
+    // the pool's adapters come from available() like the features above
+    let pool = BalancedEncoderPool::new(DataFormat::H264, dynamic_context());
+    let luids: Vec<_> = pool.sessions().iter().map(|(luid, _)| *luid).collect();
+    assert!(luids.contains(&1) && luids.contains(&2), "{:?}", luids);
+    backend::unregister_encode_driver(NAME);
+}
@@ -0,0 +1,582 @@
+use hwcodec::{
+    bitstream::{
+        annexb_nal_units,
+        assemble::AccessUnitAssembler,
+        h264, hevc, jpeg, nal_units, rbsp,
+        refs::{RefTracker, References},
+        validate::{starts_stream, validate_bitstream, BitstreamError, InvalidPacket, Validator},
+        vui::Vui,
+    },
+    common::{DataFormat, HwcodecErrno},
+};
+
+const H264_720P: &[u8] = include_bytes!("../src/res/720p.h264");
+const H265_720P: &[u8] = include_bytes!("../src/res/720p.h265");
+// baseline 4:2:0 colour bars with the K.3 huffman tables in a DHT segment before the scan
+const MJPEG_720P: &[u8] = include_bytes!("../src/res/720p.jpg");
+// the 720p h264 sample with 4 bytes length prefixes instead of start codes
+const H264_720P_AVCC: &[u8] = include_bytes!("fixtures/720p.avcc.h264");
+// 1920x1088 coded, cropped to 1080, chroma_loc 1 and bt709 in the vui
+const H264_1080P_CROPPED: &[u8] = include_bytes!("fixtures/1080p_cropped.h264");
+// 64x64 main profile, an I_PCM IDR then skipped P and B frames in decode order I P B P B
+// P B, max_num_reorder_frames 1
+const H264_BFRAMES: &[u8] = include_bytes!("fixtures/bframes.h264");
+// AUD, SPS, PPS, SEI and an IDR slice of the 720p h264 sample, 4 byte start codes
+// before the AUD, the SPS and the slice, a trailing zero byte before the slice's, 3 byte
+// ones before the PPS and the SEI. The SPS, SEI and slice have emulation prevention bytes.
+const H264_ACCESS_UNIT: &[u8] = include_bytes!("fixtures/access_unit.h264");
+// VPS and SPS in the layouts of the vendors, profile_compatibility and the constraint
+// flags differ: NV main 4.1 1920x1088 cropped, progressive and frame only
+const H265_NV_1080P: &[u8] = include_bytes!("fixtures/nv_1080p.h265");
+// AMF main 3.1 1280x720, no compatibility but main and no constraint flags
+const H265_AMF_720P: &[u8] = include_bytes!("fixtures/amf_720p.h265");
+// MFX main 4 1920x1088 cropped, non packed too, aspect_ratio_idc 1 in the vui
+const H265_MFX_1080P: &[u8] = include_bytes!("fixtures/mfx_1080p.h265");
+// main 4 640x480 of three temporal layers, sub-layer 0 with profile 3 and level, sub-layer
+// 1 with level 3.1 only
+const H265_TEMPORAL_LAYERS: &[u8] = include_bytes!("fixtures/temporal_layers.h265");
+
+#[test]
+fn h264_sps() {
+    let sps = h264::find_sps(H264_720P).unwrap();
+    assert_eq!(sps.coded_size(), (1280, 720));
+    assert_eq!(sps.cropped_size(), (1280, 720));
+    assert_eq!(sps.crop(), [0; 4]);
+    assert_eq!(sps.chroma_format_idc, 1);
+    assert_eq!(sps.bit_depth_luma, 8);
+    let signal = sps.vui.unwrap().video_signal.unwrap();
+    assert_eq!(signal.colour_description, Some([6, 6, 6]));
+}
+
+#[test]
+fn h264_sps_cropped() {
+    let sps = h264::find_sps(H264_1080P_CROPPED).unwrap();
+    assert_eq!(sps.coded_size(), (1920, 1088));
+    assert_eq!(sps.cropped_size(), (1920, 1080));
+    assert_eq!(sps.crop(), [0, 0, 0, 8]);
+    let vui = sps.vui.unwrap();
+    assert_eq!(vui.chroma_loc, Some((1, 1)));
+    assert_eq!(
+        vui.video_signal.unwrap().colour_description,
+        Some([1, 1, 1])
+    );
+}
+
+#[test]
+fn vui_sample_aspect_ratio() {
+    let vui = |idc, sar| Vui {
+        aspect_ratio_idc: idc,
+        sar,
+        ..Default::default()
+    };
+    assert_eq!(vui(Some(1), None).sample_aspect_ratio(), Some((1, 1)));
+    assert_eq!(vui(Some(14), None).sample_aspect_ratio(), Some((4, 3)));
+    assert_eq!(vui(Some(13), None).sample_aspect_ratio(), Some((160, 99)));
+    assert_eq!(
+        vui(Some(255), Some((8, 9))).sample_aspect_ratio(),
+        Some((8, 9))
+    );
+    // unspecified and reserved
+    assert_eq!(vui(Some(0), None).sample_aspect_ratio(), None);
+    assert_eq!(vui(Some(17), None).sample_aspect_ratio(), None);
+    assert_eq!(vui(None, None).sample_aspect_ratio(), None);
+    // the sample signals square pixels
+    let sps = h264::find_sps(H264_720P).unwrap();
+    assert_eq!(sps.vui.unwrap().sample_aspect_ratio(), Some((1, 1)));
+}
+
+#[test]
+fn h264_pps() {
+    let pps = annexb_nal_units(H264_720P)
+        .find(|nal| h264::nal_unit_type(nal) == Some(h264::NAL_PPS))
+        .map(|nal| h264::Pps::parse(nal).unwrap())
+        .unwrap();
+    assert_eq!((pps.pps_id, pps.sps_id), (0, 0));
+    assert!(pps.entropy_coding_mode_flag);
+}
+
+#[test]
+fn h264_sps_reorder() {
+    let sps = h264::find_sps(H264_BFRAMES).unwrap();
+    assert_eq!(sps.coded_size(), (64, 64));
+    assert_eq!(sps.max_num_reorder_frames, Some(1));
+    assert_eq!(sps.max_num_ref_frames, 2);
+    assert_eq!(h264::find_sps(H264_720P).unwrap().max_num_reorder_frames, None);
+    let mut v = Validator::new(DataFormat::H264);
+    v.validate(H264_BFRAMES).unwrap();
+    assert_eq!(v.size(), Some((64, 64)));
+}
+
+#[test]
+fn hevc_sps() {
+    let sps = annexb_nal_units(H265_720P)
+        .find(|nal| hevc::nal_unit_type(nal) == Some(hevc::NAL_SPS))
+        .map(|nal| hevc::Sps::parse(nal).unwrap())
+        .unwrap();
+    assert_eq!(sps.cropped_size(), (1280, 720));
+    // coded as 736 rows
+    assert_eq!(sps.pic_height_in_luma_samples, 736);
+    assert_eq!(sps.crop(), [0, 0, 0, 16]);
+    assert_eq!(sps.chroma_format_idc, 1);
+    assert_eq!(sps.bit_depth_luma, 8);
+    assert_eq!(sps.max_num_reorder_pics, Some(0));
+    assert!(sps.ref_pic_sets.is_some());
+    let signal = sps.vui.unwrap().video_signal.unwrap();
+    assert_eq!(signal.colour_description, Some([6, 6, 6]));
+}
+
+fn hevc_parameter_sets(data: &[u8]) -> (hevc::Vps, hevc::Sps) {
+    let nal = |t| {
+        annexb_nal_units(data)
+            .find(|nal| hevc::nal_unit_type(nal) == Some(t))
+            .unwrap()
+    };
+    let vps = hevc::Vps::parse(nal(hevc::NAL_VPS)).unwrap();
+    let sps = hevc::Sps::parse(nal(hevc::NAL_SPS)).unwrap();
+    // both carry the same profile_tier_level
+    assert_eq!(vps.ptl, sps.ptl);
+    (vps, sps)
+}
+
+#[test]
+fn hevc_profile_tier_level() {
+    let (_, nv) = hevc_parameter_sets(H265_NV_1080P);
+    let general = &nv.ptl.general;
+    assert_eq!((general.profile_space, general.tier_flag), (0, false));
+    assert_eq!(general.profile_idc, 1);
+    assert_eq!(general.profile_compatibility_flags, 0x6000_0000);
+    assert!(general.progressive_source_flag && general.frame_only_constraint_flag);
+    assert!(!general.interlaced_source_flag && !general.non_packed_constraint_flag);
+    assert_eq!(general.constraint_indicator_flags, 0x9000_0000_0000);
+    assert_eq!(nv.ptl.general_level_idc, 123);
+    assert!(nv.ptl.sub_layers.is_empty());
+    assert_eq!(nv.cropped_size(), (1920, 1080));
+    assert_eq!(nv.crop(), [0, 0, 0, 8]);
+
+    let (_, amf) = hevc_parameter_sets(H265_AMF_720P);
+    let general = &amf.ptl.general;
+    assert_eq!(general.profile_idc, 1);
+    assert_eq!(general.profile_compatibility_flags, 0x4000_0000);
+    assert_eq!(general.constraint_indicator_flags, 0);
+    assert!(!general.progressive_source_flag && !general.frame_only_constraint_flag);
+    assert_eq!(amf.ptl.general_level_idc, 93);
+    assert_eq!(amf.ptl.level(), 3.1);
+    assert_eq!(amf.cropped_size(), (1280, 720));
+    assert_eq!(amf.conformance_window, None);
+
+    let (_, mfx) = hevc_parameter_sets(H265_MFX_1080P);
+    let general = &mfx.ptl.general;
+    assert_eq!(general.profile_compatibility_flags, 0x6000_0000);
+    assert!(general.non_packed_constraint_flag);
+    assert_eq!(general.constraint_indicator_flags, 0xb000_0000_0000);
+    assert_eq!(mfx.ptl.general_level_idc, 120);
+    assert_eq!(mfx.cropped_size(), (1920, 1080));
+    // the syntax after the ptl is read through
+    let vui = mfx.vui.as_ref().unwrap();
+    assert_eq!(vui.aspect_ratio_idc, Some(1));
+    let signal = vui.video_signal.as_ref().unwrap();
+    assert_eq!(signal.colour_description, Some([1, 1, 1]));
+
+    for sps in [&nv, &amf, &mfx] {
+        assert_eq!(sps.max_sub_layers_minus1, 0);
+        assert_eq!((sps.chroma_format_idc, sps.bit_depth_luma), (1, 8));
+        assert!(sps.ref_pic_sets.is_some());
+    }
+}
+
+#[test]
+fn hevc_sub_layer_profile_tier_level() {
+    let (vps, sps) = hevc_parameter_sets(H265_TEMPORAL_LAYERS);
+    assert_eq!(vps.max_sub_layers_minus1, 2);
+    assert_eq!(sps.max_sub_layers_minus1, 2);
+    assert_eq!(sps.ptl.general_level_idc, 120);
+    let layers = &sps.ptl.sub_layers;
+    assert_eq!(layers.len(), 2);
+    assert_eq!(layers[0].profile.as_ref(), Some(&sps.ptl.general));
+    assert_eq!(layers[0].level_idc, Some(90));
+    assert_eq!(layers[1].profile, None);
+    assert_eq!(layers[1].level_idc, Some(93));
+    // the fields after the sub-layers are in place
+    assert_eq!(sps.cropped_size(), (640, 480));
+    assert_eq!(sps.max_num_reorder_pics, Some(0));
+    let signal = sps.vui.unwrap().video_signal.unwrap();
+    assert_eq!(signal.colour_description, Some([1, 1, 1]));
+}
+
+#[test]
+fn access_unit_nal_units() {
+    let nals: Vec<_> = nal_units(H264_ACCESS_UNIT).collect();
+    let types: Vec<_> = nals.iter().map(|nal| nal.h264_type()).collect();
+    assert_eq!(types, [9, 7, 8, 6, 5]);
+    let sizes: Vec<_> = nals.iter().map(|nal| nal.data.len()).collect();
+    assert_eq!(sizes, [2, 37, 4, 12, 201]);
+    // the zero bytes before a start code aren't part of the NAL unit before it
+    assert_eq!(nals[0].data, [0x09, 0xf0]);
+    assert_eq!(nals[2].data, [0x68, 0xeb, 0x8f, 0x20]);
+    // emulation prevention bytes stay in, rbsp takes them out
+    let sei = [
+        0x06, 0x00, 0x07, 0x80, 0x75, 0x30, 0x00, 0x00, 0x03, 0x00, 0x40, 0x80,
+    ];
+    assert_eq!(nals[3].data, sei);
+    assert_eq!(rbsp(nals[3].data), [&sei[..8], &sei[9..]].concat());
+    assert_eq!(rbsp(nals[1].data).len(), 36);
+    // the parameter sets of the sample
+    let sample: Vec<_> = annexb_nal_units(H264_720P).collect();
+    assert_eq!(nals[1].data, sample[0]);
+    assert_eq!(nals[4].data, sample[4]);
+}
+
+#[cfg(all(windows, feature = "vram"))]
+#[test]
+fn encode_frame_nal_units() {
+    use hwcodec::vram::encode::EncodeFrame;
+    let frame = EncodeFrame::new(H264_ACCESS_UNIT.to_vec(), 0, true);
+    let nals: Vec<_> = frame.nal_units().collect();
+    assert_eq!(nals, nal_units(H264_ACCESS_UNIT).collect::<Vec<_>>());
+    assert_eq!(nals.len(), 5);
+}
+
+#[test]
+fn avcc_matches_annexb() {
+    let annexb: Vec<_> = nal_units(H264_720P).collect();
+    let avcc: Vec<_> = nal_units(H264_720P_AVCC).collect();
+    assert!(!annexb.is_empty());
+    assert_eq!(annexb, avcc);
+    assert_eq!(annexb[0].h264_type(), h264::NAL_SPS);
+}
+
+#[test]
+fn filler_nal_units() {
+    for len in [0, 7, 100, 4096] {
+        let filler = h264::filler_nal(len);
+        assert_eq!(filler.len(), len.max(h264::FILLER_MIN_LEN));
+        let nals: Vec<_> = nal_units(&filler).collect();
+        assert_eq!(nals.len(), 1);
+        assert_eq!(nals[0].h264_type(), h264::NAL_FILLER);
+
+        let filler = hevc::filler_nal(len);
+        assert_eq!(filler.len(), len.max(hevc::FILLER_MIN_LEN));
+        let nals: Vec<_> = nal_units(&filler).collect();
+        assert_eq!(nals.len(), 1);
+        assert_eq!(nals[0].hevc_type(), hevc::NAL_FD);
+    }
+    // padding after the slices keeps the frames valid
+    let mut padded = H264_720P.to_vec();
+    padded.extend(h264::filler_nal(1000));
+    Validator::new(DataFormat::H264).validate(&padded).unwrap();
+    let mut padded = H265_720P.to_vec();
+    padded.extend(hevc::filler_nal(1000));
+    Validator::new(DataFormat::H265).validate(&padded).unwrap();
+}
+
+#[test]
+fn recovery_point_sei() {
+    // a payloadType 256 and a user data message before recovery_frame_cnt 3, exact match
+    let h264_sei = [
+        h264::NAL_SEI,
+        0xff,
+        0x01,
+        0x00,
+        0x05,
+        0x02,
+        0xaa,
+        0xbb,
+        0x06,
+        0x02,
+        0x24,
+        0x40,
+        0x80,
+    ];
+    assert_eq!(h264::recovery_point(&h264_sei), Some(3));
+    // recovery_poc_cnt -2
+    let hevc_sei = [hevc::NAL_PREFIX_SEI << 1, 0x01, 0x06, 0x01, 0x2d, 0x80];
+    assert_eq!(hevc::recovery_point(&hevc_sei), Some(-2));
+    assert_eq!(h264::recovery_point(&hevc_sei), None);
+    // a message beyond the NAL unit
+    assert_eq!(h264::recovery_point(&h264_sei[..10]), None);
+    // the SEI of the fixture is user data
+    assert!(annexb_nal_units(H264_720P).all(|nal| h264::recovery_point(nal).is_none()));
+}
+
+#[test]
+fn validator_accepts_fixtures() {
+    let mut v = Validator::new(DataFormat::H264);
+    v.validate(H264_720P).unwrap();
+    assert_eq!(v.size(), Some((1280, 720)));
+    v.validate(H264_1080P_CROPPED).unwrap();
+    assert_eq!(v.size(), Some((1920, 1080)));
+
+    let mut v = Validator::new(DataFormat::H265);
+    v.validate(H265_720P).unwrap();
+    assert_eq!(v.size(), Some((1280, 720)));
+}
+
+#[test]
+fn validator_rejects_mismatch() {
+    assert!(Validator::new(DataFormat::H265)
+        .validate(H264_720P)
+        .is_err());
+    assert!(Validator::new(DataFormat::H264)
+        .validate(H264_720P_AVCC)
+        .is_err());
+    // cut inside the SPS
+    assert!(Validator::new(DataFormat::H264)
+        .validate(&H264_720P[..12])
+        .is_err());
+}
+
+#[test]
+fn validator_rejects_corrupt() {
+    for (data, format) in [(H264_720P, DataFormat::H264), (H265_720P, DataFormat::H265)] {
+        // parameter sets cut off
+        assert!(Validator::new(format).validate(&data[..12]).is_err());
+        // forbidden_zero_bit
+        let mut corrupted = data.to_vec();
+        corrupted[4] |= 0x80;
+        assert!(Validator::new(format).validate(&corrupted).is_err());
+        // not annex-b
+        assert!(Validator::new(format).validate(&data[4..]).is_err());
+    }
+    assert!(Validator::new(DataFormat::H264)
+        .validate(H265_720P)
+        .is_err());
+}
+
+// the NAL units of data for which keep is true, with 4 byte start codes
+fn filter_nal_units(data: &[u8], keep: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    annexb_nal_units(data)
+        .filter(|nal| keep(nal))
+        .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+        .collect()
+}
+
+#[test]
+fn bitstream_validation() {
+    validate_bitstream(H264_720P, DataFormat::H264).unwrap();
+    validate_bitstream(H264_BFRAMES, DataFormat::H264).unwrap();
+    validate_bitstream(H265_720P, DataFormat::H265).unwrap();
+    let two = [H265_720P, H265_720P].concat();
+    validate_bitstream(&two, DataFormat::H265).unwrap();
+
+    // cut inside the SPS
+    assert_eq!(
+        validate_bitstream(&H264_720P[..12], DataFormat::H264),
+        Err(BitstreamError::Invalid(InvalidPacket::BadParameterSet(
+            h264::NAL_SPS
+        )))
+    );
+    assert_eq!(
+        validate_bitstream(&H264_720P[4..], DataFormat::H264),
+        Err(BitstreamError::Invalid(InvalidPacket::NoStartCode))
+    );
+    let mut corrupt = H264_720P.to_vec();
+    corrupt[4] |= 0x80;
+    assert!(matches!(
+        validate_bitstream(&corrupt, DataFormat::H264),
+        Err(BitstreamError::Invalid(InvalidPacket::BadNalHeader(_)))
+    ));
+    let no_pps = filter_nal_units(H264_720P, |nal| {
+        h264::nal_unit_type(nal) != Some(h264::NAL_PPS)
+    });
+    assert_eq!(
+        validate_bitstream(&no_pps, DataFormat::H264),
+        Err(BitstreamError::UnknownPps(0))
+    );
+    // a second IDR without its parameter sets
+    let idr = filter_nal_units(H264_720P, |nal| h264::nal_unit_type(nal) == Some(5));
+    let repeated = [H264_720P, &idr].concat();
+    assert_eq!(
+        validate_bitstream(&repeated, DataFormat::H264),
+        Err(BitstreamError::KeyframeWithoutParameterSets(5))
+    );
+    let no_vps = filter_nal_units(H265_720P, |nal| {
+        hevc::nal_unit_type(nal) != Some(hevc::NAL_VPS)
+    });
+    let two = [H265_720P, &no_vps].concat();
+    assert_eq!(
+        validate_bitstream(&two, DataFormat::H265),
+        Err(BitstreamError::KeyframeWithoutParameterSets(19))
+    );
+}
+
+#[test]
+fn streams_start_with_sps_and_idr() {
+    assert!(starts_stream(H264_720P, DataFormat::H264, 64));
+    assert!(starts_stream(H265_720P, DataFormat::H265, 64));
+    assert!(!starts_stream(H264_720P, DataFormat::H265, 64));
+    assert!(!starts_stream(&[], DataFormat::H264, 64));
+    assert!(!starts_stream(&[0; 4096], DataFormat::H264, 64));
+    assert!(!starts_stream(MJPEG_720P, DataFormat::MJPEG, 64));
+    let idr_len = annexb_nal_units(H264_720P)
+        .filter(|nal| h264::nal_unit_type(nal) == Some(5))
+        .map(|nal| nal.len())
+        .max()
+        .unwrap();
+    assert!(starts_stream(H264_720P, DataFormat::H264, idr_len));
+    assert!(!starts_stream(H264_720P, DataFormat::H264, idr_len + 1));
+    // parameter sets alone, or a P frame after them
+    let no_idr = filter_nal_units(H264_720P, |nal| h264::nal_unit_type(nal) != Some(5));
+    assert!(!starts_stream(&no_idr, DataFormat::H264, 64));
+    let no_sps = filter_nal_units(H265_720P, |nal| {
+        hevc::nal_unit_type(nal) != Some(hevc::NAL_SPS)
+    });
+    assert!(!starts_stream(&no_sps, DataFormat::H265, 64));
+}
+
+// the access units of data pushed in chunks of size, then flushed
+fn assemble(format: DataFormat, data: &[u8], size: usize) -> Vec<Vec<u8>> {
+    let mut assembler = AccessUnitAssembler::new(format).unwrap();
+    let mut units = vec![];
+    let mut consumed = 0;
+    for chunk in data.chunks(size) {
+        consumed += assembler
+            .push(chunk, |au| {
+                units.push(au.to_vec());
+                Ok(())
+            })
+            .unwrap();
+    }
+    assert_eq!(consumed + assembler.buffered(), data.len());
+    assembler
+        .flush(|au| {
+            units.push(au.to_vec());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(assembler.buffered(), 0);
+    units
+}
+
+#[test]
+fn access_units_from_any_chunking() {
+    for (format, data) in [(DataFormat::H264, H264_720P), (DataFormat::H265, H265_720P)] {
+        // each sample is one access unit with the parameter sets and SEI
+        let stream = data.repeat(3);
+        let whole = assemble(format, &stream, stream.len());
+        assert_eq!(whole, vec![data.to_vec(); 3], "{:?}", format);
+        // start codes and headers split at every position
+        for size in [1, 2, 3, 5, 1400] {
+            assert_eq!(
+                assemble(format, &stream, size),
+                whole,
+                "{:?} {}",
+                format,
+                size
+            );
+        }
+    }
+}
+
+#[test]
+fn assembler_errors() {
+    assert!(AccessUnitAssembler::new(DataFormat::VP9).is_err());
+    let mut assembler = AccessUnitAssembler::new(DataFormat::H264).unwrap();
+    let mut calls = 0;
+    let mut fail = |_: &[u8]| {
+        calls += 1;
+        Err(-1)
+    };
+    let data = H264_720P.repeat(2);
+    assert_eq!(assembler.push(&data, &mut fail), Err(-1));
+    assert_eq!(calls, 1);
+    assert!(assembler.buffered() < data.len());
+
+    // a picture never ending is dropped
+    assembler.reset();
+    let chunk = vec![0xffu8; 1 << 20];
+    let mut result = Ok(0);
+    for _ in 0..17 {
+        result = assembler.push(&chunk, |_| Ok(()));
+        if result.is_err() {
+            break;
+        }
+    }
+    assert_eq!(result, Err(HwcodecErrno::HWCODEC_ERR_INVALID_DATA as i32));
+    assert_eq!(assembler.buffered(), 0);
+}
+
+#[test]
+fn reference_tracking() {
+    // decode order I P B P B P B
+    let units = assemble(DataFormat::H264, H264_BFRAMES, H264_BFRAMES.len());
+    let mut tracker = RefTracker::new(DataFormat::H264);
+    assert_eq!(tracker.push(&units[1]), References::Missing);
+    assert_eq!(tracker.push(&units[0]), References::Idr);
+    for unit in &units[1..] {
+        assert_eq!(tracker.push(unit), References::Complete);
+    }
+    // the first P lost
+    assert_eq!(tracker.push(&units[0]), References::Idr);
+    assert_eq!(tracker.push(&units[2]), References::Missing);
+    // a non-reference B doesn't move frame_num on
+    assert_eq!(tracker.push(&units[2]), References::Missing);
+
+    let mut tracker = RefTracker::new(DataFormat::H265);
+    let parameter_sets: Vec<u8> = annexb_nal_units(H265_720P)
+        .filter(|nal| (32..=34).contains(&hevc::nal_unit_type(nal).unwrap()))
+        .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+        .collect();
+    assert_eq!(tracker.push(&parameter_sets), References::NoPicture);
+    assert_eq!(tracker.push(H265_720P), References::Idr);
+}
+
+#[test]
+fn jpeg_frame_header() {
+    let header = jpeg::FrameHeader::find(MJPEG_720P).unwrap();
+    assert_eq!(
+        (header.width, header.height, header.precision),
+        (1280, 720, 8)
+    );
+    assert!(header.is_baseline());
+    assert_eq!(header.components, [(1, 2, 2), (2, 1, 1), (3, 1, 1)]);
+    let markers: Vec<_> = jpeg::segments(MJPEG_720P).map(|s| s.marker).collect();
+    assert_eq!(markers.first(), Some(&jpeg::SOI));
+    assert_eq!(markers.last(), Some(&jpeg::SOS));
+}
+
+#[test]
+fn validator_mjpeg() {
+    let mut v = Validator::new(DataFormat::MJPEG);
+    v.validate(MJPEG_720P).unwrap();
+    assert_eq!(v.size(), Some((1280, 720)));
+    assert_eq!(
+        v.validate(&MJPEG_720P[2..]),
+        Err(InvalidPacket::NoStartCode)
+    );
+    let sof = jpeg::segments(MJPEG_720P)
+        .find(|s| s.marker == jpeg::SOF0)
+        .unwrap()
+        .offset;
+    assert_eq!(
+        v.validate(&MJPEG_720P[..sof + 6]),
+        Err(InvalidPacket::BadSlice(jpeg::SOS))
+    );
+    let mut progressive = MJPEG_720P.to_vec();
+    progressive[sof + 1] = 0xc2;
+    assert_eq!(
+        v.validate(&progressive),
+        Err(InvalidPacket::UnsupportedParameterSet(0xc2))
+    );
+    // every image stands alone
+    assert_eq!(
+        RefTracker::new(DataFormat::MJPEG).push(MJPEG_720P),
+        References::Idr
+    );
+}
+
+#[test]
+fn jpeg_default_huffman_tables() {
+    assert_eq!(jpeg::with_default_huffman_tables(MJPEG_720P), None);
+    // the tables are the defaults, put back where they were
+    let dht = jpeg::segments(MJPEG_720P)
+        .find(|s| s.marker == jpeg::DHT)
+        .unwrap();
+    let end = dht.offset + 4 + dht.payload.len();
+    let stripped = [&MJPEG_720P[..dht.offset], &MJPEG_720P[end..]].concat();
+    assert!(jpeg::segments(&stripped).all(|s| s.marker != jpeg::DHT));
+    assert_eq!(
+        jpeg::with_default_huffman_tables(&stripped).as_deref(),
+        Some(MJPEG_720P)
+    );
+    assert_eq!(jpeg::with_default_huffman_tables(&stripped[..100]), None);
+}
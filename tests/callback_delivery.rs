@@ -0,0 +1,42 @@
+#![cfg(all(windows, feature = "vram", feature = "testutil"))]
+
+use hwcodec::{
+    bitstream::nal_units,
+    vram::encode::{debug_deliver_frames, debug_deliver_frames_during_calls},
+};
+
+// frames of many NAL units, one after the other on the thread of the call
+#[test]
+fn keeps_frames_of_the_calling_thread() {
+    for _ in 0..100 {
+        let (frames, stray) = debug_deliver_frames(64, 50, 0);
+        assert_eq!(stray, 0);
+        assert_eq!(frames.len(), 50);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.pts, i as i64);
+            let nals: Vec<_> = nal_units(&frame.data).collect();
+            assert_eq!(nals.len(), 64);
+            for (n, nal) in nals.iter().enumerate() {
+                assert_eq!(nal.data, [0x65, n as u8 + 1]);
+            }
+        }
+    }
+}
+
+// deliveries racing from other threads are dropped instead of touching the frames
+#[test]
+fn drops_frames_of_other_threads() {
+    for _ in 0..20 {
+        let (frames, stray) = debug_deliver_frames(64, 50, 8);
+        assert!(frames.is_empty());
+        assert_eq!(stray, 8 * 50);
+    }
+}
+
+// a stray delivery from another thread may race the next call setting the thread
+#[test]
+fn drops_frames_of_other_threads_during_calls() {
+    for _ in 0..20 {
+        assert_eq!(debug_deliver_frames_during_calls(1000, 4), 0);
+    }
+}
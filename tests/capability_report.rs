@@ -0,0 +1,134 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use hwcodec::{
+    common::{DataFormat, DecodeCaps, Driver},
+    vram::{
+        backend::{self, DecodeBackend, DecodeDriver},
+        decode::DecodeFrame,
+        encode,
+        report::{capability_report, capability_report_with_round_trips, CapabilityReport},
+        DecodeContext, DynamicContext,
+    },
+};
+use std::sync::Arc;
+
+const NAME: &str = "capability-report-test";
+
+const CAPS: DecodeCaps = DecodeCaps {
+    maxWidth: 4096,
+    maxHeight: 2304,
+    maxLevel: 51,
+    profiles: 0,
+    bitDepths: 1 << 8,
+};
+
+// decodes everything on adapter 7, but MJPEG on adapter 8 where no session opens
+struct Fake;
+
+struct Session(Vec<DecodeFrame>);
+
+impl DecodeBackend for Session {
+    fn decode(&mut self, _packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
+        Ok(&mut self.0)
+    }
+
+    fn caps(&self) -> DecodeCaps {
+        CAPS
+    }
+}
+
+impl DecodeDriver for Fake {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn test(&self, format: DataFormat) -> Vec<(i64, Driver)> {
+        match format {
+            DataFormat::MJPEG => vec![(8, Driver::NV)],
+            _ => vec![(7, Driver::NV)],
+        }
+    }
+
+    fn create(&self, ctx: &DecodeContext) -> Result<Box<dyn DecodeBackend>, ()> {
+        match ctx.luid {
+            7 => Ok(Box::new(Session(vec![]))),
+            _ => Err(()),
+        }
+    }
+}
+
+#[test]
+fn adapters_merge_encode_and_decode() {
+    // encodes H264 on adapter 7, the sessions never open
+    let found = |format: DataFormat| match format {
+        DataFormat::H264 => vec![(7, Driver::NV)],
+        _ => vec![],
+    };
+    common::register(NAME, found, |_| Err(()));
+    backend::register_decode_driver(Arc::new(Fake));
+    let d = DynamicContext {
+        width: 1280,
+        height: 720,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: 60,
+        ..Default::default()
+    };
+    let report = capability_report(d);
+    let encoders = encode::available(d);
+    let checked = capability_report_with_round_trips(d);
+    backend::unregister_encode_driver(NAME);
+    backend::unregister_decode_driver(NAME);
+
+    let reported: Vec<_> = report
+        .adapters
+        .iter()
+        .flat_map(|a| a.encoders.iter().cloned())
+        .collect();
+    assert_eq!(reported.len(), encoders.len());
+    assert!(encoders.iter().all(|f| reported.contains(f)));
+    for adapter in &report.adapters {
+        assert!(adapter.encoders.iter().all(|f| f.luid == adapter.luid));
+        assert!(adapter.decoders.iter().all(|r| r.ctx.luid == adapter.luid));
+    }
+
+    let custom = Driver::CUSTOM(NAME.to_owned());
+    let seven = report.adapters.iter().find(|a| a.luid == 7).unwrap();
+    assert_eq!(seven.path, None);
+    assert_eq!(seven.encoders.len(), 1);
+    assert_eq!(seven.encoders[0].driver, custom);
+    let formats: Vec<_> = seven.decoders.iter().map(|r| r.ctx.data_format).collect();
+    assert_eq!(formats, [DataFormat::H264, DataFormat::H265]);
+    assert!(seven.decoders.iter().all(|r| r.caps == Some(CAPS)));
+    let eight = report.adapters.iter().find(|a| a.luid == 8).unwrap();
+    assert!(eight.encoders.is_empty());
+    assert_eq!(eight.decoders.len(), 1);
+    assert_eq!(eight.decoders[0].ctx.data_format, DataFormat::MJPEG);
+    assert_eq!(eight.decoders[0].caps, None);
+
+    let json = report.serialize().unwrap();
+    assert_eq!(CapabilityReport::deserialize(&json), Ok(report.clone()));
+
+    // the H264 encoder against the H264 decoder of adapter 7, the fake creates no
+    // sessions
+    assert!(report.round_trips.is_empty());
+    assert_eq!(checked.adapters, report.adapters);
+    let trips: Vec<_> = checked
+        .round_trips
+        .iter()
+        .filter(|t| t.encoder.driver == custom)
+        .collect();
+    assert_eq!(trips.len(), 1);
+    assert_eq!(trips[0].decoder.driver, custom);
+    assert_eq!(trips[0].decoder.luid, 7);
+    assert_eq!(trips[0].decoder.data_format, DataFormat::H264);
+    assert!(trips[0].error.is_some());
+    assert!(checked
+        .round_trips
+        .iter()
+        .all(|t| t.encoder.data_format == t.decoder.data_format));
+    let json = checked.serialize().unwrap();
+    assert_eq!(CapabilityReport::deserialize(&json), Ok(checked));
+}
@@ -0,0 +1,181 @@
+// Calls the C API the way a C program would, the encode round trip is skipped without an
+// h264 encoder and decoder.
+#![cfg(all(windows, feature = "capi", feature = "testutil"))]
+
+use hwcodec::{
+    capi::*,
+    testutil::{bgra_pattern, Device, Texture},
+};
+use libc::{c_char, c_void, strcmp, strlen};
+use std::{ffi::CString, ptr::null_mut, thread};
+
+const WIDTH: i32 = 640;
+const HEIGHT: i32 = 480;
+
+extern "C" fn on_packet(data: *const u8, len: usize, _pts: i64, key: i32, user_data: *mut c_void) {
+    let packets = unsafe { &mut *(user_data as *mut Vec<(Vec<u8>, i32)>) };
+    packets.push((
+        unsafe { std::slice::from_raw_parts(data, len) }.to_vec(),
+        key,
+    ));
+}
+
+extern "C" fn on_frame(_texture: *mut c_void, width: i32, height: i32, user_data: *mut c_void) {
+    let frames = unsafe { &mut *(user_data as *mut Vec<(i32, i32)>) };
+    frames.push((width, height));
+}
+
+extern "C" fn on_bgra(
+    data: *const u8,
+    stride: i32,
+    width: i32,
+    height: i32,
+    user_data: *mut c_void,
+) {
+    let frames = unsafe { &mut *(user_data as *mut Vec<(i32, i32, i32)>) };
+    assert!(!data.is_null());
+    frames.push((width, height, stride));
+}
+
+fn c_str(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+fn last_error() -> Option<String> {
+    let e = hwcodec_last_error();
+    if e.is_null() {
+        return None;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(e as *const u8, strlen(e)) };
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[test]
+fn version_and_error_strings() {
+    assert_eq!(hwcodec_capi_version(), HWCODEC_CAPI_VERSION);
+    let expected = c_str("reset required");
+    assert_eq!(
+        unsafe { strcmp(hwcodec_error_string(-7), expected.as_ptr()) },
+        0
+    );
+    let expected = c_str("need keyframe");
+    assert_eq!(
+        unsafe { strcmp(hwcodec_error_string(-8), expected.as_ptr()) },
+        0
+    );
+    let expected = c_str("exceeds decoder caps");
+    assert_eq!(
+        unsafe { strcmp(hwcodec_error_string(-9), expected.as_ptr()) },
+        0
+    );
+    let expected = c_str("not one frame");
+    assert_eq!(
+        unsafe { strcmp(hwcodec_error_string(-10), expected.as_ptr()) },
+        0
+    );
+    for code in [0, -1, -3, -6, -1000, 5] {
+        assert!(unsafe { strlen(hwcodec_error_string(code)) } > 0);
+    }
+}
+
+#[test]
+fn invalid_arguments_fail_cleanly() {
+    unsafe {
+        assert!(hwcodec_encoder_new(std::ptr::null(), null_mut()).is_null());
+        assert!(last_error().unwrap().contains("ctx"));
+        let ctx = c_str("{\"f\": 1}");
+        assert!(hwcodec_encoder_new(ctx.as_ptr(), null_mut()).is_null());
+        assert!(last_error().unwrap().contains("invalid encode context"));
+        assert!(hwcodec_decoder_new(ctx.as_ptr(), null_mut()).is_null());
+        assert!(last_error().unwrap().contains("invalid decode context"));
+        let mut packets: Vec<(Vec<u8>, i32)> = vec![];
+        let user_data = &mut packets as *mut _ as *mut c_void;
+        assert!(hwcodec_encode(null_mut(), null_mut(), 0, on_packet, user_data) < 0);
+        assert!(hwcodec_encoder_reset(null_mut(), WIDTH, HEIGHT, on_packet, user_data) < 0);
+        assert!(hwcodec_encoder_request_keyframe(null_mut()) < 0);
+        assert!(hwcodec_decode(null_mut(), [0u8].as_ptr(), 1, on_frame, null_mut()) < 0);
+        assert!(hwcodec_decode_bgra(null_mut(), [0u8].as_ptr(), 1, on_bgra, null_mut()) < 0);
+        assert!(packets.is_empty());
+        hwcodec_encoder_free(null_mut());
+        hwcodec_decoder_free(null_mut());
+        hwcodec_free_string(null_mut());
+    }
+}
+
+#[test]
+fn last_error_is_per_thread() {
+    unsafe {
+        assert!(hwcodec_encoder_new(std::ptr::null(), null_mut()).is_null());
+    }
+    thread::spawn(|| assert!(last_error().is_none()))
+        .join()
+        .unwrap();
+    assert!(last_error().is_some());
+}
+
+#[test]
+fn encode_decode_round_trip() {
+    let available = hwcodec_available(WIDTH, HEIGHT, 2000, 30, 60);
+    assert!(!available.is_null());
+    let json = unsafe { std::ffi::CStr::from_ptr(available as *const c_char) }
+        .to_str()
+        .unwrap()
+        .to_owned();
+    unsafe { hwcodec_free_string(available) };
+    let available: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let h264 = |v: &serde_json::Value| v["data_format"] == "H264";
+    let (Some(f), Some(d)) = (
+        available["e"].as_array().unwrap().iter().find(|f| h264(f)),
+        available["d"].as_array().unwrap().iter().find(|d| h264(d)),
+    ) else {
+        println!("no h264 encoder and decoder, skipped");
+        return;
+    };
+    let device = Device::new(f["luid"].as_i64().unwrap()).unwrap();
+    let ctx = serde_json::json!({
+        "f": f,
+        "d": {"width": WIDTH, "height": HEIGHT, "kbitrate": 2000, "framerate": 30, "gop": 60},
+    });
+    let ctx = c_str(&ctx.to_string());
+    let encoder = unsafe { hwcodec_encoder_new(ctx.as_ptr(), device.as_ptr()) };
+    assert!(!encoder.is_null(), "{:?}", last_error());
+    let mut packets: Vec<(Vec<u8>, i32)> = vec![];
+    for i in 0..10 {
+        let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        let user_data = &mut packets as *mut _ as *mut c_void;
+        let ret =
+            unsafe { hwcodec_encode(encoder, texture.as_ptr(), i as _, on_packet, user_data) };
+        assert_eq!(ret, 0, "{:?}", last_error());
+    }
+    unsafe { hwcodec_encoder_free(encoder) };
+    assert_eq!(packets[0].1, 1);
+
+    let ctx = c_str(&d.to_string());
+    let decoder = unsafe { hwcodec_decoder_new(ctx.as_ptr(), null_mut()) };
+    assert!(!decoder.is_null(), "{:?}", last_error());
+    let mut frames: Vec<(i32, i32)> = vec![];
+    for (packet, _) in packets.iter() {
+        let user_data = &mut frames as *mut _ as *mut c_void;
+        let ret =
+            unsafe { hwcodec_decode(decoder, packet.as_ptr(), packet.len(), on_frame, user_data) };
+        assert_eq!(ret, 0, "{:?}", last_error());
+    }
+    unsafe { hwcodec_decoder_free(decoder) };
+    assert!(!frames.is_empty());
+    assert!(frames.iter().all(|f| *f == (WIDTH, HEIGHT)));
+
+    let decoder = unsafe { hwcodec_decoder_new(ctx.as_ptr(), null_mut()) };
+    assert!(!decoder.is_null(), "{:?}", last_error());
+    let mut bgra: Vec<(i32, i32, i32)> = vec![];
+    for (packet, _) in packets.iter() {
+        let user_data = &mut bgra as *mut _ as *mut c_void;
+        let ret = unsafe {
+            hwcodec_decode_bgra(decoder, packet.as_ptr(), packet.len(), on_bgra, user_data)
+        };
+        assert_eq!(ret, 0, "{:?}", last_error());
+    }
+    unsafe { hwcodec_decoder_free(decoder) };
+    assert_eq!(bgra.len(), frames.len());
+    assert!(bgra.iter().all(|f| *f == (WIDTH, HEIGHT, WIDTH * 4)));
+}
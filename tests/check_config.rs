@@ -0,0 +1,183 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    common::{ColorConvert, ConfigParam, DataFormat, Driver, EncodeCaps, EntropyCoding},
+    vram::{
+        backend::{self, EncodeBackend, EncodeDriver},
+        encode::{check_config, ConfigError},
+        DynamicContext, EncodeContext,
+    },
+};
+use std::sync::Arc;
+
+// rejects framerates above its limit, creates nothing
+struct Limited {
+    name: &'static str,
+    max_framerate: i32,
+}
+
+impl EncodeDriver for Limited {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn test(&self, _format: DataFormat, _d: &DynamicContext) -> Vec<(i64, Driver)> {
+        vec![]
+    }
+
+    fn create(&self, _ctx: &EncodeContext) -> Result<Box<dyn EncodeBackend>, ()> {
+        Err(())
+    }
+
+    fn check_config(&self, ctx: &EncodeContext) -> Result<(), ConfigError> {
+        if ctx.d.framerate > self.max_framerate {
+            return Err(ConfigError::Unsupported {
+                param: ConfigParam::CONFIG_PARAM_FRAMERATE,
+                corrected: Some(self.max_framerate),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn ctx(name: &str) -> EncodeContext {
+    let mut ctx = encode_context(name);
+    ctx.f.caps = EncodeCaps {
+        maxWidth: 4096,
+        maxHeight: 2304,
+        ..Default::default()
+    };
+    ctx.d.width = 1920;
+    ctx.d.height = 1080;
+    ctx.d.kbitrate = 5000;
+    ctx
+}
+
+fn unsupported(param: ConfigParam, corrected: Option<i32>) -> Result<(), ConfigError> {
+    Err(ConfigError::Unsupported { param, corrected })
+}
+
+#[test]
+fn common_checks() {
+    // none of them reach the driver, it isn't registered
+    let name = "check-config-common";
+    let mut c = ctx(name);
+    c.d.width = 0;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_WIDTH, None)
+    );
+    let mut c = ctx(name);
+    c.d.kbitrate = -1;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_BITRATE, None)
+    );
+    let mut c = ctx(name);
+    c.d.kbitrate = 0;
+    c.d.quality = 60;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_QUALITY, Some(51))
+    );
+    let mut c = ctx(name);
+    c.d.aq_strength = 20;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_AQ_STRENGTH, Some(15))
+    );
+    let mut c = ctx(name);
+    c.d.chroma_qp_offset = -20;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_CHROMA_QP_OFFSET, Some(-12))
+    );
+    let mut c = ctx(name);
+    c.d.width = 7680;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_WIDTH, Some(4096))
+    );
+    let mut c = ctx(name);
+    c.d.height = 4320;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_HEIGHT, Some(2304))
+    );
+    // h265 has no CAVLC
+    let mut c = ctx(name);
+    c.f.data_format = DataFormat::H265;
+    c.d.entropy_coding = EntropyCoding::ENTROPY_CODING_CAVLC;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_ENTROPY_CODING, Some(0))
+    );
+    // nvenc converts the input in the encoder
+    let mut c = ctx(name);
+    c.f.driver = Driver::NV;
+    c.d.color_convert = ColorConvert::COLOR_CONVERT_SOFTWARE;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_COLOR_CONVERT, Some(0))
+    );
+    // amf has no reference invalidation
+    let mut c = ctx(name);
+    c.f.driver = Driver::AMF;
+    c.d.reference_invalidation = true;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_REF_INVALIDATION, Some(0))
+    );
+    // lossless is nvenc's, ffmpeg's other encoders have none either
+    let mut c = ctx(name);
+    c.d.lossless = true;
+    c.f.driver = Driver::MFX;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_LOSSLESS, Some(0))
+    );
+    c.f.driver = Driver::FFMPEG;
+    c.f.vendor = Driver::AMF;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_LOSSLESS, Some(0))
+    );
+    c.f.driver = Driver::CUSTOM(name.to_owned());
+    assert_eq!(check_config(&c), Err(ConfigError::NoDriver));
+    // the vui has 16 bits per term, 65536:2 reduces to fit
+    let mut c = ctx(name);
+    c.d.sar = (0, 1);
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_SAR, None)
+    );
+    c.d.sar = (70000, 3);
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_SAR, None)
+    );
+    c.d.sar = (65536, 2);
+    assert_eq!(check_config(&c), Err(ConfigError::NoDriver));
+    assert_eq!(check_config(&ctx(name)), Err(ConfigError::NoDriver));
+}
+
+#[test]
+fn custom_driver() {
+    let name = "check-config-limited";
+    backend::register_encode_driver(Arc::new(Limited {
+        name,
+        max_framerate: 60,
+    }));
+    let mut c = ctx(name);
+    assert_eq!(check_config(&c), Ok(()));
+    c.d.framerate = 120;
+    assert_eq!(
+        check_config(&c),
+        unsupported(ConfigParam::CONFIG_PARAM_FRAMERATE, Some(60))
+    );
+    backend::unregister_encode_driver(name);
+    assert_eq!(check_config(&c), Err(ConfigError::NoDriver));
+}
@@ -0,0 +1,97 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    common::HwcodecErrno,
+    vram::{
+        backend::EncodeBackend,
+        encode::{EncodeFrame, Encoder},
+    },
+};
+use std::{
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+// counts the destructions of its session, by close or by drop while still open
+struct Fake {
+    open: bool,
+    fail: bool,
+    destroyed: Arc<AtomicUsize>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl Fake {
+    fn destroy(&mut self) {
+        if std::mem::take(&mut self.open) {
+            self.destroyed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl EncodeBackend for Fake {
+    fn encode(&mut self, _tex: *mut c_void, _ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), i32> {
+        let closed = self.open;
+        self.destroy();
+        match (closed, self.fail) {
+            (true, false) => Ok(()),
+            _ => Err(HwcodecErrno::HWCODEC_ERR_COMMON as _),
+        }
+    }
+}
+
+impl Drop for Fake {
+    fn drop(&mut self) {
+        self.destroy();
+    }
+}
+
+fn closing_encoder(fail: bool) -> (Encoder, Arc<AtomicUsize>) {
+    let destroyed = Arc::new(AtomicUsize::new(0));
+    let backend = Fake {
+        open: true,
+        fail,
+        destroyed: destroyed.clone(),
+        frames: vec![],
+    };
+    let ctx = encode_context("close-test");
+    (Encoder::from_backend(Box::new(backend), ctx), destroyed)
+}
+
+#[test]
+fn close_destroys_once() {
+    let (encoder, destroyed) = closing_encoder(false);
+    assert_eq!(encoder.close(), Ok(()));
+    // the drop at the end of close destroyed nothing more
+    assert_eq!(destroyed.load(Ordering::SeqCst), 1);
+    let (encoder, destroyed) = closing_encoder(false);
+    drop(encoder);
+    assert_eq!(destroyed.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn close_returns_the_error() {
+    let (encoder, destroyed) = closing_encoder(true);
+    assert_eq!(
+        encoder.close(),
+        Err(HwcodecErrno::HWCODEC_ERR_COMMON as i32)
+    );
+    assert_eq!(destroyed.load(Ordering::SeqCst), 1);
+}
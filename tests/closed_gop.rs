@@ -0,0 +1,109 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::vram::{
+    backend::EncodeBackend,
+    encode::{EncodeFrame, Encoder, EncoderInfo},
+    EncodeContext,
+};
+use std::{ffi::c_void, ptr::null_mut};
+
+// Outputs each frame one call late like a session with B-frames, the first frame of a
+// session and those after a request are keys. The frame data is the session number.
+#[derive(Default)]
+struct Delayed {
+    bframes: i32,
+    session: u8,
+    key: bool,
+    pending: Option<EncodeFrame>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Delayed {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.frames.clear();
+        let frame = EncodeFrame::new(vec![self.session], ms, std::mem::take(&mut self.key));
+        if self.bframes == 0 {
+            self.frames.push(frame);
+        } else {
+            self.frames.extend(self.pending.replace(frame));
+        }
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.key = true;
+        Ok(())
+    }
+
+    fn info(&self) -> EncoderInfo {
+        EncoderInfo {
+            bframes: self.bframes,
+            ..Default::default()
+        }
+    }
+
+    fn flush(&mut self) -> Result<Vec<EncodeFrame>, i32> {
+        Ok(self.pending.take().into_iter().collect())
+    }
+
+    fn recreate(&mut self, _ctx: &EncodeContext) -> Result<(), ()> {
+        self.session += 1;
+        self.key = true;
+        Ok(())
+    }
+}
+
+fn encoder(bframes: i32) -> Encoder {
+    let ctx = encode_context("closed-gop-test");
+    let backend = Delayed {
+        bframes,
+        key: true,
+        ..Default::default()
+    };
+    Encoder::from_backend(Box::new(backend), ctx)
+}
+
+// (session, pts, key) of the frames of an encode call
+fn encode(encoder: &mut Encoder, ms: i64) -> Vec<(u8, i64, i32)> {
+    encoder
+        .encode(null_mut(), ms)
+        .unwrap()
+        .iter()
+        .map(|f| (f.data[0], f.pts, f.key))
+        .collect()
+}
+
+#[test]
+fn keyframe_closes_gop_without_bframes() {
+    let mut encoder = encoder(0);
+    assert_eq!(encode(&mut encoder, 0), [(0, 0, 1)]);
+    assert_eq!(encode(&mut encoder, 1), [(0, 1, 0)]);
+    encoder.close_gop_at_next().unwrap();
+    assert_eq!(encode(&mut encoder, 2), [(0, 2, 1)]);
+    assert_eq!(encode(&mut encoder, 3), [(0, 3, 0)]);
+}
+
+#[test]
+fn bframe_session_restarts_at_boundary() {
+    let mut encoder = encoder(1);
+    assert!(encode(&mut encoder, 0).is_empty());
+    assert_eq!(encode(&mut encoder, 1), [(0, 0, 1)]);
+    assert_eq!(encode(&mut encoder, 2), [(0, 1, 0)]);
+    encoder.close_gop_at_next().unwrap();
+    // the old session's last frame, the new one holds the IDR back like any frame
+    assert_eq!(encode(&mut encoder, 3), [(0, 2, 0)]);
+    assert_eq!(encode(&mut encoder, 4), [(1, 3, 1)]);
+    assert_eq!(encode(&mut encoder, 5), [(1, 4, 0)]);
+    assert_eq!(encoder.summary().frames, 5);
+}
@@ -0,0 +1,97 @@
+use hwcodec::common::{
+    ChromaFormat, CodecDescriptor, CodecProfile, DataFormat, DecodeCaps, DecodeProfile,
+    EntropyCoding,
+};
+
+#[test]
+fn from_data_format() {
+    for format in [DataFormat::H264, DataFormat::H265, DataFormat::MJPEG] {
+        let descriptor = CodecDescriptor::from(format);
+        assert_eq!(DataFormat::from(descriptor), format);
+        assert_eq!(
+            (descriptor.bit_depth, descriptor.chroma),
+            (8, ChromaFormat::Yuv420)
+        );
+        assert!(CodecDescriptor::known(format).contains(&descriptor));
+    }
+    assert_eq!(
+        CodecDescriptor::from(DataFormat::H264).profile,
+        CodecProfile::H264High
+    );
+    assert_eq!(
+        CodecDescriptor::from(DataFormat::H265).profile,
+        CodecProfile::HevcMain
+    );
+}
+
+// peers of other versions read what is sent, the form must not change
+#[test]
+fn serialization_is_stable() {
+    let main10 = CodecDescriptor {
+        format: DataFormat::H265,
+        profile: CodecProfile::HevcMain10,
+        bit_depth: 10,
+        chroma: ChromaFormat::Yuv420,
+    };
+    let json = r#"{"format":"H265","profile":"HevcMain10","bit_depth":10,"chroma":"Yuv420"}"#;
+    assert_eq!(serde_json::to_string(&main10).unwrap(), json);
+    assert_eq!(
+        serde_json::from_str::<CodecDescriptor>(json).unwrap(),
+        main10
+    );
+}
+
+#[test]
+fn decode_caps_support() {
+    let caps = DecodeCaps {
+        maxWidth: 4096,
+        maxHeight: 4096,
+        maxLevel: 153,
+        profiles: DecodeProfile::DECODE_PROFILE_HEVC_MAIN as u32,
+        bitDepths: 1 << 8,
+    };
+    let supported: Vec<_> = CodecDescriptor::known(DataFormat::H265)
+        .into_iter()
+        .filter(|d| caps.supports(d))
+        .map(|d| d.profile)
+        .collect();
+    assert_eq!(supported, [CodecProfile::HevcMain]);
+    let main444 = CodecDescriptor {
+        chroma: ChromaFormat::Yuv444,
+        ..CodecDescriptor::from(DataFormat::H265)
+    };
+    assert!(!caps.supports(&main444));
+    // nothing known, 8 bit 4:2:0 is assumed
+    let unknown = DecodeCaps::default();
+    assert!(unknown.supports(&CodecDescriptor::from(DataFormat::H264)));
+    let high10 = CodecDescriptor::known(DataFormat::H264)
+        .into_iter()
+        .find(|d| d.profile == CodecProfile::H264High10)
+        .unwrap();
+    assert!(!unknown.supports(&high10));
+}
+
+#[test]
+fn entropy_coding() {
+    use EntropyCoding::*;
+    let supported = |d: &CodecDescriptor| {
+        [
+            ENTROPY_CODING_DEFAULT,
+            ENTROPY_CODING_CABAC,
+            ENTROPY_CODING_CAVLC,
+        ]
+        .into_iter()
+        .filter(|c| d.supports_entropy_coding(*c))
+        .collect::<Vec<_>>()
+    };
+    let h264 = CodecDescriptor::known(DataFormat::H264);
+    assert_eq!(
+        supported(&h264[0]),
+        [ENTROPY_CODING_DEFAULT, ENTROPY_CODING_CAVLC]
+    );
+    for d in &h264[1..] {
+        assert_eq!(supported(d).len(), 3, "{:?}", d.profile);
+    }
+    let h265 = CodecDescriptor::from(DataFormat::H265);
+    assert_eq!(supported(&h265), [ENTROPY_CODING_DEFAULT]);
+}
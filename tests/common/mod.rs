@@ -0,0 +1,150 @@
+// The contexts, backend and driver shared by the tests of custom backends, each test uses
+// a part of them.
+#![allow(dead_code)]
+
+use hwcodec::{
+    common::{DataFormat, Driver, EncodeCaps, MemoryInfo},
+    vram::{
+        backend::{self, EncodeBackend, EncodeDriver},
+        encode::EncodeFrame,
+        DecodeContext, DynamicContext, EncodeContext, FeatureContext, OutputOrder,
+    },
+};
+use std::{
+    ffi::c_void,
+    sync::{Arc, Mutex},
+};
+
+// 640x480 h264 of the custom driver name on adapter 1, the tests change what they test
+pub fn encode_context(name: &str) -> EncodeContext {
+    EncodeContext {
+        f: FeatureContext {
+            driver: Driver::CUSTOM(name.to_owned()),
+            vendor: Driver::NV,
+            luid: 1,
+            data_format: DataFormat::H264,
+            caps: EncodeCaps::default(),
+        },
+        d: DynamicContext {
+            width: 640,
+            height: 480,
+            kbitrate: 2000,
+            framerate: 30,
+            gop: i32::MAX,
+            ..Default::default()
+        },
+    }
+}
+
+pub fn decode_context(name: &str) -> DecodeContext {
+    DecodeContext {
+        device: None,
+        driver: Driver::CUSTOM(name.to_owned()),
+        vendor: Driver::CUSTOM(name.to_owned()),
+        luid: 0,
+        data_format: DataFormat::H264,
+        output_order: OutputOrder::Decode,
+        conceal_errors: false,
+    }
+}
+
+// what the fakes sharing it were asked for
+#[derive(Default)]
+pub struct Log {
+    // width and kbitrate of each session the driver created
+    pub created: Vec<(i32, i32)>,
+    pub bitrates: Vec<i32>,
+    pub keyframes: usize,
+}
+
+// One frame of data per call, the first one and those after a request are keys. The fakes
+// sharing a log hold it, while alive its strong count is one more than theirs.
+pub struct Fake {
+    pub data: Vec<u8>,
+    pub key: bool,
+    pub memory: i64,
+    pub log: Arc<Mutex<Log>>,
+    pub frames: Vec<EncodeFrame>,
+}
+
+impl Fake {
+    pub fn new(data: &[u8], log: &Arc<Mutex<Log>>) -> Self {
+        Self {
+            data: data.to_vec(),
+            key: true,
+            memory: 0,
+            log: log.clone(),
+            frames: vec![],
+        }
+    }
+}
+
+impl EncodeBackend for Fake {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.frames.clear();
+        let key = std::mem::take(&mut self.key);
+        self.frames
+            .push(EncodeFrame::new(self.data.clone(), ms, key));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, kbs: i32) -> Result<(), i32> {
+        self.log.lock().unwrap().bitrates.push(kbs);
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.key = true;
+        self.log.lock().unwrap().keyframes += 1;
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> MemoryInfo {
+        MemoryInfo {
+            estimated: self.memory,
+            allocated: 0,
+        }
+    }
+}
+
+type Found = dyn Fn(DataFormat) -> Vec<(i64, Driver)> + Send + Sync;
+type Create = dyn Fn(&EncodeContext) -> Result<Box<dyn EncodeBackend>, ()> + Send + Sync;
+
+// found on the adapters of found for each format, creates its sessions with create
+pub struct FakeDriver {
+    name: String,
+    found: Box<Found>,
+    create: Box<Create>,
+}
+
+impl EncodeDriver for FakeDriver {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn test(&self, format: DataFormat, _d: &DynamicContext) -> Vec<(i64, Driver)> {
+        (self.found)(format)
+    }
+
+    fn create(&self, ctx: &EncodeContext) -> Result<Box<dyn EncodeBackend>, ()> {
+        (self.create)(ctx)
+    }
+}
+
+// Registered for the process under name, the tests run in parallel with names of their
+// own. Found nowhere, Encoder::new still creates the sessions of contexts of its name.
+pub fn register(
+    name: &str,
+    found: impl Fn(DataFormat) -> Vec<(i64, Driver)> + Send + Sync + 'static,
+    create: impl Fn(&EncodeContext) -> Result<Box<dyn EncodeBackend>, ()> + Send + Sync + 'static,
+) {
+    backend::register_encode_driver(Arc::new(FakeDriver {
+        name: name.to_owned(),
+        found: Box::new(found),
+        create: Box::new(create),
+    }));
+}
@@ -0,0 +1,223 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::decode_context;
+use hwcodec::{
+    bitstream::assemble::AccessUnitAssembler,
+    common::{DataFormat, HwcodecErrno},
+    vram::{
+        backend::DecodeBackend,
+        decode::{DecodeFrame, Decoder},
+        DecodeContext,
+    },
+};
+
+// see tests/bitstream.rs, decode order I P B P B P B
+const H264_BFRAMES: &[u8] = include_bytes!("fixtures/bframes.h264");
+
+// outputs a frame for every packet, like a session concealing whatever is missing
+#[derive(Default)]
+struct Concealing {
+    decoded: usize,
+    frames: Vec<DecodeFrame>,
+    // fails on other packets, like a session on slice data it can't parse
+    intact: Option<Vec<Vec<u8>>>,
+}
+
+impl DecodeBackend for Concealing {
+    fn decode(&mut self, packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
+        if let Some(intact) = self.intact.as_ref() {
+            if !intact.iter().any(|p| p == packet) {
+                return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+            }
+        }
+        self.decoded += 1;
+        self.frames.clear();
+        self.frames.push(DecodeFrame {
+            // tells the frames apart
+            width: 64 + self.decoded as i32,
+            height: 64,
+            ..Default::default()
+        });
+        Ok(&mut self.frames)
+    }
+}
+
+fn access_units() -> Vec<Vec<u8>> {
+    let mut packets = vec![];
+    let mut assembler = AccessUnitAssembler::new(DataFormat::H264).unwrap();
+    let mut collect = |au: &[u8]| {
+        packets.push(au.to_vec());
+        Ok(())
+    };
+    assembler.push(H264_BFRAMES, &mut collect).unwrap();
+    assembler.flush(&mut collect).unwrap();
+    assert_eq!(packets.len(), 7);
+    packets
+}
+
+fn decoder(conceal_errors: bool) -> Decoder {
+    decoder_of(conceal_errors, Concealing::default())
+}
+
+fn context(conceal_errors: bool) -> DecodeContext {
+    DecodeContext {
+        conceal_errors,
+        ..decode_context("conceal-test")
+    }
+}
+
+fn decoder_of(conceal_errors: bool, backend: Concealing) -> Decoder {
+    Decoder::from_backend(Box::new(backend), context(conceal_errors))
+}
+
+// Some(corrupted) of the frame of packet, None if it was discarded with NEED_KEYFRAME
+fn decode(decoder: &mut Decoder, packet: &[u8]) -> Option<bool> {
+    match decoder.decode(packet) {
+        Ok(frames) => {
+            assert_eq!(frames.len(), 1);
+            Some(frames[0].corrupted)
+        }
+        Err(e) => {
+            assert!(HwcodecErrno::is_keyframe_needed(e), "{}", e);
+            None
+        }
+    }
+}
+
+#[test]
+fn intact_stream_is_not_corrupted() {
+    for conceal_errors in [false, true] {
+        let mut decoder = decoder(conceal_errors);
+        for packet in access_units() {
+            assert_eq!(decode(&mut decoder, &packet), Some(false));
+        }
+    }
+}
+
+#[test]
+fn lost_reference_marks_frames_corrupted() {
+    let packets = access_units();
+    let mut decoder = decoder(true);
+    assert_eq!(decode(&mut decoder, &packets[0]), Some(false));
+    // the first P is lost, everything after it refers to it
+    for packet in &packets[2..] {
+        assert_eq!(decode(&mut decoder, packet), Some(true));
+    }
+    // the IDR ends the damage
+    assert_eq!(decode(&mut decoder, &packets[0]), Some(false));
+    assert_eq!(decode(&mut decoder, &packets[1]), Some(false));
+}
+
+#[test]
+fn lost_reference_discards_until_idr() {
+    let packets = access_units();
+    let mut decoder = decoder(false);
+    assert_eq!(decode(&mut decoder, &packets[0]), Some(false));
+    for packet in &packets[2..] {
+        assert_eq!(decode(&mut decoder, packet), None);
+    }
+    assert_eq!(decode(&mut decoder, &packets[0]), Some(false));
+    for packet in &packets[1..] {
+        assert_eq!(decode(&mut decoder, packet), Some(false));
+    }
+}
+
+#[test]
+fn stream_starting_without_idr() {
+    let packets = access_units();
+    let mut decoder = decoder(false);
+    // a slice before any SPS doesn't pass validation
+    decoder.set_validation(false);
+    assert_eq!(decode(&mut decoder, &packets[1]), None);
+    assert_eq!(decode(&mut decoder, &packets[0]), Some(false));
+}
+
+// the first P damaged past the start of its slice header, which validation reads
+fn corrupted_p_frame(packets: &[Vec<u8>]) -> Vec<u8> {
+    let mut packet = packets[1].clone();
+    let len = packet.len();
+    packet[len - 4..].iter_mut().for_each(|b| *b ^= 0x5a);
+    packet
+}
+
+#[test]
+fn corrupted_packet_repeats_last_frame() {
+    let packets = access_units();
+    let backend = Concealing {
+        intact: Some(packets.clone()),
+        ..Default::default()
+    };
+    let mut decoder = decoder_of(true, backend);
+    let frames = decoder.decode_with_pts(&packets[0], 0).unwrap();
+    assert_eq!(frames.len(), 1);
+    let width = frames[0].width;
+    let frames = decoder
+        .decode_with_pts(&corrupted_p_frame(&packets), 1)
+        .unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].width, width);
+    assert_eq!(frames[0].pts, 1);
+    assert!(frames[0].corrupted);
+    // the packets after it refer to the damaged picture
+    for packet in &packets[2..] {
+        assert_eq!(decode(&mut decoder, packet), Some(true));
+    }
+    assert_eq!(decode(&mut decoder, &packets[0]), Some(false));
+}
+
+#[test]
+fn corrupted_packet_without_concealment() {
+    let packets = access_units();
+    let backend = Concealing {
+        intact: Some(packets.clone()),
+        ..Default::default()
+    };
+    let mut decoder = decoder_of(false, backend);
+    assert_eq!(decode(&mut decoder, &packets[0]), Some(false));
+    assert_eq!(decode(&mut decoder, &corrupted_p_frame(&packets)), None);
+    for packet in &packets[2..] {
+        assert_eq!(decode(&mut decoder, packet), None);
+    }
+    assert_eq!(decode(&mut decoder, &packets[0]), Some(false));
+}
+
+#[test]
+fn corrupted_first_packet_is_an_error() {
+    let packets = access_units();
+    let backend = Concealing {
+        intact: Some(vec![]),
+        ..Default::default()
+    };
+    let mut decoder = decoder_of(true, backend);
+    // nothing decoded to show in its place
+    assert_eq!(
+        decoder.decode(&packets[0]).err(),
+        Some(HwcodecErrno::HWCODEC_ERR_COMMON as i32)
+    );
+}
+
+// a context without conceal_errors, e.g. one serialized before it existed, gets the errors
+// of packets failing validation or decoding
+#[test]
+fn errors_returned_by_default() {
+    let mut json = serde_json::to_value(context(true)).unwrap();
+    json.as_object_mut().unwrap().remove("conceal_errors");
+    let ctx: DecodeContext = serde_json::from_value(json).unwrap();
+    assert!(!ctx.conceal_errors);
+    let packets = access_units();
+    let backend = Concealing {
+        intact: Some(vec![packets[0].clone()]),
+        ..Default::default()
+    };
+    let mut decoder = Decoder::from_backend(Box::new(backend), ctx);
+    decoder.decode(&packets[0]).unwrap();
+    // forbidden_zero_bit
+    let invalid = [&[0, 0, 0, 1, 0x80][..], &packets[1]].concat();
+    assert_eq!(
+        decoder.decode(&invalid).err(),
+        Some(HwcodecErrno::HWCODEC_ERR_INVALID_DATA as i32)
+    );
+    assert!(decoder.decode(&packets[1]).is_err());
+}
@@ -0,0 +1,107 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::decode_context;
+use hwcodec::{
+    common::{DecodeCaps, DecodeProfile, HwcodecErrno},
+    vram::{
+        backend::DecodeBackend,
+        decode::{CapsLimit, DecodeFrame, Decoder},
+    },
+};
+
+// see tests/bitstream.rs, 64x64 main profile, the IDR with the parameter sets first
+const H264_BFRAMES: &[u8] = include_bytes!("fixtures/bframes.h264");
+
+struct Limited {
+    caps: DecodeCaps,
+    frames: Vec<DecodeFrame>,
+}
+
+impl DecodeBackend for Limited {
+    fn decode(&mut self, _packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
+        self.frames.clear();
+        self.frames.push(DecodeFrame {
+            width: 64,
+            height: 64,
+            ..Default::default()
+        });
+        Ok(&mut self.frames)
+    }
+
+    fn caps(&self) -> DecodeCaps {
+        self.caps
+    }
+}
+
+fn decoder(caps: DecodeCaps) -> Decoder {
+    let mut ctx = decode_context("caps-test");
+    ctx.conceal_errors = true;
+    let backend = Limited {
+        caps,
+        frames: vec![],
+    };
+    Decoder::from_backend(Box::new(backend), ctx)
+}
+
+fn h264_caps() -> DecodeCaps {
+    DecodeCaps {
+        maxWidth: 4096,
+        maxHeight: 4096,
+        maxLevel: 51,
+        profiles: DecodeProfile::DECODE_PROFILE_H264_MAIN as u32
+            | DecodeProfile::DECODE_PROFILE_H264_HIGH as u32,
+        bitDepths: 1 << 8,
+    }
+}
+
+// the limit the IDR fails with, None if it decodes
+fn exceeded(caps: DecodeCaps) -> Option<CapsLimit> {
+    let mut decoder = decoder(caps);
+    assert_eq!(decoder.caps(), caps);
+    match decoder.decode(H264_BFRAMES) {
+        Ok(_) => {
+            assert_eq!(decoder.exceeded_caps(), None);
+            None
+        }
+        Err(e) => {
+            assert!(HwcodecErrno::is_caps_exceeded(e), "{}", e);
+            decoder.exceeded_caps()
+        }
+    }
+}
+
+#[test]
+fn within_caps() {
+    assert_eq!(exceeded(h264_caps()), None);
+    // unknown caps aren't checked
+    assert_eq!(exceeded(DecodeCaps::default()), None);
+}
+
+#[test]
+fn beyond_caps() {
+    let mut caps = h264_caps();
+    caps.maxWidth = 32;
+    assert_eq!(
+        exceeded(caps),
+        Some(CapsLimit::Size {
+            width: 64,
+            height: 64,
+            max_width: 32,
+            max_height: 4096,
+        })
+    );
+    let mut caps = h264_caps();
+    caps.maxLevel = 1;
+    assert!(matches!(
+        exceeded(caps),
+        Some(CapsLimit::Level { max_level: 1, .. })
+    ));
+    let mut caps = h264_caps();
+    caps.profiles = DecodeProfile::DECODE_PROFILE_H264_BASELINE as u32;
+    assert_eq!(exceeded(caps), Some(CapsLimit::Profile(77)));
+    let mut caps = h264_caps();
+    caps.bitDepths = 1 << 10;
+    assert_eq!(exceeded(caps), Some(CapsLimit::BitDepth(8)));
+}
@@ -0,0 +1,66 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::decode_context;
+use hwcodec::vram::{
+    backend::DecodeBackend,
+    decode::{Crop, DecodeFrame, Decoder},
+};
+
+// see tests/bitstream.rs, 1920x1088 coded, cropped to 1080
+const H264_1080P_CROPPED: &[u8] = include_bytes!("fixtures/1080p_cropped.h264");
+const H264_720P: &[u8] = include_bytes!("../src/res/720p.h264");
+
+// a session reporting its texture, allocated at the coded size, as the frame's size
+struct Coded {
+    frames: Vec<DecodeFrame>,
+}
+
+impl DecodeBackend for Coded {
+    fn decode(&mut self, _packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
+        self.frames.clear();
+        self.frames.push(DecodeFrame {
+            width: 1920,
+            height: 1088,
+            ..Default::default()
+        });
+        Ok(&mut self.frames)
+    }
+}
+
+fn decoder() -> Decoder {
+    let ctx = decode_context("crop-test");
+    Decoder::from_backend(Box::new(Coded { frames: vec![] }), ctx)
+}
+
+#[test]
+fn frames_carry_the_sps_crop() {
+    let mut decoder = decoder();
+    let frames = decoder.decode(H264_1080P_CROPPED).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!((frames[0].width, frames[0].height), (1920, 1080));
+    assert_eq!(
+        frames[0].crop,
+        Crop {
+            left: 0,
+            top: 0,
+            right: 0,
+            bottom: 8
+        }
+    );
+
+    // until the next sps
+    let frames = decoder.decode(H264_720P).unwrap();
+    assert_eq!((frames[0].width, frames[0].height), (1280, 720));
+    assert_eq!(frames[0].crop, Crop::default());
+}
+
+#[test]
+fn frames_without_validation_are_of_the_texture() {
+    let mut decoder = decoder();
+    decoder.set_validation(false);
+    let frames = decoder.decode(H264_1080P_CROPPED).unwrap();
+    assert_eq!((frames[0].width, frames[0].height), (1920, 1088));
+    assert_eq!(frames[0].crop.bottom, 8);
+}
@@ -0,0 +1,85 @@
+// Needs an ffmpeg build, the hardware cases are skipped without a hardware h264 decoder.
+// The failing ones need --features testutil.
+use hwcodec::{
+    common::DataFormat,
+    ffmpeg::AVHWDeviceType,
+    ffmpeg_ram::decode::{DecodeContext, Decoder, SoftwareFallback},
+};
+
+// an IDR at 720p
+const H264_720P: &[u8] = include_bytes!("../src/res/720p.h264");
+
+fn hardware_context() -> Option<DecodeContext> {
+    Decoder::available_decoders()
+        .into_iter()
+        .find(|c| {
+            c.format == DataFormat::H264 && c.hwdevice != AVHWDeviceType::AV_HWDEVICE_TYPE_NONE
+        })
+        .map(|c| DecodeContext {
+            name: c.name,
+            device_type: c.hwdevice,
+            thread_count: 4,
+        })
+}
+
+fn assert_decoded(decoder: &mut Decoder) {
+    let frames = decoder.decode(H264_720P).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!((frames[0].width, frames[0].height), (1280, 720));
+}
+
+#[cfg(feature = "testutil")]
+#[test]
+fn falls_back_on_error() {
+    let Some(ctx) = hardware_context() else {
+        println!("no hardware h264 decoder, skipped");
+        return;
+    };
+    let mut decoder = Decoder::new(ctx).unwrap();
+    decoder
+        .set_software_fallback(SoftwareFallback::OnError)
+        .unwrap();
+    assert_decoded(&mut decoder);
+    assert!(!decoder.is_software());
+    decoder.debug_fail_next_decode();
+    // the failed packet is decoded again in software
+    assert_decoded(&mut decoder);
+    assert!(decoder.is_software());
+    assert_eq!(
+        decoder.ctx.device_type,
+        AVHWDeviceType::AV_HWDEVICE_TYPE_NONE
+    );
+    assert_decoded(&mut decoder);
+}
+
+#[cfg(feature = "testutil")]
+#[test]
+fn error_returned_without_fallback() {
+    let Some(ctx) = hardware_context() else {
+        println!("no hardware h264 decoder, skipped");
+        return;
+    };
+    let mut decoder = Decoder::new(ctx).unwrap();
+    decoder.debug_fail_next_decode();
+    assert!(decoder.decode(H264_720P).is_err());
+    assert!(!decoder.is_software());
+    assert_decoded(&mut decoder);
+}
+
+#[test]
+fn forced_software() {
+    let ctx = hardware_context().unwrap_or(DecodeContext {
+        name: "h264".to_owned(),
+        device_type: AVHWDeviceType::AV_HWDEVICE_TYPE_NONE,
+        thread_count: 4,
+    });
+    let mut decoder = Decoder::new(ctx).unwrap();
+    decoder
+        .set_software_fallback(SoftwareFallback::Force)
+        .unwrap();
+    assert!(decoder.is_software());
+    // software decoders ignore the simulated hardware error
+    #[cfg(feature = "testutil")]
+    decoder.debug_fail_next_decode();
+    assert_decoded(&mut decoder);
+}
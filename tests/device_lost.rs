@@ -0,0 +1,65 @@
+// Enabled with --features gpu-tests. A single test, debug_set_device_lost fails the encodes
+// of every session in the process.
+#![cfg(all(windows, feature = "gpu-tests"))]
+
+use hwcodec::{
+    common::{DataFormat, HwcodecErrno, MAX_GOP},
+    testutil::{bgra_pattern, Device, Texture},
+    vram::{
+        debug_set_device_lost,
+        encode::{best_encoder, Encoder},
+        DynamicContext, EncodeContext,
+    },
+};
+
+const WIDTH: i32 = 640;
+const HEIGHT: i32 = 480;
+
+// the encoder reports the lost device, recovers on a new one and rejects a texture of
+// another device, like a capture recreated after a session switch, without losing itself
+#[test]
+fn recovers_after_device_lost() {
+    let mut d = DynamicContext {
+        width: WIDTH,
+        height: HEIGHT,
+        kbitrate: 2000,
+        framerate: 30,
+        gop: MAX_GOP as _,
+        ..Default::default()
+    };
+    let Some(f) = best_encoder(d, &[DataFormat::H264, DataFormat::H265]) else {
+        return;
+    };
+    let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+    let device = Device::new(f.luid).unwrap();
+    d.device = Some(device.as_ptr());
+    let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+    let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+    for i in 0..3 {
+        encoder.encode(texture.as_ptr(), i).unwrap();
+    }
+    assert!(encoder.is_healthy());
+
+    debug_set_device_lost(true);
+    let err = encoder.encode(texture.as_ptr(), 3).unwrap_err();
+    debug_set_device_lost(false);
+    assert_eq!(err, HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as i32);
+    assert!(HwcodecErrno::is_codec_lost(err));
+    assert!(!encoder.is_healthy());
+
+    drop(texture);
+    drop(device);
+    let device = Device::new(f.luid).unwrap();
+    encoder.recreate_after_device_lost(device.as_ptr()).unwrap();
+    let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+    let frames = encoder.encode(texture.as_ptr(), 4).unwrap();
+    assert!(frames.iter().any(|f| f.key == 1));
+    assert!(encoder.is_healthy());
+
+    let other = Device::new(f.luid).unwrap();
+    let stale = Texture::from_bgra(other.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+    let err = encoder.encode(stale.as_ptr(), 5).unwrap_err();
+    assert!(HwcodecErrno::is_input_lost(err));
+    assert!(encoder.is_healthy());
+    encoder.encode(texture.as_ptr(), 6).unwrap();
+}
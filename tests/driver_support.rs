@@ -0,0 +1,83 @@
+#![cfg(all(windows, feature = "vram"))]
+
+use hwcodec::common::{driver_info, Driver, DriverInfo};
+#[cfg(feature = "gpu-tests")]
+use hwcodec::vram::{
+    debug_driver_support_probes, decode, encode, invalidate_driver_support, DynamicContext,
+};
+
+// nv encode, amf and mfx
+#[cfg(feature = "gpu-tests")]
+const PROBES: usize = 3;
+
+#[cfg(feature = "gpu-tests")]
+fn available() {
+    encode::available(DynamicContext {
+        width: 1280,
+        height: 720,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: 60,
+        ..Default::default()
+    });
+    decode::available();
+}
+
+// the probes of the drivers installed, enabled with --features gpu-tests
+#[cfg(feature = "gpu-tests")]
+#[test]
+fn probed_once_until_invalidated() {
+    available();
+    let probes = debug_driver_support_probes();
+    assert!(probes <= PROBES);
+    available();
+    available();
+    assert_eq!(debug_driver_support_probes(), probes);
+    // the versions come from the same probes
+    for driver in [Driver::NV, Driver::AMF, Driver::MFX] {
+        if let Some(info) = driver_info(driver) {
+            if info.supported {
+                assert!(info.installed.is_some() && !info.too_old, "{:?}", info);
+            }
+        }
+    }
+    assert_eq!(debug_driver_support_probes(), probes);
+    invalidate_driver_support();
+    available();
+    let again = debug_driver_support_probes() - probes;
+    assert!(again <= PROBES);
+    if cfg!(any(feature = "nv", feature = "amf", feature = "mfx")) {
+        assert!(again > 0);
+    }
+    available();
+    assert_eq!(debug_driver_support_probes(), probes + again);
+}
+
+#[test]
+fn driver_info_without_probe() {
+    assert_eq!(driver_info(Driver::FFMPEG), None);
+    assert_eq!(
+        driver_info(Driver::CUSTOM("driver-info-test".to_owned())),
+        None
+    );
+}
+
+#[test]
+fn driver_info_display() {
+    let mut info = DriverInfo {
+        name: "NVIDIA driver".to_owned(),
+        installed: Some("456.71".to_owned()),
+        required: Some("522.25".to_owned()),
+        too_old: true,
+        supported: false,
+    };
+    assert_eq!(info.to_string(), "driver too old: have 456.71, need 522.25");
+    info.installed = Some("531.61".to_owned());
+    info.too_old = false;
+    info.supported = true;
+    assert_eq!(info.to_string(), "NVIDIA driver 531.61");
+    info.supported = false;
+    assert_eq!(info.to_string(), "NVIDIA driver 531.61, not usable");
+    info.installed = None;
+    assert_eq!(info.to_string(), "NVIDIA driver not found");
+}
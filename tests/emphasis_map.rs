@@ -0,0 +1,64 @@
+#![cfg(all(windows, feature = "vram"))]
+
+use hwcodec::vram::encode::{emphasis_map, emphasis_map_size, MAX_EMPHASIS_LEVEL};
+
+#[test]
+fn size_rounds_up() {
+    assert_eq!(emphasis_map_size(1920, 1080), (120, 68));
+    assert_eq!(emphasis_map_size(1366, 769), (86, 49));
+    assert_eq!(emphasis_map(1366, 769, &[]).len(), 86 * 49);
+}
+
+#[test]
+fn rects_cover_touched_macroblocks() {
+    // 4x3 macroblocks
+    let map = emphasis_map(64, 48, &[([15, 16, 17, 32], 2)]);
+    #[rustfmt::skip]
+    let expected = [
+        0, 0, 0, 0,
+        2, 2, 0, 0,
+        0, 0, 0, 0,
+    ];
+    assert_eq!(map, expected);
+}
+
+#[test]
+fn overlaps_keep_highest_level() {
+    let map = emphasis_map(
+        64,
+        48,
+        &[
+            ([0, 0, 64, 48], 1),
+            ([40, 20, 50, 30], 4),
+            ([32, 16, 48, 32], 3),
+        ],
+    );
+    #[rustfmt::skip]
+    let expected = [
+        1, 1, 1, 1,
+        1, 1, 4, 4,
+        1, 1, 1, 1,
+    ];
+    assert_eq!(map, expected);
+}
+
+#[test]
+fn rects_clipped_to_frame() {
+    let map = emphasis_map(
+        64,
+        48,
+        &[
+            ([-100, -100, 1, 1], 1),
+            ([60, 40, 1000, 1000], 9),
+            ([64, 0, 80, 48], 5),
+            ([10, 10, 10, 20], 5),
+        ],
+    );
+    #[rustfmt::skip]
+    let expected = [
+        1, 0, 0, 0,
+        0, 0, 0, 0,
+        0, 0, 0, MAX_EMPHASIS_LEVEL,
+    ];
+    assert_eq!(map, expected);
+}
@@ -0,0 +1,192 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    common::HwcodecErrno,
+    vram::{
+        backend::EncodeBackend,
+        encode::{EncodeFrame, Encoder, RecoveryStats},
+        EncodeContext, RecoveryPolicy,
+    },
+};
+use std::{
+    collections::VecDeque,
+    ffi::c_void,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+const COMMON: i32 = HwcodecErrno::HWCODEC_ERR_COMMON as i32;
+const SESSION_LOST: i32 = HwcodecErrno::HWCODEC_ERR_SESSION_LOST as i32;
+
+// The encode calls fail with the errors of failures, one each, 0 encodes. A new session
+// starts with a key, recreating it fails without recreatable.
+struct Flaky {
+    failures: VecDeque<i32>,
+    recreatable: bool,
+    key: bool,
+    calls: Arc<AtomicUsize>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Flaky {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        match self.failures.pop_front() {
+            Some(e) if e != 0 => return Err(e),
+            _ => {}
+        }
+        self.frames.clear();
+        self.frames.push(EncodeFrame::new(
+            vec![0, 0, 0, 1, 0x41],
+            ms,
+            std::mem::take(&mut self.key),
+        ));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn recreate(&mut self, _ctx: &EncodeContext) -> Result<(), ()> {
+        if !self.recreatable {
+            return Err(());
+        }
+        self.key = true;
+        Ok(())
+    }
+}
+
+fn encoder(
+    recovery: Option<RecoveryPolicy>,
+    failures: &[i32],
+    recreatable: bool,
+) -> (Encoder, Arc<AtomicUsize>) {
+    let mut ctx = encode_context("recovery-test");
+    ctx.d.recovery = recovery;
+    let calls = Arc::new(AtomicUsize::new(0));
+    let backend = Flaky {
+        failures: failures.iter().copied().collect(),
+        recreatable,
+        key: false,
+        calls: calls.clone(),
+        frames: vec![],
+    };
+    (Encoder::from_backend(Box::new(backend), ctx), calls)
+}
+
+fn policy(retries: u32, reinit_after: u32) -> Option<RecoveryPolicy> {
+    Some(RecoveryPolicy {
+        retries,
+        backoff_ms: 1,
+        reinit_after,
+    })
+}
+
+// Ok(key) of the frame of an encode call
+fn encode(encoder: &mut Encoder, ms: i64) -> Result<bool, i32> {
+    encoder
+        .encode(null_mut(), ms)
+        .map(|frames| frames[0].key != 0)
+}
+
+#[test]
+fn no_policy_returns_the_error() {
+    let (mut encoder, calls) = encoder(None, &[COMMON], true);
+    assert_eq!(encode(&mut encoder, 0), Err(COMMON));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(encode(&mut encoder, 1), Ok(false));
+    assert_eq!(encoder.recovery_stats(), RecoveryStats::default());
+}
+
+#[test]
+fn retries_the_same_frame() {
+    let (mut encoder, calls) = encoder(policy(3, 0), &[COMMON, COMMON], true);
+    assert_eq!(encode(&mut encoder, 0), Ok(false));
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    let stats = encoder.recovery_stats();
+    assert_eq!(
+        (stats.retries, stats.failed_frames, stats.reinits),
+        (2, 0, 0)
+    );
+    encoder.reset_stats();
+    assert_eq!(encoder.recovery_stats(), RecoveryStats::default());
+}
+
+#[test]
+fn frames_fail_after_their_retries() {
+    let (mut encoder, calls) = encoder(policy(1, 0), &[COMMON; 4], true);
+    assert_eq!(encode(&mut encoder, 0), Err(COMMON));
+    assert_eq!(encode(&mut encoder, 1), Err(COMMON));
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+    assert_eq!(encode(&mut encoder, 2), Ok(false));
+    let stats = encoder.recovery_stats();
+    assert_eq!(
+        (stats.retries, stats.failed_frames, stats.reinits),
+        (2, 2, 0)
+    );
+}
+
+#[test]
+fn reinit_after_consecutive_failures() {
+    let (mut encoder, _) = encoder(policy(1, 2), &[COMMON; 4], true);
+    assert_eq!(encode(&mut encoder, 0), Err(COMMON));
+    // the second frame failing in a row is encoded by a new session
+    assert_eq!(encode(&mut encoder, 1), Ok(true));
+    assert_eq!(encode(&mut encoder, 2), Ok(false));
+    let stats = encoder.recovery_stats();
+    assert_eq!((stats.failed_frames, stats.reinits, stats.fatal), (2, 1, 0));
+}
+
+#[test]
+fn success_restarts_the_count() {
+    let (mut encoder, _) = encoder(policy(0, 2), &[COMMON, 0, COMMON], true);
+    assert_eq!(encode(&mut encoder, 0), Err(COMMON));
+    assert_eq!(encode(&mut encoder, 1), Ok(false));
+    assert_eq!(encode(&mut encoder, 2), Err(COMMON));
+    assert_eq!(encoder.recovery_stats().reinits, 0);
+}
+
+#[test]
+fn lost_session_is_recreated_at_once() {
+    let (mut encoder, calls) = encoder(policy(3, 5), &[SESSION_LOST], true);
+    assert_eq!(encode(&mut encoder, 0), Ok(true));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    let stats = encoder.recovery_stats();
+    assert_eq!((stats.retries, stats.reinits), (0, 1));
+}
+
+#[test]
+fn failed_reinit_is_fatal() {
+    let (mut encoder, _) = encoder(policy(0, 1), &[COMMON], false);
+    let reported = Arc::new(AtomicUsize::new(0));
+    let counter = reported.clone();
+    encoder.on_error(move |e| {
+        assert_eq!(e, SESSION_LOST);
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    assert_eq!(encode(&mut encoder, 0), Err(SESSION_LOST));
+    assert_eq!(reported.load(Ordering::SeqCst), 1);
+    assert_eq!(encoder.recovery_stats().fatal, 1);
+}
+
+#[test]
+fn caller_errors_are_not_retried() {
+    let lost = HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as i32;
+    let stale = HwcodecErrno::HWCODEC_ERR_INPUT_ACCESS_DENIED as i32;
+    let (mut encoder, calls) = encoder(policy(3, 1), &[lost, stale], true);
+    assert_eq!(encode(&mut encoder, 0), Err(lost));
+    assert_eq!(encode(&mut encoder, 1), Err(stale));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(encoder.recovery_stats(), RecoveryStats::default());
+}
@@ -0,0 +1,96 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::vram::{
+    backend::EncodeBackend,
+    encode::{EncodeFrame, EncodeSummary, Encoder},
+    EncodeContext,
+};
+use std::{ffi::c_void, ptr::null_mut, time::Duration};
+
+const FRAMERATE: i32 = 30;
+
+// outputs each frame one call late, every fourth is a key
+struct Lagging {
+    pending: Option<EncodeFrame>,
+    count: usize,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Lagging {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.frames.clear();
+        let frame = EncodeFrame::new(vec![0; self.count % 7 + 1], ms, self.count % 4 == 0);
+        self.count += 1;
+        self.frames.extend(self.pending.replace(frame));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<Vec<EncodeFrame>, i32> {
+        Ok(self.pending.take().into_iter().collect())
+    }
+
+    fn recreate(&mut self, _ctx: &EncodeContext) -> Result<(), ()> {
+        self.count = 0;
+        Ok(())
+    }
+}
+
+fn encoder() -> Encoder {
+    let mut ctx = encode_context("summary-test");
+    ctx.d.framerate = FRAMERATE;
+    let backend = Lagging {
+        pending: None,
+        count: 0,
+        frames: vec![],
+    };
+    Encoder::from_backend(Box::new(backend), ctx)
+}
+
+#[test]
+fn summary_matches_tally() {
+    let mut encoder = encoder();
+    assert_eq!(encoder.summary(), EncodeSummary::default());
+    let mut frames = vec![];
+    for i in 0..10 {
+        frames.append(encoder.encode(null_mut(), 100 + i * 33).unwrap());
+    }
+    // the frame buffered in the session isn't counted yet
+    assert_eq!(encoder.summary().frames, 9);
+    frames.append(&mut encoder.reset(1280, 720).unwrap());
+    for i in 10..15 {
+        frames.append(
+            encoder
+                .encode_with_user_data(null_mut(), 100 + i * 33, i as u64)
+                .unwrap(),
+        );
+    }
+    let summary = encoder.summary();
+    assert_eq!(summary.frames, frames.len() as u64);
+    assert_eq!(summary.frames, 14);
+    assert_eq!(
+        summary.bytes,
+        frames.iter().map(|f| f.data.len() as u64).sum::<u64>()
+    );
+    assert_eq!(
+        summary.keyframes,
+        frames.iter().filter(|f| f.key == 1).count() as u64
+    );
+    assert_eq!(summary.keyframes, 4);
+    let last = frames.iter().map(|f| f.pts).max().unwrap();
+    assert_eq!(last, 100 + 13 * 33);
+    assert_eq!(
+        summary.duration,
+        Duration::from_millis((last - 100) as u64) + Duration::from_secs(1) / FRAMERATE as u32
+    );
+}
@@ -0,0 +1,125 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::{encode_context, Fake, Log};
+use hwcodec::{
+    common::DataFormat,
+    vram::{encode::Encoder, pool::EncoderPool, EncodeContext},
+};
+use std::{
+    ptr::null_mut,
+    sync::{Arc, Mutex},
+};
+
+const MEMORY: i64 = 100;
+
+// the driver isn't registered, Encoder::new fails for contexts the pool can't serve
+fn context(width: i32, height: i32, kbitrate: i32) -> EncodeContext {
+    let mut ctx = encode_context("pool-test");
+    ctx.d.width = width;
+    ctx.d.height = height;
+    ctx.d.kbitrate = kbitrate;
+    ctx
+}
+
+// its frames carry its id, the sessions sharing the log are alive while they hold it
+fn fake(id: u8, ctx: EncodeContext, log: &Arc<Mutex<Log>>) -> Encoder {
+    let backend = Fake {
+        memory: MEMORY,
+        ..Fake::new(&[id], log)
+    };
+    Encoder::from_backend(Box::new(backend), ctx)
+}
+
+fn live(log: &Arc<Mutex<Log>>) -> usize {
+    Arc::strong_count(log) - 1
+}
+
+fn next_frame(encoder: &mut Encoder) -> (u8, i32) {
+    let frame = &encoder.encode(null_mut(), 0).unwrap()[0];
+    (frame.data[0], frame.key)
+}
+
+#[test]
+fn matching_encoder_resumes_with_keyframe() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut pool = EncoderPool::new(4, i64::MAX);
+    let mut encoder = fake(1, context(1920, 1080, 3000), &log);
+    assert_eq!(next_frame(&mut encoder), (1, 1));
+    assert_eq!(next_frame(&mut encoder), (1, 0));
+    pool.put(encoder);
+    pool.put(fake(2, context(1280, 720, 3000), &log));
+    assert_eq!(pool.len(), 2);
+
+    // same bitrate bucket, the bitrate is set on it
+    let mut encoder = pool.get(context(1920, 1080, 4000)).unwrap();
+    assert_eq!(encoder.ctx.d.kbitrate, 4000);
+    assert_eq!(next_frame(&mut encoder), (1, 1));
+    assert_eq!(pool.len(), 1);
+    assert_eq!(live(&log), 2);
+}
+
+#[test]
+fn mismatches_create_new_sessions() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut pool = EncoderPool::new(4, i64::MAX);
+    pool.put(fake(1, context(1920, 1080, 3000), &log));
+    assert!(pool.get(context(1920, 1088, 3000)).is_err());
+    assert!(pool.get(context(1920, 1080, 5000)).is_err());
+    let mut h265 = context(1920, 1080, 3000);
+    h265.f.data_format = DataFormat::H265;
+    assert!(pool.get(h265).is_err());
+    assert_eq!(pool.len(), 1);
+}
+
+#[test]
+fn least_recently_used_evicted_beyond_capacity() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut pool = EncoderPool::new(2, i64::MAX);
+    for (id, width) in [(1, 640), (2, 800), (3, 1024)] {
+        pool.put(fake(id, context(width, 480, 3000), &log));
+    }
+    assert_eq!(pool.len(), 2);
+    assert_eq!(live(&log), 2);
+    assert!(pool.get(context(640, 480, 3000)).is_err());
+    let mut encoder = pool.get(context(800, 480, 3000)).unwrap();
+    assert_eq!(next_frame(&mut encoder).0, 2);
+}
+
+#[test]
+fn budget_limits_idle_memory() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut pool = EncoderPool::new(8, MEMORY * 5 / 2);
+    for id in 0..4 {
+        pool.put(fake(id, context(640 + id as i32 * 16, 480, 3000), &log));
+    }
+    assert_eq!(pool.len(), 2);
+    assert_eq!(pool.idle_memory(), MEMORY * 2);
+    assert_eq!(live(&log), 2);
+    pool.clear();
+    assert_eq!(live(&log), 0);
+
+    let mut none = EncoderPool::new(0, i64::MAX);
+    none.put(fake(9, context(640, 480, 3000), &log));
+    assert!(none.is_empty());
+    assert_eq!(live(&log), 0);
+}
+
+#[test]
+fn quality_mode_sessions_stay_apart() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut pool = EncoderPool::new(4, i64::MAX);
+    // set_bitrate can't switch a session in or out of quality mode
+    let mut encoder = fake(1, context(1920, 1080, 0), &log);
+    assert!(encoder.set_bitrate(1).is_err());
+    pool.put(encoder);
+    assert!(pool.get(context(1920, 1080, 1)).is_err());
+    pool.put(fake(2, context(1920, 1080, 1), &log));
+    let mut encoder = pool.get(context(1920, 1080, 0)).unwrap();
+    assert!(encoder.set_bitrate(1).is_err());
+    assert_eq!(pool.len(), 1);
+    let mut encoder = pool.get(context(1920, 1080, 1)).unwrap();
+    assert!(encoder.set_bitrate(0).is_err());
+    assert_eq!(next_frame(&mut encoder), (2, 1));
+}
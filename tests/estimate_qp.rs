@@ -0,0 +1,82 @@
+use hwcodec::common::{estimate_kbitrate, estimate_qp, DataFormat, MAX_QP};
+
+const FORMATS: [DataFormat; 5] = [
+    DataFormat::H264,
+    DataFormat::H265,
+    DataFormat::VP8,
+    DataFormat::VP9,
+    DataFormat::AV1,
+];
+const SIZES: [(i32, i32, i32); 4] = [
+    (640, 480, 15),
+    (1280, 720, 30),
+    (1920, 1080, 60),
+    (3840, 2160, 30),
+];
+
+#[test]
+fn qp_non_increasing_in_bitrate() {
+    for format in FORMATS {
+        for (width, height, framerate) in SIZES {
+            let mut last = MAX_QP;
+            for kbitrate in (1..200_000).step_by(97) {
+                let qp = estimate_qp(format, width, height, framerate, kbitrate);
+                assert!((0.0..=MAX_QP).contains(&qp));
+                assert!(
+                    qp <= last,
+                    "{:?} {}x{} {}kbps",
+                    format,
+                    width,
+                    height,
+                    kbitrate
+                );
+                last = qp;
+            }
+            assert_eq!(estimate_qp(format, width, height, framerate, 1), MAX_QP);
+        }
+    }
+}
+
+#[test]
+fn bitrate_non_increasing_in_qp() {
+    for format in FORMATS {
+        for (width, height, framerate) in SIZES {
+            let mut last = i32::MAX;
+            for qp in 0..=(MAX_QP as i32 * 4) {
+                let kbitrate = estimate_kbitrate(format, width, height, framerate, qp as f32 / 4.0);
+                assert!(kbitrate >= 1);
+                assert!(
+                    kbitrate <= last,
+                    "{:?} {}x{} qp {}",
+                    format,
+                    width,
+                    height,
+                    qp
+                );
+                last = kbitrate;
+            }
+        }
+    }
+}
+
+#[test]
+fn inverse() {
+    for format in FORMATS {
+        for (width, height, framerate) in SIZES {
+            for qp in [10.0, 20.0, 26.0, 35.0, 45.0] {
+                let kbitrate = estimate_kbitrate(format, width, height, framerate, qp);
+                let estimated = estimate_qp(format, width, height, framerate, kbitrate);
+                assert!(
+                    (estimated - qp).abs() < 0.5,
+                    "{:?} qp {} -> {}",
+                    format,
+                    qp,
+                    estimated
+                );
+            }
+        }
+    }
+    // 1080p30 h264 around 6 Mbps lands in the usual range
+    let qp = estimate_qp(DataFormat::H264, 1920, 1080, 30, 6000);
+    assert!((20.0..32.0).contains(&qp));
+}
@@ -0,0 +1,38 @@
+#![cfg(all(windows, feature = "vram"))]
+
+use hwcodec::{
+    common::{DataFormat, Driver, EncodeCaps},
+    vram::{encode::Encoder, DynamicContext, EncodeContext, FeatureContext},
+};
+use std::ffi::c_void;
+
+fn ctx(driver: Driver) -> EncodeContext {
+    EncodeContext {
+        f: FeatureContext {
+            driver: driver.clone(),
+            vendor: driver,
+            luid: 1,
+            data_format: DataFormat::H264,
+            caps: EncodeCaps::default(),
+        },
+        d: DynamicContext {
+            width: 640,
+            height: 480,
+            kbitrate: 2000,
+            framerate: 30,
+            gop: i32::MAX,
+            ..Default::default()
+        },
+    }
+}
+
+// only MFX sessions are wrapped, the others fail before the handle is used
+#[test]
+fn other_drivers_rejected() {
+    let mut session = 0u64;
+    let handle = &mut session as *mut u64 as *mut c_void;
+    for driver in [Driver::NV, Driver::AMF, Driver::FFMPEG] {
+        assert!(Encoder::from_existing_session(driver.clone(), handle, ctx(driver)).is_err());
+    }
+    assert_eq!(session, 0);
+}
@@ -0,0 +1,84 @@
+#![cfg(all(windows, feature = "vram-ffmpeg"))]
+
+use hwcodec::{
+    common::{DataFormat, Driver},
+    vram::{
+        encode::{ffmpeg_options, ffmpeg_options_with, Encoder},
+        DynamicContext, EncodeContext, FeatureContext,
+    },
+};
+
+// derived on a context of the encoder, no adapter of the vendor is needed
+fn context(vendor: Driver) -> EncodeContext {
+    EncodeContext {
+        f: FeatureContext {
+            driver: Driver::FFMPEG,
+            vendor,
+            luid: 0,
+            data_format: DataFormat::H264,
+            caps: Default::default(),
+        },
+        d: DynamicContext {
+            width: 1920,
+            height: 1080,
+            kbitrate: 2000,
+            framerate: 30,
+            gop: 60,
+            ..Default::default()
+        },
+    }
+}
+
+fn get<'a>(options: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    options
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+fn overrides(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn derived_options() {
+    let nvenc = ffmpeg_options(&context(Driver::NV));
+    assert_eq!(get(&nvenc, "b"), Some("2000000"));
+    assert_eq!(get(&nvenc, "g"), Some("60"));
+    assert_eq!(get(&nvenc, "delay"), Some("0"));
+    assert_eq!(get(&nvenc, "forced-idr"), Some("true"));
+
+    // cbr with vbr
+    let qsv = ffmpeg_options(&context(Driver::MFX));
+    assert_eq!(get(&qsv, "b"), Some("1999999"));
+    assert_eq!(get(&qsv, "maxrate"), Some("2000000"));
+    assert_eq!(get(&qsv, "async_depth"), Some("1"));
+
+    let amf = ffmpeg_options(&context(Driver::AMF));
+    assert_eq!(get(&amf, "query_timeout"), Some("1000"));
+}
+
+#[test]
+fn overrides_replace_derived() {
+    let ctx = context(Driver::NV);
+    let options = ffmpeg_options_with(&ctx, &overrides(&[("g", "120"), ("delay", "2")])).unwrap();
+    assert_eq!(get(&options, "g"), Some("120"));
+    assert_eq!(get(&options, "delay"), Some("2"));
+    assert_eq!(get(&options, "b"), Some("2000000"));
+
+    assert!(ffmpeg_options_with(&ctx, &overrides(&[("no-such-option", "1")])).is_err());
+    assert!(ffmpeg_options_with(&ctx, &overrides(&[("g=1", "1")])).is_err());
+    assert!(ffmpeg_options_with(&ctx, &overrides(&[("g", "1\n")])).is_err());
+}
+
+#[test]
+fn other_drivers_take_no_options() {
+    let mut ctx = context(Driver::NV);
+    ctx.f.driver = Driver::NV;
+    assert!(ffmpeg_options(&ctx).is_empty());
+    assert!(ffmpeg_options_with(&ctx, &[]).is_err());
+    assert!(Encoder::new_with_ffmpeg_options(ctx, &overrides(&[("g", "120")])).is_err());
+}
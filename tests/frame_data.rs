@@ -0,0 +1,34 @@
+#![cfg(all(windows, feature = "vram", feature = "testutil"))]
+
+use hwcodec::{common::FrameData, vram::encode::debug_deliver_frames};
+
+// the frames of the native callback, cloned for several consumers without a copy
+#[test]
+fn clones_share_data() {
+    let (frames, _) = debug_deliver_frames(3, 2, 0);
+    for frame in frames.iter() {
+        let clone = frame.clone();
+        assert_eq!(clone.data.as_ptr(), frame.data.as_ptr());
+        assert_eq!(clone.data, frame.data);
+        assert_eq!((clone.pts, clone.key), (frame.pts, frame.key));
+    }
+    assert_eq!(
+        frames[0].data.as_slice(),
+        [0, 0, 0, 1, 0x65, 1, 0, 0, 0, 1, 0x65, 2, 0, 0, 0, 1, 0x65, 3]
+    );
+}
+
+#[test]
+fn make_mut_copies_shared_data() {
+    let mut data = FrameData::from(vec![0, 0, 0, 1, 0x65]);
+    let shared = data.clone();
+    data.make_mut()[4] = 0x41;
+    assert_eq!(shared.as_slice(), [0, 0, 0, 1, 0x65]);
+    assert_eq!(data.as_slice(), [0, 0, 0, 1, 0x41]);
+    // no other clone, changed in place
+    let ptr = data.as_ptr();
+    data.make_mut()[4] = 0x65;
+    assert_eq!(data.as_ptr(), ptr);
+    assert_eq!(data, shared);
+    assert_ne!(data.as_ptr(), shared.as_ptr());
+}
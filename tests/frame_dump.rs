@@ -0,0 +1,55 @@
+use hwcodec::ffmpeg_ram::encode::{read_stream, write_stream, EncodeFrame};
+use std::io::{Cursor, ErrorKind};
+
+fn session() -> Vec<EncodeFrame> {
+    (0..10)
+        .map(|i| EncodeFrame {
+            data: (0..i * 37).map(|b| (b * 7 + i) as u8).collect(),
+            pts: i as i64 * 33 - 5,
+            key: (i % 4 == 0) as i32,
+        })
+        .collect()
+}
+
+#[test]
+fn round_trip() {
+    let frames = session();
+    let mut dump = vec![];
+    write_stream(&mut dump, &frames).unwrap();
+    let read = read_stream(&mut Cursor::new(&dump)).unwrap();
+    assert_eq!(read.len(), frames.len());
+    for (a, b) in frames.iter().zip(read.iter()) {
+        assert_eq!((&a.data, a.pts, a.key), (&b.data, b.pts, b.key));
+    }
+}
+
+#[test]
+fn frames_read_one_by_one() {
+    let frames = session();
+    let mut dump = vec![];
+    for frame in frames.iter() {
+        frame.write_to(&mut dump).unwrap();
+    }
+    let mut r = Cursor::new(&dump);
+    for frame in frames.iter() {
+        let read = EncodeFrame::read_from(&mut r).unwrap().unwrap();
+        assert_eq!(
+            (&read.data, read.pts, read.key),
+            (&frame.data, frame.pts, frame.key)
+        );
+    }
+    assert!(EncodeFrame::read_from(&mut r).unwrap().is_none());
+}
+
+#[test]
+fn truncated_stream_fails() {
+    let mut dump = vec![];
+    write_stream(&mut dump, &session()).unwrap();
+    dump.pop();
+    let err = read_stream(&mut Cursor::new(&dump)).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    let err = read_stream(&mut Cursor::new(b"RIFF\x01\0\0\0"))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
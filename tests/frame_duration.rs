@@ -0,0 +1,170 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    bitstream::nal_units,
+    mux::{MuxContext, Muxer},
+    vram::{
+        backend::EncodeBackend,
+        encode::{EncodeFrame, Encoder, EncoderInfo},
+        EncodeContext,
+    },
+};
+use std::{ffi::c_void, ptr::null_mut};
+
+// sps, pps, sei and an idr
+const H264_720P_AVCC: &[u8] = include_bytes!("fixtures/720p.avcc.h264");
+
+// Outputs the fixture as every frame, one call late with bframes like a session that
+// reorders.
+struct Fixture {
+    bframes: i32,
+    pending: Option<EncodeFrame>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Fixture {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.frames.clear();
+        let mut data = vec![];
+        for nal in nal_units(H264_720P_AVCC) {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal.data);
+        }
+        let frame = EncodeFrame::new(data, ms, true);
+        if self.bframes == 0 {
+            self.frames.push(frame);
+        } else {
+            self.frames.extend(self.pending.replace(frame));
+        }
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn info(&self) -> EncoderInfo {
+        EncoderInfo {
+            bframes: self.bframes,
+            ..Default::default()
+        }
+    }
+
+    fn flush(&mut self) -> Result<Vec<EncodeFrame>, i32> {
+        Ok(self.pending.take().into_iter().collect())
+    }
+
+    fn recreate(&mut self, _ctx: &EncodeContext) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+fn encoder(bframes: i32) -> Encoder {
+    let ctx = encode_context("frame-duration-test");
+    let backend = Fixture {
+        bframes,
+        pending: None,
+        frames: vec![],
+    };
+    Encoder::from_backend(Box::new(backend), ctx)
+}
+
+// the body of the first box of the path
+fn find_box<'a>(mut data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (name, rest) = path.split_first()?;
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        if size < 8 || size > data.len() {
+            return None;
+        }
+        if &data[4..8] == *name {
+            let body = &data[8..size];
+            return if rest.is_empty() {
+                Some(body)
+            } else {
+                find_box(body, rest)
+            };
+        }
+        data = &data[size..];
+    }
+    None
+}
+
+// the sample durations of the first track in ms, from its stts and mdhd timescale
+fn sample_durations(mp4: &[u8]) -> Vec<i64> {
+    let be32 = |b: &[u8], at: usize| u32::from_be_bytes(b[at..at + 4].try_into().unwrap());
+    let mdhd = find_box(mp4, &[b"moov", b"trak", b"mdia", b"mdhd"]).unwrap();
+    // version 1 has 64 bit creation and modification times
+    let timescale = be32(mdhd, if mdhd[0] == 1 { 20 } else { 12 }) as i64;
+    let stbl = [b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stts"];
+    let stts = find_box(mp4, &stbl).unwrap();
+    let mut durations = vec![];
+    for i in 0..be32(stts, 4) as usize {
+        let count = be32(stts, 8 + i * 8);
+        let delta = be32(stts, 12 + i * 8) as i64;
+        durations.extend((0..count).map(|_| delta * 1000 / timescale));
+    }
+    durations
+}
+
+#[test]
+fn default_is_frame_interval() {
+    let mut encoder = encoder(0);
+    let frames = encoder.encode(null_mut(), 0).unwrap();
+    assert_eq!(frames[0].duration, 1000 / 30);
+    let frames = encoder.encode_with_duration(null_mut(), 33, 250).unwrap();
+    assert_eq!(frames[0].duration, 250);
+    assert!(encoder.encode_with_duration(null_mut(), 66, 0).is_err());
+}
+
+#[test]
+fn follows_reordered_frames() {
+    let mut encoder = encoder(1);
+    assert!(encoder
+        .encode_with_duration(null_mut(), 0, 50)
+        .unwrap()
+        .is_empty());
+    let frames = encoder.encode(null_mut(), 50).unwrap();
+    assert_eq!((frames[0].pts, frames[0].duration), (0, 50));
+    let frames = encoder.reset(1280, 720).unwrap();
+    assert_eq!((frames[0].pts, frames[0].duration), (50, 1000 / 30));
+}
+
+#[test]
+fn round_trip_through_muxer() {
+    // a variable framerate capture, the last frame is shown longest
+    let input = [(0, 33), (33, 17), (50, 100), (150, 10), (160, 250)];
+    let path = std::env::temp_dir().join("hwcodec-frame-duration.mp4");
+    let mut muxer = Muxer::new(MuxContext {
+        filename: path.to_str().unwrap().to_owned(),
+        width: 1280,
+        height: 720,
+        is265: false,
+        framerate: 30,
+    })
+    .unwrap();
+    let mut encoder = encoder(0);
+    for (ms, duration) in input {
+        let frames = encoder
+            .encode_with_duration(null_mut(), ms, duration)
+            .unwrap();
+        for frame in frames.iter() {
+            muxer
+                .write_video_with_duration(&frame.data, frame.key == 1, frame.pts, frame.duration)
+                .unwrap();
+        }
+    }
+    muxer.write_tail().unwrap();
+    drop(muxer);
+    let mp4 = std::fs::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let expected: Vec<i64> = input.iter().map(|(_, duration)| *duration).collect();
+    assert_eq!(sample_durations(&mp4), expected);
+}
@@ -0,0 +1,116 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    common::{FrameFlag::*, FrameFlags},
+    vram::{
+        backend::EncodeBackend,
+        encode::{EncodeFrame, Encoder},
+        EncodeContext,
+    },
+};
+use std::{ffi::c_void, ptr::null_mut};
+
+// a slice after the recovery point SEI of recovery_frame_cnt 3
+const RECOVERY_POINT: [u8; 16] = [
+    0, 0, 0, 1, 0x06, 0x06, 0x02, 0x24, 0x40, 0x80, 0, 0, 0, 1, 0x41, 0x9a,
+];
+
+// returns the frames of each call as given, flush returns the held ones
+#[derive(Default)]
+struct Fake {
+    calls: Vec<Vec<EncodeFrame>>,
+    held: Vec<EncodeFrame>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Fake {
+    fn encode(&mut self, _tex: *mut c_void, _ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.frames = self.calls.remove(0);
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<Vec<EncodeFrame>, i32> {
+        Ok(std::mem::take(&mut self.held))
+    }
+
+    fn recreate(&mut self, _ctx: &EncodeContext) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+fn frame(data: &[u8], pts: i64, key: i32, flags: u32) -> EncodeFrame {
+    EncodeFrame {
+        data: data.into(),
+        pts,
+        key,
+        flags: FrameFlags(flags),
+        ..Default::default()
+    }
+}
+
+fn encoder(backend: Fake) -> Encoder {
+    let ctx = encode_context("frame-flags-test");
+    Encoder::from_backend(Box::new(backend), ctx)
+}
+
+#[test]
+fn key_and_keyframe_flag_agree() {
+    let slice = [0, 0, 0, 1, 0x41, 0x9a];
+    let mut encoder = encoder(Fake {
+        calls: vec![
+            // a backend of before the flags
+            vec![frame(&slice, 0, 1, 0)],
+            vec![frame(&slice, 33, 0, FRAME_FLAG_KEYFRAME as u32)],
+            vec![frame(&slice, 66, 0, FRAME_FLAG_NON_REFERENCE as u32)],
+        ],
+        ..Default::default()
+    });
+    for (ms, key) in [(0, 1), (33, 1), (66, 0)] {
+        let frames = encoder.encode(null_mut(), ms).unwrap();
+        assert_eq!(frames[0].key, key);
+        assert_eq!(frames[0].flags.has(FRAME_FLAG_KEYFRAME), key == 1);
+    }
+    assert_eq!(encoder.summary().keyframes, 2);
+}
+
+#[test]
+fn recovery_points_are_flagged() {
+    let mut encoder = encoder(Fake {
+        calls: vec![
+            vec![frame(&RECOVERY_POINT, 0, 0, 0)],
+            vec![frame(&RECOVERY_POINT[10..], 33, 0, 0)],
+        ],
+        ..Default::default()
+    });
+    let frames = encoder.encode(null_mut(), 0).unwrap();
+    assert!(frames[0].flags.has(FRAME_FLAG_RECOVERY_POINT_START));
+    assert_eq!(frames[0].key, 0);
+    let frames = encoder.encode(null_mut(), 33).unwrap();
+    assert_eq!(frames[0].flags, FrameFlags::default());
+}
+
+#[test]
+fn reset_ends_the_sequence() {
+    let slice = [0, 0, 0, 1, 0x41, 0x9a];
+    let mut encoder = encoder(Fake {
+        held: vec![frame(&slice, 0, 0, 0), frame(&slice, 33, 0, 0)],
+        ..Default::default()
+    });
+    let frames = encoder.reset(1920, 1080).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert!(!frames[0].flags.has(FRAME_FLAG_END_OF_SEQUENCE));
+    assert!(frames[1].flags.has(FRAME_FLAG_END_OF_SEQUENCE));
+    // nothing to end without frames
+    assert!(encoder.reset(1280, 720).unwrap().is_empty());
+}
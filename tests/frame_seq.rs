@@ -0,0 +1,178 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::{decode_context, encode_context};
+use hwcodec::{
+    bitstream::assemble::AccessUnitAssembler,
+    common::{DataFormat, HwcodecErrno},
+    vram::{
+        backend::{DecodeBackend, EncodeBackend},
+        decode::{DecodeFrame, Decoder},
+        encode::{EncodeFrame, Encoder},
+        EncodeContext, RecoveryPolicy,
+    },
+};
+use std::{collections::VecDeque, ffi::c_void, ptr::null_mut};
+
+// see tests/bitstream.rs, decode order I P B P B P B
+const H264_BFRAMES: &[u8] = include_bytes!("fixtures/bframes.h264");
+const SESSION_LOST: i32 = HwcodecErrno::HWCODEC_ERR_SESSION_LOST as i32;
+
+fn frame(pts: i64) -> EncodeFrame {
+    EncodeFrame::new(vec![0, 0, 0, 1, 0x41], pts, false)
+}
+
+// Outputs each frame with the next encode call, like a session with a lookahead. The
+// encode calls fail with the errors of failures, one each, 0 encodes. A recreated session
+// has lost the held frame.
+#[derive(Default)]
+struct Delayed {
+    held: Option<i64>,
+    failures: VecDeque<i32>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Delayed {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        match self.failures.pop_front() {
+            Some(e) if e != 0 => return Err(e),
+            _ => {}
+        }
+        self.frames.clear();
+        if let Some(pts) = self.held.replace(ms) {
+            self.frames.push(frame(pts));
+        }
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<Vec<EncodeFrame>, i32> {
+        Ok(self.held.take().map(frame).into_iter().collect())
+    }
+
+    fn recreate(&mut self, _ctx: &EncodeContext) -> Result<(), ()> {
+        self.held = None;
+        Ok(())
+    }
+}
+
+fn encoder(recovery: Option<RecoveryPolicy>, failures: &[i32]) -> Encoder {
+    let mut ctx = encode_context("seq-test");
+    ctx.d.recovery = recovery;
+    let backend = Delayed {
+        failures: failures.iter().copied().collect(),
+        ..Default::default()
+    };
+    Encoder::from_backend(Box::new(backend), ctx)
+}
+
+// (pts, seq) of the frames of an encode call
+fn encode(encoder: &mut Encoder, ms: i64) -> Vec<(i64, u64)> {
+    let frames = encoder.encode(null_mut(), ms).unwrap();
+    frames.iter().map(|f| (f.pts, f.seq)).collect()
+}
+
+#[test]
+fn numbers_frames_in_output_order() {
+    let mut encoder = encoder(None, &[]);
+    // repeated and jumping pts
+    assert_eq!(encode(&mut encoder, 5), []);
+    assert_eq!(encode(&mut encoder, 5), [(5, 0)]);
+    assert_eq!(encode(&mut encoder, 0), [(5, 1)]);
+    assert_eq!(encode(&mut encoder, 40), [(0, 2)]);
+    // the flushed frame and those of the new session go on counting
+    let flushed = encoder.reset(320, 240).unwrap();
+    let flushed: Vec<_> = flushed.iter().map(|f| (f.pts, f.seq)).collect();
+    assert_eq!(flushed, [(40, 3)]);
+    assert_eq!(encode(&mut encoder, 80), []);
+    assert_eq!(encode(&mut encoder, 120), [(80, 4)]);
+}
+
+#[test]
+fn continues_across_recreated_sessions() {
+    let recovery = Some(RecoveryPolicy {
+        retries: 0,
+        backoff_ms: 1,
+        reinit_after: 1,
+    });
+    let mut encoder = encoder(recovery, &[0, 0, SESSION_LOST]);
+    assert_eq!(encode(&mut encoder, 0), []);
+    assert_eq!(encode(&mut encoder, 33), [(0, 0)]);
+    // recreated and encoded again, the frame of 33 was lost with the session
+    assert_eq!(encode(&mut encoder, 66), []);
+    assert_eq!(encoder.recovery_stats().reinits, 1);
+    assert_eq!(encode(&mut encoder, 100), [(66, 1)]);
+}
+
+// outputs a frame for every packet but those it fails on
+#[derive(Default)]
+struct Echo {
+    failing: Vec<Vec<u8>>,
+    frames: Vec<DecodeFrame>,
+}
+
+impl DecodeBackend for Echo {
+    fn decode(&mut self, packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
+        if self.failing.iter().any(|p| p == packet) {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        self.frames.clear();
+        self.frames.push(DecodeFrame {
+            width: 64,
+            height: 64,
+            ..Default::default()
+        });
+        Ok(&mut self.frames)
+    }
+}
+
+fn access_units() -> Vec<Vec<u8>> {
+    let mut packets = vec![];
+    let mut assembler = AccessUnitAssembler::new(DataFormat::H264).unwrap();
+    let mut collect = |au: &[u8]| {
+        packets.push(au.to_vec());
+        Ok(())
+    };
+    assembler.push(H264_BFRAMES, &mut collect).unwrap();
+    assembler.flush(&mut collect).unwrap();
+    packets
+}
+
+#[test]
+fn decoder_echoes_seq() {
+    let packets = access_units();
+    let backend = Echo {
+        failing: vec![packets[2].clone()],
+        ..Default::default()
+    };
+    let mut ctx = decode_context("seq-test");
+    ctx.conceal_errors = true;
+    let mut decoder = Decoder::from_backend(Box::new(backend), ctx);
+    let mut seqs = vec![];
+    for (i, packet) in packets.iter().enumerate().take(4) {
+        let frames = decoder
+            .decode_with_seq(packet, i as i64, 100 + i as u64)
+            .unwrap();
+        seqs.extend(frames.iter().map(|f| (f.seq, f.corrupted)));
+    }
+    // the frame concealing the failed packet carries its seq
+    assert_eq!(
+        seqs,
+        [
+            (Some(100), false),
+            (Some(101), false),
+            (Some(102), true),
+            (Some(103), true)
+        ]
+    );
+    let frames = decoder.decode(&packets[4]).unwrap();
+    assert_eq!(frames[0].seq, None);
+}
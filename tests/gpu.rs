@@ -0,0 +1,2415 @@
+// Runs on every encoder the machine offers, enabled with --features gpu-tests.
+#![cfg(all(windows, feature = "gpu-tests"))]
+
+#[cfg(feature = "tokio")]
+use hwcodec::vram::worker::{AsyncDecoder, AsyncEncoder};
+#[cfg(feature = "mfx")]
+use hwcodec::vram::{debug_close_mfx_session, debug_mfx_session_alive, debug_new_mfx_session};
+use hwcodec::{
+    bitstream::{assemble::AccessUnitAssembler, h264, hevc, validate::Validator},
+    common::{
+        adapter_for_luid, luid_of_device, same_adapter, AdapterVendor, AqMode, ChromaLocation,
+        ColorConvert, DataFormat, DecodeProfile, Driver, EncodeCapability, EncodeCaps,
+        EntropyCoding, FrameFlag, HwcodecErrno, MAX_GOP,
+    },
+    testutil::{
+        bgra_pattern, chroma, copy_to_texture_of_desc, luma, psnr, read_bgra, ssim, Device,
+        SharedFence, StagingTexture, Texture,
+    },
+    vram::{
+        adapter_path,
+        decode::{self, Decoder},
+        encode::{self, Encoder},
+        record::Recorder,
+        report::{capability_report, capability_report_with_round_trips},
+        self_test::self_test_all,
+        snapshot::{Snapshot, SnapshotContext},
+        split::{SplitEncoder, TileRect},
+        DecodeContext, DynamicContext, EncodeContext, FeatureContext, OutputOrder, Tune,
+    },
+};
+use std::{
+    sync::Barrier,
+    thread,
+    time::{Duration, Instant},
+};
+
+const WIDTH: i32 = 640;
+const HEIGHT: i32 = 480;
+const GOP: i32 = 5;
+const FRAMES: usize = 3 * GOP as usize;
+const MIN_SSIM: f64 = 0.9;
+// see tests/bitstream.rs
+const H264_BFRAMES: &[u8] = include_bytes!("fixtures/bframes.h264");
+
+fn dynamic_context() -> DynamicContext {
+    DynamicContext {
+        device: None,
+        width: WIDTH,
+        height: HEIGHT,
+        kbitrate: 2000,
+        framerate: 30,
+        gop: GOP,
+        ..Default::default()
+    }
+}
+
+fn matching_decoder(f: &FeatureContext, decoders: &[DecodeContext]) -> Option<DecodeContext> {
+    decoders
+        .iter()
+        .find(|d| d.luid == f.luid && d.data_format == f.data_format)
+        .cloned()
+}
+
+// packets of each source frame
+fn encode_pattern(f: &FeatureContext, device: &Device) -> Vec<Vec<encode::EncodeFrame>> {
+    let mut d = dynamic_context();
+    d.device = Some(device.as_ptr());
+    let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+    (0..FRAMES)
+        .map(|i| {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            encoder
+                .encode(texture.as_ptr(), i as _)
+                .unwrap()
+                .drain(..)
+                .collect()
+        })
+        .collect()
+}
+
+// bgra of the decoded frames
+fn decode_all(
+    mut dec_ctx: DecodeContext,
+    packets: impl IntoIterator<Item = encode::EncodeFrame>,
+) -> Vec<Vec<u8>> {
+    let dec_device = Device::new(dec_ctx.luid).unwrap();
+    dec_ctx.device = Some(dec_device.as_ptr());
+    let mut decoder = Decoder::new(dec_ctx).unwrap();
+    let mut decoded = vec![];
+    for packet in packets {
+        for frame in decoder.decode(&packet.data).unwrap().iter() {
+            decoded.push(read_bgra(frame.texture, WIDTH, HEIGHT).unwrap());
+        }
+    }
+    decoded
+}
+
+fn pattern_ssim(bgra: &[u8], index: usize) -> f64 {
+    let (width, height) = (WIDTH as usize, HEIGHT as usize);
+    let source = bgra_pattern(width, height, index);
+    ssim(
+        &luma(&source, width, height),
+        &luma(bgra, width, height),
+        width,
+        height,
+    )
+}
+
+// decoded frame n must look like bgra_pattern(first + n)
+fn assert_decodes_to_pattern(
+    f: &FeatureContext,
+    dec_ctx: DecodeContext,
+    packets: impl IntoIterator<Item = encode::EncodeFrame>,
+    first: usize,
+) {
+    let decoded = decode_all(dec_ctx, packets);
+    assert!(!decoded.is_empty(), "{:?} decoded nothing", f);
+    for (i, bgra) in decoded.iter().enumerate() {
+        let s = pattern_ssim(bgra, first + i);
+        assert!(s >= MIN_SSIM, "{:?} frame {} ssim {:.4}", f, i, s);
+    }
+}
+
+#[test]
+fn available_single_format() {
+    let h265 = encode::available_formats(dynamic_context(), &[DataFormat::H265]);
+    assert!(h265.iter().all(|f| f.data_format == DataFormat::H265));
+    let all: Vec<_> = encode::available(dynamic_context())
+        .into_iter()
+        .filter(|f| f.data_format == DataFormat::H265)
+        .collect();
+    assert_eq!(h265, all);
+}
+
+// working drivers pass the output check, trusting the tests finds the same encoders
+#[test]
+fn probe_verification_keeps_encoders() {
+    let verified = encode::available(dynamic_context());
+    let fast = encode::available_with_verification(dynamic_context(), false);
+    assert_eq!(verified, fast);
+}
+
+// the async probe tests read the counters of every probe thread
+#[cfg(feature = "async")]
+static ASYNC_PROBES: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(feature = "async")]
+#[test]
+fn available_async_matches_available() {
+    let _probes = ASYNC_PROBES.lock().unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let async_probed = runtime.block_on(encode::available_async(dynamic_context()));
+    assert_eq!(async_probed, encode::available(dynamic_context()));
+}
+
+// a dropped future's thread exits after the native test it's in, the rest are skipped
+#[cfg(feature = "async")]
+#[test]
+fn available_async_cancelled_on_drop() {
+    let _probes = ASYNC_PROBES.lock().unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (_, before) = encode::debug_available_async_probes();
+    runtime.block_on(encode::available_async(dynamic_context()));
+    let (running, after) = encode::debug_available_async_probes();
+    assert_eq!(running, 0);
+    let tests = after - before;
+    runtime.block_on(async {
+        let probe = encode::available_async(dynamic_context());
+        tokio::pin!(probe);
+        tokio::select! {
+            biased;
+            _ = &mut probe => {}
+            _ = async {} => {}
+        }
+    });
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while encode::debug_available_async_probes().0 > 0 {
+        assert!(Instant::now() < deadline, "probe thread still running");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let cancelled = encode::debug_available_async_probes().1 - after;
+    assert!(cancelled <= tests.min(1), "{} of {}", cancelled, tests);
+}
+
+// colored text on a dark desktop, on the first adapter and read back for every other
+#[test]
+fn probe_with_sample() {
+    let encoders = encode::available(dynamic_context());
+    let Some(first) = encoders.first() else {
+        return;
+    };
+    let device = Device::new(first.luid).unwrap();
+    let dark: Vec<u8> = colored_text()
+        .chunks_exact(4)
+        .flat_map(|p| match p[..3] {
+            [255, 255, 255] => [8, 8, 8, 255],
+            _ => [p[0], p[1], p[2], 255],
+        })
+        .collect();
+    let sample = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &dark).unwrap();
+    let sampled = encode::available_with_sample(dynamic_context(), sample.as_ptr()).unwrap();
+    assert_eq!(sampled, encoders);
+    let small = Texture::from_bgra(device.as_ptr(), WIDTH / 2, HEIGHT / 2, &dark).unwrap();
+    assert!(encode::available_with_sample(dynamic_context(), small.as_ptr()).is_err());
+}
+
+// every encoder does 720p, the native tests ran at the first resolution alone
+#[test]
+fn probe_resolutions() {
+    let encoders = encode::available(dynamic_context());
+    let single = encode::available_resolutions(dynamic_context(), &[(WIDTH, HEIGHT)]);
+    let features: Vec<_> = single.iter().map(|(f, _)| f.clone()).collect();
+    assert_eq!(features, encoders);
+    assert!(single.iter().all(|(_, max)| *max == (WIDTH, HEIGHT)));
+    let resolutions = [(WIDTH, HEIGHT), (1280, 720), (WIDTH / 2, HEIGHT / 2)];
+    let probed = encode::available_resolutions(dynamic_context(), &resolutions);
+    let features: Vec<_> = probed.iter().map(|(f, _)| f.clone()).collect();
+    assert_eq!(features, encoders);
+    for (f, max) in probed {
+        assert_eq!(max, (1280, 720), "{:?}", f);
+    }
+    assert!(encode::available_resolutions(dynamic_context(), &[]).is_empty());
+}
+
+// every NVENC generation does h264 b-frames up to level 5.1 at least, ffmpeg reports nothing
+#[test]
+fn caps_match_known_gpus() {
+    for f in encode::available(dynamic_context()) {
+        let caps = f.caps;
+        if caps.maxWidth > 0 {
+            assert!(
+                caps.maxWidth >= WIDTH && caps.maxHeight >= HEIGHT,
+                "{:?}",
+                f
+            );
+        }
+        match (&f.driver, f.data_format) {
+            (Driver::FFMPEG, _) => assert_eq!(caps, EncodeCaps::default(), "{:?}", f),
+            (Driver::NV, DataFormat::H264) => {
+                assert!(caps.has(EncodeCapability::ENCODE_CAP_BFRAMES), "{:?}", f);
+                assert!(caps.maxLevel >= 51, "{:?}", f);
+            }
+            (Driver::NV, DataFormat::H265) => assert!(caps.maxLevel >= 150, "{:?}", f),
+            _ => {}
+        }
+    }
+}
+
+// the 720p samples the decoders are tested with decode, so the caps cover them
+#[test]
+fn decode_caps_cover_test_streams() {
+    for ctx in decode::available() {
+        let caps = decode::query_decode_caps(&ctx).unwrap();
+        assert!(caps.maxWidth >= 1280 && caps.maxHeight >= 720, "{:?}", ctx);
+        assert!(caps.has_bit_depth(8), "{:?} {:?}", ctx, caps);
+        // 720p30 needs level 3.1
+        let (main, level) = match ctx.data_format {
+            DataFormat::H264 => (DecodeProfile::DECODE_PROFILE_H264_MAIN, 31),
+            _ => (DecodeProfile::DECODE_PROFILE_HEVC_MAIN, 93),
+        };
+        assert!(caps.has_profile(main), "{:?} {:?}", ctx, caps);
+        if caps.maxLevel > 0 {
+            assert!(caps.maxLevel >= level, "{:?} {:?}", ctx, caps);
+        }
+    }
+}
+
+// every adapter found is named and every decoder session opens for its caps
+#[test]
+fn capability_report_of_machine() {
+    let report = capability_report(dynamic_context());
+    assert_eq!(
+        report
+            .adapters
+            .iter()
+            .map(|a| a.encoders.len())
+            .sum::<usize>(),
+        encode::available(dynamic_context()).len()
+    );
+    for adapter in &report.adapters {
+        assert!(adapter.path.is_some(), "{:?}", adapter);
+        for decoder in &adapter.decoders {
+            assert!(decoder.caps.is_some(), "{:?}", decoder);
+        }
+    }
+    assert!(report.drivers.iter().any(|info| info.supported));
+    assert!(report.serialize().is_ok());
+}
+
+// an encoder's stream decodes on the decoder of its adapter, as decodes_with_quality does
+#[test]
+fn round_trips_of_machine() {
+    let report = capability_report_with_round_trips(dynamic_context());
+    let decoders = decode::available();
+    for adapter in &report.adapters {
+        for f in &adapter.encoders {
+            let trips: Vec<_> = report
+                .round_trips
+                .iter()
+                .filter(|t| &t.encoder == f)
+                .collect();
+            let formats = decoders.iter().filter(|d| d.data_format == f.data_format);
+            assert_eq!(trips.len(), formats.count(), "{:?}", f);
+            if let Some(dec_ctx) = matching_decoder(f, &decoders) {
+                let own = trips.iter().find(|t| t.decoder == dec_ctx).unwrap();
+                assert_eq!(own.error, None, "{:?}", own);
+            }
+        }
+    }
+}
+
+#[test]
+fn adapter_paths_of_codec_adapters() {
+    let luids = encode::available(dynamic_context())
+        .into_iter()
+        .map(|f| f.luid)
+        .chain(decode::available().into_iter().map(|d| d.luid));
+    for luid in luids {
+        let path = adapter_path(luid).unwrap();
+        assert!(!path.description.is_empty(), "{:?}", path);
+        assert!(path.hardware_id.starts_with("PCI\\VEN_"), "{:?}", path);
+    }
+    // luids are never 0
+    assert_eq!(adapter_path(0), None);
+}
+
+// the adapter of an encoder's luid is its vendor's, and the luid of a device on it
+#[test]
+fn adapters_of_encoder_luids() {
+    for f in encode::available(dynamic_context()) {
+        let info = adapter_for_luid(f.luid).unwrap();
+        assert_eq!(info.description, adapter_path(f.luid).unwrap().description);
+        assert!(!info.software, "{:?}", info);
+        let vendor = match f.vendor {
+            Driver::NV => AdapterVendor::ADAPTER_VENDOR_NVIDIA,
+            Driver::AMF => AdapterVendor::ADAPTER_VENDOR_AMD,
+            _ => AdapterVendor::ADAPTER_VENDOR_INTEL,
+        };
+        assert_eq!(info.vendor, vendor, "{:?}", info);
+        let device = Device::new(f.luid).unwrap();
+        let luid = luid_of_device(device.as_ptr()).unwrap();
+        assert!(same_adapter(luid, f.luid), "{:?}", info);
+    }
+    assert_eq!(adapter_for_luid(0), None);
+}
+
+#[test]
+fn falls_back_to_h264() {
+    let preferred = [DataFormat::H265, DataFormat::H264];
+    let f = encode::best_encoder_with_fallback(dynamic_context(), &preferred);
+    let h265 = encode::available_formats(dynamic_context(), &[DataFormat::H265]);
+    let h264 = encode::available_formats(dynamic_context(), &[DataFormat::H264]);
+    let expected = h265.into_iter().next().or(h264.into_iter().next());
+    assert_eq!(f, expected);
+}
+
+// MFX sessions come from the VPL runtime on Xe and newer, from the Media SDK one before
+#[test]
+fn encoders_report_runtime() {
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let info = Encoder::new(EncodeContext { f: f.clone(), d })
+            .unwrap()
+            .info();
+        assert!(!info.runtime.is_empty(), "{:?}", f);
+        if f.driver == Driver::MFX {
+            match info.runtime.as_str() {
+                "oneVPL" => assert!(info.api_version.0 >= 2, "{:?}", info),
+                "Media SDK" => assert_eq!(info.api_version.0, 1, "{:?}", info),
+                _ => panic!("{:?} {:?}", f, info),
+            }
+        }
+    }
+}
+
+// no backend is asked for B-frames, the output has to stay in display order
+#[test]
+fn bframes_inactive() {
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        assert_eq!(encoder.info().bframes, 0, "{:?}", f);
+        assert!(!encoder.bframes_active(), "{:?}", f);
+    }
+}
+
+#[test]
+fn keyframe_cadence() {
+    let encoders = encode::available(dynamic_context());
+    for f in encoders.iter() {
+        let device = Device::new(f.luid).unwrap();
+        for (i, packets) in encode_pattern(f, &device).iter().enumerate() {
+            for packet in packets {
+                assert_eq!(
+                    packet.key == 1,
+                    i % GOP as usize == 0,
+                    "{:?} frame {}",
+                    f,
+                    i
+                );
+            }
+        }
+    }
+}
+
+// a gop given as an ffmpeg option replaces the derived one
+#[test]
+fn ffmpeg_option_overrides() {
+    let encoders = encode::available(dynamic_context());
+    for f in encoders.iter().filter(|f| f.driver == Driver::FFMPEG) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let ctx = EncodeContext { f: f.clone(), d };
+        let gop = 2 * GOP as usize;
+        let overrides = vec![("g".to_owned(), gop.to_string())];
+        let options = encode::ffmpeg_options_with(&ctx, &overrides).unwrap();
+        assert!(options.contains(&overrides[0]), "{:?}", options);
+        let mut encoder = Encoder::new_with_ffmpeg_options(ctx, &overrides).unwrap();
+        for i in 0..FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            for packet in encoder.encode(texture.as_ptr(), i as _).unwrap().iter() {
+                assert_eq!(packet.key == 1, i % gop == 0, "{:?} frame {}", f, i);
+            }
+        }
+    }
+}
+
+#[test]
+fn decodes_with_quality() {
+    let encoders = encode::available(dynamic_context());
+    let decoders = decode::available();
+    for f in encoders.iter() {
+        let Some(dec_ctx) = matching_decoder(f, &decoders) else {
+            continue;
+        };
+        let enc_device = Device::new(f.luid).unwrap();
+        let packets = encode_pattern(f, &enc_device).into_iter().flatten();
+        assert_decodes_to_pattern(f, dec_ctx, packets, 0);
+    }
+}
+
+// a texture created from the reported desc takes a copy of the frame, in both orders the
+// latter of which hands out copies of the decoder's textures
+#[test]
+fn decoded_texture_desc() {
+    let encoders = encode::available(dynamic_context());
+    let decoders = decode::available();
+    for f in encoders
+        .iter()
+        .filter(|f| matches!(f.data_format, DataFormat::H264 | DataFormat::H265))
+    {
+        let Some(mut dec_ctx) = matching_decoder(f, &decoders) else {
+            continue;
+        };
+        let enc_device = Device::new(f.luid).unwrap();
+        let packets: Vec<_> = encode_pattern(f, &enc_device)
+            .into_iter()
+            .flatten()
+            .collect();
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        for order in [OutputOrder::Decode, OutputOrder::Presentation] {
+            dec_ctx.output_order = order;
+            let mut decoder = Decoder::new(dec_ctx.clone()).unwrap();
+            let mut decoded = 0;
+            for packet in &packets {
+                for frame in decoder.decode(&packet.data).unwrap().iter() {
+                    let desc = frame.desc;
+                    assert!(desc.width >= frame.width, "{:?} {:?}", f, desc);
+                    assert!(desc.height >= frame.height, "{:?} {:?}", f, desc);
+                    assert_eq!(desc.arraySlice, 0, "{:?}", f);
+                    copy_to_texture_of_desc(frame.texture, &desc)
+                        .unwrap_or_else(|_| panic!("{:?} {:?} {:?}", f, order, desc));
+                    decoded += 1;
+                }
+            }
+            assert!(decoded > 0, "{:?} decoded nothing", f);
+        }
+    }
+}
+
+// A P-frame whose slice data is garbage decodes to a frame, the last one again or what the
+// session concealed, and the stream is intact again from the next keyframe.
+#[test]
+fn corrupted_p_frame_is_concealed() {
+    let encoders = encode::available(dynamic_context());
+    let decoders = decode::available();
+    for f in encoders
+        .iter()
+        .filter(|f| matches!(f.data_format, DataFormat::H264 | DataFormat::H265))
+    {
+        let Some(mut dec_ctx) = matching_decoder(f, &decoders) else {
+            continue;
+        };
+        let enc_device = Device::new(f.luid).unwrap();
+        let mut packets: Vec<_> = encode_pattern(f, &enc_device)
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(packets.len(), FRAMES, "{:?}", f);
+        assert_eq!(packets[1].key, 0, "{:?}", f);
+        let data = packets[1].data.make_mut();
+        let half = data.len() / 2;
+        data[half..].iter_mut().for_each(|b| *b = 0x5a);
+        let keyframe = (2..FRAMES).find(|&i| packets[i].key != 0).unwrap();
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        dec_ctx.conceal_errors = true;
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        for (i, packet) in packets.iter().enumerate() {
+            let frames = decoder
+                .decode(&packet.data)
+                .unwrap_or_else(|e| panic!("{:?} packet {} failed with {}", f, i, e));
+            assert_eq!(frames.len(), 1, "{:?} packet {}", f, i);
+            if i < keyframe {
+                continue;
+            }
+            let frame = &frames[0];
+            assert!(!frame.corrupted, "{:?} packet {}", f, i);
+            let bgra = read_bgra(frame.texture, WIDTH, HEIGHT).unwrap();
+            let s = pattern_ssim(&bgra, i);
+            assert!(s >= MIN_SSIM, "{:?} frame {} ssim {:.4}", f, i, s);
+        }
+    }
+}
+
+fn crop(bgra: &[u8], width: usize, rect: &TileRect) -> Vec<u8> {
+    let (x, w) = (rect.x as usize * 4, rect.width as usize * 4);
+    bgra.chunks_exact(width * 4)
+        .skip(rect.y as _)
+        .take(rect.height as _)
+        .flat_map(|row| row[x..x + w].iter().copied())
+        .collect()
+}
+
+// caps halved below the frame width split it into two streams, each decoding to its half
+#[test]
+fn split_encoder_tiles() {
+    let encoders = encode::available(dynamic_context());
+    let decoders = decode::available();
+    for f in encoders
+        .iter()
+        .filter(|f| matches!(f.data_format, DataFormat::H264 | DataFormat::H265))
+    {
+        let Some(mut dec_ctx) = matching_decoder(f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let mut f = f.clone();
+        f.caps.maxWidth = WIDTH / 2;
+        let mut encoder = SplitEncoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let tiles = encoder.tiles();
+        assert_eq!(tiles.len(), 2, "{:?}", f);
+        let mut packets = vec![vec![], vec![]];
+        for i in 0..FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            for tile in encoder.encode(texture.as_ptr(), i as _).unwrap().drain(..) {
+                assert_eq!(tile.rect, tiles[tile.tile]);
+                packets[tile.tile].push(tile.frame);
+            }
+        }
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        for (rect, packets) in tiles.iter().zip(packets) {
+            let (width, height) = (rect.width as usize, rect.height as usize);
+            let mut decoder = Decoder::new(dec_ctx.clone()).unwrap();
+            let mut decoded = 0;
+            for packet in packets {
+                for frame in decoder.decode(&packet.data).unwrap().iter() {
+                    let bgra = read_bgra(frame.texture, rect.width, rect.height).unwrap();
+                    let source = bgra_pattern(WIDTH as _, HEIGHT as _, decoded);
+                    let source = crop(&source, WIDTH as _, rect);
+                    let s = ssim(
+                        &luma(&source, width, height),
+                        &luma(&bgra, width, height),
+                        width,
+                        height,
+                    );
+                    assert!(s >= MIN_SSIM, "{:?} {:?} ssim {:.4}", f, rect, s);
+                    decoded += 1;
+                }
+            }
+            assert!(decoded > 0, "{:?} {:?} decoded nothing", f, rect);
+        }
+    }
+}
+
+// the bytes of an sRGB render target are gamma encoded already, they encode like the same
+// bytes in a UNORM texture and not darker
+#[test]
+fn srgb_input_matches_unorm() {
+    let encoders = encode::available(dynamic_context());
+    let decoders = decode::available();
+    for f in encoders.iter() {
+        let Some(dec_ctx) = matching_decoder(f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let mut decoded = vec![];
+        for srgb in [false, true] {
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            let mut packets = vec![];
+            for i in 0..GOP as usize {
+                let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+                let texture = if srgb {
+                    Texture::from_bgra_srgb(device.as_ptr(), WIDTH, HEIGHT, &source)
+                } else {
+                    Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source)
+                };
+                let texture = texture.unwrap();
+                packets.extend(encoder.encode(texture.as_ptr(), i as _).unwrap().drain(..));
+            }
+            decoded.push(decode_all(dec_ctx.clone(), packets));
+        }
+        assert!(!decoded[0].is_empty(), "{:?} decoded nothing", f);
+        assert_eq!(decoded[0].len(), decoded[1].len(), "{:?}", f);
+        let (width, height) = (WIDTH as usize, HEIGHT as usize);
+        for (i, (unorm, srgb)) in decoded[0].iter().zip(&decoded[1]).enumerate() {
+            let (unorm, srgb) = (luma(unorm, width, height), luma(srgb, width, height));
+            let mean = |y: &[f64]| y.iter().sum::<f64>() / y.len() as f64;
+            assert!(
+                (mean(&unorm) - mean(&srgb)).abs() < 1.0,
+                "{:?} frame {} luma {:.2} vs {:.2}",
+                f,
+                i,
+                mean(&unorm),
+                mean(&srgb)
+            );
+            assert!(psnr(&unorm, &srgb) > 35.0, "{:?} frame {}", f, i);
+        }
+    }
+}
+
+// Two encoders on one device fed in lockstep from their own threads, the frames of each
+// must decode to its own input.
+#[test]
+fn shared_device_threads() {
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let Some(dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        // the second stream starts where the first ends, the frames never look alike
+        let firsts = [0, FRAMES];
+        let barrier = Barrier::new(firsts.len());
+        let outputs: Vec<Vec<encode::EncodeFrame>> = thread::scope(|s| {
+            let threads: Vec<_> = firsts
+                .iter()
+                .map(|&first| {
+                    let (f, barrier) = (f.clone(), &barrier);
+                    s.spawn(move || {
+                        let mut encoder = Encoder::new(EncodeContext { f, d }).unwrap();
+                        let mut packets = vec![];
+                        for i in 0..FRAMES {
+                            let source = bgra_pattern(WIDTH as _, HEIGHT as _, first + i);
+                            let device = d.device.unwrap();
+                            let texture =
+                                Texture::from_bgra(device, WIDTH, HEIGHT, &source).unwrap();
+                            barrier.wait();
+                            let frames = encoder.encode(texture.as_ptr(), i as _).unwrap();
+                            packets.extend(frames.drain(..));
+                        }
+                        packets
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        for (packets, first) in outputs.into_iter().zip(firsts) {
+            assert_decodes_to_pattern(&f, dec_ctx.clone(), packets, first);
+        }
+    }
+}
+
+// every frame gets the same ms, only user_data tells them apart
+#[test]
+fn user_data_follows_frames() {
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut returned = vec![];
+        for i in 0..FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let user_data = u64::MAX - i as u64;
+            for frame in encoder
+                .encode_with_user_data(texture.as_ptr(), 0, user_data)
+                .unwrap()
+                .iter()
+            {
+                returned.push(frame.user_data);
+            }
+        }
+        returned.dedup();
+        let expected: Vec<u64> = (0..FRAMES).map(|i| u64::MAX - i as u64).collect();
+        assert_eq!(returned, expected, "{:?}", f);
+    }
+}
+
+// a 120 Hz capture with gaps and jitter, budgeted for 60 fps
+#[test]
+fn pts_follow_irregular_ms() {
+    let input: Vec<i64> = [0, 8, 17, 25, 100, 108, 1000, 1001, 1009, 5000, 5033, 5041]
+        .iter()
+        .map(|ms| ms + 1_700_000_000_000)
+        .collect();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.framerate = 60;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut pts = vec![];
+        for (i, ms) in input.iter().enumerate() {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let frames = encoder.encode(texture.as_ptr(), *ms).unwrap();
+            pts.extend(frames.iter().map(|frame| frame.pts));
+        }
+        // the frames a session still holds
+        let pending = encoder.reset(WIDTH, HEIGHT).unwrap();
+        pts.extend(pending.iter().map(|frame| frame.pts));
+        pts.dedup();
+        assert_eq!(pts, input, "{:?}", f);
+    }
+}
+
+// every frame is a different full frame of noise, what a scene cut detector reacts to
+fn scene(i: usize) -> Vec<u8> {
+    let mut state = (i as u32 + 1).wrapping_mul(0x9e37_79b9);
+    (0..WIDTH as usize * HEIGHT as usize * 4)
+        .map(|b| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            if b % 4 == 3 {
+                255
+            } else {
+                (state >> 24) as u8
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn no_scene_cut_keyframes() {
+    const REQUESTED: usize = 20;
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.gop = MAX_GOP as _;
+        d.scene_cut_keyframes = false;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut keys = vec![];
+        for i in 0..FRAMES.max(2 * REQUESTED) {
+            if i == REQUESTED {
+                encoder.request_keyframe().unwrap();
+            }
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &scene(i)).unwrap();
+            for frame in encoder.encode(texture.as_ptr(), i as _).unwrap().iter() {
+                if frame.key == 1 {
+                    keys.push(i);
+                }
+            }
+        }
+        keys.dedup();
+        assert_eq!(keys, vec![0, REQUESTED], "{:?}", f);
+    }
+}
+
+// Each frame comes out of its own encode call within a fraction of its interval, and
+// with the gop left to the intra refresh no frame after the first is a keyframe or
+// far beyond its share of the bitrate.
+#[test]
+fn cloud_gaming_latency_and_rate() {
+    const FRAMERATE: i32 = 60;
+    const COUNT: usize = 120;
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.framerate = FRAMERATE;
+        d.tune = Tune::CloudGaming;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let textures: Vec<_> = (0..COUNT)
+            .map(|i| {
+                let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+                Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap()
+            })
+            .collect();
+        let mut sizes = vec![];
+        for (i, texture) in textures.iter().enumerate() {
+            let ms = i as i64 * 1000 / FRAMERATE as i64;
+            let frames = encoder.encode(texture.as_ptr(), ms).unwrap();
+            assert_eq!(frames.len(), 1, "{:?} frame {}", f, i);
+            assert_eq!(frames[0].key == 1, i == 0, "{:?} frame {}", f, i);
+            let keyframe = frames[0].flags.has(FrameFlag::FRAME_FLAG_KEYFRAME);
+            assert_eq!(keyframe, i == 0, "{:?} frame {}", f, i);
+            sizes.push(frames[0].data.len());
+        }
+        let interval = Duration::from_secs(1) / FRAMERATE as u32;
+        let latency = encoder.latency_histogram();
+        assert!(latency.p95() < interval, "{:?}: p95 {:?}", f, latency.p95());
+        let budget = (d.kbitrate as usize * 1000 / 8) / FRAMERATE as usize;
+        let max = sizes[1..].iter().max().unwrap();
+        assert!(*max < 3 * budget, "{:?}: {} bytes of {}", f, max, budget);
+    }
+}
+
+#[test]
+fn keyframes_repeat_headers() {
+    for f in encode::available(dynamic_context()) {
+        if f.data_format != DataFormat::H264 && f.data_format != DataFormat::H265 {
+            continue;
+        }
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.repeat_headers = true;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut keys = 0;
+        for i in 0..FRAMES {
+            if i == FRAMES / 2 + 1 {
+                encoder.request_keyframe().unwrap();
+            }
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            for frame in encoder.encode(texture.as_ptr(), i as _).unwrap().iter() {
+                if frame.key != 1 {
+                    continue;
+                }
+                keys += 1;
+                let types: Vec<u8> = frame
+                    .nal_units()
+                    .map(|nal| match f.data_format {
+                        DataFormat::H264 => nal.h264_type(),
+                        _ => nal.hevc_type(),
+                    })
+                    .collect();
+                let sets: &[u8] = match f.data_format {
+                    DataFormat::H264 => &[h264::NAL_SPS, h264::NAL_PPS],
+                    _ => &[hevc::NAL_VPS, hevc::NAL_SPS, hevc::NAL_PPS],
+                };
+                for set in sets {
+                    assert!(types.contains(set), "{:?} frame {}: {:?}", f, i, types);
+                }
+            }
+        }
+        assert!(keys >= FRAMES / GOP as usize, "{:?}: {} keyframes", f, keys);
+    }
+}
+
+// decoding from the boundary on needs none of the frames before it
+#[test]
+fn closed_gop_decodes_alone() {
+    const BOUNDARY: usize = 7;
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let Some(dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.gop = MAX_GOP as _;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut packets = vec![];
+        for i in 0..FRAMES {
+            if i == BOUNDARY {
+                encoder.close_gop_at_next().unwrap();
+            }
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            packets.append(encoder.encode(texture.as_ptr(), i as _).unwrap());
+        }
+        let boundary = BOUNDARY as i64;
+        let first = packets.iter().position(|p| p.pts == boundary).unwrap();
+        let (before, after) = packets.split_at(first);
+        assert!(before.iter().all(|p| p.pts < boundary), "{:?}", f);
+        assert!(after.iter().all(|p| p.pts >= boundary), "{:?}", f);
+        assert_eq!(after[0].key, 1, "{:?}", f);
+        let idr = after[0].nal_units().any(|nal| match f.data_format {
+            DataFormat::H264 => nal.h264_type() == 5,
+            _ => (19..=20).contains(&nal.hevc_type()),
+        });
+        assert!(idr, "{:?}", f);
+        assert_decodes_to_pattern(&f, dec_ctx, packets.drain(first..), BOUNDARY);
+    }
+}
+
+// Frame 3 is lost on the way, the frame after its invalidation references frame 2 and
+// decodes without it. H.264 streams show a gap in frame_num, the decoder conceals it.
+#[test]
+fn invalidated_reference_avoids_idr() {
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.gop = MAX_GOP;
+        d.reference_invalidation = true;
+        let created = Encoder::new(EncodeContext { f: f.clone(), d });
+        if !f.caps.has(EncodeCapability::ENCODE_CAP_REF_INVALIDATION) {
+            assert!(created.is_err(), "{:?}", f);
+            continue;
+        }
+        let mut encoder = created.unwrap();
+        let mut packets = vec![];
+        for i in 0..5 {
+            if i == 4 {
+                encoder.invalidate_reference(3).unwrap();
+            }
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let frames = encoder.encode(texture.as_ptr(), i as _).unwrap();
+            assert_eq!(frames.len(), 1, "{:?}", f);
+            assert_eq!(frames[0].key == 1, i == 0, "{:?} frame {}", f, i);
+            packets.append(frames);
+        }
+        let Some(mut dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        dec_ctx.conceal_errors = true;
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        let mut last = None;
+        for packet in packets.iter().filter(|p| p.pts != 3) {
+            for frame in decoder.decode(&packet.data).unwrap().iter() {
+                last = Some(read_bgra(frame.texture, WIDTH, HEIGHT).unwrap());
+            }
+        }
+        let s = pattern_ssim(&last.unwrap(), 4);
+        assert!(s >= MIN_SSIM, "{:?} ssim {:.4}", f, s);
+    }
+}
+
+// encoders without ENCODE_CAP_EMPHASIS_MAP refuse the option instead of ignoring the maps
+#[test]
+fn emphasis_map_follows_caps() {
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.emphasis_map = true;
+        let created = Encoder::new(EncodeContext { f: f.clone(), d });
+        if !f.caps.has(EncodeCapability::ENCODE_CAP_EMPHASIS_MAP) {
+            assert!(created.is_err(), "{:?}", f);
+            continue;
+        }
+        let mut encoder = created.unwrap();
+        let Some(dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let short = vec![0; encode::emphasis_map(WIDTH, HEIGHT, &[]).len() - 1];
+        let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        assert!(encoder
+            .encode_with_emphasis_map(texture.as_ptr(), 0, &short)
+            .is_err());
+        let map = encode::emphasis_map(
+            WIDTH,
+            HEIGHT,
+            &[([0, 0, WIDTH / 2, HEIGHT / 2], encode::MAX_EMPHASIS_LEVEL)],
+        );
+        let mut packets = vec![];
+        for i in 0..FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            packets.append(
+                encoder
+                    .encode_with_emphasis_map(texture.as_ptr(), i as _, &map)
+                    .unwrap(),
+            );
+        }
+        assert_decodes_to_pattern(&f, dec_ctx, packets, 0);
+    }
+}
+
+// rows of 2 pixel wide strokes in saturated colors on white, like colored text
+fn colored_text() -> Vec<u8> {
+    let colors = [[0, 0, 255], [0, 160, 0], [255, 0, 0], [200, 0, 200]];
+    let mut bgra = vec![255u8; (WIDTH * HEIGHT * 4) as usize];
+    for (i, p) in bgra.chunks_exact_mut(4).enumerate() {
+        let (x, y) = (i % WIDTH as usize, i / WIDTH as usize);
+        let (glyph, row) = (x / 12, y / 16);
+        let stroke = (x % 12 < 2 || (y % 16 + glyph) % 5 == 0) && y % 16 < 12 && x % 12 < 10;
+        if stroke && (glyph * 7 + row * 3) % 4 != 0 {
+            p[..3].copy_from_slice(&colors[(glyph + row) % colors.len()]);
+        }
+    }
+    bgra
+}
+
+// more chroma bits at a bitrate too low for the strokes, the color comes out closer
+#[test]
+fn negative_chroma_qp_offset() {
+    let decoders = decode::available();
+    let source = colored_text();
+    let (width, height) = (WIDTH as usize, HEIGHT as usize);
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.kbitrate = 300;
+        d.chroma_qp_offset = -12;
+        if f.driver != Driver::NV {
+            assert!(
+                Encoder::new(EncodeContext { f: f.clone(), d }).is_err(),
+                "{:?}",
+                f
+            );
+            continue;
+        }
+        let Some(dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let chroma_psnr = |chroma_qp_offset: i32| {
+            let mut d = d;
+            d.chroma_qp_offset = chroma_qp_offset;
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let packets: Vec<_> = (0..FRAMES)
+                .flat_map(|i| {
+                    encoder
+                        .encode(texture.as_ptr(), i as _)
+                        .unwrap()
+                        .drain(..)
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            let decoded = decode_all(dec_ctx.clone(), packets);
+            let last = decoded.last().unwrap();
+            psnr(
+                &chroma(&source, width, height),
+                &chroma(last, width, height),
+            )
+        };
+        let (default, boosted) = (chroma_psnr(0), chroma_psnr(-12));
+        println!(
+            "{:?}: chroma psnr {:.2}, with -12 {:.2}",
+            f, default, boosted
+        );
+        assert!(
+            boosted > default,
+            "{:?}: {:.2} <= {:.2}",
+            f,
+            boosted,
+            default
+        );
+    }
+}
+
+// the video processor and the cpu convert the input to about the same colours
+#[test]
+fn color_convert_paths_match() {
+    let decoders = decode::available();
+    let source = colored_text();
+    let (width, height) = (WIDTH as usize, HEIGHT as usize);
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.kbitrate = 5000;
+        d.color_convert = ColorConvert::COLOR_CONVERT_SOFTWARE;
+        if matches!(f.driver, Driver::NV | Driver::AMF) {
+            assert!(
+                Encoder::new(EncodeContext { f: f.clone(), d }).is_err(),
+                "{:?}",
+                f
+            );
+            continue;
+        }
+        let Some(dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let decoded = |color_convert: ColorConvert| {
+            let mut d = d;
+            d.color_convert = color_convert;
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let packets: Vec<_> = (0..FRAMES)
+                .flat_map(|i| {
+                    encoder
+                        .encode(texture.as_ptr(), i as _)
+                        .unwrap()
+                        .drain(..)
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            decode_all(dec_ctx.clone(), packets).pop().unwrap()
+        };
+        let hardware = decoded(ColorConvert::COLOR_CONVERT_HARDWARE);
+        let software = decoded(ColorConvert::COLOR_CONVERT_SOFTWARE);
+        let luma_psnr = psnr(
+            &luma(&hardware, width, height),
+            &luma(&software, width, height),
+        );
+        let chroma_psnr = psnr(
+            &chroma(&hardware, width, height),
+            &chroma(&software, width, height),
+        );
+        println!(
+            "{:?}: hardware and software conversion psnr luma {:.2}, chroma {:.2}",
+            f, luma_psnr, chroma_psnr
+        );
+        assert!(luma_psnr >= 35.0, "{:?}: luma {:.2}", f, luma_psnr);
+        assert!(chroma_psnr >= 30.0, "{:?}: chroma {:.2}", f, chroma_psnr);
+        let s = ssim(
+            &luma(&source, width, height),
+            &luma(&software, width, height),
+            width,
+            height,
+        );
+        assert!(s >= MIN_SSIM, "{:?}: ssim {:.4}", f, s);
+    }
+}
+
+// without a bitrate the sessions hold a quality, a better one costs more bytes
+#[test]
+fn quality_mode() {
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let encode_at = |quality: i32| {
+            let mut d = dynamic_context();
+            d.device = Some(device.as_ptr());
+            d.kbitrate = 0;
+            d.quality = quality;
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            assert!(encoder.set_bitrate(2000).is_err(), "{:?}", f);
+            let mut packets = vec![];
+            for i in 0..FRAMES {
+                let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+                let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+                packets.append(encoder.encode(texture.as_ptr(), i as _).unwrap());
+            }
+            packets
+        };
+        let (fine, coarse) = (encode_at(18), encode_at(40));
+        let bytes = |packets: &[encode::EncodeFrame]| -> usize {
+            packets.iter().map(|p| p.data.len()).sum()
+        };
+        println!(
+            "{:?}: {} bytes at quality 18, {} at 40",
+            f,
+            bytes(&fine),
+            bytes(&coarse)
+        );
+        assert!(bytes(&fine) > bytes(&coarse), "{:?}", f);
+        if let Some(dec_ctx) = matching_decoder(&f, &decoders) {
+            assert_decodes_to_pattern(&f, dec_ctx, fine, 0);
+        }
+    }
+}
+
+// every backend holds a game streaming session to one frame out per frame in
+#[test]
+fn encode_one_per_frame() {
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.tune = Tune::CloudGaming;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut packets = vec![];
+        for i in 0..FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let frame = encoder.encode_one(texture.as_ptr(), i as _).unwrap();
+            assert_eq!(frame.pts, i as i64, "{:?}", f);
+            packets.push(frame);
+        }
+        if let Some(dec_ctx) = matching_decoder(&f, &decoders) {
+            assert_decodes_to_pattern(&f, dec_ctx, packets, 0);
+        }
+    }
+}
+
+// Frames of several slices with inline parameter sets, the callback gets every NAL unit
+// of a frame with its encode call and none of another.
+#[test]
+fn many_nal_units_per_frame() {
+    const STRESS_FRAMES: usize = 120;
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.tune = Tune::CloudGaming;
+        d.repeat_headers = true;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut packets = vec![];
+        let mut nals = 0;
+        for i in 0..STRESS_FRAMES {
+            if i % 10 == 0 {
+                encoder.request_keyframe().unwrap();
+            }
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let frame = encoder.encode_one(texture.as_ptr(), i as _).unwrap();
+            assert_eq!(frame.pts, i as i64, "{:?}", f);
+            let count = frame.nal_units().count();
+            if frame.key == 1 {
+                // the parameter sets and a slice at least
+                assert!(count >= 3, "{:?} frame {} has {} NAL units", f, i, count);
+            }
+            nals += count;
+            packets.push(frame);
+        }
+        println!("{:?}: {} NAL units in {} frames", f, nals, STRESS_FRAMES);
+        if let Some(dec_ctx) = matching_decoder(&f, &decoders) {
+            assert_decodes_to_pattern(&f, dec_ctx, packets, 0);
+        }
+    }
+}
+
+// Each intra refresh starts with a recovery point SEI: NV refreshes every gop, MFX every
+// half second. AMF writes none, the FFmpeg encoders as their build does.
+#[test]
+fn recovery_points_at_refresh_cycles() {
+    const REFRESH_FRAMES: usize = 60;
+    for f in encode::available(dynamic_context()) {
+        let period = match f.driver {
+            Driver::NV if f.caps.has(EncodeCapability::ENCODE_CAP_INTRA_REFRESH) => GOP as usize,
+            Driver::MFX => 15,
+            _ => 0,
+        };
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.tune = Tune::CloudGaming;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut points = vec![];
+        for i in 0..REFRESH_FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let frame = encoder.encode_one(texture.as_ptr(), i as _).unwrap();
+            let start = frame.flags.has(FrameFlag::FRAME_FLAG_RECOVERY_POINT_START);
+            let point = frame.recovery_point(f.data_format);
+            assert_eq!(
+                start,
+                point.is_some() && frame.key == 0,
+                "{:?} frame {}",
+                f,
+                i
+            );
+            if let Some(count) = point {
+                assert!(count >= 0, "{:?} frame {}: {}", f, i, count);
+                points.push(i);
+            }
+        }
+        println!("{:?}: recovery points at {:?}", f, points);
+        if period == 0 {
+            continue;
+        }
+        assert!(points.len() >= 2, "{:?}: {:?}", f, points);
+        for pair in points.windows(2) {
+            assert_eq!(pair[1] - pair[0], period, "{:?}: {:?}", f, points);
+        }
+    }
+}
+
+// the pps signals the requested entropy coding and the stream decodes, h265 refuses CAVLC
+#[test]
+fn entropy_coding_in_pps() {
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        for coding in [
+            EntropyCoding::ENTROPY_CODING_CABAC,
+            EntropyCoding::ENTROPY_CODING_CAVLC,
+        ] {
+            let mut d = dynamic_context();
+            d.device = Some(device.as_ptr());
+            d.entropy_coding = coding;
+            let created = Encoder::new(EncodeContext { f: f.clone(), d });
+            if f.data_format != DataFormat::H264 {
+                assert!(created.is_err(), "{:?} {:?}", f, coding);
+                continue;
+            }
+            let mut encoder = created.unwrap();
+            let mut packets = vec![];
+            for i in 0..FRAMES {
+                let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+                let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+                packets.append(encoder.encode(texture.as_ptr(), i as _).unwrap());
+            }
+            let sps = h264::find_sps(&packets[0].data).unwrap();
+            // baseline has no CABAC, the sessions encode main or high
+            assert_ne!(sps.profile_idc, 66, "{:?}", f);
+            let pps = packets[0]
+                .nal_units()
+                .find(|nal| nal.h264_type() == h264::NAL_PPS)
+                .map(|nal| h264::Pps::parse(nal.data).unwrap())
+                .unwrap();
+            assert_eq!(
+                pps.entropy_coding_mode_flag,
+                coding == EntropyCoding::ENTROPY_CODING_CABAC,
+                "{:?}",
+                f
+            );
+            if let Some(dec_ctx) = matching_decoder(&f, &decoders) {
+                assert_decodes_to_pattern(&f, dec_ctx, packets, 0);
+            }
+        }
+    }
+}
+
+// the sps carries the sar, 8:9 as the extended one, 4:3 from the table
+#[test]
+fn sample_aspect_ratio_in_sps() {
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        for (sar, expected) in [((16, 18), (8, 9)), ((4, 3), (4, 3))] {
+            let mut d = dynamic_context();
+            d.device = Some(device.as_ptr());
+            d.sar = sar;
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            assert_eq!(encoder.dimensions(), (WIDTH, HEIGHT));
+            assert_eq!(encoder.sample_aspect_ratio(), expected);
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            let packets = encoder.encode(texture.as_ptr(), 0).unwrap();
+            let vui = if f.data_format == DataFormat::H264 {
+                h264::find_sps(&packets[0].data).unwrap().vui
+            } else {
+                packets[0]
+                    .nal_units()
+                    .find(|nal| nal.hevc_type() == hevc::NAL_SPS)
+                    .and_then(|nal| hevc::Sps::parse(nal.data).ok())
+                    .unwrap()
+                    .vui
+            };
+            let signaled = vui.and_then(|vui| vui.sample_aspect_ratio());
+            let expected = (expected.0 as u16, expected.1 as u16);
+            assert_eq!(signaled, Some(expected), "{:?}", f);
+        }
+    }
+}
+
+// gray noise, no chroma for the conversion to lose and nothing a lossy encode keeps
+fn gray_noise() -> Vec<u8> {
+    let mut state = 1u32;
+    let mut bgra = vec![255u8; (WIDTH * HEIGHT * 4) as usize];
+    for p in bgra.chunks_exact_mut(4) {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        p[..3].fill((state >> 24) as u8);
+    }
+    bgra
+}
+
+// a smooth gradient on the left, the gray noise on the right
+fn half_flat_half_noise() -> Vec<u8> {
+    let mut bgra = gray_noise();
+    for (y, row) in bgra.chunks_exact_mut(WIDTH as usize * 4).enumerate() {
+        for (x, p) in row[..WIDTH as usize * 2].chunks_exact_mut(4).enumerate() {
+            p[..3].fill((64 + x / 8 + y / 8) as u8);
+        }
+    }
+    bgra
+}
+
+// Spatial AQ lowers the qp of flat blocks and raises it in detail, at a tight bitrate the
+// flat half gains on the noisy one. Asserted for NVENC, AMF's VBAQ and MFX's MBBRC aren't
+// tuned to the same effect.
+#[test]
+fn spatial_aq_favors_flat_regions() {
+    let decoders = decode::available();
+    let source = half_flat_half_noise();
+    let (width, height) = (WIDTH as usize, HEIGHT as usize);
+    let source_luma = luma(&source, width, height);
+    // luma psnr of the left and the right half
+    let halves = |decoded: &[f64]| {
+        let half = |l: &[f64], right: bool| -> Vec<f64> {
+            l.chunks_exact(width)
+                .flat_map(|row| {
+                    if right {
+                        &row[width / 2..]
+                    } else {
+                        &row[..width / 2]
+                    }
+                })
+                .copied()
+                .collect()
+        };
+        (
+            psnr(&half(&source_luma, false), &half(decoded, false)),
+            psnr(&half(&source_luma, true), &half(decoded, true)),
+        )
+    };
+    for f in encode::available(dynamic_context()) {
+        let Some(dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        let encode = |aq_mode| {
+            let mut d = dynamic_context();
+            d.device = Some(device.as_ptr());
+            d.kbitrate = 300;
+            d.aq_mode = aq_mode;
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            let packets: Vec<_> = encoder
+                .encode(texture.as_ptr(), 0)
+                .unwrap()
+                .drain(..)
+                .collect();
+            let decoded = decode_all(dec_ctx.clone(), packets);
+            assert_eq!(decoded.len(), 1, "{:?}", f);
+            halves(&luma(&decoded[0], width, height))
+        };
+        let (flat_off, noise_off) = encode(AqMode::AQ_OFF);
+        let (flat_on, noise_on) = encode(AqMode::AQ_SPATIAL);
+        if f.vendor != Driver::NV {
+            continue;
+        }
+        assert!(
+            flat_on - noise_on > flat_off - noise_off,
+            "{:?} flat {:.2} -> {:.2} dB, noise {:.2} -> {:.2} dB",
+            f,
+            flat_off,
+            flat_on,
+            noise_off,
+            noise_on
+        );
+    }
+}
+
+// Off by the rounding of the color conversions at most, in and out. H.264 streams are
+// High 4:4:4 Predictive, which the d3d11 decoders don't decode, their sps is checked.
+#[test]
+fn lossless_decodes_to_source() {
+    const MAX_DIFF: u8 = 3;
+    let decoders = decode::available();
+    let source = gray_noise();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.lossless = true;
+        let created = Encoder::new(EncodeContext { f: f.clone(), d });
+        let supported = match f.driver {
+            Driver::NV => f.caps.has(EncodeCapability::ENCODE_CAP_LOSSLESS),
+            Driver::FFMPEG => f.vendor == Driver::NV,
+            _ => false,
+        };
+        assert_eq!(created.is_ok(), supported, "{:?}", f);
+        let Ok(mut encoder) = created else {
+            continue;
+        };
+        assert!(encoder.set_bitrate(4000).is_err());
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        let mut packets = vec![];
+        for i in 0..2 {
+            packets.append(encoder.encode(texture.as_ptr(), i).unwrap());
+        }
+        if f.data_format == DataFormat::H264 {
+            let sps = h264::find_sps(&packets[0].data).unwrap();
+            assert_eq!(sps.profile_idc, 244, "{:?}", f);
+            continue;
+        }
+        let Some(dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let decoded = decode_all(dec_ctx, packets);
+        assert_eq!(decoded.len(), 2, "{:?}", f);
+        for (i, bgra) in decoded.iter().enumerate() {
+            let diff = bgra
+                .chunks_exact(4)
+                .zip(source.chunks_exact(4))
+                .map(|(a, b)| a[1].abs_diff(b[1]))
+                .max()
+                .unwrap();
+            assert!(diff <= MAX_DIFF, "{:?} frame {} off by {}", f, i, diff);
+        }
+    }
+}
+
+// a static frame encodes to almost nothing, the filler keeps the floor and decodes
+#[test]
+fn static_scene_padded_to_min_bitrate() {
+    const MIN_KBITRATE: i32 = 1000;
+    const INTERVAL_MS: i64 = 33;
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.min_kbitrate = MIN_KBITRATE;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        let mut packets = vec![];
+        for i in 0..FRAMES {
+            let ms = i as i64 * INTERVAL_MS;
+            let frames = encoder.encode(texture.as_ptr(), ms).unwrap();
+            let bytes: usize = frames.iter().map(|f| f.data.len()).sum();
+            // a call without output carries the time over to the next one
+            if i > 0 && !frames.is_empty() {
+                let floor = (MIN_KBITRATE as i64 * INTERVAL_MS / 8) as usize;
+                assert!(bytes >= floor, "{:?} frame {}: {} bytes", f, i, bytes);
+            }
+            packets.append(frames);
+        }
+        encoder.set_min_bitrate(0).unwrap();
+        let frames = encoder
+            .encode(texture.as_ptr(), FRAMES as i64 * INTERVAL_MS)
+            .unwrap();
+        let filler = frames
+            .iter()
+            .flat_map(|frame| frame.nal_units())
+            .any(|nal| {
+                nal.h264_type() == h264::NAL_FILLER && f.data_format == DataFormat::H264
+                    || nal.hevc_type() == hevc::NAL_FD && f.data_format == DataFormat::H265
+            });
+        assert!(!filler, "{:?}", f);
+        if let Some(dec_ctx) = matching_decoder(&f, &decoders) {
+            let decoded = decode_all(dec_ctx, packets);
+            assert_eq!(decoded.len(), FRAMES, "{:?}", f);
+            assert!(
+                pattern_ssim(decoded.last().unwrap(), 0) > MIN_SSIM,
+                "{:?}",
+                f
+            );
+        }
+    }
+}
+
+// Frames 7 ms apart in bursts with 500 ms pauses. At a fixed rate the rate control
+// budgets kbitrate / framerate per frame whatever the pauses, the VFR mode follows the
+// intervals and stays closer to kbitrate.
+#[test]
+fn variable_framerate_adherence() {
+    let intervals: Vec<i64> = (0..60).map(|i| if i % 6 == 5 { 500 } else { 7 }).collect();
+    let duration_ms: i64 = intervals.iter().sum();
+    let kbitrate = |f: &FeatureContext, variable_framerate: bool| {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.framerate = 60;
+        d.variable_framerate = variable_framerate;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut ms = 0;
+        let mut bytes = 0;
+        for (i, interval) in intervals.iter().enumerate() {
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &scene(i)).unwrap();
+            let frames = encoder.encode(texture.as_ptr(), ms).unwrap();
+            bytes += frames.iter().map(|f| f.data.len()).sum::<usize>();
+            ms += interval;
+        }
+        (bytes * 8) as f64 / duration_ms as f64
+    };
+    let target = dynamic_context().kbitrate as f64;
+    for f in encode::available(dynamic_context()) {
+        if f.driver == Driver::MFX {
+            // no framerate changes at runtime
+            continue;
+        }
+        let fixed = kbitrate(&f, false);
+        let variable = kbitrate(&f, true);
+        println!("{:?}: fixed {:.0}k, variable {:.0}k", f, fixed, variable);
+        assert!(
+            (variable - target).abs() < (fixed - target).abs(),
+            "{:?}: fixed {:.0}k, variable {:.0}k",
+            f,
+            fixed,
+            variable
+        );
+    }
+}
+
+// An odd size is coded one row larger, the margin repeats the last row and the decoded
+// frame matches the source in the 1366x769 region.
+#[test]
+fn odd_size_round_trip() {
+    const ODD_WIDTH: i32 = 1366;
+    const ODD_HEIGHT: i32 = 769;
+    let decoders = decode::available();
+    let mut d = dynamic_context();
+    d.width = ODD_WIDTH;
+    d.height = ODD_HEIGHT;
+    for f in encode::available(d) {
+        let Some(mut dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        d.device = Some(device.as_ptr());
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        assert_eq!(encoder.display_size(), (ODD_WIDTH, ODD_HEIGHT));
+        assert_eq!(encoder.coded_size(), (ODD_WIDTH, ODD_HEIGHT + 1));
+        let (width, height) = (ODD_WIDTH as usize, ODD_HEIGHT as usize);
+        let source = bgra_pattern(width, height, 0);
+        let texture = Texture::from_bgra(device.as_ptr(), ODD_WIDTH, ODD_HEIGHT, &source).unwrap();
+        let mut packets = vec![];
+        for i in 0..FRAMES {
+            packets.append(encoder.encode(texture.as_ptr(), i as _).unwrap());
+        }
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        let mut decoded = None;
+        for packet in packets {
+            for frame in decoder.decode(&packet.data).unwrap().iter() {
+                assert_eq!((frame.width, frame.height), encoder.coded_size(), "{:?}", f);
+                decoded = Some(read_bgra(frame.texture, ODD_WIDTH, ODD_HEIGHT).unwrap());
+            }
+        }
+        let decoded = decoded.unwrap();
+        let s = ssim(
+            &luma(&source, width, height),
+            &luma(&decoded, width, height),
+            width,
+            height,
+        );
+        assert!(s >= MIN_SSIM, "{:?} ssim {:.4}", f, s);
+    }
+}
+
+// The sizes whose coded height is aligned past the displayed one, every encoder's stream
+// decoded by every decoder of the format, of any vendor. The rows next to the cropped ones
+// are compared on their own, a decoder showing the coded rows or cropping at the wrong
+// origin shifts them.
+#[test]
+fn cross_vendor_crop_round_trip() {
+    let decoders = decode::available();
+    for (width, height) in [(1920, 1080), (1280, 720), (1440, 900)] {
+        let mut d = dynamic_context();
+        d.width = width;
+        d.height = height;
+        d.kbitrate = 8000;
+        for f in encode::available(d) {
+            let device = Device::new(f.luid).unwrap();
+            d.device = Some(device.as_ptr());
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            let (w, h) = (width as usize, height as usize);
+            let source = bgra_pattern(w, h, 0);
+            let texture = Texture::from_bgra(device.as_ptr(), width, height, &source).unwrap();
+            let mut packets = vec![];
+            for i in 0..GOP {
+                packets.append(encoder.encode(texture.as_ptr(), i as _).unwrap());
+            }
+            for dec_ctx in decoders.iter().filter(|d| d.data_format == f.data_format) {
+                let mut dec_ctx = dec_ctx.clone();
+                let dec_device = Device::new(dec_ctx.luid).unwrap();
+                dec_ctx.device = Some(dec_device.as_ptr());
+                let mut decoder = Decoder::new(dec_ctx.clone()).unwrap();
+                let mut decoded = None;
+                for packet in packets.iter() {
+                    for frame in decoder.decode(&packet.data).unwrap().iter() {
+                        assert_eq!(
+                            (frame.width, frame.height),
+                            (width, height),
+                            "{:?} to {:?}",
+                            f.driver,
+                            dec_ctx.driver
+                        );
+                        decoded = Some(read_bgra(frame.texture, width, height).unwrap());
+                    }
+                }
+                let decoded = decoded.unwrap();
+                let whole = ssim(&luma(&source, w, h), &luma(&decoded, w, h), w, h);
+                let rows = 16 * w * 4;
+                let bottom = ssim(
+                    &luma(&source[source.len() - rows..], w, 16),
+                    &luma(&decoded[decoded.len() - rows..], w, 16),
+                    w,
+                    16,
+                );
+                assert!(
+                    whole >= MIN_SSIM && bottom >= MIN_SSIM,
+                    "{:?} to {:?} at {}x{}: ssim {:.4}, bottom rows {:.4}",
+                    f.driver,
+                    dec_ctx.driver,
+                    width,
+                    height,
+                    whole,
+                    bottom
+                );
+            }
+        }
+    }
+}
+
+// Textures of the display size rounded up to input_alignment, filled with the pattern of
+// the display size and garbage beyond, decode to the pattern. Larger ones are rejected.
+#[test]
+fn aligned_input_textures() {
+    const ALIGNED_WIDTH: i32 = 1366;
+    const ALIGNED_HEIGHT: i32 = 769;
+    let decoders = decode::available();
+    let mut d = dynamic_context();
+    d.width = ALIGNED_WIDTH;
+    d.height = ALIGNED_HEIGHT;
+    for f in encode::available(d) {
+        let Some(mut dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        d.device = Some(device.as_ptr());
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let (align_width, align_height) = encoder.input_alignment();
+        let texture_width = (ALIGNED_WIDTH as u32).next_multiple_of(align_width) as i32;
+        let texture_height = (ALIGNED_HEIGHT as u32).next_multiple_of(align_height) as i32;
+        let (width, height) = (ALIGNED_WIDTH as usize, ALIGNED_HEIGHT as usize);
+        let source = bgra_pattern(width, height, 0);
+        let mut aligned = vec![0xff; texture_width as usize * texture_height as usize * 4];
+        for (y, row) in source.chunks(width * 4).enumerate() {
+            let start = y * texture_width as usize * 4;
+            aligned[start..start + width * 4].copy_from_slice(row);
+        }
+        let texture =
+            Texture::from_bgra(device.as_ptr(), texture_width, texture_height, &aligned).unwrap();
+        let mut packets = vec![];
+        for i in 0..FRAMES {
+            let frames = encoder.encode(texture.as_ptr(), i as _);
+            packets.append(frames.unwrap_or_else(|e| panic!("{:?} failed: {}", f, e)));
+        }
+        let larger = vec![0u8; (texture_width + align_width as i32) as usize * 4 * height];
+        let larger = Texture::from_bgra(
+            device.as_ptr(),
+            texture_width + align_width as i32,
+            ALIGNED_HEIGHT,
+            &larger,
+        )
+        .unwrap();
+        assert_eq!(
+            encoder.encode(larger.as_ptr(), FRAMES as _).err(),
+            Some(HwcodecErrno::HWCODEC_ERR_RESET_REQUIRED as i32)
+        );
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        let mut decoded = None;
+        for packet in packets {
+            for frame in decoder.decode(&packet.data).unwrap().iter() {
+                decoded = Some(read_bgra(frame.texture, ALIGNED_WIDTH, ALIGNED_HEIGHT).unwrap());
+            }
+        }
+        let decoded = decoded.unwrap();
+        let s = ssim(
+            &luma(&source, width, height),
+            &luma(&decoded, width, height),
+            width,
+            height,
+        );
+        assert!(s >= MIN_SSIM, "{:?} ssim {:.4}", f, s);
+    }
+}
+
+// every frame given to the old session comes out before the new size applies
+#[test]
+fn resize_flushes_pending_frames() {
+    const NEW_WIDTH: i32 = 1280;
+    const NEW_HEIGHT: i32 = 720;
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let Some(mut dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut old = vec![];
+        for i in 0..FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            old.append(
+                encoder
+                    .encode_with_user_data(texture.as_ptr(), i as _, i as u64)
+                    .unwrap(),
+            );
+        }
+        let source = bgra_pattern(NEW_WIDTH as _, NEW_HEIGHT as _, 0);
+        let texture = Texture::from_bgra(device.as_ptr(), NEW_WIDTH, NEW_HEIGHT, &source).unwrap();
+        let err = encoder.encode(texture.as_ptr(), FRAMES as _).unwrap_err();
+        assert!(HwcodecErrno::is_reset_required(err), "{:?} {}", f, err);
+        old.append(&mut encoder.reset(NEW_WIDTH, NEW_HEIGHT).unwrap());
+        let mut user_data: Vec<_> = old.iter().map(|p| p.user_data).collect();
+        user_data.sort();
+        assert_eq!(user_data, (0..FRAMES as u64).collect::<Vec<_>>(), "{:?}", f);
+        assert_eq!(encoder.display_size(), (NEW_WIDTH, NEW_HEIGHT));
+        let mut new = vec![];
+        for i in 0..GOP {
+            new.append(
+                encoder
+                    .encode(texture.as_ptr(), FRAMES as i64 + i as i64)
+                    .unwrap(),
+            );
+        }
+        assert_eq!(new[0].key, 1, "{:?}", f);
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        let mut decoded = None;
+        for packet in new {
+            for frame in decoder.decode(&packet.data).unwrap().iter() {
+                assert_eq!((frame.width, frame.height), (NEW_WIDTH, NEW_HEIGHT));
+                decoded = Some(read_bgra(frame.texture, NEW_WIDTH, NEW_HEIGHT).unwrap());
+            }
+        }
+        let (width, height) = (NEW_WIDTH as usize, NEW_HEIGHT as usize);
+        let s = ssim(
+            &luma(&source, width, height),
+            &luma(&decoded.unwrap(), width, height),
+            width,
+            height,
+        );
+        assert!(s >= MIN_SSIM, "{:?} ssim {:.4}", f, s);
+    }
+}
+
+// the timings lag the encode calls, they are drained until every frame showed up
+#[test]
+fn gpu_timings_match_frames() {
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        d.gpu_timing = true;
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut timings = vec![];
+        for i in 0..FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            encoder
+                .encode_with_user_data(texture.as_ptr(), i as _, 1000 + i as u64)
+                .unwrap();
+            timings.append(&mut encoder.gpu_timings());
+        }
+        for _ in 0..100 {
+            if timings.len() >= FRAMES {
+                break;
+            }
+            device.flush();
+            thread::sleep(Duration::from_millis(10));
+            timings.append(&mut encoder.gpu_timings());
+        }
+        if f.driver == Driver::FFMPEG {
+            assert!(timings.is_empty(), "{:?}", f);
+            continue;
+        }
+        assert_eq!(timings.len(), FRAMES, "{:?}", f);
+        for (i, t) in timings.iter().enumerate() {
+            assert_eq!(
+                (t.frame, t.userData),
+                (i as i64, 1000 + i as u64),
+                "{:?}",
+                f
+            );
+            if t.convertMs >= 0.0 {
+                assert!(t.encodeMs >= 0.0, "{:?} {:?}", f, t);
+            }
+        }
+        let valid: Vec<_> = timings.iter().filter(|t| t.convertMs >= 0.0).collect();
+        println!(
+            "{:?} {:?}: {:.3} ms convert, {:.3} ms encode",
+            f.driver,
+            f.data_format,
+            valid.iter().map(|t| t.convertMs).sum::<f32>() / valid.len().max(1) as f32,
+            valid.iter().map(|t| t.encodeMs).sum::<f32>() / valid.len().max(1) as f32
+        );
+    }
+}
+
+// A second device plays a renderer on another queue, its writes reach the gpu when it
+// flushes. The encoder waits on its fence for the second frame, the first one races the
+// renderer and is only reported: whether it reads the old frame depends on the driver.
+#[test]
+fn encode_waits_on_fence() {
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let Some(dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        let producer = Device::new(f.luid).unwrap();
+        let Ok(fence) = SharedFence::new(&producer) else {
+            println!("no shared fences, skipped");
+            return;
+        };
+        let pattern = |i| bgra_pattern(WIDTH as _, HEIGHT as _, i);
+        let rendered = Texture::from_bgra(producer.as_ptr(), WIDTH, HEIGHT, &pattern(0)).unwrap();
+        producer.flush();
+        let input = rendered.open_on(&device).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+        let mut packets = vec![];
+        fence
+            .write_and_signal(&rendered, WIDTH, HEIGHT, &pattern(1), 1, false)
+            .unwrap();
+        packets.extend(encoder.encode(input.as_ptr(), 0).unwrap().drain(..));
+        producer.flush();
+        fence
+            .write_and_signal(&rendered, WIDTH, HEIGHT, &pattern(2), 2, false)
+            .unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                producer.flush();
+            });
+            let frames = encoder
+                .encode_synced(input.as_ptr(), fence.handle(), 2, 1)
+                .unwrap();
+            packets.extend(frames.drain(..));
+        });
+        let decoded = decode_all(dec_ctx, packets);
+        assert_eq!(decoded.len(), 2, "{:?}", f);
+        println!(
+            "{:?} unsynced frame ssim {:.4} to the rendered one, {:.4} to the old one",
+            f,
+            pattern_ssim(&decoded[0], 1),
+            pattern_ssim(&decoded[0], 0)
+        );
+        let s = pattern_ssim(&decoded[1], 2);
+        assert!(s >= MIN_SSIM, "{:?} synced frame ssim {:.4}", f, s);
+    }
+}
+
+#[cfg(feature = "mfx")]
+#[test]
+fn existing_session_outlives_encoder() {
+    let Some(f) = encode::available(dynamic_context())
+        .into_iter()
+        .find(|f| f.driver == Driver::MFX)
+    else {
+        return;
+    };
+    let device = Device::new(f.luid).unwrap();
+    let session = debug_new_mfx_session(device.as_ptr());
+    assert!(!session.is_null());
+    let mut d = dynamic_context();
+    d.device = Some(device.as_ptr());
+    let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+    let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+    // the second encoder proves the first one left the session usable
+    for _ in 0..2 {
+        let ctx = EncodeContext { f: f.clone(), d };
+        let mut encoder = Encoder::from_existing_session(Driver::MFX, session, ctx).unwrap();
+        assert!(encoder.recreate_after_device_lost(device.as_ptr()).is_err());
+        let frames = encoder.encode(texture.as_ptr(), 0).unwrap();
+        assert_eq!(frames[0].key, 1);
+        drop(encoder);
+        assert!(debug_mfx_session_alive(session));
+    }
+    debug_close_mfx_session(session);
+}
+
+// a closed encoder leaves the device to the next one, each closes then drops cleanly
+#[test]
+fn close_before_drop() {
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        for _ in 0..2 {
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            assert!(
+                !encoder.encode(texture.as_ptr(), 0).unwrap().is_empty(),
+                "{:?}",
+                f
+            );
+            assert_eq!(encoder.close(), Ok(()), "{:?}", f);
+        }
+    }
+}
+
+// frames land in the registered textures in turn, the stream size is checked against theirs
+#[test]
+fn decodes_into_staging_textures() {
+    let encoders = encode::available(dynamic_context());
+    let decoders = decode::available();
+    for f in encoders.iter() {
+        let Some(mut dec_ctx) = matching_decoder(f, &decoders) else {
+            continue;
+        };
+        let enc_device = Device::new(f.luid).unwrap();
+        let packets: Vec<_> = encode_pattern(f, &enc_device)
+            .into_iter()
+            .flatten()
+            .collect();
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        let textures: Vec<_> = (0..2)
+            .map(|_| StagingTexture::new(&dec_device, WIDTH, HEIGHT).unwrap())
+            .collect();
+        let ptrs: Vec<_> = textures.iter().map(|t| t.as_ptr()).collect();
+        let small = StagingTexture::new(&dec_device, WIDTH / 2, HEIGHT).unwrap();
+        let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+        let not_staging = Texture::from_bgra(dec_device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        assert!(decoder.decode_to_staging(&packets[0].data).is_err());
+        assert!(decoder.register_staging(&[]).is_err());
+        assert!(decoder.register_staging(&[not_staging.as_ptr()]).is_err());
+        assert!(decoder
+            .register_staging(&[ptrs[0], small.as_ptr()])
+            .is_err());
+        // accepted until the stream's size is known
+        decoder.register_staging(&[small.as_ptr()]).unwrap();
+        let mut first = 0;
+        for packet in &packets[..GOP as usize] {
+            first += decoder.decode(&packet.data).unwrap().len();
+        }
+        assert!(decoder.register_staging(&[small.as_ptr()]).is_err());
+
+        decoder.register_staging(&ptrs).unwrap();
+        let mut staged = vec![];
+        for packet in &packets[GOP as usize..] {
+            for frame in decoder.decode_to_staging(&packet.data).unwrap() {
+                assert_eq!((frame.width, frame.height), (WIDTH, HEIGHT), "{:?}", f);
+                assert!(frame.row_pitch >= WIDTH * 4, "{:?}", f);
+                staged.push((
+                    frame.index,
+                    read_bgra(ptrs[frame.index], WIDTH, HEIGHT).unwrap(),
+                ));
+            }
+        }
+        assert!(!staged.is_empty(), "{:?} decoded nothing", f);
+        for (i, (index, bgra)) in staged.iter().enumerate() {
+            assert_eq!(*index, i % 2, "{:?}", f);
+            let s = pattern_ssim(bgra, first + i);
+            assert!(s >= MIN_SSIM, "{:?} frame {} ssim {:.4}", f, i, s);
+        }
+    }
+}
+
+// the frames come back in memory without row padding, the staging texture is reused
+#[test]
+fn decodes_to_bgra() {
+    let encoders = encode::available(dynamic_context());
+    let decoders = decode::available();
+    for f in encoders.iter() {
+        let Some(mut dec_ctx) = matching_decoder(f, &decoders) else {
+            continue;
+        };
+        let enc_device = Device::new(f.luid).unwrap();
+        let packets = encode_pattern(f, &enc_device).into_iter().flatten();
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        let mut frames = vec![];
+        for packet in packets {
+            frames.append(&mut decoder.decode_bgra(&packet.data).unwrap());
+        }
+        assert!(!frames.is_empty(), "{:?} decoded nothing", f);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(
+                (frame.width, frame.height, frame.stride),
+                (WIDTH, HEIGHT, WIDTH * 4),
+                "{:?}",
+                f
+            );
+            assert_eq!(frame.data.len(), (WIDTH * 4 * HEIGHT) as usize, "{:?}", f);
+            let s = pattern_ssim(&frame.data, i);
+            assert!(s >= MIN_SSIM, "{:?} frame {} ssim {:.4}", f, i, s);
+        }
+    }
+}
+
+// every third frame also comes back in memory, the others only as textures
+#[test]
+fn decodes_with_cpu_copy() {
+    let encoders = encode::available(dynamic_context());
+    let decoders = decode::available();
+    for f in encoders.iter() {
+        let Some(mut dec_ctx) = matching_decoder(f, &decoders) else {
+            continue;
+        };
+        let enc_device = Device::new(f.luid).unwrap();
+        let packets = encode_pattern(f, &enc_device).into_iter().flatten();
+        let dec_device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(dec_device.as_ptr());
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        let mut index = 0;
+        let mut copies = 0;
+        for (i, packet) in packets.enumerate() {
+            let copy = i % 3 == 0;
+            let frames = decoder
+                .decode_with_copy(&packet.data, i as _, copy)
+                .unwrap();
+            for frame in frames.iter() {
+                assert!(!frame.texture.is_null(), "{:?}", f);
+                assert_eq!(frame.cpu_copy.is_some(), copy, "{:?} frame {}", f, index);
+                if let Some(bgra) = frame.cpu_copy.as_ref() {
+                    let size = (bgra.width, bgra.height, bgra.pts);
+                    assert_eq!(size, (WIDTH, HEIGHT, i as _), "{:?}", f);
+                    let s = pattern_ssim(&bgra.data, index);
+                    assert!(s >= MIN_SSIM, "{:?} frame {} ssim {:.4}", f, index, s);
+                    copies += 1;
+                }
+                index += 1;
+            }
+        }
+        assert!(copies > 0, "{:?} copied nothing", f);
+    }
+}
+
+// full range colour bars, white yellow cyan green magenta red blue black, in rgb
+const MJPEG_BARS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+// the probe image comes back with its full range colours
+#[test]
+fn decodes_mjpeg() {
+    let image = include_bytes!("../src/res/720p.jpg");
+    for mut ctx in decode::available()
+        .into_iter()
+        .filter(|c| c.data_format == DataFormat::MJPEG)
+    {
+        let device = Device::new(ctx.luid).unwrap();
+        ctx.device = Some(device.as_ptr());
+        let mut decoder = Decoder::new(ctx.clone()).unwrap();
+        let frames = decoder.decode_bgra(image).unwrap();
+        assert_eq!(frames.len(), 1, "{:?}", ctx);
+        let frame = &frames[0];
+        assert_eq!((frame.width, frame.height), (1280, 720), "{:?}", ctx);
+        for (i, rgb) in MJPEG_BARS.iter().enumerate() {
+            let x = i * 160 + 80;
+            let offset = 360 * frame.stride as usize + x * 4;
+            let bgra = &frame.data[offset..offset + 4];
+            for c in 0..3 {
+                let diff = (bgra[2 - c] as i32 - rgb[c] as i32).abs();
+                // the saturated bars' chroma may be clipped to studio range
+                assert!(diff <= 32, "{:?} bar {} {:?}", ctx, i, bgra);
+            }
+        }
+    }
+}
+
+// snapshots of every encoder's input, odd sizes included, decode back to the pattern
+#[test]
+fn snapshots_of_encoder_input() {
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let ctx = SnapshotContext::from_encode_context(&EncodeContext { f: f.clone(), d }, 90);
+        let mut snapshot = Snapshot::new(ctx).unwrap();
+        let mjpeg = decoders
+            .iter()
+            .find(|c| c.luid == f.luid && c.data_format == DataFormat::MJPEG);
+        for (width, height) in [(WIDTH, HEIGHT), (WIDTH + 1, HEIGHT + 1)] {
+            let (w, h) = (width as usize, height as usize);
+            let source = bgra_pattern(w, h, 0);
+            let texture = Texture::from_bgra(device.as_ptr(), width, height, &source).unwrap();
+            let image = snapshot.encode(texture.as_ptr()).unwrap();
+            let mut validator = Validator::new(DataFormat::MJPEG);
+            assert_eq!(validator.validate(&image), Ok(()), "{:?}", f);
+            assert_eq!(validator.size(), Some((w as u32, h as u32)), "{:?}", f);
+            let Some(dec_ctx) = mjpeg else {
+                continue;
+            };
+            let mut dec_ctx = dec_ctx.clone();
+            dec_ctx.device = Some(device.as_ptr());
+            let mut decoder = Decoder::new(dec_ctx).unwrap();
+            let frames = decoder.decode_bgra(&image).unwrap();
+            let frame = &frames[0];
+            let bgra: Vec<u8> = frame
+                .data
+                .chunks(frame.stride as usize)
+                .flat_map(|row| &row[..w * 4])
+                .copied()
+                .collect();
+            let s = ssim(&luma(&source, w, h), &luma(&bgra, w, h), w, h);
+            assert!(
+                s >= MIN_SSIM,
+                "{:?} {:?} ssim {:.4}",
+                f,
+                snapshot.driver(),
+                s
+            );
+        }
+    }
+}
+
+// a warmed up session encodes its first frame faster than a cold one, which goes first
+// and pays for the caches of the process too
+#[test]
+fn warmup_lowers_first_frame_latency() {
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        let first_frame = |warmup: bool| {
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            if warmup {
+                encoder.warmup().unwrap();
+            }
+            let start = Instant::now();
+            let frames = encoder.encode(texture.as_ptr(), 0).unwrap();
+            let elapsed = start.elapsed();
+            // the blank warmup frame must not be its reference
+            assert_eq!(frames[0].key, 1, "{:?}", f);
+            elapsed
+        };
+        let cold = first_frame(false);
+        let warm = first_frame(true);
+        assert!(warm < cold, "{:?} cold {:?} warm {:?}", f, cold, warm);
+    }
+}
+
+// absent chroma_loc_info means left, amf can't signal the others
+#[test]
+fn chroma_location_signaled() {
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        for location in [
+            ChromaLocation::CHROMA_LOC_LEFT,
+            ChromaLocation::CHROMA_LOC_CENTER,
+            ChromaLocation::CHROMA_LOC_TOP_LEFT,
+        ] {
+            if f.vendor == Driver::AMF && location != ChromaLocation::CHROMA_LOC_LEFT {
+                continue;
+            }
+            let mut d = dynamic_context();
+            d.device = Some(device.as_ptr());
+            d.chroma_location = location;
+            let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+            let data = encoder.encode(texture.as_ptr(), 0).unwrap()[0].data.clone();
+            let vui = match f.data_format {
+                DataFormat::H264 => h264::find_sps(&data).and_then(|sps| sps.vui),
+                DataFormat::H265 => hevc::parameter_sets(&data).sps.and_then(|sps| sps.vui),
+                _ => continue,
+            };
+            let signaled = vui.and_then(|vui| vui.chroma_loc).unwrap_or((0, 0));
+            let expected = (location as u32, location as u32);
+            assert_eq!(signaled, expected, "{:?} {:?}", f, location);
+        }
+    }
+}
+
+// every encoder round trips through a decoder of its format
+#[test]
+fn self_tests_pass() {
+    for report in self_test_all(dynamic_context()) {
+        assert!(report.success, "{:?}", report);
+    }
+}
+
+// the workers encode and decode every frame, the one after a keyframe request is an IDR
+#[cfg(feature = "tokio")]
+#[test]
+fn async_codecs_round_trip() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let decoders = decode::available();
+    for f in encode::available(dynamic_context()) {
+        let Some(mut dec_ctx) = matching_decoder(&f, &decoders) else {
+            continue;
+        };
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.gop = MAX_GOP as _;
+        d.device = Some(device.as_ptr());
+        dec_ctx.device = Some(device.as_ptr());
+        let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+        let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+        let decoded = runtime.block_on(async {
+            let encoder = AsyncEncoder::new(EncodeContext { f: f.clone(), d }, 2).unwrap();
+            let mut decoder = AsyncDecoder::new(dec_ctx).unwrap();
+            let mut decoded = 0;
+            for i in 0..FRAMES {
+                if i == FRAMES / 2 {
+                    encoder.request_keyframe().await.unwrap();
+                }
+                let frames = encoder.encode(texture.as_ptr(), i as _).await.unwrap();
+                if i == FRAMES / 2 {
+                    assert_eq!(frames[0].key, 1, "{:?}", f);
+                }
+                for frame in frames {
+                    decoded += decoder.decode(frame.data).await.unwrap().len();
+                }
+            }
+            decoded
+        });
+        assert!(decoded > 0, "{:?} decoded nothing", f);
+    }
+}
+
+// the sessions output B-frame streams in decode order, frames come out by pts regardless
+#[test]
+fn presentation_order_output() {
+    let mut packets = vec![];
+    let mut assembler = AccessUnitAssembler::new(DataFormat::H264).unwrap();
+    let mut collect = |au: &[u8]| {
+        packets.push(au.to_vec());
+        Ok(())
+    };
+    assembler.push(H264_BFRAMES, &mut collect).unwrap();
+    assembler.flush(&mut collect).unwrap();
+    // decode order I P B P B P B
+    let pts = [0, 2, 1, 4, 3, 6, 5];
+    assert_eq!(packets.len(), pts.len());
+    for mut dec_ctx in decode::available() {
+        if dec_ctx.data_format != DataFormat::H264 {
+            continue;
+        }
+        let device = Device::new(dec_ctx.luid).unwrap();
+        dec_ctx.device = Some(device.as_ptr());
+        dec_ctx.output_order = OutputOrder::Presentation;
+        let driver = dec_ctx.driver.clone();
+        let mut decoder = Decoder::new(dec_ctx).unwrap();
+        let mut output = vec![];
+        for (packet, pts) in packets.iter().zip(pts) {
+            let frames = decoder.decode_with_pts(packet, pts).unwrap();
+            output.extend(frames.iter().map(|f| f.pts));
+            assert_eq!(decoder.reorder_depth(), 1, "{:?}", driver);
+            assert!(decoder.held_frames() <= 1, "{:?}", driver);
+        }
+        output.extend(decoder.flush().iter().map(|f| f.pts));
+        assert_eq!(decoder.held_frames(), 0);
+        assert!(!output.is_empty(), "{:?} decoded nothing", driver);
+        assert!(
+            output.windows(2).all(|w| w[0] < w[1]),
+            "{:?} {:?}",
+            driver,
+            output
+        );
+    }
+}
+
+// the body of the first box of the path
+fn find_box<'a>(mut data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (name, rest) = path.split_first()?;
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        if size < 8 || size > data.len() {
+            return None;
+        }
+        if &data[4..8] == *name {
+            let body = &data[8..size];
+            return if rest.is_empty() {
+                Some(body)
+            } else {
+                find_box(body, rest)
+            };
+        }
+        data = &data[size..];
+    }
+    None
+}
+
+// the file has a sample per frame written and the size of the frames
+#[test]
+fn recorded_file_round_trip() {
+    let be32 = |b: &[u8], at: usize| u32::from_be_bytes(b[at..at + 4].try_into().unwrap());
+    for f in encode::available(dynamic_context()) {
+        let device = Device::new(f.luid).unwrap();
+        let mut d = dynamic_context();
+        d.device = Some(device.as_ptr());
+        let path = std::env::temp_dir().join("hwcodec-recorder.mp4");
+        let ctx = EncodeContext { f: f.clone(), d };
+        let mut recorder = Recorder::with_context(&path, ctx).unwrap();
+        for i in 0..FRAMES {
+            let source = bgra_pattern(WIDTH as _, HEIGHT as _, i);
+            let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+            recorder
+                .write(texture.as_ptr(), (i * 1000 / 30) as _)
+                .unwrap();
+        }
+        recorder.finish().unwrap();
+        let mp4 = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let stbl = [b"moov", b"trak", b"mdia", b"minf", b"stbl"];
+        let stbl = find_box(&mp4, &stbl).unwrap();
+        let stsz = find_box(stbl, &[b"stsz"]).unwrap();
+        assert_eq!(be32(stsz, 8) as usize, FRAMES, "{:?}", f);
+        // the sample entry of the codec
+        let stsd = find_box(stbl, &[b"stsd"]).unwrap();
+        let fourcc = &stsd[12..16];
+        match f.data_format {
+            DataFormat::H264 => assert_eq!(fourcc, b"avc1", "{:?}", f),
+            _ => assert!(fourcc == b"hvc1" || fourcc == b"hev1", "{:?}", f),
+        }
+        // 16.16 fixed point, at the end of the track header
+        let tkhd = find_box(&mp4, &[b"moov", b"trak", b"tkhd"]).unwrap();
+        let width = be32(tkhd, tkhd.len() - 8) >> 16;
+        let height = be32(tkhd, tkhd.len() - 4) >> 16;
+        assert_eq!((width, height), (WIDTH as u32, HEIGHT as u32), "{:?}", f);
+    }
+}
@@ -0,0 +1,134 @@
+// Needs an ffmpeg build and a hardware h264 encoder, skipped without one.
+use hwcodec::{
+    common::{DataFormat, Quality, RateControl},
+    ffmpeg::{AVHWDeviceType, AVPixelFormat},
+    ffmpeg_ram::{
+        decode::{DecodeContext, Decoder},
+        encode::{EncodeContext, Encoder},
+    },
+};
+
+const WIDTH: usize = 640;
+const HEIGHT: usize = 360;
+// padded rows, encode_nv12 has to skip the padding
+const Y_STRIDE: usize = WIDTH + 64;
+const UV_STRIDE: usize = WIDTH + 32;
+
+fn encode_context(name: String) -> EncodeContext {
+    EncodeContext {
+        name,
+        mc_name: None,
+        width: WIDTH as _,
+        height: HEIGHT as _,
+        pixfmt: AVPixelFormat::AV_PIX_FMT_NV12,
+        align: 0,
+        fps: 30,
+        gop: 60,
+        rc: RateControl::RC_CBR,
+        quality: Quality::Quality_Default,
+        kbs: 4000,
+        q: -1,
+        thread_count: 1,
+    }
+}
+
+// smooth gradients with a few edges, the padding is filled with garbage
+fn synthetic_nv12() -> (Vec<u8>, Vec<u8>) {
+    let mut y = vec![0xEE; Y_STRIDE * HEIGHT];
+    for row in 0..HEIGHT {
+        for col in 0..WIDTH {
+            let edge = if (row / 40 + col / 40) % 2 == 0 {
+                40
+            } else {
+                0
+            };
+            y[row * Y_STRIDE + col] = (16 + (col + row) * 160 / (WIDTH + HEIGHT) + edge) as u8;
+        }
+    }
+    let mut uv = vec![0x11; UV_STRIDE * HEIGHT / 2];
+    for row in 0..HEIGHT / 2 {
+        for col in 0..WIDTH / 2 {
+            uv[row * UV_STRIDE + col * 2] = (64 + col * 128 / WIDTH) as u8;
+            uv[row * UV_STRIDE + col * 2 + 1] = (192 - row * 128 / HEIGHT) as u8;
+        }
+    }
+    (y, uv)
+}
+
+fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    assert_eq!(a.len(), b.len());
+    let mse = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum::<f64>()
+        / a.len() as f64;
+    10.0 * (255.0 * 255.0 / mse.max(1e-10)).log10()
+}
+
+fn packed(plane: &[u8], stride: usize, width: usize, rows: usize) -> Vec<u8> {
+    (0..rows)
+        .flat_map(|r| &plane[r * stride..r * stride + width])
+        .copied()
+        .collect()
+}
+
+#[test]
+fn encode_decode_round_trip() {
+    let Some(info) = Encoder::available_encoders(encode_context(String::new()), None)
+        .into_iter()
+        .find(|c| c.format == DataFormat::H264)
+    else {
+        println!("no h264 encoder, skipped");
+        return;
+    };
+    let mut encoder = Encoder::new(encode_context(info.name)).unwrap();
+    let mut decoder = Decoder::new(DecodeContext {
+        name: "h264".to_owned(),
+        device_type: AVHWDeviceType::AV_HWDEVICE_TYPE_NONE,
+        thread_count: 1,
+    })
+    .unwrap();
+    let (y, uv) = synthetic_nv12();
+    let mut decoded = None;
+    // encoders with a lookahead return nothing for the first frames
+    for i in 0..30 {
+        let packets: Vec<Vec<u8>> = encoder
+            .encode_nv12(&y, &uv, [Y_STRIDE, UV_STRIDE], i * 33)
+            .unwrap()
+            .iter()
+            .map(|f| f.data.clone())
+            .collect();
+        for packet in packets {
+            if let Some(frame) = decoder.decode(&packet).unwrap().last() {
+                decoded = frame.to_nv12();
+            }
+        }
+    }
+    let (dy, duv) = decoded.expect("nothing decoded");
+    let y_psnr = psnr(&packed(&y, Y_STRIDE, WIDTH, HEIGHT), &dy);
+    let uv_psnr = psnr(&packed(&uv, UV_STRIDE, WIDTH, HEIGHT / 2), &duv);
+    println!("psnr y:{:.1}, uv:{:.1}", y_psnr, uv_psnr);
+    assert!(y_psnr > 35.0, "y psnr {}", y_psnr);
+    assert!(uv_psnr > 35.0, "uv psnr {}", uv_psnr);
+}
+
+#[test]
+fn short_planes_rejected() {
+    let Some(info) = Encoder::available_encoders(encode_context(String::new()), None)
+        .into_iter()
+        .find(|c| c.format == DataFormat::H264)
+    else {
+        println!("no h264 encoder, skipped");
+        return;
+    };
+    let mut encoder = Encoder::new(encode_context(info.name)).unwrap();
+    let (y, uv) = synthetic_nv12();
+    assert!(encoder
+        .encode_nv12(&y[..Y_STRIDE * (HEIGHT - 1)], &uv, [Y_STRIDE, UV_STRIDE], 0)
+        .is_err());
+    // a stride narrower than a row
+    assert!(encoder
+        .encode_nv12(&y, &uv, [WIDTH - 1, UV_STRIDE], 0)
+        .is_err());
+}
@@ -0,0 +1,89 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::vram::{
+    backend::EncodeBackend,
+    encode::{EncodeFrame, Encoder},
+};
+use std::{ffi::c_void, ptr::null_mut, time::Duration};
+
+// one frame per call, an IDR when one was requested or at the ms of keys
+#[derive(Default)]
+struct Fake {
+    keys: Vec<i64>,
+    requested: bool,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Fake {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        let key = std::mem::take(&mut self.requested) || self.keys.contains(&ms);
+        self.frames = vec![EncodeFrame::new(
+            vec![0, 0, 0, 1, if key { 0x65 } else { 0x41 }, 0x88],
+            ms,
+            key,
+        )];
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.requested = true;
+        Ok(())
+    }
+}
+
+fn new_encoder(backend: Fake) -> Encoder {
+    let ctx = encode_context("keyframe-throttle-test");
+    Encoder::from_backend(Box::new(backend), ctx)
+}
+
+// the ms of the keyframes of frames every 10 ms from 0 to last, requests sent before the
+// frames of the ms of requests
+fn keyframes(encoder: &mut Encoder, requests: &[i64], last: i64) -> Vec<i64> {
+    let mut keyframes = vec![];
+    for ms in (0..=last).step_by(10) {
+        for _ in requests.iter().filter(|r| **r == ms) {
+            encoder.request_keyframe_throttled(Duration::from_millis(100));
+        }
+        let frames = encoder.encode(null_mut(), ms).unwrap();
+        keyframes.extend(frames.iter().filter(|f| f.key == 1).map(|f| f.pts));
+    }
+    keyframes
+}
+
+#[test]
+fn rapid_requests_make_one_keyframe() {
+    let mut encoder = new_encoder(Fake::default());
+    assert_eq!(keyframes(&mut encoder, &[10; 20], 200), [10]);
+    // those after the granted one are held back for 100 ms after it
+    let mut encoder = new_encoder(Fake::default());
+    let requests = [10, 10, 30, 50, 50, 60, 150];
+    assert_eq!(keyframes(&mut encoder, &requests, 300), [10, 110, 210]);
+}
+
+#[test]
+fn own_keyframes_count() {
+    let keys = vec![0, 60];
+    // the session's keyframe at 60 answers the request held back since 30
+    let mut encoder = new_encoder(Fake {
+        keys: keys.clone(),
+        ..Default::default()
+    });
+    assert_eq!(keyframes(&mut encoder, &[30], 300), [0, 60]);
+    // and holds back the next one
+    let mut encoder = new_encoder(Fake {
+        keys,
+        ..Default::default()
+    });
+    assert_eq!(keyframes(&mut encoder, &[100], 300), [0, 60, 160]);
+}
@@ -0,0 +1,74 @@
+use hwcodec::common::LatencyHistogram;
+use std::time::Duration;
+
+fn ms(ms: f64) -> Duration {
+    Duration::from_secs_f64(ms / 1000.0)
+}
+
+// within the bucket resolution, 1/64 of the duration
+fn assert_near(actual: Duration, expected: Duration) {
+    let tolerance = expected / 64 + Duration::from_micros(1);
+    assert!(
+        actual >= expected && actual <= expected + tolerance,
+        "{:?} not within {:?} above {:?}",
+        actual,
+        tolerance,
+        expected
+    );
+}
+
+#[test]
+fn empty() {
+    let h = LatencyHistogram::default();
+    assert_eq!(h.count(), 0);
+    assert_eq!(h.p50(), Duration::ZERO);
+    assert_eq!(h.p99(), Duration::ZERO);
+    assert_eq!(h.mean(), Duration::ZERO);
+}
+
+#[test]
+fn uniform_percentiles() {
+    let mut h = LatencyHistogram::default();
+    // 1 ms to 100 ms in 0.1 ms steps, shuffled
+    for i in 0..1000u64 {
+        h.record(ms(((i * 617) % 1000 + 1) as f64 / 10.0));
+    }
+    assert_eq!(h.count(), 1000);
+    assert_near(h.p50(), ms(50.0));
+    assert_near(h.p95(), ms(95.0));
+    assert_near(h.p99(), ms(99.0));
+    assert_eq!(h.max(), ms(100.0));
+    assert_near(h.mean(), ms(50.05));
+}
+
+// a steady encoder with occasional stalls, the average hides them
+#[test]
+fn tail_of_spikes() {
+    let mut h = LatencyHistogram::default();
+    for i in 0..1000 {
+        h.record(if i % 50 == 0 { ms(250.0) } else { ms(4.0) });
+    }
+    assert_near(h.p50(), ms(4.0));
+    assert_near(h.p95(), ms(4.0));
+    assert_near(h.p99(), ms(250.0));
+    assert!(h.mean() < ms(10.0));
+}
+
+#[test]
+fn long_durations_capped_at_max() {
+    let mut h = LatencyHistogram::default();
+    h.record(ms(1.0));
+    h.record(ms(3000.0));
+    assert_eq!(h.percentile(100.0), ms(3000.0));
+    assert_near(h.percentile(0.0), ms(1.0));
+}
+
+#[test]
+fn reset() {
+    let mut h = LatencyHistogram::default();
+    h.record(ms(12.0));
+    h.reset();
+    assert_eq!(h, LatencyHistogram::default());
+    h.record(ms(2.0));
+    assert_near(h.p99(), ms(2.0));
+}
@@ -0,0 +1,89 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::vram::{
+    backend::EncodeBackend,
+    encode::{EncodeFrame, Encoder, EncoderInfo},
+};
+use std::{ffi::c_void, ptr::null_mut};
+
+const MAX_GOP: i32 = 10;
+
+// A clip of frames numbered by their ms, the frames in cuts are scene cuts the session
+// codes as keyframes like the first one and the requested ones. With delay each frame
+// comes out one call late.
+struct SceneCuts {
+    cuts: Vec<i64>,
+    delay: bool,
+    key: bool,
+    pending: Option<EncodeFrame>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for SceneCuts {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.frames.clear();
+        let key = std::mem::take(&mut self.key) || self.cuts.contains(&ms);
+        let frame = EncodeFrame::new(vec![0], ms, key);
+        if self.delay {
+            self.frames.extend(self.pending.replace(frame));
+        } else {
+            self.frames.push(frame);
+        }
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.key = true;
+        Ok(())
+    }
+
+    fn info(&self) -> EncoderInfo {
+        EncoderInfo::default()
+    }
+}
+
+// the pts of the keyframes of a clip of frames frames
+fn keyframes(cuts: &[i64], delay: bool, frames: i64) -> Vec<i64> {
+    let mut ctx = encode_context("max-gop-test");
+    ctx.d.gop = 0;
+    ctx.d.max_gop = MAX_GOP;
+    let backend = SceneCuts {
+        cuts: cuts.to_vec(),
+        delay,
+        key: true,
+        pending: None,
+        frames: vec![],
+    };
+    let mut encoder = Encoder::from_backend(Box::new(backend), ctx);
+    let mut keys = vec![];
+    for ms in 0..frames {
+        let output = encoder.encode(null_mut(), ms).unwrap();
+        keys.extend(output.iter().filter(|f| f.key == 1).map(|f| f.pts));
+    }
+    keys
+}
+
+#[test]
+fn keyframes_without_cuts() {
+    assert_eq!(keyframes(&[], false, 35), [0, 10, 20, 30]);
+}
+
+#[test]
+fn cuts_closer_and_farther_than_max_gop() {
+    // 4 and 8 come before the bound, after 8 it takes over until the cut at 30
+    let expected = [0, 4, 8, 18, 28, 30, 40];
+    assert_eq!(keyframes(&[4, 8, 30], false, 45), expected);
+    // the cuts are counted where they were input, not where they came out
+    assert_eq!(keyframes(&[4, 8, 30], true, 46), expected);
+}
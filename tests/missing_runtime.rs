@@ -0,0 +1,98 @@
+#![cfg(all(windows, feature = "vram", feature = "testutil"))]
+
+mod common;
+
+use hwcodec::{
+    common::{DataFormat, Driver},
+    vram::{
+        backend::{self, DecodeBackend, DecodeDriver},
+        debug_hide_runtime, decode, encode, DecodeContext, DynamicContext,
+    },
+};
+use std::sync::Arc;
+
+const NAME: &str = "missing-runtime-test";
+
+// reports one adapter for every format, always there
+struct Present;
+
+impl DecodeDriver for Present {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn test(&self, _format: DataFormat) -> Vec<(i64, Driver)> {
+        vec![(1, Driver::NV)]
+    }
+
+    fn create(&self, _ctx: &DecodeContext) -> Result<Box<dyn DecodeBackend>, ()> {
+        Err(())
+    }
+}
+
+type Found = Vec<(Driver, i64, DataFormat)>;
+
+fn available() -> (Found, Found) {
+    let encoders = encode::available(DynamicContext {
+        width: 1280,
+        height: 720,
+        kbitrate: 5000,
+        framerate: 30,
+        gop: 60,
+        ..Default::default()
+    })
+    .into_iter()
+    .map(|f| (f.driver, f.luid, f.data_format))
+    .collect();
+    let decoders = decode::available()
+        .into_iter()
+        .map(|d| (d.driver, d.luid, d.data_format))
+        .collect();
+    (encoders, decoders)
+}
+
+// without the driver's contexts, the others are all still found
+fn assert_only_missing(before: &Found, after: &Found, driver: Option<&Driver>) {
+    for found in before {
+        if Some(&found.0) == driver {
+            assert!(!after.contains(found), "{:?} found", found);
+        } else {
+            assert!(after.contains(found), "{:?} missing", found);
+        }
+    }
+}
+
+#[test]
+fn other_drivers_stay_available() {
+    common::register(NAME, |_| vec![(1, Driver::NV)], |_| Err(()));
+    backend::register_decode_driver(Arc::new(Present));
+    let (encoders, decoders) = available();
+    let custom = Driver::CUSTOM(NAME.to_owned());
+    assert!(encoders.iter().any(|f| f.0 == custom));
+    assert!(decoders.iter().any(|d| d.0 == custom));
+    // the dlls of each runtime, which driver loses its encoders and decoders
+    let cases: [(&[&str], Option<Driver>, Option<Driver>); 5] = [
+        (&["nvcuda.dll"], Some(Driver::NV), Some(Driver::NV)),
+        (&["nvEncodeAPI64.dll"], Some(Driver::NV), None),
+        (&["nvcuvid.dll"], None, Some(Driver::NV)),
+        (&["amfrt64.dll"], Some(Driver::AMF), Some(Driver::AMF)),
+        (
+            &["libmfx64-gen.dll", "libmfxhw64.dll"],
+            Some(Driver::MFX),
+            Some(Driver::MFX),
+        ),
+    ];
+    for (modules, encode_driver, decode_driver) in cases {
+        modules.iter().for_each(|m| debug_hide_runtime(m, true));
+        let (hidden_encoders, hidden_decoders) = available();
+        modules.iter().for_each(|m| debug_hide_runtime(m, false));
+        assert_only_missing(&encoders, &hidden_encoders, encode_driver.as_ref());
+        assert_only_missing(&decoders, &hidden_decoders, decode_driver.as_ref());
+    }
+    // found again once the dlls are back
+    let (again_encoders, again_decoders) = available();
+    assert_only_missing(&encoders, &again_encoders, None);
+    assert_only_missing(&decoders, &again_decoders, None);
+    backend::unregister_encode_driver(NAME);
+    backend::unregister_decode_driver(NAME);
+}
@@ -0,0 +1,94 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::decode_context;
+use hwcodec::{
+    bitstream::jpeg,
+    common::{DataFormat, DecodeCaps, HwcodecErrno},
+    vram::{
+        backend::DecodeBackend,
+        decode::{CapsLimit, DecodeFrame, Decoder},
+    },
+};
+use std::sync::{Arc, Mutex};
+
+// see tests/bitstream.rs
+const MJPEG_720P: &[u8] = include_bytes!("../src/res/720p.jpg");
+
+// keeps the packets it was given
+struct Images {
+    caps: DecodeCaps,
+    packets: Arc<Mutex<Vec<Vec<u8>>>>,
+    frames: Vec<DecodeFrame>,
+}
+
+impl DecodeBackend for Images {
+    fn decode(&mut self, packet: &[u8]) -> Result<&mut Vec<DecodeFrame>, i32> {
+        self.packets.lock().unwrap().push(packet.to_vec());
+        self.frames.clear();
+        self.frames.push(DecodeFrame {
+            width: 1280,
+            height: 720,
+            ..Default::default()
+        });
+        Ok(&mut self.frames)
+    }
+
+    fn caps(&self) -> DecodeCaps {
+        self.caps
+    }
+}
+
+fn decoder(caps: DecodeCaps) -> (Decoder, Arc<Mutex<Vec<Vec<u8>>>>) {
+    let mut ctx = decode_context("mjpeg-test");
+    ctx.data_format = DataFormat::MJPEG;
+    let packets = Arc::new(Mutex::new(vec![]));
+    let backend = Images {
+        caps,
+        packets: packets.clone(),
+        frames: vec![],
+    };
+    (Decoder::from_backend(Box::new(backend), ctx), packets)
+}
+
+#[test]
+fn images_without_huffman_tables() {
+    let dht = jpeg::segments(MJPEG_720P)
+        .find(|s| s.marker == jpeg::DHT)
+        .unwrap();
+    let end = dht.offset + 4 + dht.payload.len();
+    let stripped = [&MJPEG_720P[..dht.offset], &MJPEG_720P[end..]].concat();
+    let (mut decoder, packets) = decoder(DecodeCaps::default());
+    // every image decodes on its own
+    for image in [&stripped[..], MJPEG_720P, &stripped[..]] {
+        assert_eq!(decoder.decode(image).unwrap().len(), 1);
+    }
+    // the driver gets the default tables inserted
+    assert!(packets.lock().unwrap().iter().all(|p| p == MJPEG_720P));
+}
+
+#[test]
+fn images_beyond_caps() {
+    let caps = DecodeCaps {
+        maxWidth: 640,
+        maxHeight: 480,
+        bitDepths: 1 << 8,
+        ..Default::default()
+    };
+    let (mut decoder, packets) = decoder(caps);
+    let Err(e) = decoder.decode(MJPEG_720P) else {
+        panic!("decoded beyond the caps");
+    };
+    assert!(HwcodecErrno::is_caps_exceeded(e), "{}", e);
+    assert_eq!(
+        decoder.exceeded_caps(),
+        Some(CapsLimit::Size {
+            width: 1280,
+            height: 720,
+            max_width: 640,
+            max_height: 480,
+        })
+    );
+    assert!(packets.lock().unwrap().is_empty());
+}
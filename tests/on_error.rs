@@ -0,0 +1,123 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+#[cfg(feature = "tokio")]
+use hwcodec::vram::worker::AsyncEncoder;
+use hwcodec::{
+    common::HwcodecErrno,
+    vram::{
+        backend::EncodeBackend,
+        encode::{EncodeFrame, Encoder},
+        EncodeContext,
+    },
+};
+use std::{
+    ffi::c_void,
+    ptr::null_mut,
+    sync::{Arc, Mutex},
+};
+
+const DEVICE_LOST: i32 = HwcodecErrno::HWCODEC_ERR_DEVICE_LOST as i32;
+
+// fails with error after a number of frames, recreating it gives one more
+struct Losing {
+    error: i32,
+    frames_left: usize,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Losing {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        if self.frames_left == 0 {
+            return Err(self.error);
+        }
+        self.frames_left -= 1;
+        self.frames.clear();
+        self.frames.push(EncodeFrame::new(vec![0; 16], ms, true));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn recreate(&mut self, _ctx: &EncodeContext) -> Result<(), ()> {
+        self.frames_left = 1;
+        Ok(())
+    }
+}
+
+fn encoder(error: i32, frames_left: usize) -> Encoder {
+    let ctx = encode_context("on-error-test");
+    let backend = Losing {
+        error,
+        frames_left,
+        frames: vec![],
+    };
+    Encoder::from_backend(Box::new(backend), ctx)
+}
+
+// the errors the callback was called with and the names of the threads it ran on
+type Calls = Arc<Mutex<Vec<(i32, Option<String>)>>>;
+
+fn recorder(encoder: &mut Encoder) -> Calls {
+    let calls = Arc::new(Mutex::new(vec![]));
+    let recorded = calls.clone();
+    encoder.on_error(move |e| {
+        let thread = std::thread::current().name().map(str::to_owned);
+        recorded.lock().unwrap().push((e, thread));
+    });
+    calls
+}
+
+#[test]
+fn reported_once_per_loss() {
+    let mut encoder = encoder(DEVICE_LOST, 2);
+    let calls = recorder(&mut encoder);
+    for ms in 0..2 {
+        encoder.encode(null_mut(), ms).unwrap();
+    }
+    assert!(calls.lock().unwrap().is_empty());
+    for ms in 2..5 {
+        assert_eq!(encoder.encode(null_mut(), ms).err(), Some(DEVICE_LOST));
+    }
+    assert_eq!(calls.lock().unwrap().len(), 1);
+    assert_eq!(calls.lock().unwrap()[0].0, DEVICE_LOST);
+    // the recreated session is lost again after one frame
+    encoder.recreate_after_device_lost(null_mut()).unwrap();
+    encoder.encode(null_mut(), 5).unwrap();
+    assert!(encoder.encode(null_mut(), 6).is_err());
+    assert_eq!(calls.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn other_errors_not_reported() {
+    let denied = HwcodecErrno::HWCODEC_ERR_INPUT_ACCESS_DENIED as i32;
+    let mut encoder = encoder(denied, 0);
+    let calls = recorder(&mut encoder);
+    assert_eq!(encoder.encode(null_mut(), 0).err(), Some(denied));
+    assert!(calls.lock().unwrap().is_empty());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn reported_from_worker() {
+    let mut encoder = encoder(DEVICE_LOST, 1);
+    let calls = recorder(&mut encoder);
+    let encoder = AsyncEncoder::from_encoder(encoder, 2).unwrap();
+    let (first, second) =
+        tokio::join!(encoder.encode(null_mut(), 0), encoder.encode(null_mut(), 1));
+    assert_eq!(first.unwrap().len(), 1);
+    assert_eq!(second.err(), Some(DEVICE_LOST));
+    // before the second future completed, on the worker
+    assert_eq!(
+        calls.lock().unwrap().as_slice(),
+        &[(DEVICE_LOST, Some("hwcodec-encoder".to_owned()))]
+    );
+}
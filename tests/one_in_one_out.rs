@@ -0,0 +1,136 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    common::HwcodecErrno,
+    vram::{
+        backend::{self, EncodeBackend},
+        encode::{EncodeFrame, Encoder, EncoderInfo},
+        EncodeContext, Tune,
+    },
+};
+use std::{collections::VecDeque, ffi::c_void, ptr::null_mut};
+
+const NAME: &str = "one-in-one-out-test";
+
+// returns the next of counts frames per call, one once they run out
+#[derive(Default)]
+struct Counts {
+    bframes: i32,
+    counts: VecDeque<usize>,
+    keyframe: bool,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Counts {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.frames.clear();
+        for _ in 0..self.counts.pop_front().unwrap_or(1) {
+            self.frames.push(EncodeFrame::new(
+                vec![0, 0, 0, 1, 0x65],
+                ms,
+                std::mem::take(&mut self.keyframe),
+            ));
+        }
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.keyframe = true;
+        Ok(())
+    }
+
+    fn info(&self) -> EncoderInfo {
+        EncoderInfo {
+            bframes: self.bframes,
+            ..Default::default()
+        }
+    }
+}
+
+fn context(tune: Tune, strict: Option<bool>) -> EncodeContext {
+    let mut ctx = encode_context(NAME);
+    ctx.d.tune = tune;
+    ctx.d.strict_one_in_one_out = strict;
+    ctx
+}
+
+fn encoder(counts: &[usize]) -> Encoder {
+    let backend = Counts {
+        counts: counts.iter().copied().collect(),
+        ..Default::default()
+    };
+    Encoder::from_backend(Box::new(backend), context(Tune::CloudGaming, None))
+}
+
+#[test]
+fn follows_tune_unless_set() {
+    assert!(context(Tune::CloudGaming, None).d.one_in_one_out());
+    assert!(!context(Tune::Default, None).d.one_in_one_out());
+    assert!(!context(Tune::CloudGaming, Some(false)).d.one_in_one_out());
+    assert!(context(Tune::Default, Some(true)).d.one_in_one_out());
+}
+
+#[test]
+fn encode_one_returns_the_frame() {
+    let mut encoder = encoder(&[]);
+    for ms in 0..5 {
+        assert_eq!(encoder.encode_one(null_mut(), ms).unwrap().pts, ms);
+    }
+    // not for sessions that aren't held to it
+    let backend = Counts::default();
+    let ctx = context(Tune::Default, None);
+    let mut encoder = Encoder::from_backend(Box::new(backend), ctx);
+    assert_eq!(
+        encoder.encode_one(null_mut(), 0).err(),
+        Some(HwcodecErrno::HWCODEC_ERR_COMMON as i32)
+    );
+    assert_eq!(encoder.encode(null_mut(), 0).unwrap().len(), 1);
+}
+
+#[test]
+fn other_counts_fail_and_restart_with_idr() {
+    let mut encoder = encoder(&[1, 0, 2, 1]);
+    assert!(encoder.encode_one(null_mut(), 0).is_ok());
+    for ms in [33, 66] {
+        let e = encoder.encode(null_mut(), ms).err().unwrap();
+        assert!(HwcodecErrno::is_not_one_frame(e), "{}", e);
+    }
+    let frame = encoder.encode_one(null_mut(), 100).unwrap();
+    assert_eq!((frame.pts, frame.key), (100, 1));
+}
+
+#[test]
+fn creation_fails_for_sessions_without_it() {
+    // sessions with the B-frames of the requested bitrate
+    common::register(
+        NAME,
+        |_| vec![],
+        |ctx| {
+            Ok(Box::new(Counts {
+                bframes: (ctx.d.kbitrate == 1000) as i32,
+                ..Default::default()
+            }))
+        },
+    );
+    assert!(Encoder::new(context(Tune::CloudGaming, None)).is_ok());
+    let mut ctx = context(Tune::CloudGaming, None);
+    ctx.d.kbitrate = 1000;
+    assert!(Encoder::new(ctx.clone()).is_err());
+    ctx.d.strict_one_in_one_out = Some(false);
+    assert!(Encoder::new(ctx).is_ok());
+    let mut ctx = context(Tune::Default, Some(true));
+    ctx.d.max_frame_bytes = 10000;
+    assert!(Encoder::new(ctx).is_err());
+    backend::unregister_encode_driver(NAME);
+}
@@ -0,0 +1,208 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    common::{FrameFlag::*, HwcodecErrno},
+    vram::{
+        backend::EncodeBackend,
+        encode::{EncodeFrame, Encoder},
+        EncodeContext, OversizePolicy,
+    },
+};
+use std::{ffi::c_void, ptr::null_mut};
+
+const MAX_FRAME_BYTES: i32 = 1500;
+
+// The frame of ms has sizes[ms] bytes, halved every 6 qp of delta. The first frame and
+// those after a request are keys.
+struct Sized {
+    sizes: Vec<usize>,
+    qp_delta: bool,
+    delta: i32,
+    key: bool,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Sized {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        let size = self.sizes[ms as usize] >> (std::mem::take(&mut self.delta) / 6);
+        self.frames.clear();
+        self.frames.push(EncodeFrame::new(
+            (0..size).map(|i| i as u8).collect::<Vec<u8>>(),
+            ms,
+            std::mem::take(&mut self.key),
+        ));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.key = true;
+        Ok(())
+    }
+
+    fn set_qp_delta(&mut self, delta: i32) -> Result<(), i32> {
+        if !self.qp_delta {
+            return Err(HwcodecErrno::HWCODEC_ERR_COMMON as _);
+        }
+        self.delta = delta;
+        Ok(())
+    }
+}
+
+// outputs data as a keyframe for every frame
+struct Fixed {
+    data: Vec<u8>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for Fixed {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        self.frames.clear();
+        self.frames
+            .push(EncodeFrame::new(self.data.clone(), ms, true));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+}
+
+fn context(oversize: OversizePolicy) -> EncodeContext {
+    let mut ctx = encode_context("oversize-test");
+    ctx.d.max_frame_bytes = MAX_FRAME_BYTES;
+    ctx.d.oversize = oversize;
+    ctx
+}
+
+fn encoder(oversize: OversizePolicy, sizes: &[usize], qp_delta: bool) -> Encoder {
+    let backend = Sized {
+        sizes: sizes.to_vec(),
+        qp_delta,
+        delta: 0,
+        key: true,
+        frames: vec![],
+    };
+    Encoder::from_backend(Box::new(backend), context(oversize))
+}
+
+// (size, pts, key) of the frames of an encode call
+fn encode(encoder: &mut Encoder, ms: i64) -> Vec<(usize, i64, i32)> {
+    encoder
+        .encode(null_mut(), ms)
+        .unwrap()
+        .iter()
+        .map(|f| (f.data.len(), f.pts, f.key))
+        .collect()
+}
+
+#[test]
+fn dropped_keyframe_requests_another() {
+    let mut encoder = encoder(OversizePolicy::Drop, &[4000, 100, 100], true);
+    assert!(encode(&mut encoder, 0).is_empty());
+    assert_eq!(encode(&mut encoder, 1), [(100, 1, 1)]);
+    assert_eq!(encode(&mut encoder, 2), [(100, 2, 0)]);
+    assert_eq!(encoder.summary().frames, 2);
+}
+
+#[test]
+fn reencoded_at_higher_qp_until_it_fits() {
+    let mut encoder = encoder(OversizePolicy::Reencode, &[4000, 100], true);
+    // +6 halves it to 2000, +12 to 1000
+    assert_eq!(encode(&mut encoder, 0), [(1000, 0, 1)]);
+    assert_eq!(encode(&mut encoder, 1), [(100, 1, 0)]);
+}
+
+#[test]
+fn reencode_gives_up_at_highest_delta() {
+    let mut encoder = encoder(OversizePolicy::Reencode, &[100_000, 100], true);
+    assert!(encode(&mut encoder, 0).is_empty());
+    assert_eq!(encode(&mut encoder, 1), [(100, 1, 1)]);
+}
+
+#[test]
+fn reencode_without_qp_delta_drops() {
+    let mut encoder = encoder(OversizePolicy::Reencode, &[4000, 100], false);
+    assert!(encode(&mut encoder, 0).is_empty());
+    assert_eq!(encode(&mut encoder, 1), [(100, 1, 1)]);
+}
+
+#[test]
+fn split_into_fragments() {
+    let mut encoder = encoder(OversizePolicy::Split, &[4000, 100], true);
+    let frames: Vec<_> = encoder.encode(null_mut(), 0).unwrap().drain(..).collect();
+    let sizes: Vec<_> = frames.iter().map(|f| f.data.len()).collect();
+    assert_eq!(sizes, [1500, 1500, 1000]);
+    assert!(frames
+        .iter()
+        .all(|f| f.pts == 0 && f.flags.has(FRAME_FLAG_FRAGMENT)));
+    let keys: Vec<_> = frames.iter().map(|f| f.key).collect();
+    assert_eq!(keys, [1, 0, 0]);
+    let last: Vec<_> = frames
+        .iter()
+        .map(|f| f.flags.has(FRAME_FLAG_LAST_FRAGMENT))
+        .collect();
+    assert_eq!(last, [false, false, true]);
+    let data: Vec<u8> = frames.into_iter().flat_map(|f| f.data.to_vec()).collect();
+    assert!(data.iter().enumerate().all(|(i, b)| *b == i as u8));
+    assert_eq!(encode(&mut encoder, 1), [(100, 1, 0)]);
+    let summary = encoder.summary();
+    assert_eq!(
+        (summary.frames, summary.keyframes, summary.bytes),
+        (2, 1, 4100)
+    );
+}
+
+// SPS, PPS and a slice fit in the first fragment, the next slice in one of its own, the
+// last one is larger than the limit and cut
+#[test]
+fn split_on_nal_boundaries() {
+    let nal = |start_code: &[u8], header: u8, len: usize| {
+        let mut nal = start_code.to_vec();
+        nal.push(header);
+        nal.resize(start_code.len() + len, 0xaa);
+        nal
+    };
+    let data = [
+        nal(&[0, 0, 0, 1], 0x67, 20),
+        nal(&[0, 0, 1], 0x68, 8),
+        nal(&[0, 0, 0, 1], 0x65, 1000),
+        nal(&[0, 0, 1], 0x65, 1200),
+        nal(&[0, 0, 0, 1], 0x65, 3500),
+    ]
+    .concat();
+    let backend = Fixed {
+        data: data.clone(),
+        frames: vec![],
+    };
+    let mut encoder = Encoder::from_backend(Box::new(backend), context(OversizePolicy::Split));
+    let frames: Vec<_> = encoder.encode(null_mut(), 0).unwrap().drain(..).collect();
+    let sizes: Vec<_> = frames.iter().map(|f| f.data.len()).collect();
+    assert_eq!(sizes, [1039, 1203, 1500, 1500, 504]);
+    assert!(frames[1].data.starts_with(&[0, 0, 1, 0x65]));
+    assert!(frames[2].data.starts_with(&[0, 0, 0, 1, 0x65]));
+    let keys: Vec<_> = frames
+        .iter()
+        .map(|f| (f.key, f.flags.has(FRAME_FLAG_KEYFRAME)))
+        .collect();
+    assert_eq!(keys[0], (1, true));
+    assert!(keys[1..].iter().all(|k| *k == (0, false)));
+    assert!(frames.iter().all(|f| f.flags.has(FRAME_FLAG_FRAGMENT)));
+    assert!(frames[4].flags.has(FRAME_FLAG_LAST_FRAGMENT));
+    let joined: Vec<u8> = frames.iter().flat_map(|f| f.data.to_vec()).collect();
+    assert_eq!(joined, data);
+}
@@ -0,0 +1,116 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::encode_context;
+use hwcodec::{
+    common::HwcodecErrno,
+    vram::{
+        backend::EncodeBackend,
+        encode::{EncodeFrame, Encoder},
+    },
+};
+use std::{
+    ffi::c_void,
+    ptr::null_mut,
+    sync::{Arc, Mutex},
+};
+
+// Keeps the ms of the frames since the latest key as references, like the NV shim.
+// Invalidating one drops it and those after it, the first one stays.
+#[derive(Default)]
+struct References {
+    references: Vec<i64>,
+    key: bool,
+    // the invalidations that reached the backend
+    invalidated: Arc<Mutex<Vec<i64>>>,
+    frames: Vec<EncodeFrame>,
+}
+
+impl EncodeBackend for References {
+    fn encode(&mut self, _tex: *mut c_void, ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        let key = std::mem::take(&mut self.key) || self.references.is_empty();
+        if key {
+            self.references.clear();
+        }
+        self.references.push(ms);
+        self.frames.clear();
+        self.frames.push(EncodeFrame::new(
+            vec![0, 0, 0, 1, if key { 0x65 } else { 0x41 }],
+            ms,
+            key,
+        ));
+        Ok(&mut self.frames)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.key = true;
+        Ok(())
+    }
+
+    fn invalidate_reference(&mut self, ms: i64) -> Result<(), i32> {
+        self.invalidated.lock().unwrap().push(ms);
+        match self.references.iter().rposition(|&r| r == ms) {
+            Some(i) if i > 0 => {
+                self.references.truncate(i);
+                Ok(())
+            }
+            _ => Err(HwcodecErrno::HWCODEC_ERR_COMMON as _),
+        }
+    }
+}
+
+fn encoder(reference_invalidation: bool) -> (Encoder, Arc<Mutex<Vec<i64>>>) {
+    let mut ctx = encode_context("reference-invalidation-test");
+    ctx.d.reference_invalidation = reference_invalidation;
+    let backend = References::default();
+    let invalidated = backend.invalidated.clone();
+    (Encoder::from_backend(Box::new(backend), ctx), invalidated)
+}
+
+fn key(encoder: &mut Encoder, ms: i64) -> bool {
+    encoder.encode(null_mut(), ms).unwrap()[0].key != 0
+}
+
+#[test]
+fn invalidation_avoids_a_keyframe() {
+    let (mut encoder, invalidated) = encoder(true);
+    assert!(key(&mut encoder, 0));
+    assert!(!key(&mut encoder, 33));
+    assert!(!key(&mut encoder, 66));
+    encoder.invalidate_reference(33).unwrap();
+    assert_eq!(*invalidated.lock().unwrap(), [33]);
+    assert!(!key(&mut encoder, 100));
+}
+
+#[test]
+fn frames_without_an_earlier_reference_get_a_keyframe() {
+    let (mut encoder, invalidated) = encoder(true);
+    assert!(key(&mut encoder, 0));
+    assert!(!key(&mut encoder, 33));
+    // nothing before the key
+    encoder.invalidate_reference(0).unwrap();
+    assert!(key(&mut encoder, 66));
+    // no longer among the references
+    encoder.invalidate_reference(33).unwrap();
+    assert!(key(&mut encoder, 100));
+    assert_eq!(*invalidated.lock().unwrap(), [0, 33]);
+}
+
+#[test]
+fn sessions_without_invalidation_get_a_keyframe() {
+    let (mut encoder, invalidated) = encoder(false);
+    assert!(key(&mut encoder, 0));
+    assert!(!key(&mut encoder, 33));
+    encoder.invalidate_reference(33).unwrap();
+    assert!(invalidated.lock().unwrap().is_empty());
+    assert!(key(&mut encoder, 66));
+}
@@ -0,0 +1,123 @@
+#![cfg(all(windows, feature = "vram"))]
+
+use hwcodec::{
+    common::{AdapterInfo, AdapterVendor, DataFormat, Driver, EncodeCaps},
+    vram::{
+        encode::{select_best_on, SelectionPolicy},
+        FeatureContext,
+    },
+};
+
+const IGPU: i64 = 0x5a3f;
+const DGPU: i64 = 0x7b21;
+
+fn feature(driver: Driver, luid: i64, data_format: DataFormat) -> FeatureContext {
+    FeatureContext {
+        driver: driver.clone(),
+        vendor: driver,
+        luid,
+        data_format,
+        caps: EncodeCaps::default(),
+    }
+}
+
+fn adapter(luid: i64, vendor: AdapterVendor, integrated: bool) -> AdapterInfo {
+    AdapterInfo {
+        luid,
+        description: String::new(),
+        vendor,
+        vendor_id: vendor as u32,
+        device_id: 0,
+        dedicated_video_memory: if integrated { 128 << 20 } else { 8 << 30 },
+        shared_system_memory: 16 << 30,
+        integrated,
+        software: false,
+    }
+}
+
+// an intel igpu and an nvidia dgpu, both encoding h264 and h265
+fn hybrid() -> (Vec<FeatureContext>, Vec<AdapterInfo>) {
+    let features = vec![
+        feature(Driver::NV, DGPU, DataFormat::H264),
+        feature(Driver::NV, DGPU, DataFormat::H265),
+        feature(Driver::MFX, IGPU, DataFormat::H264),
+        feature(Driver::MFX, IGPU, DataFormat::H265),
+    ];
+    let adapters = vec![
+        adapter(IGPU, AdapterVendor::ADAPTER_VENDOR_INTEL, true),
+        adapter(DGPU, AdapterVendor::ADAPTER_VENDOR_NVIDIA, false),
+    ];
+    (features, adapters)
+}
+
+#[test]
+fn single_gpu() {
+    let features = vec![
+        feature(Driver::NV, DGPU, DataFormat::H264),
+        feature(Driver::NV, DGPU, DataFormat::H265),
+    ];
+    let adapters = [adapter(DGPU, AdapterVendor::ADAPTER_VENDOR_NVIDIA, false)];
+    let best = |capture, policy| select_best_on(&features, capture, policy, &adapters);
+    // every policy falls back to what there is
+    for policy in [
+        SelectionPolicy::default(),
+        SelectionPolicy::PreferDiscrete,
+        SelectionPolicy::PreferIntegrated,
+    ] {
+        assert_eq!(best(Some(DGPU), policy), Some(&features[0]));
+        assert_eq!(best(None, policy), Some(&features[0]));
+    }
+    let h265 = SelectionPolicy::PreferFormat(DataFormat::H265);
+    assert_eq!(best(Some(DGPU), h265), Some(&features[1]));
+}
+
+#[test]
+fn hybrid_laptop() {
+    let (features, adapters) = hybrid();
+    let best = |capture, policy| select_best_on(&features, capture, policy, &adapters);
+    assert_eq!(
+        SelectionPolicy::default(),
+        SelectionPolicy::PreferCaptureAdapter
+    );
+    // the desktop is on the igpu
+    let default = SelectionPolicy::default();
+    assert_eq!(best(Some(IGPU), default), Some(&features[2]));
+    assert_eq!(best(Some(DGPU), default), Some(&features[0]));
+    // the capture adapter breaks the ties of the other policies
+    let discrete = SelectionPolicy::PreferDiscrete;
+    assert_eq!(best(Some(IGPU), discrete), Some(&features[0]));
+    let integrated = SelectionPolicy::PreferIntegrated;
+    assert_eq!(best(Some(DGPU), integrated), Some(&features[2]));
+    assert_eq!(best(None, integrated), Some(&features[2]));
+    let h265 = SelectionPolicy::PreferFormat(DataFormat::H265);
+    assert_eq!(best(Some(IGPU), h265), Some(&features[3]));
+    assert_eq!(best(Some(DGPU), h265), Some(&features[1]));
+    // without adapter infos only the capture adapter and the order are left
+    assert_eq!(
+        select_best_on(&features, Some(IGPU), discrete, &[]),
+        Some(&features[2])
+    );
+}
+
+#[test]
+fn no_match() {
+    let (features, adapters) = hybrid();
+    assert_eq!(
+        select_best_on(&[], Some(IGPU), SelectionPolicy::default(), &adapters),
+        None
+    );
+    // a capture adapter without encoders, e.g. a display link adapter
+    let best = select_best_on(
+        &features,
+        Some(0x9c04),
+        SelectionPolicy::default(),
+        &adapters,
+    );
+    assert_eq!(best, Some(&features[0]));
+    // no AV1 encoder, the preferred adapter's H264 one is taken
+    let av1 = SelectionPolicy::PreferFormat(DataFormat::AV1);
+    assert_eq!(
+        select_best_on(&features, Some(IGPU), av1, &adapters),
+        Some(&features[2])
+    );
+}
@@ -0,0 +1,41 @@
+// Self tests every encoder the machine offers, enabled with --features gpu-tests.
+#![cfg(all(windows, feature = "gpu-tests"))]
+
+mod common;
+
+use hwcodec::{
+    common::{DataFormat, Driver},
+    vram::{backend, encode, self_test::self_test_all, DynamicContext},
+};
+
+const NAME: &str = "self-test-test";
+
+#[test]
+fn one_report_per_encoder() {
+    // an h264 encoder that is found but never opens
+    let found = |format: DataFormat| match format {
+        DataFormat::H264 => vec![(1, Driver::NV)],
+        _ => vec![],
+    };
+    common::register(NAME, found, |_| Err(()));
+    let d = DynamicContext {
+        width: 640,
+        height: 480,
+        kbitrate: 2000,
+        framerate: 30,
+        gop: 30,
+        ..Default::default()
+    };
+    let encoders = encode::available(d);
+    let reports = self_test_all(d);
+    backend::unregister_encode_driver(NAME);
+    let tested: Vec<_> = reports.iter().map(|r| r.encoder.clone()).collect();
+    assert_eq!(tested, encoders);
+    for report in &reports {
+        assert_eq!(report.success, report.error.is_none(), "{:?}", report);
+    }
+    let custom = Driver::CUSTOM(NAME.to_owned());
+    let broken = reports.iter().find(|r| r.encoder.driver == custom).unwrap();
+    assert!(!broken.success);
+    assert_eq!(broken.encoded, 0);
+}
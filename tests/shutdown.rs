@@ -0,0 +1,93 @@
+// Needs an NV adapter, enabled with --features gpu-tests. A single test, sessions of others
+// in the same process would keep shutdown from running.
+#![cfg(all(windows, feature = "gpu-tests"))]
+
+use hwcodec::{
+    common::{DataFormat, Driver},
+    shutdown,
+    testutil::{bgra_pattern, Device, Texture},
+    vram::{
+        debug_vendor_module_references,
+        decode::{self, Decoder},
+        encode::{self, Encoder},
+        loaded_vendor_modules, DecodeContext, DynamicContext, EncodeContext,
+    },
+};
+
+const WIDTH: i32 = 640;
+const HEIGHT: i32 = 480;
+
+fn dynamic_context() -> DynamicContext {
+    DynamicContext {
+        width: WIDTH,
+        height: HEIGHT,
+        kbitrate: 2000,
+        framerate: 30,
+        gop: 30,
+        ..Default::default()
+    }
+}
+
+fn open_sessions(device: &Device, luid: i64) -> (Encoder, Decoder) {
+    let f = encode::available(dynamic_context())
+        .into_iter()
+        .find(|f| f.driver == Driver::NV && f.luid == luid && f.data_format == DataFormat::H264)
+        .unwrap();
+    let mut d = dynamic_context();
+    d.device = Some(device.as_ptr());
+    let mut encoder = Encoder::new(EncodeContext { f: f.clone(), d }).unwrap();
+    let source = bgra_pattern(WIDTH as _, HEIGHT as _, 0);
+    let texture = Texture::from_bgra(device.as_ptr(), WIDTH, HEIGHT, &source).unwrap();
+    let packets: Vec<_> = encoder
+        .encode(texture.as_ptr(), 0)
+        .unwrap()
+        .drain(..)
+        .collect();
+    let dec_ctx = decode::available()
+        .into_iter()
+        .find(|d| d.driver == Driver::NV && d.luid == f.luid && d.data_format == f.data_format)
+        .unwrap();
+    let mut decoder = Decoder::new(DecodeContext {
+        device: Some(device.as_ptr()),
+        ..dec_ctx
+    })
+    .unwrap();
+    for packet in packets {
+        decoder.decode(&packet.data).unwrap();
+    }
+    (encoder, decoder)
+}
+
+#[test]
+fn releases_vendor_modules() {
+    // nothing is open yet
+    shutdown().unwrap();
+    let Some(nv) = encode::available(dynamic_context())
+        .into_iter()
+        .find(|f| f.driver == Driver::NV)
+    else {
+        return;
+    };
+    let nv_modules = ["nvEncodeAPI64.dll", "nvcuvid.dll"];
+    let device = Device::new(nv.luid).unwrap();
+    let (encoder, decoder) = open_sessions(&device, nv.luid);
+    let loaded = loaded_vendor_modules();
+    for module in nv_modules {
+        assert!(loaded.contains(&module), "{:?}", loaded);
+    }
+    assert!(debug_vendor_module_references() > 0);
+    assert!(shutdown().is_err());
+    drop(encoder);
+    assert!(shutdown().is_err());
+    drop(decoder);
+    shutdown().unwrap();
+    assert_eq!(debug_vendor_module_references(), 0);
+    let loaded = loaded_vendor_modules();
+    for module in nv_modules {
+        assert!(!loaded.contains(&module), "{:?}", loaded);
+    }
+    // the next use loads them again
+    let (encoder, decoder) = open_sessions(&device, nv.luid);
+    drop((encoder, decoder));
+    shutdown().unwrap();
+}
@@ -0,0 +1,201 @@
+#![cfg(all(windows, feature = "vram"))]
+
+mod common;
+
+use common::{encode_context, Fake, Log};
+use hwcodec::{
+    common::{EncodeCaps, HwcodecErrno},
+    vram::{
+        backend::{self, EncodeBackend},
+        encode::EncodeFrame,
+        split::{tile_rects, SplitEncoder, TileRect},
+        DynamicContext, EncodeContext,
+    },
+};
+use std::{
+    ffi::c_void,
+    ptr::null_mut,
+    sync::{Arc, Mutex},
+};
+
+// a driver of its own per test, they run in parallel
+fn register(name: &str) -> Arc<Mutex<Log>> {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let sessions = log.clone();
+    common::register(
+        name,
+        |_| vec![],
+        move |ctx| {
+            sessions
+                .lock()
+                .unwrap()
+                .created
+                .push((ctx.d.width, ctx.d.kbitrate));
+            Ok(Box::new(Fake::new(&[0, 0, 0, 1, 0x65], &sessions)))
+        },
+    );
+    log
+}
+
+fn context(name: &str, width: i32, height: i32, max_width: i32) -> EncodeContext {
+    let mut ctx = encode_context(name);
+    ctx.f.caps = EncodeCaps {
+        maxWidth: max_width,
+        maxHeight: 4096,
+        ..Default::default()
+    };
+    ctx.d = DynamicContext {
+        width,
+        height,
+        kbitrate: 12000,
+        framerate: 60,
+        ..ctx.d
+    };
+    ctx
+}
+
+fn widths(rects: &[TileRect]) -> Vec<i32> {
+    rects.iter().map(|r| r.width).collect()
+}
+
+#[test]
+fn tiles_within_caps() {
+    let rects = tile_rects(&context("", 3840, 2160, 4096)).unwrap();
+    assert_eq!(
+        rects,
+        vec![TileRect {
+            x: 0,
+            y: 0,
+            width: 3840,
+            height: 2160
+        }]
+    );
+    // caps without a limit
+    assert_eq!(tile_rects(&context("", 11520, 2160, 0)).unwrap().len(), 1);
+
+    let rects = tile_rects(&context("", 7680, 1440, 4096)).unwrap();
+    assert_eq!(widths(&rects), [3840, 3840]);
+    assert_eq!(rects[1].x, 3840);
+    assert!(rects.iter().all(|r| r.y == 0 && r.height == 1440));
+
+    // three 1920 wide monitors on a session of at most 4096
+    let rects = tile_rects(&context("", 5760, 1080, 4096)).unwrap();
+    assert_eq!(widths(&rects), [2880, 2880]);
+
+    let rects = tile_rects(&context("", 5000, 1080, 4096)).unwrap();
+    assert_eq!(widths(&rects), [2512, 2488]);
+    let rects = tile_rects(&context("", 12000, 1080, 4096)).unwrap();
+    assert_eq!(widths(&rects), [4000, 4000, 4000]);
+    // adjacent and covering the width, only the last may be unaligned
+    let rects = tile_rects(&context("", 8197, 720, 4100)).unwrap();
+    assert_eq!(widths(&rects), [2736, 2736, 2725]);
+    let mut x = 0;
+    for rect in &rects {
+        assert_eq!(rect.x, x);
+        x += rect.width;
+    }
+    assert_eq!(x, 8197);
+
+    // tiles are only side by side
+    assert!(tile_rects(&context("", 3840, 4320, 4096)).is_err());
+    assert!(tile_rects(&context("", 0, 1080, 4096)).is_err());
+}
+
+#[test]
+fn sessions_share_bitrate_by_area() {
+    const NAME: &str = "split-bitrate-test";
+    let log = register(NAME);
+    let mut encoder = SplitEncoder::new(context(NAME, 12000, 1080, 4096)).unwrap();
+    assert!(encoder.is_split());
+    assert_eq!(encoder.tiles().len(), 3);
+    assert_eq!(
+        log.lock().unwrap().created,
+        [(4000, 4000), (4000, 4000), (4000, 4000)]
+    );
+
+    encoder.set_bitrate(6000).unwrap();
+    assert_eq!(log.lock().unwrap().bitrates, [2000, 2000, 2000]);
+    assert_eq!(encoder.ctx.d.kbitrate, 6000);
+    assert!(encoder.set_bitrate(0).is_err());
+
+    encoder.request_keyframe().unwrap();
+    assert_eq!(log.lock().unwrap().keyframes, 3);
+    backend::unregister_encode_driver(NAME);
+}
+
+#[test]
+fn uneven_tiles_share_bitrate_by_width() {
+    const NAME: &str = "split-uneven-test";
+    let log = register(NAME);
+    let encoder = SplitEncoder::new(context(NAME, 5000, 1080, 4096)).unwrap();
+    assert_eq!(widths(&encoder.tiles()), [2512, 2488]);
+    assert_eq!(log.lock().unwrap().created, [(2512, 6028), (2488, 5971)]);
+    backend::unregister_encode_driver(NAME);
+}
+
+#[test]
+fn single_session_encodes_whole_frame() {
+    const NAME: &str = "split-single-test";
+    let log = register(NAME);
+    let mut encoder = SplitEncoder::new(context(NAME, 1920, 1080, 4096)).unwrap();
+    assert!(!encoder.is_split());
+    assert_eq!(log.lock().unwrap().created, [(1920, 12000)]);
+    let frames = encoder.encode(null_mut(), 0).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].tile, 0);
+    assert_eq!(
+        frames[0].rect,
+        TileRect {
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080
+        }
+    );
+    encoder.request_keyframe().unwrap();
+    assert_eq!(log.lock().unwrap().keyframes, 1);
+    backend::unregister_encode_driver(NAME);
+}
+
+// fails every encode, its keyframe requests are logged
+struct Failing(Arc<Mutex<Log>>);
+
+impl EncodeBackend for Failing {
+    fn encode(&mut self, _tex: *mut c_void, _ms: i64) -> Result<&mut Vec<EncodeFrame>, i32> {
+        Err(HwcodecErrno::HWCODEC_ERR_COMMON as _)
+    }
+
+    fn set_bitrate(&mut self, _kbs: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn set_framerate(&mut self, _framerate: i32) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) -> Result<(), i32> {
+        self.0.lock().unwrap().keyframes += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_encode_requests_keyframe() {
+    const NAME: &str = "split-failing-test";
+    let log = Arc::new(Mutex::new(Log::default()));
+    let sessions = log.clone();
+    common::register(
+        NAME,
+        |_| vec![],
+        move |_| Ok(Box::new(Failing(sessions.clone()))),
+    );
+    let mut encoder = SplitEncoder::new(context(NAME, 1920, 1080, 4096)).unwrap();
+    assert!(encoder.encode(null_mut(), 0).is_err());
+    assert_eq!(log.lock().unwrap().keyframes, 1);
+    backend::unregister_encode_driver(NAME);
+}
+
+#[test]
+fn unregistered_driver_fails() {
+    assert!(SplitEncoder::new(context("split-missing-test", 7680, 1080, 4096)).is_err());
+}